@@ -1,52 +1,234 @@
 use std::{
 	borrow::Cow,
 	collections::{HashMap, HashSet},
-	path::PathBuf,
-	sync::{Arc, Mutex, RwLock},
+	future::Future,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, OnceLock, RwLock},
+	time::{Duration, Instant},
 };
 
-use directories::ProjectDirs;
 use futures_util::StreamExt;
 pub use llm::{InferenceFeedback, InferenceResponse};
 use llm::{
-	InferenceParameters, InferenceSession, InferenceSessionConfig, InferenceSnapshot, InferenceStats, Model, ModelParameters, OutputRequest, Prompt,
-	TokenId, TokenizerSource,
+	InferenceParameters, InferenceSession, InferenceSessionConfig, InferenceSnapshot, Model, ModelParameters, OutputRequest, Prompt, TokenId,
+	TokenizerSource,
 };
+use poly_bias::vocabulary::VocabularyInfo;
 use regex::Regex;
-use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc::Sender, task::spawn_blocking};
+use sha2::{Digest, Sha256};
+use tokio::{
+	fs::{File, OpenOptions},
+	io::{AsyncReadExt, AsyncWriteExt},
+	runtime::{Handle, RuntimeFlavor},
+	sync::mpsc::Sender,
+	task::{block_in_place, spawn_blocking},
+};
 
 use crate::{
-	config::{BackendConfig, ModelConfig},
-	memory::{hierarchically_chunk, Memory, MemoryError},
-	session::BackendSession,
-	stats::TaskStats,
-	types::{BackendError, EmbeddingResponse, PromptRequest, SessionRequest, TokenResponse, TokenizationResponse},
+	cache::{EmbeddingCache, DEFAULT_EMBEDDING_CACHE_SIZE},
+	config::{BackendConfig, ConfigProblem, ModelArchitecture, ModelConfig, TaskConfig},
+	memory::{hierarchically_chunk, ItemMetadata, Memory, MemoryHit, MemoryItem, MemoryQuery},
+	pool::InferencePool,
+	preflight::{self, PrivateTokens},
+	redact::Redactor,
+	session::{BackendSession, Cancellation, Completion, SessionCheckpoint},
+	stats::{Gauge, MemoryStats, ModelStats, TaskStats, TokenUsage},
+	tools,
+	trace::TraceWriter,
+	types::{
+		BackendError, EmbeddingResponse, MemoryStage, ModelInfo, ModelState, PromptEmbedding, PromptRequest, PromptViolation, ReloadReport,
+		RerankMethod, RerankRequest, RerankResponse, RerankResult, SessionRequest, SummaryLevel, SummaryProgress, SummaryResponse, TokenResponse,
+		TokenizationResponse, ValidationResponse,
+	},
 };
 
 use tracing::*;
 
 pub struct BackendStats {
 	pub task_stats: Mutex<HashMap<String, TaskStats>>,
+	model_stats: Mutex<HashMap<String, ModelStats>>,
+
+	/// Number of currently active sessions
+	pub sessions: Arc<Gauge>,
+
+	/// Number of currently active sessions per model
+	model_sessions: Mutex<HashMap<String, Arc<Gauge>>>,
 }
 
 pub struct Backend {
+	/// The configuration the backend was started with. Tasks are not included as these can be replaced while running
+	/// (see [`Backend::reload`] and [`Backend::task`]).
 	pub config: BackendConfig,
-	pub models: HashMap<String, Arc<Box<dyn llm::Model>>>,
+	tasks: RwLock<HashMap<String, TaskConfig>>,
+	models: RwLock<HashMap<String, LoadedModel>>,
 	pub memories: HashMap<String, Arc<Box<dyn Memory>>>,
 	pub stats: Arc<BackendStats>,
 	pub prelude_snapshots: RwLock<HashMap<String, InferenceSnapshot>>,
+
+	/// Models that are configured but could not be made available, with the reason why. These can be loaded later
+	/// using [`Backend::retry_unavailable_models`].
+	unavailable_models: RwLock<HashMap<String, String>>,
+
+	/// Models that share the loaded weights of another model entry (as they are loaded from the same file with the
+	/// same options), with the name of that entry
+	pub model_aliases: HashMap<String, String>,
+
+	/// Recently calculated embeddings
+	embedding_cache: EmbeddingCache,
+
+	/// The threads on which completions and embeddings are performed
+	pub pool: InferencePool,
+
+	/// Writes the traces of completions for tasks that have a trace directory
+	pub(crate) traces: TraceWriter,
+
+	/// The runtime the backend was started in, on which memory operations requested by sessions are performed (see
+	/// [`Backend::block_on_memory`])
+	runtime: Handle,
+}
+
+/// The analysis of the vocabulary of a model that is shared between the biasers of all sessions using the model. It is
+/// made when first needed.
+pub(crate) type SharedVocabulary = Arc<OnceLock<Arc<VocabularyInfo>>>;
+
+/// A loaded model, with what was found out about it while loading. When a model is loaded again, it gets a new analysis
+/// of its (possibly different) vocabulary.
+#[derive(Clone)]
+struct LoadedModel {
+	model: Arc<Box<dyn Model>>,
+	info: ModelInfo,
+	vocabulary: SharedVocabulary,
+}
+
+/// The options that determine how the weights of a model are loaded. Model entries with equal options share a single
+/// loaded model; other settings (such as the number of threads) are applied per entry.
+#[derive(Debug, PartialEq)]
+struct ModelLoadOptions {
+	path: PathBuf,
+	architecture: ModelArchitecture,
+	context_size: usize,
+	lora_adapters: Option<Vec<PathBuf>>,
+	tokenizer_path: Option<PathBuf>,
+	tokenizer_repo: Option<String>,
+	use_gpu: bool,
+	gpu_layers: Option<usize>,
+}
+
+impl ModelLoadOptions {
+	fn new(path: &Path, model_config: &ModelConfig) -> ModelLoadOptions {
+		ModelLoadOptions {
+			path: std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+			architecture: model_config.architecture,
+			context_size: model_config.context_size,
+			lora_adapters: model_config.lora_adapters.clone(),
+			tokenizer_path: model_config.tokenizer_path.clone(),
+			tokenizer_repo: model_config.tokenizer_repo.clone(),
+			use_gpu: model_config.use_gpu,
+			// Ignored when not using the GPU
+			gpu_layers: model_config.gpu_layers.filter(|_| model_config.use_gpu),
+		}
+	}
+}
+
+pub(crate) const CACHE_MODELS_DIR: &str = "models";
+
+/// Environment variable that is used to obtain a Hugging Face access token when none is configured
+const HF_TOKEN_ENV: &str = "HF_TOKEN";
+
+/// Translate `hf://org/repo/path/to/file` URLs to the Hugging Face download URL for the file
+fn resolve_model_url(url: &str) -> Cow<str> {
+	match url.strip_prefix("hf://").and_then(|rest| {
+		let mut parts = rest.splitn(3, '/');
+		Some((parts.next()?, parts.next()?, parts.next()?))
+	}) {
+		Some((org, repo, file)) => Cow::Owned(format!("https://huggingface.co/{org}/{repo}/resolve/main/{file}")),
+		None => Cow::Borrowed(url),
+	}
+}
+
+/// Number of tokens in the vocabulary of a model itself (i.e. the number of logits it outputs for a token, which is
+/// not necessarily the number of tokens of its tokenizer when an external tokenizer is loaded) and the number of
+/// dimensions of its embeddings, found by evaluating a single token
+fn model_dimensions(model: &dyn Model) -> (usize, usize) {
+	let mut session = model.start_session(InferenceSessionConfig::default());
+	let mut output_request = OutputRequest {
+		embeddings: Some(Vec::new()),
+		all_logits: Some(Vec::new()),
+	};
+	model.evaluate(&mut session, &[0], &mut output_request);
+	(
+		output_request.all_logits.map_or(0, |logits| logits.len()),
+		output_request.embeddings.map_or(0, |embeddings| embeddings.len()),
+	)
+}
+
+/// The name of an architecture as used in the configuration (e.g. `gptneox`)
+fn architecture_name(architecture: ModelArchitecture) -> String {
+	format!("{architecture:?}").to_lowercase()
 }
 
-const CACHE_MODELS_DIR: &str = "models";
+/// The quantization format of a model file as indicated by its name, following the usual naming of converted models
+/// (e.g. `llama-2-7b.ggmlv3.q4_K_M.bin` or `ggml-model-f16.bin`)
+fn quantization_from_file_name(path: &Path) -> Option<String> {
+	let stem = path.file_stem()?.to_str()?.to_lowercase();
+	stem.rsplit(['.', '-'])
+		.find(|part| {
+			let is_quantized = part
+				.strip_prefix('q')
+				.is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()) && rest.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+			is_quantized || matches!(*part, "f16" | "f32")
+		})
+		.map(str::to_string)
+}
+
+/// Size of the blocks in which files are read to calculate their checksum
+const HASH_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Calculate the SHA-256 checksum of a file (as lowercase hex string). Calls `progress` with the fraction of the file
+/// that was read, as hashing multi-gigabyte model files takes a while.
+pub async fn sha256_file(path: &Path, progress: impl Fn(f64)) -> Result<String, String> {
+	let mut file = File::open(path).await.map_err(|e| format!("could not open {path:?}: {e}"))?;
+	let size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+	let mut hasher = Sha256::new();
+	let mut buffer = vec![0u8; HASH_BLOCK_SIZE];
+	let mut hashed = 0u64;
+	loop {
+		let n = file.read(&mut buffer).await.map_err(|e| format!("could not read {path:?}: {e}"))?;
+		if n == 0 {
+			break;
+		}
+		hasher.update(&buffer[..n]);
+		hashed += n as u64;
+		if size > 0 {
+			progress(hashed as f64 / size as f64);
+		}
+	}
+	Ok(format!("{:x}", hasher.finalize()))
+}
 
 impl Backend {
+	/// Wait for a memory operation from synchronous code (such as a session). The operation is performed on the runtime
+	/// the backend was started in, so this works on a plain thread, in a blocking task and in an asynchronous task on a
+	/// multi-threaded runtime (whose worker thread is handed over to other tasks while waiting). It must not be called
+	/// from an asynchronous task on the thread of a current-thread runtime the backend was started in, as that runtime
+	/// could then never perform the operation.
+	pub(crate) fn block_on_memory<T: Send + 'static>(&self, operation: impl Future<Output = T> + Send + 'static) -> T {
+		let (tx, rx) = std::sync::mpsc::sync_channel(1);
+		self.runtime.spawn(async move {
+			_ = tx.send(operation.await);
+		});
+		let wait = move || rx.recv().expect("memory operation is performed");
+		match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+			Ok(RuntimeFlavor::MultiThread) => block_in_place(wait),
+			_ => wait(),
+		}
+	}
+
+	/// Load the models, memories and tasks of a configuration. The fraction of the models that has been loaded is
+	/// reported through `progress`; models are loaded in order of their names.
 	pub async fn from(mut config: BackendConfig, progress: Option<Sender<f64>>) -> Backend {
 		// Determine cache path
-		if config.cache_path.is_none() {
-			if let Some(pd) = ProjectDirs::from("nl.dialogic", "Dialogic", "Poly") {
-				config.cache_path = Some(pd.cache_dir().to_path_buf());
-			}
-		}
+		config.set_default_cache_path();
 
 		// Ensure cache directory exists (if there is one)
 		let cache_path = config.cache_path.clone();
@@ -59,17 +241,34 @@ impl Backend {
 			cache_path = cache_path.as_ref().map(|x| x.to_str().map(|y| y.to_string())),
 			"backend instantiating"
 		);
+		let stats = Arc::new(BackendStats::new(config.soft_session_limit));
+		let tasks = std::mem::take(&mut config.tasks);
+		let embedding_cache = EmbeddingCache::new(config.embedding_cache_size.unwrap_or(DEFAULT_EMBEDDING_CACHE_SIZE));
+		let pool = InferencePool::new(config.inference_threads());
 		let mut backend = Backend {
 			config,
-			models: HashMap::new(),
-			stats: Arc::new(BackendStats::default()),
+			tasks: RwLock::new(HashMap::new()),
+			models: RwLock::new(HashMap::new()),
+			stats,
 			memories: HashMap::new(),
 			prelude_snapshots: RwLock::new(HashMap::new()),
+			unavailable_models: RwLock::new(HashMap::new()),
+			model_aliases: HashMap::new(),
+			embedding_cache,
+			pool,
+			traces: TraceWriter::new(),
+			runtime: Handle::current(),
 		};
+		let hf_token = backend.hf_token();
 
 		// Load models
 		let n_models = backend.config.models.len();
-		for (index, (model_name, model_config)) in backend.config.models.iter().enumerate() {
+		let mut loaded: Vec<(ModelLoadOptions, String)> = vec![];
+
+		// Models are loaded in order of their names, so that the progress reported can be attributed to a model
+		let mut models: Vec<_> = backend.config.models.iter().collect();
+		models.sort_by_key(|(model_name, _)| *model_name);
+		for (index, (model_name, model_config)) in models.into_iter().enumerate() {
 			// Warn about invalid configurations
 			if !model_config.use_gpu && model_config.gpu_layers.is_some() {
 				tracing::warn!("gpu_layers set but ignored because use_gpu is not set to true");
@@ -79,76 +278,64 @@ impl Backend {
 			}
 
 			// Check if we already have a copy of the model, or download it
-			let actual_model_path = model_config.model_path.clone().unwrap_or_else(|| {
-				cache_path
-					.clone()
-					.expect("cache path is set when models without path are specified")
-					.join(CACHE_MODELS_DIR)
-					.join(format!("{model_name}.bin"))
-			});
+			let actual_model_path = match backend.config.model_path(model_name) {
+				Ok(path) => path,
+				Err(e) => {
+					tracing::error!("model {model_name} is not available: {e}");
+					backend.unavailable_models.get_mut().unwrap().insert(model_name.clone(), e);
+					continue;
+				}
+			};
 
-			if !actual_model_path.exists() {
-				// See if we can download this file
-				if let Some(ref url) = model_config.url {
-					// Download
-					tracing::info!("downloading model {model_name} from {url}");
-					Self::download_model(url, &actual_model_path).await.expect("could not download model");
-					if !actual_model_path.exists() {
-						panic!("model file not found for model {model_name} at path {actual_model_path:?} even after downloading");
-					}
-				} else {
-					panic!("model file not found for model {model_name} at path {actual_model_path:?}");
+			let download_progress = |fraction: f64| {
+				if let Some(ref p) = progress {
+					_ = p.try_send((index as f64 + fraction) / n_models as f64);
 				}
+			};
+			if let Err(e) = Self::ensure_model_file(model_name, model_config, &actual_model_path, hf_token.as_deref(), download_progress).await {
+				tracing::error!("model {model_name} is not available: {e}");
+				backend.unavailable_models.get_mut().unwrap().insert(model_name.clone(), e);
+				continue;
 			}
-
-			// Set up hyperparameters
-			let params = ModelParameters {
-				prefer_mmap: true,
-				context_size: model_config.context_size,
-				lora_adapters: model_config.lora_adapters.clone(),
-				use_gpu: model_config.use_gpu,
-				gpu_layers: model_config.gpu_layers,
-				rope_overrides: None,
-				n_gqa: None,
+			let tokenizer = match backend.tokenizer_source(model_name, hf_token.as_deref()).await {
+				Ok(tokenizer) => tokenizer,
+				Err(e) => {
+					tracing::error!("model {model_name} is not available: {e}");
+					backend.unavailable_models.get_mut().unwrap().insert(model_name.clone(), e);
+					continue;
+				}
 			};
 
-			// Actually load the model
-			let model_config = model_config.clone();
-			let model_name_copy = model_name.clone();
+			// Share the model loaded for an earlier entry when the same file is loaded in the same way
+			let load_options = ModelLoadOptions::new(&actual_model_path, model_config);
+			if let Some((_, loaded_name)) = loaded.iter().find(|(options, _)| *options == load_options) {
+				info!("Model {model_name} shares the weights loaded for model {loaded_name}");
+				let models = backend.models.get_mut().unwrap();
+				let mut model = models[loaded_name].clone();
+				model.info.name = model_name.clone();
+				models.insert(model_name.clone(), model);
+				backend.model_aliases.insert(model_name.clone(), loaded_name.clone());
+				continue;
+			}
 
+			// Actually load the model
 			let progress_sender = progress.clone();
-			let model = spawn_blocking(move || {
-				Arc::new(
-					llm::load_dynamic(
-						Some(model_config.architecture),
-						&actual_model_path,
-						TokenizerSource::Embedded,
-						params,
-						|load_progress| {
-							let fp: f64 = match load_progress {
-								llm::LoadProgress::HyperparametersLoaded => 0.0,
-								llm::LoadProgress::ContextSize { .. } => 0.0,
-								llm::LoadProgress::LoraApplied { .. } => 0.0,
-								llm::LoadProgress::TensorLoaded {
-									current_tensor,
-									tensor_count,
-								} => (current_tensor as f64) / (tensor_count as f64),
-								llm::LoadProgress::Loaded { .. } => 1.0,
-							};
-							if let Some(ref p) = progress_sender {
-								_ = p.blocking_send((index as f64 + fp) / n_models as f64);
-							}
-							trace!("Loading model {model_name_copy}: {load_progress:#?}");
-						},
-					)
-					.expect("load model"),
-				)
-			})
-			.await
-			.unwrap();
-
-			backend.models.insert(model_name.clone(), model);
-			info!("Loaded model {} use_gpu={:?}", model_name, model_config.use_gpu);
+			let load_progress = move |fraction: f64| {
+				if let Some(ref p) = progress_sender {
+					_ = p.blocking_send((index as f64 + fraction) / n_models as f64);
+				}
+			};
+			match Self::load_model(model_name, model_config, actual_model_path, tokenizer, load_progress).await {
+				Ok(model) => {
+					backend.models.get_mut().unwrap().insert(model_name.clone(), model);
+					loaded.push((load_options, model_name.clone()));
+					info!("Loaded model {} use_gpu={:?}", model_name, model_config.use_gpu);
+				}
+				Err(e) => {
+					tracing::error!("model {model_name} is not available: {e}");
+					backend.unavailable_models.get_mut().unwrap().insert(model_name.clone(), e);
+				}
+			}
 		}
 
 		info!("All models loaded");
@@ -156,7 +343,13 @@ impl Backend {
 		// Load memories
 		for (memory_name, memory_config) in backend.config.memories.iter() {
 			info!("Loading memory {memory_name}");
-			if !backend.models.contains_key(&memory_config.embedding_model) {
+			if backend.unavailable_models.read().unwrap().contains_key(&memory_config.embedding_model) {
+				tracing::warn!(
+					"embedding model {} for memory {} is not available",
+					memory_config.embedding_model,
+					memory_name
+				);
+			} else if !backend.models.read().unwrap().contains_key(&memory_config.embedding_model) {
 				panic!("embedding model {} not found for memory {}", memory_config.embedding_model, memory_name);
 			}
 			let mem = memory_config.store.from(memory_config).expect("memory construction");
@@ -166,19 +359,13 @@ impl Backend {
 		info!("All memories loaded");
 
 		// Verify tasks
-		for (task_name, task_config) in &backend.config.tasks {
-			if !backend.models.contains_key(&task_config.model) {
-				panic!("model {} not found for task {}", task_config.model, task_name);
-			}
-
-			if let Some(memorization) = &task_config.memorization {
-				if !backend.memories.contains_key(&memorization.memory) {
-					panic!("memory {} not found for task {}", memorization.memory, task_name);
-				}
-			}
+		if let Some(problem) = backend.check_tasks(&tasks).first() {
+			panic!("{problem}");
 		}
+		*backend.tasks.get_mut().unwrap() = tasks;
 
 		info!("All tasks loaded");
+		backend.log_degraded();
 
 		if let Some(ref p) = progress {
 			_ = p.send(1.0).await;
@@ -187,31 +374,327 @@ impl Backend {
 		backend
 	}
 
-	/// Downloads a file to the indicated location
-	async fn download_model(url: &str, target_path: &PathBuf) -> Result<(), String> {
+	/// Returns the Hugging Face access token to use for downloads, from the configuration or the environment
+	fn hf_token(&self) -> Option<String> {
+		self.config
+			.hf_token
+			.as_ref()
+			.map(|t| t.expose().to_string())
+			.or_else(|| std::env::var(HF_TOKEN_ENV).ok())
+	}
+
+	/// Returns the tokenizer to load for a model. When a Hugging Face repository is configured for the tokenizer, its
+	/// `tokenizer.json` is downloaded to the cache (unless it is already there).
+	async fn tokenizer_source(&self, model_name: &str, auth_token: Option<&str>) -> Result<TokenizerSource, String> {
+		let model_config = &self.config.models[model_name];
+		if let Some(ref path) = model_config.tokenizer_path {
+			if !path.exists() {
+				return Err(format!("tokenizer file not found at path {path:?}"));
+			}
+			return Ok(TokenizerSource::HuggingFaceTokenizerFile(path.clone()));
+		}
+
+		let Some(ref repo) = model_config.tokenizer_repo else {
+			return Ok(TokenizerSource::Embedded);
+		};
+		let Some(ref cache_path) = self.config.cache_path else {
+			return Err(String::from("tokenizer_repo is set but no cache path is available"));
+		};
+		let path = cache_path.join(CACHE_MODELS_DIR).join(format!("{model_name}.tokenizer.json"));
+		if !path.exists() {
+			let url = format!("https://huggingface.co/{repo}/resolve/main/tokenizer.json");
+			tracing::info!("downloading tokenizer for model {model_name} from {url}");
+			Self::download_model(&url, &path, auth_token, |_| {})
+				.await
+				.map_err(|e| format!("could not download tokenizer from {url}: {e}"))?;
+		}
+		Ok(TokenizerSource::HuggingFaceTokenizerFile(path))
+	}
+
+	/// Load the weights of a model from a file and find out what is to be known about it (see [`ModelInfo`]). Calls
+	/// `progress` with the fraction of the model that was loaded. When an external tokenizer is used, it must have as
+	/// many tokens as the vocabulary of the model.
+	async fn load_model(
+		model_name: &str,
+		model_config: &ModelConfig,
+		path: PathBuf,
+		tokenizer: TokenizerSource,
+		progress: impl Fn(f64) + Send + 'static,
+	) -> Result<LoadedModel, String> {
+		let params = ModelParameters {
+			prefer_mmap: true,
+			context_size: model_config.context_size,
+			lora_adapters: model_config.lora_adapters.clone(),
+			use_gpu: model_config.use_gpu,
+			gpu_layers: model_config.gpu_layers,
+			rope_overrides: None,
+			n_gqa: None,
+		};
+		let architecture = model_config.architecture;
+		let model_name = model_name.to_string();
+		let tokenizer_path = match tokenizer {
+			TokenizerSource::HuggingFaceTokenizerFile(ref path) => Some(path.clone()),
+			_ => None,
+		};
+
+		spawn_blocking(move || {
+			let model = llm::load_dynamic(Some(architecture), &path, tokenizer, params, |load_progress| {
+				let fp: f64 = match load_progress {
+					llm::LoadProgress::HyperparametersLoaded => 0.0,
+					llm::LoadProgress::ContextSize { .. } => 0.0,
+					llm::LoadProgress::LoraApplied { .. } => 0.0,
+					llm::LoadProgress::TensorLoaded {
+						current_tensor,
+						tensor_count,
+					} => (current_tensor as f64) / (tensor_count as f64),
+					llm::LoadProgress::Loaded { .. } => 1.0,
+				};
+				progress(fp);
+				trace!("Loading model {model_name}: {load_progress:#?}");
+			})
+			.map_err(|e| format!("could not load model from {path:?}: {e}"))?;
+
+			let (n_vocab, n_embd) = model_dimensions(model.as_ref());
+			if let Some(tokenizer_path) = tokenizer_path {
+				let n_tokens = model.tokenizer().len();
+				if n_tokens != n_vocab {
+					return Err(format!(
+						"tokenizer {tokenizer_path:?} has {n_tokens} tokens, but the vocabulary of the model has {n_vocab} tokens"
+					));
+				}
+			}
+
+			let info = ModelInfo {
+				name: model_name,
+				architecture: architecture_name(architecture),
+				quantization: quantization_from_file_name(&path),
+				context_length: model.context_size(),
+				embedding_length: Some(n_embd),
+				file_size: std::fs::metadata(&path).ok().map(|metadata| metadata.len()),
+				state: ModelState::Loaded,
+				error: None,
+			};
+			Ok(LoadedModel {
+				model: Arc::new(model),
+				info,
+				vocabulary: SharedVocabulary::default(),
+			})
+		})
+		.await
+		.unwrap_or_else(|e| Err(format!("loading model panicked: {e}")))
+	}
+
+	/// Try again to load the models that could not be loaded before (using the configuration the backend was started
+	/// with). Returns the names of the models that were loaded; models that still cannot be loaded remain unavailable
+	/// (see [`Backend::unavailable_models`]).
+	pub async fn retry_unavailable_models(&self) -> Vec<String> {
+		let hf_token = self.hf_token();
+		let model_names: Vec<String> = self.unavailable_models.read().unwrap().keys().cloned().collect();
+		let mut loaded = vec![];
+		for model_name in model_names {
+			let model_config = &self.config.models[&model_name];
+			info!("retrying to load model {model_name}");
+			let result = async {
+				let path = self.config.model_path(&model_name)?;
+				Self::ensure_model_file(&model_name, model_config, &path, hf_token.as_deref(), |_| {}).await?;
+				let tokenizer = self.tokenizer_source(&model_name, hf_token.as_deref()).await?;
+				Self::load_model(&model_name, model_config, path, tokenizer, |_| {}).await
+			}
+			.await;
+
+			match result {
+				Ok(model) => {
+					info!("Loaded model {} use_gpu={:?}", model_name, model_config.use_gpu);
+					self.models.write().unwrap().insert(model_name.clone(), model);
+					self.unavailable_models.write().unwrap().remove(&model_name);
+					loaded.push(model_name);
+				}
+				Err(e) => {
+					tracing::error!("model {model_name} is still not available: {e}");
+					self.unavailable_models.write().unwrap().insert(model_name, e);
+				}
+			}
+		}
+		loaded.sort();
+		loaded
+	}
+
+	/// Models that are configured but could not be loaded, with the reason why
+	pub fn unavailable_models(&self) -> HashMap<String, String> {
+		self.unavailable_models.read().unwrap().clone()
+	}
+
+	/// All configured models (in order of their names), with what was found out about them when they were loaded or why
+	/// they could not be loaded
+	pub fn model_info(&self) -> Vec<ModelInfo> {
+		let models = self.models.read().unwrap();
+		let unavailable_models = self.unavailable_models.read().unwrap();
+		let mut model_info: Vec<ModelInfo> = self
+			.config
+			.models
+			.iter()
+			.map(|(model_name, model_config)| match models.get(model_name) {
+				Some(loaded) => loaded.info.clone(),
+				None => ModelInfo {
+					name: model_name.clone(),
+					architecture: architecture_name(model_config.architecture),
+					quantization: None,
+					context_length: model_config.context_size,
+					embedding_length: None,
+					file_size: None,
+					state: ModelState::Unavailable,
+					error: unavailable_models.get(model_name).cloned(),
+				},
+			})
+			.collect();
+		model_info.sort_by(|a, b| a.name.cmp(&b.name));
+		model_info
+	}
+
+	/// Tasks that cannot be used because their model could not be loaded, with the reason why
+	pub fn unavailable_tasks(&self) -> HashMap<String, String> {
+		let unavailable_models = self.unavailable_models.read().unwrap();
+		self.tasks
+			.read()
+			.unwrap()
+			.iter()
+			.filter_map(|(task_name, task_config)| {
+				let reason = unavailable_models.get(&task_config.model)?;
+				Some((task_name.clone(), format!("model {} is not available: {reason}", task_config.model)))
+			})
+			.collect()
+	}
+
+	/// Log a summary of the functionality that is unavailable because models could not be loaded
+	fn log_degraded(&self) {
+		let unavailable_models = self.unavailable_models();
+		if unavailable_models.is_empty() {
+			return;
+		}
+
+		let mut unavailable_tasks: Vec<String> = self.unavailable_tasks().into_keys().collect();
+		unavailable_tasks.sort();
+		tracing::error!(
+			"running in degraded mode: {} of {} model(s) could not be loaded; unavailable tasks: {}",
+			unavailable_models.len(),
+			self.config.models.len(),
+			if unavailable_tasks.is_empty() {
+				String::from("none")
+			} else {
+				unavailable_tasks.join(", ")
+			}
+		);
+		for (model_name, reason) in &unavailable_models {
+			tracing::error!("model {model_name} is not available: {reason}");
+		}
+	}
+
+	/// Makes sure the model file is present at the indicated path and (when configured) has the expected checksum. If
+	/// this is not the case, the model is downloaded (when a URL is configured). The progress of hashing and downloading
+	/// is reported through `progress`.
+	async fn ensure_model_file(
+		model_name: &str,
+		model_config: &ModelConfig,
+		path: &Path,
+		auth_token: Option<&str>,
+		progress: impl Fn(f64),
+	) -> Result<(), String> {
+		if path.exists() {
+			let Some(ref expected) = model_config.sha256 else {
+				return Ok(());
+			};
+
+			let actual = sha256_file(path, &progress).await?;
+			if actual.eq_ignore_ascii_case(expected) {
+				return Ok(());
+			}
+			if model_config.url.is_none() {
+				return Err(format!("checksum mismatch for {path:?}: expected {expected}, found {actual}"));
+			}
+			tracing::warn!(model_name, "checksum of model file {path:?} does not match, downloading it again");
+		}
+
+		let Some(ref url) = model_config.url else {
+			return Err(format!("model file not found at path {path:?}"));
+		};
+
+		let url = resolve_model_url(url);
+		tracing::info!("downloading model {model_name} from {url}");
+		Self::download_model(&url, path, auth_token, &progress).await?;
+
+		if let Some(ref expected) = model_config.sha256 {
+			let actual = sha256_file(path, &progress).await?;
+			if !actual.eq_ignore_ascii_case(expected) {
+				_ = tokio::fs::remove_file(path).await;
+				return Err(format!(
+					"checksum mismatch for model downloaded from {url}: expected {expected}, found {actual}"
+				));
+			}
+		}
+		Ok(())
+	}
+
+	/// Downloads a file to the indicated location. When a partially downloaded file is present from an earlier
+	/// attempt, the download is resumed.
+	async fn download_model(url: &str, target_path: &Path, auth_token: Option<&str>, progress: impl Fn(f64)) -> Result<(), String> {
 		let client = reqwest::Client::new();
-		let res = client.get(url).send().await.map_err(|x| x.to_string())?;
 
-		let mut temp_path = target_path.clone();
+		let mut temp_path = target_path.to_path_buf();
 		temp_path.set_extension("download");
-		let mut file = File::create(&temp_path)
-			.await
-			.map_err(|x| format!("could not create temp file at {temp_path:?}: {x}"))?;
-		let total_size = res.content_length().ok_or(format!("Failed to get content length from '{}'", &url))? as usize;
+		let already_downloaded = tokio::fs::metadata(&temp_path).await.map(|m| m.len()).unwrap_or(0);
+
+		let mut request = client.get(url);
+		if let Some(auth_token) = auth_token {
+			request = request.bearer_auth(auth_token);
+		}
+		if already_downloaded > 0 {
+			request = request.header(reqwest::header::RANGE, format!("bytes={already_downloaded}-"));
+		}
+		let res = request.send().await.and_then(|r| r.error_for_status()).map_err(|x| x.to_string())?;
+
+		// The server may not support range requests, in which case we start over
+		let resuming = res.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+		let mut file = if resuming {
+			tracing::info!(url, already_downloaded, "resuming download");
+			OpenOptions::new()
+				.append(true)
+				.open(&temp_path)
+				.await
+				.map_err(|x| format!("could not open temp file at {temp_path:?}: {x}"))?
+		} else {
+			File::create(&temp_path)
+				.await
+				.map_err(|x| format!("could not create temp file at {temp_path:?}: {x}"))?
+		};
+
+		let mut downloaded = if resuming { already_downloaded as usize } else { 0 };
+		let total_size = res.content_length().map(|length| length as usize + downloaded);
 
 		let mut stream = res.bytes_stream();
-		let mut downloaded: usize = 0;
-		while let Some(item) = stream.next().await {
-			let chunk = item.or(Err("Error while downloading file".to_string()))?;
+		let received = loop {
+			let Some(item) = stream.next().await else {
+				break Ok(());
+			};
+			let Ok(chunk) = item else {
+				break Err("Error while downloading file".to_string());
+			};
 			file.write_all(&chunk).await.or(Err("Error while writing to file".to_string()))?;
 			downloaded += chunk.len();
-			tracing::debug!(url, "download: {}/{} bytes", downloaded, total_size);
-		}
-		if downloaded != total_size {
-			tracing::debug!(
-				url,
-				"download completed, but size mismatches: {downloaded} downloaded bytes, {total_size} total size bytes",
-			);
+			tracing::debug!(url, "download: {}/{:?} bytes", downloaded, total_size);
+			if let Some(total_size) = total_size {
+				progress(downloaded as f64 / total_size as f64);
+			}
+		};
+
+		// What was received is written out completely even when the download failed, so that it can be resumed
+		file.flush().await.map_err(|x| x.to_string())?;
+		received?;
+		if let Some(total_size) = total_size {
+			if downloaded != total_size {
+				return Err(format!(
+					"download incomplete: {downloaded} downloaded bytes, {total_size} total size bytes"
+				));
+			}
 		}
 		tracing::debug!(url, "download completed");
 
@@ -220,48 +703,278 @@ impl Backend {
 		Ok(())
 	}
 
+	/// Returns the loaded model with the specified name
+	fn model(&self, model_name: &str) -> Result<Arc<Box<dyn Model>>, BackendError> {
+		self.loaded_model(model_name).map(|loaded| loaded.model)
+	}
+
+	/// Returns the loaded model with the specified name, along with what is known about it
+	fn loaded_model(&self, model_name: &str) -> Result<LoadedModel, BackendError> {
+		if let Some(loaded) = self.models.read().unwrap().get(model_name) {
+			return Ok(loaded.clone());
+		}
+		match self.unavailable_models.read().unwrap().get(model_name) {
+			Some(reason) => Err(BackendError::ModelNotAvailable {
+				model: model_name.to_string(),
+				reason: reason.clone(),
+			}),
+			None => Err(BackendError::ModelNotFound(model_name.to_string())),
+		}
+	}
+
+	/// Check whether sessions can be started for a task, i.e. whether it exists and its model is loaded
+	pub fn check_task_available(&self, task_name: &str) -> Result<(), BackendError> {
+		let task_config = self.task(task_name).ok_or_else(|| BackendError::TaskNotFound(task_name.to_string()))?;
+		self.model(&task_config.model).map(|_| ())
+	}
+
+	/// Returns the embedding of a prompt calculated by a model. Recently calculated embeddings are cached.
+	#[instrument(level = "info", skip(self, prompt), fields(cached))]
 	pub fn embedding(&self, model_name: &str, prompt: &PromptRequest) -> Result<EmbeddingResponse, BackendError> {
 		info!(model_name, "embedding request");
 
-		if !self.models.contains_key(model_name) {
-			return Err(BackendError::ModelNotFound(model_name.to_string()));
+		let (embedding, hit) = self
+			.embedding_cache
+			.get_or_insert_with(model_name, &prompt.text(), || self.calculate_embedding(model_name, prompt))?;
+		Span::current().record("cached", hit);
+		self.stats.add_embedding_cache_lookup(model_name, hit);
+		Ok(EmbeddingResponse { embedding, embeddings: None })
+	}
+
+	/// Returns the embeddings of several prompts calculated by a model, along with the number of tokens of each prompt.
+	/// The prompts are evaluated one after the other in a single session (embeddings found in the cache are not
+	/// calculated again).
+	#[instrument(level = "info", skip(self, prompts), fields(n = prompts.len()))]
+	pub fn embedding_batch(&self, model_name: &str, prompts: &[PromptRequest]) -> Result<EmbeddingResponse, BackendError> {
+		let model = self.model(model_name)?;
+		let mut session = None;
+		let embeddings = prompts
+			.iter()
+			.map(|prompt| {
+				let text = prompt.text();
+				let tokens = Self::embedding_tokens(&model, &text)?;
+				let (embedding, hit) = self.embedding_cache.get_or_insert_with(model_name, &text, || {
+					let session = session.get_or_insert_with(|| self.embedding_session(model_name, &model));
+					Ok::<_, BackendError>(self.evaluate_embedding(model_name, &model, session, &tokens))
+				})?;
+				self.stats.add_embedding_cache_lookup(model_name, hit);
+				Ok(PromptEmbedding {
+					embedding,
+					prompt_tokens: tokens.len(),
+				})
+			})
+			.collect::<Result<Vec<_>, BackendError>>()?;
+		Ok(EmbeddingResponse {
+			embedding: Vec::new(),
+			embeddings: Some(embeddings),
+		})
+	}
+
+	/// Returns the cosine similarity of the embedding of `a` with that of each of the texts in `b`, calculated by a model.
+	/// Each distinct text is embedded once (identical texts share their embedding through the cache).
+	#[instrument(level = "info", skip(self, a, b), fields(n = b.len()))]
+	pub fn similarity(&self, model_name: &str, a: &str, b: &[&str]) -> Result<Vec<f32>, BackendError> {
+		if b.is_empty() {
+			return Err(BackendError::InvalidQuery(String::from(
+				"at least one text must be given to compare with",
+			)));
+		}
+		let max_texts = self
+			.config
+			.models
+			.get(model_name)
+			.ok_or_else(|| BackendError::ModelNotFound(model_name.to_string()))?
+			.max_similarity_texts;
+		if b.len() > max_texts {
+			return Err(BackendError::InvalidQuery(format!(
+				"at most {max_texts} texts may be given to compare with"
+			)));
+		}
+		if a.trim().is_empty() || b.iter().any(|text| text.trim().is_empty()) {
+			return Err(BackendError::InvalidQuery(String::from("texts to compare must not be empty")));
+		}
+
+		let embedding_a = self.embedding(model_name, &PromptRequest::new(a))?.embedding;
+		b.iter()
+			.map(|text| {
+				let embedding_b = self.embedding(model_name, &PromptRequest::new(*text))?.embedding;
+				cosine_similarity(&embedding_a, &embedding_b)
+					.ok_or_else(|| BackendError::InvalidQuery(String::from("the embedding of a text to compare is zero")))
+			})
+			.collect()
+	}
+
+	/// Order documents by their relevance to a query, using the rerank method configured for the task. Fails when this
+	/// takes longer than the budget of the task.
+	#[instrument(level = "info", skip(self, request), fields(n = request.documents.len()))]
+	pub fn rerank(&self, task_name: &str, request: &RerankRequest) -> Result<RerankResponse, BackendError> {
+		let task_config = self.task(task_name).ok_or_else(|| BackendError::TaskNotFound(task_name.to_string()))?;
+		let rerank = &task_config.rerank;
+		if request.documents.len() > rerank.max_documents {
+			return Err(BackendError::InvalidParameter(
+				String::from("documents"),
+				format!("at most {} documents may be given", rerank.max_documents),
+			));
+		}
+		if request.top_n == Some(0) {
+			return Err(BackendError::InvalidParameter(String::from("top_n"), String::from("must be at least 1")));
+		}
+		if request.query.trim().is_empty() || request.documents.iter().any(|document| document.trim().is_empty()) {
+			return Err(BackendError::InvalidQuery(String::from("the query and documents must not be empty")));
+		}
+
+		let start = Instant::now();
+		let check_budget = || {
+			if start.elapsed() > Duration::from_millis(rerank.budget_ms) {
+				Err(BackendError::BudgetExceeded { budget_ms: rerank.budget_ms })
+			} else {
+				Ok(())
+			}
+		};
+
+		let scores: Vec<f32> = match rerank.method {
+			RerankMethod::Embedding => {
+				let query_embedding = self.embedding(&task_config.model, &PromptRequest::new(request.query.as_str()))?.embedding;
+				request
+					.documents
+					.iter()
+					.map(|document| {
+						check_budget()?;
+						let embedding = self.embedding(&task_config.model, &PromptRequest::new(document.as_str()))?.embedding;
+						cosine_similarity(&query_embedding, &embedding)
+							.ok_or_else(|| BackendError::InvalidQuery(String::from("the embedding of a document is zero")))
+					})
+					.collect::<Result<_, _>>()?
+			}
+			RerankMethod::CrossScoring => {
+				let model = self.model(&task_config.model)?;
+				let answer_token = |token: &String| -> Result<TokenId, BackendError> {
+					let tokens = model.tokenizer().tokenize(token, false)?;
+					if tokens.len() != 1 {
+						return Err(BackendError::InvalidRerankToken(token.clone()));
+					}
+					Ok(tokens[0].1)
+				};
+				let (yes, no) = (answer_token(&rerank.yes_token)?, answer_token(&rerank.no_token)?);
+				request
+					.documents
+					.iter()
+					.map(|document| {
+						check_budget()?;
+						let prompt = rerank.template.replace("{query}", &request.query).replace("{document}", document);
+						self.cross_score(&task_config, &prompt, yes, no)
+					})
+					.collect::<Result<_, _>>()?
+			}
+		};
+		check_budget()?;
+
+		let mut results: Vec<RerankResult> = request
+			.documents
+			.iter()
+			.zip(scores)
+			.enumerate()
+			.map(|(index, (document, score))| RerankResult {
+				index,
+				document: document.clone(),
+				score,
+			})
+			.collect();
+		// Documents with the same score keep their order
+		results.sort_by(|a, b| b.score.total_cmp(&a.score));
+		results.truncate(request.top_n.unwrap_or(results.len()));
+		Ok(RerankResponse {
+			results,
+			method: rerank.method,
+		})
+	}
+
+	/// The probability that the model answers the prompt with the `yes` token rather than the `no` token
+	fn cross_score(&self, task_config: &TaskConfig, prompt: &str, yes: TokenId, no: TokenId) -> Result<f32, BackendError> {
+		let model = self.model(&task_config.model)?;
+		let tokens: Vec<TokenId> = model.tokenizer().tokenize(prompt, true)?.iter().map(|(_, token)| *token).collect();
+		if tokens.len() > model.context_size() {
+			return Err(BackendError::ContextFull {
+				needed: tokens.len(),
+				available: model.context_size(),
+			});
+		}
+
+		let model_config = &self.config.models[&task_config.model];
+		let mut session = model.start_session(InferenceSessionConfig {
+			n_threads: task_config.threads_per_session.unwrap_or(model_config.threads_per_session),
+			n_batch: task_config.batch_size.unwrap_or(model_config.batch_size),
+			..InferenceSessionConfig::default()
+		});
+		let mut output_request = OutputRequest {
+			embeddings: None,
+			all_logits: Some(Vec::new()),
+		};
+		model.evaluate(&mut session, &tokens, &mut output_request);
+
+		// The logits of all tokens are returned; only those following the last token of the prompt are of interest
+		let logits = output_request.all_logits.unwrap_or_default();
+		let n_vocab = model.tokenizer().len();
+		let last = &logits[logits.len().saturating_sub(n_vocab)..];
+		let (Some(yes), Some(no)) = (last.get(yes as usize), last.get(no as usize)) else {
+			return Err(BackendError::InferenceFailed {
+				after_tokens: 0,
+				source: "the model did not return logits".into(),
+			});
 		};
+		Ok(1.0 / (1.0 + (no - yes).exp()))
+	}
+
+	#[instrument(level = "debug", skip(self, prompt), fields(n_tokens))]
+	fn calculate_embedding(&self, model_name: &str, prompt: &PromptRequest) -> Result<Vec<f32>, BackendError> {
+		let model = self.model(model_name)?;
+		let mut session = self.embedding_session(model_name, &model);
+		let tokens = Self::embedding_tokens(&model, &prompt.text())?;
+		Ok(self.evaluate_embedding(model_name, &model, &mut session, &tokens))
+	}
 
-		let model = self.models.get(model_name).unwrap();
+	/// Start a session for calculating embeddings using a model
+	fn embedding_session(&self, model_name: &str, model: &Arc<Box<dyn Model>>) -> InferenceSession {
 		let inference_config = InferenceSessionConfig {
 			n_threads: self.config.models[model_name].threads_per_session,
 			n_batch: 8,
 			..InferenceSessionConfig::default()
 		};
-		let mut session = model.start_session(inference_config);
-		let mut output_request = OutputRequest {
-			embeddings: Some(Vec::new()),
-			all_logits: None,
-		};
+		model.start_session(inference_config)
+	}
 
-		let vocab = model.tokenizer();
+	fn embedding_tokens(model: &Arc<Box<dyn Model>>, text: &str) -> Result<Vec<TokenId>, BackendError> {
 		let beginning_of_sentence = true;
-		let query_token_ids = vocab
-			.tokenize(&prompt.prompt, beginning_of_sentence)
-			.unwrap()
+		Ok(model
+			.tokenizer()
+			.tokenize(text, beginning_of_sentence)?
 			.iter()
 			.map(|(_, tok)| *tok)
-			.collect::<Vec<_>>();
-		model.evaluate(&mut session, &query_token_ids, &mut output_request);
-		Ok(EmbeddingResponse {
-			embedding: output_request.embeddings.unwrap(),
-		})
+			.collect())
+	}
+
+	/// Calculate the embedding of tokens in a session. Anything evaluated in the session before is discarded, so that the
+	/// session can be reused for the next prompt.
+	fn evaluate_embedding(&self, model_name: &str, model: &Arc<Box<dyn Model>>, session: &mut InferenceSession, tokens: &[TokenId]) -> Vec<f32> {
+		session.n_past = 0;
+		session.tokens.clear();
+		session.decoded_tokens.clear();
+		let mut output_request = OutputRequest {
+			embeddings: Some(Vec::new()),
+			all_logits: None,
+		};
+		Span::current().record("n_tokens", tokens.len());
+		let start = Instant::now();
+		model.evaluate(session, tokens, &mut output_request);
+		self.stats.add_embedding(model_name, tokens.len(), start.elapsed());
+		output_request.embeddings.unwrap()
 	}
 
 	pub fn tokenize(&self, model_name: &str, prompt: &PromptRequest) -> Result<TokenizationResponse, BackendError> {
 		info!(model_name, "tokenization request");
 
-		if !self.models.contains_key(model_name) {
-			return Err(BackendError::ModelNotFound(model_name.to_string()));
-		};
-
-		let model = self.models.get(model_name).unwrap();
-		let res = model.tokenizer().tokenize(&prompt.prompt, true)?;
+		let model = self.model(model_name)?;
+		let res = model.tokenizer().tokenize(&prompt.text(), true)?;
 		Ok(TokenizationResponse {
 			tokens: res
 				.iter()
@@ -273,56 +986,206 @@ impl Backend {
 		})
 	}
 
+	/// Check a prompt for a task the way a completion would (tokenizing it with the prefix and postfix, scanning it for
+	/// private tokens, checking whether it fits in the maximum number of prompt tokens of the task and in the context
+	/// window of a new session and setting up the biaser),
+	/// without starting a session. Only the tokenizer of the model is used. Problems with the prompt are reported as
+	/// violations; an error is only returned when the task cannot be performed at all.
+	#[instrument(level = "info", skip(self, request, prompt))]
+	pub fn validate(&self, task_name: &str, request: &SessionRequest, prompt: &PromptRequest) -> Result<ValidationResponse, BackendError> {
+		let mut task_config = self.task(task_name).ok_or_else(|| BackendError::TaskNotFound(task_name.to_string()))?;
+		if let Some(ref prefix) = request.prefix {
+			task_config.prefix = Some(prefix.clone());
+		}
+		let model = self.model(&task_config.model)?;
+		let tokenizer = model.tokenizer();
+		let mut violations = vec![];
+
+		let parameters = match task_config.with_overrides(prompt) {
+			Ok(parameters) => Some(parameters),
+			Err(BackendError::InvalidParameter(parameter, message)) => {
+				violations.push(PromptViolation::InvalidParameter { parameter, message });
+				None
+			}
+			Err(e) => return Err(e),
+		};
+
+		// A new session starts with the prelude, after which no beginning-of-sentence token is added to the prompt
+		let prelude_tokens = match tools::prelude(&task_config) {
+			Some(ref prelude) if !prelude.is_empty() => Prompt::Text(prelude).to_tokens(tokenizer, true)?.len(),
+			_ => 0,
+		};
+		let beginning_of_sentence = model.bot_token_id().is_some() && prelude_tokens == 0;
+		let segments = match tools::prompt_segments(&task_config, prompt) {
+			Ok(segments) => segments,
+			Err(BackendError::InvalidVariables { missing, unknown, too_long }) => {
+				// The prompt is checked without the template instead
+				violations.push(PromptViolation::InvalidVariables { missing, unknown, too_long });
+				prompt.segments().into_owned()
+			}
+			Err(e) => return Err(e),
+		};
+		let mut tokens = preflight::prompt_tokens(tokenizer, &task_config, None, &segments, beginning_of_sentence)?;
+		violations.extend(tokens.violations());
+		let truncated_tokens = match tokens.limit(task_config.max_prompt_tokens, prompt.truncate) {
+			Ok(truncated) => truncated,
+			Err(BackendError::PromptTooLong { needed, allowed }) => {
+				violations.push(PromptViolation::PromptTooLong { needed, allowed });
+				0
+			}
+			Err(e) => return Err(e),
+		};
+
+		let available = model.context_size().saturating_sub(prelude_tokens);
+		if let Err(BackendError::ContextFull { needed, available }) = preflight::check_context(tokens.tokens.len(), available) {
+			violations.push(PromptViolation::ContextFull { needed, available });
+		}
+
+		match preflight::biaser_schema(&task_config).and_then(|_| preflight::text_biaser(&task_config)) {
+			Ok(_) => {}
+			Err(BackendError::InvalidBiaser(message)) => violations.push(PromptViolation::InvalidBiaser { message }),
+			Err(e) => return Err(e),
+		}
+
+		Ok(ValidationResponse {
+			valid: violations.is_empty(),
+			prompt_tokens: tokens.prompt,
+			overhead_tokens: tokens.tokens.len() - tokens.prompt,
+			truncated_tokens,
+			prelude_tokens,
+			context_size: model.context_size(),
+			violations,
+			parameters,
+		})
+	}
+
 	pub async fn forget(&self, memory_name: &str) -> Result<(), BackendError> {
 		if !self.memories.contains_key(memory_name) {
 			return Err(BackendError::MemoryNotFound(memory_name.to_string()));
 		}
 		let memory = self.memories.get(memory_name).unwrap();
 		tracing::info!("clearing memory {memory_name}");
-		memory.clear().await.map_err(BackendError::Memory)
+		memory.clear().await.map_err(BackendError::memory(MemoryStage::Remove))
 	}
 
-	pub async fn recall(&self, memory_name: &str, prompt: &str, top_n: usize) -> Result<Vec<String>, BackendError> {
-		if !self.memories.contains_key(memory_name) {
-			return Err(BackendError::MemoryNotFound(memory_name.to_string()));
-		}
+	#[instrument(level = "info", skip(self, query))]
+	pub async fn recall(&self, memory_name: &str, query: MemoryQuery<'_>, top_n: usize) -> Result<Vec<String>, BackendError> {
+		let memory = self.memory(memory_name)?;
+		let embedding = self.query_embedding(memory_name, query)?;
+		memory.get(&embedding, top_n).await.map_err(BackendError::memory(MemoryStage::Retrieve))
+	}
 
+	/// Like `recall`, but returns the chunks with their identifiers, metadata and relevance scores. Only chunks whose
+	/// metadata matches the filter are returned (see [`ItemMetadata::matches`]).
+	#[instrument(level = "info", skip(self, query))]
+	pub async fn recall_hits(
+		&self,
+		memory_name: &str,
+		query: MemoryQuery<'_>,
+		top_n: usize,
+		filter: &ItemMetadata,
+	) -> Result<Vec<MemoryHit>, BackendError> {
+		let memory = self.memory(memory_name)?;
+		let embedding = self.query_embedding(memory_name, query)?;
+		memory
+			.search(&embedding, top_n, filter)
+			.await
+			.map_err(BackendError::memory(MemoryStage::Retrieve))
+	}
+
+	/// The embedding to search a memory with: that of the prompt (calculated using the embedding model of the memory), or
+	/// the supplied embedding after checking that it fits the memory
+	fn query_embedding(&self, memory_name: &str, query: MemoryQuery<'_>) -> Result<Vec<f32>, BackendError> {
 		let memory_config = &self.config.memories[memory_name];
+		match query {
+			MemoryQuery::Prompt(prompt) => Ok(self.embedding(&memory_config.embedding_model, &PromptRequest::new(prompt))?.embedding),
+			MemoryQuery::Embedding(embedding) if embedding.len() != memory_config.dimensions => Err(BackendError::InvalidQuery(format!(
+				"embedding has {} dimensions, but memory '{memory_name}' has {}",
+				embedding.len(),
+				memory_config.dimensions
+			))),
+			MemoryQuery::Embedding(embedding) if embedding.iter().any(|value| !value.is_finite()) => {
+				Err(BackendError::InvalidQuery(String::from("embedding contains values that are not finite")))
+			}
+			MemoryQuery::Embedding(embedding) => Ok(embedding.to_vec()),
+		}
+	}
 
-		// Generate embedding for prompt
-		let embedding = self.embedding(&memory_config.embedding_model, &PromptRequest { prompt: prompt.to_string() })?;
-		let memory = self.memories.get(memory_name).unwrap();
-		memory.get(&embedding.embedding, top_n).await.map_err(BackendError::Memory)
+	/// List the chunks stored in a memory, skipping the first `offset` and returning at most `limit`
+	pub async fn list_memory(&self, memory_name: &str, offset: usize, limit: usize) -> Result<Vec<MemoryItem>, BackendError> {
+		self.memory(memory_name)?
+			.list(offset, limit)
+			.await
+			.map_err(BackendError::memory(MemoryStage::List))
+	}
+
+	/// Remove a single chunk (identified as in [`MemoryItem::id`]) from a memory
+	pub async fn forget_item(&self, memory_name: &str, id: &str) -> Result<(), BackendError> {
+		let memory = self.memory(memory_name)?;
+		tracing::info!("removing item {id} from memory {memory_name}");
+		memory.remove(id).await.map_err(BackendError::memory(MemoryStage::Remove))
+	}
+
+	/// Persist the contents of all memories. All memories are flushed, even when flushing one fails; the first error is
+	/// returned.
+	pub async fn flush_memories(&self) -> Result<(), BackendError> {
+		let mut result = Ok(());
+		for (memory_name, memory) in &self.memories {
+			if let Err(e) = memory.flush().await {
+				tracing::error!("could not flush memory {memory_name}: {e}");
+				if result.is_ok() {
+					result = Err(BackendError::memory(MemoryStage::Flush)(e));
+				}
+			}
+		}
+		result
+	}
+
+	/// Statistics of each memory. Memories whose statistics cannot be retrieved (e.g. because their server is unreachable)
+	/// are left out.
+	pub async fn memory_stats(&self) -> HashMap<String, MemoryStats> {
+		let mut stats = HashMap::new();
+		for (memory_name, memory) in &self.memories {
+			match memory.stats().await {
+				Ok(memory_stats) => {
+					stats.insert(memory_name.clone(), memory_stats);
+				}
+				Err(e) => tracing::warn!("could not retrieve statistics of memory {memory_name}: {e}"),
+			}
+		}
+		stats
 	}
 
+	fn memory(&self, memory_name: &str) -> Result<&Arc<Box<dyn Memory>>, BackendError> {
+		self.memories
+			.get(memory_name)
+			.ok_or_else(|| BackendError::MemoryNotFound(memory_name.to_string()))
+	}
+
+	#[instrument(level = "info", skip(self, data), fields(data_length = data.len()))]
 	pub async fn memorize(&self, memory_name: &str, data: &str) -> Result<(), BackendError> {
+		self.memorize_sections(memory_name, &[data], &ItemMetadata::default()).await
+	}
+
+	/// Store the sections of a document in a memory. Each section is chunked separately, so that chunks never span more
+	/// than one section (e.g. a group of rows of a table). The metadata is stored with each chunk.
+	#[instrument(level = "info", skip(self, sections), fields(n_sections = sections.len()))]
+	pub async fn memorize_sections(&self, memory_name: &str, sections: &[&str], metadata: &ItemMetadata) -> Result<(), BackendError> {
 		// Obtain memorization configuration
-		tracing::info!(memory_name, data_length = data.len(), "memorize");
+		tracing::info!(
+			memory_name,
+			data_length = sections.iter().map(|s| s.len()).sum::<usize>(),
+			n_sections = sections.len(),
+			"memorize"
+		);
 		let memory_config = &self.config.memories[memory_name];
 		let memory = self.memories[memory_name].clone();
 		let model_name = &memory_config.embedding_model;
 
 		// Get embedding model
-		if !self.models.contains_key(model_name) {
-			return Err(BackendError::ModelNotFound(model_name.to_string()));
-		};
-
-		let model = self.models.get(model_name).unwrap().clone();
+		let model = self.model(model_name)?;
 		let model_config = self.config.models[model_name].clone();
 
-		// Apply pre-filter
-		let mut data = Cow::from(data);
-		if !memory_config.pre_filter.is_empty() {
-			for filter_string in memory_config.pre_filter.iter() {
-				let regex = Regex::new(filter_string).unwrap();
-				let out = regex.replace_all(&data, " ").to_string();
-				data = Cow::Owned(out);
-			}
-
-			// Replace double spaces with single spaces
-			data = Cow::Owned(data.replace("  ", " "));
-		}
-
 		// Split the input by all separators
 		let vocab = model.tokenizer();
 		let separator_tokens: Vec<TokenId> = memory_config
@@ -337,8 +1200,24 @@ impl Backend {
 			})
 			.collect::<Result<Vec<TokenId>, BackendError>>()?;
 
-		let body_tokens = vocab.tokenize(data.as_ref(), false)?;
-		let chunks = hierarchically_chunk(body_tokens, &separator_tokens, memory_config.chunk_max_tokens);
+		let mut chunks = vec![];
+		for section in sections {
+			// Apply pre-filter
+			let mut data = Cow::from(*section);
+			if !memory_config.pre_filter.is_empty() {
+				for filter_string in memory_config.pre_filter.iter() {
+					let regex = Regex::new(filter_string).unwrap();
+					let out = regex.replace_all(&data, " ").to_string();
+					data = Cow::Owned(out);
+				}
+
+				// Replace double spaces with single spaces
+				data = Cow::Owned(data.replace("  ", " "));
+			}
+
+			let body_tokens = vocab.tokenize(data.as_ref(), false)?;
+			chunks.extend(hierarchically_chunk(body_tokens, &separator_tokens, memory_config.chunk_max_tokens));
+		}
 
 		let post_filter_tokens = memory_config
 			.post_filter
@@ -367,20 +1246,29 @@ impl Backend {
 				let chars: Vec<u8> = chunk.iter().flat_map(|x| x.0.clone()).collect();
 				let chunk_text = String::from_utf8_lossy(&chars);
 				tracing::trace!(?chunk_text, chunk_size_tokens = chunk_tokens.len(), "chunk for ingest");
-				Self::memorize_chunk(model.clone(), &model_config, &chunk_text, chunk_tokens, memory.clone()).await?;
+				let embedding = self
+					.embed_chunk(model.clone(), &model_config, &chunk_text, chunk_tokens, model_name)
+					.await;
+				memory
+					.store(&chunk_text, &embedding, metadata)
+					.await
+					.map_err(BackendError::memory(MemoryStage::Store))?;
 			}
 		}
 
 		Ok(())
 	}
 
-	async fn memorize_chunk(
+	/// Calculate the embedding of a chunk to memorize
+	#[instrument(level = "info", skip_all, fields(n_tokens = tokens.len()))]
+	async fn embed_chunk(
+		&self,
 		model: Arc<Box<dyn Model>>,
 		model_config: &ModelConfig,
 		text: &str,
 		tokens: Vec<TokenId>,
-		memory: Arc<Box<dyn Memory>>,
-	) -> Result<(), MemoryError> {
+		model_name: &str,
+	) -> Vec<f32> {
 		// Calculate embedding
 		tracing::trace!(n_tokens = tokens.len(), ?text, "memorize chunk");
 
@@ -392,74 +1280,212 @@ impl Backend {
 
 		let mut session = model.start_session(inference_config);
 
-		let embeddings = spawn_blocking(move || {
-			let mut output_request = OutputRequest {
-				embeddings: Some(Vec::new()),
-				all_logits: None,
-			};
-			model.evaluate(&mut session, &tokens, &mut output_request);
-			output_request.embeddings.unwrap()
-		})
-		.await
-		.unwrap();
+		let start = Instant::now();
+		let n_tokens = tokens.len();
+		let embeddings = self
+			.pool
+			.run(move || {
+				let mut output_request = OutputRequest {
+					embeddings: Some(Vec::new()),
+					all_logits: None,
+				};
+				model.evaluate(&mut session, &tokens, &mut output_request);
+				output_request.embeddings.unwrap()
+			})
+			.await;
+		self.stats.add_embedding(model_name, n_tokens, start.elapsed());
+		embeddings
+	}
 
-		memory.store(text, &embeddings).await?;
-		Ok(())
+	/// Returns the current configuration of a task (if it exists)
+	pub fn task(&self, task_name: &str) -> Option<TaskConfig> {
+		self.tasks.read().unwrap().get(task_name).cloned()
 	}
 
-	pub fn start(&self, task_name: &str, _request: &SessionRequest, backend: Arc<Backend>) -> Result<BackendSession, BackendError> {
-		info!("Start session {task_name}");
+	/// Returns the names of the tasks that sessions can currently be started for
+	pub fn task_names(&self) -> Vec<String> {
+		self.tasks.read().unwrap().keys().cloned().collect()
+	}
 
-		if !self.config.tasks.contains_key(task_name) {
-			return Err(BackendError::TaskNotFound(task_name.to_string()));
-		};
+	/// Returns the names of the tasks that can be used without authenticating (see [`TaskConfig::public`])
+	pub fn public_task_names(&self) -> Vec<String> {
+		self.tasks
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|(_, task)| task.public)
+			.map(|(name, _)| name.clone())
+			.collect()
+	}
+
+	/// Verify that the models and memories tasks refer to are loaded. Tasks using models that are configured but not
+	/// available are accepted (sessions for these will fail to start).
+	fn check_tasks(&self, tasks: &HashMap<String, TaskConfig>) -> Vec<ConfigProblem> {
+		let mut problems = vec![];
+		for (task_name, task_config) in tasks {
+			let key = format!("tasks.{task_name}");
+			if self.unavailable_models.read().unwrap().contains_key(&task_config.model) {
+				tracing::warn!("model {} for task {} is not available", task_config.model, task_name);
+			} else if !self.models.read().unwrap().contains_key(&task_config.model) {
+				problems.push(ConfigProblem::new(&key, format!("model '{}' is not loaded", task_config.model)));
+			}
+
+			if let Some(memorization) = &task_config.memorization {
+				if !self.memories.contains_key(&memorization.memory) {
+					problems.push(ConfigProblem::new(&key, format!("memory '{}' is not loaded", memorization.memory)));
+				}
+			}
+
+			// Private tokens may span several tokens, but must correspond to at least one
+			if let Some(loaded) = self.models.read().unwrap().get(&task_config.model) {
+				if let Err(e) = PrivateTokens::new(loaded.model.tokenizer(), task_config) {
+					problems.push(ConfigProblem::new(&key, e.to_string()));
+				}
+			}
+		}
+		problems
+	}
+
+	/// Apply the task configuration from a new configuration to the running backend. Tasks that were added or changed
+	/// become available for new sessions and removed tasks stop accepting new sessions. Sessions that are already
+	/// running keep using the configuration they were started with. Changes to models and memories are reported, but
+	/// only take effect after a restart as they require loading weights. When any problems are found in the new
+	/// configuration, nothing is changed.
+	pub fn reload(&self, config: &BackendConfig) -> Result<ReloadReport, Vec<ConfigProblem>> {
+		let mut problems = config.check();
+		problems.extend(self.check_tasks(&config.tasks));
+		if !problems.is_empty() {
+			return Err(problems);
+		}
+
+		let mut report = ReloadReport::default();
+		for (model_name, model_config) in &config.models {
+			if self.config.models.get(model_name) != Some(model_config) {
+				report.deferred.push(format!("models.{model_name}"));
+			}
+		}
+		for (memory_name, memory_config) in &config.memories {
+			if self.config.memories.get(memory_name) != Some(memory_config) {
+				report.deferred.push(format!("memories.{memory_name}"));
+			}
+		}
+		report.deferred.extend(
+			self.config
+				.models
+				.keys()
+				.filter(|k| !config.models.contains_key(*k))
+				.map(|k| format!("models.{k}")),
+		);
+		report.deferred.extend(
+			self.config
+				.memories
+				.keys()
+				.filter(|k| !config.memories.contains_key(*k))
+				.map(|k| format!("memories.{k}")),
+		);
 
-		let task_config = self.config.tasks.get(task_name).unwrap();
+		{
+			let mut tasks = self.tasks.write().unwrap();
+			for (task_name, task_config) in &config.tasks {
+				match tasks.get(task_name) {
+					None => report.added_tasks.push(task_name.clone()),
+					Some(old_config) if old_config != task_config => report.changed_tasks.push(task_name.clone()),
+					Some(_) => {}
+				}
+			}
+			report.removed_tasks = tasks.keys().filter(|k| !config.tasks.contains_key(*k)).cloned().collect();
+			*tasks = config.tasks.clone();
+		}
+
+		// Prelude snapshots of changed and removed tasks are stale. This is done after the tasks have been replaced, so
+		// that sessions starting concurrently will not cache a snapshot for the old configuration (see [`Backend::start`]).
+		{
+			let mut snapshots = self.prelude_snapshots.write().unwrap();
+			for task_name in report.changed_tasks.iter().chain(report.removed_tasks.iter()) {
+				snapshots.remove(task_name);
+			}
+		}
+
+		report.added_tasks.sort();
+		report.changed_tasks.sort();
+		report.removed_tasks.sort();
+		report.deferred.sort();
+		info!(
+			added = ?report.added_tasks,
+			changed = ?report.changed_tasks,
+			removed = ?report.removed_tasks,
+			deferred = ?report.deferred,
+			"configuration reloaded"
+		);
+		Ok(report)
+	}
+
+	#[instrument(level = "info", skip(self, request, backend))]
+	pub fn start(&self, task_name: &str, request: &SessionRequest, backend: Arc<Backend>) -> Result<BackendSession, BackendError> {
+		info!("Start session {task_name}");
+
+		let mut task_config = self.task(task_name).ok_or_else(|| BackendError::TaskNotFound(task_name.to_string()))?;
+		if let Some(ref prefix) = request.prefix {
+			task_config.prefix = Some(prefix.clone());
+		}
 
 		let memory = task_config.memorization.as_ref().map(|mc| self.memories.get(&mc.memory).unwrap());
 
-		let model = self.models.get(&task_config.model).unwrap().clone();
-		let n_threads = self.config.models[&task_config.model].threads_per_session;
+		let LoadedModel { model, vocabulary, .. } = self.loaded_model(&task_config.model)?;
+		let eot_token_ids = preflight::eot_token_ids(model.tokenizer(), &task_config, model.eot_token_id())?;
+		let model_config = &self.config.models[&task_config.model];
+		let n_threads = task_config.threads_per_session.unwrap_or(model_config.threads_per_session);
+		let n_batch = task_config.batch_size.unwrap_or(model_config.batch_size);
 		let inference_config: InferenceSessionConfig = InferenceSessionConfig {
 			n_threads,
-			n_batch: self.config.models[&task_config.model].batch_size,
+			n_batch,
 			..InferenceSessionConfig::default()
 		};
 
 		let inference_parameters: InferenceParameters = task_config.clone().into();
 
-		let session = if let Some(ref prelude_prompt) = task_config.prelude {
+		// Tasks with tools list them in the prelude
+		let prelude = tools::prelude(&task_config).map(Cow::into_owned);
+		let session = if let Some(ref prelude_prompt) = prelude {
 			if !prelude_prompt.is_empty() {
 				// Do we have a snapshot?
 				let cache = self.prelude_snapshots.read().unwrap();
 				if let Some(snapshot) = cache.get(task_name) {
 					// We have a snapshot
 					tracing::debug!("Re-using prelude snapshot for task {task_name}");
+					self.stats.add_prelude_cache_lookup(task_name, &task_config.model, true);
 					InferenceSession::from_snapshot(snapshot.clone(), model.as_ref().as_ref()).expect("restore prelude")
 				} else {
 					// We are dropping the read lock here because further on we want to acquire a write lock, and RwLock
 					// has no way to upgrade the read lock to a write lock. This is fine for now - it might cause us to
 					// generate the prelude twice but that's okay.
 					drop(cache);
+					self.stats.add_prelude_cache_lookup(task_name, &task_config.model, false);
 					let mut session = model.start_session(inference_config);
 
-					tracing::debug!("feeding prelude prompt: '{prelude_prompt}'");
-					session.feed_prompt(
-						model.as_ref().as_ref(),
-						Prompt::Text(&prelude_prompt.clone()),
-						&mut OutputRequest::default(),
-						|r| -> Result<InferenceFeedback, BackendError> {
-							tracing::trace!("Feed prompt: received {r:?}");
-							Ok(InferenceFeedback::Continue)
-						},
-					)?;
-
-					// Save snapshot
+					let redactor = Redactor::new(task_config.private_tokens.iter().flatten());
+					tracing::debug!("feeding prelude prompt: '{}'", redactor.redact(&prelude_prompt));
+					let prelude_tokens = Prompt::Text(&prelude_prompt).to_tokens(model.tokenizer(), true)?;
+					session
+						.feed_prompt(
+							model.as_ref().as_ref(),
+							Prompt::Tokens(&prelude_tokens),
+							&mut OutputRequest::default(),
+							|r| -> Result<InferenceFeedback, BackendError> {
+								tracing::trace!("Feed prompt: received {}", redactor.redact(&format!("{r:?}")));
+								Ok(InferenceFeedback::Continue)
+							},
+						)
+						.map_err(|e| BackendError::from_inference(e, prelude_tokens.len(), model.context_size(), 0))?;
+
+					// Save snapshot, unless the task was reloaded with a different prelude in the meantime
 					tracing::trace!("Caching prelude snapshot for task {task_name}");
 					let snapshot = unsafe { session.get_snapshot().to_owned() };
 					{
 						let mut cache = self.prelude_snapshots.write().unwrap();
-						cache.insert(task_name.to_string(), snapshot);
+						if self.task(task_name).is_some_and(|t| tools::prelude(&t).as_deref() == prelude.as_deref()) {
+							cache.insert(task_name.to_string(), snapshot);
+						}
 					}
 					session
 				}
@@ -472,6 +1498,7 @@ impl Backend {
 			model.start_session(inference_config)
 		};
 
+		let pinned = SessionCheckpoint::of(&session, 0);
 		Ok(BackendSession {
 			model: model.clone(),
 			memory: memory.cloned(),
@@ -481,28 +1508,459 @@ impl Backend {
 			stats: self.stats.clone(),
 			task_name: task_name.to_string(),
 			n_threads,
+			n_batch,
+			prompt_chunk_size: model_config.prompt_chunk_size,
+			eot_token_ids,
+			vocabulary,
+			pinned,
+			context_generation: 0,
 			backend,
+			_session_guard: self.stats.sessions.enter(),
+			_model_session_guard: self.stats.model_sessions(&task_config.model).enter(),
 		})
 	}
+
+	/// Start a session for a task and perform a single completion, calling `on_token` for each generated token. Returns
+	/// the generated text along with the completion.
+	pub fn complete(
+		self: &Arc<Self>,
+		task_name: &str,
+		request: &SessionRequest,
+		prompt: &PromptRequest,
+		on_token: impl FnMut(&str) -> InferenceFeedback,
+	) -> Result<(String, Completion), BackendError> {
+		self.complete_cancellable(task_name, request, prompt, &Cancellation::default(), on_token)
+	}
+
+	/// Like [`Backend::complete`], but ends the completion early (with [`crate::types::FinishReason::Cancelled`]) when
+	/// it is cancelled through `cancellation`
+	pub fn complete_cancellable(
+		self: &Arc<Self>,
+		task_name: &str,
+		request: &SessionRequest,
+		prompt: &PromptRequest,
+		cancellation: &Cancellation,
+		mut on_token: impl FnMut(&str) -> InferenceFeedback,
+	) -> Result<(String, Completion), BackendError> {
+		let mut text = String::new();
+		let completion = self
+			.start(task_name, request, self.clone())?
+			.complete_cancellable(prompt, cancellation, |r| match r {
+				InferenceResponse::InferredToken(t) => {
+					let feedback = on_token(&t);
+					text += &t;
+					Ok(feedback)
+				}
+				_ => Ok(InferenceFeedback::Continue),
+			})?;
+		Ok((text, completion))
+	}
+
+	/// Summarize a document that may be larger than the context window of the model of a task. The document is split into
+	/// parts that are each completed using the task (so its prefix and postfix should ask for a summary). The summaries
+	/// are then joined and summarized again in the same way, until a round results in a single summary. `on_progress` is
+	/// called before each completion. Completions are performed one after the other.
+	#[instrument(level = "info", skip(self, text, on_progress), fields(n_chars = text.len()))]
+	pub fn summarize(
+		self: &Arc<Self>,
+		task_name: &str,
+		text: &str,
+		mut on_progress: impl FnMut(&SummaryProgress),
+	) -> Result<SummaryResponse, BackendError> {
+		let task_config = self.task(task_name).ok_or_else(|| BackendError::TaskNotFound(task_name.to_string()))?;
+		let summarize = &task_config.summarize;
+		if text.trim().is_empty() {
+			return Err(BackendError::InvalidDocument);
+		}
+
+		let model = self.model(&task_config.model)?;
+		let vocab = model.tokenizer();
+		let separator_tokens: Vec<TokenId> = summarize
+			.chunk_separators
+			.iter()
+			.map(|s| {
+				let tokens = vocab.tokenize(s, false)?;
+				if tokens.len() != 1 {
+					return Err(BackendError::InvalidChunkSeparator(s.clone()));
+				}
+				Ok(tokens[0].1)
+			})
+			.collect::<Result<Vec<TokenId>, BackendError>>()?;
+		let chunk_tokens = summarize.chunk_tokens.unwrap_or(model.context_size() / 2).max(1);
+
+		let mut levels: Vec<SummaryLevel> = vec![];
+		let mut n_chunks = 0;
+		let mut spent = 0;
+		let mut input = text.to_string();
+		loop {
+			let tokens = vocab.tokenize(&input, false)?;
+			if spent + tokens.len() > summarize.max_total_tokens {
+				return Err(BackendError::SummaryLimitExceeded(format!(
+					"more than {} tokens would be needed",
+					summarize.max_total_tokens
+				)));
+			}
+			let chunks: Vec<String> = hierarchically_chunk(tokens, &separator_tokens, chunk_tokens)
+				.into_iter()
+				.map(|chunk| String::from_utf8_lossy(&chunk.into_iter().flat_map(|(bytes, _)| bytes).collect::<Vec<u8>>()).into_owned())
+				.filter(|chunk| !chunk.trim().is_empty())
+				.collect();
+			if levels.is_empty() {
+				n_chunks = chunks.len();
+			}
+
+			let mut level = SummaryLevel {
+				chunks: chunks.len(),
+				usage: TokenUsage::default(),
+			};
+			let mut summaries = Vec::with_capacity(chunks.len());
+			for (index, chunk) in chunks.iter().enumerate() {
+				on_progress(&SummaryProgress {
+					level: levels.len(),
+					chunk: index,
+					chunks: chunks.len(),
+				});
+				let (summary, completion) = self.complete(task_name, &SessionRequest::default(), &PromptRequest::new(chunk.as_str()), |_| {
+					InferenceFeedback::Continue
+				})?;
+				level.usage.add(&completion.usage);
+				spent += completion.usage.total();
+				if spent > summarize.max_total_tokens {
+					return Err(BackendError::SummaryLimitExceeded(format!(
+						"more than {} tokens were used",
+						summarize.max_total_tokens
+					)));
+				}
+				summaries.push(summary.trim().to_string());
+			}
+			levels.push(level);
+
+			if summaries.len() <= 1 {
+				return Ok(SummaryResponse {
+					text: summaries.pop().unwrap_or_default(),
+					chunks: n_chunks,
+					levels,
+				});
+			}
+			if levels.len() >= summarize.max_levels {
+				return Err(BackendError::SummaryLimitExceeded(format!(
+					"{} summaries remain after {} rounds",
+					summaries.len(),
+					levels.len()
+				)));
+			}
+			input = summaries.join("\n\n");
+		}
+	}
+}
+
+impl Backend {
+	/// Check the parts of the configuration that can only be verified using the loaded models (i.e. their tokenizers).
+	/// Returns all problems found.
+	pub fn check(&self) -> Vec<ConfigProblem> {
+		let mut problems = vec![];
+		let models = self.models.read().unwrap();
+		let is_single_token = |model_name: &str, s: &str| -> bool {
+			models
+				.get(model_name)
+				.is_some_and(|loaded| loaded.model.tokenizer().tokenize(s, false).is_ok_and(|tokens| tokens.len() == 1))
+		};
+
+		for (task_name, task_config) in self.tasks.read().unwrap().iter() {
+			for token in task_config.eot_token.iter().flat_map(|t| t.tokens()) {
+				if !is_single_token(&task_config.model, token) {
+					problems.push(ConfigProblem::new(
+						format!("tasks.{task_name}"),
+						format!("end-of-text token '{token}' does not correspond to exactly one token"),
+					));
+				}
+			}
+		}
+
+		for (memory_name, memory_config) in &self.config.memories {
+			for separator in memory_config.chunk_separators.iter().chain(memory_config.post_filter.iter()) {
+				if !is_single_token(&memory_config.embedding_model, separator) {
+					problems.push(ConfigProblem::new(
+						format!("memories.{memory_name}"),
+						format!("separator/filter '{separator}' does not correspond to exactly one token"),
+					));
+				}
+			}
+		}
+
+		problems
+	}
 }
 
 impl BackendStats {
-	pub fn add(&self, task_name: &str, stats: &InferenceStats, n_threads: usize) {
+	pub fn new(soft_session_limit: Option<usize>) -> BackendStats {
+		BackendStats {
+			task_stats: Mutex::new(HashMap::new()),
+			model_stats: Mutex::new(HashMap::new()),
+			sessions: Arc::new(Gauge::new("sessions", soft_session_limit)),
+			model_sessions: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn add(&self, task_name: &str, model_name: &str, completion: &Completion, n_threads: usize, n_batch: usize) {
 		let mut ts = self.task_stats.lock().unwrap();
-		if let Some(task_stats) = ts.get_mut(task_name) {
-			task_stats.add_cycle(stats, n_threads);
-		} else {
-			let mut task_stats = TaskStats::default();
-			task_stats.add_cycle(stats, n_threads);
-			ts.insert(task_name.to_string(), task_stats);
+		ts.entry(task_name.to_string()).or_default().add_cycle(completion, n_threads, n_batch);
+		drop(ts);
+
+		let mut ms = self.model_stats.lock().unwrap();
+		ms.entry(model_name.to_string()).or_default().add_cycle(completion, n_threads, n_batch);
+	}
+
+	pub fn add_embedding(&self, model_name: &str, n_tokens: usize, duration: Duration) {
+		let mut ms = self.model_stats.lock().unwrap();
+		ms.entry(model_name.to_string()).or_default().add_embedding(n_tokens, duration);
+	}
+
+	pub fn add_embedding_cache_lookup(&self, model_name: &str, hit: bool) {
+		let mut ms = self.model_stats.lock().unwrap();
+		ms.entry(model_name.to_string()).or_default().add_embedding_cache_lookup(hit);
+	}
+
+	/// Count a session for a task with a prelude that did (`hit`) or did not start from a cached prelude snapshot
+	pub fn add_prelude_cache_lookup(&self, task_name: &str, model_name: &str, hit: bool) {
+		let mut ts = self.task_stats.lock().unwrap();
+		ts.entry(task_name.to_string()).or_default().add_prelude_cache_lookup(hit);
+		drop(ts);
+
+		let mut ms = self.model_stats.lock().unwrap();
+		ms.entry(model_name.to_string()).or_default().generation.add_prelude_cache_lookup(hit);
+	}
+
+	/// Returns the gauge counting the active sessions for a model
+	pub(crate) fn model_sessions(&self, model_name: &str) -> Arc<Gauge> {
+		let mut gauges = self.model_sessions.lock().unwrap();
+		gauges
+			.entry(model_name.to_string())
+			.or_insert_with(|| Arc::new(Gauge::new("model_sessions", None)))
+			.clone()
+	}
+
+	/// Returns statistics per model, including the number of currently active sessions
+	pub fn model_stats(&self) -> HashMap<String, ModelStats> {
+		let mut model_stats = self.model_stats.lock().unwrap().clone();
+		for (model_name, gauge) in self.model_sessions.lock().unwrap().iter() {
+			model_stats.entry(model_name.clone()).or_default().set_sessions(gauge.get());
 		}
+		model_stats
 	}
 }
 
 impl Default for BackendStats {
 	fn default() -> Self {
-		BackendStats {
-			task_stats: Mutex::new(HashMap::new()),
-		}
+		BackendStats::new(None)
+	}
+}
+
+/// Returns the cosine similarity of two vectors of the same length, or None when either is zero (or not finite)
+fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+	let (mut dot, mut norm_a, mut norm_b) = (0.0f64, 0.0f64, 0.0f64);
+	for (x, y) in a.iter().zip(b) {
+		let (x, y) = (*x as f64, *y as f64);
+		dot += x * y;
+		norm_a += x * x;
+		norm_b += y * y;
+	}
+	let norms = norm_a.sqrt() * norm_b.sqrt();
+	if norms == 0.0 || !norms.is_finite() {
+		return None;
+	}
+	Some((dot / norms).clamp(-1.0, 1.0) as f32)
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		path::Path,
+		sync::{Arc, Mutex},
+	};
+
+	use sha2::{Digest, Sha256};
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::TcpListener,
+	};
+
+	use crate::config::{ModelArchitecture, ModelConfig};
+
+	use super::{architecture_name, cosine_similarity, quantization_from_file_name, sha256_file, Backend, ModelLoadOptions, HASH_BLOCK_SIZE};
+
+	#[test]
+	fn test_cosine_similarity() {
+		let a = [0.3, -1.2, 4.5];
+		assert!((cosine_similarity(&a, &a).unwrap() - 1.0).abs() < 1e-6);
+		assert!((cosine_similarity(&a, &[-0.3, 1.2, -4.5]).unwrap() + 1.0).abs() < 1e-6);
+		assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 2.0]), Some(0.0));
+		assert_eq!(cosine_similarity(&a, &[0.0, 0.0, 0.0]), None);
+	}
+
+	#[tokio::test]
+	async fn test_sha256_file() {
+		let path = std::env::temp_dir().join(format!("poly-backend-hash-{}.bin", std::process::id()));
+		std::fs::write(&path, b"abc").unwrap();
+		assert_eq!(
+			sha256_file(&path, |_| {}).await.unwrap(),
+			"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+		);
+
+		// Progress is reported while reading the file
+		std::fs::write(&path, vec![0u8; HASH_BLOCK_SIZE * 2 + 1]).unwrap();
+		let fractions = Mutex::new(vec![]);
+		sha256_file(&path, |fraction| fractions.lock().unwrap().push(fraction)).await.unwrap();
+		let fractions = fractions.into_inner().unwrap();
+		assert!(fractions.len() >= 3);
+		assert!(fractions.windows(2).all(|w| w[0] < w[1]));
+		assert_eq!(fractions.last(), Some(&1.0));
+
+		assert!(sha256_file(&path.with_extension("missing"), |_| {}).await.is_err());
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	/// Serve `content` over HTTP, honouring requests for the remainder of the file from an offset. The first `truncate`
+	/// responses end after half of the promised bytes. Returns the URL of the file and the offsets requested.
+	async fn serve_file(content: Vec<u8>, mut truncate: usize) -> (String, Arc<Mutex<Vec<Option<usize>>>>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/model.bin", listener.local_addr().unwrap());
+		let offsets = Arc::new(Mutex::new(vec![]));
+		let requested = offsets.clone();
+		tokio::spawn(async move {
+			loop {
+				let (mut stream, _) = listener.accept().await.unwrap();
+				let mut head = vec![];
+				while !head.ends_with(b"\r\n\r\n") {
+					head.push(stream.read_u8().await.unwrap());
+				}
+				let head = String::from_utf8_lossy(&head).to_lowercase();
+				let offset = head
+					.lines()
+					.find_map(|line| line.strip_prefix("range: bytes=")?.strip_suffix('-')?.parse::<usize>().ok());
+				requested.lock().unwrap().push(offset);
+
+				let body = &content[offset.unwrap_or(0)..];
+				let status = if offset.is_some() { "206 Partial Content" } else { "200 OK" };
+				let sent = if truncate > 0 {
+					truncate -= 1;
+					&body[..body.len() / 2]
+				} else {
+					body
+				};
+				let head = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+				stream.write_all(head.as_bytes()).await.unwrap();
+				stream.write_all(sent).await.unwrap();
+			}
+		});
+		(url, offsets)
+	}
+
+	fn download_config(url: &str, content: &[u8]) -> ModelConfig {
+		let sha256 = format!("{:x}", Sha256::digest(content));
+		toml::from_str(&format!("architecture = \"gpt2\"\nurl = \"{url}\"\nsha256 = \"{sha256}\"")).unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_download_resume() {
+		let dir = std::env::temp_dir().join(format!("poly-backend-download-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("model.bin");
+		let partial_path = dir.join("model.download");
+		let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+		let (url, offsets) = serve_file(content.clone(), 1).await;
+		let config = download_config(&url, &content);
+
+		// The first download is cut off, leaving a partial file
+		assert!(Backend::ensure_model_file("gpt2", &config, &path, None, |_| {}).await.is_err());
+		assert!(!path.exists());
+		let partial = std::fs::metadata(&partial_path).unwrap().len() as usize;
+		assert!(partial < content.len());
+
+		// The next attempt only requests the rest of the file
+		Backend::ensure_model_file("gpt2", &config, &path, None, |_| {}).await.unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), content);
+		assert!(!partial_path.exists());
+		assert_eq!(*offsets.lock().unwrap(), vec![None, Some(partial)]);
+
+		// A file that is present and has the right checksum is not downloaded again
+		Backend::ensure_model_file("gpt2", &config, &path, None, |_| {}).await.unwrap();
+		assert_eq!(offsets.lock().unwrap().len(), 2);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_download_checksum_mismatch() {
+		let dir = std::env::temp_dir().join(format!("poly-backend-checksum-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("model.bin");
+		let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+		let (url, offsets) = serve_file(content.clone(), 0).await;
+
+		// A downloaded file that does not have the expected checksum is rejected and removed
+		let config = download_config(&url, b"other content");
+		let error = Backend::ensure_model_file("gpt2", &config, &path, None, |_| {}).await.unwrap_err();
+		assert!(error.contains("checksum mismatch"), "{error}");
+		assert!(!path.exists());
+		assert!(!dir.join("model.download").exists());
+
+		// A file that is present but has the wrong checksum is downloaded again
+		std::fs::write(&path, b"corrupt").unwrap();
+		Backend::ensure_model_file("gpt2", &download_config(&url, &content), &path, None, |_| {})
+			.await
+			.unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), content);
+		assert_eq!(offsets.lock().unwrap().len(), 2);
+
+		// Without a URL, it can only be rejected
+		std::fs::write(&path, b"corrupt").unwrap();
+		let mut config = download_config(&url, &content);
+		config.url = None;
+		assert!(Backend::ensure_model_file("gpt2", &config, &path, None, |_| {}).await.is_err());
+		assert!(path.exists());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_model_load_options() {
+		let dir = std::env::temp_dir().join(format!("poly-backend-models-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("model.bin");
+		std::fs::write(&path, b"").unwrap();
+
+		let model_config = |config: &str| -> ModelConfig { toml::from_str(&format!("architecture = \"llama\"\n{config}")).unwrap() };
+		let chat = ModelLoadOptions::new(&path, &model_config("threads_per_session = 8"));
+
+		// Different paths to the same file, and settings that are applied per session, do not matter
+		let embed = ModelLoadOptions::new(&dir.join(".").join("model.bin"), &model_config("threads_per_session = 2\nbatch_size = 4"));
+		assert_eq!(chat, embed);
+		assert_eq!(chat, ModelLoadOptions::new(&path, &model_config("gpu_layers = 10")));
+
+		// Settings that affect how the model is loaded do
+		assert_ne!(chat, ModelLoadOptions::new(&path, &model_config("context_size = 2048")));
+		assert_ne!(chat, ModelLoadOptions::new(&path, &model_config("use_gpu = true")));
+		assert_ne!(
+			ModelLoadOptions::new(&path, &model_config("use_gpu = true")),
+			ModelLoadOptions::new(&path, &model_config("use_gpu = true\ngpu_layers = 10"))
+		);
+		assert_ne!(chat, ModelLoadOptions::new(&dir.join("other.bin"), &model_config("")));
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_model_metadata() {
+		assert_eq!(architecture_name(ModelArchitecture::GptNeoX), "gptneox");
+		assert_eq!(architecture_name(ModelArchitecture::Llama), "llama");
+
+		let quantization = |path: &str| quantization_from_file_name(Path::new(path));
+		assert_eq!(quantization("models/llama-2-7b.ggmlv3.q4_K_M.bin").as_deref(), Some("q4_k_m"));
+		assert_eq!(quantization("pythia-160m-q4_0.bin").as_deref(), Some("q4_0"));
+		assert_eq!(quantization("ggml-model-f16.bin").as_deref(), Some("f16"));
+		assert_eq!(quantization("open-llama-3b-q8.bin").as_deref(), Some("q8"));
+		assert_eq!(quantization("gpt2-117M.bin"), None);
+		assert_eq!(quantization("models/qwen.bin"), None);
 	}
 }
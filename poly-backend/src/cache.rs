@@ -0,0 +1,165 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use sha2::{Digest, Sha256};
+
+/// Default number of embeddings held by the [`EmbeddingCache`]
+pub const DEFAULT_EMBEDDING_CACHE_SIZE: usize = 256;
+
+/// Identifies an embedding: the name of the model and the SHA-256 hash of the normalized prompt
+type EmbeddingKey = (String, [u8; 32]);
+
+struct CachedEmbedding {
+	embedding: Vec<f32>,
+
+	/// Value of [`EmbeddingCacheState::clock`] when the embedding was last used
+	last_used: u64,
+}
+
+#[derive(Default)]
+struct EmbeddingCacheState {
+	entries: HashMap<EmbeddingKey, CachedEmbedding>,
+	clock: u64,
+}
+
+/// Holds the most recently used embeddings, so that embeddings of the same prompt (e.g. for recalling from and storing
+/// to memory) are only calculated once. When full, the least recently used embedding is evicted. Models are not
+/// reloaded while a backend lives, so cached embeddings never become stale; a backend with other models starts with an
+/// empty cache.
+pub struct EmbeddingCache {
+	capacity: usize,
+	state: Mutex<EmbeddingCacheState>,
+}
+
+impl EmbeddingCache {
+	/// Create a cache holding at most `capacity` embeddings (none when zero)
+	pub fn new(capacity: usize) -> EmbeddingCache {
+		EmbeddingCache {
+			capacity,
+			state: Mutex::new(EmbeddingCacheState::default()),
+		}
+	}
+
+	/// Prompts that only differ in surrounding whitespace or the length of whitespace runs share an embedding
+	fn key(model_name: &str, prompt: &str) -> EmbeddingKey {
+		let mut hasher = Sha256::new();
+		for (index, word) in prompt.split_whitespace().enumerate() {
+			if index > 0 {
+				hasher.update(b" ");
+			}
+			hasher.update(word.as_bytes());
+		}
+		(model_name.to_string(), hasher.finalize().into())
+	}
+
+	/// Returns the cached embedding of the prompt for the model, or calculates it using `calculate` and caches the
+	/// result. The boolean is true when the embedding was found in the cache. The cache is not locked while calculating,
+	/// so an embedding requested concurrently may be calculated more than once.
+	pub fn get_or_insert_with<E>(
+		&self,
+		model_name: &str,
+		prompt: &str,
+		calculate: impl FnOnce() -> Result<Vec<f32>, E>,
+	) -> Result<(Vec<f32>, bool), E> {
+		if self.capacity == 0 {
+			return Ok((calculate()?, false));
+		}
+
+		let key = Self::key(model_name, prompt);
+		{
+			let mut state = self.state.lock().unwrap();
+			state.clock += 1;
+			let clock = state.clock;
+			if let Some(cached) = state.entries.get_mut(&key) {
+				cached.last_used = clock;
+				return Ok((cached.embedding.clone(), true));
+			}
+		}
+
+		let embedding = calculate()?;
+		let mut state = self.state.lock().unwrap();
+		if !state.entries.contains_key(&key) && state.entries.len() >= self.capacity {
+			if let Some(oldest) = state
+				.entries
+				.iter()
+				.min_by_key(|(_, cached)| cached.last_used)
+				.map(|(key, _)| key.clone())
+			{
+				state.entries.remove(&oldest);
+			}
+		}
+		let last_used = state.clock;
+		state.entries.insert(
+			key,
+			CachedEmbedding {
+				embedding: embedding.clone(),
+				last_used,
+			},
+		);
+		Ok((embedding, false))
+	}
+
+	/// Number of embeddings currently held
+	pub fn len(&self) -> usize {
+		self.state.lock().unwrap().entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{cell::Cell, convert::Infallible};
+
+	use super::EmbeddingCache;
+
+	#[test]
+	fn test_embedding_cache() {
+		let cache = EmbeddingCache::new(2);
+		let calculated = Cell::new(0);
+		let embed = |model: &str, prompt: &str| {
+			cache
+				.get_or_insert_with(model, prompt, || {
+					calculated.set(calculated.get() + 1);
+					Ok::<_, Infallible>(vec![prompt.len() as f32])
+				})
+				.unwrap()
+		};
+
+		assert_eq!(embed("model", "Hello world"), (vec![11.0], false));
+		assert_eq!(calculated.get(), 1);
+
+		// The second identical request does not touch the model
+		assert_eq!(embed("model", "Hello world"), (vec![11.0], true));
+		assert_eq!(embed("model", "  Hello \n world"), (vec![11.0], true));
+		assert_eq!(calculated.get(), 1);
+
+		// Embeddings are cached per model
+		assert_eq!(embed("other", "Hello world"), (vec![11.0], false));
+		assert_eq!(calculated.get(), 2);
+		assert_eq!(cache.len(), 2);
+
+		// The least recently used embedding is evicted
+		embed("model", "Hello world");
+		embed("model", "Goodbye");
+		assert_eq!(cache.len(), 2);
+		assert!(embed("model", "Hello world").1);
+		assert!(!embed("other", "Hello world").1);
+		assert_eq!(calculated.get(), 4);
+
+		// Failures are not cached
+		assert!(cache.get_or_insert_with("model", "fails", || Err(())).is_err());
+		assert!(!embed("model", "fails").1);
+	}
+
+	#[test]
+	fn test_embedding_cache_disabled() {
+		let cache = EmbeddingCache::new(0);
+		let (_, hit) = cache.get_or_insert_with("model", "Hello", || Ok::<_, Infallible>(vec![1.0])).unwrap();
+		assert!(!hit);
+		let (_, hit) = cache.get_or_insert_with("model", "Hello", || Ok::<_, Infallible>(vec![1.0])).unwrap();
+		assert!(!hit);
+		assert!(cache.is_empty());
+	}
+}
@@ -1,3 +1,4 @@
+use directories::ProjectDirs;
 use llm::samplers::{
 	llm_samplers::{
 		configure::{SamplerChainBuilder, SamplerSlot},
@@ -7,11 +8,28 @@ use llm::samplers::{
 	ConfiguredSamplers,
 };
 pub use llm::ModelArchitecture;
-use poly_bias::json::JsonSchema;
-use serde::{Deserialize, Deserializer, Serialize};
-use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use poly_bias::{json::JsonSchema, regex::RegexBiaser};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::{
+	collections::{HashMap, HashSet},
+	fmt::Display,
+	fs::File,
+	io::{BufReader, Read},
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+use thiserror::Error;
 
-use crate::memory::MemoryStoreConfig;
+use crate::{
+	backend::CACHE_MODELS_DIR,
+	memory::{Capacity, EvictionPolicy, MemoryStoreConfig},
+	sequence::MatchOptions,
+	template::{PromptTemplate, INPUT_VARIABLE},
+	types::{BackendError, ChatRole, Priority, PromptRequest, RerankMethod},
+	validation,
+};
 
 fn architecture_from_str<'de, D>(deserializer: D) -> Result<ModelArchitecture, D::Error>
 where
@@ -29,7 +47,7 @@ where
 	}
 }
 
-#[derive(Deserialize, Debug, Clone, Serialize)]
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq)]
 pub struct MemoryConfig {
 	/// The type of memory to be constructed
 	pub store: MemoryStoreConfig,
@@ -56,6 +74,24 @@ pub struct MemoryConfig {
 	/// Remove the following tokens after chunking (strings must refer to single tokens)
 	#[serde(default = "default_post_filter")]
 	pub post_filter: Vec<String>,
+
+	/// Maximum number of chunks the memory may hold (unlimited when not set)
+	#[serde(default)]
+	pub max_items: Option<usize>,
+
+	/// What to do when a chunk is stored while the memory holds `max_items` chunks
+	#[serde(default)]
+	pub eviction: EvictionPolicy,
+}
+
+impl MemoryConfig {
+	/// The limit on the number of chunks in the memory, if any
+	pub fn capacity(&self) -> Option<Capacity> {
+		self.max_items.map(|max_items| Capacity {
+			max_items,
+			eviction: self.eviction,
+		})
+	}
 }
 
 fn default_pre_filter() -> Vec<String> {
@@ -70,7 +106,7 @@ fn default_post_filter() -> Vec<String> {
 	vec!["\n".to_string()]
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct ModelConfig {
 	/// The model architecture type
 	#[serde(deserialize_with = "architecture_from_str")]
@@ -80,15 +116,30 @@ pub struct ModelConfig {
 	pub model_path: Option<PathBuf>,
 
 	/// URL where to download the model from. This is used in case no file is found at `model_path` or when `model_path`
-	///  is not specified (in which case a cache location will be used)
+	///  is not specified (in which case a cache location will be used). Files on Hugging Face can be referred to as
+	/// `hf://org/repo/file.bin`.
+	#[serde(alias = "model_url")]
 	pub url: Option<String>,
 
+	/// Expected SHA-256 checksum of the model file (hex). The file is verified each time the model is loaded and after
+	/// it is downloaded. When the file does not match, it is downloaded again (if a URL is configured); otherwise the
+	/// model is not loaded. Use `llmd check --hash` to calculate the checksums of configured models.
+	pub sha256: Option<String>,
+
+	/// Path to a Hugging Face `tokenizer.json` to use instead of the vocabulary embedded in the model file (for
+	/// conversions with a broken embedded vocabulary). The tokenizer must have as many tokens as the model.
+	pub tokenizer_path: Option<PathBuf>,
+
+	/// Hugging Face repository (e.g. `org/repo`) to download the `tokenizer.json` to use instead of the vocabulary embedded
+	/// in the model file from. The file is stored in the cache. Cannot be combined with `tokenizer_path`.
+	pub tokenizer_repo: Option<String>,
+
 	/// The [LoRA](https://arxiv.org/abs/2106.09685) adapters to use when loading the model. Note that these cannot currently
 	/// be downloaded automatically on-demand.
 	pub lora_adapters: Option<Vec<PathBuf>>,
 
-	/// Threads per session
-	#[serde(default = "default_threads_per_session")]
+	/// Threads per session (can be overridden per task)
+	#[serde(default = "default_threads_per_session", alias = "n_threads")]
 	pub threads_per_session: usize,
 
 	/// Context size
@@ -110,9 +161,18 @@ pub struct ModelConfig {
 	/// However, you will be fundamentally limited by your machine's ability to evaluate
 	/// the transformer model, so increasing the batch size will not always help.
 	///
-	/// A reasonable default value is 8.
-	#[serde(default = "default_batch_size")]
+	/// A reasonable default value is 8. Can be overridden per task.
+	#[serde(default = "default_batch_size", alias = "n_batch")]
 	pub batch_size: usize,
+
+	/// Number of prompt tokens that are fed to a session at once when performing a completion. Between chunks, the
+	/// completion can be cancelled and progress is reported.
+	#[serde(default = "default_prompt_chunk_size")]
+	pub prompt_chunk_size: usize,
+
+	/// Maximum number of texts a text may be compared with in a single similarity request, as each is embedded separately
+	#[serde(default = "default_max_similarity_texts")]
+	pub max_similarity_texts: usize,
 }
 
 const fn default_use_gpu() -> bool {
@@ -135,7 +195,7 @@ fn default_chunk_separators() -> Vec<String> {
 	vec![String::from(" ")]
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum BiaserConfig {
 	/// Configure Biaser from JSON schema included directly in the configuration
@@ -143,9 +203,185 @@ pub enum BiaserConfig {
 
 	/// Configure Biaser using an external file containing a JSON schema (in JSON)
 	JsonSchemaFile(PathBuf),
+
+	/// Configure Biaser to only generate output that matches a regular expression as a whole (e.g. `\d{4}-\d{2}-\d{2}`
+	/// for a date)
+	Regex(String),
+
+	/// Configure Biaser to only generate one of the listed strings (e.g. the labels of a classification). Output that is
+	/// one of the choices ends as soon as it cannot be continued to another choice.
+	Choices(Vec<String>),
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Prompt for the `cross_scoring` rerank method, in which `{query}` and `{document}` are replaced
+const DEFAULT_RERANK_TEMPLATE: &str = "Query: {query}\nDocument: {document}\nIs the document relevant to the query? Answer yes or no.\nAnswer:";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RerankConfig {
+	/// How documents are scored: by the cosine similarity of their embedding with that of the query (`embedding`), or by
+	/// asking the model whether each document is relevant to the query (`cross_scoring`, slower but usually better)
+	pub method: RerankMethod,
+
+	/// Prompt asking the model whether a document is relevant to a query (for `cross_scoring`). `{query}` and
+	/// `{document}` are replaced by the query and the document.
+	pub template: String,
+
+	/// The answers to the prompt (each must consist of exactly one token). The score of a document is the probability of
+	/// the positive answer relative to that of the negative answer.
+	pub yes_token: String,
+	pub no_token: String,
+
+	/// Maximum number of documents in a request
+	pub max_documents: usize,
+
+	/// Maximum time a request may take (in milliseconds). Scoring stops with an error when this is exceeded.
+	pub budget_ms: u64,
+}
+
+impl Default for RerankConfig {
+	fn default() -> Self {
+		RerankConfig {
+			method: RerankMethod::Embedding,
+			template: String::from(DEFAULT_RERANK_TEMPLATE),
+			yes_token: String::from(" yes"),
+			no_token: String::from(" no"),
+			max_documents: 100,
+			budget_ms: 10_000,
+		}
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SummarizeConfig {
+	/// Maximum length of the parts a document is split into (in tokens). Each part is completed separately, so it must
+	/// fit in the context window together with the prefix, postfix and generated summary. When not set, half of the
+	/// context window of the model is used.
+	pub chunk_tokens: Option<usize>,
+
+	/// Separators to split documents at, in order of preference (each must consist of exactly one token)
+	pub chunk_separators: Vec<String>,
+
+	/// Maximum number of tokens (fed and generated) that the completions for a single document may use together
+	pub max_total_tokens: usize,
+
+	/// Maximum number of rounds of summarizing, including the first round over the parts of the document
+	pub max_levels: usize,
+}
+
+impl Default for SummarizeConfig {
+	fn default() -> Self {
+		SummarizeConfig {
+			chunk_tokens: None,
+			chunk_separators: vec![String::from("\n"), String::from(" ")],
+			max_total_tokens: 100_000,
+			max_levels: 5,
+		}
+	}
+}
+
+/// A tool that the model of a task can choose to call (see [`TaskConfig::tools`])
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ToolConfig {
+	pub name: String,
+
+	/// What the tool does, as explained to the model
+	pub description: String,
+
+	/// Schema of the arguments of the tool (must be an object)
+	pub arguments: JsonSchema,
+}
+
+/// Prompt listing the tools of a task, in which `{tools}` is replaced by a line for each tool
+const DEFAULT_TOOLS_TEMPLATE: &str = "You can use the following tools:\n{tools}\nTo use a tool, respond with a JSON object holding the \
+	name of the tool as \"tool\" and its arguments as \"arguments\". To respond without using a tool, respond with a JSON object \
+	holding the response as \"answer\".\n\n";
+
+/// Prompt passing the result of a tool call to the model, in which `{tool}` and `{result}` are replaced
+const DEFAULT_TOOL_RESULT_TEMPLATE: &str = "Result of tool {tool}: {result}";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ToolTemplates {
+	/// Text placed before the prelude of each session, in which `{tools}` is replaced by a line for each tool holding its
+	/// name, description and the schema of its arguments
+	pub list: String,
+
+	/// Prompt holding the result of a tool call, in which `{tool}` and `{result}` are replaced by the name of the tool and
+	/// the result
+	pub result: String,
+}
+
+impl Default for ToolTemplates {
+	fn default() -> Self {
+		ToolTemplates {
+			list: String::from(DEFAULT_TOOLS_TEMPLATE),
+			result: String::from(DEFAULT_TOOL_RESULT_TEMPLATE),
+		}
+	}
+}
+
+/// How the messages of a chat are rendered into a prompt (see [`TaskConfig::chat_template`]). Each message is placed
+/// between the prefix and the suffix for its role, e.g. `user_prefix = "<|im_start|>user\n"` and
+/// `user_suffix = "<|im_end|>\n"`. The prefixes and suffixes may contain private tokens.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ChatTemplate {
+	pub system_prefix: String,
+	pub system_suffix: String,
+	pub user_prefix: String,
+	pub user_suffix: String,
+
+	/// Also placed after the last message, so that the model continues with a reply
+	pub assistant_prefix: String,
+
+	/// Also placed after each reply of the model
+	pub assistant_suffix: String,
+}
+
+impl ChatTemplate {
+	/// The prefix and suffix for messages with a role
+	pub fn affixes(&self, role: ChatRole) -> (&str, &str) {
+		match role {
+			ChatRole::System => (&self.system_prefix, &self.system_suffix),
+			ChatRole::User => (&self.user_prefix, &self.user_suffix),
+			ChatRole::Assistant => (&self.assistant_prefix, &self.assistant_suffix),
+		}
+	}
+}
+
+/// Prompt asking the model to repair invalid output, in which `{errors}` is replaced by the validation errors
+const DEFAULT_REPAIR_TEMPLATE: &str = "The response above is invalid:\n{errors}\nRespond again, with output that is valid.\n";
+
+/// How the output of a task with a biaser is validated after each completion (see [`TaskConfig::validation`])
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ValidationConfig {
+	/// A JSON Schema (draft 2020-12) the output must conform to in addition to the schema of the biaser, for constraints
+	/// the biaser cannot enforce (e.g. formats or dependencies between keys)
+	pub schema: Option<serde_json::Value>,
+
+	/// Number of times the model is asked to repair invalid output before the completion fails. When larger than zero,
+	/// the output is held back until it is valid.
+	pub max_repair_attempts: usize,
+
+	/// Prompt asking the model to repair invalid output, in which `{errors}` is replaced by the validation errors (one
+	/// per line)
+	pub repair_template: String,
+}
+
+impl Default for ValidationConfig {
+	fn default() -> Self {
+		ValidationConfig {
+			schema: None,
+			max_repair_attempts: 0,
+			repair_template: String::from(DEFAULT_REPAIR_TEMPLATE),
+		}
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct TaskMemorizationConfig {
 	/// The memory to use
 	pub memory: String,
@@ -155,12 +391,20 @@ pub struct TaskMemorizationConfig {
 
 	/// How many items from the memory to retrieve
 	pub retrieve: Option<usize>,
+
+	/// Put how long ago each retrieved item was stored in front of it (e.g. "[3 days ago] ..."), so that the model can
+	/// tell recent items from old ones. Use `{now}` or `{today}` in the prefix to tell the model the current date.
+	#[serde(default)]
+	pub include_timestamps: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct TaskConfig {
 	pub model: String,
 
+	/// The profile (from `task_profiles`) this task inherits settings from
+	pub profile: Option<String>,
+
 	/// Text to start each conversation with
 	pub prelude: Option<String>,
 
@@ -170,12 +414,64 @@ pub struct TaskConfig {
 	/// Text to postfix each user input with
 	pub postfix: Option<String>,
 
-	/// Tokens that users should not be able to input as they are used for signalling
+	/// Template the prompt of each request is rendered with (before the prefix and postfix are added), e.g.
+	/// `"Summarize the following {language} text for a {audience}:\n{input}"`. `{input}` is replaced by the prompt of the
+	/// request and must occur in the template; the other variables are replaced by the `variables` of the request, all of
+	/// which must be given. Use `{{` and `}}` for literal braces. The text of the template may contain private tokens; the
+	/// values of variables may not.
+	pub prompt_template: Option<String>,
+
+	/// Maximum number of characters of the value of each variable of the prompt template (`{input}` excepted)
+	#[serde(default = "default_max_variable_chars")]
+	pub max_variable_chars: usize,
+
+	/// Tokens that users should not be able to input as they are used for signalling. These are also removed from the
+	/// output. A private token may correspond to several tokens (e.g. `"<|im_start|>"` for a model that has no such
+	/// token), in which case the sequence of tokens is not allowed in untrusted input.
 	pub private_tokens: Option<Vec<String>>,
 
-	/// Maximum number of tokens to be generated (when biaser is enabled: applies only to unbiased phase when bias_prompt is used)
+	/// Token that ends generation instead of the end-of-text token of the model, for fine-tunes that use a different one
+	/// (e.g. `"<|im_end|>"`). Can also be a list of alternatives, any of which ends generation. Each must correspond to
+	/// exactly one token.
+	pub eot_token: Option<EotTokenConfig>,
+
+	/// When the context window is full, drop the oldest half of the conversation (but never the prelude) instead of
+	/// ending generation with finish reason `context_full`
+	#[serde(default)]
+	pub slide_context: bool,
+
+	/// Maximum number of tokens to be generated. When a biaser is enabled, this also limits the biased output: when the
+	/// limit is reached, the biaser closes the output (finish reason `max_tokens`), or the completion fails when that takes
+	/// too many tokens (see `BackendError::TokenBudgetExceeded`). When bias_prompt is used, the limit
+	/// applies to the unbiased and the biased phase separately. Leave unset for unlimited output.
 	pub max_tokens: Option<usize>,
 
+	/// Maximum number of characters to generate; the output is cut off at the limit (not applied when a biaser is enabled)
+	pub max_chars: Option<usize>,
+
+	/// Maximum number of lines to generate; the output ends before the line break that would start the next line (not
+	/// applied when a biaser is enabled)
+	pub max_lines: Option<usize>,
+
+	/// Maximum number of tokens of a prompt, including the prefix, postfix and recalled memories. Longer prompts are
+	/// rejected, or truncated when the request asks for it. Leave unset to only limit prompts by the context window.
+	pub max_prompt_tokens: Option<usize>,
+
+	/// Maximum duration of a completion in seconds (fractions allowed), including feeding the prompt. When it is reached,
+	/// generation ends with finish reason `timeout` and the output generated until then is returned. Leave unset for no
+	/// limit.
+	pub max_duration_secs: Option<f64>,
+
+	/// Priority of requests for this task while they wait to be serviced (`low`, `normal` or `high`). Requests may ask
+	/// for a lower priority, and for a higher one up to the maximum their key allows.
+	#[serde(default)]
+	pub priority: Priority,
+
+	/// Whether the task can be used without authenticating, even when the server is not public. Such requests are made
+	/// as user `anonymous`, who cannot use anything but the public tasks.
+	#[serde(default)]
+	pub public: bool,
+
 	/// Biaser: the biaser to apply to the output (if any)
 	pub biaser: Option<BiaserConfig>,
 
@@ -183,32 +479,225 @@ pub struct TaskConfig {
 	/// a biased response is generated.
 	pub bias_prompt: Option<String>,
 
-	/// Sequences that when they occur end generation (just like end-of-text token)
+	/// How biased output is validated once it is complete. Output that was closed by the biaser because max_tokens was
+	/// reached is not repaired.
+	#[serde(default)]
+	pub validation: ValidationConfig,
+
+	/// Sequences that when they occur end generation (just like end-of-text token). When a biaser is enabled, the biaser
+	/// closes the output first, and the matched text is not removed from the output.
 	#[serde(default = "default_stop_sequences")]
-	pub stop_sequences: Vec<String>,
+	pub stop_sequences: Vec<StopSequenceConfig>,
+
+	/// Phrases the output must not contain, regardless of how they are tokenized. Text that may be the start of a phrase
+	/// is withheld until it is known whether it is. Cannot be combined with a biaser or tools.
+	#[serde(default)]
+	pub banned_phrases: Vec<BannedPhraseConfig>,
+
+	/// Text that replaces a banned phrase that is censored
+	#[serde(default = "default_banned_phrase_placeholder")]
+	pub banned_phrase_placeholder: String,
+
+	/// Maximum number of times generation is rewound for banned phrases with action `retry` in a single completion, after
+	/// which such phrases are censored
+	#[serde(default = "default_banned_phrase_retries")]
+	pub banned_phrase_retries: usize,
 
 	/// Sampler configuration
 	#[serde(flatten)]
 	pub sampler: SamplerConfig,
 
+	/// Seed for the random number generator used for sampling, which makes the output reproducible for the same prompt,
+	/// model and configuration (when not set, a random seed is used for each completion)
+	pub seed: Option<u64>,
+
 	/// Memorization config
 	pub memorization: Option<TaskMemorizationConfig>,
+
+	/// Directory to write a trace of each completion to (see [`crate::trace::CompletionTrace`]): the parameters, every
+	/// token fed and generated with timing, biaser decisions, recalled memories and the finish reason. Traces are
+	/// appended as lines of JSON to a file per task per day. Private tokens are redacted.
+	pub trace_dir: Option<PathBuf>,
+
+	/// How documents are reranked for a query
+	#[serde(default)]
+	pub rerank: RerankConfig,
+
+	/// Tools the model can choose to call. When set, the output of the model is constrained to either a call of one of
+	/// the tools or an answer (see [`crate::types::ToolCall`]), and the tools are listed at the start of each session.
+	/// Cannot be combined with a biaser.
+	#[serde(default)]
+	pub tools: Vec<ToolConfig>,
+
+	/// Prompts used to list the tools and to pass the result of a tool call to the model
+	#[serde(default)]
+	pub tool_templates: ToolTemplates,
+
+	/// Render prompts as a chat: the messages of each request (or else its prompt, as a user message) are placed between
+	/// the prefixes and suffixes for their roles, followed by the assistant prefix. Each reply of the model is closed
+	/// with the assistant suffix, so that a session (such as a chat on a WebSocket) can continue with the next prompt.
+	pub chat_template: Option<ChatTemplate>,
+
+	/// How documents larger than the context window are summarized
+	#[serde(default)]
+	pub summarize: SummarizeConfig,
+
+	/// Threads per session (when not set, the setting of the model is used)
+	#[serde(alias = "n_threads")]
+	pub threads_per_session: Option<usize>,
+
+	/// Batch size for prompt ingestion (when not set, the setting of the model is used)
+	#[serde(alias = "n_batch")]
+	pub batch_size: Option<usize>,
+}
+
+/// End-of-text tokens of a task: a single token, or a list of alternatives (e.g. `["<|im_end|>", "</s>"]`)
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum EotTokenConfig {
+	One(String),
+	Many(Vec<String>),
+}
+
+impl EotTokenConfig {
+	pub fn tokens(&self) -> &[String] {
+		match self {
+			EotTokenConfig::One(token) => std::slice::from_ref(token),
+			EotTokenConfig::Many(tokens) => tokens,
+		}
+	}
+}
+
+/// A stop sequence: either just its text (matched exactly) or a table with matching options, e.g.
+/// `{ text = "User:", case_insensitive = true }`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum StopSequenceConfig {
+	Text(String),
+	Options {
+		text: String,
+
+		/// Match regardless of case (e.g. "USER:" matches "User:")
+		#[serde(default)]
+		case_insensitive: bool,
+
+		/// Treat any run of whitespace (or none) as equivalent (e.g. "User :" matches "User:")
+		#[serde(default)]
+		normalize_whitespace: bool,
+	},
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// What to do when a banned phrase is generated
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BannedPhraseAction {
+	/// Replace the phrase by the placeholder and continue
+	#[default]
+	Censor,
+
+	/// End generation before the phrase (finish reason `banned_phrase`)
+	Stop,
+
+	/// Rewind generation to the token that started the phrase and generate again, never choosing that token at that
+	/// position
+	Retry,
+}
+
+/// A banned phrase: either just its text or a table with options, e.g. `{ text = "Project Falcon", action = "retry" }`.
+/// Unlike stop sequences, banned phrases are matched regardless of case unless `case_insensitive = false`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum BannedPhraseConfig {
+	Text(String),
+	Options {
+		text: String,
+
+		#[serde(default = "default_case_insensitive")]
+		case_insensitive: bool,
+
+		/// Treat any run of whitespace (or none) as equivalent
+		#[serde(default)]
+		normalize_whitespace: bool,
+
+		#[serde(default)]
+		action: BannedPhraseAction,
+	},
+}
+
+impl BannedPhraseConfig {
+	pub fn text(&self) -> &str {
+		match self {
+			BannedPhraseConfig::Text(text) | BannedPhraseConfig::Options { text, .. } => text,
+		}
+	}
+
+	pub fn options(&self) -> MatchOptions {
+		match *self {
+			BannedPhraseConfig::Text(_) => MatchOptions {
+				case_insensitive: true,
+				normalize_whitespace: false,
+			},
+			BannedPhraseConfig::Options {
+				case_insensitive,
+				normalize_whitespace,
+				..
+			} => MatchOptions {
+				case_insensitive,
+				normalize_whitespace,
+			},
+		}
+	}
+
+	pub fn action(&self) -> BannedPhraseAction {
+		match *self {
+			BannedPhraseConfig::Text(_) => BannedPhraseAction::default(),
+			BannedPhraseConfig::Options { action, .. } => action,
+		}
+	}
+}
+
+impl StopSequenceConfig {
+	pub fn text(&self) -> &str {
+		match self {
+			StopSequenceConfig::Text(text) | StopSequenceConfig::Options { text, .. } => text,
+		}
+	}
+
+	pub fn options(&self) -> MatchOptions {
+		match *self {
+			StopSequenceConfig::Text(_) => MatchOptions::default(),
+			StopSequenceConfig::Options {
+				case_insensitive,
+				normalize_whitespace,
+				..
+			} => MatchOptions {
+				case_insensitive,
+				normalize_whitespace,
+			},
+		}
+	}
+}
+
+impl PartialEq<&str> for StopSequenceConfig {
+	fn eq(&self, other: &&str) -> bool {
+		self.options() == MatchOptions::default() && self.text() == *other
+	}
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum SamplerConfig {
 	Advanced(AdvancedSamplerConfig),
 	Standard(StandardSamplerConfig),
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct AdvancedSamplerConfig {
 	// Samplers to apply
 	pub samplers: Vec<String>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct StandardSamplerConfig {
 	/// The top K words by score are kept during sampling.
 	#[serde(default = "default_top_k")]
@@ -243,15 +732,17 @@ impl SamplerConfig {
 }
 
 impl AdvancedSamplerConfig {
-	pub(crate) fn sampler_chain(&self) -> SamplerChain {
-		let sampler_options = self
-			.samplers
+	fn sampler_options(&self) -> String {
+		self.samplers
 			.iter()
 			.map(|s| s.trim())
 			.filter(|s| !s.is_empty())
 			.map(|s| "/".to_string() + s)
-			.collect::<String>();
-		let configured_samplers = ConfiguredSamplers::from_str(&sampler_options).expect("valid sampler chain");
+			.collect::<String>()
+	}
+
+	pub(crate) fn sampler_chain(&self) -> SamplerChain {
+		let configured_samplers = ConfiguredSamplers::from_str(&self.sampler_options()).expect("valid sampler chain");
 		configured_samplers.builder.into_chain()
 	}
 }
@@ -300,16 +791,94 @@ impl TaskConfig {
 	pub(crate) fn sampler_chain(&self) -> SamplerChain {
 		self.sampler.sampler_chain()
 	}
+
+	/// Variables of the prompt template that requests must give values for (`input` excepted), in order of first use
+	pub fn template_variables(&self) -> Vec<String> {
+		let Some(Ok(template)) = self.prompt_template.as_deref().map(PromptTemplate::parse) else {
+			return vec![];
+		};
+		template
+			.variables()
+			.into_iter()
+			.filter(|name| *name != INPUT_VARIABLE)
+			.map(String::from)
+			.collect()
+	}
+
+	/// Returns the configuration with the sampling parameters replaced by those specified in the request. Temperature
+	/// and top-p cannot be overridden for tasks that configure their own sampler chain.
+	pub(crate) fn with_overrides(&self, request: &PromptRequest) -> Result<TaskConfig, BackendError> {
+		request.check_parameters()?;
+		let mut config = self.clone();
+		if let Some(ref tool_result) = request.tool_result {
+			if !self.tools.iter().any(|tool| tool.name == tool_result.tool) {
+				return Err(BackendError::InvalidParameter(
+					String::from("tool_result"),
+					format!("the task has no tool named '{}'", tool_result.tool),
+				));
+			}
+		}
+		if request.max_tokens.is_some() {
+			config.max_tokens = request.max_tokens;
+		}
+		if request.max_chars.is_some() {
+			config.max_chars = request.max_chars;
+		}
+		if request.max_lines.is_some() {
+			config.max_lines = request.max_lines;
+		}
+		config.stop_sequences.extend(request.stop.iter().cloned().map(StopSequenceConfig::Text));
+
+		match config.sampler {
+			SamplerConfig::Standard(ref mut sampler) => {
+				sampler.temperature = request.temperature.unwrap_or(sampler.temperature);
+				sampler.top_p = request.top_p.unwrap_or(sampler.top_p);
+			}
+			SamplerConfig::Advanced(_) if request.temperature.is_some() || request.top_p.is_some() => {
+				let parameter = if request.temperature.is_some() { "temperature" } else { "top_p" };
+				return Err(BackendError::InvalidParameter(
+					parameter.to_string(),
+					String::from("cannot be changed for a task with a custom sampler chain"),
+				));
+			}
+			SamplerConfig::Advanced(_) => {}
+		}
+		Ok(config)
+	}
 }
 
-const fn default_stop_sequences() -> Vec<String> {
+const fn default_stop_sequences() -> Vec<StopSequenceConfig> {
 	vec![]
 }
 
+const fn default_case_insensitive() -> bool {
+	true
+}
+
+const fn default_max_variable_chars() -> usize {
+	1024
+}
+
+fn default_banned_phrase_placeholder() -> String {
+	String::from("[censored]")
+}
+
+const fn default_banned_phrase_retries() -> usize {
+	3
+}
+
 const fn default_batch_size() -> usize {
 	8
 }
 
+const fn default_prompt_chunk_size() -> usize {
+	64
+}
+
+const fn default_max_similarity_texts() -> usize {
+	100
+}
+
 const fn default_top_k() -> usize {
 	40
 }
@@ -344,4 +913,1334 @@ pub struct BackendConfig {
 
 	/// Directory to store downloaded assets
 	pub cache_path: Option<PathBuf>,
+
+	/// Access token to use when downloading models from Hugging Face (when not set, the `HF_TOKEN` environment variable
+	/// is used). Can also be read from a file using `hf_token_file` (see [SECRET_KEYS]).
+	pub hf_token: Option<Secret>,
+
+	/// A warning is logged when the number of concurrently active sessions exceeds this number
+	pub soft_session_limit: Option<usize>,
+
+	/// Maximum number of recently calculated embeddings to keep, so that embeddings of the same prompt are only calculated
+	/// once (default [`crate::cache::DEFAULT_EMBEDDING_CACHE_SIZE`]; zero disables the cache)
+	pub embedding_cache_size: Option<usize>,
+
+	/// Number of threads on which completions and embeddings are performed (see [`crate::pool`]). By default, the number
+	/// of physical cores divided by the largest `threads_per_session` of the models, so that sessions running at the same
+	/// time do not compete for cores.
+	pub inference_threads: Option<usize>,
+
+	/// The files each configuration entry was read from (only set when loaded using [`from_toml_file`])
+	#[serde(skip)]
+	pub sources: ConfigSources,
+}
+
+/// A secret configuration value (e.g. an API key or access token). The value is redacted when formatted, so it cannot
+/// end up in logs or error messages by accident. Use [`Secret::expose`] to obtain the actual value.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+	pub fn new(value: impl Into<String>) -> Secret {
+		Secret(value.into())
+	}
+
+	pub fn expose(&self) -> &str {
+		&self.0
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// Compare the secret to a value, taking the same time for any value of the same length
+	pub fn matches(&self, value: &str) -> bool {
+		let (secret, value) = (self.0.as_bytes(), value.as_bytes());
+		secret.len() == value.len() && secret.iter().zip(value).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+	}
+}
+
+impl std::fmt::Debug for Secret {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("Secret(<redacted>)")
+	}
+}
+
+impl Display for Secret {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("<redacted>")
+	}
+}
+
+/// Records for each configuration entry (e.g. "bind_address" or "tasks.assistant") which file it was read from
+#[derive(Clone, Debug, Default)]
+pub struct ConfigSources {
+	files: HashMap<String, PathBuf>,
+}
+
+impl ConfigSources {
+	/// Sets the file for each problem based on the configuration entry it was found in
+	pub fn annotate(&self, problems: &mut [ConfigProblem]) {
+		for problem in problems.iter_mut() {
+			if problem.file.is_none() {
+				problem.file = self.file_for(&problem.key).map(Path::to_path_buf);
+			}
+		}
+	}
+
+	/// Returns the file that the entry with the specified key (or the entry containing it) was read from
+	pub fn file_for(&self, key: &str) -> Option<&Path> {
+		let mut key = key;
+		loop {
+			if let Some(file) = self.files.get(key) {
+				return Some(file);
+			}
+			key = &key[..key.rfind(['.', '['])?];
+		}
+	}
+}
+
+/// A problem found while checking a configuration
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+	/// The configuration entry the problem was found in (e.g. "tasks.assistant")
+	pub key: String,
+	pub message: String,
+
+	/// The file the configuration entry was read from, if known
+	pub file: Option<PathBuf>,
+}
+
+impl ConfigProblem {
+	pub fn new(key: impl Into<String>, message: impl Into<String>) -> ConfigProblem {
+		ConfigProblem {
+			key: key.into(),
+			message: message.into(),
+			file: None,
+		}
+	}
+}
+
+impl Display for ConfigProblem {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if let Some(file) = &self.file {
+			write!(f, "{}: ", file.display())?;
+		}
+		write!(f, "{}: {}", self.key, self.message)
+	}
+}
+
+/// Magic values at the start of GGML model files (little endian: 'ggml', 'ggmf' and 'ggjt')
+const GGML_MAGICS: [u32; 3] = [0x67676d6c, 0x67676d66, 0x67676a74];
+
+/// Magic bytes at the start of GGUF model files
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+fn check_model_file(path: &Path) -> Result<(), String> {
+	let mut file = File::open(path).map_err(|e| format!("cannot open model file {path:?}: {e}"))?;
+	let mut magic = [0u8; 4];
+	file.read_exact(&mut magic).map_err(|e| format!("cannot read model file {path:?}: {e}"))?;
+	if &magic == GGUF_MAGIC || GGML_MAGICS.contains(&u32::from_le_bytes(magic)) {
+		Ok(())
+	} else {
+		Err(format!("file {path:?} does not appear to be a GGML/GGUF model file"))
+	}
+}
+
+fn check_schema(schema: &JsonSchema, key: &str, problems: &mut Vec<ConfigProblem>) {
+	match schema {
+		JsonSchema::Object { required, properties } => {
+			for field in required {
+				if !properties.contains_key(field) {
+					problems.push(ConfigProblem::new(key, format!("required field '{field}' has no schema in properties")));
+				}
+			}
+			for (field, field_schema) in properties {
+				check_schema(field_schema, &format!("{key}.{field}"), problems);
+			}
+		}
+		JsonSchema::Array { items, min_items, max_items } => {
+			if let (Some(min_items), Some(max_items)) = (min_items, max_items) {
+				if min_items > max_items {
+					problems.push(ConfigProblem::new(key, "min_items is larger than max_items"));
+				}
+			}
+			check_schema(items, &format!("{key}[]"), problems);
+		}
+		JsonSchema::Number {
+			min: Some(min),
+			max: Some(max),
+			..
+		}
+		| JsonSchema::Integer {
+			min: Some(min),
+			max: Some(max),
+			..
+		} if min > max => {
+			problems.push(ConfigProblem::new(key, "min is larger than max"));
+		}
+		JsonSchema::Number {
+			multiple_of: Some(multiple_of),
+			..
+		}
+		| JsonSchema::Integer {
+			multiple_of: Some(multiple_of),
+			..
+		} if *multiple_of <= 0.0 => {
+			problems.push(ConfigProblem::new(key, "multiple_of must be larger than zero"));
+		}
+		JsonSchema::Number { r#enum: Some(values), .. } if values.is_empty() => {
+			problems.push(ConfigProblem::new(key, "enum must not be empty"));
+		}
+		JsonSchema::Enum { r#enum } if r#enum.is_empty() => {
+			problems.push(ConfigProblem::new(key, "enum must not be empty"));
+		}
+		JsonSchema::String {
+			min_length,
+			max_length,
+			r#enum,
+			pattern,
+		} => {
+			if let (Some(min_length), Some(max_length)) = (min_length, max_length) {
+				if min_length > max_length {
+					problems.push(ConfigProblem::new(key, "min_length is larger than max_length"));
+				}
+			}
+			if let (Some(max_length), Some(values)) = (max_length, r#enum) {
+				if values.iter().all(|v| v.chars().count() > *max_length) {
+					problems.push(ConfigProblem::new(key, "no enum value fits within max_length"));
+				}
+			}
+			if let Some(pattern) = pattern {
+				if let Err(e) = RegexBiaser::new(pattern) {
+					problems.push(ConfigProblem::new(key, format!("invalid pattern: {e}")));
+				}
+			}
+		}
+		JsonSchema::OneOf { one_of } => {
+			if one_of.is_empty() {
+				problems.push(ConfigProblem::new(key, "one_of must not be empty"));
+			}
+			for (index, alternative) in one_of.iter().enumerate() {
+				check_schema(alternative, &format!("{key}.one_of[{index}]"), problems);
+			}
+		}
+		_ => {}
+	}
+}
+
+impl BiaserConfig {
+	/// Load the JSON schema for this biaser (`None` for a biaser that does not use a JSON schema)
+	pub fn schema(&self) -> Result<Option<JsonSchema>, String> {
+		match self {
+			BiaserConfig::JsonSchema(schema) => Ok(Some(schema.clone())),
+			BiaserConfig::JsonSchemaFile(path) => {
+				let file = File::open(path).map_err(|e| format!("cannot open JSON schema file {path:?}: {e}"))?;
+				serde_json::from_reader(BufReader::new(file))
+					.map(Some)
+					.map_err(|e| format!("invalid JSON schema in file {path:?}: {e}"))
+			}
+			BiaserConfig::Regex(_) | BiaserConfig::Choices(_) => Ok(None),
+		}
+	}
+
+	/// Compile the regular expression of this biaser (`None` for a biaser that does not use one)
+	pub fn regex(&self) -> Result<Option<RegexBiaser>, String> {
+		match self {
+			BiaserConfig::Regex(pattern) => RegexBiaser::new(pattern)
+				.map(Some)
+				.map_err(|e| format!("invalid regular expression: {e}")),
+			_ => Ok(None),
+		}
+	}
+}
+
+impl BackendConfig {
+	/// Use the default cache directory of the platform when no cache path is configured
+	pub fn set_default_cache_path(&mut self) {
+		if self.cache_path.is_none() {
+			if let Some(pd) = ProjectDirs::from("nl.dialogic", "Dialogic", "Poly") {
+				self.cache_path = Some(pd.cache_dir().to_path_buf());
+			}
+		}
+	}
+
+	/// Returns the path the weights of a model are loaded from. Models without a configured path are stored in the cache.
+	pub fn model_path(&self, model_name: &str) -> Result<PathBuf, String> {
+		let model_config = self.models.get(model_name).ok_or_else(|| format!("model '{model_name}' not found"))?;
+		match (&model_config.model_path, &self.cache_path) {
+			(Some(path), _) => Ok(path.clone()),
+			(None, Some(cache_path)) => Ok(cache_path.join(CACHE_MODELS_DIR).join(format!("{model_name}.bin"))),
+			(None, None) => Err(String::from("no model path configured and no cache path available")),
+		}
+	}
+
+	/// Number of threads of the inference pool (see [`BackendConfig::inference_threads`])
+	pub fn inference_threads(&self) -> usize {
+		self.inference_threads.unwrap_or_else(|| {
+			let per_session = self.models.values().map(|m| m.threads_per_session).max().unwrap_or(1).max(1);
+			(num_cpus::get_physical() / per_session).max(1)
+		})
+	}
+
+	/// Check the configuration for problems that can be found without loading any models. Returns all problems found.
+	pub fn check(&self) -> Vec<ConfigProblem> {
+		let mut problems = vec![];
+
+		if self.inference_threads == Some(0) {
+			problems.push(ConfigProblem::new("inference_threads", "must be larger than zero"));
+		}
+
+		for (model_name, model_config) in &self.models {
+			let key = format!("models.{model_name}");
+			match (&model_config.model_path, &model_config.url) {
+				(Some(path), _) if path.exists() => {
+					if let Err(e) = check_model_file(path) {
+						problems.push(ConfigProblem::new(&key, e));
+					}
+				}
+				(_, Some(_)) => {
+					// Model will be downloaded at startup
+				}
+				(Some(path), None) => problems.push(ConfigProblem::new(&key, format!("model file {path:?} does not exist"))),
+				(None, None) => problems.push(ConfigProblem::new(&key, "either model_path or url must be specified")),
+			}
+
+			match (&model_config.tokenizer_path, &model_config.tokenizer_repo) {
+				(Some(_), Some(_)) => problems.push(ConfigProblem::new(&key, "tokenizer_path and tokenizer_repo cannot both be set")),
+				(Some(path), None) if !path.exists() => problems.push(ConfigProblem::new(&key, format!("tokenizer file {path:?} does not exist"))),
+				_ => {}
+			}
+
+			for lora_path in model_config.lora_adapters.iter().flatten() {
+				if !lora_path.exists() {
+					problems.push(ConfigProblem::new(&key, format!("LoRA adapter {lora_path:?} does not exist")));
+				}
+			}
+
+			if let Some(ref sha256) = model_config.sha256 {
+				if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+					problems.push(ConfigProblem::new(&key, "sha256 must consist of 64 hexadecimal characters"));
+				}
+			}
+
+			if model_config.context_size == 0 {
+				problems.push(ConfigProblem::new(&key, "context_size must be larger than zero"));
+			}
+			if model_config.threads_per_session == 0 {
+				problems.push(ConfigProblem::new(&key, "threads_per_session must be larger than zero"));
+			}
+			if model_config.batch_size == 0 {
+				problems.push(ConfigProblem::new(&key, "batch_size must be larger than zero"));
+			}
+			if model_config.prompt_chunk_size == 0 {
+				problems.push(ConfigProblem::new(&key, "prompt_chunk_size must be larger than zero"));
+			}
+			if model_config.max_similarity_texts == 0 {
+				problems.push(ConfigProblem::new(&key, "max_similarity_texts must be larger than zero"));
+			}
+		}
+
+		let mut memory_paths: HashMap<&PathBuf, (&String, usize)> = HashMap::new();
+		for (memory_name, memory_config) in &self.memories {
+			let key = format!("memories.{memory_name}");
+			if !self.models.contains_key(&memory_config.embedding_model) {
+				problems.push(ConfigProblem::new(
+					&key,
+					format!("embedding model '{}' not found", memory_config.embedding_model),
+				));
+			}
+
+			if memory_config.dimensions == 0 {
+				problems.push(ConfigProblem::new(&key, "dimensions must be larger than zero"));
+			}
+
+			if memory_config.chunk_max_tokens == 0 {
+				problems.push(ConfigProblem::new(&key, "chunk_max_tokens must be larger than zero"));
+			}
+
+			if memory_config.max_items == Some(0) {
+				problems.push(ConfigProblem::new(&key, "max_items must be larger than zero"));
+			}
+
+			for filter in &memory_config.pre_filter {
+				if let Err(e) = Regex::new(filter) {
+					problems.push(ConfigProblem::new(&key, format!("invalid pre_filter pattern '{filter}': {e}")));
+				}
+			}
+
+			if let MemoryStoreConfig::Hora { path: Some(path) } = &memory_config.store {
+				// The directory the memory is stored in must be writable
+				let dir = match path.parent() {
+					Some(p) if !p.as_os_str().is_empty() => p,
+					_ => Path::new("."),
+				};
+				match dir.metadata() {
+					Ok(m) if m.permissions().readonly() => {
+						problems.push(ConfigProblem::new(&key, format!("memory directory {dir:?} is not writable")))
+					}
+					Ok(_) => {}
+					Err(e) => problems.push(ConfigProblem::new(&key, format!("memory directory {dir:?} is not accessible: {e}"))),
+				}
+
+				// Memories sharing a file must agree on dimensionality
+				if let Some((other_name, other_dimensions)) = memory_paths.get(path) {
+					if *other_dimensions != memory_config.dimensions {
+						problems.push(ConfigProblem::new(
+							&key,
+							format!("memory shares its file with memory '{other_name}' but has different dimensions"),
+						));
+					}
+				} else {
+					memory_paths.insert(path, (memory_name, memory_config.dimensions));
+				}
+			}
+		}
+
+		for (task_name, task_config) in &self.tasks {
+			let key = format!("tasks.{task_name}");
+			if !self.models.contains_key(&task_config.model) {
+				problems.push(ConfigProblem::new(&key, format!("model '{}' not found", task_config.model)));
+			}
+
+			let rerank = &task_config.rerank;
+			if rerank.max_documents == 0 || rerank.budget_ms == 0 {
+				problems.push(ConfigProblem::new(
+					&key,
+					"rerank.max_documents and rerank.budget_ms must be larger than zero",
+				));
+			}
+			if rerank.method == RerankMethod::CrossScoring && !(rerank.template.contains("{query}") && rerank.template.contains("{document}")) {
+				problems.push(ConfigProblem::new(&key, "rerank.template must contain {query} and {document}"));
+			}
+
+			let mut tool_names = HashSet::new();
+			for tool in &task_config.tools {
+				let tool_key = format!("{key}.tools.{}", tool.name);
+				if tool.name.is_empty() || !tool_names.insert(&tool.name) {
+					problems.push(ConfigProblem::new(&key, "tool names must be unique and not empty"));
+				}
+				if !matches!(tool.arguments, JsonSchema::Object { .. }) {
+					problems.push(ConfigProblem::new(&tool_key, "arguments must be an object schema"));
+				}
+				check_schema(&tool.arguments, &format!("{tool_key}.arguments"), &mut problems);
+			}
+			if !task_config.tools.is_empty() {
+				if task_config.biaser.is_some() {
+					problems.push(ConfigProblem::new(&key, "tools cannot be combined with a biaser"));
+				}
+				let templates = &task_config.tool_templates;
+				if !templates.list.contains("{tools}") || !templates.result.contains("{result}") {
+					problems.push(ConfigProblem::new(
+						&key,
+						"tool_templates.list must contain {tools} and tool_templates.result must contain {result}",
+					));
+				}
+			}
+
+			if let Some(ref template) = task_config.prompt_template {
+				match PromptTemplate::parse(template) {
+					Ok(template) if !template.variables().contains(&INPUT_VARIABLE) => {
+						problems.push(ConfigProblem::new(&key, "prompt_template must contain {input}"));
+					}
+					Ok(_) => {}
+					Err(e) => problems.push(ConfigProblem::new(&key, format!("invalid prompt_template: {e}"))),
+				}
+			}
+
+			if task_config.banned_phrases.iter().any(|p| p.text().trim().is_empty()) {
+				problems.push(ConfigProblem::new(&key, "banned phrases must not be empty"));
+			}
+			if !task_config.banned_phrases.is_empty() && (task_config.biaser.is_some() || !task_config.tools.is_empty()) {
+				problems.push(ConfigProblem::new(&key, "banned_phrases cannot be combined with a biaser or tools"));
+			}
+
+			let summarize = &task_config.summarize;
+			if summarize.chunk_tokens == Some(0) || summarize.max_total_tokens == 0 || summarize.max_levels == 0 {
+				problems.push(ConfigProblem::new(
+					&key,
+					"summarize.chunk_tokens, summarize.max_total_tokens and summarize.max_levels must be larger than zero",
+				));
+			}
+
+			if let Some(memorization) = &task_config.memorization {
+				if !self.memories.contains_key(&memorization.memory) {
+					problems.push(ConfigProblem::new(&key, format!("memory '{}' not found", memorization.memory)));
+				}
+			}
+
+			if task_config.eot_token.as_ref().is_some_and(|t| t.tokens().is_empty()) {
+				problems.push(ConfigProblem::new(&key, "eot_token must not be an empty list"));
+			}
+
+			if task_config.threads_per_session == Some(0) {
+				problems.push(ConfigProblem::new(&key, "threads_per_session must be larger than zero"));
+			}
+			if task_config.batch_size == Some(0) {
+				problems.push(ConfigProblem::new(&key, "batch_size must be larger than zero"));
+			}
+			if task_config.max_prompt_tokens == Some(0) {
+				problems.push(ConfigProblem::new(&key, "max_prompt_tokens must be larger than zero"));
+			}
+			if task_config.max_duration_secs.is_some_and(|secs| !(secs > 0.0 && secs.is_finite())) {
+				problems.push(ConfigProblem::new(&key, "max_duration_secs must be larger than zero"));
+			}
+
+			if let Some(biaser) = &task_config.biaser {
+				match biaser.schema() {
+					Ok(Some(schema)) => check_schema(&schema, &format!("{key}.biaser"), &mut problems),
+					Ok(None) => {}
+					Err(e) => problems.push(ConfigProblem::new(&key, e)),
+				}
+				if let Err(e) = biaser.regex() {
+					problems.push(ConfigProblem::new(format!("{key}.biaser"), e));
+				}
+				if let BiaserConfig::Choices(choices) = biaser {
+					if choices.is_empty() || choices.iter().any(String::is_empty) {
+						problems.push(ConfigProblem::new(format!("{key}.biaser"), "choices must not be empty"));
+					}
+					if choices.iter().collect::<HashSet<_>>().len() != choices.len() {
+						problems.push(ConfigProblem::new(format!("{key}.biaser"), "choices must be unique"));
+					}
+				}
+			}
+
+			let validation = &task_config.validation;
+			let validation_key = format!("{key}.validation");
+			if !validation.repair_template.contains("{errors}") {
+				problems.push(ConfigProblem::new(&validation_key, "repair_template must contain {errors}"));
+			}
+			if let Some(schema) = &validation.schema {
+				if task_config.biaser.is_none() {
+					problems.push(ConfigProblem::new(&validation_key, "a schema can only be used for a task with a biaser"));
+				} else if matches!(task_config.biaser, Some(BiaserConfig::Regex(_) | BiaserConfig::Choices(_))) {
+					problems.push(ConfigProblem::new(
+						&validation_key,
+						"a schema can only be used for a task with a JSON schema biaser",
+					));
+				}
+				if let Err(e) = validation::compile(schema) {
+					problems.push(ConfigProblem::new(&validation_key, format!("invalid JSON Schema: {e}")));
+				}
+			}
+
+			if let SamplerConfig::Advanced(advanced) = &task_config.sampler {
+				if let Err(e) = ConfiguredSamplers::from_str(&advanced.sampler_options()) {
+					problems.push(ConfigProblem::new(&key, format!("invalid sampler chain: {e}")));
+				}
+			}
+		}
+
+		self.sources.annotate(&mut problems);
+		problems
+	}
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+	#[error("invalid configuration: {0}")]
+	Parse(#[from] toml::de::Error),
+
+	#[error("environment variable '{variable}' referenced by '{key}' is not set")]
+	MissingVariable { variable: String, key: String },
+
+	#[error("invalid environment variable reference in '{key}': {message}")]
+	InvalidReference { key: String, message: String },
+
+	#[error("cannot read file {path:?} referenced by '{key}': {error}")]
+	Read { key: String, path: PathBuf, error: std::io::Error },
+
+	#[error("'{key}' and '{other_key}' cannot both be specified")]
+	Conflict { key: String, other_key: String },
+
+	#[error("invalid configuration in {path:?}: {error}")]
+	ParseFile { path: PathBuf, error: toml::de::Error },
+
+	#[error("invalid include '{pattern}': {message}")]
+	Include { pattern: String, message: String },
+
+	#[error("'{key}' is defined in both {first:?} and {second:?}")]
+	IncludeConflict { key: String, first: PathBuf, second: PathBuf },
+
+	#[error("invalid task setting '{key}': {message}")]
+	InvalidTaskSetting { key: String, message: String },
+
+	#[error("invalid value for '{key}': {message}")]
+	InvalidValue { key: String, message: String },
+}
+
+/// Top-level keys in the backend configuration that hold secrets. Instead of specifying a secret directly (`key = "..."`),
+/// it can be read from a file (`key_file = "/run/secrets/key"`) or an environment variable (`key_env = "VAR"`).
+pub const SECRET_KEYS: &[&str] = &["hf_token"];
+
+const SECRET_FILE_SUFFIX: &str = "_file";
+const SECRET_ENV_SUFFIX: &str = "_env";
+
+/// Key in a configuration file that lists (glob patterns for) files to include
+const INCLUDE_KEY: &str = "include";
+
+/// Parse a TOML configuration string. Any `${VAR}` references in string values are replaced with the value of
+/// environment variable `VAR`. When the variable is not set, the syntax `${VAR:-default}` can be used to specify a
+/// default value. A literal `$` can be written as `$$`. Secrets (see [SECRET_KEYS]) are read from the files or
+/// environment variables they refer to.
+pub fn from_toml_str<T: DeserializeOwned>(config_string: &str) -> Result<T, ConfigError> {
+	let mut table: toml::Table = toml::from_str(config_string)?;
+	apply_task_defaults(&mut table)?;
+	finish_toml_table(table, SECRET_KEYS)
+}
+
+/// Interpolates environment variables and resolves secrets, then deserializes the configuration
+fn finish_toml_table<T: DeserializeOwned>(table: toml::Table, secret_keys: &[&str]) -> Result<T, ConfigError> {
+	let lookup = |name: &str| std::env::var(name).ok();
+	let mut value = toml::Value::Table(table);
+	interpolate_value(&mut value, "", &lookup)?;
+	if let toml::Value::Table(ref mut table) = value {
+		resolve_secrets(table, secret_keys, &lookup)?;
+	}
+	Ok(value.try_into()?)
+}
+
+/// Replaces `key_file` and `key_env` entries for each of the specified secret keys with `key`, reading the value from the
+/// file (without trailing whitespace) or environment variable. Only one of these may be specified.
+fn resolve_secrets(table: &mut toml::Table, secret_keys: &[&str], lookup: &impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+	for key in secret_keys {
+		let file_key = format!("{key}{SECRET_FILE_SUFFIX}");
+		let env_key = format!("{key}{SECRET_ENV_SUFFIX}");
+		let conflict = |key: &str, other_key: &str| ConfigError::Conflict {
+			key: key.to_string(),
+			other_key: other_key.to_string(),
+		};
+		let string_value = |value: toml::Value, key: &str| match value {
+			toml::Value::String(s) => Ok(s),
+			_ => Err(ConfigError::InvalidValue {
+				key: key.to_string(),
+				message: String::from("must be a string"),
+			}),
+		};
+
+		let value = match (table.contains_key(*key), table.remove(&file_key), table.remove(&env_key)) {
+			(_, None, None) => continue,
+			(true, Some(_), _) => return Err(conflict(key, &file_key)),
+			(true, None, Some(_)) => return Err(conflict(key, &env_key)),
+			(false, Some(_), Some(_)) => return Err(conflict(&file_key, &env_key)),
+			(false, Some(path), None) => {
+				let path = PathBuf::from(string_value(path, &file_key)?);
+				match std::fs::read_to_string(&path) {
+					Ok(contents) => contents.trim_end().to_string(),
+					Err(error) => return Err(ConfigError::Read { key: file_key, path, error }),
+				}
+			}
+			(false, None, Some(variable)) => {
+				let variable = string_value(variable, &env_key)?;
+				lookup(&variable).ok_or(ConfigError::MissingVariable { variable, key: env_key })?
+			}
+		};
+		table.insert(key.to_string(), toml::Value::String(value));
+	}
+	Ok(())
+}
+
+/// Read a TOML configuration file. Environment variables are interpolated and secrets resolved as in [`from_toml_str`]. The file may
+/// contain an `include` key with a list of glob patterns (relative to the directory of the file that includes them). The
+/// matching files are read (in alphabetical order per pattern) and merged into the configuration: entries in top-level
+/// tables (e.g. `tasks` or `models`) are combined, but an entry or top-level value that is defined in more than one file
+/// is an error. Returns the configuration as well as the file each entry was read from.
+pub fn from_toml_file<T: DeserializeOwned>(path: &Path) -> Result<(T, ConfigSources), ConfigError> {
+	from_toml_file_with_secrets(path, SECRET_KEYS)
+}
+
+/// Read a TOML configuration file like [`from_toml_file`], resolving the specified (top-level) keys as secrets
+pub fn from_toml_file_with_secrets<T: DeserializeOwned>(path: &Path, secret_keys: &[&str]) -> Result<(T, ConfigSources), ConfigError> {
+	let mut files = vec![];
+	read_toml_files(path, "config_path", &mut HashSet::new(), &mut files)?;
+
+	let mut table = toml::Table::new();
+	let mut sources = ConfigSources::default();
+	for (file, file_table) in files {
+		merge_toml_table(&mut table, file_table, &file, &mut sources)?;
+	}
+	apply_task_defaults(&mut table)?;
+	Ok((finish_toml_table(table, secret_keys)?, sources))
+}
+
+/// Reads the configuration file at `path` and (recursively) the files it includes, in order
+fn read_toml_files(path: &Path, key: &str, visited: &mut HashSet<PathBuf>, files: &mut Vec<(PathBuf, toml::Table)>) -> Result<(), ConfigError> {
+	let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+	if !visited.insert(canonical_path) {
+		return Err(ConfigError::Include {
+			pattern: path.display().to_string(),
+			message: String::from("file is included more than once"),
+		});
+	}
+
+	let config_string = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+		key: key.to_string(),
+		path: path.to_path_buf(),
+		error,
+	})?;
+	let mut table: toml::Table = toml::from_str(&config_string).map_err(|error| ConfigError::ParseFile {
+		path: path.to_path_buf(),
+		error,
+	})?;
+
+	let includes = match table.remove(INCLUDE_KEY) {
+		None => vec![],
+		Some(includes) => includes.try_into::<Vec<String>>().map_err(|e| ConfigError::Include {
+			pattern: format!("{INCLUDE_KEY} in {path:?}"),
+			message: e.to_string(),
+		})?,
+	};
+	files.push((path.to_path_buf(), table));
+
+	let base = path.parent().unwrap_or(Path::new("."));
+	for pattern in includes {
+		for included_path in expand_include(base, &pattern)? {
+			read_toml_files(&included_path, INCLUDE_KEY, visited, files)?;
+		}
+	}
+	Ok(())
+}
+
+/// Returns the files matching an include pattern in deterministic (alphabetical) order
+fn expand_include(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, ConfigError> {
+	let invalid = |message: String| ConfigError::Include {
+		pattern: pattern.to_string(),
+		message,
+	};
+
+	let full_pattern = base.join(pattern);
+	let full_pattern = full_pattern.to_str().ok_or_else(|| invalid(String::from("path is not valid UTF-8")))?;
+	let mut paths = glob::glob(full_pattern)
+		.map_err(|e| invalid(e.to_string()))?
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| invalid(e.to_string()))?;
+	paths.sort();
+
+	// A pattern without wildcards refers to a single file, which should exist
+	if paths.is_empty() && !pattern.contains(['*', '?', '[']) {
+		return Err(invalid(String::from("file does not exist")));
+	}
+	Ok(paths)
+}
+
+fn merge_toml_table(target: &mut toml::Table, source: toml::Table, file: &Path, sources: &mut ConfigSources) -> Result<(), ConfigError> {
+	for (key, value) in source {
+		if !target.contains_key(&key) {
+			if let toml::Value::Table(entries) = &value {
+				for entry_key in entries.keys() {
+					sources.files.insert(format!("{key}.{entry_key}"), file.to_path_buf());
+				}
+			}
+			sources.files.insert(key.clone(), file.to_path_buf());
+			target.insert(key, value);
+			continue;
+		}
+
+		match (target.get_mut(&key), value) {
+			(Some(toml::Value::Table(existing)), toml::Value::Table(entries)) => {
+				for (entry_key, entry) in entries {
+					let entry_path = format!("{key}.{entry_key}");
+					if existing.contains_key(&entry_key) {
+						return Err(ConfigError::IncludeConflict {
+							first: sources.files.get(&entry_path).cloned().unwrap_or_default(),
+							key: entry_path,
+							second: file.to_path_buf(),
+						});
+					}
+					sources.files.insert(entry_path, file.to_path_buf());
+					existing.insert(entry_key, entry);
+				}
+			}
+			_ => {
+				return Err(ConfigError::IncludeConflict {
+					first: sources.files.get(&key).cloned().unwrap_or_default(),
+					key,
+					second: file.to_path_buf(),
+				})
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Top-level table with settings that apply to all tasks (unless overridden by the task or its profile)
+const TASK_DEFAULTS_KEY: &str = "task_defaults";
+
+/// Top-level table with named sets of task settings, which a task can select using `profile = "name"`
+const TASK_PROFILES_KEY: &str = "task_profiles";
+
+const TASK_PROFILE_KEY: &str = "profile";
+
+/// Prefix for task setting keys whose value (an array) is appended to the inherited value instead of replacing it
+const APPEND_PREFIX: char = '+';
+
+/// Replaces each task in the configuration with the result of merging `task_defaults`, the task's profile (if any) and
+/// the task's own settings, in that order. Settings replace inherited values as a whole (this also applies to arrays
+/// and tables), except for array settings written as `"+name" = [...]`, which are appended to the inherited array.
+fn apply_task_defaults(table: &mut toml::Table) -> Result<(), ConfigError> {
+	let defaults = take_table(table, TASK_DEFAULTS_KEY)?;
+	let profiles = take_table(table, TASK_PROFILES_KEY)?;
+
+	let Some(toml::Value::Table(tasks)) = table.get_mut("tasks") else {
+		return Ok(());
+	};
+
+	for (task_name, task) in tasks.iter_mut() {
+		let key = format!("tasks.{task_name}");
+		let toml::Value::Table(settings) = task else {
+			// Deserialization will report this
+			continue;
+		};
+
+		let mut effective = toml::Table::new();
+		merge_task_settings(&mut effective, &defaults, TASK_DEFAULTS_KEY)?;
+
+		// The profile may also be selected for all tasks in the defaults
+		if let Some(profile) = settings.get(TASK_PROFILE_KEY).or_else(|| defaults.get(TASK_PROFILE_KEY)) {
+			let invalid = |message: String| ConfigError::InvalidTaskSetting {
+				key: format!("{key}.{TASK_PROFILE_KEY}"),
+				message,
+			};
+			let profile_name = profile.as_str().ok_or_else(|| invalid(String::from("profile must be a string")))?;
+			let profile = profiles
+				.get(profile_name)
+				.and_then(toml::Value::as_table)
+				.ok_or_else(|| invalid(format!("profile '{profile_name}' does not exist")))?;
+			merge_task_settings(&mut effective, profile, &format!("{TASK_PROFILES_KEY}.{profile_name}"))?;
+		}
+
+		merge_task_settings(&mut effective, settings, &key)?;
+		*settings = effective;
+	}
+	Ok(())
+}
+
+/// Removes a top-level table from the configuration, returning an empty table if it does not exist
+fn take_table(table: &mut toml::Table, key: &str) -> Result<toml::Table, ConfigError> {
+	match table.remove(key) {
+		None => Ok(toml::Table::new()),
+		Some(toml::Value::Table(t)) => Ok(t),
+		Some(_) => Err(ConfigError::InvalidTaskSetting {
+			key: key.to_string(),
+			message: String::from("must be a table"),
+		}),
+	}
+}
+
+fn merge_task_settings(target: &mut toml::Table, settings: &toml::Table, key: &str) -> Result<(), ConfigError> {
+	for (setting, value) in settings {
+		let Some(setting) = setting.strip_prefix(APPEND_PREFIX) else {
+			target.insert(setting.clone(), value.clone());
+			continue;
+		};
+
+		let invalid = || ConfigError::InvalidTaskSetting {
+			key: format!("{key}.{APPEND_PREFIX}{setting}"),
+			message: String::from("only arrays can be appended to"),
+		};
+		let toml::Value::Array(items) = value else {
+			return Err(invalid());
+		};
+		match target.entry(setting).or_insert_with(|| toml::Value::Array(vec![])) {
+			toml::Value::Array(inherited) => inherited.extend(items.iter().cloned()),
+			_ => return Err(invalid()),
+		}
+	}
+	Ok(())
+}
+
+fn interpolate_value(value: &mut toml::Value, key: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+	match value {
+		toml::Value::String(s) => *s = interpolate_str(s, key, lookup)?,
+		toml::Value::Array(items) => {
+			for (index, item) in items.iter_mut().enumerate() {
+				interpolate_value(item, &format!("{key}[{index}]"), lookup)?;
+			}
+		}
+		toml::Value::Table(table) => {
+			for (child_key, child) in table.iter_mut() {
+				let child_path = if key.is_empty() {
+					child_key.clone()
+				} else {
+					format!("{key}.{child_key}")
+				};
+				interpolate_value(child, &child_path, lookup)?;
+			}
+		}
+		_ => {}
+	}
+	Ok(())
+}
+
+fn interpolate_str(s: &str, key: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String, ConfigError> {
+	let mut result = String::with_capacity(s.len());
+	let mut rest = s;
+
+	while let Some(position) = rest.find('$') {
+		result.push_str(&rest[..position]);
+		let after = &rest[(position + 1)..];
+
+		if let Some(after) = after.strip_prefix('$') {
+			// Escaped dollar sign
+			result.push('$');
+			rest = after;
+		} else if let Some(reference) = after.strip_prefix('{') {
+			let Some(end) = reference.find('}') else {
+				return Err(ConfigError::InvalidReference {
+					key: key.to_string(),
+					message: String::from("unterminated '${'"),
+				});
+			};
+
+			let (variable, default) = match reference[..end].split_once(":-") {
+				Some((variable, default)) => (variable, Some(default)),
+				None => (&reference[..end], None),
+			};
+
+			if variable.is_empty() || !variable.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+				return Err(ConfigError::InvalidReference {
+					key: key.to_string(),
+					message: format!("invalid variable name '{variable}'"),
+				});
+			}
+
+			// Like in shells, the default is also used when the variable is set but empty
+			match (lookup(variable).filter(|v| !v.is_empty() || default.is_none()), default) {
+				(Some(v), _) => result.push_str(&v),
+				(None, Some(default)) => result.push_str(default),
+				(None, None) => {
+					return Err(ConfigError::MissingVariable {
+						variable: variable.to_string(),
+						key: key.to_string(),
+					})
+				}
+			}
+			rest = &reference[(end + 1)..];
+		} else {
+			// A dollar sign not followed by '{' is taken literally
+			result.push('$');
+			rest = after;
+		}
+	}
+
+	result.push_str(rest);
+	Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+	use super::{
+		from_toml_file, from_toml_str, interpolate_str, resolve_secrets, BackendConfig, BannedPhraseAction, ConfigError, SamplerConfig, Secret,
+		StopSequenceConfig,
+	};
+	use crate::{
+		sequence::MatchOptions,
+		types::{BackendError, PromptRequest},
+	};
+	use std::fs;
+
+	#[test]
+	fn test_check_reports_all_problems() {
+		let config: BackendConfig = toml::from_str(
+			r#"
+			inference_threads = 0
+
+			[models.gpt2]
+			architecture = "gpt2"
+			url = "https://example.com/gpt2.bin"
+			sha256 = "0123abcd"
+			prompt_chunk_size = 0
+			max_similarity_texts = 0
+
+			[models.missing]
+			architecture = "gpt2"
+			model_path = "../data/does-not-exist.bin"
+			tokenizer_path = "../data/does-not-exist.json"
+			tokenizer_repo = "gpt2"
+
+			[tasks.ok]
+			model = "gpt2"
+
+			[tasks.broken]
+			model = "gpt3"
+			memorization = { memory = "nope", store_prompts = false }
+			biaser = { json_schema = { type = "object", required = ["foo"], properties = {} } }
+			rerank = { method = "cross_scoring", template = "Is {document} relevant?", max_documents = 0 }
+			summarize = { max_levels = 0 }
+			tools = [{ name = "search", description = "Searches the web", arguments = { type = "string" } }]
+			validation = { repair_template = "Try again" }
+			banned_phrases = ["Project Falcon", { text = " ", action = "stop" }]
+			max_prompt_tokens = 0
+			max_duration_secs = 0
+			prompt_template = "Summarize {text"
+
+			[tasks.pattern]
+			model = "gpt2"
+			biaser = { regex = '(yes|no)' }
+			validation = { schema = { type = "string" } }
+
+			[tasks.choices]
+			model = "gpt2"
+			biaser = { choices = ["yes", "no", "", "yes"] }
+
+			[tasks.colors]
+			model = "gpt2"
+			biaser = { json_schema = { type = "enum", enum = [] } }
+
+			[tasks.code]
+			model = "gpt2"
+			biaser = { json_schema = { type = "string", min_length = 5, max_length = 3 } }
+
+			[tasks.percentage]
+			model = "gpt2"
+			biaser = { json_schema = { type = "integer", min = 0, max = 100, multiple_of = 0 } }
+			"#,
+		)
+		.unwrap();
+
+		let mut problems: Vec<String> = config.check().iter().map(|p| p.to_string()).collect();
+		problems.sort();
+		assert_eq!(
+			problems,
+			vec![
+				"inference_threads: must be larger than zero",
+				"models.gpt2: max_similarity_texts must be larger than zero",
+				"models.gpt2: prompt_chunk_size must be larger than zero",
+				"models.gpt2: sha256 must consist of 64 hexadecimal characters",
+				"models.missing: model file \"../data/does-not-exist.bin\" does not exist",
+				"models.missing: tokenizer_path and tokenizer_repo cannot both be set",
+				"tasks.broken.biaser: required field 'foo' has no schema in properties",
+				"tasks.broken.tools.search: arguments must be an object schema",
+				"tasks.broken.validation: repair_template must contain {errors}",
+				"tasks.broken: banned phrases must not be empty",
+				"tasks.broken: banned_phrases cannot be combined with a biaser or tools",
+				"tasks.broken: invalid prompt_template: unclosed '{' (use '{{' for a literal brace)",
+				"tasks.broken: max_duration_secs must be larger than zero",
+				"tasks.broken: max_prompt_tokens must be larger than zero",
+				"tasks.broken: memory 'nope' not found",
+				"tasks.broken: model 'gpt3' not found",
+				"tasks.broken: rerank.max_documents and rerank.budget_ms must be larger than zero",
+				"tasks.broken: rerank.template must contain {query} and {document}",
+				"tasks.broken: summarize.chunk_tokens, summarize.max_total_tokens and summarize.max_levels must be larger than zero",
+				"tasks.broken: tools cannot be combined with a biaser",
+				"tasks.choices.biaser: choices must be unique",
+				"tasks.choices.biaser: choices must not be empty",
+				"tasks.code.biaser: min_length is larger than max_length",
+				"tasks.colors.biaser: enum must not be empty",
+				"tasks.pattern.validation: a schema can only be used for a task with a JSON schema biaser",
+				"tasks.percentage.biaser: multiple_of must be larger than zero",
+			]
+		);
+
+		let config: BackendConfig = toml::from_str(
+			r#"
+			[models.gpt2]
+			architecture = "gpt2"
+			url = "https://example.com/gpt2.bin"
+
+			[tasks.pattern]
+			model = "gpt2"
+			biaser = { regex = '(yes|no' }
+
+			[tasks.code]
+			model = "gpt2"
+			biaser = { json_schema = { type = "string", pattern = '[A-Z' } }
+			"#,
+		)
+		.unwrap();
+		let mut problems: Vec<String> = config.check().iter().map(|p| p.to_string()).collect();
+		problems.sort();
+		assert_eq!(problems.len(), 2);
+		assert!(problems[0].starts_with("tasks.code.biaser: invalid pattern"));
+		assert!(problems[1].starts_with("tasks.pattern.biaser: invalid regular expression"));
+	}
+
+	#[test]
+	fn test_interpolate() {
+		let lookup = |name: &str| match name {
+			"MODELS" => Some(String::from("/srv/models")),
+			"EMPTY" => Some(String::new()),
+			_ => None,
+		};
+
+		assert_eq!(interpolate_str("${MODELS}/gpt2.bin", "k", &lookup).unwrap(), "/srv/models/gpt2.bin");
+		assert_eq!(interpolate_str("${MISSING:-/tmp}/x", "k", &lookup).unwrap(), "/tmp/x");
+		assert_eq!(interpolate_str("${EMPTY:-default}", "k", &lookup).unwrap(), "default");
+		assert_eq!(interpolate_str("${EMPTY}", "k", &lookup).unwrap(), "");
+		assert_eq!(interpolate_str("costs $$5 or $5", "k", &lookup).unwrap(), "costs $5 or $5");
+		assert_eq!(interpolate_str("$${MODELS}", "k", &lookup).unwrap(), "${MODELS}");
+
+		match interpolate_str("${MISSING}", "models.gpt2.model_path", &lookup) {
+			Err(ConfigError::MissingVariable { variable, key }) => {
+				assert_eq!(variable, "MISSING");
+				assert_eq!(key, "models.gpt2.model_path");
+			}
+			r => panic!("unexpected result {r:?}"),
+		}
+		assert!(interpolate_str("${MODELS", "k", &lookup).is_err());
+		assert!(interpolate_str("${FOO BAR}", "k", &lookup).is_err());
+	}
+
+	#[test]
+	fn test_secrets() {
+		let secret = Secret::new("hunter2");
+		assert_eq!(format!("{secret:?} {secret}"), "Secret(<redacted>) <redacted>");
+		assert!(secret.matches("hunter2"));
+		assert!(!secret.matches("hunter3"));
+		assert!(!secret.matches("hunter"));
+
+		let path = std::env::temp_dir().join(format!("poly-config-secret-{}", std::process::id()));
+		fs::write(&path, "from-file\n").unwrap();
+		let lookup = |name: &str| (name == "TOKEN").then(|| String::from("from-env"));
+		let resolve = |config: &str| {
+			let mut table: toml::Table = toml::from_str(config).unwrap();
+			resolve_secrets(&mut table, &["hf_token"], &lookup).map(|_| table)
+		};
+
+		let table = resolve(&format!("hf_token_file = {:?}", path.to_str().unwrap())).unwrap();
+		assert_eq!(table["hf_token"].as_str(), Some("from-file"));
+		assert!(!table.contains_key("hf_token_file"));
+		assert_eq!(resolve("hf_token_env = \"TOKEN\"").unwrap()["hf_token"].as_str(), Some("from-env"));
+		assert_eq!(resolve("hf_token = \"inline\"").unwrap()["hf_token"].as_str(), Some("inline"));
+
+		assert!(matches!(
+			resolve("hf_token = \"inline\"\nhf_token_env = \"TOKEN\""),
+			Err(ConfigError::Conflict { .. })
+		));
+		assert!(matches!(resolve("hf_token_env = \"MISSING\""), Err(ConfigError::MissingVariable { .. })));
+		match resolve("hf_token_file = \"/nonexistent/hf_token\"") {
+			Err(ConfigError::Read { key, .. }) => assert_eq!(key, "hf_token_file"),
+			r => panic!("unexpected result {r:?}"),
+		}
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_include() {
+		let dir = std::env::temp_dir().join(format!("poly-config-include-{}", std::process::id()));
+		fs::create_dir_all(dir.join("tasks")).unwrap();
+		fs::write(
+			dir.join("config.toml"),
+			r#"
+			include = ["tasks/*.toml"]
+
+			[models.gpt2]
+			architecture = "gpt2"
+			url = "https://example.com/gpt2.bin"
+			"#,
+		)
+		.unwrap();
+		fs::write(dir.join("tasks/b.toml"), "[tasks.broken]\nmodel = \"gpt3\"\n").unwrap();
+		fs::write(dir.join("tasks/a.toml"), "[tasks.ok]\nmodel = \"gpt2\"\n").unwrap();
+
+		let (mut config, sources): (BackendConfig, _) = from_toml_file(&dir.join("config.toml")).unwrap();
+		config.sources = sources;
+		assert_eq!(config.tasks.len(), 2);
+		assert_eq!(config.sources.file_for("models.gpt2.url"), Some(dir.join("config.toml").as_path()));
+		assert_eq!(config.sources.file_for("tasks.ok"), Some(dir.join("tasks/a.toml").as_path()));
+
+		let problems = config.check();
+		assert_eq!(problems.len(), 1);
+		assert_eq!(problems[0].file.as_deref(), Some(dir.join("tasks/b.toml").as_path()));
+
+		// Defining the same entry twice is an error that mentions both files
+		fs::write(dir.join("tasks/c.toml"), "[tasks.ok]\nmodel = \"gpt2\"\n").unwrap();
+		match from_toml_file::<BackendConfig>(&dir.join("config.toml")) {
+			Err(ConfigError::IncludeConflict { key, first, second }) => {
+				assert_eq!(key, "tasks.ok");
+				assert_eq!(first, dir.join("tasks/a.toml"));
+				assert_eq!(second, dir.join("tasks/c.toml"));
+			}
+			r => panic!("unexpected result {r:?}"),
+		}
+
+		// Literal includes must exist
+		fs::write(dir.join("config.toml"), "include = [\"missing.toml\"]\n").unwrap();
+		assert!(matches!(
+			from_toml_file::<BackendConfig>(&dir.join("config.toml")),
+			Err(ConfigError::Include { .. })
+		));
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn test_task_defaults() {
+		let config: BackendConfig = from_toml_str(
+			r#"
+			[task_defaults]
+			temperature = 0.5
+			max_tokens = 100
+			stop_sequences = ["\n\n"]
+			memorization = { memory = "facts", store_prompts = false, retrieve = 2 }
+
+			[task_profiles.chat]
+			prefix = "User: "
+			stop_sequences = ["User:"]
+
+			[tasks.plain]
+			model = "gpt2"
+
+			[tasks.chat]
+			model = "gpt2"
+			profile = "chat"
+			max_tokens = 20
+
+			[tasks.appended]
+			model = "gpt2"
+			profile = "chat"
+			"+stop_sequences" = ["Bot:"]
+			memorization = { memory = "other", store_prompts = true }
+			"#,
+		)
+		.unwrap();
+
+		let temperature = |task: &str| match &config.tasks[task].sampler {
+			SamplerConfig::Standard(s) => s.temperature,
+			SamplerConfig::Advanced(_) => panic!("unexpected sampler config"),
+		};
+
+		// Defaults apply to all tasks
+		let plain = &config.tasks["plain"];
+		assert_eq!(temperature("plain"), 0.5);
+		assert_eq!(plain.max_tokens, Some(100));
+		assert_eq!(plain.stop_sequences, vec!["\n\n"]);
+		assert_eq!(plain.prefix, None);
+		assert_eq!(plain.memorization.as_ref().unwrap().retrieve, Some(2));
+
+		// Profile settings replace defaults, task settings replace both
+		let chat = &config.tasks["chat"];
+		assert_eq!(temperature("chat"), 0.5);
+		assert_eq!(chat.max_tokens, Some(20));
+		assert_eq!(chat.prefix.as_deref(), Some("User: "));
+		assert_eq!(chat.stop_sequences, vec!["User:"]);
+		assert_eq!(chat.profile.as_deref(), Some("chat"));
+
+		// Arrays can be appended to explicitly; tables are replaced as a whole
+		let appended = &config.tasks["appended"];
+		assert_eq!(appended.stop_sequences, vec!["User:", "Bot:"]);
+		let memorization = appended.memorization.as_ref().unwrap();
+		assert_eq!(memorization.memory, "other");
+		assert_eq!(memorization.retrieve, None);
+
+		match from_toml_str::<BackendConfig>("[tasks.x]\nmodel = \"gpt2\"\nprofile = \"missing\"\n") {
+			Err(ConfigError::InvalidTaskSetting { key, .. }) => assert_eq!(key, "tasks.x.profile"),
+			r => panic!("unexpected result {r:?}"),
+		}
+		assert!(from_toml_str::<BackendConfig>("[tasks.x]\nmodel = \"gpt2\"\n\"+prefix\" = \"foo\"\n").is_err());
+	}
+
+	#[test]
+	fn test_task_overrides() {
+		let config: BackendConfig = from_toml_str(
+			r#"
+			[tasks.standard]
+			model = "gpt2"
+			temperature = 0.5
+			max_tokens = 100
+
+			[tasks.advanced]
+			model = "gpt2"
+			samplers = ["mirostat1:n_vocab=32000"]
+			"#,
+		)
+		.unwrap();
+
+		let standard = &config.tasks["standard"];
+		let request = PromptRequest {
+			temperature: Some(1.5),
+			max_tokens: Some(10),
+			..PromptRequest::new("hello")
+		};
+		let overridden = standard.with_overrides(&request).unwrap();
+		assert_eq!(overridden.max_tokens, Some(10));
+		assert_eq!(overridden.max_chars, None);
+		match overridden.sampler {
+			SamplerConfig::Standard(s) => assert_eq!((s.temperature, s.top_p), (1.5, 0.95)),
+			SamplerConfig::Advanced(_) => panic!("unexpected sampler config"),
+		}
+		assert_eq!(&standard.with_overrides(&PromptRequest::new("hello")).unwrap(), standard);
+
+		// Values out of range are rejected
+		let request = PromptRequest {
+			top_p: Some(1.5),
+			..PromptRequest::new("hello")
+		};
+		assert!(matches!(standard.with_overrides(&request), Err(BackendError::InvalidParameter(p, _)) if p == "top_p"));
+		let request = PromptRequest {
+			max_lines: Some(0),
+			..PromptRequest::new("hello")
+		};
+		assert!(matches!(standard.with_overrides(&request), Err(BackendError::InvalidParameter(p, _)) if p == "max_lines"));
+
+		// Stop sequences are added to the ones of the task
+		let request = PromptRequest {
+			stop: vec![String::from("\n\n")],
+			..PromptRequest::new("hello")
+		};
+		let overridden = standard.with_overrides(&request).unwrap();
+		assert_eq!(overridden.stop_sequences.len(), standard.stop_sequences.len() + 1);
+		assert_eq!(overridden.stop_sequences.last().map(StopSequenceConfig::text), Some("\n\n"));
+		let request = PromptRequest {
+			stop: vec![String::new()],
+			..PromptRequest::new("hello")
+		};
+		assert!(matches!(standard.with_overrides(&request), Err(BackendError::InvalidParameter(p, _)) if p == "stop"));
+
+		// Custom sampler chains cannot be changed
+		let advanced = &config.tasks["advanced"];
+		let request = PromptRequest {
+			temperature: Some(1.0),
+			..PromptRequest::new("hello")
+		};
+		assert!(advanced.with_overrides(&request).is_err());
+		assert!(advanced.with_overrides(&PromptRequest::new("hello")).is_ok());
+	}
+
+	#[test]
+	fn test_stop_sequences() {
+		let config: BackendConfig = from_toml_str(
+			r#"
+			[tasks.chat]
+			model = "gpt2"
+			stop_sequences = ["Bot:", { text = "User:", case_insensitive = true }, { text = "Human:", normalize_whitespace = true }]
+			"#,
+		)
+		.unwrap();
+
+		let stop_sequences = &config.tasks["chat"].stop_sequences;
+		assert_eq!(stop_sequences[0], "Bot:");
+		assert_eq!(stop_sequences[1].text(), "User:");
+		assert_eq!(
+			stop_sequences[1].options(),
+			MatchOptions {
+				case_insensitive: true,
+				normalize_whitespace: false
+			}
+		);
+		assert!(stop_sequences[2].options().normalize_whitespace);
+		assert_ne!(stop_sequences[1], "User:");
+	}
+
+	#[test]
+	fn test_banned_phrases() {
+		let config: BackendConfig = from_toml_str(
+			r#"
+			[tasks.chat]
+			model = "gpt2"
+			banned_phrases = ["Project Falcon", { text = "ACME", case_insensitive = false, action = "retry" }]
+			"#,
+		)
+		.unwrap();
+
+		let task = &config.tasks["chat"];
+		assert_eq!(task.banned_phrases[0].text(), "Project Falcon");
+		assert!(task.banned_phrases[0].options().case_insensitive);
+		assert_eq!(task.banned_phrases[0].action(), BannedPhraseAction::Censor);
+		assert!(!task.banned_phrases[1].options().case_insensitive);
+		assert_eq!(task.banned_phrases[1].action(), BannedPhraseAction::Retry);
+		assert_eq!(task.banned_phrase_placeholder, "[censored]");
+		assert_eq!(task.banned_phrase_retries, 3);
+	}
 }
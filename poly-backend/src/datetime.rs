@@ -0,0 +1,119 @@
+//! Describing points in time to a model: how long ago memorized items were stored (see
+//! [`crate::config::TaskMemorizationConfig::include_timestamps`]) and the current date and time, which can be put in
+//! the prefix of a task using the `{now}` and `{today}` variables.
+
+use std::borrow::Cow;
+
+const MINUTE: u64 = 60 * 1000;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+const WEEKDAYS: [&str; 7] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+const MONTHS: [&str; 12] = [
+	"January",
+	"February",
+	"March",
+	"April",
+	"May",
+	"June",
+	"July",
+	"August",
+	"September",
+	"October",
+	"November",
+	"December",
+];
+
+/// Describe how long ago something happened, given the time elapsed in milliseconds (e.g. "3 days ago")
+pub(crate) fn humanize_age(age_ms: u64) -> String {
+	let (count, unit) = match age_ms {
+		age if age < MINUTE => return String::from("just now"),
+		age if age < HOUR => (age / MINUTE, "minute"),
+		age if age < DAY => (age / HOUR, "hour"),
+		age if age < 30 * DAY => (age / DAY, "day"),
+		age if age < 365 * DAY => (age / (30 * DAY), "month"),
+		age => (age / (365 * DAY), "year"),
+	};
+	if count == 1 {
+		format!("1 {unit} ago")
+	} else {
+		format!("{count} {unit}s ago")
+	}
+}
+
+/// The date (in UTC) of a time in milliseconds since the Unix epoch, e.g. "Thursday 15 October 2026"
+pub(crate) fn format_date(millis: u64) -> String {
+	let days = millis / DAY;
+	let (year, month, day) = civil_from_days(days);
+	// The Unix epoch was on a Thursday
+	let weekday = WEEKDAYS[((days + 3) % 7) as usize];
+	format!("{weekday} {day} {} {year}", MONTHS[(month - 1) as usize])
+}
+
+/// The date and time (in UTC) of a time in milliseconds since the Unix epoch, e.g. "Thursday 15 October 2026, 14:03 UTC"
+pub(crate) fn format_datetime(millis: u64) -> String {
+	let minutes = (millis % DAY) / MINUTE;
+	format!("{}, {:02}:{:02} UTC", format_date(millis), minutes / 60, minutes % 60)
+}
+
+/// The date (in UTC) of a time in milliseconds since the Unix epoch in ISO 8601 format, e.g. "2026-10-15"
+pub(crate) fn format_iso_date(millis: u64) -> String {
+	let (year, month, day) = civil_from_days(millis / DAY);
+	format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Replace `{now}` and `{today}` in a text by the date and time (see [`format_datetime`]) and the date (see
+/// [`format_date`]) at the specified time
+pub(crate) fn expand_variables(text: &str, now_millis: u64) -> Cow<str> {
+	if !text.contains("{now}") && !text.contains("{today}") {
+		return Cow::Borrowed(text);
+	}
+	Cow::Owned(
+		text.replace("{now}", &format_datetime(now_millis))
+			.replace("{today}", &format_date(now_millis)),
+	)
+}
+
+/// Year, month (1-12) and day (1-31) of the day that is `days` days after the Unix epoch (see
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+	let z = days + 719_468;
+	let era = z / 146_097;
+	let day_of_era = z - era * 146_097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = year_of_era + era * 400 + u64::from(month <= 2);
+	(year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+	use super::{expand_variables, format_date, format_datetime, format_iso_date, humanize_age, DAY, HOUR, MINUTE};
+
+	#[test]
+	fn test_humanize_age() {
+		assert_eq!(humanize_age(10_000), "just now");
+		assert_eq!(humanize_age(MINUTE), "1 minute ago");
+		assert_eq!(humanize_age(5 * HOUR + 10 * MINUTE), "5 hours ago");
+		assert_eq!(humanize_age(3 * DAY + HOUR), "3 days ago");
+		assert_eq!(humanize_age(65 * DAY), "2 months ago");
+		assert_eq!(humanize_age(800 * DAY), "2 years ago");
+	}
+
+	#[test]
+	fn test_format() {
+		assert_eq!(format_date(0), "Thursday 1 January 1970");
+		// 2024-02-29T13:05:00Z
+		let leap_day = 1_709_211_900_000;
+		assert_eq!(format_datetime(leap_day), "Thursday 29 February 2024, 13:05 UTC");
+		assert_eq!(format_iso_date(leap_day), "2024-02-29");
+		assert_eq!(
+			expand_variables("Today is {today}. It is now {now}.", leap_day),
+			"Today is Thursday 29 February 2024. It is now Thursday 29 February 2024, 13:05 UTC."
+		);
+		assert_eq!(expand_variables("No {variables} here", leap_day), "No {variables} here");
+	}
+}
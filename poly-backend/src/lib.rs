@@ -1,7 +1,16 @@
 pub mod backend;
+pub mod cache;
 pub mod config;
+mod datetime;
 pub mod memory;
+pub mod pool;
+mod preflight;
+pub mod redact;
 pub mod sequence;
 pub mod session;
 pub mod stats;
+mod template;
+mod tools;
+pub mod trace;
 pub mod types;
+mod validation;
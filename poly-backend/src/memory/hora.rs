@@ -1,20 +1,86 @@
-use std::path::PathBuf;
+use std::{
+	collections::{HashMap, HashSet},
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::memory::{Memory, MemoryError};
+use crate::{
+	memory::{item_id, now_millis, Capacity, ItemMetadata, ItemUsage, Memory, MemoryError, MemoryHit, MemoryItem},
+	stats::MemoryStats,
+};
 use async_trait::async_trait;
 use hora::core::ann_index::ANNIndex;
 use hora::core::ann_index::SerializableIndex;
 use hora::index::hnsw_idx::HNSWIndex;
 use hora::index::hnsw_params::HNSWParams;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
 pub struct HoraMemory {
 	path: Option<PathBuf>,
 	index: Mutex<HNSWIndex<f32, String>>,
+
+	/// Metadata and usage of the chunks. The index can only hold the text of chunks, so these are persisted in a separate
+	/// file (see [`metadata_path`]). Always locked after `index`.
+	records: Mutex<Records>,
+
+	capacity: Option<Capacity>,
+
+	/// Number of chunks evicted since the memory was opened
+	evictions: AtomicUsize,
+}
+
+/// What is kept about a chunk next to the index
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ItemRecord {
+	#[serde(flatten)]
+	metadata: ItemMetadata,
+
+	#[serde(flatten)]
+	usage: ItemUsage,
+}
+
+/// The records of the chunks in the index by identifier. There is exactly one record for each chunk in the index.
+#[derive(Default)]
+struct Records {
+	items: HashMap<String, ItemRecord>,
+
+	/// The most recent time handed out by [`Records::timestamp`]
+	clock: u64,
+}
+
+impl Records {
+	/// The current time (see [`ItemUsage`]), but always later than the time handed out before, so that the order in which
+	/// chunks are stored and retrieved is kept within the same millisecond
+	fn timestamp(&mut self) -> u64 {
+		self.clock = now_millis().max(self.clock + 1);
+		self.clock
+	}
+
+	fn retrieved(&mut self, id: &str) {
+		let timestamp = self.timestamp();
+		if let Some(record) = self.items.get_mut(id) {
+			record.usage.retrieved_at = Some(timestamp);
+		}
+	}
+
+	/// Identifiers of the chunks to evict so that `incoming` more chunks fit, or None when they do not fit
+	fn victims(&self, capacity: &Capacity, incoming: usize) -> Option<HashSet<String>> {
+		capacity
+			.victims(self.items.iter().map(|(id, record)| (id.as_str(), record.usage)), incoming)
+			.map(|victims| victims.into_iter().map(String::from).collect())
+	}
+}
+
+/// Path of the file that holds the metadata of the chunks in the index at `path`
+fn metadata_path(path: &Path) -> PathBuf {
+	let mut metadata_path = path.as_os_str().to_owned();
+	metadata_path.push(".meta.json");
+	PathBuf::from(metadata_path)
 }
 
 impl HoraMemory {
-	pub fn new(path: Option<PathBuf>, dims: usize) -> Result<HoraMemory, MemoryError> {
+	pub fn new(path: Option<PathBuf>, dims: usize, capacity: Option<Capacity>) -> Result<HoraMemory, MemoryError> {
 		let index = if let Some(ref path) = path {
 			if path.exists() {
 				HNSWIndex::<f32, String>::load(path.to_str().unwrap()).unwrap()
@@ -30,63 +96,427 @@ impl HoraMemory {
 			return Err(MemoryError::DimensionalityMismatch);
 		}
 
-		Ok(HoraMemory {
+		let mut stored: HashMap<String, ItemRecord> = match path.as_deref().map(metadata_path) {
+			Some(metadata_path) if metadata_path.exists() => {
+				let json = std::fs::read(&metadata_path).map_err(|e| MemoryError::Storage(e.to_string()))?;
+				serde_json::from_slice(&json).map_err(|e| MemoryError::Storage(e.to_string()))?
+			}
+			_ => HashMap::new(),
+		};
+
+		// Older files only hold records for chunks that have metadata, and may hold records of removed chunks
+		let ids: HashSet<String> = items(&index).into_iter().map(|(text, _)| item_id(&text)).collect();
+		stored.retain(|id, _| ids.contains(id));
+		for id in ids {
+			stored.entry(id).or_default();
+		}
+		let clock = stored
+			.values()
+			.map(|record| record.usage.retrieved_at.unwrap_or(0).max(record.usage.stored_at))
+			.max()
+			.unwrap_or(0);
+
+		let mut memory = HoraMemory {
 			index: Mutex::new(index),
+			records: Mutex::new(Records { items: stored, clock }),
 			path,
-		})
+			capacity,
+			evictions: AtomicUsize::new(0),
+		};
+		memory.compact()?;
+		Ok(memory)
+	}
+
+	/// Evict chunks when the memory holds more than it may (e.g. because `max_items` was lowered since it was stored)
+	fn compact(&mut self) -> Result<(), MemoryError> {
+		let Some(capacity) = self.capacity else {
+			return Ok(());
+		};
+		let records = self.records.get_mut();
+		match records.victims(&capacity, 0) {
+			None => tracing::warn!(
+				items = records.items.len(),
+				max_items = capacity.max_items,
+				"memory holds more chunks than allowed; new chunks will be rejected"
+			),
+			Some(victims) if !victims.is_empty() => {
+				let index = self.index.get_mut();
+				let evicted = remove_items(index, records, &victims)?;
+				tracing::info!(
+					evicted,
+					max_items = capacity.max_items,
+					"evicted chunks from memory that holds more than allowed"
+				);
+				*self.evictions.get_mut() += evicted;
+				dump(self.path.as_deref(), index, &records.items)?;
+			}
+			Some(_) => {}
+		}
+		Ok(())
+	}
+
+	fn dump(&self, index: &HNSWIndex<f32, String>, records: &Records) {
+		if let Err(e) = dump(self.path.as_deref(), index, &records.items) {
+			tracing::error!("could not persist memory: {e}");
+		}
+	}
+}
+
+/// Write the index to a file (when a path is set). The index is written next to the file first and then moved over it,
+/// so that an interrupted write does not leave a corrupt file behind.
+fn dump(path: Option<&Path>, index: &HNSWIndex<f32, String>, records: &HashMap<String, ItemRecord>) -> Result<(), MemoryError> {
+	let Some(path) = path else {
+		return Ok(());
+	};
+	let mut temporary = path.as_os_str().to_owned();
+	temporary.push(".tmp");
+	let temporary = PathBuf::from(temporary);
+	index.dump(temporary.to_str().unwrap()).map_err(|e| MemoryError::Storage(e.to_string()))?;
+	std::fs::rename(&temporary, path).map_err(|e| MemoryError::Storage(e.to_string()))?;
+
+	let json = serde_json::to_vec(records).map_err(|e| MemoryError::Storage(e.to_string()))?;
+	let metadata_path = metadata_path(path);
+	let mut temporary = metadata_path.as_os_str().to_owned();
+	temporary.push(".tmp");
+	std::fs::write(&temporary, json).map_err(|e| MemoryError::Storage(e.to_string()))?;
+	std::fs::rename(&temporary, metadata_path).map_err(|e| MemoryError::Storage(e.to_string()))
+}
+
+/// Returns all chunks in the index with their embeddings, ordered by identifier. The index cannot be iterated, so this
+/// searches for as many nodes as it holds.
+fn items(index: &HNSWIndex<f32, String>) -> Vec<(String, Vec<f32>)> {
+	if index.nodes_size() == 0 {
+		return vec![];
 	}
+	let origin = vec![0.0; index.dimension()];
+	let mut items: Vec<(String, Vec<f32>)> = index
+		.search_nodes(&origin, index.nodes_size())
+		.into_iter()
+		.filter_map(|(node, _distance)| Some((node.idx().clone()?, node.vectors().clone())))
+		.collect();
+	items.sort_by_cached_key(|(text, _)| item_id(text));
+	items
+}
+
+/// Remove the chunks with the given identifiers from the index and their records, returning how many were removed. Nodes
+/// cannot be removed from the index, so it is rebuilt without the chunks.
+fn remove_items(index: &mut HNSWIndex<f32, String>, records: &mut Records, ids: &HashSet<String>) -> Result<usize, MemoryError> {
+	let remaining: Vec<(String, Vec<f32>)> = items(index).into_iter().filter(|(text, _)| !ids.contains(&item_id(text))).collect();
+	let removed = index.nodes_size() - remaining.len();
+	records.items.retain(|id, _| !ids.contains(id));
+	if removed == 0 {
+		return Ok(0);
+	}
+
+	if remaining.is_empty() {
+		index.clear();
+	} else {
+		let mut rebuilt = HNSWIndex::<f32, String>::new(index.dimension(), &HNSWParams::<f32>::default());
+		for (text, embedding) in remaining {
+			rebuilt.add(&embedding, text).map_err(|e| MemoryError::Storage(e.to_string()))?;
+		}
+		rebuilt
+			.build(hora::core::metrics::Metric::Euclidean)
+			.map_err(|e| MemoryError::Storage(e.to_string()))?;
+		*index = rebuilt;
+	}
+	Ok(removed)
 }
 
 impl Drop for HoraMemory {
 	fn drop(&mut self) {
-		if let Some(ref path) = self.path {
-			self.index.blocking_lock().dump(path.to_str().unwrap()).unwrap();
+		// The memory is dropped exclusively, so the index can be accessed without locking (which would panic when dropped
+		// from within the runtime)
+		if let Err(e) = dump(self.path.as_deref(), self.index.get_mut(), &self.records.get_mut().items) {
+			tracing::error!("could not persist memory: {e}");
 		}
 	}
 }
 
 #[async_trait]
 impl Memory for HoraMemory {
-	async fn store(&self, text: &str, embedding: &[f32]) -> Result<(), MemoryError> {
+	async fn store(&self, text: &str, embedding: &[f32], metadata: &ItemMetadata) -> Result<(), MemoryError> {
 		let mut index = self.index.lock().await;
 		assert_eq!(embedding.len(), index.dimension());
-		// TODO: error handling
-		index.add(embedding, text.to_string()).unwrap();
-		index.build(hora::core::metrics::Metric::Euclidean).unwrap();
-		if let Some(ref path) = self.path {
-			index.dump(path.to_str().unwrap()).unwrap();
+		let mut records = self.records.lock().await;
+		let id = item_id(text);
+
+		// A chunk that is already stored only has its record updated
+		if !records.items.contains_key(&id) {
+			if let Some(capacity) = self.capacity {
+				let victims = records.victims(&capacity, 1).ok_or(MemoryError::Full {
+					max_items: capacity.max_items,
+				})?;
+				if !victims.is_empty() {
+					let evicted = remove_items(&mut index, &mut records, &victims)?;
+					tracing::debug!(evicted, "evicted chunks from memory to make room");
+					self.evictions.fetch_add(evicted, Ordering::Relaxed);
+				}
+			}
+
+			// TODO: error handling
+			index.add(embedding, text.to_string()).unwrap();
+			index.build(hora::core::metrics::Metric::Euclidean).unwrap();
 		}
+
+		let stored_at = records.timestamp();
+		records.items.insert(
+			id,
+			ItemRecord {
+				metadata: metadata.clone(),
+				usage: ItemUsage {
+					stored_at,
+					retrieved_at: None,
+				},
+			},
+		);
+		self.dump(&index, &records);
 		Ok(())
 	}
 
 	async fn get(&self, embedding: &[f32], top_n: usize) -> Result<Vec<String>, MemoryError> {
 		let index = self.index.lock().await;
 		assert_eq!(embedding.len(), index.dimension());
-		Ok(index.search(embedding, top_n))
+		let texts = index.search(embedding, top_n);
+		let mut records = self.records.lock().await;
+		for text in &texts {
+			records.retrieved(&item_id(text));
+		}
+		Ok(texts)
+	}
+
+	async fn search(&self, embedding: &[f32], top_n: usize, filter: &ItemMetadata) -> Result<Vec<MemoryHit>, MemoryError> {
+		let index = self.index.lock().await;
+		let mut records = self.records.lock().await;
+		assert_eq!(embedding.len(), index.dimension());
+
+		// The index cannot filter, so when filtering all chunks are searched and the first matching ones are returned
+		let n = if *filter == ItemMetadata::default() { top_n } else { index.nodes_size() };
+		let hits: Vec<MemoryHit> = index
+			.search_nodes(embedding, n)
+			.into_iter()
+			.filter_map(|(node, distance)| {
+				let text = node.idx().clone()?;
+				let id = item_id(&text);
+				let record = records.items.get(&id).cloned().unwrap_or_default();
+				Some(MemoryHit {
+					id,
+					text,
+					// Distances are Euclidean; map them so that closer chunks score higher (at most 1.0)
+					score: 1.0 / (1.0 + distance),
+					stored_at: Some(record.usage.stored_at).filter(|t| *t > 0),
+					metadata: record.metadata,
+				})
+			})
+			.filter(|hit| hit.metadata.matches(filter))
+			.take(top_n)
+			.collect();
+		for hit in &hits {
+			records.retrieved(&hit.id);
+		}
+		Ok(hits)
+	}
+
+	async fn list(&self, offset: usize, limit: usize) -> Result<Vec<MemoryItem>, MemoryError> {
+		let index = self.index.lock().await;
+		let records = self.records.lock().await;
+		Ok(items(&index)
+			.into_iter()
+			.skip(offset)
+			.take(limit)
+			.map(|(text, _)| {
+				let id = item_id(&text);
+				let metadata = records.items.get(&id).map(|record| record.metadata.clone()).unwrap_or_default();
+				MemoryItem { id, text, metadata }
+			})
+			.collect())
+	}
+
+	async fn remove(&self, id: &str) -> Result<(), MemoryError> {
+		let mut index = self.index.lock().await;
+		let mut records = self.records.lock().await;
+		if remove_items(&mut index, &mut records, &HashSet::from([id.to_string()]))? > 0 {
+			self.dump(&index, &records);
+		}
+		Ok(())
 	}
 
 	async fn clear(&self) -> Result<(), MemoryError> {
 		let mut index = self.index.lock().await;
 		index.clear();
-		if let Some(ref path) = self.path {
-			index.dump(path.to_str().unwrap()).unwrap();
-		}
+		let mut records = self.records.lock().await;
+		records.items.clear();
+		self.dump(&index, &records);
 		Ok(())
 	}
+
+	async fn flush(&self) -> Result<(), MemoryError> {
+		let index = self.index.lock().await;
+		let records = self.records.lock().await;
+		dump(self.path.as_deref(), &index, &records.items)
+	}
+
+	async fn stats(&self) -> Result<MemoryStats, MemoryError> {
+		let index = self.index.lock().await;
+		Ok(MemoryStats {
+			items: index.nodes_size(),
+			max_items: self.capacity.map(|capacity| capacity.max_items),
+			evictions: self.evictions.load(Ordering::Relaxed),
+		})
+	}
 }
 
 #[cfg(test)]
 mod test {
 	use super::HoraMemory;
-	use crate::memory::Memory;
+	use crate::memory::{item_id, Capacity, EvictionPolicy, ItemMetadata, Memory, MemoryError};
 
 	#[tokio::test]
 	pub async fn test_store() {
-		let hm = HoraMemory::new(None, 3).unwrap();
-		hm.store("foo", &[1.0, 2.0, 3.0]).await.unwrap();
-		hm.store("bar", &[-1.0, 2.0, 3.0]).await.unwrap();
-		hm.store("baz", &[1.0, -2.0, 3.0]).await.unwrap();
-		hm.store("boo", &[1.0, -2.0, -3.0]).await.unwrap();
+		let hm = HoraMemory::new(None, 3, None).unwrap();
+		hm.store("foo", &[1.0, 2.0, 3.0], &ItemMetadata::default()).await.unwrap();
+		hm.store("bar", &[-1.0, 2.0, 3.0], &ItemMetadata::default()).await.unwrap();
+		hm.store("baz", &[1.0, -2.0, 3.0], &ItemMetadata::default()).await.unwrap();
+		hm.store("boo", &[1.0, -2.0, -3.0], &ItemMetadata::default()).await.unwrap();
 		assert_eq!(hm.get(&[0.0, -1.0, 0.0], 2).await.unwrap(), vec!["baz", "boo"]);
 	}
+
+	#[tokio::test]
+	pub async fn test_browse() {
+		let hm = HoraMemory::new(None, 3, None).unwrap();
+		hm.store("foo", &[1.0, 2.0, 3.0], &ItemMetadata::default()).await.unwrap();
+		hm.store("bar", &[-1.0, 2.0, 3.0], &ItemMetadata::default()).await.unwrap();
+		hm.store("baz", &[1.0, -2.0, 3.0], &ItemMetadata::default()).await.unwrap();
+
+		let hits = hm.search(&[1.0, 2.0, 3.0], 2, &ItemMetadata::default()).await.unwrap();
+		assert_eq!(hits[0].text, "foo");
+		assert_eq!(hits[0].id, item_id("foo"));
+		assert_eq!(hits[0].score, 1.0);
+		assert!(hits[1].score < hits[0].score);
+		assert!(hits[0].stored_at.is_some_and(|t| t > 0));
+
+		let items = hm.list(0, 10).await.unwrap();
+		assert_eq!(items.len(), 3);
+		assert!(items.windows(2).all(|w| w[0].id < w[1].id));
+		assert_eq!(hm.list(1, 1).await.unwrap(), items[1..2]);
+
+		hm.remove(&item_id("foo")).await.unwrap();
+		hm.remove(&item_id("missing")).await.unwrap();
+		let mut texts: Vec<String> = hm.list(0, 10).await.unwrap().into_iter().map(|item| item.text).collect();
+		texts.sort();
+		assert_eq!(texts, vec!["bar", "baz"]);
+	}
+
+	fn language(language: &str) -> ItemMetadata {
+		ItemMetadata {
+			language: Some(language.to_string()),
+		}
+	}
+
+	#[tokio::test]
+	pub async fn test_filter() {
+		let hm = HoraMemory::new(None, 3, None).unwrap();
+		hm.store("foo", &[1.0, 2.0, 3.0], &language("eng")).await.unwrap();
+		hm.store("bar", &[1.0, 2.0, 2.0], &language("nld")).await.unwrap();
+		hm.store("baz", &[-1.0, -2.0, -3.0], &language("nld")).await.unwrap();
+
+		// Without a filter the nearest chunks are returned, otherwise the nearest chunks that match
+		let hits = hm.search(&[1.0, 2.0, 3.0], 1, &ItemMetadata::default()).await.unwrap();
+		assert_eq!(hits[0].text, "foo");
+		assert_eq!(hits[0].metadata, language("eng"));
+		let hits = hm.search(&[1.0, 2.0, 3.0], 2, &language("nld")).await.unwrap();
+		let texts: Vec<&str> = hits.iter().map(|hit| hit.text.as_str()).collect();
+		assert_eq!(texts, vec!["bar", "baz"]);
+		assert!(hm.search(&[1.0, 2.0, 3.0], 2, &language("deu")).await.unwrap().is_empty());
+
+		hm.remove(&item_id("bar")).await.unwrap();
+		let hits = hm.search(&[1.0, 2.0, 3.0], 2, &language("nld")).await.unwrap();
+		assert_eq!(hits.len(), 1);
+	}
+
+	async fn texts(hm: &HoraMemory) -> Vec<String> {
+		let mut texts: Vec<String> = hm.list(0, 10).await.unwrap().into_iter().map(|item| item.text).collect();
+		texts.sort();
+		texts
+	}
+
+	#[tokio::test]
+	pub async fn test_capacity() {
+		let none = ItemMetadata::default();
+		let capacity = |eviction| Some(Capacity { max_items: 2, eviction });
+
+		let hm = HoraMemory::new(None, 3, capacity(EvictionPolicy::Reject)).unwrap();
+		hm.store("foo", &[1.0, 2.0, 3.0], &none).await.unwrap();
+		hm.store("bar", &[-1.0, 2.0, 3.0], &none).await.unwrap();
+		assert!(matches!(
+			hm.store("baz", &[1.0, -2.0, 3.0], &none).await,
+			Err(MemoryError::Full { max_items: 2 })
+		));
+		// Storing a chunk that is already stored does not need room
+		hm.store("foo", &[1.0, 2.0, 3.0], &none).await.unwrap();
+		assert_eq!(texts(&hm).await, vec!["bar", "foo"]);
+
+		let hm = HoraMemory::new(None, 3, capacity(EvictionPolicy::EvictOldest)).unwrap();
+		hm.store("foo", &[1.0, 2.0, 3.0], &none).await.unwrap();
+		hm.store("bar", &[-1.0, 2.0, 3.0], &none).await.unwrap();
+		hm.get(&[1.0, 2.0, 3.0], 1).await.unwrap();
+		hm.store("baz", &[1.0, -2.0, 3.0], &none).await.unwrap();
+		assert_eq!(texts(&hm).await, vec!["bar", "baz"]);
+
+		let hm = HoraMemory::new(None, 3, capacity(EvictionPolicy::EvictLeastRecentlyRetrieved)).unwrap();
+		hm.store("foo", &[1.0, 2.0, 3.0], &none).await.unwrap();
+		hm.store("bar", &[-1.0, 2.0, 3.0], &none).await.unwrap();
+		hm.search(&[1.0, 2.0, 3.0], 1, &none).await.unwrap();
+		hm.store("baz", &[1.0, -2.0, 3.0], &none).await.unwrap();
+		assert_eq!(texts(&hm).await, vec!["baz", "foo"]);
+
+		let stats = hm.stats().await.unwrap();
+		assert_eq!(stats.items, 2);
+		assert_eq!(stats.max_items, Some(2));
+		assert_eq!(stats.evictions, 1);
+	}
+
+	#[tokio::test]
+	pub async fn test_compact() {
+		let path = std::env::temp_dir().join(format!("poly-hora-compact-test-{}.idx", std::process::id()));
+		let hm = HoraMemory::new(Some(path.clone()), 3, None).unwrap();
+		hm.store("foo", &[1.0, 2.0, 3.0], &language("eng")).await.unwrap();
+		hm.store("bar", &[-1.0, 2.0, 3.0], &language("nld")).await.unwrap();
+		hm.store("baz", &[1.0, -2.0, 3.0], &language("eng")).await.unwrap();
+		drop(hm);
+
+		// When opened with a lower capacity, the oldest chunks are evicted from both the index and the metadata
+		let capacity = Capacity {
+			max_items: 1,
+			eviction: EvictionPolicy::EvictOldest,
+		};
+		let hm = HoraMemory::new(Some(path.clone()), 3, Some(capacity)).unwrap();
+		assert_eq!(texts(&hm).await, vec!["baz"]);
+		assert_eq!(hm.stats().await.unwrap().evictions, 2);
+		drop(hm);
+
+		let hm = HoraMemory::new(Some(path.clone()), 3, None).unwrap();
+		assert_eq!(hm.list(0, 10).await.unwrap()[0].metadata, language("eng"));
+		assert_eq!(hm.records.lock().await.items.len(), 1);
+		drop(hm);
+		std::fs::remove_file(&path).unwrap();
+		std::fs::remove_file(super::metadata_path(&path)).unwrap();
+	}
+
+	#[tokio::test]
+	pub async fn test_flush() {
+		let path = std::env::temp_dir().join(format!("poly-hora-test-{}.idx", std::process::id()));
+		let hm = HoraMemory::new(Some(path.clone()), 3, None).unwrap();
+		hm.store("foo", &[1.0, 2.0, 3.0], &language("eng")).await.unwrap();
+		hm.flush().await.unwrap();
+		drop(hm);
+
+		// Metadata is persisted with the index
+		let hm = HoraMemory::new(Some(path.clone()), 3, None).unwrap();
+		assert_eq!(hm.get(&[1.0, 2.0, 3.0], 1).await.unwrap(), vec!["foo"]);
+		assert_eq!(hm.list(0, 1).await.unwrap()[0].metadata, language("eng"));
+		drop(hm);
+		std::fs::remove_file(&path).unwrap();
+		std::fs::remove_file(super::metadata_path(&path)).unwrap();
+	}
 }
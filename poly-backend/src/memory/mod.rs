@@ -3,14 +3,17 @@ mod hora;
 #[cfg(feature = "qdrant")]
 mod qdrant;
 
-use std::path::PathBuf;
+use std::{
+	path::PathBuf,
+	time::{SystemTime, UNIX_EPOCH},
+};
 
 use async_trait::async_trait;
 use llm::TokenId;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::config::MemoryConfig;
+use crate::{config::MemoryConfig, stats::MemoryStats};
 
 #[derive(Debug, Error)]
 pub enum MemoryError {
@@ -19,21 +22,161 @@ pub enum MemoryError {
 
 	#[error("storage error: {0}")]
 	Storage(String),
+
+	/// The memory holds the maximum number of chunks and its eviction policy is [`EvictionPolicy::Reject`]
+	#[error("memory is full (holds the maximum of {max_items} chunks)")]
+	Full { max_items: usize },
+}
+
+/// Information about a chunk that is stored alongside it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ItemMetadata {
+	/// Language of the document the chunk was taken from (ISO 639-3 code, e.g. "eng"), or "unknown" when it could not be
+	/// detected
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub language: Option<String>,
+}
+
+impl ItemMetadata {
+	/// Whether this metadata matches a filter, i.e. has the same value for all fields that are set in the filter
+	pub fn matches(&self, filter: &ItemMetadata) -> bool {
+		filter.language.is_none() || filter.language == self.language
+	}
+}
+
+/// A chunk stored in a memory
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MemoryItem {
+	/// Identifies the chunk; derived from its text (see [`item_id`])
+	pub id: String,
+	pub text: String,
+
+	#[serde(flatten)]
+	pub metadata: ItemMetadata,
+}
+
+/// A chunk retrieved from a memory for a query
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MemoryHit {
+	pub id: String,
+	pub text: String,
+
+	/// How relevant the chunk is to the query (higher is more relevant; the scale depends on the memory store)
+	pub score: f32,
+
+	/// When the chunk was stored, in milliseconds since the Unix epoch (unknown for chunks stored before this was recorded)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub stored_at: Option<u64>,
+
+	#[serde(flatten)]
+	pub metadata: ItemMetadata,
+}
+
+/// What to do when a chunk is stored in a memory that already holds the maximum number of chunks
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionPolicy {
+	/// Refuse to store the chunk
+	#[default]
+	Reject,
+
+	/// Remove the chunk that was stored longest ago
+	EvictOldest,
+
+	/// Remove the chunk that was last returned by a search longest ago (chunks that were never returned count as
+	/// retrieved when they were stored)
+	EvictLeastRecentlyRetrieved,
+}
+
+/// Limit on the number of chunks in a memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+	pub max_items: usize,
+	pub eviction: EvictionPolicy,
+}
+
+/// When a chunk was stored and last retrieved, in milliseconds since the Unix epoch. Used to decide which chunks to evict.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ItemUsage {
+	/// Zero for chunks stored before usage was recorded, which are therefore evicted first
+	#[serde(default)]
+	pub stored_at: u64,
+
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub retrieved_at: Option<u64>,
+}
+
+impl Capacity {
+	/// Select the chunks to remove from a memory holding `items` so that `incoming` more chunks fit. Returns None when
+	/// they do not fit and the policy is to reject them.
+	pub fn victims<'a>(&self, items: impl IntoIterator<Item = (&'a str, ItemUsage)>, incoming: usize) -> Option<Vec<&'a str>> {
+		let mut items: Vec<(&str, ItemUsage)> = items.into_iter().collect();
+		let excess = (items.len() + incoming).saturating_sub(self.max_items);
+		if excess == 0 {
+			return Some(vec![]);
+		}
+
+		// Ties are broken by identifier, so that the same chunks are chosen each time
+		match self.eviction {
+			EvictionPolicy::Reject => return None,
+			EvictionPolicy::EvictOldest => items.sort_by_key(|(id, usage)| (usage.stored_at, *id)),
+			EvictionPolicy::EvictLeastRecentlyRetrieved => items.sort_by_key(|(id, usage)| (usage.retrieved_at.unwrap_or(usage.stored_at), *id)),
+		}
+		Some(items.into_iter().take(excess).map(|(id, _)| id).collect())
+	}
+}
+
+/// Returns the current time in milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// What to search a memory for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryQuery<'a> {
+	/// A text, which is embedded using the embedding model of the memory
+	Prompt(&'a str),
+
+	/// An embedding calculated before (e.g. by the client itself). It must have the dimensionality of the memory.
+	Embedding(&'a [f32]),
+}
+
+const ITEM_NAMESPACE: uuid::Uuid = uuid::uuid!("067FB304-F9B1-4E74-8ACA-28051B8492AB");
+
+/// Returns the identifier of a chunk with the specified text
+pub fn item_id(text: &str) -> String {
+	uuid::Uuid::new_v5(&ITEM_NAMESPACE, text.as_bytes()).to_string()
 }
 
 #[async_trait]
 pub trait Memory: Send + Sync {
 	/// Store the provided chunk in the memory
-	async fn store(&self, text: &str, embedding: &[f32]) -> Result<(), MemoryError>;
+	async fn store(&self, text: &str, embedding: &[f32], metadata: &ItemMetadata) -> Result<(), MemoryError>;
 
 	/// Retrieve relevant chunks from memory given an embedding. At most `top_n` chunks will be returned
 	async fn get(&self, embedding: &[f32], top_n: usize) -> Result<Vec<String>, MemoryError>;
 
+	/// Like `get`, but returns the chunks with their identifiers, metadata and relevance scores (most relevant first). Only
+	/// chunks whose metadata matches `filter` are returned (see [`ItemMetadata::matches`]).
+	async fn search(&self, embedding: &[f32], top_n: usize, filter: &ItemMetadata) -> Result<Vec<MemoryHit>, MemoryError>;
+
+	/// List the chunks in the memory, skipping the first `offset` and returning at most `limit`
+	async fn list(&self, offset: usize, limit: usize) -> Result<Vec<MemoryItem>, MemoryError>;
+
+	/// Remove a chunk from the memory. Removing a chunk that is not in the memory is not an error.
+	async fn remove(&self, id: &str) -> Result<(), MemoryError>;
+
 	/// Clear the memory
 	async fn clear(&self) -> Result<(), MemoryError>;
+
+	/// Make sure everything stored in the memory is persisted (e.g. before the process exits)
+	async fn flush(&self) -> Result<(), MemoryError>;
+
+	/// The number of chunks in the memory, its capacity and the number of chunks evicted since it was opened
+	async fn stats(&self) -> Result<MemoryStats, MemoryError>;
 }
 
-#[derive(Deserialize, Debug, Clone, Serialize)]
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum MemoryStoreConfig {
 	Hora {
@@ -60,10 +203,19 @@ fn default_qdrant_url() -> String {
 impl MemoryStoreConfig {
 	pub fn from(&self, memory_config: &MemoryConfig) -> Result<Box<dyn Memory>, MemoryError> {
 		match self {
-			Self::Hora { path } => Ok(Box::new(hora::HoraMemory::new(path.clone(), memory_config.dimensions)?)),
+			Self::Hora { path } => Ok(Box::new(hora::HoraMemory::new(
+				path.clone(),
+				memory_config.dimensions,
+				memory_config.capacity(),
+			)?)),
 
 			#[cfg(feature = "qdrant")]
-			Self::Qdrant { url, collection } => Ok(Box::new(qdrant::QdrantMemory::new(url, collection, memory_config.dimensions)?)),
+			Self::Qdrant { url, collection } => Ok(Box::new(qdrant::QdrantMemory::new(
+				url,
+				collection,
+				memory_config.dimensions,
+				memory_config.capacity(),
+			)?)),
 		}
 	}
 }
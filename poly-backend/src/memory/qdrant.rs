@@ -1,40 +1,147 @@
+use std::{
+	collections::{HashMap, HashSet},
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
 use async_trait::async_trait;
-use qdrant_client::{prelude::*, qdrant::PointsSelector};
+use qdrant_client::{
+	prelude::*,
+	qdrant::{
+		points_selector::PointsSelectorOneOf, value::Kind, Condition, CountPoints, Filter, PointId, PointsIdsList, PointsSelector, ScrollPoints,
+		Value,
+	},
+};
 use serde_json::json;
 
-use super::{Memory, MemoryError};
+use super::{item_id, now_millis, Capacity, EvictionPolicy, ItemMetadata, ItemUsage, Memory, MemoryError, MemoryHit, MemoryItem};
+use crate::stats::MemoryStats;
 
 pub struct QdrantMemory {
 	client: QdrantClient,
 	collection_name: String,
 	dimensions: usize,
+	capacity: Option<Capacity>,
+
+	/// Number of chunks evicted since the memory was opened
+	evictions: AtomicUsize,
 }
 
 impl QdrantMemory {
-	pub fn new(url: &str, collection_name: &str, dimensions: usize) -> Result<QdrantMemory, MemoryError> {
+	pub fn new(url: &str, collection_name: &str, dimensions: usize, capacity: Option<Capacity>) -> Result<QdrantMemory, MemoryError> {
 		let config = QdrantClientConfig::from_url(url);
 		let client = QdrantClient::new(Some(config)).map_err(|x| MemoryError::Storage(x.to_string()))?;
 		Ok(QdrantMemory {
 			client,
 			collection_name: collection_name.to_string(),
 			dimensions,
+			capacity,
+			evictions: AtomicUsize::new(0),
 		})
 	}
+
+	/// Selects points by their identifiers
+	fn selector(ids: impl IntoIterator<Item = String>) -> PointsSelector {
+		PointsSelector {
+			points_selector_one_of: Some(PointsSelectorOneOf::Points(PointsIdsList {
+				ids: ids.into_iter().map(PointId::from).collect(),
+			})),
+		}
+	}
+
+	/// Make room for a chunk with the given identifier by evicting other chunks when needed. As the memory never holds
+	/// more than the maximum number of chunks, all of them are retrieved with a single request.
+	async fn make_room(&self, capacity: &Capacity, id: &str) -> Result<(), MemoryError> {
+		let scroll_result = self
+			.client
+			.scroll(&ScrollPoints {
+				collection_name: self.collection_name.to_string(),
+				limit: Some(capacity.max_items as u32 + 1),
+				with_payload: Some(true.into()),
+				..Default::default()
+			})
+			.await
+			.map_err(|x| MemoryError::Storage(x.to_string()))?;
+
+		let items: Vec<(String, ItemUsage)> = scroll_result
+			.result
+			.iter()
+			.map(|r| (item_id(&payload_text(&r.payload)), payload_usage(&r.payload)))
+			.collect();
+		// Storing a chunk that is already stored replaces it
+		if items.iter().any(|(item, _)| item == id) {
+			return Ok(());
+		}
+
+		let victims: HashSet<String> = capacity
+			.victims(items.iter().map(|(id, usage)| (id.as_str(), *usage)), 1)
+			.ok_or(MemoryError::Full {
+				max_items: capacity.max_items,
+			})?
+			.into_iter()
+			.map(String::from)
+			.collect();
+		if !victims.is_empty() {
+			let evicted = victims.len();
+			self.client
+				.delete_points(self.collection_name.to_string(), None, &Self::selector(victims), None)
+				.await
+				.map_err(|x| MemoryError::Storage(x.to_string()))?;
+			tracing::debug!(evicted, "evicted chunks from memory to make room");
+			self.evictions.fetch_add(evicted, Ordering::Relaxed);
+		}
+		Ok(())
+	}
+}
+
+/// Returns the text of a chunk from the payload of its point
+fn payload_text(payload: &HashMap<String, Value>) -> String {
+	match payload.get("text").and_then(|value| value.kind.as_ref()) {
+		Some(Kind::StringValue(text)) => text.clone(),
+		Some(_) => payload["text"].to_string(),
+		None => String::new(),
+	}
 }
 
-const ITEM_NAMESPACE: uuid::Uuid = uuid::uuid!("067FB304-F9B1-4E74-8ACA-28051B8492AB");
+/// Returns when a chunk was stored and retrieved from the payload of its point
+fn payload_usage(payload: &HashMap<String, Value>) -> ItemUsage {
+	let timestamp = |key: &str| match payload.get(key).and_then(|value| value.kind.as_ref()) {
+		Some(Kind::IntegerValue(timestamp)) => Some(*timestamp as u64),
+		_ => None,
+	};
+	ItemUsage {
+		stored_at: timestamp("stored_at").unwrap_or(0),
+		retrieved_at: timestamp("retrieved_at"),
+	}
+}
+
+/// Returns the metadata of a chunk from the payload of its point
+fn payload_metadata(payload: &HashMap<String, Value>) -> ItemMetadata {
+	ItemMetadata {
+		language: match payload.get("language").and_then(|value| value.kind.as_ref()) {
+			Some(Kind::StringValue(language)) => Some(language.clone()),
+			_ => None,
+		},
+	}
+}
 
 #[async_trait]
 impl Memory for QdrantMemory {
-	async fn store(&self, text: &str, embedding: &[f32]) -> Result<(), MemoryError> {
+	async fn store(&self, text: &str, embedding: &[f32], metadata: &ItemMetadata) -> Result<(), MemoryError> {
 		assert_eq!(
 			embedding.len(),
 			self.dimensions,
 			"embedding to store must have same dimensionality as configured for the memory"
 		);
-		let payload: Payload = json!({ "text": text }).try_into().unwrap();
-		let id = uuid::Uuid::new_v5(&ITEM_NAMESPACE, text.as_bytes());
-		let points = vec![PointStruct::new(id.to_string(), embedding.to_vec(), payload)];
+		if let Some(ref capacity) = self.capacity {
+			self.make_room(capacity, &item_id(text)).await?;
+		}
+
+		let mut payload = json!({ "text": text, "stored_at": now_millis() });
+		if let Some(ref language) = metadata.language {
+			payload["language"] = json!(language);
+		}
+		let payload: Payload = payload.try_into().unwrap();
+		let points = vec![PointStruct::new(item_id(text), embedding.to_vec(), payload)];
 		self.client
 			.upsert_points_blocking(&self.collection_name, None, points, None)
 			.await
@@ -43,6 +150,15 @@ impl Memory for QdrantMemory {
 	}
 
 	async fn get(&self, embedding: &[f32], top_n: usize) -> Result<Vec<String>, MemoryError> {
+		Ok(self
+			.search(embedding, top_n, &ItemMetadata::default())
+			.await?
+			.into_iter()
+			.map(|hit| hit.text)
+			.collect())
+	}
+
+	async fn search(&self, embedding: &[f32], top_n: usize, filter: &ItemMetadata) -> Result<Vec<MemoryHit>, MemoryError> {
 		assert_eq!(
 			embedding.len(),
 			self.dimensions,
@@ -53,7 +169,10 @@ impl Memory for QdrantMemory {
 			.search_points(&SearchPoints {
 				collection_name: self.collection_name.to_string(),
 				vector: embedding.to_vec(),
-				filter: None,
+				filter: filter
+					.language
+					.as_ref()
+					.map(|language| Filter::must([Condition::matches("language", language.clone())])),
 				limit: top_n as u64,
 				with_payload: Some(true.into()),
 				..Default::default()
@@ -61,7 +180,74 @@ impl Memory for QdrantMemory {
 			.await
 			.map_err(|x| MemoryError::Storage(x.to_string()))?;
 
-		Ok(search_result.result.into_iter().map(|r| r.payload["text"].to_string()).collect())
+		let hits: Vec<MemoryHit> = search_result
+			.result
+			.into_iter()
+			.map(|r| {
+				let text = payload_text(&r.payload);
+				MemoryHit {
+					id: item_id(&text),
+					text,
+					score: r.score,
+					stored_at: Some(payload_usage(&r.payload).stored_at).filter(|t| *t > 0),
+					metadata: payload_metadata(&r.payload),
+				}
+			})
+			.collect();
+
+		// Retrieval is only recorded when it is used for eviction, as it takes another request
+		let records_retrieval = matches!(
+			self.capacity,
+			Some(Capacity {
+				eviction: EvictionPolicy::EvictLeastRecentlyRetrieved,
+				..
+			})
+		);
+		if records_retrieval && !hits.is_empty() {
+			let payload: Payload = json!({ "retrieved_at": now_millis() }).try_into().unwrap();
+			let selector = Self::selector(hits.iter().map(|hit| hit.id.clone()));
+			self.client
+				.set_payload(self.collection_name.to_string(), None, &selector, payload, None)
+				.await
+				.map_err(|x| MemoryError::Storage(x.to_string()))?;
+		}
+		Ok(hits)
+	}
+
+	async fn list(&self, offset: usize, limit: usize) -> Result<Vec<MemoryItem>, MemoryError> {
+		// Qdrant pages by point identifier rather than by position, so the skipped points are retrieved as well
+		let scroll_result = self
+			.client
+			.scroll(&ScrollPoints {
+				collection_name: self.collection_name.to_string(),
+				limit: Some((offset + limit) as u32),
+				with_payload: Some(true.into()),
+				..Default::default()
+			})
+			.await
+			.map_err(|x| MemoryError::Storage(x.to_string()))?;
+
+		Ok(scroll_result
+			.result
+			.into_iter()
+			.skip(offset)
+			.map(|r| {
+				let text = payload_text(&r.payload);
+				MemoryItem {
+					id: item_id(&text),
+					text,
+					metadata: payload_metadata(&r.payload),
+				}
+			})
+			.collect())
+	}
+
+	async fn remove(&self, id: &str) -> Result<(), MemoryError> {
+		self.client
+			.delete_points(self.collection_name.to_string(), None, &Self::selector([id.to_string()]), None)
+			.await
+			.map_err(|x| MemoryError::Storage(x.to_string()))?;
+		Ok(())
 	}
 
 	async fn clear(&self) -> Result<(), MemoryError> {
@@ -71,4 +257,26 @@ impl Memory for QdrantMemory {
 			.map_err(|x| MemoryError::Storage(x.to_string()))?;
 		Ok(())
 	}
+
+	async fn flush(&self) -> Result<(), MemoryError> {
+		// The server persists points as they are stored
+		Ok(())
+	}
+
+	async fn stats(&self) -> Result<MemoryStats, MemoryError> {
+		let count = self
+			.client
+			.count(&CountPoints {
+				collection_name: self.collection_name.to_string(),
+				exact: Some(true),
+				..Default::default()
+			})
+			.await
+			.map_err(|x| MemoryError::Storage(x.to_string()))?;
+		Ok(MemoryStats {
+			items: count.result.map_or(0, |result| result.count as usize),
+			max_items: self.capacity.map(|capacity| capacity.max_items),
+			evictions: self.evictions.load(Ordering::Relaxed),
+		})
+	}
 }
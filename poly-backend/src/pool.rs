@@ -0,0 +1,179 @@
+//! A fixed number of threads on which inference (completions and embeddings) is performed. Each session already uses
+//! several threads itself (see [`crate::config::ModelConfig::threads_per_session`]), so running more sessions at the
+//! same time than there are cores for only makes all of them slower. Jobs submitted while all threads of the pool are
+//! busy wait in line until a thread becomes available.
+//!
+//! Jobs run within the context of the Tokio runtime the pool was started from (if any), so they can use
+//! [`tokio::runtime::Handle::current`] like jobs started with [`tokio::task::spawn_blocking`] could.
+
+use std::{
+	panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		mpsc, Arc, Mutex,
+	},
+	thread,
+};
+
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct InferencePool {
+	sender: mpsc::Sender<Job>,
+	size: usize,
+	load: Arc<PoolLoad>,
+}
+
+#[derive(Default)]
+struct PoolLoad {
+	/// Number of threads currently performing a job
+	busy: AtomicUsize,
+
+	/// Number of jobs waiting for a thread
+	queued: AtomicUsize,
+}
+
+impl InferencePool {
+	/// Start a pool with the specified number of threads (at least one)
+	pub fn new(size: usize) -> InferencePool {
+		let size = size.max(1);
+		let (sender, receiver) = mpsc::channel::<Job>();
+		let receiver = Arc::new(Mutex::new(receiver));
+		let load = Arc::new(PoolLoad::default());
+		let runtime = tokio::runtime::Handle::try_current().ok();
+
+		for index in 0..size {
+			let receiver = receiver.clone();
+			let load = load.clone();
+			let runtime = runtime.clone();
+			thread::Builder::new()
+				.name(format!("inference-{index}"))
+				.spawn(move || {
+					let _runtime = runtime.as_ref().map(|r| r.enter());
+					loop {
+						// The lock is released as soon as a job is received, so that other threads can receive the next one
+						let job = receiver.lock().unwrap().recv();
+						let Ok(job) = job else {
+							// The pool was dropped
+							break;
+						};
+						load.queued.fetch_sub(1, Ordering::SeqCst);
+						load.busy.fetch_add(1, Ordering::SeqCst);
+						job();
+						load.busy.fetch_sub(1, Ordering::SeqCst);
+					}
+				})
+				.expect("spawn inference thread");
+		}
+
+		tracing::info!(size, "started inference pool");
+		InferencePool { sender, size, load }
+	}
+
+	/// Number of threads in the pool
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	/// Number of threads currently performing a job
+	pub fn busy(&self) -> usize {
+		self.load.busy.load(Ordering::SeqCst)
+	}
+
+	/// Number of jobs waiting for a thread to become available
+	pub fn queued(&self) -> usize {
+		self.load.queued.load(Ordering::SeqCst)
+	}
+
+	/// Perform a job on one of the threads of the pool without waiting for it to finish. A panic in the job does not take
+	/// down the thread.
+	pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+		self.load.queued.fetch_add(1, Ordering::SeqCst);
+		let job = move || {
+			if catch_unwind(AssertUnwindSafe(job)).is_err() {
+				tracing::error!("inference job panicked");
+			}
+		};
+		self.sender.send(Box::new(job)).expect("inference threads are running");
+	}
+
+	/// Perform a job on one of the threads of the pool and return its result once it is done. When the job panics, so
+	/// does this function.
+	pub async fn run<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> T {
+		let result = self.submit(job);
+		match result.await.expect("inference job is performed") {
+			Ok(value) => value,
+			Err(panic) => resume_unwind(panic),
+		}
+	}
+
+	/// Like [`InferencePool::run`], but blocks the calling thread while waiting. Must not be called from an asynchronous
+	/// context or from a job running on the pool (which could wait forever for a thread to become available).
+	pub fn run_blocking<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> T {
+		let result = self.submit(job);
+		match result.blocking_recv().expect("inference job is performed") {
+			Ok(value) => value,
+			Err(panic) => resume_unwind(panic),
+		}
+	}
+
+	fn submit<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> oneshot::Receiver<thread::Result<T>> {
+		let (tx, rx) = oneshot::channel();
+		self.load.queued.fetch_add(1, Ordering::SeqCst);
+		let job = move || {
+			// The receiver is gone when the caller stopped waiting, in which case the result is not needed anymore
+			_ = tx.send(catch_unwind(AssertUnwindSafe(job)));
+		};
+		self.sender.send(Box::new(job)).expect("inference threads are running");
+		rx
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc,
+		},
+		thread::sleep,
+		time::Duration,
+	};
+
+	use super::InferencePool;
+
+	#[tokio::test]
+	async fn test_pool_limits_concurrency() {
+		let pool = Arc::new(InferencePool::new(2));
+		let running = Arc::new(AtomicUsize::new(0));
+		let max_running = Arc::new(AtomicUsize::new(0));
+
+		let jobs = (0..8).map(|i| {
+			let (running, max_running) = (running.clone(), max_running.clone());
+			pool.run(move || {
+				let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+				max_running.fetch_max(now, Ordering::SeqCst);
+				sleep(Duration::from_millis(20));
+				running.fetch_sub(1, Ordering::SeqCst);
+				i * 2
+			})
+		});
+		let results = futures_util::future::join_all(jobs).await;
+		assert_eq!(results, (0..8).map(|i| i * 2).collect::<Vec<_>>());
+		assert_eq!(max_running.load(Ordering::SeqCst), 2);
+		assert_eq!(pool.queued(), 0);
+	}
+
+	#[tokio::test]
+	async fn test_pool_survives_panics() {
+		let pool = Arc::new(InferencePool::new(1));
+		let panicking = pool.clone();
+		let result = tokio::spawn(async move { panicking.run(|| panic!("job failed")).await }).await;
+		assert!(result.is_err());
+
+		pool.spawn(|| panic!("job failed"));
+		assert_eq!(pool.run(|| 42).await, 42);
+		assert_eq!(tokio::task::spawn_blocking(move || pool.run_blocking(|| 43)).await.unwrap(), 43);
+	}
+}
@@ -0,0 +1,352 @@
+use std::{borrow::Cow, ops::Range, sync::Arc};
+
+use llm::{Prompt, TokenId, Tokenizer};
+use poly_bias::{
+	choices::ChoicesBiaser,
+	json::{JsonBiaser, JsonSchema},
+	vocabulary::VocabularyInfo,
+	Biaser, NullBiaser,
+};
+
+use crate::{
+	config::{BiaserConfig, TaskConfig},
+	datetime,
+	memory::now_millis,
+	tools,
+	types::{BackendError, PromptSegment, PromptViolation},
+};
+
+// The checks in this module are performed both before a completion and when validating a prompt without performing the
+// completion (see `Backend::validate`), so that the two cannot disagree on whether a prompt is accepted.
+
+/// Tokens of a prompt as they are fed to a session, and the private tokens found in its untrusted segments
+pub(crate) struct PromptTokens {
+	pub tokens: Vec<TokenId>,
+
+	/// Number of tokens of the segments of the prompt itself (i.e. excluding recalled memories, prefix and postfix)
+	pub prompt: usize,
+
+	/// For each private token found in an untrusted segment: the token, the index of the segment and the byte offset of
+	/// the token in the text of the segment
+	pub illegal: Vec<(String, usize, usize)>,
+
+	/// The tokens of the prompt itself that may be cut off to make it fit (i.e. excluding a beginning-of-sentence token)
+	truncatable: Range<usize>,
+}
+
+impl PromptTokens {
+	/// The tokens, or an error for the first private token found in an untrusted segment
+	pub fn checked(self) -> Result<Vec<TokenId>, BackendError> {
+		match self.illegal.into_iter().next() {
+			Some((_, segment, offset)) => Err(BackendError::IllegalToken { segment, offset }),
+			None => Ok(self.tokens),
+		}
+	}
+
+	/// Make sure the prompt takes at most `max_tokens` tokens (when set). When `truncate` is set, tokens are cut off the
+	/// start of the prompt itself to make it fit. Returns the number of tokens that were cut off.
+	pub fn limit(&mut self, max_tokens: Option<usize>, truncate: bool) -> Result<usize, BackendError> {
+		let Some(allowed) = max_tokens else {
+			return Ok(0);
+		};
+		let needed = self.tokens.len();
+		let excess = needed.saturating_sub(allowed);
+		if excess == 0 {
+			return Ok(0);
+		}
+		if !truncate || excess > self.truncatable.len() {
+			return Err(BackendError::PromptTooLong { needed, allowed });
+		}
+		self.tokens.drain(self.truncatable.start..self.truncatable.start + excess);
+		self.truncatable.end -= excess;
+		self.prompt -= excess;
+		Ok(excess)
+	}
+
+	pub fn violations(&self) -> impl Iterator<Item = PromptViolation> + '_ {
+		self.illegal.iter().map(|(token, segment, offset)| PromptViolation::IllegalToken {
+			token: token.clone(),
+			segment: *segment,
+			offset: *offset,
+		})
+	}
+}
+
+/// The private tokens of a task with the tokens they correspond to. Most private tokens are a single token; longer ones
+/// are found in prompts as a sequence of tokens, and in generated text by their text.
+pub(crate) struct PrivateTokens {
+	tokens: Vec<(String, Vec<TokenId>)>,
+}
+
+impl PrivateTokens {
+	/// Tokenize the private tokens of a task. Fails for a private token that does not correspond to any tokens.
+	pub fn new(tokenizer: &Tokenizer, task_config: &TaskConfig) -> Result<PrivateTokens, BackendError> {
+		let tokens = task_config
+			.private_tokens
+			.iter()
+			.flatten()
+			.map(|token| {
+				let ids: Vec<TokenId> = tokenizer.tokenize(token, false)?.into_iter().map(|(_, id)| id).collect();
+				if ids.is_empty() {
+					return Err(BackendError::InvalidPrivateToken(token.clone()));
+				}
+				Ok((token.clone(), ids))
+			})
+			.collect::<Result<_, BackendError>>()?;
+		Ok(PrivateTokens { tokens })
+	}
+
+	/// The private tokens that correspond to a single token, which are never generated
+	pub fn single_ids(&self) -> Vec<TokenId> {
+		self.tokens.iter().filter(|(_, ids)| ids.len() == 1).map(|(_, ids)| ids[0]).collect()
+	}
+
+	/// The private tokens that correspond to more than one token, which are removed from generated text
+	pub fn sequences(&self) -> impl Iterator<Item = &str> {
+		self.tokens.iter().filter(|(_, ids)| ids.len() > 1).map(|(token, _)| token.as_str())
+	}
+
+	/// Find the private tokens in a list of tokens. Returns each private token found with the index of its first token,
+	/// in order of position.
+	pub fn find(&self, tokens: &[TokenId]) -> Vec<(&str, usize)> {
+		let mut found = vec![];
+		let mut index = 0;
+		while index < tokens.len() {
+			match self.tokens.iter().find(|(_, ids)| tokens[index..].starts_with(ids)) {
+				Some((token, ids)) => {
+					found.push((token.as_str(), index));
+					index += ids.len();
+				}
+				None => index += 1,
+			}
+		}
+		found
+	}
+}
+
+/// The tokens that end generation for a task: the end-of-text tokens configured for the task, or else the end-of-text
+/// token of the model. The first token is the one the biaser allows once its output is complete.
+pub(crate) fn eot_token_ids(tokenizer: &Tokenizer, task_config: &TaskConfig, model_eot_token: TokenId) -> Result<Vec<TokenId>, BackendError> {
+	let Some(ref eot_token) = task_config.eot_token else {
+		return Ok(vec![model_eot_token]);
+	};
+	eot_token
+		.tokens()
+		.iter()
+		.map(|token| {
+			let tokens = tokenizer.tokenize(token, false)?;
+			if tokens.len() != 1 {
+				return Err(BackendError::InvalidEotToken(token.clone()));
+			}
+			Ok(tokens[0].1)
+		})
+		.collect()
+}
+
+/// Tokenize a prompt the way it is fed to the model: recalled memories, prefix, user prompt and postfix. The segments
+/// of the user prompt are tokenized separately; untrusted segments are checked for private tokens.
+pub(crate) fn prompt_tokens(
+	tokenizer: &Tokenizer,
+	task_config: &TaskConfig,
+	remember_prompt: Option<&str>,
+	segments: &[PromptSegment],
+	beginning_of_sentence: bool,
+) -> Result<PromptTokens, BackendError> {
+	let mut tokens = vec![];
+
+	// Append remember tokens
+	if let Some(remember_prompt) = remember_prompt {
+		tokens.append(&mut Prompt::Text(remember_prompt).to_tokens(tokenizer, beginning_of_sentence && tokens.is_empty())?)
+	}
+
+	// Append prefix tokens (with the current date and time filled in)
+	if let Some(ref prefix) = task_config.prefix {
+		let prefix = datetime::expand_variables(prefix, now_millis());
+		tokens.append(&mut Prompt::Text(&prefix).to_tokens(tokenizer, beginning_of_sentence && tokens.is_empty())?);
+	}
+
+	// Generate user prompt tokens. A beginning-of-sentence token added to the first segment is never cut off.
+	let private_tokens = PrivateTokens::new(tokenizer, task_config)?;
+	let prompt_start = tokens.len() + usize::from(beginning_of_sentence && tokens.is_empty() && !segments.is_empty());
+	let mut prompt = 0;
+	let mut illegal = vec![];
+	for (index, segment) in segments.iter().enumerate() {
+		let mut segment_tokens = Prompt::Text(&segment.text).to_tokens(tokenizer, beginning_of_sentence && tokens.is_empty())?;
+
+		// Check for private tokens (which may span several tokens) in untrusted segments
+		if !segment.trusted {
+			let offsets: Vec<usize> = segment_tokens
+				.iter()
+				.scan(0, |offset, token_id| {
+					let start = *offset;
+					*offset += tokenizer.token(*token_id as usize).len();
+					Some(start)
+				})
+				.collect();
+			for (token, start) in private_tokens.find(&segment_tokens) {
+				illegal.push((token.to_string(), index, offsets[start]));
+			}
+		}
+		prompt += segment_tokens.len();
+		tokens.append(&mut segment_tokens);
+	}
+
+	let truncatable = prompt_start..tokens.len().max(prompt_start);
+
+	// Append postfix tokens
+	if let Some(ref postfix) = task_config.postfix {
+		tokens.append(&mut Prompt::Text(postfix).to_tokens(tokenizer, beginning_of_sentence && tokens.is_empty())?);
+	}
+	Ok(PromptTokens {
+		tokens,
+		prompt,
+		illegal,
+		truncatable,
+	})
+}
+
+/// Check whether `needed` tokens fit in a context window that has room for `available` more tokens
+pub(crate) fn check_context(needed: usize, available: usize) -> Result<(), BackendError> {
+	if needed > available {
+		return Err(BackendError::ContextFull { needed, available });
+	}
+	Ok(())
+}
+
+/// Load the JSON schema of the biaser configured for the task (if any)
+pub(crate) fn biaser_schema(task_config: &TaskConfig) -> Result<Option<Cow<'_, JsonSchema>>, BackendError> {
+	// Tasks with tools cannot have a biaser of their own
+	if !task_config.tools.is_empty() {
+		return Ok(Some(Cow::Owned(tools::output_schema(&task_config.tools))));
+	}
+
+	match task_config.biaser {
+		None | Some(BiaserConfig::Regex(_) | BiaserConfig::Choices(_)) => Ok(None),
+		Some(BiaserConfig::JsonSchema(ref schema)) => Ok(Some(Cow::Borrowed(schema))),
+		Some(ref config @ BiaserConfig::JsonSchemaFile(_)) => Ok(config.schema().map_err(BackendError::InvalidBiaser)?.map(Cow::Owned)),
+	}
+}
+
+/// Create the biaser configured for the task when it constrains the text of the output directly instead of using a JSON
+/// schema (i.e. a regular expression or a set of choices)
+pub(crate) fn text_biaser(task_config: &TaskConfig) -> Result<Option<Box<dyn Biaser>>, BackendError> {
+	if !task_config.tools.is_empty() {
+		return Ok(None);
+	}
+	match task_config.biaser {
+		Some(ref config @ BiaserConfig::Regex(_)) => Ok(config
+			.regex()
+			.map_err(BackendError::InvalidBiaser)?
+			.map(|regex| Box::new(regex) as Box<dyn Biaser>)),
+		Some(BiaserConfig::Choices(ref choices)) => Ok(Some(Box::new(ChoicesBiaser::new(choices.clone())))),
+		_ => Ok(None),
+	}
+}
+
+/// Create the biaser for a schema (if any), or use the biaser that constrains the text of the output (if any). The
+/// analysis of the vocabulary of the model is only obtained when needed.
+pub(crate) fn biaser<'a>(
+	schema: Option<&'a JsonSchema>,
+	text_biaser: Option<Box<dyn Biaser>>,
+	vocabulary: impl FnOnce() -> Arc<VocabularyInfo>,
+) -> Box<dyn Biaser + 'a> {
+	match (schema, text_biaser) {
+		(Some(schema), _) => Box::new(JsonBiaser::with_vocabulary(schema, vocabulary())),
+		(None, Some(text_biaser)) => text_biaser,
+		(None, None) => Box::new(NullBiaser {}),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::path::{Path, PathBuf};
+
+	use llm::{Tokenizer, TokenizerSource};
+
+	use super::{eot_token_ids, prompt_tokens, PrivateTokens};
+	use crate::{
+		config::TaskConfig,
+		types::{BackendError, PromptRequest},
+	};
+
+	/// A tokenizer with a handful of tokens, including "<|im_end|>" (3) and "</s>" (4)
+	fn tokenizer() -> Tokenizer {
+		let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/../data/tiny-tokenizer.json"));
+		TokenizerSource::HuggingFaceTokenizerFile(path).retrieve(Path::new("")).unwrap()
+	}
+
+	fn task_config(config: &str) -> TaskConfig {
+		toml::from_str(&format!("model = \"tiny\"\n{config}")).unwrap()
+	}
+
+	#[test]
+	fn test_eot_token_ids() {
+		let tokenizer = tokenizer();
+
+		// Without configured tokens, the token of the model is used
+		assert_eq!(eot_token_ids(&tokenizer, &task_config(""), 0).unwrap(), vec![0]);
+
+		// Configured tokens replace the token of the model; the first is the one the biaser uses
+		assert_eq!(eot_token_ids(&tokenizer, &task_config("eot_token = \"</s>\""), 0).unwrap(), vec![4]);
+		assert_eq!(
+			eot_token_ids(&tokenizer, &task_config("eot_token = [\"<|im_end|>\", \"</s>\"]"), 0).unwrap(),
+			vec![3, 4]
+		);
+
+		// Each token must correspond to exactly one token
+		assert!(matches!(
+			eot_token_ids(&tokenizer, &task_config("eot_token = [\"</s>\", \"hello world\"]"), 0),
+			Err(BackendError::InvalidEotToken(token)) if token == "hello world"
+		));
+	}
+
+	#[test]
+	fn test_private_tokens() {
+		let tokenizer = tokenizer();
+		let config = task_config("private_tokens = [\"</s>\", \"hello world\"]");
+		let private = PrivateTokens::new(&tokenizer, &config).unwrap();
+		assert_eq!(private.single_ids(), vec![4]);
+		assert_eq!(private.sequences().collect::<Vec<_>>(), vec!["hello world"]);
+
+		// Private tokens spanning several tokens are found as a sequence of tokens, with the offset of their first token
+		let segments = PromptRequest::new("world hello world </s> hello").segments().into_owned();
+		let tokens = prompt_tokens(&tokenizer, &config, None, &segments, false).unwrap();
+		assert_eq!(tokens.illegal, vec![(String::from("hello world"), 0, 5), (String::from("</s>"), 0, 15)]);
+
+		// Private tokens that do not correspond to any tokens are rejected
+		assert!(matches!(
+			PrivateTokens::new(&tokenizer, &task_config("private_tokens = [\"</s>\", \"\"]")),
+			Err(BackendError::InvalidPrivateToken(token)) if token.is_empty()
+		));
+	}
+
+	#[test]
+	fn test_limit_prompt_tokens() {
+		let tokenizer = tokenizer();
+		let task_config = task_config("prefix = \"hello\"\npostfix = \"</s>\"");
+		let segments = PromptRequest::new("world hello world world").segments().into_owned();
+		let tokens = || prompt_tokens(&tokenizer, &task_config, None, &segments, false).unwrap();
+
+		// Prompts that fit are left alone
+		let mut fits = tokens();
+		assert_eq!(fits.limit(None, false).unwrap(), 0);
+		assert_eq!(fits.limit(Some(6), false).unwrap(), 0);
+		assert_eq!(fits.tokens, vec![1, 2, 1, 2, 2, 4]);
+
+		// Prompts that do not fit are rejected, unless they may be truncated
+		assert!(matches!(
+			tokens().limit(Some(4), false),
+			Err(BackendError::PromptTooLong { needed: 6, allowed: 4 })
+		));
+		let mut truncated = tokens();
+		assert_eq!(truncated.limit(Some(4), true).unwrap(), 2);
+		assert_eq!(truncated.tokens, vec![1, 2, 2, 4]);
+		assert_eq!(truncated.prompt, 2);
+
+		// The prefix and postfix are never cut off
+		assert!(matches!(
+			tokens().limit(Some(1), true),
+			Err(BackendError::PromptTooLong { needed: 6, allowed: 1 })
+		));
+	}
+}
@@ -0,0 +1,117 @@
+use std::borrow::Cow;
+
+/// Placeholder for text that is left out of logs
+pub const REDACTED: &str = "<redacted>";
+
+/// Replaces the private tokens of a task in text that is logged (such as transcripts, generated tokens and error
+/// messages), so that enabling debug logging does not reveal them
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+	/// The text of the private tokens, longest first (so that a token containing another is replaced as a whole)
+	private_tokens: Vec<String>,
+}
+
+impl Redactor {
+	pub fn new<S: AsRef<str>>(private_tokens: impl IntoIterator<Item = S>) -> Redactor {
+		let mut private_tokens: Vec<String> = private_tokens
+			.into_iter()
+			.map(|t| t.as_ref().to_string())
+			.filter(|t| !t.is_empty())
+			.collect();
+		private_tokens.sort_by_key(|t| std::cmp::Reverse(t.len()));
+		Redactor { private_tokens }
+	}
+
+	/// Returns the text with each occurrence of a private token replaced by [`REDACTED`]
+	pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+		if !self.private_tokens.iter().any(|t| text.contains(t.as_str())) {
+			return Cow::Borrowed(text);
+		}
+
+		let mut redacted = String::with_capacity(text.len());
+		let mut rest = text;
+		while let Some(c) = rest.chars().next() {
+			match self.private_tokens.iter().find(|t| rest.starts_with(t.as_str())) {
+				Some(token) => {
+					redacted.push_str(REDACTED);
+					rest = &rest[token.len()..];
+				}
+				None => {
+					redacted.push(c);
+					rest = &rest[c.len_utf8()..];
+				}
+			}
+		}
+		Cow::Owned(redacted)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::Arc;
+
+	use tracing_test::traced_test;
+
+	use crate::{
+		backend::Backend,
+		config::from_toml_str,
+		session::InferenceFeedback,
+		types::{BackendError, PromptRequest, SessionRequest},
+	};
+
+	use super::Redactor;
+
+	#[test]
+	fn test_redact() {
+		let redactor = Redactor::new(["<|im_end|>", "<|im_start|>", "<|im", ""]);
+		assert_eq!(redactor.redact("Hello"), "Hello");
+		assert_eq!(
+			redactor.redact("<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"),
+			"<redacted>user\nHi<redacted>\n<redacted>assistant\n"
+		);
+		assert_eq!(redactor.redact("<|im|>"), "<redacted>|>");
+		assert_eq!(redactor.redact("é<|im_end|>ë"), "é<redacted>ë");
+
+		// The placeholder itself is never redacted again
+		let redactor = Redactor::new(["red"]);
+		assert_eq!(redactor.redact("redacted"), "<redacted>acted");
+		assert_eq!(Redactor::default().redact("<|im_end|>"), "<|im_end|>");
+	}
+
+	#[tokio::test(flavor = "multi_thread")]
+	#[traced_test]
+	async fn test_redacted_logs() {
+		// The private token is in the prefix, and the biaser forces the model to generate it
+		let config = from_toml_str(
+			r#"
+			[models.gpt2]
+			architecture = "gpt2"
+			model_path = "../data/gpt2.bin"
+
+			[tasks.secret]
+			model = "gpt2"
+			prefix = "The password is Sesame. "
+			private_tokens = ["Sesame"]
+			biaser = { json_schema = { type = "const", const = "Sesame" } }
+			"#,
+		)
+		.unwrap();
+		let backend = Arc::new(Backend::from(config, None).await);
+		let mut session = backend.start("secret", &SessionRequest::default(), backend.clone()).unwrap();
+		session
+			.complete(&PromptRequest::new("What is the password?"), |_| Ok(InferenceFeedback::Continue))
+			.unwrap();
+		assert!(session.transcript().contains("Sesame"));
+
+		// Untrusted prompts may not contain the private token, and the error does not reveal it
+		let error = session
+			.complete(&PromptRequest::new("Sesame"), |_| Ok(InferenceFeedback::Continue))
+			.unwrap_err();
+		assert!(matches!(error, BackendError::IllegalToken { segment: 0, offset: 0 }), "{error:?}");
+		tracing::debug!("completion failed: {error}");
+
+		assert!(logs_contain("full transcript"));
+		assert!(logs_contain("<redacted>"));
+		assert!(!logs_contain("Sesame"));
+	}
+}
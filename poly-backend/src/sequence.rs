@@ -1,49 +1,141 @@
+use std::collections::VecDeque;
+
+/// How text is compared to a sequence
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchOptions {
+	/// Compare characters regardless of their case
+	pub case_insensitive: bool,
+
+	/// Ignore whitespace when comparing, so that any run of whitespace (or none at all) is equivalent to any other. Not
+	/// applied to sequences that consist of whitespace only.
+	pub normalize_whitespace: bool,
+}
+
+/// Result of matching a sequence against text from a specific position
+enum Outcome {
+	/// The sequence was matched; the match ends at the byte offset
+	Complete(usize),
+
+	/// The text matches the start of the sequence, but ends before the sequence does
+	Partial,
+	NoMatch,
+}
+
 #[derive(Debug)]
 pub struct Sequence {
-	tokens: String,
-	state: usize,
+	/// Characters of the sequence (without whitespace when normalizing whitespace)
+	pattern: Vec<char>,
+	options: MatchOptions,
+
+	/// Text seen since the earliest position at which the sequence may start
+	buffer: String,
+
+	/// After a match: number of bytes at the end of the text seen that are part of the match or follow it
+	tail: Option<usize>,
+
+	/// After a match: number of bytes of the match
+	matched: usize,
 }
 
 impl Sequence {
 	pub fn new(tokens: String) -> Sequence {
-		Sequence { tokens, state: 0 }
+		Self::with_options(&tokens, MatchOptions::default())
+	}
+
+	pub fn with_options(text: &str, mut options: MatchOptions) -> Sequence {
+		if text.chars().all(char::is_whitespace) {
+			options.normalize_whitespace = false;
+		}
+		Sequence {
+			pattern: text.chars().filter(|c| !(options.normalize_whitespace && c.is_whitespace())).collect(),
+			options,
+			buffer: String::new(),
+			tail: None,
+			matched: 0,
+		}
 	}
 
-	fn is_complete(&self) -> bool {
-		self.state == self.tokens.len()
+	fn is_skipped(&self, c: char) -> bool {
+		self.options.normalize_whitespace && c.is_whitespace()
 	}
 
+	fn is_equal(&self, a: char, b: char) -> bool {
+		a == b || (self.options.case_insensitive && a.to_lowercase().eq(b.to_lowercase()))
+	}
+
+	/// Match the sequence against text starting at a byte offset. Matches start with a character that is compared (i.e.
+	/// not with ignored whitespace).
+	fn match_in(&self, text: &str, start: usize) -> Outcome {
+		let mut matched = 0;
+		for (offset, c) in text[start..].char_indices() {
+			if self.is_skipped(c) {
+				if matched == 0 {
+					return Outcome::NoMatch;
+				}
+				continue;
+			}
+			if !self.is_equal(c, self.pattern[matched]) {
+				return Outcome::NoMatch;
+			}
+			matched += 1;
+			if matched == self.pattern.len() {
+				return Outcome::Complete(start + offset + c.len_utf8());
+			}
+		}
+		Outcome::Partial
+	}
+
+	/// Advance the sequence with the next part of the text. Returns true when the sequence was completed in this part.
+	/// Text following a match is used to match the sequence again.
 	pub fn advance(&mut self, token: &str) -> bool {
-		if self.state >= self.tokens.len() {
-			true // Already complete
-		} else {
-			let remainder = &self.tokens.as_bytes()[self.state..];
-			let overlap_length = remainder.len().min(token.len());
-			if (remainder.len() == token.len() && remainder == token.as_bytes()) || remainder.starts_with(&token.as_bytes()[0..overlap_length]) {
-				self.state += overlap_length;
-				// The unused part of the token (if it was longer than our remainder) can be used to advance once more
-				if token.len() > remainder.len() && self.is_complete() {
-					self.state = 0;
-					self.advance(&token[remainder.len()..]);
-					return true;
+		self.tail = None;
+		if self.pattern.is_empty() {
+			return false;
+		}
+		self.buffer.push_str(token);
+
+		loop {
+			let mut partial = None;
+			let mut complete = None;
+			for (start, _) in self.buffer.char_indices() {
+				match self.match_in(&self.buffer, start) {
+					Outcome::Complete(end) => {
+						complete = Some((start, end));
+						break;
+					}
+					Outcome::Partial => {
+						partial = partial.or(Some(start));
+					}
+					Outcome::NoMatch => {}
 				}
-			} else {
-				// Reset back to zero
-				if self.state != 0 {
-					// Try again from the beginning if we weren't at zero already
-					self.state = 0;
-					return self.advance(token);
-				} else {
-					// Just reset back to zero
-					self.state = 0;
+			}
+
+			match complete {
+				Some((start, end)) => {
+					// Only the first match in this part is reported
+					if self.tail.is_none() {
+						self.tail = Some(self.buffer.len() - start);
+						self.matched = end - start;
+					}
+					self.buffer.drain(..end);
+				}
+				None => {
+					self.buffer.drain(..partial.unwrap_or(self.buffer.len()));
+					break;
 				}
 			}
-			self.is_complete()
 		}
+		self.tail.is_some()
+	}
+
+	/// Number of bytes at the end of the text seen so far that may be the start of the sequence
+	pub fn pending(&self) -> usize {
+		self.buffer.len()
 	}
 
 	pub fn reset(&mut self) {
-		self.state = 0;
+		self.buffer.clear();
+		self.tail = None;
 	}
 }
 
@@ -73,12 +165,292 @@ impl SequenceSet {
 		});
 		any_complete
 	}
+
+	/// After `advance` returned true: the number of bytes at the end of the text advanced so far that are part of the
+	/// earliest completed sequence or follow it. As sequences may be matched regardless of case or whitespace, this can
+	/// differ from the length of the sequence.
+	pub fn matched_tail(&self) -> Option<usize> {
+		self.sequences.iter().filter_map(|s| s.tail).max()
+	}
+
+	/// Like [`SequenceSet::matched_tail`], but also returns the number of bytes of the earliest completed sequence
+	pub fn matched(&self) -> Option<(usize, usize)> {
+		self.sequences
+			.iter()
+			.filter_map(|s| Some((s.tail?, s.matched)))
+			.max_by_key(|(tail, _)| *tail)
+	}
+
+	/// Number of bytes at the end of the text advanced so far that may be the start of one of the sequences, and should
+	/// therefore be withheld until it is known whether they are
+	pub fn pending(&self) -> usize {
+		self.sequences.iter().map(Sequence::pending).max().unwrap_or(0)
+	}
+}
+
+/// A banned phrase found by a [`PhraseFilter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhraseMatch {
+	/// Index of the phrase that was found
+	pub phrase: usize,
+
+	/// Tag of the chunk in which the match starts
+	pub tag: usize,
+
+	/// Text of the chunk in which the match starts that precedes the match
+	pub before: String,
+
+	/// Text following the match, with the tag of the chunk it was taken from. It has not been filtered yet.
+	pub after: (String, usize),
+}
+
+/// Finds banned phrases in generated text. Text is pushed in tagged chunks (e.g. the text of one or more tokens, tagged
+/// with the index of the first token) and released once it cannot be part of a phrase. Chunks are never split when they
+/// are released, so that a match always starts in the first chunk that is withheld.
+#[derive(Debug)]
+pub struct PhraseFilter {
+	/// Only the patterns and options of the sequences are used
+	phrases: Vec<Sequence>,
+	chunks: VecDeque<(String, usize)>,
+}
+
+impl PhraseFilter {
+	pub fn new(phrases: Vec<Sequence>) -> PhraseFilter {
+		PhraseFilter {
+			phrases: phrases.into_iter().filter(|p| !p.pattern.is_empty()).collect(),
+			chunks: VecDeque::new(),
+		}
+	}
+
+	/// Tag of the first chunk that is withheld
+	pub fn first_tag(&self) -> Option<usize> {
+		self.chunks.front().map(|(_, tag)| *tag)
+	}
+
+	/// Add a chunk of text. Returns the chunks that cannot be part of a phrase, in order, followed by the earliest phrase
+	/// found (if any). When a phrase is found, all text is removed from the filter.
+	pub fn push(&mut self, text: String, tag: usize) -> (Vec<(String, usize)>, Option<PhraseMatch>) {
+		if !text.is_empty() {
+			self.chunks.push_back((text, tag));
+		}
+		let text: String = self.chunks.iter().map(|(chunk, _)| chunk.as_str()).collect();
+
+		// Find the earliest position at which a phrase starts (or may start)
+		let mut found = None;
+		for (start, _) in text.char_indices() {
+			let mut partial = false;
+			for (index, phrase) in self.phrases.iter().enumerate() {
+				match phrase.match_in(&text, start) {
+					Outcome::Complete(end) => {
+						found = Some((start, Some((index, end))));
+						break;
+					}
+					Outcome::Partial => partial = true,
+					Outcome::NoMatch => {}
+				}
+			}
+			if found.is_none() && partial {
+				found = Some((start, None));
+			}
+			if found.is_some() {
+				break;
+			}
+		}
+
+		// Release the chunks that end before that position
+		let start = found.map(|(start, _)| start).unwrap_or(text.len());
+		let mut released = vec![];
+		let mut offset = 0;
+		while let Some((chunk, _)) = self.chunks.front() {
+			if offset + chunk.len() > start {
+				break;
+			}
+			offset += chunk.len();
+			released.extend(self.chunks.pop_front());
+		}
+
+		let Some((start, Some((phrase, end)))) = found else {
+			return (released, None);
+		};
+		let tag = self.chunks.front().map(|(_, tag)| *tag).unwrap_or(tag);
+		let last_tag = self.chunks.back().map(|(_, tag)| *tag).unwrap_or(tag);
+		self.chunks.clear();
+		let found = PhraseMatch {
+			phrase,
+			tag,
+			before: text[offset..start].to_string(),
+			after: (text[end..].to_string(), last_tag),
+		};
+		(released, Some(found))
+	}
+
+	/// Release all text that is withheld, for when no more text will follow
+	pub fn flush(&mut self) -> Vec<(String, usize)> {
+		self.chunks.drain(..).collect()
+	}
+}
+
+/// Generated text that is withheld from the output while it may be part of a stop sequence. Private text (e.g. private
+/// tokens) counts towards the text seen by the stop sequences, but is never returned.
+#[derive(Debug, Default)]
+pub struct OutputBuffer {
+	chunks: VecDeque<(String, bool)>,
+	len: usize,
+}
+
+impl OutputBuffer {
+	pub fn push(&mut self, text: String, private: bool) {
+		self.len += text.len();
+		self.chunks.push_back((text, private));
+	}
+
+	/// Make `len` bytes private, starting `tail` bytes before the end of the text pushed so far. Used for private text
+	/// that is spread over several chunks (e.g. a private token the model generated as several tokens).
+	pub fn hide(&mut self, tail: usize, len: usize) {
+		let start = self.len.saturating_sub(tail);
+		let end = start + len;
+		let mut offset = 0;
+		let mut chunks = VecDeque::with_capacity(self.chunks.len() + 2);
+		for (text, private) in self.chunks.drain(..) {
+			let (chunk_start, chunk_end) = (offset, offset + text.len());
+			offset = chunk_end;
+			if private || chunk_end <= start || chunk_start >= end {
+				chunks.push_back((text, private));
+				continue;
+			}
+
+			// Split the chunk at the boundaries of the private text
+			let from = start.saturating_sub(chunk_start);
+			let to = (end - chunk_start).min(text.len());
+			for (part, private) in [(&text[..from], false), (&text[from..to], true), (&text[to..], false)] {
+				if !part.is_empty() {
+					chunks.push_back((part.to_string(), private));
+				}
+			}
+		}
+		self.chunks = chunks;
+	}
+
+	/// Remove all text except for the last `keep` bytes and return the part of it that is not private
+	pub fn take(&mut self, keep: usize) -> String {
+		let mut remaining = self.len.saturating_sub(keep);
+		let mut output = String::new();
+		while remaining > 0 {
+			let Some((text, private)) = self.chunks.front_mut() else {
+				break;
+			};
+			if text.len() <= remaining {
+				remaining -= text.len();
+				self.len -= text.len();
+				if !*private {
+					output.push_str(text);
+				}
+				self.chunks.pop_front();
+			} else {
+				// Private text is never split; it is withheld as a whole. Sequences match on character boundaries, so
+				// other text can be split at the boundary.
+				if !*private {
+					output.push_str(&text[..remaining]);
+					text.drain(..remaining);
+					self.len -= remaining;
+				}
+				break;
+			}
+		}
+		output
+	}
+}
+
+/// Turns the bytes of generated tokens into text. A character may be spread over several tokens; its bytes are held
+/// until the token completing it arrives. Bytes that cannot be part of a valid character are replaced by U+FFFD.
+#[derive(Debug, Default)]
+pub struct Utf8Buffer {
+	pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+	/// Add the bytes of a token, returning the text of all characters that are complete
+	pub fn push(&mut self, bytes: &[u8]) -> Option<String> {
+		self.pending.extend_from_slice(bytes);
+		let mut text = String::new();
+		loop {
+			match std::str::from_utf8(&self.pending) {
+				Ok(valid) => {
+					text.push_str(valid);
+					self.pending.clear();
+					break;
+				}
+				Err(e) => {
+					let valid_up_to = e.valid_up_to();
+					text.push_str(std::str::from_utf8(&self.pending[..valid_up_to]).unwrap());
+					match e.error_len() {
+						// The bytes at the end may be completed by the next token
+						None => {
+							self.pending.drain(..valid_up_to);
+							break;
+						}
+						Some(invalid) => {
+							text.push(char::REPLACEMENT_CHARACTER);
+							self.pending.drain(..(valid_up_to + invalid));
+						}
+					}
+				}
+			}
+		}
+		(!text.is_empty()).then_some(text)
+	}
+
+	/// Return the bytes of a character that was not completed (as U+FFFD), for when no more tokens will follow
+	pub fn flush(&mut self) -> Option<String> {
+		if self.pending.is_empty() {
+			return None;
+		}
+		let text = String::from_utf8_lossy(&self.pending).into_owned();
+		self.pending.clear();
+		Some(text)
+	}
+}
+
+/// Limits on the length of generated text in characters and lines
+#[derive(Debug, Default)]
+pub struct OutputLimit {
+	max_chars: Option<usize>,
+	max_lines: Option<usize>,
+	chars: usize,
+	line_breaks: usize,
+}
+
+impl OutputLimit {
+	pub fn new(max_chars: Option<usize>, max_lines: Option<usize>) -> OutputLimit {
+		OutputLimit {
+			max_chars,
+			max_lines,
+			..Default::default()
+		}
+	}
+
+	/// Count the next part of the output. Returns the number of bytes of it that fit within the limits (never splitting
+	/// a character) and whether a limit has been reached, after which nothing more should be output. A line break that
+	/// would start a line beyond the limit does not fit.
+	pub fn fit(&mut self, text: &str) -> (usize, bool) {
+		for (offset, c) in text.char_indices() {
+			let chars_full = self.max_chars.is_some_and(|max| self.chars >= max);
+			let lines_full = c == '\n' && self.max_lines.is_some_and(|max| self.line_breaks + 1 >= max);
+			if chars_full || lines_full {
+				return (offset, true);
+			}
+			self.chars += 1;
+			if c == '\n' {
+				self.line_breaks += 1;
+			}
+		}
+		(text.len(), self.max_chars.is_some_and(|max| self.chars >= max))
+	}
 }
 
 #[cfg(test)]
 mod test {
-	use super::Sequence;
-	use super::SequenceSet;
+	use super::{MatchOptions, OutputBuffer, OutputLimit, PhraseFilter, PhraseMatch, Sequence, SequenceSet, Utf8Buffer};
 
 	#[test]
 	fn test_sequences() {
@@ -107,4 +479,270 @@ mod test {
 		println!("{s:?}");
 		assert!(!s.advance("ef"));
 	}
+
+	/// Pass tokens through stop sequences and an output buffer the way a completion does. Returns the text output after
+	/// each token (text that may be part of a stop sequence is withheld), the text output at the end and whether a stop
+	/// sequence was matched.
+	fn stop(sequences: &[&str], tokens: &[&str]) -> (Vec<String>, String, bool) {
+		let mut s = SequenceSet::new(sequences.iter().map(|s| Sequence::new(s.to_string())).collect());
+		let mut buffer = OutputBuffer::default();
+		let mut outputs = vec![];
+		for token in tokens {
+			let stopped = s.advance(token);
+			buffer.push(token.to_string(), false);
+			if stopped {
+				outputs.push(buffer.take(s.matched_tail().unwrap_or(0)));
+				return (outputs, String::new(), true);
+			}
+			outputs.push(buffer.take(s.pending()));
+		}
+		(outputs, buffer.take(0), false)
+	}
+
+	#[test]
+	fn test_multi_token_stop_sequence() {
+		// A stop sequence split over tokens is matched, and none of it is output
+		let (outputs, rest, stopped) = stop(&["\nUser:"], &["Fine", ".", "\nUs", "er:", " hi"]);
+		assert!(stopped);
+		assert_eq!(outputs, vec!["Fine", ".", "", ""]);
+		assert_eq!(rest, "");
+
+		// Text before the match in the same token is output
+		let (outputs, _, stopped) = stop(&["\nUser:"], &["Fine.\nU", "se", "r: hi"]);
+		assert!(stopped);
+		assert_eq!(outputs, vec!["Fine.", "", ""]);
+
+		// Withheld text that turns out not to be a stop sequence is output as soon as that is known
+		let (outputs, rest, stopped) = stop(&["\nUser:"], &["Fine.\nUs", "age", " is high", "\n"]);
+		assert!(!stopped);
+		assert_eq!(outputs, vec!["Fine.", "\nUsage", " is high", ""]);
+		assert_eq!(rest, "\n");
+	}
+
+	#[test]
+	fn test_overlapping_stop_sequences() {
+		// The shortest of overlapping sequences ends generation as soon as it is complete
+		let (outputs, _, stopped) = stop(&["abc", "ab"], &["xa", "b", "c"]);
+		assert!(stopped);
+		assert_eq!(outputs, vec!["x", ""]);
+
+		// A sequence starting within a partial match of another is found
+		let (outputs, _, stopped) = stop(&["abc", "bd"], &["a", "b", "d"]);
+		assert!(stopped);
+		assert_eq!(outputs, vec!["", "", "a"]);
+
+		// When only the longer sequence matches, the text is withheld until it is complete
+		let (outputs, _, stopped) = stop(&["abc", "abd"], &["ab", "c"]);
+		assert!(stopped);
+		assert_eq!(outputs, vec!["", ""]);
+		let (outputs, rest, stopped) = stop(&["abc", "abd"], &["ab", "e"]);
+		assert!(!stopped);
+		assert_eq!(outputs, vec!["", "abe"]);
+		assert_eq!(rest, "");
+	}
+
+	#[test]
+	fn test_single_character_stop_sequence() {
+		// A single character is never withheld unless it matches
+		let (outputs, _, stopped) = stop(&["."], &["Hello", " world", ". And", " more"]);
+		assert!(stopped);
+		assert_eq!(outputs, vec!["Hello", " world", ""]);
+
+		let (outputs, rest, stopped) = stop(&["\n"], &["one", " two"]);
+		assert!(!stopped);
+		assert_eq!(outputs, vec!["one", " two"]);
+		assert_eq!(rest, "");
+	}
+
+	#[test]
+	fn test_case_insensitive() {
+		let options = MatchOptions {
+			case_insensitive: true,
+			normalize_whitespace: false,
+		};
+		let mut s = SequenceSet::new(vec![Sequence::with_options("User:", options), Sequence::new("Bot:".to_string())]);
+
+		assert!(!s.advance("Hello\nUS"));
+		assert_eq!(s.pending(), 2);
+		assert!(s.advance("ER: hi"));
+		assert_eq!(s.matched_tail(), Some(8));
+
+		s.reset();
+		assert!(s.advance("user:"));
+		s.reset();
+		assert!(!s.advance("BOT:"));
+		assert_eq!(s.pending(), 0);
+
+		// Case changes may change the length of characters
+		let mut s = SequenceSet::new(vec![Sequence::with_options("straße", options)]);
+		assert!(s.advance("STRAẞE"));
+		assert_eq!(s.matched_tail(), Some("STRAẞE".len()));
+	}
+
+	#[test]
+	fn test_normalize_whitespace() {
+		let options = MatchOptions {
+			case_insensitive: true,
+			normalize_whitespace: true,
+		};
+		let mut s = SequenceSet::new(vec![Sequence::with_options("User:", options)]);
+		assert!(!s.advance("Fine.\nUser"));
+		assert!(!s.advance(" \t"));
+		assert_eq!(s.pending(), 6);
+		assert!(s.advance(":"));
+		assert_eq!(s.matched_tail(), Some(7));
+
+		s.reset();
+		assert!(s.advance("\nuSeR  : "));
+		assert_eq!(s.matched_tail(), Some(8));
+
+		// Sequences of only whitespace are matched as they are
+		let mut s = SequenceSet::new(vec![Sequence::with_options("\n\n", options)]);
+		assert!(!s.advance("a \n b"));
+		assert!(s.advance("\n\n"));
+	}
+
+	#[test]
+	fn test_phrase_filter() {
+		let options = MatchOptions {
+			case_insensitive: true,
+			normalize_whitespace: false,
+		};
+		let mut f = PhraseFilter::new(vec![
+			Sequence::with_options("Project Falcon", options),
+			Sequence::with_options("ACME", options),
+		]);
+		let chunk = |text: &str, tag: usize| (text.to_string(), tag);
+
+		assert_eq!(f.push(String::from("Hello"), 0), (vec![chunk("Hello", 0)], None));
+
+		// A phrase spanning chunks is found regardless of case; the chunk in which it starts is withheld as a whole
+		assert_eq!(f.push(String::from(". The pro"), 1), (vec![], None));
+		assert_eq!(f.first_tag(), Some(1));
+		assert_eq!(f.push(String::from("JECT fal"), 2), (vec![], None));
+		assert_eq!(
+			f.push(String::from("con is acme"), 4),
+			(
+				vec![],
+				Some(PhraseMatch {
+					phrase: 0,
+					tag: 1,
+					before: String::from(". The "),
+					after: (String::from(" is acme"), 4),
+				})
+			)
+		);
+		assert_eq!(f.first_tag(), None);
+
+		// Text after a match is filtered again
+		let (released, found) = f.push(String::from(" is acme"), 4);
+		assert_eq!(released, vec![]);
+		assert_eq!(
+			found.map(|m| (m.phrase, m.before, m.after)),
+			Some((1, String::from(" is "), (String::new(), 4)))
+		);
+
+		// Text that turns out not to be a phrase is released in the chunks it was pushed in
+		assert_eq!(f.push(String::from("Proj"), 5), (vec![], None));
+		assert_eq!(f.push(String::from("ection"), 6), (vec![chunk("Proj", 5), chunk("ection", 6)], None));
+		assert_eq!(f.push(String::from("Ac"), 7), (vec![], None));
+		assert_eq!(f.flush(), vec![chunk("Ac", 7)]);
+
+		// Without phrases, everything is released
+		let mut f = PhraseFilter::new(vec![]);
+		assert_eq!(f.push(String::from("Project"), 0), (vec![chunk("Project", 0)], None));
+	}
+
+	#[test]
+	fn test_output_buffer() {
+		let mut b = OutputBuffer::default();
+		b.push(String::from("Hello "), false);
+		b.push(String::from("<|im_end|>"), true);
+		b.push(String::from("Us"), false);
+		assert_eq!(b.take(2), "Hello ");
+		assert_eq!(b.take(0), "Us");
+
+		// Private text is not split
+		b.push(String::from("<|im_end|>"), true);
+		assert_eq!(b.take(3), "");
+		assert_eq!(b.take(0), "");
+	}
+
+	#[test]
+	fn test_private_sequences() {
+		// Pass tokens through private sequences the way a completion does, returning the text output after each token
+		let hide = |sequences: &[&str], tokens: &[&str]| -> (Vec<String>, String) {
+			let mut s = SequenceSet::new(sequences.iter().map(|s| Sequence::new(s.to_string())).collect());
+			let mut buffer = OutputBuffer::default();
+			let mut outputs = vec![];
+			for token in tokens {
+				let matched = s.advance(token);
+				buffer.push(token.to_string(), false);
+				if let Some((tail, len)) = s.matched().filter(|_| matched) {
+					buffer.hide(tail, len);
+				}
+				outputs.push(buffer.take(s.pending()));
+			}
+			(outputs, buffer.take(0))
+		};
+
+		// Private text spread over several tokens is removed, including from the tokens it starts and ends in
+		let (outputs, rest) = hide(&["<|im_start|>"], &["Hi", " <|im", "_st", "art|>user", " there"]);
+		assert_eq!(outputs, vec!["Hi", " ", "", "user", " there"]);
+		assert_eq!(rest, "");
+
+		// Text that only starts like private text is output once that is known
+		let (outputs, rest) = hide(&["<|im_start|>"], &["<|im", "age|>", "<|im"]);
+		assert_eq!(outputs, vec!["", "<|image|>", ""]);
+		assert_eq!(rest, "<|im");
+
+		// Private text that is a single chunk is removed too
+		let (outputs, _) = hide(&["<|im_start|>"], &["a", "<|im_start|>", "b"]);
+		assert_eq!(outputs, vec!["a", "", "b"]);
+	}
+
+	#[test]
+	fn test_utf8_buffer() {
+		let emoji = "🦀".as_bytes();
+		let mut b = Utf8Buffer::default();
+		assert_eq!(b.push(b"ab"), Some(String::from("ab")));
+
+		// Complete characters before an incomplete one are not held back
+		assert_eq!(b.push(&[b'c', emoji[0]]), Some(String::from("c")));
+		assert_eq!(b.push(&emoji[1..3]), None);
+		assert_eq!(b.push(&emoji[3..]), Some(String::from("🦀")));
+		assert_eq!(b.flush(), None);
+
+		// A character that is interrupted is replaced
+		assert_eq!(b.push(&emoji[..2]), None);
+		assert_eq!(b.push(b"d"), Some(String::from("\u{FFFD}d")));
+
+		// An incomplete character at the end is flushed as replacement character
+		assert_eq!(b.push(&[b'e', emoji[0]]), Some(String::from("e")));
+		assert_eq!(b.flush(), Some(String::from("\u{FFFD}")));
+		assert_eq!(b.flush(), None);
+	}
+
+	#[test]
+	fn test_output_limit() {
+		let mut l = OutputLimit::new(Some(5), None);
+		assert_eq!(l.fit("abc"), (3, false));
+		assert_eq!(l.fit("dé"), (3, true));
+		assert_eq!(l.fit("f"), (0, true));
+
+		// Characters are never split
+		let mut l = OutputLimit::new(Some(2), None);
+		assert_eq!(l.fit("aéb"), (3, true));
+
+		let mut l = OutputLimit::new(None, Some(2));
+		assert_eq!(l.fit("one\ntwo"), (7, false));
+		assert_eq!(l.fit(" and\nthree"), (4, true));
+
+		// Whichever limit is reached first applies
+		let mut l = OutputLimit::new(Some(10), Some(3));
+		assert_eq!(l.fit("a\nb\nc\nd"), (5, true));
+		let mut l = OutputLimit::new(Some(3), Some(3));
+		assert_eq!(l.fit("a\nb\nc"), (3, true));
+		assert_eq!(OutputLimit::default().fit("a\nb"), (3, false));
+	}
 }
@@ -1,32 +1,190 @@
 use std::{
 	borrow::Cow,
+	collections::{HashMap, VecDeque},
 	fmt::Debug,
-	fs::File,
-	io::BufReader,
-	sync::{Arc, Mutex},
+	io::{Read, Write},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
 	time::{Duration, Instant},
 };
 
 use llm::{
-	samplers::llm_samplers::types::SamplerChain, InferenceError, InferenceParameters, InferenceRequest, InferenceStats, OutputRequest, Prompt,
-	TokenId, TokenUtf8Buffer,
-};
-use poly_bias::{
-	json::{JsonBiaser, JsonSchema},
-	Biaser, NullBiaser,
+	samplers::llm_samplers::types::SamplerChain, InferenceError, InferenceParameters, InferenceRequest, InferenceSession, InferenceSnapshot,
+	OutputRequest, Prompt, TokenId,
 };
 
-pub use llm::{InferenceFeedback, InferenceResponse};
+pub use llm::{InferenceFeedback, InferenceResponse, InferenceStats};
+use poly_bias::vocabulary::VocabularyInfo;
+use rand::{rngs::StdRng, SeedableRng};
+use tracing::Instrument;
 
 use crate::{
-	backend::{Backend, BackendStats},
-	config::{BiaserConfig, TaskConfig},
-	memory::Memory,
-	sequence::{Sequence, SequenceSet},
-	stats::InferenceStatsAdd,
-	types::{BackendError, PromptRequest},
+	backend::{Backend, BackendStats, SharedVocabulary},
+	config::{BannedPhraseAction, TaskConfig},
+	datetime::{self, humanize_age},
+	memory::{now_millis, ItemMetadata, Memory},
+	preflight::{self, PrivateTokens},
+	redact::{Redactor, REDACTED},
+	sequence::{OutputBuffer, OutputLimit, PhraseFilter, Sequence, SequenceSet, Utf8Buffer},
+	stats::{GaugeGuard, GenerationTimings, InferenceStatsAdd, TokenUsage},
+	tools::{self, ToolOutput},
+	trace::{TokenChoice, TraceRecorder},
+	types::{BackendError, FinishReason, MemoryStage, OutputValidation, PromptRequest, PromptSegment, ToolCall},
+	validation::{self, OutputValidator},
 };
 
+/// Maximum number of tokens the biaser may add to close the output when generation in biased mode has to end (see
+/// [`BackendError::TokenBudgetExceeded`])
+const MAX_CLOSING_TOKENS: usize = 32;
+
+/// A position in the conversation held by a session, which the session can be rewound to using
+/// [`BackendSession::rewind_to`]
+#[derive(Clone, Debug)]
+pub struct SessionCheckpoint {
+	n_past: usize,
+	tokens: usize,
+	decoded_tokens: usize,
+	last_logits: Vec<f32>,
+
+	/// The generation of the context window the checkpoint was taken in (see [`BackendSession::context_generation`])
+	generation: usize,
+}
+
+impl SessionCheckpoint {
+	pub(crate) fn of(session: &InferenceSession, generation: usize) -> SessionCheckpoint {
+		SessionCheckpoint {
+			n_past: session.n_past,
+			tokens: session.tokens.len(),
+			decoded_tokens: session.decoded_tokens.len(),
+			last_logits: session.last_logits.clone(),
+			generation,
+		}
+	}
+}
+
+/// Lets another thread cancel a completion (see [`BackendSession::complete_cancellable`]). The completion then stops
+/// before it feeds the next part of the prompt or generates the next token, and ends with [`FinishReason::Cancelled`].
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+	pub fn new() -> Cancellation {
+		Cancellation::default()
+	}
+
+	pub fn cancel(&self) {
+		self.0.store(true, Ordering::SeqCst);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::SeqCst)
+	}
+}
+
+/// Why a completion has to end before it is done: because it was cancelled or because the maximum duration of the task
+/// was reached
+#[derive(Clone, Copy)]
+struct Interruption<'a> {
+	cancellation: &'a Cancellation,
+	deadline: Option<Instant>,
+}
+
+impl Interruption<'_> {
+	fn timed_out(&self) -> bool {
+		self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+	}
+
+	/// The reason to end the completion now, if there is one
+	fn reason(&self) -> Option<FinishReason> {
+		if self.cancellation.is_cancelled() {
+			Some(FinishReason::Cancelled)
+		} else if self.timed_out() {
+			Some(FinishReason::Timeout)
+		} else {
+			None
+		}
+	}
+}
+
+/// Statistics of a completion and the reason it ended
+#[derive(Clone, Debug)]
+pub struct Completion {
+	/// Durations and token counts of prompt feeding and sampling (excluding tokens forced by the biaser)
+	pub stats: InferenceStats,
+	pub usage: TokenUsage,
+	pub timings: GenerationTimings,
+	pub finish_reason: FinishReason,
+
+	/// The end-of-text token that ended generation (when the finish reason is [`FinishReason::Eot`])
+	pub eot_token: Option<String>,
+
+	/// Whether max_tokens or the maximum duration was reached in biased mode before the output was complete, in which
+	/// case the biaser closed it (e.g. by adding closing brackets and quotes)
+	pub force_closed: bool,
+
+	/// The tool the model called instead of answering (for tasks with tools)
+	pub tool_call: Option<ToolCall>,
+
+	/// Whether the output is valid according to the schema of the biaser (for tasks with a biaser)
+	pub validation: Option<OutputValidation>,
+
+	/// Index of the choice the output is (for tasks with a choices biaser)
+	pub choice: Option<usize>,
+
+	/// Number of tokens cut off the start of the prompt to fit in the maximum number of prompt tokens of the task
+	pub truncated_tokens: usize,
+
+	/// Time taken by the completion, including attempts to repair invalid output and the completions before tool calls
+	pub duration: Duration,
+}
+
+impl Completion {
+	/// A completion that ended for the given reason without feeding or generating any tokens
+	pub fn new(finish_reason: FinishReason) -> Completion {
+		Completion {
+			stats: InferenceStats::default(),
+			usage: TokenUsage::default(),
+			timings: GenerationTimings::default(),
+			finish_reason,
+			eot_token: None,
+			force_closed: false,
+			tool_call: None,
+			validation: None,
+			choice: None,
+			truncated_tokens: 0,
+			duration: Duration::ZERO,
+		}
+	}
+}
+
+/// Number of tokens a prompt would take in the context window of a session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenCount {
+	/// Tokens of the prompt itself
+	pub prompt: usize,
+
+	/// Tokens the task adds to the prompt (prefix, postfix and beginning-of-sentence token)
+	pub overhead: usize,
+
+	/// Tokens already in the context window
+	pub used: usize,
+
+	/// Tokens in the context window that are never dropped to make room (the prelude)
+	pub pinned: usize,
+
+	/// Size of the context window
+	pub context: usize,
+}
+
+impl TokenCount {
+	/// Tokens in the context window that may be dropped to make room for new ones
+	pub fn unpinned(&self) -> usize {
+		self.used.saturating_sub(self.pinned)
+	}
+}
+
 pub struct BackendSession {
 	pub(crate) model: Arc<Box<dyn llm::Model>>,
 	pub(crate) memory: Option<Arc<Box<dyn Memory>>>,
@@ -37,6 +195,23 @@ pub struct BackendSession {
 	pub(crate) task_name: String,
 	pub(crate) backend: Arc<Backend>,
 	pub(crate) n_threads: usize,
+	pub(crate) n_batch: usize,
+	pub(crate) prompt_chunk_size: usize,
+
+	/// Tokens that end generation (see [`crate::preflight::eot_token_ids`])
+	pub(crate) eot_token_ids: Vec<TokenId>,
+
+	/// The analysis of the vocabulary of the model, shared with the other sessions using the model
+	pub(crate) vocabulary: SharedVocabulary,
+
+	/// End of the prelude, which is kept when the session is rewound or tokens are dropped to make room
+	pub(crate) pinned: SessionCheckpoint,
+
+	/// Incremented each time the tokens following the prelude move to other positions in the context window (when
+	/// tokens are dropped to make room, or a state is restored), which invalidates the checkpoints taken before
+	pub(crate) context_generation: usize,
+	pub(crate) _session_guard: GaugeGuard,
+	pub(crate) _model_session_guard: GaugeGuard,
 }
 
 impl Debug for BackendSession {
@@ -49,29 +224,121 @@ impl Debug for BackendSession {
 	}
 }
 
+/// What to do after generated text was passed through the banned phrases (see [`filter_phrases`])
+enum Filtered {
+	Continue,
+	Finish(FinishReason),
+
+	/// Rewind generation to before the token at the index, which started a banned phrase with action `retry`
+	Retry(usize),
+}
+
+/// Pass generated text (of the tokens starting at index `tag`) through the banned phrases of a task, and output the text
+/// that is released. Banned phrases with action `retry` are censored when `retry` is false.
+fn filter_phrases(
+	filter: &mut PhraseFilter,
+	task_config: &TaskConfig,
+	retry: bool,
+	mut text: String,
+	mut tag: usize,
+	output_text: &mut impl FnMut(String) -> Result<Option<FinishReason>, BackendError>,
+) -> Result<Filtered, BackendError> {
+	loop {
+		let (released, found) = filter.push(text, tag);
+		for (chunk, _) in released {
+			if let Some(reason) = output_text(chunk)? {
+				return Ok(Filtered::Finish(reason));
+			}
+		}
+		let Some(found) = found else {
+			return Ok(Filtered::Continue);
+		};
+
+		let action = task_config.banned_phrases[found.phrase].action();
+		tracing::info!(phrase = found.phrase, ?action, "banned phrase generated");
+		match action {
+			BannedPhraseAction::Retry if retry => return Ok(Filtered::Retry(found.tag)),
+			BannedPhraseAction::Stop => {
+				if !found.before.is_empty() {
+					if let Some(reason) = output_text(found.before)? {
+						return Ok(Filtered::Finish(reason));
+					}
+				}
+				return Ok(Filtered::Finish(FinishReason::BannedPhrase));
+			}
+			BannedPhraseAction::Censor | BannedPhraseAction::Retry => {
+				if let Some(reason) = output_text(found.before + &task_config.banned_phrase_placeholder)? {
+					return Ok(Filtered::Finish(reason));
+				}
+				(text, tag) = found.after;
+				if text.is_empty() {
+					return Ok(Filtered::Continue);
+				}
+			}
+		}
+	}
+}
+
+/// Describe an error that occurred while generating, after `after_tokens` tokens were generated. The message is
+/// redacted, as it may contain generated text.
+fn inference_failed(redactor: &Redactor, error: InferenceError, after_tokens: usize) -> BackendError {
+	let message = redactor.redact(&error.to_string()).into_owned();
+	tracing::error!("inference error after {after_tokens} tokens: {message}");
+	BackendError::InferenceFailed {
+		after_tokens,
+		source: message.into(),
+	}
+}
+
 impl BackendSession {
+	#[tracing::instrument(level = "info", skip_all)]
 	fn remember_prompt(&mut self, request: &PromptRequest) -> Result<Option<String>, BackendError> {
 		// Check if we need to recall items from memory first
 		if let Some(memorization) = &self.task_config.memorization {
 			if let Some(retrieve) = memorization.retrieve {
-				if retrieve > 0 {
+				let text = request.memory_text();
+				if retrieve > 0 && !text.trim().is_empty() {
 					// Calculate embedding for prompt
 					let backend = self.backend.clone();
-					let embedding = backend.embedding(&self.task_config.model, request)?;
-
-					let handle = tokio::runtime::Handle::current();
-					let _guard = handle.enter();
-					let memory = self.memory.clone().unwrap();
-					let remember_prompt = handle
-						.block_on(tokio::spawn(async move {
-							let rm = memory.get(&embedding.embedding, retrieve);
-							let remembered = rm.await?;
-							tracing::debug!("retrieved from memory: {remembered:?}");
+					let embedding = backend.embedding(&self.task_config.model, &PromptRequest::new(text))?;
+
+					let memory = self
+						.memory
+						.clone()
+						.ok_or_else(|| BackendError::MemoryNotFound(memorization.memory.clone()))?;
+					let span = tracing::info_span!("memory_retrieve", top_n = retrieve);
+					let redactor = self.redactor();
+					let include_timestamps = memorization.include_timestamps;
+					let remember_prompt = backend.block_on_memory(
+						async move {
+							let remembered: Vec<String> = if include_timestamps {
+								let hits = memory
+									.search(&embedding.embedding, retrieve, &ItemMetadata::default())
+									.await
+									.map_err(BackendError::memory(MemoryStage::Retrieve))?;
+								let now = now_millis();
+								hits.into_iter()
+									.map(|hit| match hit.stored_at {
+										Some(stored_at) => format!("[{}] {}", humanize_age(now.saturating_sub(stored_at)), hit.text),
+										None => hit.text,
+									})
+									.collect()
+							} else {
+								memory
+									.get(&embedding.embedding, retrieve)
+									.await
+									.map_err(BackendError::memory(MemoryStage::Retrieve))?
+							};
+							tracing::debug!(
+								"retrieved from memory: {:?}",
+								remembered.iter().map(|r| redactor.redact(r)).collect::<Vec<_>>()
+							);
 							let remember_prompt: String = remembered.join("\n");
 							Ok::<_, BackendError>(remember_prompt)
-						}))
-						.unwrap()?;
-					tracing::info!("Remember prompt: {remember_prompt}");
+						}
+						.instrument(span),
+					)?;
+					tracing::info!("Remember prompt: {}", self.redactor().redact(&remember_prompt));
 					return Ok(Some(remember_prompt));
 				}
 			}
@@ -79,56 +346,557 @@ impl BackendSession {
 		Ok(None)
 	}
 
+	/// Redacts the private tokens of the task from text that is logged
+	fn redactor(&self) -> Redactor {
+		Redactor::new(self.task_config.private_tokens.iter().flatten())
+	}
+
+	fn private_tokens(&self) -> Result<PrivateTokens, BackendError> {
+		PrivateTokens::new(self.model.tokenizer(), &self.task_config)
+	}
+
+	/// The analysis of the vocabulary of the model used by biasers, which is made by the first session that needs it
+	fn vocabulary_info(&self) -> Arc<VocabularyInfo> {
+		self.vocabulary
+			.get_or_init(|| Arc::new(VocabularyInfo::new(self.model.tokenizer())))
+			.clone()
+	}
+
+	/// Tokenize a prompt the way it is fed to the model: recalled memories, prefix, user prompt and postfix. Fails when
+	/// an untrusted segment of the user prompt contains a private token, or when the prompt takes more tokens than the
+	/// task allows and `truncate` is not set. Also returns the number of tokens cut off the prompt to make it fit.
+	fn prompt_tokens(
+		&self,
+		remember_prompt: Option<&str>,
+		segments: &[PromptSegment],
+		beginning_of_sentence: bool,
+		truncate: bool,
+	) -> Result<(Vec<TokenId>, usize), BackendError> {
+		let mut tokens = preflight::prompt_tokens(
+			self.model.tokenizer(),
+			&self.task_config,
+			remember_prompt,
+			segments,
+			beginning_of_sentence,
+		)?;
+		let truncated = tokens.limit(self.task_config.max_prompt_tokens, truncate)?;
+		if truncated > 0 {
+			tracing::info!(truncated, "cut off the start of the prompt to fit in max_prompt_tokens");
+		}
+		Ok((tokens.checked()?, truncated))
+	}
+
+	/// Name of the task the session was started for
+	pub fn task_name(&self) -> &str {
+		&self.task_name
+	}
+
+	/// Name of the model the session runs on
+	pub fn model_name(&self) -> &str {
+		&self.task_config.model
+	}
+
+	/// Feed an earlier exchange (a prompt and the response to it) to the session without generating anything, e.g. to
+	/// restore a conversation. The prompt is fed like [`BackendSession::complete`] would, but without recalling or
+	/// storing memories.
+	#[tracing::instrument(level = "info", skip_all, fields(task = %self.task_name, model = %self.task_config.model))]
+	pub fn replay(&mut self, request: &PromptRequest, response: &str) -> Result<InferenceStats, BackendError> {
+		let beginning_of_sentence = self.model.bot_token_id().is_some() && self.session.n_past == 0;
+		let (mut tokens, _) = self.prompt_tokens(
+			None,
+			&tools::prompt_segments(&self.task_config, request)?,
+			beginning_of_sentence,
+			request.truncate,
+		)?;
+		tokens.append(&mut Prompt::Text(response).to_tokens(self.model.tokenizer(), false)?);
+		tokens.append(&mut self.reply_suffix_tokens()?);
+
+		let start = Instant::now();
+		let available = self.context_remaining();
+		self.session
+			.feed_prompt(
+				self.model.as_ref().as_ref(),
+				Prompt::Tokens(&tokens),
+				&mut OutputRequest::default(),
+				|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
+			)
+			.map_err(|e| BackendError::from_inference(e, tokens.len(), available, 0))?;
+		Ok(InferenceStats {
+			feed_prompt_duration: Instant::now().duration_since(start),
+			prompt_tokens: tokens.len(),
+			predict_duration: Duration::ZERO,
+			predict_tokens: 0,
+		})
+	}
+
+	/// Tokens of the assistant suffix of the chat template of the task (if any), which close a reply of the model
+	fn reply_suffix_tokens(&self) -> Result<Vec<TokenId>, BackendError> {
+		match self.task_config.chat_template {
+			Some(ref template) if !template.assistant_suffix.is_empty() => {
+				Ok(Prompt::Text(&template.assistant_suffix).to_tokens(self.model.tokenizer(), false)?)
+			}
+			_ => Ok(vec![]),
+		}
+	}
+
+	/// Close the reply that was just generated (see [`BackendSession::reply_suffix_tokens`]), so that the session can
+	/// continue with the next prompt of a chat. Nothing is fed when the suffix does not fit in the context window.
+	fn close_reply(&mut self) -> Result<(), BackendError> {
+		let tokens = self.reply_suffix_tokens()?;
+		if tokens.is_empty() {
+			return Ok(());
+		}
+		if self.task_config.slide_context {
+			self.make_room(tokens.len())?;
+		}
+		let available = self.context_remaining();
+		if tokens.len() > available {
+			return Ok(());
+		}
+		self.session
+			.feed_prompt(
+				self.model.as_ref().as_ref(),
+				Prompt::Tokens(&tokens),
+				&mut OutputRequest::default(),
+				|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
+			)
+			.map_err(|e| BackendError::from_inference(e, tokens.len(), available, 0))?;
+		Ok(())
+	}
+
+	/// Count the tokens a prompt would take when fed to the session, without touching the inference state. Memories that
+	/// would be recalled for the prompt are not counted, as recalling them requires calculating an embedding.
+	pub fn count_tokens(&self, prompt: &str) -> Result<TokenCount, BackendError> {
+		let tokenizer = self.model.tokenizer();
+		let beginning_of_sentence = self.model.bot_token_id().is_some() && self.session.n_past == 0;
+		let mut overhead = usize::from(beginning_of_sentence);
+		for text in self.task_config.prefix.iter().chain(&self.task_config.postfix) {
+			let text = datetime::expand_variables(text, now_millis());
+			overhead += Prompt::Text(&text).to_tokens(tokenizer, false)?.len();
+		}
+		Ok(TokenCount {
+			prompt: Prompt::Text(prompt).to_tokens(tokenizer, false)?.len(),
+			overhead,
+			used: self.session.n_past,
+			pinned: self.pinned.n_past,
+			context: self.model.context_size(),
+		})
+	}
+
+	/// Number of tokens that can still be fed to the session before its context window is full
+	pub fn context_remaining(&self) -> usize {
+		self.model.context_size().saturating_sub(self.session.n_past)
+	}
+
+	/// Number of threads the model uses to evaluate tokens in the session
+	pub fn inference_threads(&mut self) -> usize {
+		unsafe { self.session.get_snapshot() }.config.n_threads
+	}
+
+	/// Mark the current position in the conversation, so that anything fed or generated after it can be undone later
+	pub fn checkpoint(&self) -> SessionCheckpoint {
+		SessionCheckpoint::of(&self.session, self.context_generation)
+	}
+
+	/// Rewind the session to a checkpoint taken earlier using [`BackendSession::checkpoint`]. Tokens after the checkpoint
+	/// are forgotten; their entries in the key/value memory are overwritten as new tokens are fed. Fails when the session
+	/// has not advanced up to the checkpoint (e.g. because it was rewound in the meantime), when the checkpoint lies
+	/// within the prelude, or when the conversation after the prelude has moved since the checkpoint was taken (because
+	/// tokens were dropped to make room or a state was restored).
+	pub fn rewind_to(&mut self, checkpoint: &SessionCheckpoint) -> Result<(), BackendError> {
+		if checkpoint.n_past < self.pinned.n_past {
+			return Err(BackendError::SessionState(format!(
+				"cannot rewind to position {} as it lies within the prelude (which ends at position {})",
+				checkpoint.n_past, self.pinned.n_past
+			)));
+		}
+
+		// The prelude never moves, so the end of it stays a valid checkpoint
+		if checkpoint.generation != self.context_generation && checkpoint.n_past > self.pinned.n_past {
+			return Err(BackendError::SessionState(format!(
+				"cannot rewind to position {} as the conversation has moved since the checkpoint was taken",
+				checkpoint.n_past
+			)));
+		}
+		if checkpoint.n_past > self.session.n_past
+			|| checkpoint.tokens > self.session.tokens.len()
+			|| checkpoint.decoded_tokens > self.session.decoded_tokens.len()
+		{
+			return Err(BackendError::SessionState(format!(
+				"cannot rewind to position {} as the session is at position {}",
+				checkpoint.n_past, self.session.n_past
+			)));
+		}
+		self.session.n_past = checkpoint.n_past;
+		self.session.tokens.truncate(checkpoint.tokens);
+		self.session.decoded_tokens.truncate(checkpoint.decoded_tokens);
+		self.session.last_logits.clone_from(&checkpoint.last_logits);
+		Ok(())
+	}
+
+	/// Write the inference state of the session (including the model's key/value memory) so that it can be restored
+	/// later using [`BackendSession::restore_state`]. The state can be large (up to the size of the context window times
+	/// the size of the key/value memory per token).
+	pub fn save_state(&mut self, writer: impl Write) -> Result<(), BackendError> {
+		let snapshot = unsafe { self.session.get_snapshot() };
+		bincode::serialize_into(writer, &snapshot).map_err(|e| BackendError::SessionState(e.to_string()))
+	}
+
+	/// Restore an inference state written by [`BackendSession::save_state`]. Fails when the state was saved for a
+	/// different model.
+	pub fn restore_state(&mut self, reader: impl Read) -> Result<(), BackendError> {
+		let mut snapshot: InferenceSnapshot = bincode::deserialize_from(reader).map_err(|e| BackendError::SessionState(e.to_string()))?;
+
+		// The state may have been saved by a session that used a different number of threads or batch size
+		snapshot.config.n_threads = self.n_threads;
+		snapshot.config.n_batch = self.n_batch;
+		self.session =
+			InferenceSession::from_snapshot(snapshot, self.model.as_ref().as_ref()).map_err(|e| BackendError::SessionState(e.to_string()))?;
+		self.context_generation += 1;
+		Ok(())
+	}
+
+	/// Drop the oldest tokens following the prelude from the context window to make room for at least `needed` tokens.
+	/// Half of the tokens following the prelude are dropped (or more, when needed). As the key/value memory cannot
+	/// express dropping tokens from the middle, the session is rewound to the end of the prelude and the tokens that are
+	/// kept are fed again. Returns the number of tokens dropped; none are dropped when `needed` tokens would not fit even
+	/// after dropping all of them.
+	pub fn make_room(&mut self, needed: usize) -> Result<usize, BackendError> {
+		let unpinned = self.session.tokens.len().saturating_sub(self.pinned.tokens);
+		let shortfall = needed.saturating_sub(self.context_remaining());
+		let n_drop = (unpinned / 2).max(shortfall);
+		if shortfall == 0 || n_drop > unpinned {
+			return Ok(0);
+		}
+
+		let kept = self.session.tokens[(self.pinned.tokens + n_drop)..].to_vec();
+		let pinned = self.pinned.clone();
+		self.rewind_to(&pinned)?;
+		self.context_generation += 1;
+		let available = self.context_remaining();
+		self.session
+			.feed_prompt(
+				self.model.as_ref().as_ref(),
+				Prompt::Tokens(&kept),
+				&mut OutputRequest::default(),
+				|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
+			)
+			.map_err(|e| BackendError::from_inference(e, kept.len(), available, 0))?;
+		tracing::info!(
+			"dropped {n_drop} tokens from the context window, keeping {} tokens of prelude and {} tokens of conversation",
+			self.pinned.n_past,
+			kept.len()
+		);
+		Ok(n_drop)
+	}
+
+	/// The text currently held in the context window of the session (including the prelude)
+	pub fn transcript(&self) -> String {
+		String::from_utf8_lossy(&self.session.decoded_tokens).to_string()
+	}
+
+	/// Feed tokens to the session in chunks of the configured size. After each chunk, the callback receives the text of
+	/// the chunk as [`InferenceResponse::PromptToken`] and may halt feeding. Feeding also stops when the completion is
+	/// cancelled or the deadline passes.
+	/// Returns the reason feeding stopped early (if it did). Fails before feeding anything when the tokens do not fit in
+	/// the context window.
+	fn feed_chunked(
+		&mut self,
+		tokens: &[TokenId],
+		interruption: Interruption,
+		callback: &mut impl FnMut(InferenceResponse) -> Result<InferenceFeedback, BackendError>,
+		stats: &mut InferenceStats,
+	) -> Result<Option<FinishReason>, BackendError> {
+		preflight::check_context(tokens.len(), self.context_remaining())?;
+
+		let tokenizer = self.model.tokenizer();
+		for chunk in tokens.chunks(self.prompt_chunk_size.max(1)) {
+			if let Some(reason) = interruption.reason() {
+				return Ok(Some(reason));
+			}
+			let start = Instant::now();
+			let available = self.context_remaining();
+			self.session
+				.feed_prompt(
+					self.model.as_ref().as_ref(),
+					Prompt::Tokens(chunk),
+					&mut OutputRequest::default(),
+					|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
+				)
+				.map_err(|e| BackendError::from_inference(e, chunk.len(), available, 0))?;
+			stats.add(&InferenceStats {
+				feed_prompt_duration: Instant::now().duration_since(start),
+				prompt_tokens: chunk.len(),
+				predict_duration: Duration::ZERO,
+				predict_tokens: 0,
+			});
+
+			let text: Vec<u8> = chunk
+				.iter()
+				.filter(|t| Some(**t) != self.model.bot_token_id())
+				.flat_map(|t| tokenizer.token(*t as usize))
+				.collect();
+			if let InferenceFeedback::Halt = callback(InferenceResponse::PromptToken(String::from_utf8_lossy(&text).to_string()))? {
+				return Ok(Some(FinishReason::Cancelled));
+			}
+		}
+		Ok(None)
+	}
+
 	/// Perform a completion task following the task's configuration.
 	pub fn complete(
 		&mut self,
 		request: &PromptRequest,
 		callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, BackendError>,
-	) -> Result<InferenceStats, BackendError> {
+	) -> Result<Completion, BackendError> {
+		self.complete_cancellable(request, &Cancellation::default(), callback)
+	}
+
+	/// Perform a completion task following the task's configuration, which ends early (with the statistics up to that
+	/// point) when it is cancelled through `cancellation`
+	#[tracing::instrument(level = "info", skip_all, fields(task = %self.task_name, model = %self.task_config.model))]
+	pub fn complete_cancellable(
+		&mut self,
+		request: &PromptRequest,
+		cancellation: &Cancellation,
+		callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, BackendError>,
+	) -> Result<Completion, BackendError> {
+		// The maximum duration of the task covers everything done for the request (e.g. also repairing invalid output)
+		let interruption = Interruption {
+			cancellation,
+			deadline: self
+				.task_config
+				.max_duration_secs
+				.and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+				.map(|duration| Instant::now() + duration),
+		};
+
 		// Perform inference
-		let stats = self.complete_actual(request, callback)?;
+		let started = Instant::now();
+		let mut completion = if !self.task_config.tools.is_empty() {
+			self.complete_with_tools(request, interruption, callback)?
+		} else if self.task_config.biaser.is_some() {
+			self.complete_with_validation(request, interruption, callback)?
+		} else {
+			self.complete_actual(request, interruption, callback)?
+		};
+		completion.duration = started.elapsed();
+		let stats = &completion.stats;
 		let prompt_tokens_per_s = (stats.prompt_tokens as f64) / stats.feed_prompt_duration.as_secs_f64();
 		let predict_tokens_per_s = (stats.predict_tokens as f64) / stats.predict_duration.as_secs_f64();
 
 		tracing::info!(
-			"completion finished; {prompt_tokens_per_s:.3} t/s prompt, {predict_tokens_per_s:.3} t/s predict; stats: {:?}",
+			"completion finished ({:?}); {prompt_tokens_per_s:.3} t/s prompt, {predict_tokens_per_s:.3} t/s predict; stats: {:?}",
+			completion.finish_reason,
 			stats
 		);
-		self.stats.add(&self.task_name, &stats, self.n_threads);
+		tracing::debug!("generation timings: {:?}", completion.timings);
+		self.stats
+			.add(&self.task_name, &self.task_config.model, &completion, self.n_threads, self.n_batch);
 
 		// Perform memorization
+		let text = request.memory_text();
 		if let Some(memorization) = &self.task_config.memorization {
-			if memorization.store_prompts {
+			if memorization.store_prompts && !text.trim().is_empty() {
 				let backend = self.backend.clone();
 
-				// Calculate embedding
-				let embedding = backend.embedding(&self.task_config.model, request)?;
+				// Calculate embedding (only of the segments that are to be stored)
+				let embedding = backend.embedding(&self.task_config.model, &PromptRequest::new(text.as_str()))?;
 
 				// Commit to memory in the background
-				let text = request.prompt.clone();
-				let memory = self.memory.clone().unwrap();
-
-				let handle = tokio::runtime::Handle::current();
-				let _guard = handle.enter();
-				handle
-					.block_on(tokio::spawn(async move {
-						memory.store(&text, &embedding.embedding).await?;
-						tracing::debug!("committed to memory: {text}");
+				let logged = if request.segments().iter().any(|s| s.memorize && s.redact_in_logs) {
+					REDACTED.to_string()
+				} else {
+					self.redactor().redact(&text).into_owned()
+				};
+				let memory = self
+					.memory
+					.clone()
+					.ok_or_else(|| BackendError::MemoryNotFound(memorization.memory.clone()))?;
+
+				let span = tracing::info_span!("memory_store");
+				backend.block_on_memory(
+					async move {
+						memory
+							.store(&text, &embedding.embedding, &ItemMetadata::default())
+							.await
+							.map_err(BackendError::memory(MemoryStage::Store))?;
+						tracing::debug!("committed to memory: {logged}");
 						Ok::<(), BackendError>(())
-					}))
-					.unwrap()?;
+					}
+					.instrument(span),
+				)?;
 			}
 		}
 
-		Ok(stats)
+		Ok(completion)
 	}
 
+	/// Perform a completion for a task with tools. The output (a JSON object) is held back until it is complete. An answer
+	/// is then passed to the callback as a single token, whereas a tool call is returned with the completion.
+	fn complete_with_tools(
+		&mut self,
+		request: &PromptRequest,
+		interruption: Interruption,
+		mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, BackendError>,
+	) -> Result<Completion, BackendError> {
+		let mut output = String::new();
+		let mut completion = self.complete_actual(request, interruption, |r| match r {
+			InferenceResponse::InferredToken(t) => {
+				output += &t;
+				Ok(InferenceFeedback::Continue)
+			}
+			r => callback(r),
+		})?;
+		if matches!(completion.finish_reason, FinishReason::Cancelled | FinishReason::Timeout) {
+			return Ok(completion);
+		}
+
+		match tools::parse_output(&output) {
+			Some(ToolOutput::Call(tool_call)) => {
+				tracing::debug!(tool = tool_call.tool, "model called a tool");
+				completion.tool_call = Some(tool_call);
+			}
+			Some(ToolOutput::Answer(answer)) => {
+				if !answer.is_empty() {
+					callback(InferenceResponse::InferredToken(answer))?;
+				}
+			}
+			None => {
+				return Err(BackendError::InferenceFailed {
+					after_tokens: completion.usage.sampled_tokens,
+					source: "the output of the model is neither a tool call nor an answer".into(),
+				})
+			}
+		}
+		Ok(completion)
+	}
+
+	/// Perform a biased completion and validate the output once it is complete. Invalid output is repaired by feeding
+	/// the validation errors to the model and completing again, up to the configured number of times, after which the
+	/// completion fails. When repairs are allowed, the output is held back until it is valid.
+	fn complete_with_validation(
+		&mut self,
+		request: &PromptRequest,
+		interruption: Interruption,
+		mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, BackendError>,
+	) -> Result<Completion, BackendError> {
+		let validation_config = self.task_config.validation.clone();
+		let validator = match preflight::biaser_schema(&self.task_config)? {
+			Some(schema) => OutputValidator::new(&schema, &validation_config).map_err(BackendError::InvalidBiaser)?,
+			None => return self.complete_actual(request, interruption, callback),
+		};
+		let held_back = validation_config.max_repair_attempts > 0;
+
+		let mut request = Cow::Borrowed(request);
+		let mut previous: Option<Completion> = None;
+		let mut attempt = 0;
+		loop {
+			attempt += 1;
+			let mut output = String::new();
+			let mut completion = self.complete_actual(&request, interruption, |r| match r {
+				InferenceResponse::InferredToken(t) if held_back => {
+					output += &t;
+					Ok(InferenceFeedback::Continue)
+				}
+				InferenceResponse::InferredToken(t) => {
+					output += &t;
+					callback(InferenceResponse::InferredToken(t))
+				}
+				r => callback(r),
+			})?;
+
+			// Usage and statistics cover all attempts
+			if let Some(previous) = previous.take() {
+				completion.stats.add(&previous.stats);
+				completion.usage.add(&previous.usage);
+				completion.timings.add(&previous.timings);
+				completion.truncated_tokens += previous.truncated_tokens;
+			}
+			if completion.finish_reason == FinishReason::Cancelled {
+				return Ok(completion);
+			}
+
+			// Output cut off by the deadline is returned as it is, as there is no time left to repair it
+			if completion.finish_reason == FinishReason::Timeout && !completion.force_closed {
+				if held_back && !output.is_empty() {
+					callback(InferenceResponse::InferredToken(output))?;
+				}
+				return Ok(completion);
+			}
+
+			let errors = match validator.validate(&output) {
+				Ok(()) => vec![],
+				Err(errors) if completion.force_closed => errors,
+				Err(errors) if attempt <= validation_config.max_repair_attempts => {
+					tracing::info!(attempt, n_errors = errors.len(), "output is invalid, asking the model to repair it");
+					let prompt = validation::repair_prompt(&validation_config, &errors);
+					request = Cow::Owned(PromptRequest {
+						segments: vec![PromptSegment {
+							text: prompt,
+							trusted: true,
+							..Default::default()
+						}],
+						tool_result: None,
+						..request.into_owned()
+					});
+					previous = Some(completion);
+					continue;
+				}
+				Err(errors) => return Err(BackendError::BiasedOutputInvalid { attempts: attempt, errors }),
+			};
+
+			if held_back && !output.is_empty() {
+				callback(InferenceResponse::InferredToken(output))?;
+			}
+			completion.validation = Some(OutputValidation {
+				attempts: attempt,
+				valid: errors.is_empty(),
+				errors,
+			});
+			return Ok(completion);
+		}
+	}
+
+	/// Perform a single completion, writing a trace of it when the task has a trace directory
 	fn complete_actual(
 		&mut self,
 		request: &PromptRequest,
+		interruption: Interruption,
+		callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, BackendError>,
+	) -> Result<Completion, BackendError> {
+		let Some(trace_dir) = self.task_config.trace_dir.clone() else {
+			return self.complete_traced(request, interruption, callback, &mut None);
+		};
+		let mut trace = Some(TraceRecorder::new(
+			&self.task_name,
+			&self.task_config.model,
+			self.redactor(),
+			self.private_tokens()?.single_ids(),
+		));
+		let result = self.complete_traced(request, interruption, callback, &mut trace);
+		if let Some(trace) = trace {
+			self.backend.traces.write(trace_dir, trace.finish(&result));
+		}
+		result
+	}
+
+	fn complete_traced(
+		&mut self,
+		request: &PromptRequest,
+		interruption: Interruption,
 		mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, BackendError>,
-	) -> Result<InferenceStats, BackendError> {
+		trace: &mut Option<TraceRecorder>,
+	) -> Result<Completion, BackendError> {
+		let started = Instant::now();
 		let mut completion_stats = InferenceStats::default();
+		let mut usage = TokenUsage::default();
+		let mut timings = GenerationTimings::default();
+
+		// Apply the sampling parameters specified in the request
+		let task_config = self.task_config.with_overrides(request)?;
+		let inference_parameters: InferenceParameters = task_config.clone().into();
+		let redactor = self.redactor();
 
 		// Generate tokens (prefix + prompt + postfix)
 		let beginning_of_sentence = self.model.bot_token_id().is_some() && self.session.n_past == 0;
@@ -136,71 +904,79 @@ impl BackendSession {
 			"beginning-of-text token is {:?}, beginning_of_sentence={beginning_of_sentence:?}",
 			self.model.bot_token_id()
 		);
-		let mut tokens = vec![];
-
-		// Append remember tokens
-		if let Some(remember_prompt) = self.remember_prompt(request)? {
-			tokens.append(&mut Prompt::Text(&remember_prompt).to_tokens(self.model.tokenizer(), beginning_of_sentence && tokens.is_empty())?)
-		}
-
-		// Append prefix tokens
-		if let Some(ref prefix) = self.task_config.prefix {
-			tokens.append(&mut Prompt::Text(prefix).to_tokens(self.model.tokenizer(), beginning_of_sentence && tokens.is_empty())?);
+		let segments = tools::prompt_segments(&self.task_config, request)?;
+		let remember_prompt = self.remember_prompt(request)?;
+		let (mut tokens, truncated_tokens) = self.prompt_tokens(remember_prompt.as_deref(), &segments, beginning_of_sentence, request.truncate)?;
+		if request.has_redactions() {
+			tracing::trace!("prompt (redacted): {}", redactor.redact(&request.log_text()));
+		} else {
+			tracing::trace!("prompt tokens: {tokens:?}");
 		}
-
-		// Generate user prompt tokens
-		let mut user_tokens = Prompt::Text(&request.prompt).to_tokens(self.model.tokenizer(), beginning_of_sentence && tokens.is_empty())?;
-
-		// Check for private tokens in user prompt
-		let private_tokens = self.task_config.private_tokens.clone().unwrap_or_default();
-		let private_token_ids: Vec<u32> = private_tokens
-			.iter()
-			.map(|token_str| {
-				let toks = self.model.tokenizer().tokenize(token_str, false).unwrap();
-				if toks.len() != 1 {
-					panic!("invalid forbidden token configured: {token_str}");
-				}
-				toks[0].1
-			})
-			.collect();
-		if !private_token_ids.is_empty() && user_tokens.iter().any(|t| private_token_ids.contains(t)) {
-			return Err(BackendError::IllegalToken);
+		let n_prompt_tokens = tokens.len();
+		if let Some(trace) = trace {
+			trace.recalled(remember_prompt.as_deref());
+			if request.has_redactions() {
+				trace.redact_prompt();
+			}
 		}
-		tokens.append(&mut user_tokens);
 
-		// Append postfix tokens
-		if let Some(ref postfix) = self.task_config.postfix {
-			tokens.append(&mut Prompt::Text(postfix).to_tokens(self.model.tokenizer(), beginning_of_sentence && tokens.is_empty())?);
-		}
+		let private_tokens = task_config.private_tokens.clone().unwrap_or_default();
+		let private = self.private_tokens()?;
+		let private_token_ids = private.single_ids();
 
-		tracing::trace!("prompt tokens: {tokens:?}");
+		// Set up biaser
+		let schema = preflight::biaser_schema(&task_config)?;
+		let text_biaser = preflight::text_biaser(&task_config)?;
+		let biased = schema.is_some() || text_biaser.is_some();
+		let mut biaser = preflight::biaser(schema.as_deref(), text_biaser, || self.vocabulary_info());
 
 		// Feed initial prompt
-		let start = Instant::now();
-		self.session.feed_prompt(
-			self.model.as_ref().as_ref(),
-			Prompt::Tokens(&tokens),
-			&mut OutputRequest::default(),
-			|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
-		)?;
-		completion_stats.add(&InferenceStats {
-			feed_prompt_duration: Instant::now().duration_since(start),
-			prompt_tokens: tokens.len(),
-			predict_duration: Duration::ZERO,
-			predict_tokens: 0,
-		});
+		if task_config.slide_context {
+			self.make_room(tokens.len())?;
+		}
+		let stopped = tracing::info_span!("feed_prompt", n_tokens = tokens.len())
+			.in_scope(|| self.feed_chunked(&tokens, interruption, &mut callback, &mut completion_stats))?;
+		if let Some(trace) = trace {
+			trace.fed(&tokens, |t| self.model.tokenizer().token(t as usize));
+		}
+		if let Some(finish_reason) = stopped {
+			tracing::info!("completion stopped while feeding the prompt ({finish_reason:?})");
+			return Ok(Completion {
+				usage: TokenUsage {
+					prompt_tokens: completion_stats.prompt_tokens,
+					..usage
+				},
+				stats: completion_stats,
+				timings,
+				truncated_tokens,
+				duration: started.elapsed(),
+				..Completion::new(finish_reason)
+			});
+		}
 
 		// If a bias prompt is configured, let the model freely generate tokens, then feed the bias prompt and start
 		// biased prompt generation. The tokens generated before the bias prompt is fed are not returned.
-		let mut rng = rand::thread_rng();
-		if let Some(ref bias_prompt) = self.task_config.bias_prompt {
+		// When tracing, a random seed is chosen up front so that the trace can tell which one was used
+		let seed = task_config.seed.or_else(|| trace.as_ref().map(|_| rand::random()));
+		let mut rng = match seed {
+			Some(seed) => StdRng::seed_from_u64(seed),
+			None => StdRng::from_entropy(),
+		};
+		if let (Some(trace), Some(seed)) = (trace.as_mut(), seed) {
+			trace.parameters(&task_config, seed, biased);
+		}
+		let eot_tokens = task_config.eot_token.as_ref().map(|t| t.tokens().to_vec()).unwrap_or_default();
+		if let Some(ref bias_prompt) = task_config.bias_prompt {
+			let n_past_before = self.session.n_past;
+			let n_tokens_before = self.session.tokens.len();
+			let mut interrupted = None;
 			let stats = self.session.infer(
 				self.model.as_ref().as_ref(),
 				&mut rng,
 				&InferenceRequest {
 					prompt: Prompt::Tokens(&[]),
-					parameters: &self.inference_parameters,
-					maximum_token_count: self.task_config.max_tokens,
+					parameters: &inference_parameters,
+					maximum_token_count: task_config.max_tokens,
 					play_back_previous_tokens: false,
 				},
 				&mut OutputRequest::default(),
@@ -209,134 +985,307 @@ impl BackendSession {
 						InferenceResponse::SnapshotToken(_) => Ok(InferenceFeedback::Continue),
 						InferenceResponse::PromptToken(_) => Ok(InferenceFeedback::Continue),
 						InferenceResponse::InferredToken(t) => {
-							// Save to transcript
-							if tracing::enabled!(tracing::Level::DEBUG) {
-								tokens.push(self.model.tokenizer().tokenize(&t, false).unwrap()[0].1);
+							tracing::trace!("Unbiased output token: {}", redactor.redact(&t));
+							if eot_tokens.contains(&t) {
+								return Ok(InferenceFeedback::Halt);
+							}
+							if let Some(reason) = interruption.reason() {
+								interrupted = Some(reason);
+								return Ok(InferenceFeedback::Halt);
 							}
-							tracing::trace!("Unbiased output token: {t}");
 							Ok(InferenceFeedback::Continue)
 						}
 						InferenceResponse::EotToken => Ok(InferenceFeedback::Halt),
 					}
 				},
-			)?;
-			completion_stats.add(&stats);
+			);
+
+			// The callback only receives the text of each token, so the ids of the generated tokens are read back from the
+			// session (re-tokenizing the text does not necessarily give the token that was sampled)
+			let generated_ids = &self.session.tokens[n_tokens_before.min(self.session.tokens.len())..];
+			if tracing::enabled!(tracing::Level::DEBUG) {
+				tokens.extend_from_slice(generated_ids);
+			}
+			if let Some(trace) = trace.as_mut() {
+				for id in generated_ids {
+					trace.generated(*id, &self.model.tokenizer().token(*id as usize), TokenChoice::Unbiased, None);
+				}
+			}
+
+			// The tokens generated before the bias prompt is fed are not returned, so none were generated yet
+			let stats = stats.map_err(|e| BackendError::from_inference(e, 1, self.context_remaining(), 0))?;
+
+			// The token counts reported by `infer` are the total number of tokens in the session rather than the number of
+			// tokens processed by this call. As the prompt is empty, every token it processed was generated.
+			completion_stats.add(&InferenceStats {
+				feed_prompt_duration: stats.feed_prompt_duration,
+				prompt_tokens: 0,
+				predict_duration: stats.predict_duration,
+				predict_tokens: self.session.n_past - n_past_before,
+			});
+
+			// Nothing was output yet, as the unbiased tokens are not returned
+			if let Some(finish_reason) = interrupted {
+				tracing::info!("completion ended before the bias prompt was fed ({finish_reason:?})");
+				usage.prompt_tokens = completion_stats.prompt_tokens;
+				usage.sampled_tokens = completion_stats.predict_tokens;
+				return Ok(Completion {
+					stats: completion_stats,
+					usage,
+					timings,
+					truncated_tokens,
+					duration: started.elapsed(),
+					..Completion::new(finish_reason)
+				});
+			}
 
 			// Feed the bias prompt
-			tracing::info!("feeding bias prompt: {bias_prompt}");
+			tracing::info!("feeding bias prompt: {}", redactor.redact(bias_prompt));
 			if tracing::enabled!(tracing::Level::DEBUG) {
 				tokens.extend(self.model.tokenizer().tokenize(bias_prompt, false).unwrap().iter().map(|x| x.1));
 			}
 			let start = Instant::now();
-			self.session.feed_prompt(
-				self.model.as_ref().as_ref(),
-				Prompt::Text(bias_prompt.as_str()),
-				&mut OutputRequest::default(),
-				|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
-			)?;
+			let bias_tokens = Prompt::Text(bias_prompt.as_str()).to_tokens(self.model.tokenizer(), false)?;
+			let available = self.context_remaining();
+			self.session
+				.feed_prompt(
+					self.model.as_ref().as_ref(),
+					Prompt::Tokens(&bias_tokens),
+					&mut OutputRequest::default(),
+					|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
+				)
+				.map_err(|e| BackendError::from_inference(e, bias_tokens.len(), available, 0))?;
 			completion_stats.add(&InferenceStats {
 				feed_prompt_duration: Instant::now().duration_since(start),
-				prompt_tokens: tokens.len(),
+				prompt_tokens: bias_tokens.len(),
 				predict_duration: Duration::ZERO,
 				predict_tokens: 0,
 			});
+			if let Some(trace) = trace {
+				trace.fed(&bias_tokens, |t| self.model.tokenizer().token(t as usize));
+			}
 		}
 
-		// Set up biaser
-		let schema: Option<Cow<JsonSchema>>;
-		let mut biaser: Box<dyn Biaser> = match self.task_config.biaser {
-			Some(BiaserConfig::JsonSchema(ref schema)) => Box::new(JsonBiaser::new(schema)),
-			Some(BiaserConfig::JsonSchemaFile(ref path)) => {
-				let file = File::open(path).unwrap();
-				let rdr = BufReader::new(file);
-				schema = Some(Cow::Owned(serde_json::from_reader(rdr).expect("valid JSON schema in file")));
-				Box::new(JsonBiaser::new(schema.as_ref().unwrap()))
-			}
-			None => Box::new(NullBiaser {}),
-		};
-
 		// Inference loop
-		let mut result_buffer = TokenUtf8Buffer::new();
-		let vocabulary = self.model.tokenizer();
-		let eot_token = self.model.eot_token_id();
-		let mut inference_params = self.inference_parameters.clone();
+		let mut result_buffer = Utf8Buffer::default();
+		let model = self.model.clone();
+		let vocabulary = model.tokenizer();
+		let eot_token_ids = self.eot_token_ids.clone();
+		let mut eot_token_id = None;
+		let mut force_closed = false;
 		let mut tokens_generated: usize = 0;
-		let mut stop_sequences = if self.task_config.stop_sequences.is_empty() {
-			None
-		} else if self.task_config.biaser.is_some() {
-			tracing::warn!(
-				"a biaser is configured for task {}, therefore the stop sequences are ignored",
-				self.task_name
-			);
+		let mut stop_sequences = if task_config.stop_sequences.is_empty() {
 			None
 		} else {
 			Some(SequenceSet::new(
-				self.task_config.stop_sequences.iter().map(|x| Sequence::new(x.clone())).collect(),
+				task_config
+					.stop_sequences
+					.iter()
+					.map(|x| Sequence::with_options(x.text(), x.options()))
+					.collect(),
 			))
 		};
 
-		loop {
-			let mut biaser_bias = biaser.bias(vocabulary, eot_token);
+		// Text that may be the start of a stop sequence is withheld until it is known whether it is
+		let mut output_buffer = OutputBuffer::default();
+		let private_sequences: Vec<Sequence> = private.sequences().map(|s| Sequence::new(s.to_string())).collect();
+		let mut private_sequences = (!private_sequences.is_empty()).then(|| SequenceSet::new(private_sequences));
+
+		// Text that may be the start of a banned phrase is withheld as well. To retry a phrase, generation is rewound to
+		// a checkpoint taken before the token that started it; checkpoints are kept for the tokens whose text is withheld.
+		let mut phrase_filter = PhraseFilter::new(
+			task_config
+				.banned_phrases
+				.iter()
+				.map(|p| Sequence::with_options(p.text(), p.options()))
+				.collect(),
+		);
+		let retry_phrases = task_config.banned_phrases.iter().any(|p| p.action() == BannedPhraseAction::Retry);
+		let mut checkpoints: VecDeque<(usize, SessionCheckpoint)> = VecDeque::new();
+		let mut retries = 0;
+
+		// Tokens generated so far, the index of the first token whose text has not been passed to the filter yet, and for
+		// each position the tokens that started a banned phrase there
+		let mut generated: Vec<TokenId> = vec![];
+		let mut text_start = 0;
+		let mut banned_at: HashMap<usize, Vec<TokenId>> = HashMap::new();
+
+		// The length limits do not apply in biased mode, as cutting off the output would make it invalid
+		let mut output_limit = if biased {
+			OutputLimit::default()
+		} else {
+			OutputLimit::new(task_config.max_chars, task_config.max_lines)
+		};
+
+		// Pass generated text through the stop sequences and length limits, and output what is not withheld or private.
+		// Returns the reason to end generation, if any. In biased mode, text matching a stop sequence is not withheld, as
+		// the biaser has already accepted it and leaving it out could make the output invalid.
+		let mut output_text = |output: String| -> Result<Option<FinishReason>, BackendError> {
+			tracing::trace!("text: {}", redactor.redact(&output));
+
+			// Private tokens are swallowed, but can still end generation as part of a stop sequence. Private tokens that
+			// span several tokens are found in the text, which is withheld while it may be the start of one.
+			let (private_match, private_pending) = match private_sequences {
+				Some(ref mut private_sequences) => {
+					let matched = private_sequences.advance(&output);
+					(matched.then(|| private_sequences.matched()).flatten(), private_sequences.pending())
+				}
+				None => (None, 0),
+			};
+			let (stopped, withheld) = match stop_sequences {
+				Some(ref mut stop_sequences) => {
+					let stopped = stop_sequences.advance(&output);
+					let withheld = if biased {
+						0
+					} else if stopped {
+						stop_sequences.matched_tail().unwrap_or(0)
+					} else {
+						stop_sequences.pending()
+					};
+					(stopped, withheld)
+				}
+				None => (false, 0),
+			};
+			let private = private_tokens.contains(&output);
+			output_buffer.push(output, private);
+			if let Some((tail, len)) = private_match {
+				output_buffer.hide(tail, len);
+			}
+			let withheld = if stopped { withheld } else { withheld.max(private_pending) };
+
+			// The text before the stop sequence is returned; the matched text (which, depending on the matching options, can
+			// differ from the sequence) and anything after it is not. The returned text is cut off when it reaches a length
+			// limit.
+			let mut text = output_buffer.take(withheld);
+			let (fits, limited) = output_limit.fit(&text);
+			text.truncate(fits);
+			if !text.is_empty() {
+				match callback(InferenceResponse::InferredToken(text))? {
+					InferenceFeedback::Continue => {}
+					InferenceFeedback::Halt => return Ok(Some(FinishReason::Cancelled)),
+				}
+			}
+			if limited {
+				tracing::debug!("stop because the length limit was reached");
+				return Ok(Some(FinishReason::LengthLimit));
+			}
+			if stopped {
+				tracing::debug!("stop because stop sequence encountered");
+				return Ok(Some(FinishReason::StopSequence));
+			}
+			Ok(None)
+		};
+
+		let generate_span = tracing::info_span!("generate", biased, tokens_generated = tracing::field::Empty);
+		let generate_guard = generate_span.enter();
+
+		// In biased mode, a stop sequence ends generation once the biaser has closed the output (see below)
+		let mut stopped = false;
+
+		let mut finish_reason = loop {
+			if interruption.cancellation.is_cancelled() {
+				tracing::info!("completion was cancelled");
+				break FinishReason::Cancelled;
+			}
+
+			// Generating a token takes a position in the context window, and so does feeding it back to the model. Dropping
+			// tokens to make room invalidates the checkpoints.
+			if task_config.slide_context && self.make_room(2)? > 0 {
+				checkpoints.clear();
+			}
+			if retry_phrases {
+				let keep_from = phrase_filter.first_tag().unwrap_or(text_start);
+				while checkpoints.front().is_some_and(|(index, _)| *index < keep_from) {
+					checkpoints.pop_front();
+				}
+				checkpoints.push_back((tokens_generated, self.checkpoint()));
+			}
+
+			let bias_start = Instant::now();
+			let mut biaser_bias = biaser.bias(vocabulary, eot_token_ids[0]);
+			timings.bias_duration += Instant::now().duration_since(bias_start);
 
 			// Remove private tokens from biaser
 			biaser_bias.retain_mut(|t| !private_token_ids.contains(&t.0));
 
+			// Never choose a token that started a banned phrase at this position before
+			if let Some(banned) = banned_at.get(&tokens_generated) {
+				biaser_bias.extend(banned.iter().map(|t| (*t, f32::NEG_INFINITY)));
+			}
+
+			// Number of tokens the biaser allows here, for the trace
+			let allowed_tokens = (trace.is_some() && biased).then(|| biaser_bias.iter().filter(|(_, bias)| *bias > f32::NEG_INFINITY).count());
+
 			// If there is only one token positively biased, that will be the next token
-			let out_token_id = if biaser_bias.len() == 1 && biaser_bias[0].1 > 0.0 {
+			let (out_token_id, choice) = if biaser_bias.len() == 1 && biaser_bias[0].1 > 0.0 {
 				tracing::debug!("only one token in bias, that will be our next: {:?}", biaser_bias[0]);
 				// Still need to feed it to our model!
 				let only_possible_token = biaser_bias[0].0;
-				if only_possible_token != self.model.eot_token_id() {
+				if !eot_token_ids.contains(&only_possible_token) {
 					let start = Instant::now();
-					self.session.feed_prompt(
-						self.model.as_ref().as_ref(),
-						Prompt::Tokens(&[only_possible_token as TokenId]),
-						&mut OutputRequest::default(),
-						|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
-					)?;
-					completion_stats.add(&InferenceStats {
-						feed_prompt_duration: Instant::now().duration_since(start),
-						prompt_tokens: 1,
-						predict_duration: Duration::ZERO,
-						predict_tokens: 0,
-					});
+					let available = self.context_remaining();
+					self.session
+						.feed_prompt(
+							self.model.as_ref().as_ref(),
+							Prompt::Tokens(&[only_possible_token as TokenId]),
+							&mut OutputRequest::default(),
+							|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
+						)
+						.map_err(|e| BackendError::from_inference(e, 1, available, tokens_generated))?;
+					usage.forced_duration += Instant::now().duration_since(start);
+					usage.forced_tokens += 1;
 				}
-				only_possible_token
+				(only_possible_token, TokenChoice::Forced)
 			} else {
+				// Like `InferenceSession::infer_next_token`, which is not used so that sampling and evaluation can be
+				// timed separately: sampling a token requires room for it and for the position after it
+				if self.context_remaining() <= 1 {
+					tracing::warn!("ending generation because context is full");
+					break FinishReason::ContextFull;
+				}
+
+				let start = Instant::now();
 				let mut samplers = SamplerChain::new();
 				let flat_bias = llm::samplers::llm_samplers::samplers::SampleFlatBias::new(biaser_bias);
 				samplers.push_sampler(flat_bias);
-				samplers += self.task_config.sampler_chain();
+				samplers += task_config.sampler_chain();
 				tracing::debug!("sampler: {samplers:?}");
-				inference_params.sampler = Arc::new(Mutex::new(samplers));
+				let sampled = llm::samplers::sample_token(&mut samplers, &mut rng, &self.session.tokens, self.session.last_logits.iter().copied())
+					.map_err(|e| inference_failed(&redactor, e, tokens_generated))?;
+				let sampled_at = Instant::now();
+				timings.sample_duration += sampled_at.duration_since(start);
 
-				let start = Instant::now();
-				let out =
-					match self
-						.session
-						.infer_next_token(self.model.as_ref().as_ref(), &inference_params, &mut OutputRequest::default(), &mut rng)
-					{
-						Ok(out) => out,
-						Err(InferenceError::EndOfText) => break,
-						Err(InferenceError::ContextFull) => {
-							tracing::warn!("ending generation because context is full");
-							break;
-						}
-						Err(e) => {
-							tracing::error!("inference error: {e}");
-							break;
-						}
-					};
+				// Evaluate the model with the sampled token, yielding the logits for the token after it
+				self.session
+					.feed_prompt(
+						self.model.as_ref().as_ref(),
+						Prompt::Tokens(&[sampled]),
+						&mut OutputRequest::default(),
+						|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
+					)
+					.map_err(|e| inference_failed(&redactor, e, tokens_generated))?;
+				timings.evaluate_duration += Instant::now().duration_since(sampled_at);
 				completion_stats.add(&InferenceStats {
 					feed_prompt_duration: Duration::ZERO,
 					prompt_tokens: 0,
 					predict_duration: Instant::now().duration_since(start),
 					predict_tokens: 1,
 				});
-				vocabulary.id(&out).unwrap()
+				if eot_token_ids.contains(&sampled) {
+					if let Some(trace) = trace {
+						trace.generated(sampled, &vocabulary.token(sampled as usize), TokenChoice::Sampled, allowed_tokens);
+					}
+					eot_token_id = Some(sampled);
+					break FinishReason::Eot;
+				}
+				(sampled, TokenChoice::Sampled)
 			};
 
 			tokens_generated += 1;
+			generated.push(out_token_id);
+			if let Some(trace) = trace {
+				trace.generated(out_token_id, &vocabulary.token(out_token_id as usize), choice, allowed_tokens);
+			}
 
 			// Save to transcript
 			if tracing::enabled!(tracing::Level::DEBUG) {
@@ -344,8 +1293,9 @@ impl BackendSession {
 			}
 
 			// Check for end of text
-			if out_token_id == eot_token {
-				break;
+			if eot_token_ids.contains(&out_token_id) {
+				eot_token_id = Some(out_token_id);
+				break FinishReason::Eot;
 			}
 
 			// Advance biaser
@@ -354,39 +1304,179 @@ impl BackendSession {
 			// Add token to result
 			tracing::trace!("token: {out_token_id}");
 			if let Some(output) = result_buffer.push(&vocabulary.token(out_token_id as usize)) {
-				tracing::trace!("text: {output}");
+				let tag = std::mem::replace(&mut text_start, tokens_generated);
+				let retry = retries < task_config.banned_phrase_retries;
+				match filter_phrases(&mut phrase_filter, &task_config, retry, output, tag, &mut output_text)? {
+					Filtered::Continue => {}
+					Filtered::Finish(FinishReason::StopSequence) if biased => stopped = true,
+					Filtered::Finish(reason) => break reason,
+					Filtered::Retry(index) => {
+						let Some(position) = checkpoints.iter().position(|(i, _)| *i == index) else {
+							tracing::warn!("cannot rewind to retry a banned phrase, as tokens were dropped to make room");
+							break FinishReason::BannedPhrase;
+						};
+						let (_, checkpoint) = checkpoints.drain(position..).next().unwrap();
+						self.rewind_to(&checkpoint)?;
+						retries += 1;
+						tracing::debug!(retries, "rewound {} tokens to retry a banned phrase", tokens_generated - index);
 
-				if let Some(ref mut stop_sequences) = stop_sequences {
-					if stop_sequences.advance(&output) {
-						tracing::debug!("stop because stop sequence encountered");
-						break;
+						banned_at.retain(|position, _| *position <= index);
+						banned_at.entry(index).or_default().push(generated[index]);
+						if tracing::enabled!(tracing::Level::DEBUG) {
+							tokens.truncate(tokens.len().saturating_sub(tokens_generated - index));
+						}
+						if let Some(trace) = trace {
+							trace.rewind(tokens_generated - index);
+						}
+						generated.truncate(index);
+						tokens_generated = index;
+						text_start = index;
+						result_buffer = Utf8Buffer::default();
+						continue;
 					}
 				}
+			}
 
-				if !private_tokens.contains(&output) {
-					// Swallow private tokens
-					match callback(InferenceResponse::InferredToken(output))? {
-						InferenceFeedback::Continue => {}
-						InferenceFeedback::Halt => break,
+			// Stop once we have enough tokens, the time is up or (in biased mode) a stop sequence was generated. In biased mode,
+			// the biaser closes the output first so that it stays valid, using at most a few more tokens.
+			let limit = if stopped {
+				Some(FinishReason::StopSequence)
+			} else if task_config.max_tokens.is_some_and(|max_tokens| tokens_generated >= max_tokens) {
+				Some(FinishReason::MaxTokens)
+			} else if interruption.timed_out() {
+				Some(FinishReason::Timeout)
+			} else {
+				None
+			};
+			if let Some(limit) = limit {
+				let Some(closing) = biaser.closing_tokens(vocabulary).filter(|closing| closing.len() <= MAX_CLOSING_TOKENS) else {
+					tracing::warn!("{limit:?} reached in biased mode, but the output cannot be closed");
+					return Err(BackendError::TokenBudgetExceeded {
+						overflow: MAX_CLOSING_TOKENS,
+					});
+				};
+				if !closing.is_empty() {
+					tracing::info!("{limit:?} reached in biased mode, closing output with {} tokens", closing.len());
+					force_closed = true;
+					self.feed_forced(&closing, &mut usage, tokens_generated)?;
+					if tracing::enabled!(tracing::Level::DEBUG) {
+						tokens.extend_from_slice(&closing);
+					}
+					if let Some(trace) = trace {
+						for token in &closing {
+							trace.generated(*token, &vocabulary.token(*token as usize), TokenChoice::Closing, None);
+						}
+					}
+					let text: String = closing
+						.iter()
+						.filter_map(|t| result_buffer.push(&vocabulary.token(*t as usize)))
+						.collect();
+					if !text.is_empty() {
+						output_text(text)?;
 					}
 				}
+				break limit;
 			}
+		};
 
-			// Stop once we have enough tokens (and not in biased mode, because then the biaser decides when we stop)
-			if self.task_config.biaser.is_none() {
-				if let Some(max_tokens) = self.task_config.max_tokens {
-					if tokens_generated >= max_tokens {
-						break;
-					}
+		generate_span.record("tokens_generated", tokens_generated);
+		drop(generate_guard);
+
+		// When generation ends within a character, the bytes held for it are output as a replacement character (unless
+		// the output was cut off anyway)
+		if matches!(
+			finish_reason,
+			FinishReason::Eot | FinishReason::MaxTokens | FinishReason::ContextFull | FinishReason::Timeout
+		) {
+			if let Some(output) = result_buffer.flush() {
+				tracing::debug!("generation ended within a character");
+				if let Filtered::Finish(reason) = filter_phrases(&mut phrase_filter, &task_config, false, output, text_start, &mut output_text)? {
+					finish_reason = reason;
+				}
+			}
+		}
+
+		// Text withheld because it could have been the start of a banned phrase turned out not to be
+		if matches!(
+			finish_reason,
+			FinishReason::Eot | FinishReason::MaxTokens | FinishReason::ContextFull | FinishReason::Timeout
+		) {
+			for (text, _) in phrase_filter.flush() {
+				if let Some(reason) = output_text(text)? {
+					finish_reason = reason;
+					break;
 				}
 			}
 		}
 
+		// Text withheld because it could have been the start of a stop sequence turned out not to be
+		if !matches!(
+			finish_reason,
+			FinishReason::StopSequence | FinishReason::Cancelled | FinishReason::LengthLimit
+		) {
+			let mut text = output_buffer.take(0);
+			let (fits, _) = output_limit.fit(&text);
+			if fits < text.len() {
+				text.truncate(fits);
+				finish_reason = FinishReason::LengthLimit;
+			}
+			if !text.is_empty() {
+				callback(InferenceResponse::InferredToken(text))?;
+			}
+		}
+
 		if tracing::enabled!(tracing::Level::DEBUG) {
-			let decoded = self.model.tokenizer().decode(tokens, false);
-			let txt = String::from_utf8_lossy(&decoded);
-			tracing::debug!("full transcript (excluding prelude): {txt}");
+			if request.has_redactions() {
+				let decoded = self.model.tokenizer().decode(tokens[n_prompt_tokens..].to_vec(), false);
+				let txt = String::from_utf8_lossy(&decoded);
+				tracing::debug!("transcript (excluding prelude and prompt): {}", redactor.redact(&txt));
+			} else {
+				let decoded = self.model.tokenizer().decode(tokens, false);
+				let txt = String::from_utf8_lossy(&decoded);
+				tracing::debug!("full transcript (excluding prelude): {}", redactor.redact(&txt));
+			}
+		}
+		usage.prompt_tokens = completion_stats.prompt_tokens;
+		usage.sampled_tokens = completion_stats.predict_tokens;
+		let eot_token = eot_token_id.map(|t| String::from_utf8_lossy(&self.model.tokenizer().token(t as usize)).into_owned());
+		if let Some(ref eot_token) = eot_token {
+			tracing::debug!("stop because end-of-text token {eot_token:?} was generated");
+		}
+		if finish_reason != FinishReason::ContextFull {
+			self.close_reply()?;
+		}
+		Ok(Completion {
+			stats: completion_stats,
+			usage,
+			timings,
+			finish_reason,
+			eot_token,
+			force_closed,
+			tool_call: None,
+			validation: None,
+			choice: biaser.choice(),
+			truncated_tokens,
+			duration: started.elapsed(),
+		})
+	}
+
+	/// Feed tokens forced by the biaser to the model, after `tokens_generated` tokens were generated
+	fn feed_forced(&mut self, forced: &[TokenId], usage: &mut TokenUsage, tokens_generated: usize) -> Result<(), BackendError> {
+		if self.task_config.slide_context {
+			self.make_room(forced.len())?;
 		}
-		Ok(completion_stats)
+		let start = Instant::now();
+		let available = self.context_remaining();
+		self.session
+			.feed_prompt(
+				self.model.as_ref().as_ref(),
+				Prompt::Tokens(forced),
+				&mut OutputRequest::default(),
+				|_| -> Result<InferenceFeedback, BackendError> { Ok(InferenceFeedback::Continue) },
+			)
+			.map_err(|e| BackendError::from_inference(e, forced.len(), available, tokens_generated))?;
+		usage.forced_duration += Instant::now().duration_since(start);
+		usage.forced_tokens += forced.len();
+		Ok(())
 	}
 }
@@ -1,7 +1,16 @@
-use std::time::Duration;
+use std::{
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
 
 use llm::InferenceStats;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::session::Completion;
 
 pub trait InferenceStatsAdd {
 	fn add(&mut self, stats: &InferenceStats);
@@ -16,20 +25,164 @@ impl InferenceStatsAdd for InferenceStats {
 	}
 }
 
-#[derive(Serialize, Debug, Clone)]
+/// Schemas of types from other crates, as serde serializes them
+pub mod schema {
+	use utoipa::ToSchema;
+
+	/// A [`std::time::Duration`]
+	#[derive(ToSchema)]
+	#[allow(dead_code)]
+	pub struct Duration {
+		/// Whole seconds
+		secs: u64,
+
+		/// Nanoseconds in addition to the whole seconds
+		nanos: u32,
+	}
+}
+
+/// How long requests of a priority waited before they were serviced
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub struct QueueWaitStats {
+	/// Number of requests that were admitted
+	pub admitted: usize,
+
+	/// Number of admitted requests that had to wait
+	pub queued: usize,
+
+	/// Time spent waiting by all admitted requests together
+	#[schema(value_type = schema::Duration)]
+	pub total_wait: Duration,
+
+	/// Longest time an admitted request waited
+	#[schema(value_type = schema::Duration)]
+	pub max_wait: Duration,
+}
+
+impl QueueWaitStats {
+	/// Count an admitted request that waited for the specified time (`None` when it was admitted immediately)
+	pub fn add(&mut self, wait: Option<Duration>) {
+		self.admitted += 1;
+		if let Some(wait) = wait {
+			self.queued += 1;
+			self.total_wait += wait;
+			self.max_wait = self.max_wait.max(wait);
+		}
+	}
+}
+
+/// Requests of clients that did not authenticate that were rejected for exceeding the rate limit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub struct RateLimitStats {
+	/// Number of requests rejected because the client exceeded the rate limit
+	pub limited: usize,
+
+	/// Number of requests rejected because the client was banned
+	pub rejected_banned: usize,
+
+	/// Number of times a client was banned for repeatedly exceeding the rate limit
+	pub bans: usize,
+
+	/// Number of clients currently banned
+	pub banned: usize,
+}
+
+/// Number of tokens processed for a completion, by where they came from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub struct TokenUsage {
+	/// Tokens fed to the model as input (the prompt with prefix, postfix and recalled memories, and the bias prompt)
+	pub prompt_tokens: usize,
+
+	/// Generated tokens that were sampled from the output of the model, including an end-of-text token that ended
+	/// generation and the tokens generated before the bias prompt is fed (which are not returned)
+	pub sampled_tokens: usize,
+
+	/// Generated tokens that were not sampled because the biaser allowed only a single token. These are fed to the model
+	/// like prompt tokens, which is much faster than sampling, but are not counted as prompt tokens. An end-of-text token
+	/// forced by the biaser is not fed and therefore not counted.
+	pub forced_tokens: usize,
+
+	/// Time spent feeding forced tokens to the model
+	#[schema(value_type = schema::Duration)]
+	pub forced_duration: Duration,
+}
+
+impl TokenUsage {
+	/// Tokens of all kinds together
+	pub fn total(&self) -> usize {
+		self.prompt_tokens + self.sampled_tokens + self.forced_tokens
+	}
+
+	pub fn add(&mut self, usage: &TokenUsage) {
+		self.prompt_tokens += usage.prompt_tokens;
+		self.sampled_tokens += usage.sampled_tokens;
+		self.forced_tokens += usage.forced_tokens;
+		self.forced_duration += usage.forced_duration;
+	}
+}
+
+/// Time spent on the steps of generating tokens (not including the unbiased generation before a bias prompt is fed).
+/// Measuring these takes two calls to [`std::time::Instant::now`] per step.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub struct GenerationTimings {
+	/// Time spent by the biaser determining which tokens may follow
+	#[schema(value_type = schema::Duration)]
+	pub bias_duration: Duration,
+
+	/// Time spent setting up the samplers and sampling tokens from the output of the model
+	#[schema(value_type = schema::Duration)]
+	pub sample_duration: Duration,
+
+	/// Time spent evaluating the model with sampled tokens (forced tokens are not included)
+	#[schema(value_type = schema::Duration)]
+	pub evaluate_duration: Duration,
+}
+
+impl GenerationTimings {
+	pub fn add(&mut self, timings: &GenerationTimings) {
+		self.bias_duration += timings.bias_duration;
+		self.sample_duration += timings.sample_duration;
+		self.evaluate_duration += timings.evaluate_duration;
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct TaskStats {
 	/// Number of completion cycles (`Backend::completion`) that were completed for this model
-	cycles: usize,
+	pub cycles: usize,
 
 	/// Total duration of prediction measured in thread-time
-	predict_duration: Duration,
-	predict_duration_threads: Duration,
-	predict_tokens: usize,
+	#[schema(value_type = schema::Duration)]
+	pub predict_duration: Duration,
+	#[schema(value_type = schema::Duration)]
+	pub predict_duration_threads: Duration,
+	pub predict_tokens: usize,
 
 	/// Total duration of prompt feeding measured in thread-time
-	prompt_duration: Duration,
-	prompt_duration_threads: Duration,
-	prompt_tokens: usize,
+	#[schema(value_type = schema::Duration)]
+	pub prompt_duration: Duration,
+	#[schema(value_type = schema::Duration)]
+	pub prompt_duration_threads: Duration,
+	pub prompt_tokens: usize,
+
+	/// Total duration of feeding tokens forced by the biaser (not included in the prompt or prediction totals)
+	#[schema(value_type = schema::Duration)]
+	pub forced_duration: Duration,
+	pub forced_tokens: usize,
+
+	/// Total time spent on each of the steps of generating tokens
+	#[serde(flatten)]
+	pub timings: GenerationTimings,
+
+	/// Number of threads and batch size used for the most recent cycle
+	pub n_threads: usize,
+	pub n_batch: usize,
+
+	/// Number of sessions that started from the cached state after feeding the prelude
+	pub prelude_cache_hits: usize,
+
+	/// Number of sessions that had to feed the prelude (as it was not cached yet)
+	pub prelude_cache_misses: usize,
 }
 
 impl Default for TaskStats {
@@ -44,12 +197,24 @@ impl Default for TaskStats {
 			prompt_duration: Duration::ZERO,
 			prompt_duration_threads: Duration::ZERO,
 			prompt_tokens: 0,
+
+			forced_duration: Duration::ZERO,
+			forced_tokens: 0,
+
+			timings: GenerationTimings::default(),
+
+			n_threads: 0,
+			n_batch: 0,
+
+			prelude_cache_hits: 0,
+			prelude_cache_misses: 0,
 		}
 	}
 }
 
 impl TaskStats {
-	pub fn add_cycle(&mut self, stats: &InferenceStats, n_threads: usize) {
+	pub fn add_cycle(&mut self, completion: &Completion, n_threads: usize, n_batch: usize) {
+		let Completion { stats, usage, timings, .. } = completion;
 		self.predict_tokens += stats.predict_tokens;
 		self.prompt_tokens += stats.prompt_tokens;
 		self.prompt_duration += stats.feed_prompt_duration;
@@ -57,6 +222,206 @@ impl TaskStats {
 
 		self.predict_duration += stats.predict_duration;
 		self.predict_duration_threads += stats.predict_duration * (n_threads as u32);
+
+		self.forced_tokens += usage.forced_tokens;
+		self.forced_duration += usage.forced_duration;
+		self.timings.bias_duration += timings.bias_duration;
+		self.timings.sample_duration += timings.sample_duration;
+		self.timings.evaluate_duration += timings.evaluate_duration;
 		self.cycles += 1;
+		self.n_threads = n_threads;
+		self.n_batch = n_batch;
+	}
+
+	pub fn add_prelude_cache_lookup(&mut self, hit: bool) {
+		if hit {
+			self.prelude_cache_hits += 1;
+		} else {
+			self.prelude_cache_misses += 1;
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, ToSchema)]
+pub struct ModelStats {
+	/// Generation statistics, summed over all tasks that use this model
+	#[serde(flatten)]
+	pub generation: TaskStats,
+
+	/// Number of embedding calculations performed using this model
+	pub embedding_cycles: usize,
+
+	/// Number of tokens that embeddings were calculated for
+	pub embedding_tokens: usize,
+
+	/// Total duration of embedding calculation
+	#[schema(value_type = schema::Duration)]
+	pub embedding_duration: Duration,
+
+	/// Number of embeddings requested for this model that were found in the embedding cache
+	pub embedding_cache_hits: usize,
+
+	/// Number of embeddings requested for this model that were not found in the embedding cache (and were calculated)
+	pub embedding_cache_misses: usize,
+
+	/// Number of sessions currently using this model
+	pub sessions: usize,
+}
+
+impl ModelStats {
+	pub fn add_cycle(&mut self, completion: &Completion, n_threads: usize, n_batch: usize) {
+		self.generation.add_cycle(completion, n_threads, n_batch);
+	}
+
+	pub fn add_embedding(&mut self, n_tokens: usize, duration: Duration) {
+		self.embedding_cycles += 1;
+		self.embedding_tokens += n_tokens;
+		self.embedding_duration += duration;
+	}
+
+	pub fn add_embedding_cache_lookup(&mut self, hit: bool) {
+		if hit {
+			self.embedding_cache_hits += 1;
+		} else {
+			self.embedding_cache_misses += 1;
+		}
+	}
+
+	pub fn set_sessions(&mut self, sessions: usize) {
+		self.sessions = sessions;
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, ToSchema)]
+pub struct MemoryStats {
+	/// Number of chunks in the memory
+	pub items: usize,
+
+	/// Maximum number of chunks the memory may hold, if limited
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub max_items: Option<usize>,
+
+	/// Number of chunks removed to make room for new chunks since the memory was opened
+	pub evictions: usize,
+}
+
+/// Counter for the number of currently active items of some kind (e.g. sessions or connections)
+#[derive(Debug)]
+pub struct Gauge {
+	name: &'static str,
+	value: AtomicUsize,
+
+	/// When the value exceeds this limit, a warning is logged
+	soft_limit: Option<usize>,
+}
+
+/// Keeps a [Gauge] incremented for as long as it lives. The gauge is decremented when the guard is dropped, which also
+/// happens when a thread holding the guard panics.
+#[derive(Debug)]
+pub struct GaugeGuard {
+	gauge: Arc<Gauge>,
+}
+
+impl Gauge {
+	pub fn new(name: &'static str, soft_limit: Option<usize>) -> Gauge {
+		Gauge {
+			name,
+			value: AtomicUsize::new(0),
+			soft_limit,
+		}
+	}
+
+	/// Increment the gauge. It will be decremented again when the returned guard is dropped.
+	pub fn enter(self: &Arc<Self>) -> GaugeGuard {
+		let value = self.value.fetch_add(1, Ordering::SeqCst) + 1;
+		if let Some(soft_limit) = self.soft_limit {
+			if value > soft_limit {
+				tracing::warn!(gauge = self.name, value, soft_limit, "number of active items exceeds soft limit");
+			}
+		}
+		GaugeGuard { gauge: self.clone() }
+	}
+
+	/// The current value of the gauge
+	pub fn get(&self) -> usize {
+		self.value.load(Ordering::SeqCst)
+	}
+}
+
+impl Drop for GaugeGuard {
+	fn drop(&mut self) {
+		self.gauge.value.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{sync::Arc, time::Duration};
+
+	use llm::InferenceStats;
+
+	use super::{Gauge, GenerationTimings, TaskStats, TokenUsage};
+	use crate::{session::Completion, types::FinishReason};
+
+	#[test]
+	fn test_task_stats() {
+		let completion = Completion {
+			stats: InferenceStats {
+				feed_prompt_duration: Duration::from_millis(20),
+				prompt_tokens: 10,
+				predict_duration: Duration::from_millis(50),
+				predict_tokens: 5,
+			},
+			usage: TokenUsage {
+				prompt_tokens: 10,
+				sampled_tokens: 5,
+				forced_tokens: 15,
+				forced_duration: Duration::from_millis(30),
+			},
+			timings: GenerationTimings {
+				bias_duration: Duration::from_millis(40),
+				sample_duration: Duration::from_millis(10),
+				evaluate_duration: Duration::from_millis(40),
+			},
+			duration: Duration::from_millis(200),
+			..Completion::new(FinishReason::Eot)
+		};
+		let mut stats = TaskStats::default();
+		stats.add_cycle(&completion, 4, 8);
+		stats.add_cycle(&completion, 4, 8);
+
+		let stats = serde_json::to_value(&stats).unwrap();
+		assert_eq!(stats["cycles"], 2);
+		assert_eq!(stats["prompt_tokens"], 20);
+		assert_eq!(stats["predict_tokens"], 10);
+		assert_eq!(stats["forced_tokens"], 30);
+		assert_eq!(stats["forced_duration"], serde_json::to_value(Duration::from_millis(60)).unwrap());
+		assert_eq!(stats["bias_duration"], serde_json::to_value(Duration::from_millis(80)).unwrap());
+		assert_eq!(stats["evaluate_duration"], serde_json::to_value(Duration::from_millis(80)).unwrap());
+	}
+
+	#[test]
+	fn test_gauge() {
+		let gauge = Arc::new(Gauge::new("test", Some(2)));
+		let guards: Vec<_> = (0..5).map(|_| gauge.enter()).collect();
+		assert_eq!(gauge.get(), 5);
+		drop(guards);
+		assert_eq!(gauge.get(), 0);
+
+		// Guards held by threads that panic must still decrement the gauge
+		let threads: Vec<_> = (0..4)
+			.map(|i| {
+				let gauge = gauge.clone();
+				std::thread::spawn(move || {
+					let _guard = gauge.enter();
+					if i % 2 == 0 {
+						panic!("generation thread panicked");
+					}
+				})
+			})
+			.collect();
+		let n_panicked = threads.into_iter().map(|t| t.join()).filter(|r| r.is_err()).count();
+		assert_eq!(n_panicked, 2);
+		assert_eq!(gauge.get(), 0);
 	}
 }
@@ -0,0 +1,319 @@
+//! Prompt templates (see [`crate::config::TaskConfig::prompt_template`]). A template consists of literal text and
+//! variables between braces (e.g. `{language}`); literal braces are written as `{{` and `}}`. The variable `{input}` is
+//! bound to the prompt of the request, all other variables to the `variables` of the request. For tasks with a chat
+//! template (see [`crate::config::TaskConfig::chat_template`]), the prompt is rendered as a chat first.
+
+use std::mem::take;
+
+use crate::{
+	config::{ChatTemplate, TaskConfig},
+	types::{BackendError, ChatRole, PromptRequest, PromptSegment},
+};
+
+/// The variable that is bound to the prompt of the request
+pub const INPUT_VARIABLE: &str = "input";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+	Text(String),
+	Variable(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PromptTemplate {
+	parts: Vec<Part>,
+}
+
+impl PromptTemplate {
+	pub fn parse(template: &str) -> Result<PromptTemplate, String> {
+		let mut parts = vec![];
+		let mut text = String::new();
+		let mut chars = template.chars().peekable();
+		while let Some(c) = chars.next() {
+			match c {
+				'{' | '}' if chars.peek() == Some(&c) => {
+					chars.next();
+					text.push(c);
+				}
+				'{' => {
+					let mut name = String::new();
+					loop {
+						match chars.next() {
+							Some('}') => break,
+							Some(c) if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+							Some(c) => return Err(format!("invalid character '{c}' in variable name (use '{{{{' for a literal brace)")),
+							None => return Err(String::from("unclosed '{' (use '{{' for a literal brace)")),
+						}
+					}
+					if name.is_empty() {
+						return Err(String::from("empty variable name"));
+					}
+					if !text.is_empty() {
+						parts.push(Part::Text(take(&mut text)));
+					}
+					parts.push(Part::Variable(name));
+				}
+				'}' => return Err(String::from("unmatched '}' (use '}}' for a literal brace)")),
+				c => text.push(c),
+			}
+		}
+		if !text.is_empty() {
+			parts.push(Part::Text(text));
+		}
+		Ok(PromptTemplate { parts })
+	}
+
+	/// Names of the variables in the template in order of first use, including [`INPUT_VARIABLE`]
+	pub fn variables(&self) -> Vec<&str> {
+		let mut variables: Vec<&str> = vec![];
+		for part in &self.parts {
+			if let Part::Variable(name) = part {
+				if !variables.contains(&name.as_str()) {
+					variables.push(name);
+				}
+			}
+		}
+		variables
+	}
+
+	/// Render the template for a request. Literal text of the template is trusted, as it comes from the configuration;
+	/// the values of variables are not. `{input}` is replaced by `input` (the segments of the prompt of the request).
+	pub fn render(&self, request: &PromptRequest, input: &[PromptSegment], max_variable_chars: usize) -> Result<Vec<PromptSegment>, BackendError> {
+		let used = self.variables();
+		let missing: Vec<String> = used
+			.iter()
+			.filter(|name| **name != INPUT_VARIABLE && !request.variables.contains_key(**name))
+			.map(|name| name.to_string())
+			.collect();
+		let mut unknown: Vec<String> = request
+			.variables
+			.keys()
+			.filter(|name| *name == INPUT_VARIABLE || !used.contains(&name.as_str()))
+			.cloned()
+			.collect();
+		unknown.sort();
+		let mut too_long: Vec<String> = request
+			.variables
+			.iter()
+			.filter(|(name, value)| used.contains(&name.as_str()) && value.chars().count() > max_variable_chars)
+			.map(|(name, _)| name.clone())
+			.collect();
+		too_long.sort();
+		if !missing.is_empty() || !unknown.is_empty() || !too_long.is_empty() {
+			return Err(BackendError::InvalidVariables { missing, unknown, too_long });
+		}
+
+		let mut segments = vec![];
+		for part in &self.parts {
+			match part {
+				Part::Text(text) => segments.push(PromptSegment {
+					text: text.clone(),
+					trusted: true,
+					..Default::default()
+				}),
+				Part::Variable(name) if name == INPUT_VARIABLE => segments.extend(input.iter().cloned()),
+				Part::Variable(name) => segments.push(PromptSegment {
+					text: request.variables[name].clone(),
+					..Default::default()
+				}),
+			}
+		}
+		Ok(segments)
+	}
+}
+
+/// Render the messages of a chat (each given as the segments of its content) between the prefix and suffix for their
+/// roles, followed by the assistant prefix. The text of the template is trusted.
+pub(crate) fn render_chat(template: &ChatTemplate, messages: impl IntoIterator<Item = (ChatRole, Vec<PromptSegment>)>) -> Vec<PromptSegment> {
+	let trusted = |text: &str| PromptSegment {
+		text: text.to_string(),
+		trusted: true,
+		..Default::default()
+	};
+	let mut segments = vec![];
+	for (role, content) in messages {
+		let (prefix, suffix) = template.affixes(role);
+		segments.push(trusted(prefix));
+		segments.extend(content);
+		segments.push(trusted(suffix));
+	}
+	segments.push(trusted(&template.assistant_prefix));
+	segments.retain(|s| !s.text.is_empty());
+	segments
+}
+
+/// The segments of the prompt of a request. For tasks with a chat template, these are the messages of the request (or
+/// else its prompt as a user message) rendered as a chat; other tasks do not accept messages.
+fn input(task_config: &TaskConfig, request: &PromptRequest) -> Result<Vec<PromptSegment>, BackendError> {
+	match task_config.chat_template {
+		Some(ref template) if request.messages.is_empty() => Ok(render_chat(template, [(ChatRole::User, request.segments().into_owned())])),
+		Some(ref template) => {
+			// Only messages of the user are stored in memory
+			let messages = request.messages.iter().map(|message| {
+				let content = PromptSegment {
+					text: message.content.clone(),
+					memorize: message.role == ChatRole::User,
+					..Default::default()
+				};
+				(message.role, vec![content])
+			});
+			Ok(render_chat(template, messages))
+		}
+		None if !request.messages.is_empty() => Err(BackendError::InvalidParameter(
+			String::from("messages"),
+			String::from("the task has no chat template"),
+		)),
+		None => Ok(request.segments().into_owned()),
+	}
+}
+
+/// The segments of the prompt of a request, rendered using the chat template and the prompt template of the task (if it
+/// has them). Requests for tasks without a prompt template cannot have variables.
+pub(crate) fn render(task_config: &TaskConfig, request: &PromptRequest) -> Result<Vec<PromptSegment>, BackendError> {
+	let input = input(task_config, request)?;
+	match task_config.prompt_template {
+		Some(ref template) => {
+			PromptTemplate::parse(template)
+				.map_err(BackendError::InvalidPromptTemplate)?
+				.render(request, &input, task_config.max_variable_chars)
+		}
+		None if !request.variables.is_empty() => {
+			let mut unknown: Vec<String> = request.variables.keys().cloned().collect();
+			unknown.sort();
+			Err(BackendError::InvalidVariables {
+				missing: vec![],
+				unknown,
+				too_long: vec![],
+			})
+		}
+		None => Ok(input),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+
+	use super::{render, PromptTemplate};
+	use crate::{
+		config::TaskConfig,
+		types::{BackendError, ChatMessage, ChatRole, PromptRequest, PromptSegment},
+	};
+
+	fn request(prompt: &str, variables: &[(&str, &str)]) -> PromptRequest {
+		PromptRequest {
+			variables: variables.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>(),
+			..PromptRequest::new(prompt)
+		}
+	}
+
+	#[test]
+	fn test_parse() {
+		let template = PromptTemplate::parse("Summarize this {language} text for a {audience}:\n{input}\n{{not a variable}}").unwrap();
+		assert_eq!(template.variables(), vec!["language", "audience", "input"]);
+		assert_eq!(PromptTemplate::parse("{input}{input}").unwrap().variables(), vec!["input"]);
+
+		for invalid in ["{input", "input}", "{}", "{in put}", "{{input}"] {
+			assert!(PromptTemplate::parse(invalid).is_err(), "{invalid}");
+		}
+	}
+
+	#[test]
+	fn test_render() {
+		let template = PromptTemplate::parse("Translate to {language} {{formal}}:\n{input}").unwrap();
+		let render = |request: &PromptRequest| template.render(request, &request.segments(), 10);
+		let segments = render(&request("Hello", &[("language", "Dutch")])).unwrap();
+		assert_eq!(
+			segments,
+			vec![
+				PromptSegment {
+					text: String::from("Translate to "),
+					trusted: true,
+					..Default::default()
+				},
+				PromptSegment {
+					text: String::from("Dutch"),
+					..Default::default()
+				},
+				PromptSegment {
+					text: String::from(" {formal}:\n"),
+					trusted: true,
+					..Default::default()
+				},
+				PromptSegment {
+					text: String::from("Hello"),
+					memorize: true,
+					..Default::default()
+				},
+			]
+		);
+
+		match render(&request("Hello", &[("input", "Hi"), ("tone", "polite")])) {
+			Err(BackendError::InvalidVariables { missing, unknown, too_long }) => {
+				assert_eq!(missing, vec!["language"]);
+				assert_eq!(unknown, vec!["input", "tone"]);
+				assert!(too_long.is_empty());
+			}
+			r => panic!("unexpected result {r:?}"),
+		}
+
+		match render(&request("Hello", &[("language", "Old High German")])) {
+			Err(BackendError::InvalidVariables { too_long, .. }) => assert_eq!(too_long, vec!["language"]),
+			r => panic!("unexpected result {r:?}"),
+		}
+	}
+
+	#[test]
+	fn test_render_chat() {
+		let task_config: TaskConfig = toml::from_str(
+			r#"
+			model = "tiny"
+			prompt_template = "Conversation:\n{input}"
+
+			[chat_template]
+			system_prefix = "["
+			system_suffix = "]\n"
+			user_prefix = "User: "
+			user_suffix = "\n"
+			assistant_prefix = "Assistant:"
+			assistant_suffix = "\n"
+			"#,
+		)
+		.unwrap();
+		let message = |role, content: &str| ChatMessage {
+			role,
+			content: content.to_string(),
+		};
+		let request = PromptRequest {
+			messages: vec![
+				message(ChatRole::System, "Be brief."),
+				message(ChatRole::User, "Hi"),
+				message(ChatRole::Assistant, " Hello!"),
+				message(ChatRole::User, "Bye"),
+			],
+			..PromptRequest::new("ignored")
+		};
+		let segments = render(&task_config, &request).unwrap();
+		let text: String = segments.iter().map(|s| s.text.as_str()).collect();
+		assert_eq!(text, "Conversation:\n[Be brief.]\nUser: Hi\nAssistant: Hello!\nUser: Bye\nAssistant:");
+
+		// The template is trusted, the messages are not; only messages of the user are stored in memory
+		for segment in &segments {
+			let is_message = ["Be brief.", "Hi", " Hello!", "Bye"].contains(&segment.text.as_str());
+			assert_eq!(segment.trusted, !is_message, "{segment:?}");
+			assert_eq!(segment.memorize, segment.text == "Hi" || segment.text == "Bye", "{segment:?}");
+		}
+
+		// Without messages, the prompt is the message of the user
+		let segments = render(&task_config, &PromptRequest::new("Hi")).unwrap();
+		let text: String = segments.iter().map(|s| s.text.as_str()).collect();
+		assert_eq!(text, "Conversation:\nUser: Hi\nAssistant:");
+
+		// Tasks without a chat template do not accept messages
+		let task_config: TaskConfig = toml::from_str("model = \"tiny\"").unwrap();
+		assert!(matches!(
+			render(&task_config, &request),
+			Err(BackendError::InvalidParameter(parameter, _)) if parameter == "messages"
+		));
+	}
+}
@@ -0,0 +1,171 @@
+//! Tool calling: the output of a task with tools is constrained to a JSON object that either calls one of the tools or
+//! holds an answer (see [`crate::config::TaskConfig::tools`])
+
+use std::{borrow::Cow, collections::HashMap};
+
+use poly_bias::json::JsonSchema;
+use serde_json::Value;
+
+use crate::{
+	config::{TaskConfig, ToolConfig},
+	template,
+	types::{BackendError, PromptRequest, PromptSegment, ToolCall, ToolResult},
+};
+
+/// What the model of a task with tools responded with
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ToolOutput {
+	Call(ToolCall),
+	Answer(String),
+}
+
+/// Schema of the output of a task with tools: for each tool, an object holding its name as `tool` and its arguments as
+/// `arguments`, or else an object holding the response as `answer`
+pub(crate) fn output_schema(tools: &[ToolConfig]) -> JsonSchema {
+	let string = |values: Option<Vec<String>>| {
+		Box::new(JsonSchema::String {
+			min_length: None,
+			max_length: None,
+			r#enum: values,
+			pattern: None,
+		})
+	};
+	let mut one_of: Vec<JsonSchema> = tools
+		.iter()
+		.map(|tool| JsonSchema::Object {
+			required: vec![String::from("tool"), String::from("arguments")],
+			properties: HashMap::from([
+				(String::from("tool"), string(Some(vec![tool.name.clone()]))),
+				(String::from("arguments"), Box::new(tool.arguments.clone())),
+			]),
+		})
+		.collect();
+	one_of.push(JsonSchema::Object {
+		required: vec![String::from("answer")],
+		properties: HashMap::from([(String::from("answer"), string(None))]),
+	});
+	JsonSchema::OneOf { one_of }
+}
+
+/// The text to start each session of a task with: the list of tools (when the task has any), followed by the prelude
+pub(crate) fn prelude(task_config: &TaskConfig) -> Option<Cow<str>> {
+	if task_config.tools.is_empty() {
+		return task_config.prelude.as_deref().map(Cow::Borrowed);
+	}
+
+	let tools: Vec<String> = task_config
+		.tools
+		.iter()
+		.map(|tool| {
+			let arguments = serde_json::to_string(&tool.arguments).unwrap_or_default();
+			format!("- {}: {} Arguments: {arguments}", tool.name, tool.description)
+		})
+		.collect();
+	let list = task_config.tool_templates.list.replace("{tools}", &tools.join("\n"));
+	Some(Cow::Owned(format!("{list}{}", task_config.prelude.as_deref().unwrap_or_default())))
+}
+
+/// The prompt segment that passes the result of a tool call to the model
+pub(crate) fn result_segment(task_config: &TaskConfig, tool_result: &ToolResult) -> PromptSegment {
+	// The result is inserted last, so that placeholders in it are not replaced
+	PromptSegment {
+		text: task_config
+			.tool_templates
+			.result
+			.replace("{tool}", &tool_result.tool)
+			.replace("{result}", &tool_result.result),
+		..Default::default()
+	}
+}
+
+/// The segments of a prompt (rendered using the prompt template of the task, if any), followed by the result of a tool
+/// call when the prompt holds one
+pub(crate) fn prompt_segments(task_config: &TaskConfig, request: &PromptRequest) -> Result<Vec<PromptSegment>, BackendError> {
+	let mut segments = template::render(task_config, request)?;
+	if let Some(ref tool_result) = request.tool_result {
+		segments.push(result_segment(task_config, tool_result));
+	}
+	Ok(segments)
+}
+
+/// Interpret the output of a task with tools. Returns None when it is neither a tool call nor an answer (e.g. because
+/// generation was halted before the output was complete).
+pub(crate) fn parse_output(output: &str) -> Option<ToolOutput> {
+	let Ok(Value::Object(mut object)) = serde_json::from_str(output) else {
+		return None;
+	};
+	match (object.remove("tool"), object.remove("arguments"), object.remove("answer")) {
+		(Some(Value::String(tool)), Some(arguments), None) => Some(ToolOutput::Call(ToolCall { tool, arguments })),
+		(None, None, Some(Value::String(answer))) => Some(ToolOutput::Answer(answer)),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use serde_json::json;
+
+	use super::{output_schema, parse_output, prelude, result_segment, ToolOutput};
+	use crate::{
+		config::TaskConfig,
+		types::{ToolCall, ToolResult},
+	};
+
+	fn task_config() -> TaskConfig {
+		toml::from_str(
+			r#"
+			model = "tiny"
+			prelude = "Be helpful.\n"
+			tool_templates = { list = "Tools:\n{tools}\n", result = "{tool} says: {result}" }
+
+			[[tools]]
+			name = "weather"
+			description = "Looks up the weather in a city."
+			arguments = { type = "object", required = ["city"], properties = { city = { type = "string" } } }
+			"#,
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn test_output_schema() {
+		let schema = output_schema(&task_config().tools);
+		assert!(schema.is_valid(&json!({"tool": "weather", "arguments": {"city": "Utrecht"}})));
+		assert!(schema.is_valid(&json!({"answer": "It is sunny"})));
+		assert!(!schema.is_valid(&json!({"tool": "weather", "arguments": {"town": "Utrecht"}})));
+		assert!(!schema.is_valid(&json!({"tool": "weather", "arguments": {"city": "Utrecht"}, "answer": "Sunny"})));
+	}
+
+	#[test]
+	fn test_prompts() {
+		let task_config = task_config();
+		assert_eq!(
+			prelude(&task_config).unwrap(),
+			"Tools:\n- weather: Looks up the weather in a city. Arguments: {\"type\":\"object\",\"required\":[\"city\"],\"properties\":{\"city\":{\"type\":\"string\",\"max_length\":null,\"enum\":null}}}\nBe helpful.\n"
+		);
+
+		let segment = result_segment(
+			&task_config,
+			&ToolResult {
+				tool: String::from("weather"),
+				result: String::from("sunny, {tool}"),
+			},
+		);
+		assert_eq!(segment.text, "weather says: sunny, {tool}");
+		assert!(!segment.trusted && !segment.memorize);
+	}
+
+	#[test]
+	fn test_parse_output() {
+		assert_eq!(
+			parse_output(r#"{"tool":"weather","arguments":{"city":"Utrecht"}}"#),
+			Some(ToolOutput::Call(ToolCall {
+				tool: String::from("weather"),
+				arguments: json!({"city": "Utrecht"}),
+			}))
+		);
+		assert_eq!(parse_output(r#"{"answer":"Sunny"}"#), Some(ToolOutput::Answer(String::from("Sunny"))));
+		assert_eq!(parse_output(r#"{"answer":"Sun"#), None);
+		assert_eq!(parse_output(r#"{"tool":"weather"}"#), None);
+	}
+}
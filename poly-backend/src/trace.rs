@@ -0,0 +1,365 @@
+//! Records of how completions were performed, for offline analysis of model behaviour. For tasks that have a
+//! [`crate::config::TaskConfig::trace_dir`], a [`CompletionTrace`] is written for each completion as a single line of
+//! JSON to a file in that directory. A new file is started each day (e.g. `summarize-2026-10-15.jsonl`). The files can
+//! be read back using [`read_traces`].
+//!
+//! Private tokens of the task are replaced by [`REDACTED`] in the text of traced tokens. The prompt tokens of requests
+//! with segments that must not be logged are left out entirely.
+
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, BufRead, BufReader, Write},
+	path::{Path, PathBuf},
+	sync::mpsc,
+	thread,
+	time::Instant,
+};
+
+use llm::TokenId;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	config::{SamplerConfig, TaskConfig},
+	datetime::format_iso_date,
+	memory::now_millis,
+	redact::{Redactor, REDACTED},
+	session::Completion,
+	stats::TokenUsage,
+	types::{BackendError, FinishReason},
+};
+
+/// Everything that happened during a single completion
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletionTrace {
+	/// Random identifier of the completion
+	pub id: String,
+	pub task: String,
+	pub model: String,
+
+	/// When the completion started, in milliseconds since the Unix epoch
+	pub started_at: u64,
+
+	/// How long the completion took, in microseconds
+	pub duration_us: u64,
+
+	/// The parameters in effect for the completion (after applying the overrides of the request). Not set when the
+	/// completion failed before they were determined.
+	pub parameters: Option<TraceParameters>,
+
+	/// Text recalled from the memory of the task and fed before the prompt
+	pub recalled: Option<String>,
+
+	/// Whether the prompt tokens were left out because the request contained segments that must not be logged
+	pub prompt_redacted: bool,
+
+	/// Tokens fed to the model: the prompt (with prefix, postfix and recalled text) and the bias prompt
+	pub prompt: Vec<TracedToken>,
+
+	/// Tokens generated by the model, including an end-of-text token that ended generation. Tokens discarded to retry a
+	/// banned phrase are not included.
+	pub generated: Vec<TracedToken>,
+
+	/// Number of times generation was rewound to retry a banned phrase
+	pub retries: usize,
+
+	/// Why the completion ended (not set when it failed)
+	pub finish_reason: Option<FinishReason>,
+
+	/// Number of tokens processed (not set when the completion failed)
+	pub usage: Option<TokenUsage>,
+
+	/// Why the completion failed
+	pub error: Option<String>,
+}
+
+/// Parameters of a completion that determine its output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraceParameters {
+	#[serde(flatten)]
+	pub sampler: SamplerConfig,
+
+	/// The seed of the random number generator used for sampling. When the task does not configure a seed, this is the
+	/// random seed that was chosen for the completion, so that it can be reproduced.
+	pub seed: u64,
+	pub max_tokens: Option<usize>,
+	pub max_chars: Option<usize>,
+	pub max_lines: Option<usize>,
+
+	/// Whether the output was constrained by a biaser
+	pub biased: bool,
+}
+
+/// A token fed to or generated by the model
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TracedToken {
+	pub id: TokenId,
+
+	/// Text of the token (lossily decoded, as a token can hold part of a character)
+	pub text: String,
+
+	/// When the token was generated or fed to the model, in microseconds since the start of the completion. Prompt tokens
+	/// are fed in chunks and all have the time at which their chunk was fed.
+	pub at_us: u64,
+
+	/// How a generated token was chosen (not set for prompt tokens)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub choice: Option<TokenChoice>,
+
+	/// Number of tokens the biaser allowed at this position (only set for tokens sampled in biased mode)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub allowed_tokens: Option<usize>,
+}
+
+/// How a generated token was chosen
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenChoice {
+	/// Generated freely before the bias prompt was fed (these tokens are not returned)
+	Unbiased,
+
+	/// Sampled from the output of the model
+	Sampled,
+
+	/// The only token the biaser allowed
+	Forced,
+
+	/// Added by the biaser to close the output when generation was cut off
+	Closing,
+}
+
+/// Collects a [`CompletionTrace`] while a completion is performed
+pub(crate) struct TraceRecorder {
+	trace: CompletionTrace,
+	started: Instant,
+	redactor: Redactor,
+	private_token_ids: Vec<TokenId>,
+}
+
+impl TraceRecorder {
+	pub fn new(task: &str, model: &str, redactor: Redactor, private_token_ids: Vec<TokenId>) -> TraceRecorder {
+		TraceRecorder {
+			trace: CompletionTrace {
+				id: format!("{:032x}", rand::random::<u128>()),
+				task: task.to_string(),
+				model: model.to_string(),
+				started_at: now_millis(),
+				duration_us: 0,
+				parameters: None,
+				recalled: None,
+				prompt_redacted: false,
+				prompt: vec![],
+				generated: vec![],
+				retries: 0,
+				finish_reason: None,
+				usage: None,
+				error: None,
+			},
+			started: Instant::now(),
+			redactor,
+			private_token_ids,
+		}
+	}
+
+	fn elapsed_us(&self) -> u64 {
+		self.started.elapsed().as_micros() as u64
+	}
+
+	fn token(&self, id: TokenId, bytes: &[u8], choice: Option<TokenChoice>, allowed_tokens: Option<usize>) -> TracedToken {
+		let text = if self.private_token_ids.contains(&id) {
+			String::from(REDACTED)
+		} else {
+			self.redactor.redact(&String::from_utf8_lossy(bytes)).into_owned()
+		};
+		TracedToken {
+			id,
+			text,
+			at_us: self.elapsed_us(),
+			choice,
+			allowed_tokens,
+		}
+	}
+
+	pub fn parameters(&mut self, task_config: &TaskConfig, seed: u64, biased: bool) {
+		self.trace.parameters = Some(TraceParameters {
+			sampler: task_config.sampler.clone(),
+			seed,
+			max_tokens: task_config.max_tokens,
+			max_chars: task_config.max_chars,
+			max_lines: task_config.max_lines,
+			biased,
+		});
+	}
+
+	pub fn recalled(&mut self, recalled: Option<&str>) {
+		self.trace.recalled = recalled.map(|text| self.redactor.redact(text).into_owned());
+	}
+
+	/// Record tokens that were fed to the model, unless the prompt must not be logged
+	pub fn fed(&mut self, tokens: &[TokenId], token_text: impl Fn(TokenId) -> Vec<u8>) {
+		if self.trace.prompt_redacted {
+			return;
+		}
+		for id in tokens {
+			let token = self.token(*id, &token_text(*id), None, None);
+			self.trace.prompt.push(token);
+		}
+	}
+
+	/// Leave the tokens of the prompt out of the trace
+	pub fn redact_prompt(&mut self) {
+		self.trace.prompt_redacted = true;
+		self.trace.prompt.clear();
+	}
+
+	pub fn generated(&mut self, id: TokenId, bytes: &[u8], choice: TokenChoice, allowed_tokens: Option<usize>) {
+		let token = self.token(id, bytes, Some(choice), allowed_tokens);
+		self.trace.generated.push(token);
+	}
+
+	/// Remove the last `n` generated tokens, which were discarded to retry a banned phrase
+	pub fn rewind(&mut self, n: usize) {
+		let generated = &mut self.trace.generated;
+		generated.truncate(generated.len().saturating_sub(n));
+		self.trace.retries += 1;
+	}
+
+	pub fn finish(mut self, result: &Result<Completion, BackendError>) -> CompletionTrace {
+		self.trace.duration_us = self.elapsed_us();
+		match result {
+			Ok(completion) => {
+				self.trace.finish_reason = Some(completion.finish_reason);
+				self.trace.usage = Some(completion.usage);
+			}
+			Err(e) => self.trace.error = Some(self.redactor.redact(&e.to_string()).into_owned()),
+		}
+		self.trace
+	}
+}
+
+/// Appends traces to the trace files on a background thread, so that writing them does not hold up completions
+pub(crate) struct TraceWriter {
+	sender: mpsc::Sender<(PathBuf, CompletionTrace)>,
+}
+
+impl TraceWriter {
+	pub fn new() -> TraceWriter {
+		let (sender, receiver) = mpsc::channel::<(PathBuf, CompletionTrace)>();
+		thread::Builder::new()
+			.name(String::from("trace-writer"))
+			.spawn(move || {
+				// Ends when the writer is dropped
+				for (dir, trace) in receiver {
+					if let Err(e) = append(&dir, &trace) {
+						tracing::error!(dir = %dir.display(), "could not write completion trace: {e}");
+					}
+				}
+			})
+			.expect("spawn trace writer thread");
+		TraceWriter { sender }
+	}
+
+	/// Append a trace to the current trace file of its task in the specified directory
+	pub fn write(&self, dir: PathBuf, trace: CompletionTrace) {
+		if self.sender.send((dir, trace)).is_err() {
+			tracing::error!("trace writer is not running");
+		}
+	}
+}
+
+/// Name of the file in which the traces of a task started at the specified time are collected
+fn trace_file_name(task: &str, started_at: u64) -> String {
+	let task: String = task
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+		.collect();
+	format!("{task}-{}.jsonl", format_iso_date(started_at))
+}
+
+fn append(dir: &Path, trace: &CompletionTrace) -> io::Result<()> {
+	fs::create_dir_all(dir)?;
+	let mut line = serde_json::to_vec(trace)?;
+	line.push(b'\n');
+	OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(dir.join(trace_file_name(&trace.task, trace.started_at)))?
+		.write_all(&line)
+}
+
+/// Read all traces from a trace file
+pub fn read_traces(path: &Path) -> io::Result<Vec<CompletionTrace>> {
+	let mut traces = vec![];
+	for line in BufReader::new(File::open(path)?).lines() {
+		let line = line?;
+		if !line.trim().is_empty() {
+			traces.push(serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+		}
+	}
+	Ok(traces)
+}
+
+#[cfg(test)]
+mod test {
+	use crate::{
+		config::TaskConfig,
+		redact::{Redactor, REDACTED},
+		types::BackendError,
+	};
+
+	use super::{append, read_traces, trace_file_name, TokenChoice, TraceParameters, TraceRecorder};
+
+	#[test]
+	fn test_recorder_redacts_private_tokens() {
+		let mut recorder = TraceRecorder::new("chat", "model", Redactor::new(["<|im_end|>"]), vec![2]);
+		recorder.fed(&[1, 2], |id| if id == 2 { b"<|im_end|>".to_vec() } else { b"Hello".to_vec() });
+		recorder.generated(2, b"<|im_end|>", TokenChoice::Sampled, None);
+		recorder.recalled(Some("Earlier<|im_end|>"));
+		let trace = recorder.finish(&Err(BackendError::InferenceFailed {
+			after_tokens: 1,
+			source: "unexpected <|im_end|>".into(),
+		}));
+
+		assert_eq!(trace.prompt.iter().map(|t| t.text.as_str()).collect::<Vec<_>>(), vec!["Hello", REDACTED]);
+		assert_eq!(trace.generated[0].text, REDACTED);
+		assert_eq!(trace.recalled.as_deref(), Some("Earlier<redacted>"));
+		assert!(!trace.error.unwrap().contains("<|im_end|>"));
+
+		let mut recorder = TraceRecorder::new("chat", "model", Redactor::default(), vec![]);
+		recorder.redact_prompt();
+		recorder.fed(&[1], |_| b"Secret".to_vec());
+		assert!(recorder.finish(&Err(BackendError::TaskNotFound(String::from("chat")))).prompt.is_empty());
+	}
+
+	#[test]
+	fn test_write_and_read_traces() {
+		let dir = std::env::temp_dir().join(format!("poly-backend-traces-{}", std::process::id()));
+		let mut recorder = TraceRecorder::new("summarize/short", "model", Redactor::default(), vec![]);
+		let task_config: TaskConfig = toml::from_str("model = \"tiny\"\nmax_tokens = 10").unwrap();
+		recorder.parameters(&task_config, 42, false);
+		recorder.fed(&[1, 2], |_| b"a".to_vec());
+		recorder.generated(3, b"b", TokenChoice::Sampled, None);
+		recorder.generated(4, b"c", TokenChoice::Sampled, None);
+		recorder.rewind(1);
+		let trace = recorder.finish(&Err(BackendError::TaskNotFound(String::from("summarize/short"))));
+		assert_eq!(trace.generated.len(), 1);
+		assert_eq!(trace.retries, 1);
+		assert_eq!(
+			trace.parameters,
+			Some(TraceParameters {
+				sampler: task_config.sampler.clone(),
+				seed: 42,
+				max_tokens: Some(10),
+				max_chars: None,
+				max_lines: None,
+				biased: false,
+			})
+		);
+
+		append(&dir, &trace).unwrap();
+		append(&dir, &trace).unwrap();
+		let file_name = trace_file_name(&trace.task, trace.started_at);
+		assert!(file_name.starts_with("summarize_short-"));
+		assert_eq!(read_traces(&dir.join(file_name)).unwrap(), vec![trace.clone(), trace]);
+		std::fs::remove_dir_all(dir).unwrap();
+	}
+}
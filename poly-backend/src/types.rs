@@ -1,20 +1,256 @@
 use llm::{InferenceError, InferenceParameters, TokenId, TokenizationError};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+	ops::RangeInclusive,
+	str::FromStr,
+	sync::{Arc, Mutex},
+};
 use thiserror::Error;
+use utoipa::{IntoParams, ToSchema};
 
-use crate::{config::TaskConfig, memory::MemoryError};
+use crate::{
+	config::{SamplerConfig, TaskConfig},
+	memory::{MemoryError, MemoryQuery},
+	redact::REDACTED,
+	stats::{MemoryStats, ModelStats, QueueWaitStats, RateLimitStats, TaskStats, TokenUsage},
+};
 
-#[derive(Deserialize, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 #[serde(default)]
-pub struct SessionRequest {}
+pub struct SessionRequest {
+	/// Text to prefix each user input with instead of the prefix configured for the task. Not accepted from API
+	/// clients, as the prefix may contain private tokens.
+	#[serde(skip)]
+	pub prefix: Option<String>,
+}
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoParams, ToSchema)]
+#[into_params(parameter_in = Query)]
 pub struct PromptRequest {
+	/// The prompt (ignored when segments are given)
+	#[serde(default)]
 	pub prompt: String,
+
+	/// The prompt, composed of segments from different sources that are concatenated in order. When empty, the prompt
+	/// consists of a single untrusted segment holding `prompt`, which is stored in memory (when the task does so).
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub segments: Vec<PromptSegment>,
+
+	/// Messages of a chat to reply to, rendered using the chat template of the task (the prompt and segments are ignored
+	/// when messages are given). The content of the messages is untrusted.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub messages: Vec<ChatMessage>,
+
+	/// Values for the variables of the prompt template of the task (except `input`, which is bound to the prompt). All
+	/// variables of the template must be given, and no others.
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	#[param(value_type = Option<Object>)]
+	pub variables: HashMap<String, String>,
+
+	/// Temperature to sample with instead of the one configured for the task
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub temperature: Option<f32>,
+
+	/// Cumulative probability to sample from instead of the one configured for the task
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub top_p: Option<f32>,
+
+	/// Maximum number of tokens to generate instead of the number configured for the task
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub max_tokens: Option<usize>,
+
+	/// Maximum number of characters to generate instead of the number configured for the task
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub max_chars: Option<usize>,
+
+	/// Maximum number of lines to generate instead of the number configured for the task
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub max_lines: Option<usize>,
+
+	/// Sequences that end generation in addition to the stop sequences configured for the task
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub stop: Vec<String>,
+
+	/// The result of a tool call made by the model, which is passed to the model after the prompt (using the result
+	/// template of the task)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	#[param(value_type = Option<Object>)]
+	pub tool_result: Option<ToolResult>,
+
+	/// When the prompt takes more tokens than the task allows (see `max_prompt_tokens`), cut off the start of the prompt
+	/// instead of rejecting it. Prefix, postfix and recalled memories are never cut off.
+	#[serde(default)]
+	pub truncate: bool,
+}
+
+/// A call of one of the tools of a task, made by the model instead of answering
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ToolCall {
+	/// Name of the tool
+	pub tool: String,
+
+	/// Arguments of the call, according to the schema of the tool
+	#[schema(value_type = Object)]
+	pub arguments: serde_json::Value,
+}
+
+/// The result of calling a tool, to continue a conversation in which the model called it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ToolResult {
+	/// Name of the tool that was called
+	pub tool: String,
+	pub result: String,
+}
+
+/// A part of a prompt (see [`PromptRequest::segments`])
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, ToSchema)]
+pub struct PromptSegment {
+	pub text: String,
+
+	/// Whether the text comes from a trusted source (e.g. the application rather than the end user), and may therefore
+	/// contain private tokens
+	#[serde(default)]
+	pub trusted: bool,
+
+	/// Whether the text is left out of logs
+	#[serde(default)]
+	pub redact_in_logs: bool,
+
+	/// Whether the text is stored in memory and used to recall from memory (when the task does so)
+	#[serde(default)]
+	pub memorize: bool,
+}
+
+/// Author of a message in a chat (see [`PromptRequest::messages`])
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatRole {
+	System,
+	User,
+	Assistant,
+}
+
+/// A message in a chat (see [`PromptRequest::messages`])
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ChatMessage {
+	pub role: ChatRole,
+	pub content: String,
+}
+
+/// Valid values for [`PromptRequest::temperature`]
+pub const TEMPERATURE_RANGE: RangeInclusive<f32> = 0.0..=2.0;
+
+/// Valid values for [`PromptRequest::top_p`]
+pub const TOP_P_RANGE: RangeInclusive<f32> = 0.0..=1.0;
+
+impl PromptRequest {
+	pub fn new(prompt: impl Into<String>) -> PromptRequest {
+		PromptRequest {
+			prompt: prompt.into(),
+			..Default::default()
+		}
+	}
+
+	/// The segments of the prompt; a single untrusted segment holding [`PromptRequest::prompt`] when no segments are given
+	pub fn segments(&self) -> Cow<[PromptSegment]> {
+		if self.segments.is_empty() {
+			Cow::Owned(vec![PromptSegment {
+				text: self.prompt.clone(),
+				trusted: false,
+				redact_in_logs: false,
+				memorize: true,
+			}])
+		} else {
+			Cow::Borrowed(&self.segments)
+		}
+	}
+
+	/// The full text of the prompt
+	pub fn text(&self) -> Cow<str> {
+		if self.segments.is_empty() {
+			Cow::Borrowed(&self.prompt)
+		} else {
+			Cow::Owned(self.segments.iter().map(|s| s.text.as_str()).collect())
+		}
+	}
+
+	/// The text of the prompt as it may be logged, with segments that are to be redacted replaced by [`REDACTED`]
+	pub fn log_text(&self) -> Cow<str> {
+		if self.has_redactions() {
+			Cow::Owned(
+				self.segments
+					.iter()
+					.map(|s| if s.redact_in_logs { REDACTED } else { s.text.as_str() })
+					.collect(),
+			)
+		} else {
+			self.text()
+		}
+	}
+
+	/// The text of the segments that are stored in memory, or for a chat, of the messages of the user
+	pub fn memory_text(&self) -> String {
+		if !self.messages.is_empty() {
+			let messages: Vec<&str> = self
+				.messages
+				.iter()
+				.filter(|m| m.role == ChatRole::User)
+				.map(|m| m.content.as_str())
+				.collect();
+			return messages.join("\n");
+		}
+		self.segments().iter().filter(|s| s.memorize).map(|s| s.text.as_str()).collect()
+	}
+
+	/// Whether any of the segments is redacted in logs
+	pub fn has_redactions(&self) -> bool {
+		self.segments.iter().any(|s| s.redact_in_logs)
+	}
+
+	/// Whether the request overrides any of the parameters configured for the task
+	pub fn has_overrides(&self) -> bool {
+		self.temperature.is_some()
+			|| self.top_p.is_some()
+			|| self.max_tokens.is_some()
+			|| self.max_chars.is_some()
+			|| self.max_lines.is_some()
+			|| !self.stop.is_empty()
+	}
+
+	pub(crate) fn check_parameters(&self) -> Result<(), BackendError> {
+		let invalid = |parameter: &str, message: String| Err(BackendError::InvalidParameter(parameter.to_string(), message));
+		if let Some(temperature) = self.temperature {
+			if !TEMPERATURE_RANGE.contains(&temperature) {
+				return invalid(
+					"temperature",
+					format!("must be between {} and {}", TEMPERATURE_RANGE.start(), TEMPERATURE_RANGE.end()),
+				);
+			}
+		}
+		if let Some(top_p) = self.top_p {
+			if !TOP_P_RANGE.contains(&top_p) {
+				return invalid("top_p", format!("must be between {} and {}", TOP_P_RANGE.start(), TOP_P_RANGE.end()));
+			}
+		}
+		for (parameter, value) in [
+			("max_tokens", self.max_tokens),
+			("max_chars", self.max_chars),
+			("max_lines", self.max_lines),
+		] {
+			if value == Some(0) {
+				return invalid(parameter, String::from("must be at least 1"));
+			}
+		}
+		if self.stop.iter().any(String::is_empty) {
+			return invalid("stop", String::from("stop sequences must not be empty"));
+		}
+		Ok(())
+	}
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct SessionAndPromptRequest {
 	#[serde(flatten)]
 	pub session: SessionRequest,
@@ -23,19 +259,168 @@ pub struct SessionAndPromptRequest {
 	pub prompt: PromptRequest,
 }
 
-#[derive(Serialize, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 pub struct EmbeddingResponse {
 	pub embedding: Vec<f32>,
+
+	/// For a batch of prompts (see `Backend::embedding_batch`): the embedding of each prompt, in order of the prompts.
+	/// `embedding` is then empty.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub embeddings: Option<Vec<PromptEmbedding>>,
+}
+
+/// The embedding of one of a batch of prompts
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct PromptEmbedding {
+	pub embedding: Vec<f32>,
+
+	/// Number of tokens of the prompt
+	pub prompt_tokens: usize,
+}
+
+/// Texts to compare by the similarity of their embeddings
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct SimilarityRequest {
+	pub a: String,
+
+	/// The text or texts to compare with `a`
+	pub b: SimilarityTexts,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(untagged)]
+pub enum SimilarityTexts {
+	One(String),
+	Many(Vec<String>),
+}
+
+impl SimilarityTexts {
+	pub fn texts(&self) -> Vec<&str> {
+		match self {
+			SimilarityTexts::One(text) => vec![text.as_str()],
+			SimilarityTexts::Many(texts) => texts.iter().map(|text| text.as_str()).collect(),
+		}
+	}
+}
+
+/// Documents to order by their relevance to a query
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct RerankRequest {
+	pub query: String,
+	pub documents: Vec<String>,
+
+	/// Number of documents to return (all documents are returned by default)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub top_n: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct RerankResponse {
+	/// The documents, most relevant first
+	pub results: Vec<RerankResult>,
+
+	/// How the scores were calculated
+	pub method: RerankMethod,
 }
 
-#[derive(Serialize, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct RerankResult {
+	/// Position of the document in the request
+	pub index: usize,
+	pub document: String,
+
+	/// Relevance of the document to the query (higher is more relevant; the scale depends on the method)
+	pub score: f32,
+}
+
+/// How documents are scored for relevance to a query
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RerankMethod {
+	/// Cosine similarity of the embeddings of the document and the query (between -1.0 and 1.0)
+	#[default]
+	Embedding,
+
+	/// Probability (between 0.0 and 1.0) that the model answers that the document is relevant to the query
+	CrossScoring,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct SimilarityResponse {
+	/// Cosine similarity (between -1.0 and 1.0) of the embedding of `a` with that of each of the texts in `b`, in order
+	pub scores: Vec<f32>,
+}
+
+/// A reason a prompt would be rejected (see [`ValidationResponse`])
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PromptViolation {
+	/// An untrusted segment of the prompt contains a private token of the task, at a byte offset in the text of the
+	/// segment
+	IllegalToken { token: String, segment: usize, offset: usize },
+
+	/// The prompt needs room for `needed` tokens, but a new session only has room for `available`
+	ContextFull { needed: usize, available: usize },
+
+	/// The prompt takes `needed` tokens, but the task allows at most `allowed` (and the request does not allow truncation,
+	/// or cutting off the prompt itself is not enough)
+	PromptTooLong { needed: usize, allowed: usize },
+
+	/// A parameter in the request has an invalid value
+	InvalidParameter { parameter: String, message: String },
+
+	/// The biaser of the task could not be set up
+	InvalidBiaser { message: String },
+
+	/// The variables given do not match those of the prompt template of the task
+	InvalidVariables {
+		missing: Vec<String>,
+		unknown: Vec<String>,
+		too_long: Vec<String>,
+	},
+}
+
+/// Result of checking a prompt the way a completion would, without performing the completion
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ValidationResponse {
+	/// Whether a completion for the prompt would be started (i.e. there are no violations)
+	pub valid: bool,
+
+	/// Tokens of the prompt itself
+	pub prompt_tokens: usize,
+
+	/// Tokens the task adds to the prompt (prefix, postfix and beginning-of-sentence token). Memories that would be
+	/// recalled for the prompt are not counted, as recalling them requires calculating an embedding.
+	pub overhead_tokens: usize,
+
+	/// Tokens that would be cut off the start of the prompt to fit in the maximum number of prompt tokens of the task
+	/// (only when the request asks for truncation)
+	pub truncated_tokens: usize,
+
+	/// Tokens in the context window of a new session before the prompt is fed (i.e. the prelude)
+	pub prelude_tokens: usize,
+
+	/// Size of the context window
+	pub context_size: usize,
+
+	pub violations: Vec<PromptViolation>,
+
+	/// The task configuration with the parameters of the request applied, as the completion would use it (not set when
+	/// the parameters are invalid)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	#[schema(value_type = Option<Object>)]
+	pub parameters: Option<TaskConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 pub struct TokenizationResponse {
 	pub tokens: Vec<TokenResponse>,
 }
 
-#[derive(Serialize, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
 pub struct TokenResponse {
 	pub text: String,
+	#[schema(value_type = u32)]
 	pub token: TokenId,
 }
 
@@ -47,35 +432,585 @@ impl From<TaskConfig> for InferenceParameters {
 	}
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct ModelsResponse {
-	pub models: Vec<String>,
+	pub models: Vec<ModelInfo>,
+}
+
+/// Whether a configured model could be loaded
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelState {
+	Loaded,
+
+	/// The model could not be loaded (see [`ModelInfo::error`])
+	Unavailable,
+}
+
+/// A configured model and what is known about it. The quantization, embedding length and file size are only known for
+/// models that were loaded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ModelInfo {
+	pub name: String,
+
+	/// The architecture of the model as configured (e.g. `llama`)
+	pub architecture: String,
+
+	/// The quantization format of the weights (e.g. `q4_0` or `f16`) as indicated by the name of the model file
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub quantization: Option<String>,
+
+	/// Maximum number of tokens in the context of a session
+	pub context_length: usize,
+
+	/// Number of dimensions of the embeddings calculated using the model
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub embedding_length: Option<usize>,
+
+	/// Size of the model file in bytes
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub file_size: Option<u64>,
+
+	pub state: ModelState,
+
+	/// Why the model could not be loaded
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct TasksResponse {
 	pub tasks: Vec<String>,
 }
 
-#[derive(Serialize)]
+/// The settings of a task (after applying defaults and its profile) that users of the task may know about. Prompts,
+/// private tokens, tools and other settings that are internal to the server are left out.
+#[derive(Serialize, Clone, Debug)]
+pub struct TaskResponse {
+	pub model: String,
+	pub profile: Option<String>,
+	pub max_tokens: Option<usize>,
+	pub max_chars: Option<usize>,
+	pub max_lines: Option<usize>,
+	pub max_prompt_tokens: Option<usize>,
+	pub max_variable_chars: usize,
+	pub max_duration_secs: Option<f64>,
+	pub priority: Priority,
+	pub public: bool,
+	pub slide_context: bool,
+	pub seed: Option<u64>,
+
+	#[serde(flatten)]
+	pub sampler: SamplerConfig,
+
+	/// Variables of the prompt template that requests must give values for (`input` excepted)
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub template_variables: Vec<String>,
+}
+
+impl TaskResponse {
+	pub fn new(config: &TaskConfig) -> TaskResponse {
+		TaskResponse {
+			model: config.model.clone(),
+			profile: config.profile.clone(),
+			max_tokens: config.max_tokens,
+			max_chars: config.max_chars,
+			max_lines: config.max_lines,
+			max_prompt_tokens: config.max_prompt_tokens,
+			max_variable_chars: config.max_variable_chars,
+			max_duration_secs: config.max_duration_secs,
+			priority: config.priority,
+			public: config.public,
+			slide_context: config.slide_context,
+			seed: config.seed,
+			sampler: config.sampler.clone(),
+			template_variables: config.template_variables(),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct MemoriesResponse {
 	pub memories: Vec<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, IntoParams, ToSchema)]
+#[into_params(parameter_in = Query)]
+pub struct RecallRequest {
+	/// Text to find similar chunks for. Either this or `embedding` must be given.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub prompt: Option<String>,
+
+	/// Embedding to find similar chunks for, instead of a prompt (e.g. one calculated by the client using the embedding
+	/// model of the memory). It must have the dimensionality of the memory. Cannot be given in the query string.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub embedding: Option<Vec<f32>>,
+
+	/// Number of chunks to return (1 by default)
+	pub n: Option<usize>,
+
+	/// Only return chunks of documents in this language (ISO 639-3 code, e.g. "eng", as detected when the document was
+	/// stored; "unknown" returns chunks of documents whose language could not be detected)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub language: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct RecallResponse {
+	pub chunks: Vec<String>,
+
+	/// Where the embedding the chunks were searched with came from
+	pub query: QuerySource,
+}
+
+/// Where the embedding used to search a memory came from
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QuerySource {
+	/// The prompt was embedded by the server
+	Embedded,
+
+	/// The embedding was supplied in the request
+	Supplied,
+}
+
+impl RecallRequest {
+	/// The query described by this request. Exactly one of `prompt` and `embedding` must be given.
+	pub fn query(&self) -> Result<MemoryQuery<'_>, BackendError> {
+		match (&self.prompt, &self.embedding) {
+			(Some(prompt), None) => Ok(MemoryQuery::Prompt(prompt)),
+			(None, Some(embedding)) => Ok(MemoryQuery::Embedding(embedding)),
+			(Some(_), Some(_)) => Err(BackendError::InvalidQuery(String::from(
+				"either a prompt or an embedding must be given, not both",
+			))),
+			(None, None) => Err(BackendError::InvalidQuery(String::from("a prompt or an embedding must be given"))),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct ForgetResponse {}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct RememberResponse {
+	/// Where the document was fetched from, when it was referred to by URL
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub source: Option<DocumentSource>,
+
+	/// Language of the document (ISO 639-3 code, e.g. "eng", or "unknown"), when it was detected
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub language: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, ToSchema)]
+pub struct DocumentSource {
+	/// The URL the document was read from, after following redirects
+	pub url: String,
+
+	/// Title of the document, if it has one
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub title: Option<String>,
+
+	/// Time the document was fetched, in seconds since the Unix epoch
+	pub fetched_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct StatsResponse {
+	pub tasks: HashMap<String, TaskStats>,
+	pub models: HashMap<String, ModelStats>,
+	pub active: ActiveStats,
+
+	/// Statistics of the memories
+	#[serde(default)]
+	pub memories: HashMap<String, MemoryStats>,
+
+	/// How long requests waited before they were serviced, by the priority they were given
+	#[serde(default)]
+	pub queue: HashMap<Priority, QueueWaitStats>,
+
+	/// Requests of clients that did not authenticate that were rejected for exceeding the rate limit
+	#[serde(default)]
+	pub rate_limit: RateLimitStats,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct ActiveStats {
+	/// Number of backend sessions currently held by the server
+	pub sessions: usize,
+
+	/// Number of connected chat WebSockets
+	pub chats: usize,
+
+	/// Number of chats whose WebSocket closed that are kept so that the client can resume them
+	#[serde(default)]
+	pub parked_chats: usize,
+
+	/// Number of open SSE streams
+	pub live_streams: usize,
+
+	/// Number of inference threads performing a completion or embedding
+	#[serde(default)]
+	pub inference_busy: usize,
+
+	/// Number of completions and embeddings waiting for an inference thread
+	#[serde(default)]
+	pub inference_queued: usize,
+}
+
+/// The order in which requests waiting to be serviced are admitted (see [`crate::config::TaskConfig::priority`])
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+	/// Background work (e.g. batch summarization), admitted when no request of a higher priority is waiting
+	Low,
+
+	#[default]
+	Normal,
+
+	/// Interactive use (e.g. chat), admitted before waiting requests of lower priorities
+	High,
+}
+
+impl Priority {
+	/// This priority raised by the specified number of levels (at most to [`Priority::High`])
+	pub fn promoted(self, levels: u64) -> Priority {
+		match (self, levels) {
+			(priority, 0) => priority,
+			(Priority::Low, 1) => Priority::Normal,
+			_ => Priority::High,
+		}
+	}
+}
+
+impl FromStr for Priority {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"low" => Ok(Priority::Low),
+			"normal" => Ok(Priority::Normal),
+			"high" => Ok(Priority::High),
+			_ => Err(format!("invalid priority '{s}' (expected low, normal or high)")),
+		}
+	}
+}
+
+/// Why a completion ended
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+	/// The model generated an end-of-text token (the one of the model, or one configured for the task; see
+	/// [`crate::session::Completion::eot_token`])
+	Eot,
+
+	/// One of the stop sequences of the task was generated
+	StopSequence,
+
+	/// The maximum number of tokens configured for the task was generated
+	MaxTokens,
+
+	/// The maximum number of characters or lines configured for the task was generated (the output is cut off at the
+	/// limit)
+	LengthLimit,
+
+	/// The context window of the session is full
+	ContextFull,
+
+	/// Generation was halted by the caller
+	Cancelled,
+
+	/// A banned phrase with action `stop` was generated (the output ends before it)
+	BannedPhrase,
+
+	/// The completion took longer than the maximum duration configured for the task. The output is what was generated
+	/// until then (in biased mode, closed by the biaser like when max_tokens is reached).
+	Timeout,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct GenerateResponse {
+	/// The generated text (empty when the model called a tool)
+	pub text: String,
+
+	/// Number of tokens processed for the completion
+	pub usage: TokenUsage,
+
+	/// Why the completion ended (e.g. `timeout` when the output was cut off by the maximum duration of the task)
+	pub finish_reason: FinishReason,
+
+	/// Whether `max_tokens` or the maximum duration was reached before biased output was complete, in which case the
+	/// output was closed (e.g. by adding closing brackets and quotes) to keep it valid
+	#[serde(default)]
+	pub force_closed: bool,
+
+	/// The tool the model called instead of answering (for tasks with tools)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub tool_call: Option<ToolCall>,
+
+	/// Whether the output is valid according to the schema (for tasks with a biaser)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub validation: Option<OutputValidation>,
+
+	/// Index of the choice the output is (for tasks with a choices biaser)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub choice: Option<usize>,
+
+	/// Number of tokens cut off the start of the prompt to fit in `max_prompt_tokens` (when truncation was requested)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub truncated_tokens: Option<usize>,
+
+	/// Time taken by the completion in milliseconds (not including the time spent waiting for a free slot)
+	#[serde(default)]
+	pub duration_ms: u64,
+}
+
+/// Sent as `done` event at the end of a live stream, so that clients can tell a finished completion from a dropped
+/// connection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+pub struct LiveDone {
+	/// Number of tokens processed for the completion
+	pub usage: TokenUsage,
+
+	/// Why the completion ended
+	pub finish_reason: FinishReason,
+
+	/// Time taken by the completion in milliseconds (not including the time spent waiting for a free slot)
+	pub duration_ms: u64,
+}
+
+/// The result of validating biased output once it was complete
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, ToSchema)]
+pub struct OutputValidation {
+	/// Number of completions performed: one, plus the number of times the model was asked to repair invalid output
+	pub attempts: usize,
+
+	/// Whether the returned output is valid. Only output that was closed by the biaser (because max_tokens was reached)
+	/// is returned when it is invalid.
+	pub valid: bool,
+
+	/// Why the returned output is invalid
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub errors: Vec<String>,
+}
+
+/// The summary of a document, made by summarizing its parts and then the summaries of those parts until a single summary
+/// remains
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct SummaryResponse {
 	pub text: String,
+
+	/// Number of parts the document was split into
+	pub chunks: usize,
+
+	/// The rounds of summarizing, starting with the one over the parts of the document
+	pub levels: Vec<SummaryLevel>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct SummaryLevel {
+	/// Number of texts summarized in this round
+	pub chunks: usize,
+
+	/// Number of tokens processed for the completions of this round together
+	pub usage: TokenUsage,
+}
+
+/// Reported before each completion while summarizing a document
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+pub struct SummaryProgress {
+	/// Round of summarizing (zero for the round over the parts of the document)
+	pub level: usize,
+
+	/// Index of the text that is summarized next
+	pub chunk: usize,
+
+	/// Number of texts summarized in this round
+	pub chunks: usize,
+}
+
+/// Message sent by a client on a chat WebSocket, in the JSON and MessagePack formats
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatClientMessage {
+	/// A prompt to generate a response to, optionally with parameters that override the ones configured for the task
+	/// (see [`PromptRequest`])
+	Prompt {
+		text: String,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		max_tokens: Option<usize>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		temperature: Option<f32>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		top_p: Option<f32>,
+	},
+
+	/// The result of a tool call (see [`ChatServerMessage::ToolCall`]), to which the model responds like to a prompt
+	ToolResult { tool: String, result: String },
+
+	/// Stop generating the response to the last prompt (or tool result). The response ends like any other, with finish
+	/// reason `cancelled`. Does nothing when the response is already complete.
+	Cancel,
+}
+
+impl ChatClientMessage {
+	/// A prompt without parameters
+	pub fn prompt(text: impl Into<String>) -> ChatClientMessage {
+		ChatClientMessage::Prompt {
+			text: text.into(),
+			max_tokens: None,
+			temperature: None,
+			top_p: None,
+		}
+	}
 }
 
-#[derive(Serialize)]
+/// Message sent by the server on a chat WebSocket, in the JSON and MessagePack formats
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatServerMessage {
+	/// Sent first on each connection when chats can be resumed: the token with which the client can resume the chat
+	/// after reconnecting. A new token is issued on each connection.
+	Session {
+		token: String,
+
+		/// Whether an earlier chat was resumed. Messages generated while the client was away follow this message.
+		resumed: bool,
+
+		/// Why the chat that the client asked to resume could not be resumed (a new chat was started instead)
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		notice: Option<String>,
+
+		/// Number of messages generated while the client was away that were dropped because too many were generated
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		dropped_messages: Option<usize>,
+	},
+
+	/// The prompt waits to be processed because the server is servicing the maximum number of requests. Sent
+	/// periodically until processing starts, with the position of the prompt in line (one when it is next) and the
+	/// estimated time until it is processed (when known).
+	Queued {
+		position: usize,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		estimated_wait_ms: Option<u64>,
+	},
+
+	/// A generated token
+	Token { text: String },
+
+	/// The model called a tool instead of answering (sent before the end of the response). The client should respond
+	/// with the result of the call.
+	ToolCall {
+		tool: String,
+		#[schema(value_type = Object)]
+		arguments: serde_json::Value,
+	},
+
+	/// The response to the prompt is complete
+	End {
+		/// Number of tokens processed for the response
+		#[serde(default)]
+		usage: TokenUsage,
+
+		/// Why the response ended (e.g. `cancelled` when the client cancelled it)
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		finish_reason: Option<FinishReason>,
+	},
+
+	/// Generating the response failed
+	Error { message: String },
+}
+
+/// Changes made to the running configuration by reloading it
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct ReloadReport {
+	pub added_tasks: Vec<String>,
+	pub changed_tasks: Vec<String>,
+	pub removed_tasks: Vec<String>,
+
+	/// Configuration entries (e.g. "models.gpt2") that changed, but that will only take effect after a restart
+	pub deferred: Vec<String>,
+
+	/// Models that could not be loaded before, but were loaded now
+	pub loaded_models: Vec<String>,
+
+	/// Models that still cannot be loaded, with the reason why
+	pub unavailable_models: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Status {
 	Ok,
+
+	/// Some models could not be loaded. Tasks using these models are unavailable; everything else works.
+	Degraded,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 pub struct StatusResponse {
 	pub status: Status,
+
+	/// Models that could not be loaded, with the reason why
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub unavailable_models: HashMap<String, String>,
+
+	/// Tasks that cannot be used because their model could not be loaded, with the reason why
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub unavailable_tasks: HashMap<String, String>,
+
+	/// Models that share the loaded weights of another model (as they load the same file in the same way), with the name
+	/// of that model
+	#[serde(default, skip_serializing_if = "HashMap::is_empty")]
+	pub model_aliases: HashMap<String, String>,
+
+	/// Number of requests waiting to be serviced because the server is servicing the maximum number of requests
+	#[serde(default)]
+	pub queued_requests: usize,
+}
+
+impl StatusResponse {
+	pub fn ok() -> StatusResponse {
+		StatusResponse {
+			status: Status::Ok,
+			unavailable_models: HashMap::new(),
+			unavailable_tasks: HashMap::new(),
+			model_aliases: HashMap::new(),
+			queued_requests: 0,
+		}
+	}
+}
+
+/// The operation on a memory that failed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryStage {
+	/// Recalling items (e.g. for a prompt)
+	Retrieve,
+
+	/// Storing items (e.g. a prompt after completion)
+	Store,
+
+	/// Listing the items
+	List,
+
+	/// Removing one or all items
+	Remove,
+
+	/// Writing the items to persistent storage
+	Flush,
+}
+
+impl std::fmt::Display for MemoryStage {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			MemoryStage::Retrieve => "retrieving from memory",
+			MemoryStage::Store => "storing in memory",
+			MemoryStage::List => "listing memory",
+			MemoryStage::Remove => "removing from memory",
+			MemoryStage::Flush => "flushing memory",
+		})
+	}
 }
 
 #[derive(Error, Debug)]
@@ -86,18 +1021,61 @@ pub enum BackendError {
 	#[error("model not found: {0}")]
 	ModelNotFound(String),
 
-	// llm_base::InferenceError is not Send
-	#[error("inference error: {0}")]
-	InferenceError(String),
+	/// The model is configured, but could not be loaded (see [`crate::backend::Backend::retry_unavailable_models`])
+	#[error("model {model} is not available: {reason}")]
+	ModelNotAvailable { model: String, reason: String },
+
+	/// Inference failed after generating `after_tokens` tokens (which may have been passed on already). The source is
+	/// boxed as llm_base::InferenceError is not Send.
+	#[error("inference failed after {after_tokens} tokens: {source}")]
+	InferenceFailed {
+		after_tokens: usize,
+		source: Box<dyn std::error::Error + Send + Sync>,
+	},
+
+	/// The session needs room for `needed` more tokens, but only has room for `available`
+	#[error("the context window of the session is full ({needed} tokens needed, {available} available)")]
+	ContextFull { needed: usize, available: usize },
+
+	/// The prompt (including prefix, postfix and recalled memories) takes `needed` tokens, but the task allows at most
+	/// `allowed` (see [`crate::config::TaskConfig::max_prompt_tokens`])
+	#[error("the prompt is too long ({needed} tokens, the task allows at most {allowed})")]
+	PromptTooLong { needed: usize, allowed: usize },
+
+	#[error("session state could not be saved or restored: {0}")]
+	SessionState(String),
 
 	#[error("tokenization error: {0}")]
 	TokenizationError(#[from] TokenizationError),
 
-	#[error("illegal token encountered")]
-	IllegalToken,
+	/// The prompt contains a token that may not be used in prompts (e.g. one of the private tokens of the task). Only the
+	/// location of the token is kept, so that the error can be logged and returned without revealing the token.
+	#[error("illegal token encountered in segment {segment} at byte offset {offset}")]
+	IllegalToken { segment: usize, offset: usize },
+
+	#[error("invalid value for parameter {0}: {1}")]
+	InvalidParameter(String, String),
+
+	/// The prompt has segments marked as trusted, but the caller is not allowed to mark segments as such
+	#[error("prompt segments may only be marked as trusted by callers that are allowed to do so")]
+	TrustedSegmentNotAllowed,
+
+	#[error("invalid biaser: {0}")]
+	InvalidBiaser(String),
+
+	/// The variables given for the prompt template of the task are missing, unknown or longer than allowed
+	#[error("invalid variables for the prompt template (missing: {missing:?}, unknown: {unknown:?}, too long: {too_long:?})")]
+	InvalidVariables {
+		missing: Vec<String>,
+		unknown: Vec<String>,
+		too_long: Vec<String>,
+	},
 
-	#[error("memory error: {0}")]
-	Memory(#[from] MemoryError),
+	#[error("invalid prompt template: {0}")]
+	InvalidPromptTemplate(String),
+
+	#[error("{stage} failed: {source}")]
+	MemoryFailed { stage: MemoryStage, source: MemoryError },
 
 	#[error("memory not found: {0}")]
 	MemoryNotFound(String),
@@ -105,12 +1083,223 @@ pub enum BackendError {
 	#[error("invalid document supplied")]
 	InvalidDocument,
 
+	/// The query to recall chunks for or the texts to compare are missing, ambiguous or unusable (e.g. an embedding of the
+	/// wrong dimensionality, or an empty text)
+	#[error("invalid query: {0}")]
+	InvalidQuery(String),
+
 	#[error("chunk separator '{0}' invalid: must consist of exactly one token")]
 	InvalidChunkSeparator(String),
+
+	#[error("end-of-text token '{0}' invalid: must consist of exactly one token")]
+	InvalidEotToken(String),
+
+	#[error("private token '{0}' invalid: must consist of at least one token")]
+	InvalidPrivateToken(String),
+
+	#[error("rerank answer token '{0}' invalid: must consist of exactly one token")]
+	InvalidRerankToken(String),
+
+	/// The request could not be completed within the time allowed for it
+	#[error("the request took longer than its budget of {budget_ms} ms")]
+	BudgetExceeded { budget_ms: u64 },
+
+	/// Generation in biased mode had to end (because `max_tokens` or the maximum duration was reached), but the output
+	/// could not be made valid within the number of tokens allowed for closing it
+	#[error("the output could not be closed within {overflow} tokens after generation had to end")]
+	TokenBudgetExceeded { overflow: usize },
+
+	/// Summarizing a document took more tokens than allowed for the task, or did not result in a single summary within
+	/// the maximum number of rounds
+	#[error("summary limit exceeded: {0}")]
+	SummaryLimitExceeded(String),
+
+	/// The biased output was still invalid according to the schema of the task after the maximum number of repairs
+	#[error("the output is invalid after {attempts} attempts: {}", .errors.join("; "))]
+	BiasedOutputInvalid { attempts: usize, errors: Vec<String> },
 }
 
-impl From<InferenceError> for BackendError {
-	fn from(e: InferenceError) -> BackendError {
-		BackendError::InferenceError(e.to_string())
+impl BackendError {
+	/// Describe an inference error that occurred while feeding `needed` tokens to a session that had room for
+	/// `available` tokens, after `after_tokens` tokens were generated
+	pub(crate) fn from_inference(error: InferenceError, needed: usize, available: usize, after_tokens: usize) -> BackendError {
+		match error {
+			InferenceError::ContextFull => BackendError::ContextFull { needed, available },
+			e => BackendError::InferenceFailed {
+				after_tokens,
+				source: e.to_string().into(),
+			},
+		}
+	}
+
+	pub(crate) fn memory(stage: MemoryStage) -> impl FnOnce(MemoryError) -> BackendError {
+		move |source| BackendError::MemoryFailed { stage, source }
+	}
+
+	/// Whether the same request may succeed when it is sent again later. Errors caused by the request itself (or by the
+	/// session being full) are not retryable.
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			// A full memory stays full until chunks are removed from it
+			BackendError::MemoryFailed {
+				source: MemoryError::Full { .. },
+				..
+			} => false,
+			BackendError::ModelNotAvailable { .. } | BackendError::InferenceFailed { .. } | BackendError::MemoryFailed { .. } => true,
+			_ => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use llm::InferenceError;
+
+	use super::{
+		BackendError, FinishReason, GenerateResponse, MemoryStage, PromptRequest, PromptSegment, RecallRequest, SimilarityRequest, TaskResponse,
+	};
+	use crate::{
+		config::TaskConfig,
+		memory::{MemoryError, MemoryQuery},
+		stats::TokenUsage,
+	};
+
+	#[test]
+	fn test_backend_error() {
+		let error = BackendError::from_inference(InferenceError::ContextFull, 12, 5, 0);
+		assert!(matches!(error, BackendError::ContextFull { needed: 12, available: 5 }));
+		assert!(!error.is_retryable());
+
+		let error = BackendError::from_inference(InferenceError::EndOfText, 1, 5, 7);
+		assert!(matches!(error, BackendError::InferenceFailed { after_tokens: 7, .. }));
+		assert!(error.is_retryable());
+
+		let error = BackendError::memory(MemoryStage::Store)(MemoryError::Storage(String::from("disk full")));
+		assert!(matches!(
+			error,
+			BackendError::MemoryFailed {
+				stage: MemoryStage::Store,
+				..
+			}
+		));
+		assert_eq!(error.to_string(), "storing in memory failed: storage error: disk full");
+		assert!(error.is_retryable());
+		assert!(!BackendError::memory(MemoryStage::Store)(MemoryError::Full { max_items: 10 }).is_retryable());
+
+		let error = BackendError::IllegalToken { segment: 1, offset: 4 };
+		assert!(!error.is_retryable());
+		assert_eq!(error.to_string(), "illegal token encountered in segment 1 at byte offset 4");
+	}
+
+	#[test]
+	fn test_prompt_segments() {
+		let request: PromptRequest = serde_json::from_str(r#"{"prompt": "Hello"}"#).unwrap();
+		assert_eq!(request.text(), "Hello");
+		assert_eq!(request.memory_text(), "Hello");
+		assert!(!request.segments()[0].trusted);
+
+		let request: PromptRequest = serde_json::from_str(
+			r#"{"segments": [
+				{"text": "<|system|>Answer using the document: ", "trusted": true},
+				{"text": "Our password is hunter2. ", "trusted": true, "redact_in_logs": true},
+				{"text": "What is the password?", "memorize": true}
+			]}"#,
+		)
+		.unwrap();
+		assert_eq!(
+			request.text(),
+			"<|system|>Answer using the document: Our password is hunter2. What is the password?"
+		);
+		assert_eq!(request.log_text(), "<|system|>Answer using the document: <redacted>What is the password?");
+		assert_eq!(request.memory_text(), "What is the password?");
+		assert_eq!(
+			request.segments()[2],
+			PromptSegment {
+				text: String::from("What is the password?"),
+				trusted: false,
+				redact_in_logs: false,
+				memorize: true,
+			}
+		);
+	}
+
+	#[test]
+	fn test_recall_query() {
+		let request: RecallRequest = serde_json::from_str(r#"{"prompt": "Hello"}"#).unwrap();
+		assert_eq!(request.query().unwrap(), MemoryQuery::Prompt("Hello"));
+
+		let request: RecallRequest = serde_json::from_str(r#"{"embedding": [0.5, -1.0], "n": 3}"#).unwrap();
+		assert_eq!(request.query().unwrap(), MemoryQuery::Embedding(&[0.5, -1.0]));
+
+		for body in [r#"{"n": 3}"#, r#"{"prompt": "Hello", "embedding": [0.5, -1.0]}"#] {
+			let request: RecallRequest = serde_json::from_str(body).unwrap();
+			assert!(matches!(request.query(), Err(BackendError::InvalidQuery(_))), "{body}");
+		}
+	}
+
+	#[test]
+	fn test_generate_response() {
+		let usage = TokenUsage::default();
+		let response = serde_json::json!({"text": "{}", "usage": usage, "finish_reason": "max_tokens", "force_closed": true});
+		let response: GenerateResponse = serde_json::from_value(response).unwrap();
+		assert_eq!(response.finish_reason, FinishReason::MaxTokens);
+		assert!(response.force_closed);
+
+		// Responses of servers that do not report it were not closed
+		let response = serde_json::json!({"text": "Hi", "usage": usage, "finish_reason": "eot"});
+		let response: GenerateResponse = serde_json::from_value(response).unwrap();
+		assert!(!response.force_closed);
+	}
+
+	#[test]
+	fn test_similarity_request() {
+		let request: SimilarityRequest = serde_json::from_str(r#"{"a": "cat", "b": "dog"}"#).unwrap();
+		assert_eq!(request.b.texts(), vec!["dog"]);
+		let request: SimilarityRequest = serde_json::from_str(r#"{"a": "cat", "b": ["dog", "car"]}"#).unwrap();
+		assert_eq!(request.b.texts(), vec!["dog", "car"]);
+	}
+
+	#[test]
+	fn test_task_response() {
+		let config: TaskConfig = toml::from_str(
+			r#"
+			model = "gpt2"
+			prefix = "<|im_start|>system\nThe password is hunter2.<|im_end|>\n<|im_start|>user\n"
+			prompt_template = "Translate to {language}: {input}"
+			private_tokens = ["<|im_start|>", "<|im_end|>"]
+			trace_dir = "/var/trace"
+			max_tokens = 100
+			temperature = 0.5
+
+			[[tools]]
+			name = "lookup"
+			description = "Look up a customer"
+			arguments = { type = "object" }
+			"#,
+		)
+		.unwrap();
+
+		// Only settings that users of the task may know about are returned
+		let response = serde_json::to_value(TaskResponse::new(&config)).unwrap();
+		assert_eq!(response["model"], "gpt2");
+		assert_eq!(response["max_tokens"], 100);
+		assert_eq!(response["temperature"], 0.5);
+		assert_eq!(response["template_variables"], serde_json::json!(["language"]));
+		for key in [
+			"prefix",
+			"prompt_template",
+			"private_tokens",
+			"trace_dir",
+			"tools",
+			"tool_templates",
+			"memorization",
+		] {
+			assert!(response.get(key).is_none(), "{key} should not be returned");
+		}
+		let text = response.to_string();
+		assert!(
+			!text.contains("hunter2") && !text.contains("<|im_start|>") && !text.contains("lookup"),
+			"{text}"
+		);
 	}
 }
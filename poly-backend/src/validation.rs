@@ -0,0 +1,124 @@
+//! Validation of biased output once it is complete (see [`crate::config::TaskConfig::validation`]). The biaser enforces
+//! the structure of the output token by token, but not every constraint (e.g. formats or dependencies between keys), so
+//! the complete output is checked against the full schema using a JSON Schema validator.
+
+use jsonschema::{Draft, JSONSchema};
+use poly_bias::json::JsonSchema;
+use serde_json::{json, Value};
+
+use crate::config::ValidationConfig;
+
+/// Compile a JSON Schema document (draft 2020-12)
+pub(crate) fn compile(schema: &Value) -> Result<JSONSchema, String> {
+	JSONSchema::options()
+		.with_draft(Draft::Draft202012)
+		.compile(schema)
+		.map_err(|e| e.to_string())
+}
+
+/// Validates output against the schema of the biaser together with the additional schema configured for the task
+pub(crate) struct OutputValidator {
+	schema: JSONSchema,
+}
+
+impl OutputValidator {
+	pub fn new(schema: &JsonSchema, config: &ValidationConfig) -> Result<OutputValidator, String> {
+		let full = match config.schema {
+			Some(ref extra) => json!({ "allOf": [schema.to_json_schema(), extra] }),
+			None => schema.to_json_schema(),
+		};
+		Ok(OutputValidator { schema: compile(&full)? })
+	}
+
+	/// Validate output, returning a description of each problem (prefixed with its location, if any) when it is invalid
+	pub fn validate(&self, output: &str) -> Result<(), Vec<String>> {
+		let value: Value = serde_json::from_str(output).map_err(|e| vec![format!("the output is not valid JSON: {e}")])?;
+		self.schema.validate(&value).map_err(|errors| {
+			errors
+				.map(|error| {
+					let path = error.instance_path.to_string();
+					if path.is_empty() {
+						error.to_string()
+					} else {
+						format!("{path}: {error}")
+					}
+				})
+				.collect()
+		})
+	}
+}
+
+/// The prompt asking the model to repair output that has the given problems
+pub(crate) fn repair_prompt(config: &ValidationConfig, errors: &[String]) -> String {
+	config.repair_template.replace("{errors}", &errors.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+
+	use poly_bias::json::JsonSchema;
+	use serde_json::json;
+
+	use super::{repair_prompt, OutputValidator};
+	use crate::config::ValidationConfig;
+
+	fn schema() -> JsonSchema {
+		let number = || {
+			Box::new(JsonSchema::Number {
+				min: Some(0.0),
+				max: None,
+				max_decimals: None,
+				exclusive_min: None,
+				exclusive_max: None,
+				multiple_of: None,
+				r#enum: None,
+			})
+		};
+		JsonSchema::Object {
+			required: vec![String::from("min"), String::from("max")],
+			properties: HashMap::from([(String::from("min"), number()), (String::from("max"), number())]),
+		}
+	}
+
+	#[test]
+	fn test_validate() {
+		let validator = OutputValidator::new(&schema(), &ValidationConfig::default()).unwrap();
+		assert_eq!(validator.validate(r#"{"min": 1, "max": 5}"#), Ok(()));
+		assert!(validator.validate(r#"{"min": 1}"#).is_err());
+		assert!(validator.validate(r#"{"min": 1, "max": 5, "step": 1}"#).is_err());
+
+		let errors = validator.validate(r#"{"min": 1, "max": "#).unwrap_err();
+		assert_eq!(errors.len(), 1);
+		assert!(errors[0].starts_with("the output is not valid JSON"), "{errors:?}");
+
+		// Constraints the biaser cannot express are added by the configured schema
+		let config = ValidationConfig {
+			schema: Some(json!({ "properties": { "max": { "minimum": 10 } } })),
+			..Default::default()
+		};
+		let validator = OutputValidator::new(&schema(), &config).unwrap();
+		assert_eq!(validator.validate(r#"{"min": 1, "max": 15}"#), Ok(()));
+		let errors = validator.validate(r#"{"min": 1, "max": 5}"#).unwrap_err();
+		assert_eq!(errors.len(), 1);
+		assert!(errors[0].starts_with("/max: "), "{errors:?}");
+
+		let config = ValidationConfig {
+			schema: Some(json!({ "type": 12 })),
+			..Default::default()
+		};
+		assert!(OutputValidator::new(&schema(), &config).is_err());
+	}
+
+	#[test]
+	fn test_repair_prompt() {
+		let config = ValidationConfig {
+			repair_template: String::from("Fix these problems:\n{errors}\n"),
+			..Default::default()
+		};
+		assert_eq!(
+			repair_prompt(&config, &[String::from("/max: 5 is less than 10"), String::from("/min: missing")]),
+			"Fix these problems:\n/max: 5 is less than 10\n/min: missing\n"
+		);
+	}
+}
@@ -0,0 +1,268 @@
+use std::sync::Arc;
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	session::{InferenceFeedback, InferenceResponse},
+	types::{BackendError, ChatMessage, ChatRole, FinishReason, PromptRequest, SessionRequest},
+};
+
+static PRELUDE: &str = "The following is a conversation between a user and a friendly assistant.";
+
+fn config(slide_context: bool) -> BackendConfig {
+	from_toml_str(&format!(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+		context_size = 64
+
+		[tasks.chat]
+		model = "gpt2"
+		prelude = "{PRELUDE}"
+		prefix = "User: "
+		postfix = "\nAssistant:"
+		max_tokens = 24
+		stop_sequences = []
+		slide_context = {slide_context}
+
+		[tasks.chat_seeded]
+		model = "gpt2"
+		prefix = "User: "
+		postfix = "\nAssistant:"
+		max_tokens = 8
+		seed = 42
+		stop_sequences = []
+
+		[tasks.assistant]
+		model = "gpt2"
+		max_tokens = 8
+		chat_template = {{ user_prefix = "User: ", user_suffix = "\n", assistant_prefix = "Assistant:", assistant_suffix = "\n\n" }}
+
+		[tasks.emoji]
+		model = "gpt2"
+		biaser = {{ choices = ["🦀", "🦁", "🧀", "🧁"] }}
+		"#
+	))
+	.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_pinned_prelude() {
+	let backend = Arc::new(Backend::from(config(true), None).await);
+	let mut session = backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap();
+	let pinned = session.count_tokens("").unwrap().pinned;
+	assert!(pinned > 0);
+	assert!(session.transcript().starts_with(PRELUDE));
+
+	// Each round takes about half of the context window, so the conversation overflows it several times
+	let start = session.checkpoint();
+	let mut first_round = None;
+	for round in 0..8 {
+		let completion = session
+			.complete(&PromptRequest::new(format!("Tell me something about the number {round}.")), |_| {
+				Ok(InferenceFeedback::Continue)
+			})
+			.unwrap();
+		assert_ne!(completion.finish_reason, FinishReason::ContextFull);
+
+		let count = session.count_tokens("").unwrap();
+		assert_eq!(count.pinned, pinned);
+		assert!(count.used <= count.context);
+		assert_eq!(count.unpinned(), count.used - pinned);
+		assert!(session.transcript().starts_with(PRELUDE));
+		first_round.get_or_insert_with(|| session.checkpoint());
+	}
+	assert!(session.transcript().contains("the number 7"));
+
+	// Tokens were dropped since the first round, so its checkpoint no longer points at the same conversation (even when
+	// the session has advanced past its position again)
+	assert!(matches!(session.rewind_to(&first_round.unwrap()), Err(BackendError::SessionState(_))));
+
+	// Rewinding to the end of the prelude keeps the prelude
+	session.rewind_to(&start).unwrap();
+	assert_eq!(session.count_tokens("").unwrap().unpinned(), 0);
+	assert_eq!(session.transcript(), PRELUDE);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_regenerate() {
+	let backend = Arc::new(Backend::from(config(false), None).await);
+	let mut session = backend.start("chat_seeded", &SessionRequest::default(), backend.clone()).unwrap();
+	session
+		.complete(&PromptRequest::new("Tell me something about the number 1."), |_| {
+			Ok(InferenceFeedback::Continue)
+		})
+		.unwrap();
+
+	// Answer the next prompt, then rewind to before it
+	let checkpoint = session.checkpoint();
+	let before = (session.count_tokens("").unwrap(), session.context_remaining(), session.transcript());
+	let prompt = PromptRequest::new("And the number 2?");
+	let first = session.complete(&prompt, |_| Ok(InferenceFeedback::Continue)).unwrap();
+	let after = (session.count_tokens("").unwrap(), session.context_remaining(), session.transcript());
+	assert!(after.0.used > before.0.used);
+
+	session.rewind_to(&checkpoint).unwrap();
+	assert_eq!(session.count_tokens("").unwrap().used, before.0.used);
+	assert_eq!(session.context_remaining(), before.1);
+	assert_eq!(session.transcript(), before.2);
+
+	// Regenerating the answer (with the same seed) takes the session to where it was after the first answer
+	let second = session.complete(&prompt, |_| Ok(InferenceFeedback::Continue)).unwrap();
+	assert_eq!(second.usage.prompt_tokens, first.usage.prompt_tokens);
+	assert_eq!(second.usage.sampled_tokens, first.usage.sampled_tokens);
+	assert_eq!(session.count_tokens("").unwrap().used, after.0.used);
+	assert_eq!(session.context_remaining(), after.1);
+	assert_eq!(session.transcript(), after.2);
+
+	// A checkpoint cannot be used once the session was rewound to before it
+	let later = session.checkpoint();
+	session.rewind_to(&checkpoint).unwrap();
+	assert!(matches!(session.rewind_to(&later), Err(BackendError::SessionState(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_context_full() {
+	let backend = Arc::new(Backend::from(config(false), None).await);
+	let mut session = backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap();
+
+	// Without sliding, the conversation ends once the context window is full
+	let mut full = false;
+	for round in 0..8 {
+		match session.complete(&PromptRequest::new(format!("Tell me something about the number {round}.")), |_| {
+			Ok(InferenceFeedback::Continue)
+		}) {
+			Ok(completion) if completion.finish_reason == FinishReason::ContextFull => full = true,
+			Ok(_) => {}
+			Err(BackendError::ContextFull { .. }) => full = true,
+			Err(e) => panic!("unexpected error: {e}"),
+		}
+		if full {
+			break;
+		}
+	}
+	assert!(full);
+	assert!(session.transcript().starts_with(PRELUDE));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_prelude_cache() {
+	let backend = Arc::new(Backend::from(config(false), None).await);
+	let first = backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap();
+	let second = backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap();
+
+	// The second session starts from the state cached by the first, which is the same
+	assert_eq!(first.transcript(), second.transcript());
+	assert_eq!(first.count_tokens("").unwrap().pinned, second.count_tokens("").unwrap().pinned);
+	let stats = backend.stats.task_stats.lock().unwrap()["chat"].clone();
+	assert_eq!((stats.prelude_cache_hits, stats.prelude_cache_misses), (1, 1));
+	assert_eq!(backend.stats.model_stats()["gpt2"].generation.prelude_cache_hits, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_threads_per_session() {
+	let mut config = config(false);
+	config.tasks.get_mut("chat").unwrap().threads_per_session = Some(1);
+	let backend = Arc::new(Backend::from(config, None).await);
+
+	// Both a session that feeds the prelude and one that starts from the cached prelude use the configured threads
+	for _ in 0..2 {
+		let mut session = backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap();
+		assert_eq!(session.inference_threads(), 1);
+		session
+			.complete(&PromptRequest::new("Tell me something about the number 1."), |_| {
+				Ok(InferenceFeedback::Continue)
+			})
+			.unwrap();
+		assert_eq!(session.inference_threads(), 1);
+	}
+
+	let stats = backend.stats.task_stats.lock().unwrap()["chat"].clone();
+	assert_eq!(stats.cycles, 2);
+	assert_eq!(stats.n_threads, 1);
+	assert!(stats.prompt_tokens > 0);
+	assert_eq!(stats.prompt_duration_threads, stats.prompt_duration);
+	assert_eq!(stats.predict_duration_threads, stats.predict_duration);
+
+	// A session keeps using the configured threads after restoring a state saved by a session that used more
+	let mut other = backend.start("chat_seeded", &SessionRequest::default(), backend.clone()).unwrap();
+	assert_eq!(other.inference_threads(), 8);
+	let mut state = vec![];
+	other.save_state(&mut state).unwrap();
+	let mut session = backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap();
+	session.restore_state(state.as_slice()).unwrap();
+	assert_eq!(session.inference_threads(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_chat_template() {
+	let backend = Arc::new(Backend::from(config(false), None).await);
+	let mut session = backend.start("assistant", &SessionRequest::default(), backend.clone()).unwrap();
+
+	// Messages are rendered using the template, and the reply is closed so that the chat can continue
+	let request = PromptRequest {
+		messages: vec![ChatMessage {
+			role: ChatRole::User,
+			content: String::from("Tell me about the number 1."),
+		}],
+		..Default::default()
+	};
+	session.complete(&request, |_| Ok(InferenceFeedback::Continue)).unwrap();
+	assert!(session.transcript().starts_with("User: Tell me about the number 1.\nAssistant:"));
+	assert!(session.transcript().ends_with("\n\n"));
+
+	// A prompt is rendered as a message of the user
+	session
+		.complete(&PromptRequest::new("And the number 2?"), |_| Ok(InferenceFeedback::Continue))
+		.unwrap();
+	assert!(session.transcript().contains("\n\nUser: And the number 2?\nAssistant:"));
+
+	// Tasks without a chat template do not accept messages
+	let mut session = backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap();
+	assert!(matches!(
+		session.complete(&request, |_| Ok(InferenceFeedback::Continue)),
+		Err(BackendError::InvalidParameter(parameter, _)) if parameter == "messages"
+	));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_partial_character() {
+	let backend = Arc::new(Backend::from(config(false), None).await);
+	let mut session = backend.start("emoji", &SessionRequest::default(), backend.clone()).unwrap();
+
+	// Fill the context window so that one token can be generated. The choices are four-byte characters that share their
+	// first bytes, so that token is only part of a character.
+	let mut prompt = String::new();
+	loop {
+		let count = session.count_tokens(&prompt).unwrap();
+		let remaining = count.context - count.used - count.overhead - count.prompt;
+		if remaining <= 2 {
+			assert_eq!(remaining, 2);
+			break;
+		}
+		prompt.push_str(" a");
+	}
+
+	// The bytes of the character that was not completed are output as a replacement character
+	let mut output = String::new();
+	let completion = session
+		.complete(&PromptRequest::new(prompt.as_str()), |r| {
+			if let InferenceResponse::InferredToken(t) = r {
+				output += &t;
+			}
+			Ok(InferenceFeedback::Continue)
+		})
+		.unwrap();
+	assert_eq!(completion.finish_reason, FinishReason::ContextFull);
+	assert_eq!(output, "\u{FFFD}");
+
+	// It is also part of the text of the completion (which the server returns as `GenerateResponse::text`)
+	let (text, completion) = backend
+		.complete("emoji", &SessionRequest::default(), &PromptRequest::new(prompt.as_str()), |_| {
+			InferenceFeedback::Continue
+		})
+		.unwrap();
+	assert_eq!(completion.finish_reason, FinishReason::ContextFull);
+	assert_eq!(text, "\u{FFFD}");
+}
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	types::{BackendError, PromptRequest},
+};
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		embedding_cache_size = 0
+
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+		max_similarity_texts = 2
+		"#,
+	)
+	.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_embedding_batch() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let prompts = [
+		PromptRequest::new("The sky is blue"),
+		PromptRequest::new("Once upon a time, in a land far away"),
+		PromptRequest::new("The sky is blue"),
+	];
+	let response = backend.embedding_batch("gpt2", &prompts).unwrap();
+	assert!(response.embedding.is_empty());
+	let embeddings = response.embeddings.unwrap();
+	assert_eq!(embeddings.len(), 3);
+
+	// Reusing the session for the next prompt does not affect its embedding
+	for (prompt, batched) in prompts.iter().zip(&embeddings) {
+		assert_eq!(backend.embedding("gpt2", prompt).unwrap().embedding, batched.embedding);
+	}
+	assert_eq!(embeddings[0].embedding, embeddings[2].embedding);
+	assert_eq!(embeddings[0].prompt_tokens, embeddings[2].prompt_tokens);
+	assert!(embeddings[1].prompt_tokens > embeddings[0].prompt_tokens);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_similarity_limit() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let scores = backend
+		.similarity("gpt2", "The sky is blue", &["The sky is blue", "Once upon a time"])
+		.unwrap();
+	assert_eq!(scores.len(), 2);
+	assert!(scores[0] > scores[1]);
+
+	// Each text is embedded separately, so no more texts may be given than the model allows
+	assert!(matches!(
+		backend.similarity("gpt2", "The sky is blue", &["a", "b", "c"]),
+		Err(BackendError::InvalidQuery(message)) if message == "at most 2 texts may be given to compare with"
+	));
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	memory::MemoryError,
+	session::InferenceFeedback,
+	types::{BackendError, MemoryStage, PromptRequest, SessionRequest},
+};
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+		context_size = 64
+
+		[memories.facts]
+		store = { hora = {} }
+		dimensions = 768
+		embedding_model = "gpt2"
+		max_items = 1
+
+		[tasks.chat]
+		model = "gpt2"
+		max_tokens = 2
+		private_tokens = ["<|endoftext|>"]
+
+		[tasks.remember]
+		model = "gpt2"
+		max_tokens = 2
+		memorization = { memory = "facts", store_prompts = true }
+
+		[tasks.assistant]
+		model = "gpt2"
+		max_chars = 5
+
+		[[tasks.assistant.tools]]
+		name = "weather"
+		description = "Looks up the weather in a city."
+		arguments = { type = "object", required = ["city"], properties = { city = { type = "string" } } }
+		"#,
+	)
+	.unwrap()
+}
+
+/// Complete a prompt in a new session for the task (on a blocking thread, as storing in memory blocks on the runtime)
+async fn complete(backend: &Arc<Backend>, task: &'static str, prompt: &str) -> Result<(), BackendError> {
+	let backend = backend.clone();
+	let prompt = prompt.to_string();
+	tokio::task::spawn_blocking(move || {
+		let mut session = backend.start(task, &SessionRequest::default(), backend.clone())?;
+		session.complete(&PromptRequest::new(prompt), |_| Ok(InferenceFeedback::Continue))?;
+		Ok(())
+	})
+	.await
+	.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_context_full() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let prompt = "The quick brown fox jumps over the lazy dog. ".repeat(10);
+	let error = complete(&backend, "chat", &prompt).await.unwrap_err();
+	assert!(
+		matches!(error, BackendError::ContextFull { needed, available } if needed > available),
+		"{error:?}"
+	);
+	assert!(!error.is_retryable());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_inference_failed() {
+	// The length limit cuts off the output of the model before it is a complete tool call or answer
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let error = complete(&backend, "assistant", "What is the weather in Paris?").await.unwrap_err();
+	assert!(matches!(error, BackendError::InferenceFailed { .. }), "{error:?}");
+	assert!(error.is_retryable());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_illegal_token() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let error = complete(&backend, "chat", "Hello<|endoftext|>").await.unwrap_err();
+	assert!(matches!(error, BackendError::IllegalToken { segment: 0, offset: 5 }), "{error:?}");
+	assert!(!error.is_retryable());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_memory_store_failed() {
+	// The memory holds at most one chunk and rejects more
+	let backend = Arc::new(Backend::from(config(), None).await);
+	complete(&backend, "remember", "The sky is blue").await.unwrap();
+	let error = complete(&backend, "remember", "The grass is green").await.unwrap_err();
+	assert!(
+		matches!(
+			error,
+			BackendError::MemoryFailed {
+				stage: MemoryStage::Store,
+				source: MemoryError::Full { max_items: 1 },
+			}
+		),
+		"{error:?}"
+	);
+	assert!(!error.is_retryable());
+}
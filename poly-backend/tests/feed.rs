@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	session::{Cancellation, InferenceFeedback, InferenceResponse},
+	types::{Completion, FinishReason, PromptRequest, SessionRequest},
+};
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+		prompt_chunk_size = 3
+
+		[models.gpt2_unchunked]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+
+		[tasks.chunked]
+		model = "gpt2"
+		prefix = "Question: "
+		postfix = "\nAnswer:"
+		max_tokens = 1
+		stop_sequences = []
+
+		[tasks.unchunked]
+		model = "gpt2_unchunked"
+		prefix = "Question: "
+		postfix = "\nAnswer:"
+		max_tokens = 1
+		stop_sequences = []
+		"#,
+	)
+	.unwrap()
+}
+
+const PROMPT: &str = "Why is the sky blue during the day, but red at sunset?";
+
+/// Complete the prompt with `task`, returning the completion and the text of each chunk of prompt that was fed
+async fn complete(task: &str, cancellation: &Cancellation, mut feedback: impl FnMut(usize) -> InferenceFeedback) -> (Completion, Vec<String>) {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let mut session = backend.start(task, &SessionRequest::default(), backend.clone()).unwrap();
+	let mut chunks = vec![];
+	let completion = session
+		.complete_cancellable(&PromptRequest::new(PROMPT), cancellation, |r| {
+			if let InferenceResponse::PromptToken(text) = r {
+				chunks.push(text);
+				return Ok(feedback(chunks.len()));
+			}
+			Ok(InferenceFeedback::Continue)
+		})
+		.unwrap();
+	(completion, chunks)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_feed_chunked_totals() {
+	let (unchunked, whole) = complete("unchunked", &Cancellation::new(), |_| InferenceFeedback::Continue).await;
+	let (chunked, chunks) = complete("chunked", &Cancellation::new(), |_| InferenceFeedback::Continue).await;
+
+	// The whole prompt is fed as one chunk, or as chunks of three tokens
+	let n_tokens = unchunked.stats.prompt_tokens;
+	assert!(n_tokens > 3);
+	assert_eq!(whole.len(), 1);
+	assert_eq!(chunks.len(), n_tokens.div_ceil(3));
+	assert_eq!(chunks.concat(), whole[0]);
+
+	// The statistics of the chunks add up to those of feeding the prompt at once
+	assert_eq!(chunked.stats.prompt_tokens, n_tokens);
+	assert_eq!(chunked.usage.prompt_tokens, unchunked.usage.prompt_tokens);
+	assert_eq!(chunked.finish_reason, unchunked.finish_reason);
+	assert_eq!(chunked.usage.sampled_tokens, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_feed_chunked_interrupted() {
+	// The callback halts feeding after the second chunk
+	let (completion, chunks) = complete("chunked", &Cancellation::new(), |n| {
+		if n == 2 {
+			InferenceFeedback::Halt
+		} else {
+			InferenceFeedback::Continue
+		}
+	})
+	.await;
+	assert_eq!(chunks.len(), 2);
+	assert_eq!(completion.finish_reason, FinishReason::Cancelled);
+	assert_eq!(completion.stats.prompt_tokens, 6);
+	assert_eq!(completion.usage.sampled_tokens, 0);
+
+	// Cancellation is checked before each chunk, so the chunk during which it happens is the last one fed
+	let cancellation = Cancellation::new();
+	let (completion, chunks) = complete("chunked", &cancellation, |n| {
+		if n == 3 {
+			cancellation.cancel();
+		}
+		InferenceFeedback::Continue
+	})
+	.await;
+	assert_eq!(chunks.len(), 3);
+	assert_eq!(completion.finish_reason, FinishReason::Cancelled);
+	assert_eq!(completion.stats.prompt_tokens, 9);
+	assert_eq!(completion.usage.sampled_tokens, 0);
+}
@@ -0,0 +1,213 @@
+use std::{
+	sync::{mpsc, Arc},
+	thread::{self, sleep},
+	time::Duration,
+};
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	session::{Cancellation, InferenceFeedback, InferenceResponse},
+	types::{Completion, FinishReason, PromptRequest, SessionRequest},
+};
+
+/// Time after which the deadline of the tasks with a maximum duration (0.2 seconds) has certainly passed
+const PAST_DEADLINE: Duration = Duration::from_millis(250);
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+		prompt_chunk_size = 2
+
+		[models.gpt2_unchunked]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+
+		[tasks.story]
+		model = "gpt2"
+		max_tokens = 32
+		stop_sequences = []
+
+		[tasks.story_timed]
+		model = "gpt2"
+		max_tokens = 32
+		max_duration_secs = 0.2
+		stop_sequences = []
+
+		[tasks.verdict]
+		model = "gpt2_unchunked"
+		prefix = "Statement: "
+		postfix = "\nReasoning:"
+		bias_prompt = "\nVerdict (true or false):"
+		max_tokens = 8
+		biaser = { json_schema = { type = "boolean" } }
+
+		[tasks.verdict_timed]
+		model = "gpt2_unchunked"
+		prefix = "Statement: "
+		postfix = "\nReasoning:"
+		bias_prompt = "\nVerdict (true or false):"
+		max_tokens = 8
+		max_duration_secs = 0.2
+		biaser = { json_schema = { type = "boolean" } }
+		"#,
+	)
+	.unwrap()
+}
+
+/// How a completion is ended before it is done
+#[derive(Clone, Copy, Debug)]
+enum Interrupt {
+	/// The completion is cancelled
+	Cancel,
+
+	/// The maximum duration of the task passes (the `_timed` variant of the task is used)
+	Timeout,
+}
+
+impl Interrupt {
+	const ALL: [Interrupt; 2] = [Interrupt::Cancel, Interrupt::Timeout];
+
+	fn task(self, task: &str) -> String {
+		match self {
+			Interrupt::Cancel => task.to_string(),
+			Interrupt::Timeout => format!("{task}_timed"),
+		}
+	}
+
+	fn finish_reason(self) -> FinishReason {
+		match self {
+			Interrupt::Cancel => FinishReason::Cancelled,
+			Interrupt::Timeout => FinishReason::Timeout,
+		}
+	}
+
+	fn trigger(self, cancellation: &Cancellation) {
+		match self {
+			Interrupt::Cancel => cancellation.cancel(),
+			Interrupt::Timeout => sleep(PAST_DEADLINE),
+		}
+	}
+}
+
+/// Complete `prompt` using `task`, interrupting the completion from the callback the first time `interrupt_at` returns
+/// true. The completion then ends at the next check.
+async fn complete_interrupted(
+	interrupt: Interrupt,
+	task: &str,
+	prompt: &str,
+	mut interrupt_at: impl FnMut(&InferenceResponse) -> bool,
+) -> Completion {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let mut session = backend.start(&interrupt.task(task), &SessionRequest::default(), backend.clone()).unwrap();
+
+	let cancellation = Cancellation::new();
+	let mut interrupted = false;
+	let completion = session
+		.complete_cancellable(&PromptRequest::new(prompt), &cancellation, |r| {
+			if interrupt_at(&r) && !interrupted {
+				interrupted = true;
+				interrupt.trigger(&cancellation);
+			}
+			Ok(InferenceFeedback::Continue)
+		})
+		.unwrap();
+	assert_eq!(completion.finish_reason, interrupt.finish_reason(), "{interrupt:?}");
+	completion
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_interrupt_while_generating() {
+	for interrupt in Interrupt::ALL {
+		let mut output = String::new();
+		let completion = complete_interrupted(interrupt, "story", "Once upon a time", |r| match r {
+			InferenceResponse::InferredToken(t) => {
+				output += t;
+				true
+			}
+			_ => false,
+		})
+		.await;
+		assert!(!output.is_empty(), "{interrupt:?}");
+		assert!(completion.usage.sampled_tokens < 32, "{interrupt:?}");
+	}
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_interrupt_while_feeding() {
+	for interrupt in Interrupt::ALL {
+		// The prompt is fed in chunks of two tokens; the completion is interrupted after the first chunk, and checked
+		// before each chunk
+		let mut chunks = 0;
+		let completion = complete_interrupted(interrupt, "story", "Once upon a time, in a land far away, there lived a king", |r| {
+			if let InferenceResponse::PromptToken(_) = r {
+				chunks += 1;
+			}
+			chunks > 0
+		})
+		.await;
+		assert_eq!(chunks, 1, "{interrupt:?}");
+		assert_eq!(completion.usage.prompt_tokens, 2, "{interrupt:?}");
+		assert_eq!(completion.usage.sampled_tokens, 0, "{interrupt:?}");
+	}
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_interrupt_before_bias_prompt() {
+	for interrupt in Interrupt::ALL {
+		// The prompt is fed as a single chunk, so the completion is interrupted while the unbiased tokens are generated.
+		// These are never output, so there is no output at all.
+		let mut output = String::new();
+		complete_interrupted(interrupt, "verdict", "The sky is green.", |r| match r {
+			InferenceResponse::PromptToken(_) => true,
+			InferenceResponse::InferredToken(t) => {
+				output += t;
+				false
+			}
+			_ => false,
+		})
+		.await;
+		assert!(output.is_empty(), "{interrupt:?}");
+	}
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_cancel_from_other_thread() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let mut session = backend.start("story", &SessionRequest::default(), backend.clone()).unwrap();
+
+	// Another thread cancels the completion once it is told the first token was generated
+	let cancellation = Cancellation::new();
+	let (generating_sender, generating) = mpsc::channel();
+	let (cancelled_sender, cancelled) = mpsc::channel();
+	let canceller = {
+		let cancellation = cancellation.clone();
+		thread::spawn(move || {
+			generating.recv().unwrap();
+			cancellation.cancel();
+			cancelled_sender.send(()).unwrap();
+		})
+	};
+
+	let mut tokens = 0;
+	let completion = session
+		.complete_cancellable(&PromptRequest::new("Once upon a time"), &cancellation, |r| {
+			if let InferenceResponse::InferredToken(_) = r {
+				tokens += 1;
+				if tokens == 1 {
+					// Wait for the other thread, so that the cancellation certainly arrives while tokens are generated
+					generating_sender.send(()).unwrap();
+					cancelled.recv_timeout(Duration::from_secs(10)).unwrap();
+				}
+			}
+			Ok(InferenceFeedback::Continue)
+		})
+		.unwrap();
+	canceller.join().unwrap();
+	assert_eq!(completion.finish_reason, FinishReason::Cancelled);
+	assert!(tokens >= 1);
+	assert!(completion.usage.sampled_tokens < 32);
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	memory::Memory,
+	session::InferenceFeedback,
+	types::{BackendError, PromptRequest, SessionRequest},
+};
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+
+		[memories.facts]
+		store = { hora = {} }
+		dimensions = 768
+		embedding_model = "gpt2"
+
+		[tasks.recall]
+		model = "gpt2"
+		max_tokens = 4
+		memorization = { memory = "facts", store_prompts = true, retrieve = 1 }
+		"#,
+	)
+	.unwrap()
+}
+
+/// Complete a prompt for a task that both recalls from and stores prompts to memory
+fn complete(backend: &Arc<Backend>, prompt: &str) -> Result<(), BackendError> {
+	let mut session = backend.start("recall", &SessionRequest::default(), backend.clone())?;
+	session.complete(&PromptRequest::new(prompt), |_| Ok(InferenceFeedback::Continue))?;
+	Ok(())
+}
+
+async fn stored_items(backend: &Backend) -> usize {
+	backend.memories["facts"].stats().await.unwrap().items
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_memory_from_plain_thread() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let b = backend.clone();
+	let thread = std::thread::spawn(move || {
+		complete(&b, "The sky is blue").unwrap();
+		complete(&b, "The grass is green").unwrap();
+	});
+
+	// Wait for the thread without blocking a worker thread of the runtime, which performs the memory operations
+	tokio::task::spawn_blocking(move || thread.join()).await.unwrap().unwrap();
+	assert_eq!(stored_items(&backend).await, 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_memory_from_blocking_task() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let b = backend.clone();
+	tokio::task::spawn_blocking(move || {
+		complete(&b, "The sky is blue").unwrap();
+		complete(&b, "The grass is green").unwrap();
+	})
+	.await
+	.unwrap();
+	assert_eq!(stored_items(&backend).await, 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_memory_from_async_task() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	complete(&backend, "The sky is blue").unwrap();
+	complete(&backend, "The grass is green").unwrap();
+	assert_eq!(stored_items(&backend).await, 2);
+}
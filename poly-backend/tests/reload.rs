@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	session::InferenceFeedback,
+	types::{BackendError, PromptRequest, SessionRequest},
+};
+
+fn config(tasks: &str) -> BackendConfig {
+	from_toml_str(&format!(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+
+		{tasks}
+		"#
+	))
+	.unwrap()
+}
+
+const TASKS: &str = r#"
+	[tasks.kept]
+	model = "gpt2"
+	max_tokens = 1
+
+	[tasks.changed]
+	model = "gpt2"
+	prefix = "Old: "
+	max_tokens = 1
+
+	[tasks.removed]
+	model = "gpt2"
+	max_tokens = 1
+"#;
+
+const NEW_TASKS: &str = r#"
+	[tasks.kept]
+	model = "gpt2"
+	max_tokens = 1
+
+	[tasks.changed]
+	model = "gpt2"
+	prefix = "New: "
+	max_tokens = 1
+
+	[tasks.added]
+	model = "gpt2"
+	max_tokens = 1
+"#;
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_reload_tasks() {
+	let backend = Arc::new(Backend::from(config(TASKS), None).await);
+	let mut running = backend.start("changed", &SessionRequest::default(), backend.clone()).unwrap();
+
+	let report = backend.reload(&config(NEW_TASKS)).unwrap();
+	assert_eq!(report.added_tasks, vec!["added"]);
+	assert_eq!(report.changed_tasks, vec!["changed"]);
+	assert_eq!(report.removed_tasks, vec!["removed"]);
+	assert!(report.deferred.is_empty());
+
+	// New sessions use the new configuration; removed tasks stop accepting sessions
+	assert_eq!(backend.task("changed").unwrap().prefix.as_deref(), Some("New: "));
+	assert!(backend.start("added", &SessionRequest::default(), backend.clone()).is_ok());
+	assert!(matches!(
+		backend.start("removed", &SessionRequest::default(), backend.clone()),
+		Err(BackendError::TaskNotFound(_))
+	));
+
+	// Sessions that were already running keep the configuration they were started with
+	running
+		.complete(&PromptRequest::new("hello"), |_| Ok(InferenceFeedback::Continue))
+		.unwrap();
+	assert!(running.transcript().starts_with("Old: "), "{}", running.transcript());
+
+	// Reloading the same configuration again changes nothing
+	let report = backend.reload(&config(NEW_TASKS)).unwrap();
+	assert!(report.added_tasks.is_empty() && report.changed_tasks.is_empty() && report.removed_tasks.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_reload_deferred() {
+	let backend = Arc::new(Backend::from(config(TASKS), None).await);
+
+	// Changing a model requires loading its weights again, which only happens after a restart
+	let mut new_config = config(TASKS);
+	new_config.models.get_mut("gpt2").unwrap().threads_per_session += 1;
+	let report = backend.reload(&new_config).unwrap();
+	assert_eq!(report.deferred, vec!["models.gpt2"]);
+	assert!(report.added_tasks.is_empty() && report.changed_tasks.is_empty() && report.removed_tasks.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_reload_failed() {
+	let backend = Arc::new(Backend::from(config(TASKS), None).await);
+
+	// The new configuration adds and changes tasks, but one of its tasks uses a model that does not exist
+	let problems = backend
+		.reload(&config(&format!(
+			r#"
+			{NEW_TASKS}
+
+			[tasks.broken]
+			model = "gpt3"
+			"#
+		)))
+		.unwrap_err();
+	assert!(problems.iter().any(|p| p.to_string().contains("tasks.broken")), "{problems:?}");
+
+	// The previous configuration is still fully in effect
+	let mut task_names = backend.task_names();
+	task_names.sort();
+	assert_eq!(task_names, vec!["changed", "kept", "removed"]);
+	assert_eq!(backend.task("changed").unwrap().prefix.as_deref(), Some("Old: "));
+	assert!(backend.start("removed", &SessionRequest::default(), backend.clone()).is_ok());
+	assert!(backend.start("added", &SessionRequest::default(), backend.clone()).is_err());
+}
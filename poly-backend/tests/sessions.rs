@@ -0,0 +1,61 @@
+use std::{sync::Arc, thread};
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	session::InferenceFeedback,
+	types::{PromptRequest, SessionRequest},
+};
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		soft_session_limit = 2
+
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+
+		[tasks.chat]
+		model = "gpt2"
+		max_tokens = 4
+		"#,
+	)
+	.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_active_sessions() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let active = || (backend.stats.sessions.get(), backend.stats.model_stats()["gpt2"].sessions);
+
+	// Each session counts as active until it is dropped, also beyond the soft limit
+	let sessions: Vec<_> = (0..5)
+		.map(|_| backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap())
+		.collect();
+	assert_eq!(active(), (5, 5));
+	drop(sessions);
+	assert_eq!(active(), (0, 0));
+
+	// Sessions that fail to start are not counted
+	assert!(backend.start("missing", &SessionRequest::default(), backend.clone()).is_err());
+	assert_eq!(backend.stats.sessions.get(), 0);
+
+	// Sessions are no longer counted when the thread using them panics
+	let threads: Vec<_> = (0..4)
+		.map(|i| {
+			let mut session = backend.start("chat", &SessionRequest::default(), backend.clone()).unwrap();
+			thread::spawn(move || {
+				session
+					.complete(&PromptRequest::new("Hello"), |_| Ok(InferenceFeedback::Continue))
+					.unwrap();
+				if i % 2 == 0 {
+					panic!("generation thread panicked");
+				}
+			})
+		})
+		.collect();
+	let n_panicked = threads.into_iter().map(|t| t.join()).filter(|r| r.is_err()).count();
+	assert_eq!(n_panicked, 2);
+	assert_eq!(active(), (0, 0));
+}
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	types::BackendError,
+};
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+
+		[tasks.summary]
+		model = "gpt2"
+		postfix = "\nIn short:"
+		max_tokens = 4
+		seed = 42
+		stop_sequences = []
+		summarize = { chunk_tokens = 16 }
+
+		[tasks.summary_one_level]
+		model = "gpt2"
+		max_tokens = 4
+		stop_sequences = []
+		summarize = { chunk_tokens = 16, max_levels = 1 }
+
+		[tasks.summary_few_tokens]
+		model = "gpt2"
+		max_tokens = 4
+		stop_sequences = []
+		summarize = { chunk_tokens = 16, max_total_tokens = 20 }
+		"#,
+	)
+	.unwrap()
+}
+
+const DOCUMENT: &str = "The city council met on Tuesday to discuss the new park. Residents asked for more trees and benches. \
+	The mayor promised to publish a plan next month. Several shop owners worried about parking near the park. \
+	The council will vote on the budget for the park in the autumn, after a second round of consultation.";
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_summarize_levels() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+
+	// The parts are summarized, and the summaries summarized again, until a round results in a single summary
+	let mut progress = vec![];
+	let summary = backend.summarize("summary", DOCUMENT, |p| progress.push(*p)).unwrap();
+	assert!(summary.chunks > 1);
+	assert!(summary.levels.len() > 1);
+	assert_eq!(summary.levels[0].chunks, summary.chunks);
+	assert_eq!(summary.levels.last().unwrap().chunks, 1);
+	assert!(summary.levels.windows(2).all(|w| w[1].chunks < w[0].chunks));
+
+	// Progress is reported before each completion, in order
+	assert_eq!(progress.len(), summary.levels.iter().map(|l| l.chunks).sum::<usize>());
+	let mut expected = vec![];
+	for (level, l) in summary.levels.iter().enumerate() {
+		for chunk in 0..l.chunks {
+			expected.push((level, chunk, l.chunks));
+		}
+	}
+	let reported: Vec<(usize, usize, usize)> = progress.iter().map(|p| (p.level, p.chunk, p.chunks)).collect();
+	assert_eq!(reported, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_summarize_limits() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+
+	// More rounds would be needed than allowed
+	let mut progress = 0;
+	let result = backend.summarize("summary_one_level", DOCUMENT, |_| progress += 1);
+	assert!(matches!(result, Err(BackendError::SummaryLimitExceeded(_))), "{result:?}");
+	assert!(progress > 1);
+
+	// The document alone has more tokens than allowed, so nothing is completed
+	let mut progress = 0;
+	let result = backend.summarize("summary_few_tokens", DOCUMENT, |_| progress += 1);
+	assert!(matches!(result, Err(BackendError::SummaryLimitExceeded(_))), "{result:?}");
+	assert_eq!(progress, 0);
+
+	// An empty document cannot be summarized
+	let result = backend.summarize("summary", " \n ", |_| {});
+	assert!(matches!(result, Err(BackendError::InvalidDocument)), "{result:?}");
+}
@@ -0,0 +1,45 @@
+use poly_backend::{
+	backend::Backend,
+	config::from_toml_str,
+	types::{BackendError, PromptRequest},
+};
+
+#[tokio::test]
+pub async fn test_external_tokenizer() {
+	let config = from_toml_str(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+
+		[models.tiny]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+		tokenizer_path = "../data/tiny-tokenizer.json"
+
+		[models.missing]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+		tokenizer_path = "../data/does-not-exist.json"
+		"#,
+	)
+	.unwrap();
+	let backend = Backend::from(config, None).await;
+
+	// Models whose tokenizer does not match the vocabulary of the model, or cannot be found, are not available
+	let unavailable = backend.unavailable_models();
+	assert!(
+		unavailable["tiny"].contains("has 5 tokens, but the vocabulary of the model has 50257 tokens"),
+		"{}",
+		unavailable["tiny"]
+	);
+	assert!(unavailable["missing"].contains("tokenizer file not found"), "{}", unavailable["missing"]);
+	assert!(matches!(
+		backend.tokenize("tiny", &PromptRequest::new("hello world")),
+		Err(BackendError::ModelNotAvailable { .. })
+	));
+
+	// The same file loaded with a different tokenizer does not share the loaded model
+	assert!(backend.model_aliases.is_empty());
+	assert!(!backend.tokenize("gpt2", &PromptRequest::new("hello world")).unwrap().tokens.is_empty());
+}
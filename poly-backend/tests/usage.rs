@@ -0,0 +1,170 @@
+use std::{sync::Arc, time::Duration};
+
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	session::{InferenceFeedback, InferenceResponse},
+	types::{BackendError, FinishReason, PromptRequest, SessionRequest},
+};
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+
+		[tasks.status]
+		model = "gpt2"
+		prefix = "Status report: "
+
+		[tasks.status.biaser.json_schema]
+		type = "object"
+		required = ["status", "component"]
+
+		[tasks.status.biaser.json_schema.properties]
+		status = { type = "string", enum = ["acknowledged"] }
+		component = { type = "string", enum = ["storage subsystem"] }
+
+		[tasks.report]
+		model = "gpt2"
+		prefix = "Status report: "
+
+		[tasks.report.biaser.json_schema]
+		type = "object"
+		required = ["summary"]
+
+		[tasks.report.biaser.json_schema.properties.summary]
+		type = "string"
+		enum = ["all storage, network, scheduling, telemetry, authentication, billing and notification subsystems have been checked and are operating within their expected parameters, and no incidents have been reported during the last maintenance window"]
+
+		[tasks.verdict]
+		model = "gpt2"
+		prefix = "Statement: "
+		postfix = "\nReasoning:"
+		bias_prompt = "\nVerdict (true or false):"
+		max_tokens = 8
+		seed = 42
+		biaser = { json_schema = { type = "boolean" } }
+		"#,
+	)
+	.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_forced_tokens() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let mut session = backend.start("status", &SessionRequest::default(), backend.clone()).unwrap();
+
+	let mut output = String::new();
+	let completion = session
+		.complete(&PromptRequest::new("all systems nominal"), |r| {
+			if let InferenceResponse::InferredToken(t) = r {
+				output += &t;
+			}
+			Ok(InferenceFeedback::Continue)
+		})
+		.unwrap();
+	let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+	assert_eq!(value["status"], "acknowledged");
+
+	// Nearly all of the output is dictated by the schema, so most tokens are forced rather than sampled
+	let usage = completion.usage;
+	assert!(usage.forced_tokens > usage.sampled_tokens);
+	assert_eq!(usage.prompt_tokens, completion.stats.prompt_tokens);
+	assert_eq!(usage.sampled_tokens, completion.stats.predict_tokens);
+	assert!(usage.prompt_tokens > 0);
+
+	// The biaser runs for every generated token; sampling and evaluation only for sampled tokens
+	let timings = completion.timings;
+	assert!(timings.bias_duration > Duration::ZERO);
+	assert_eq!(timings.evaluate_duration.is_zero(), usage.sampled_tokens == 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_max_tokens_biased() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let mut session = backend.start("status", &SessionRequest::default(), backend.clone()).unwrap();
+
+	// When max_tokens is reached before the object is complete, the output is closed so that it is still valid
+	let request = PromptRequest {
+		max_tokens: Some(3),
+		..PromptRequest::new("all systems nominal")
+	};
+	let mut output = String::new();
+	let completion = session
+		.complete(&request, |r| {
+			if let InferenceResponse::InferredToken(t) = r {
+				output += &t;
+			}
+			Ok(InferenceFeedback::Continue)
+		})
+		.unwrap();
+	assert_eq!(completion.finish_reason, FinishReason::MaxTokens);
+	assert!(completion.force_closed);
+	let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+	assert!(value["status"].is_string());
+	assert!(value["component"].is_string());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_stop_sequence_biased() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let mut session = backend.start("status", &SessionRequest::default(), backend.clone()).unwrap();
+
+	// A stop sequence ends biased generation after the output is closed; the matched text stays part of the output
+	let request = PromptRequest {
+		stop: vec![String::from("acknowledged")],
+		..PromptRequest::new("all systems nominal")
+	};
+	let mut output = String::new();
+	let completion = session
+		.complete(&request, |r| {
+			if let InferenceResponse::InferredToken(t) = r {
+				output += &t;
+			}
+			Ok(InferenceFeedback::Continue)
+		})
+		.unwrap();
+	assert_eq!(completion.finish_reason, FinishReason::StopSequence);
+	let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+	assert_eq!(value["status"], "acknowledged");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_token_budget_exceeded() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let mut session = backend.start("report", &SessionRequest::default(), backend.clone()).unwrap();
+
+	// Closing the output would take more tokens than allowed beyond max_tokens
+	let request = PromptRequest {
+		max_tokens: Some(2),
+		..PromptRequest::new("all systems nominal")
+	};
+	let result = session.complete(&request, |_| Ok(InferenceFeedback::Continue));
+	assert!(matches!(result, Err(BackendError::TokenBudgetExceeded { overflow: 32 })));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn test_bias_prompt_usage() {
+	let backend = Arc::new(Backend::from(config(), None).await);
+	let mut session = backend.start("verdict", &SessionRequest::default(), backend.clone()).unwrap();
+	let prompt = "the moon is made of cheese";
+	let prompt_count = session.count_tokens(prompt).unwrap();
+	let bias_prompt_tokens = session.count_tokens("\nVerdict (true or false):").unwrap().prompt;
+
+	let completion = session
+		.complete(&PromptRequest::new(prompt), |_| Ok(InferenceFeedback::Continue))
+		.unwrap();
+
+	// The prompt and the bias prompt are counted once, the hidden unbiased generation as sampled tokens
+	let usage = completion.usage;
+	assert_eq!(usage.prompt_tokens, prompt_count.prompt + prompt_count.overhead + bias_prompt_tokens);
+	assert_eq!(usage.prompt_tokens, completion.stats.prompt_tokens);
+	assert_eq!(usage.sampled_tokens, completion.stats.predict_tokens);
+	assert!(usage.sampled_tokens > 1);
+
+	// Every token in the context window was either fed as prompt, sampled or forced
+	let used = session.count_tokens("").unwrap().used;
+	assert_eq!(usage.prompt_tokens + usage.sampled_tokens + usage.forced_tokens, used);
+}
@@ -0,0 +1,92 @@
+use poly_backend::{
+	backend::Backend,
+	config::{from_toml_str, BackendConfig},
+	types::{BackendError, PromptRequest, PromptViolation, SessionRequest},
+};
+use serde_json::json;
+
+fn config() -> BackendConfig {
+	from_toml_str(
+		r#"
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "../data/gpt2.bin"
+		context_size = 64
+
+		[tasks.chat]
+		model = "gpt2"
+		prelude = "A conversation."
+		prefix = "User: "
+		postfix = "\nAssistant:"
+		private_tokens = ["<|endoftext|>"]
+
+		[tasks.autocomplete]
+		model = "gpt2"
+		prefix = "Complete: "
+		max_prompt_tokens = 8
+		"#,
+	)
+	.unwrap()
+}
+
+fn prompt(request: serde_json::Value) -> PromptRequest {
+	serde_json::from_value(request).unwrap()
+}
+
+#[tokio::test]
+pub async fn test_validate() {
+	let backend = Backend::from(config(), None).await;
+	let session = SessionRequest::default();
+
+	let report = backend.validate("chat", &session, &PromptRequest::new("Hello")).unwrap();
+	assert!(report.valid);
+	assert!(report.violations.is_empty());
+	assert!(report.prompt_tokens > 0);
+	assert!(report.overhead_tokens > 0);
+	assert!(report.prelude_tokens > 0);
+	assert_eq!(report.context_size, 64);
+	assert!(report.parameters.is_some());
+
+	// Private tokens are only allowed in trusted segments, and are reported with their position
+	let request = prompt(json!({
+		"segments": [
+			{ "text": "<|endoftext|>", "trusted": true },
+			{ "text": "Hello" },
+			{ "text": "<|endoftext|>" },
+		]
+	}));
+	let report = backend.validate("chat", &session, &request).unwrap();
+	assert!(!report.valid);
+	assert_eq!(
+		report.violations,
+		vec![PromptViolation::IllegalToken {
+			token: String::from("<|endoftext|>"),
+			segment: 2,
+			offset: 0,
+		}]
+	);
+
+	// All problems are reported at once
+	let request = prompt(json!({ "prompt": "Hello ".repeat(100), "max_lines": 0 }));
+	let report = backend.validate("chat", &session, &request).unwrap();
+	assert!(report.parameters.is_none());
+	assert!(matches!(report.violations[0], PromptViolation::InvalidParameter { ref parameter, .. } if parameter == "max_lines"));
+	assert!(matches!(report.violations[1], PromptViolation::ContextFull { needed, available } if needed > available));
+
+	// Prompts longer than the task allows are rejected, unless the request allows cutting them off
+	let report = backend
+		.validate("autocomplete", &session, &PromptRequest::new("Hello ".repeat(10)))
+		.unwrap();
+	assert!(!report.valid);
+	assert!(matches!(report.violations[..], [PromptViolation::PromptTooLong { needed, allowed: 8 }] if needed > 8));
+	let request = prompt(json!({ "prompt": "Hello ".repeat(10), "truncate": true }));
+	let report = backend.validate("autocomplete", &session, &request).unwrap();
+	assert!(report.valid);
+	assert!(report.truncated_tokens > 0);
+	assert_eq!(report.prompt_tokens + report.overhead_tokens, 8);
+
+	assert!(matches!(
+		backend.validate("missing", &session, &PromptRequest::new("Hello")),
+		Err(BackendError::TaskNotFound(_))
+	));
+}
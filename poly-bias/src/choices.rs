@@ -0,0 +1,105 @@
+use std::sync::OnceLock;
+
+use llm::{TokenId, Tokenizer};
+
+use crate::{Biaser, TOKEN_ALLOWED};
+
+/// A biaser that constrains the output to be exactly one of a fixed set of strings (e.g. the labels of a classification).
+/// The output is matched against the choices byte by byte, so that a choice can be generated using any sequence of tokens
+/// that spells it (not just the one the tokenizer would produce). When a choice is complete and no other choice continues
+/// it, only the end-of-text token is allowed. When a longer choice does continue it, the longer choice can still be
+/// generated; the output only ends at the shorter choice when the model ends it (or generation has to end).
+#[derive(Debug)]
+pub struct ChoicesBiaser {
+	choices: Vec<String>,
+
+	/// The bytes generated so far
+	output: Vec<u8>,
+
+	/// The tokens of the vocabulary that occur in any of the choices, looked up on first use
+	candidates: OnceLock<Vec<(TokenId, Vec<u8>)>>,
+}
+
+impl ChoicesBiaser {
+	pub fn new(choices: Vec<String>) -> ChoicesBiaser {
+		ChoicesBiaser {
+			choices,
+			output: vec![],
+			candidates: OnceLock::new(),
+		}
+	}
+
+	fn candidates(&self, vocabulary: &Tokenizer) -> &[(TokenId, Vec<u8>)] {
+		self.candidates.get_or_init(|| {
+			let candidates: Vec<(TokenId, Vec<u8>)> = (0..vocabulary.len())
+				.map(|token| (token as TokenId, vocabulary.token(token)))
+				.filter(|(_, bytes)| {
+					!bytes.is_empty()
+						&& self
+							.choices
+							.iter()
+							.any(|choice| choice.as_bytes().windows(bytes.len()).any(|window| window == bytes.as_slice()))
+				})
+				.collect();
+			tracing::debug!("total tokens: {} occurring in choices: {}", vocabulary.len(), candidates.len());
+			candidates
+		})
+	}
+
+	/// The rest of each choice that starts with the output so far (empty for a choice that is complete)
+	fn remainders(&self) -> impl Iterator<Item = &[u8]> {
+		self.choices
+			.iter()
+			.filter_map(|choice| choice.as_bytes().strip_prefix(self.output.as_slice()))
+	}
+
+	/// Whether the output so far can be followed by `bytes` (which need not consist of whole characters)
+	pub fn allows(&self, bytes: &[u8]) -> bool {
+		self.remainders().any(|remainder| remainder.starts_with(bytes))
+	}
+
+	/// Append `bytes` to the output (which must be allowed, see [`ChoicesBiaser::allows`])
+	pub fn advance_bytes(&mut self, bytes: &[u8]) {
+		debug_assert!(self.allows(bytes), "bytes are not allowed by any of the choices");
+		self.output.extend_from_slice(bytes);
+	}
+}
+
+impl Biaser for ChoicesBiaser {
+	fn bias(&self, vocabulary: &Tokenizer, eot_token: TokenId) -> Vec<(TokenId, f32)> {
+		let mut next_valid_tokens: Vec<(TokenId, f32)> = self
+			.candidates(vocabulary)
+			.iter()
+			.filter(|(token, bytes)| *token != eot_token && self.allows(bytes))
+			.map(|(token, _)| (*token, TOKEN_ALLOWED))
+			.collect();
+
+		if self.choice().is_some() {
+			next_valid_tokens.push((eot_token, TOKEN_ALLOWED));
+		}
+		next_valid_tokens
+	}
+
+	fn advance(&mut self, vocabulary: &Tokenizer, token: TokenId) {
+		self.advance_bytes(&vocabulary.token(token as usize));
+	}
+
+	fn closing_tokens(&self, vocabulary: &Tokenizer) -> Option<Vec<TokenId>> {
+		// The rest of the shortest choice that can still be generated, as the tokenizer would spell it
+		self.remainders()
+			.filter_map(|remainder| {
+				if remainder.is_empty() {
+					return Some(vec![]);
+				}
+				let text = std::str::from_utf8(remainder).ok()?;
+				let tokens = vocabulary.tokenize(text, false).ok()?;
+				let spelled: Vec<u8> = tokens.iter().flat_map(|(bytes, _)| bytes.iter().copied()).collect();
+				(spelled == remainder).then(|| tokens.into_iter().map(|(_, token_id)| token_id).collect())
+			})
+			.min_by_key(Vec::len)
+	}
+
+	fn choice(&self) -> Option<usize> {
+		self.choices.iter().position(|choice| choice.as_bytes() == self.output)
+	}
+}
@@ -1,6 +1,7 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::sync::{Arc, OnceLock};
 
 use llm::TokenizationError;
 use llm::{TokenId, Tokenizer};
@@ -9,9 +10,9 @@ use serde_json::Value;
 use serde_json::{json, Map};
 use thiserror::Error;
 
-use crate::{Biaser, TOKEN_ALLOWED};
+use crate::{regex::RegexBiaser, vocabulary::VocabularyInfo, Biaser, TOKEN_ALLOWED};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum JsonSchema {
 	Boolean,
@@ -24,6 +25,34 @@ pub enum JsonSchema {
 		min: Option<f64>,
 		max: Option<f64>,
 		max_decimals: Option<usize>,
+
+		/// Bounds the number must stay above and below (without being equal to them)
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		exclusive_min: Option<f64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		exclusive_max: Option<f64>,
+
+		/// A number the number must be a multiple of (must be larger than zero)
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		multiple_of: Option<f64>,
+
+		/// The numbers allowed (when set, the other constraints are not used for generation)
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		r#enum: Option<Vec<f64>>,
+	},
+
+	/// A number without decimals
+	Integer {
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		min: Option<f64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		max: Option<f64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		exclusive_min: Option<f64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		exclusive_max: Option<f64>,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		multiple_of: Option<f64>,
 	},
 	Array {
 		items: Box<JsonSchema>,
@@ -31,8 +60,33 @@ pub enum JsonSchema {
 		max_items: Option<usize>,
 	},
 	String {
+		/// The minimum number of characters (Unicode scalar values, not bytes)
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		min_length: Option<usize>,
+
+		/// The maximum number of characters (Unicode scalar values, not bytes)
 		max_length: Option<usize>,
 		r#enum: Option<Vec<String>>,
+
+		/// A regular expression the string must match as a whole (unlike in JSON Schema, where a pattern may match any
+		/// part of the string)
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		pattern: Option<String>,
+	},
+
+	/// A value that is valid according to exactly one of the schemas (e.g. objects that differ in the value of a key)
+	OneOf {
+		one_of: Vec<JsonSchema>,
+	},
+
+	/// One of the listed values, which may be of different types (e.g. strings and numbers)
+	Enum {
+		r#enum: Vec<Value>,
+	},
+
+	/// Exactly the given value
+	Const {
+		r#const: Value,
 	},
 }
 
@@ -70,23 +124,405 @@ impl JsonSchema {
 				}
 				return array_items.iter().all(|item| items.is_valid(item));
 			}
-			(JsonSchema::Number { min, max, .. }, Value::Number(v)) => {
-				if let Some(min) = min {
-					if v.as_f64().unwrap() < *min {
-						return false;
-					}
+			(JsonSchema::Number { r#enum: Some(values), .. }, Value::Number(v)) => values.iter().any(|value| Some(*value) == v.as_f64()),
+			(
+				JsonSchema::Number {
+					min,
+					max,
+					exclusive_min,
+					exclusive_max,
+					multiple_of,
+					..
+				},
+				Value::Number(v),
+			) => is_within_bounds(v.as_f64().unwrap(), *min, *max, *exclusive_min, *exclusive_max, *multiple_of),
+			(
+				JsonSchema::Integer {
+					min,
+					max,
+					exclusive_min,
+					exclusive_max,
+					multiple_of,
+				},
+				Value::Number(v),
+			) => {
+				let v = v.as_f64().unwrap();
+				v.fract() == 0.0 && is_within_bounds(v, *min, *max, *exclusive_min, *exclusive_max, *multiple_of)
+			}
+			(
+				JsonSchema::String {
+					min_length,
+					max_length,
+					pattern,
+					..
+				},
+				Value::String(s),
+			) => {
+				let length = s.chars().count();
+				min_length.map_or(true, |min_length| length >= min_length)
+					&& max_length.map_or(true, |max_length| length <= max_length)
+					&& pattern
+						.as_ref()
+						.map_or(true, |pattern| RegexBiaser::new(pattern).is_ok_and(|regex| regex.matches(s.as_bytes())))
+			}
+			(JsonSchema::OneOf { one_of }, value) => one_of.iter().filter(|schema| schema.is_valid(value)).count() == 1,
+			(JsonSchema::Enum { r#enum }, value) => r#enum.contains(value),
+			(JsonSchema::Const { r#const }, value) => r#const == value,
+			_ => false,
+		}
+	}
+
+	/// Express this schema as a standard JSON Schema document (draft 2020-12), e.g. to validate values with a full
+	/// validator. Objects do not allow keys that have no schema, as in [`JsonSchema::is_valid`].
+	pub fn to_json_schema(&self) -> Value {
+		match self {
+			JsonSchema::Boolean => json!({ "type": "boolean" }),
+			JsonSchema::Null => json!({ "type": "null" }),
+			JsonSchema::Object { required, properties } => {
+				let properties: Map<String, Value> = properties.iter().map(|(key, schema)| (key.clone(), schema.to_json_schema())).collect();
+				json!({
+					"type": "object",
+					"required": required,
+					"properties": properties,
+					"additionalProperties": false,
+				})
+			}
+			JsonSchema::Number {
+				min,
+				max,
+				exclusive_min,
+				exclusive_max,
+				multiple_of,
+				r#enum,
+				..
+			} => {
+				let mut schema = number_json_schema("number", *min, *max, *exclusive_min, *exclusive_max, *multiple_of);
+				if let Some(values) = r#enum {
+					schema.insert(String::from("enum"), json!(values));
 				}
-				if let Some(max) = max {
-					if v.as_f64().unwrap() > *max {
-						return false;
-					}
+				Value::Object(schema)
+			}
+			JsonSchema::Integer {
+				min,
+				max,
+				exclusive_min,
+				exclusive_max,
+				multiple_of,
+			} => Value::Object(number_json_schema("integer", *min, *max, *exclusive_min, *exclusive_max, *multiple_of)),
+			JsonSchema::Array { items, min_items, max_items } => {
+				let mut schema = Map::from_iter([(String::from("type"), json!("array")), (String::from("items"), items.to_json_schema())]);
+				if let Some(min_items) = min_items {
+					schema.insert(String::from("minItems"), json!(min_items));
+				}
+				if let Some(max_items) = max_items {
+					schema.insert(String::from("maxItems"), json!(max_items));
 				}
-				true
+				Value::Object(schema)
 			}
-			(JsonSchema::String { .. }, Value::String(_s)) => true,
-			_ => false,
+			JsonSchema::String {
+				min_length,
+				max_length,
+				r#enum,
+				pattern,
+			} => {
+				let mut schema = Map::from_iter([(String::from("type"), json!("string"))]);
+				if let Some(min_length) = min_length {
+					schema.insert(String::from("minLength"), json!(min_length));
+				}
+				if let Some(max_length) = max_length {
+					schema.insert(String::from("maxLength"), json!(max_length));
+				}
+				if let Some(values) = r#enum {
+					schema.insert(String::from("enum"), json!(values));
+				}
+				if let Some(pattern) = pattern {
+					// Anchor the pattern, as it must match the string as a whole
+					schema.insert(String::from("pattern"), json!(format!("^(?:{pattern})$")));
+				}
+				Value::Object(schema)
+			}
+			JsonSchema::OneOf { one_of } => json!({ "oneOf": one_of.iter().map(JsonSchema::to_json_schema).collect::<Vec<_>>() }),
+			JsonSchema::Enum { r#enum } => json!({ "enum": r#enum }),
+			JsonSchema::Const { r#const } => json!({ "const": r#const }),
+		}
+	}
+
+	/// The JSON text of each of the values this schema allows, when it only allows a fixed set of values (an enum or a
+	/// constant)
+	fn literals(&self) -> Option<Vec<String>> {
+		match self {
+			JsonSchema::Enum { r#enum } => Some(r#enum.iter().map(Value::to_string).collect()),
+			JsonSchema::Const { r#const } => Some(vec![r#const.to_string()]),
+			JsonSchema::Number { r#enum: Some(values), .. } => Some(values.iter().map(f64::to_string).collect()),
+			_ => None,
 		}
 	}
+
+	/// The numbers a number or integer schema allows while generating (`None` for other schemas)
+	fn number_bounds(&self) -> Option<NumberBounds> {
+		match self {
+			JsonSchema::Number {
+				min,
+				max,
+				max_decimals,
+				exclusive_min,
+				exclusive_max,
+				multiple_of,
+				..
+			} => Some(NumberBounds::new(
+				max_decimals.unwrap_or(0),
+				*min,
+				*max,
+				*exclusive_min,
+				*exclusive_max,
+				*multiple_of,
+			)),
+			JsonSchema::Integer {
+				min,
+				max,
+				exclusive_min,
+				exclusive_max,
+				multiple_of,
+			} => Some(NumberBounds::new(0, *min, *max, *exclusive_min, *exclusive_max, *multiple_of)),
+			_ => None,
+		}
+	}
+}
+
+/// Whether a number is within the bounds of a number or integer schema
+fn is_within_bounds(
+	value: f64,
+	min: Option<f64>,
+	max: Option<f64>,
+	exclusive_min: Option<f64>,
+	exclusive_max: Option<f64>,
+	multiple_of: Option<f64>,
+) -> bool {
+	min.map_or(true, |min| value >= min)
+		&& max.map_or(true, |max| value <= max)
+		&& exclusive_min.map_or(true, |exclusive_min| value > exclusive_min)
+		&& exclusive_max.map_or(true, |exclusive_max| value < exclusive_max)
+		&& multiple_of.map_or(true, |multiple_of| {
+			let quotient = value / multiple_of;
+			(quotient - quotient.round()).abs() < 1e-9
+		})
+}
+
+/// The JSON Schema document for a number or integer schema
+fn number_json_schema(
+	r#type: &str,
+	min: Option<f64>,
+	max: Option<f64>,
+	exclusive_min: Option<f64>,
+	exclusive_max: Option<f64>,
+	multiple_of: Option<f64>,
+) -> Map<String, Value> {
+	let mut schema = Map::from_iter([(String::from("type"), json!(r#type))]);
+	for (keyword, value) in [
+		("minimum", min),
+		("maximum", max),
+		("exclusiveMinimum", exclusive_min),
+		("exclusiveMaximum", exclusive_max),
+		("multipleOf", multiple_of),
+	] {
+		if let Some(value) = value {
+			schema.insert(String::from(keyword), json!(value));
+		}
+	}
+	schema
+}
+
+/// The largest number of decimals numbers are generated with (more cannot be represented precisely)
+const MAX_DECIMALS: usize = 15;
+
+/// The largest number of digits before the decimal point (numbers are limited to what fits in a 32 bit integer)
+const MAX_INTEGER_DIGITS: usize = 10;
+
+/// The numbers a number (or integer) schema allows while generating. Numbers are counted in steps of the smallest
+/// fraction they can have (e.g. hundredths for numbers with at most two decimals), so that bounds are checked exactly
+/// instead of with floating point numbers.
+#[derive(Debug, Clone, Copy)]
+struct NumberBounds {
+	/// The maximum number of decimals
+	decimals: usize,
+
+	/// The number of steps in one
+	scale: i128,
+
+	/// The smallest and largest allowed number of steps (inclusive)
+	min: i128,
+	max: i128,
+
+	/// The number of steps must be a multiple of this
+	step: i128,
+}
+
+impl NumberBounds {
+	fn new(
+		decimals: usize,
+		min: Option<f64>,
+		max: Option<f64>,
+		exclusive_min: Option<f64>,
+		exclusive_max: Option<f64>,
+		multiple_of: Option<f64>,
+	) -> NumberBounds {
+		let decimals = decimals.min(MAX_DECIMALS);
+		let scale = 10i128.pow(decimals as u32);
+
+		// Bounds are rounded to whole steps; a bound that is (nearly) a whole number of steps is not rounded, so that
+		// e.g. 0.1 is exactly ten hundredths
+		let to_steps = |value: f64, round: fn(f64) -> f64| {
+			let steps = value * scale as f64;
+			if (steps - steps.round()).abs() < 1e-6 {
+				(steps.round() as i128, true)
+			} else {
+				(round(steps) as i128, false)
+			}
+		};
+
+		let limit = u32::MAX as i128 * scale - 1;
+		let mut min_steps = -limit;
+		let mut max_steps = limit;
+		if let Some(min) = min {
+			min_steps = min_steps.max(to_steps(min, f64::ceil).0);
+		}
+		if let Some(exclusive_min) = exclusive_min {
+			let (steps, exact) = to_steps(exclusive_min, f64::ceil);
+			min_steps = min_steps.max(if exact { steps + 1 } else { steps });
+		}
+		if let Some(max) = max {
+			max_steps = max_steps.min(to_steps(max, f64::floor).0);
+		}
+		if let Some(exclusive_max) = exclusive_max {
+			let (steps, exact) = to_steps(exclusive_max, f64::floor);
+			max_steps = max_steps.min(if exact { steps - 1 } else { steps });
+		}
+
+		NumberBounds {
+			decimals,
+			scale,
+			min: min_steps,
+			max: max_steps,
+			step: multiple_of.map_or(1, |multiple_of| multiple_steps(multiple_of, decimals)),
+		}
+	}
+
+	/// The sign and the digits before and after the decimal point of a (possibly incomplete) number, when it is written
+	/// in a way this schema allows (no leading zeros, and not too many digits)
+	fn parts<'a>(&self, text: &'a str) -> Option<(bool, &'a str, Option<&'a str>)> {
+		let (negative, digits) = match text.strip_prefix('-') {
+			Some(digits) => (true, digits),
+			None => (false, text),
+		};
+		let (integer, fraction) = match digits.split_once('.') {
+			Some((integer, fraction)) => (integer, Some(fraction)),
+			None => (digits, None),
+		};
+		let valid = integer.chars().all(|c| c.is_ascii_digit())
+			&& integer.len() <= MAX_INTEGER_DIGITS
+			&& (integer == "0" || !integer.starts_with('0'))
+			&& fraction.map_or(true, |fraction| {
+				self.decimals > 0 && !integer.is_empty() && fraction.len() <= self.decimals && fraction.chars().all(|c| c.is_ascii_digit())
+			});
+		valid.then_some((negative, integer, fraction))
+	}
+
+	/// The number of steps of a positive number with the given digits before and after the decimal point
+	fn steps(&self, integer: &str, fraction: Option<&str>) -> i128 {
+		let fraction = fraction.unwrap_or("");
+		let integer_steps = integer.parse::<i128>().unwrap_or(0) * self.scale;
+		let fraction_steps = fraction.parse::<i128>().unwrap_or(0) * 10i128.pow((self.decimals - fraction.len()) as u32);
+		integer_steps + fraction_steps
+	}
+
+	/// Whether any allowed number of steps lies within `from..=to`
+	fn any_within(&self, from: i128, to: i128) -> bool {
+		let (from, to) = (from.max(self.min), to.min(self.max));
+		let first_multiple = from + (self.step - from.rem_euclid(self.step)) % self.step;
+		first_multiple <= to
+	}
+
+	/// Whether the number so far (e.g. "-", "12" or "0.5") can still become an allowed number
+	fn allows(&self, prefix: &str) -> bool {
+		let Some((negative, integer, fraction)) = self.parts(prefix) else {
+			return false;
+		};
+
+		// The ranges of (the number of steps of) the positive numbers that start with the digits so far
+		let ranges: Vec<(i128, i128)> = match (integer, fraction) {
+			("", _) => vec![(0, i128::MAX)],
+			(integer, Some(fraction)) => {
+				let from = self.steps(integer, Some(fraction));
+				vec![(from, from + 10i128.pow((self.decimals - fraction.len()) as u32) - 1)]
+			}
+			// After a leading zero, only decimals can follow
+			("0", None) => vec![(0, self.scale - 1)],
+			// Any number of digits may follow (e.g. "12" can become 12 to 12.99, 120 to 129.99, 1200 to 1299.99, ...)
+			(integer, None) => {
+				let largest = self.min.abs().max(self.max.abs());
+				let from = self.steps(integer, None);
+				(0..=(MAX_INTEGER_DIGITS - integer.len()) as u32)
+					.map(|digits| 10i128.pow(digits))
+					.map(|factor| (from * factor, (from + self.scale) * factor - 1))
+					.take_while(|(from, _)| *from <= largest)
+					.collect()
+			}
+		};
+
+		// Negative numbers cannot be zero (i.e. "-0" may only be followed by decimals that are not all zero)
+		ranges.into_iter().any(|(from, to)| {
+			if negative {
+				self.any_within(-to, -from.max(1))
+			} else {
+				self.any_within(from, to)
+			}
+		})
+	}
+
+	/// Whether the number so far is complete and allowed
+	fn can_end(&self, text: &str) -> bool {
+		let Some((negative, integer, fraction)) = self.parts(text) else {
+			return false;
+		};
+		if integer.is_empty() || fraction == Some("") {
+			return false;
+		}
+		let steps = self.steps(integer, fraction);
+		if negative && steps == 0 {
+			return false;
+		}
+		let steps = if negative { -steps } else { steps };
+		self.any_within(steps, steps)
+	}
+}
+
+/// The number of steps (see [`NumberBounds`]) of which the number of steps of a number must be a multiple, for it to
+/// be a multiple of `multiple_of`
+fn multiple_steps(multiple_of: f64, decimals: usize) -> i128 {
+	// Write the multiple as a whole number divided by a power of ten (e.g. 0.25 = 25 / 10^2)
+	let Some((numerator, exponent)) = (0..=MAX_DECIMALS).find_map(|exponent| {
+		let scaled = multiple_of * 10f64.powi(exponent as i32);
+		((scaled - scaled.round()).abs() < 1e-6 && scaled.round() >= 1.0).then_some((scaled.round() as i128, exponent))
+	}) else {
+		tracing::warn!("ignoring multiple_of {multiple_of}, which is not a positive number with at most {MAX_DECIMALS} decimals");
+		return 1;
+	};
+
+	if exponent <= decimals {
+		numerator * 10i128.pow((decimals - exponent) as u32)
+	} else {
+		// A number of steps is a multiple when multiplied by the power of ten it is divisible by the numerator
+		let power = 10i128.pow((exponent - decimals) as u32);
+		numerator / gcd(numerator, power)
+	}
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+	if b == 0 {
+		a
+	} else {
+		gcd(b, a % b)
+	}
 }
 
 #[derive(Clone)]
@@ -137,10 +573,27 @@ enum JsonParserState<'schema> {
 
 	/// Inside a string
 	InString(String),
+
+	/// Inside a value that may match any of the schemas of a [`JsonSchema::OneOf`]; holds a parser for each of the schemas
+	/// that the input so far matches
+	InOneOf(Vec<JsonBiaser<'schema>>),
+
+	/// Inside one of the values of an enum or constant (holds the text of the value so far)
+	InLiteral(String),
 }
 
 impl<'schema> Biaser for JsonBiaser<'schema> {
 	fn bias(&self, vocabulary: &Tokenizer, eot_token: TokenId) -> Vec<(TokenId, f32)> {
+		let analyzed;
+		let info = match self.vocabulary {
+			Some(ref info) => info.as_ref(),
+			None => {
+				analyzed = VocabularyInfo::new(vocabulary);
+				&analyzed
+			}
+		};
+		debug_assert_eq!(info.len(), vocabulary.len(), "vocabulary info is for another vocabulary");
+
 		let next_valid_json_tokens = self.next_valid_tokens();
 		tracing::trace!("next valid tokens: {:?}", next_valid_json_tokens);
 
@@ -155,69 +608,71 @@ impl<'schema> Biaser for JsonBiaser<'schema> {
 						"no empty strings allowed in JSONToken::AnyOf"
 					);
 
-					let valid_tokens: Vec<TokenId> = (0..=(vocabulary.len() - 1) as TokenId)
+					let max_length = string_values.iter().map(|s| s.chars().count()).max();
+					let valid_tokens: Vec<TokenId> = info
+						.string_tokens(max_length)
+						.iter()
+						.copied()
 						.filter(|token_id| {
-							if *token_id == eot_token {
-								return false;
-							}
-							let bytes = vocabulary.token(*token_id as usize);
-							let Ok(s) = String::from_utf8(bytes) else {
-								return false;
-							};
-
-							if s.is_empty() {
-								return false;
-							}
-
-							if s.contains('\"') || s.contains('\n') || s.contains('\t') || s.contains('\r') {
-								return false;
-							}
-
-							return string_values.iter().any(|sv| sv.starts_with(&s));
+							*token_id != eot_token && info.text(*token_id).is_some_and(|s| string_values.iter().any(|sv| sv.starts_with(s)))
 						})
 						.collect();
 
-					tracing::debug!("any-of: total tokens: {} valid: {}", vocabulary.len(), valid_tokens.len());
+					tracing::debug!("any-of: total tokens: {} valid: {}", info.len(), valid_tokens.len());
 					tracing::trace!("any-of prefixes: {string_values:?} valid: {valid_tokens:?}");
 
 					valid_tokens.iter().map(|vt| (*vt, TOKEN_ALLOWED)).collect()
 				}
 
-				// Basically any token is allowed if it fits the max length. Filter them from the vocabulary
-				JsonToken::AnyString { max_length } => {
-					let mut valid_tokens: Vec<TokenId> = (0..=(vocabulary.len() - 1) as TokenId)
+				// Any token that spells the start of the rest of one of the values is allowed. When only one value is left,
+				// only the longest such token is, so that the value is emitted without sampling.
+				JsonToken::Literal(literals) => {
+					let valid_tokens: Vec<TokenId> = (0..info.len() as TokenId)
 						.filter(|token_id| {
-							if *token_id == eot_token {
-								return false;
-							}
-							let bytes = vocabulary.token(*token_id as usize);
-							let Ok(s) = String::from_utf8(bytes) else {
-								return false;
-							};
-
-							// Reject tokens that would make the string go over the maximum length
-							if let Some(max_length) = max_length {
-								if *max_length < s.len() {
-									return false;
-								}
-							}
-
-							if s.contains('\"') || s.contains('\n') || s.contains('\t') || s.contains('\r') {
-								return false;
-							}
-							true
+							*token_id != eot_token
+								&& info
+									.text(*token_id)
+									.is_some_and(|s| !s.is_empty() && literals.iter().any(|l| l.starts_with(s)))
 						})
 						.collect();
+					tracing::trace!("literals: {literals:?} valid: {valid_tokens:?}");
+
+					match literals.as_slice() {
+						[_] => valid_tokens
+							.into_iter()
+							.max_by_key(|token_id| info.text(*token_id).map_or(0, str::len))
+							.map(|token_id| (token_id, TOKEN_ALLOWED))
+							.into_iter()
+							.collect(),
+						_ => valid_tokens.iter().map(|vt| (*vt, TOKEN_ALLOWED)).collect(),
+					}
+				}
 
-					valid_tokens.push(JsonToken::DoubleQuote.token_id(vocabulary).unwrap());
+				// Basically any token is allowed if it fits the max length (which rejects tokens that would make the
+				// string go over the maximum length, even when their first characters would fit) and keeps the pattern
+				// satisfiable. The closing quote is a separate next valid token (when the string may end).
+				JsonToken::AnyString { max_length, pattern, .. } => {
+					let valid_tokens: Vec<TokenId> = info
+						.string_tokens(*max_length)
+						.iter()
+						.copied()
+						.filter(|token_id| {
+							*token_id != eot_token
+								&& pattern
+									.as_ref()
+									.map_or(true, |pattern| info.text(*token_id).is_some_and(|s| pattern.allows(s.as_bytes())))
+						})
+						.collect();
 
-					tracing::debug!("total tokens: {} valid: {}", vocabulary.len(), valid_tokens.len());
+					tracing::debug!("total tokens: {} valid: {}", info.len(), valid_tokens.len());
 
 					valid_tokens.iter().map(|vt| (*vt, TOKEN_ALLOWED)).collect()
 				}
 				json_token => {
 					vec![(
-						(*json_token).token_id(vocabulary).unwrap_or_else(|| panic!("token id for {json_token}")),
+						info.json_token(json_token)
+							.or_else(|| json_token.token_id(vocabulary))
+							.unwrap_or_else(|| panic!("token id for {json_token}")),
 						TOKEN_ALLOWED,
 					)]
 				}
@@ -235,27 +690,40 @@ impl<'schema> Biaser for JsonBiaser<'schema> {
 		self.advance(&out_json_token).unwrap();
 		tracing::debug!("Token: {:?}, next valid tokens: {:?}", &out_json_token, self.next_valid_tokens());
 	}
+
+	fn closing_tokens(&self, vocabulary: &Tokenizer) -> Option<Vec<TokenId>> {
+		let mut tokens = vec![];
+		for json_token in self.closing_sequence()? {
+			let text = json_token.to_string().expect("closing sequence only contains concrete tokens");
+			tokens.extend(vocabulary.tokenize(&text, false).ok()?.into_iter().map(|(_, token_id)| token_id));
+		}
+		Some(tokens)
+	}
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JsonBiaser<'schema> {
 	schema: &'schema JsonSchema,
 	state: JsonParserState<'schema>,
-}
 
-impl<'schema> Clone for JsonBiaser<'schema> {
-	fn clone(&self) -> Self {
-		Self {
-			schema: self.schema,
-			state: JsonParserState::Start,
-		}
-	}
+	/// The analysis of the vocabulary of the model that is shared between biasers (see [`JsonBiaser::with_vocabulary`])
+	vocabulary: Option<Arc<VocabularyInfo>>,
+
+	/// The pattern of a string schema, compiled when first needed
+	pattern: OnceLock<Option<RegexBiaser>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum JsonToken {
-	AnyString { max_length: Option<usize> }, // Any string except double quote (used in next_valid_token)
-	AnyOf(Vec<String>),                      // Any string from the list (or a prefix of it)
+	// Any string except double quote (used in next_valid_token). The lengths are the number of characters still needed
+	// and allowed; the pattern is in the state the string so far brings it in.
+	AnyString {
+		min_length: usize,
+		max_length: Option<usize>,
+		pattern: Option<RegexBiaser>,
+	},
+	AnyOf(Vec<String>),   // Any string from the list (or a prefix of it)
+	Literal(Vec<String>), // Any text from the list (or a prefix of it), including quotes and separators
 	BracketClose,
 	BracketOpen,
 	Colon,
@@ -315,7 +783,7 @@ impl JsonToken {
 			JsonToken::Digit(n) => Cow::from(format!("{n}")),
 			JsonToken::DoubleQuote => Cow::from("\""),
 			JsonToken::String(s) => Cow::from(s.clone()),
-			JsonToken::AnyString { .. } | JsonToken::AnyOf(_) => return None,
+			JsonToken::AnyString { .. } | JsonToken::AnyOf(_) | JsonToken::Literal(_) => return None,
 		})
 	}
 
@@ -341,11 +809,38 @@ impl JsonToken {
 	}
 }
 
+impl JsonToken {
+	/// Whether `token` is allowed where this token is a valid next token (e.g. a prefix of one of the strings of an
+	/// [`JsonToken::AnyOf`])
+	pub fn allows(&self, token: &JsonToken) -> bool {
+		match self {
+			JsonToken::AnyOf(string_values) | JsonToken::Literal(string_values) => token
+				.to_string()
+				.is_some_and(|s| !s.is_empty() && string_values.iter().any(|sv| sv.starts_with(s.as_ref()))),
+			JsonToken::AnyString { max_length, pattern, .. } => token.to_string().is_some_and(|s| {
+				!s.contains('"')
+					&& max_length.map_or(true, |max_length| s.chars().count() <= max_length)
+					&& pattern.as_ref().map_or(true, |pattern| pattern.allows(s.as_bytes()))
+			}),
+			json_token => json_token == token,
+		}
+	}
+}
+
 impl Display for JsonToken {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match self {
 			JsonToken::AnyOf(s) => write!(f, "<any of: {}>", s.join(", ")),
-			JsonToken::AnyString { max_length } => write!(f, "<any string max_length={max_length:?}>"),
+			JsonToken::Literal(s) => write!(f, "<literal: {}>", s.join(", ")),
+			JsonToken::AnyString {
+				min_length,
+				max_length,
+				pattern,
+			} => write!(
+				f,
+				"<any string min_length={min_length} max_length={max_length:?} pattern={}>",
+				pattern.is_some()
+			),
 			JsonToken::BracketClose
 			| JsonToken::BracketOpen
 			| JsonToken::Comma
@@ -364,6 +859,17 @@ impl Display for JsonToken {
 	}
 }
 
+/// Order in which tokens are preferred when closing a value: lower ranks end the value sooner
+fn closing_rank(json_token: &JsonToken) -> usize {
+	match json_token {
+		JsonToken::CurlyClose | JsonToken::BracketClose | JsonToken::DoubleQuote => 0,
+		JsonToken::Colon | JsonToken::Comma => 1,
+		JsonToken::Null | JsonToken::False | JsonToken::True | JsonToken::Digit(_) => 2,
+		JsonToken::CurlyOpen | JsonToken::BracketOpen | JsonToken::Minus | JsonToken::Decimal | JsonToken::String(_) => 3,
+		JsonToken::AnyOf(_) | JsonToken::Literal(_) | JsonToken::AnyString { .. } => 4,
+	}
+}
+
 #[derive(Error, Debug)]
 pub enum BiaserError {
 	#[error("invalid next token {0}")]
@@ -494,8 +1000,14 @@ impl<'schema> JsonParserState<'schema> {
 				}
 				Some(Value::Array(items))
 			}
-			JsonParserState::InInteger(s) => Some(json! { s.parse::<f32>().unwrap() }),
+			JsonParserState::InInteger(s) => s.parse::<i64>().map(Value::from).or_else(|_| s.parse::<f64>().map(Value::from)).ok(),
+			JsonParserState::InLiteral(s) => serde_json::from_str(s).ok(),
 			JsonParserState::End(v) => Some(v.clone()),
+			JsonParserState::InOneOf(branches) => branches
+				.iter()
+				.find(|branch| branch.can_end())
+				.or(branches.first())
+				.and_then(|branch| branch.state.value()),
 		}
 	}
 
@@ -571,20 +1083,49 @@ impl<'schema> JsonParserState<'schema> {
 				}
 			},
 
-			JsonParserState::End(_) => return Err(BiaserError::InvalidToken(input.clone())),
+			// Alternatives and literals are advanced by the biaser itself (see JsonBiaser::advance)
+			JsonParserState::End(_) | JsonParserState::InOneOf(_) | JsonParserState::InLiteral(_) => {
+				return Err(BiaserError::InvalidToken(input.clone()))
+			}
 		};
 		Ok(())
 	}
 }
 
 impl<'schema> JsonBiaser<'schema> {
+	/// A biaser that only parses, or that analyzes the vocabulary each time it biases. Use
+	/// [`JsonBiaser::with_vocabulary`] to bias generation.
 	pub fn new(schema: &'schema JsonSchema) -> JsonBiaser<'schema> {
 		JsonBiaser {
 			schema,
 			state: JsonParserState::Start,
+			vocabulary: None,
+			pattern: OnceLock::new(),
 		}
 	}
 
+	/// A biaser that uses an analysis of the vocabulary of the model made before (and shared with other biasers)
+	pub fn with_vocabulary(schema: &'schema JsonSchema, vocabulary: Arc<VocabularyInfo>) -> JsonBiaser<'schema> {
+		JsonBiaser {
+			vocabulary: Some(vocabulary),
+			..JsonBiaser::new(schema)
+		}
+	}
+
+	/// The compiled pattern of a string schema (`None` when the schema has no pattern, or one that is not valid)
+	fn pattern(&self) -> Option<&RegexBiaser> {
+		self.pattern
+			.get_or_init(|| {
+				let JsonSchema::String { pattern: Some(pattern), .. } = self.schema else {
+					return None;
+				};
+				RegexBiaser::new(pattern)
+					.map_err(|e| tracing::warn!("ignoring invalid pattern {pattern:?}: {e}"))
+					.ok()
+			})
+			.as_ref()
+	}
+
 	fn child_item_schema(&self) -> Option<&'schema JsonSchema> {
 		match &self.schema {
 			JsonSchema::Array { items, .. } => Some(items.as_ref()),
@@ -594,17 +1135,133 @@ impl<'schema> JsonBiaser<'schema> {
 	}
 
 	pub fn advance(&mut self, input: &JsonToken) -> Result<(), BiaserError> {
+		if let JsonSchema::OneOf { one_of } = self.schema {
+			// Continue with the alternatives that accept the input
+			let branches = match std::mem::replace(&mut self.state, JsonParserState::Start) {
+				JsonParserState::InOneOf(branches) => branches,
+				_ => one_of.iter().map(JsonBiaser::new).collect(),
+			};
+			let branches: Vec<JsonBiaser> = branches
+				.into_iter()
+				.filter_map(|mut branch| (branch.accepts(input) && branch.advance(input).is_ok()).then_some(branch))
+				.collect();
+			if branches.is_empty() {
+				return Err(BiaserError::InvalidToken(input.clone()));
+			}
+			self.state = JsonParserState::InOneOf(branches);
+			return Ok(());
+		}
+		if let Some(literals) = self.schema.literals() {
+			// Continue with the text of the value so far, which must remain the start of one of the values
+			let so_far = match self.state {
+				JsonParserState::InLiteral(ref so_far) => so_far.as_str(),
+				_ => "",
+			};
+			let text = input.to_string().unwrap_or_default();
+			let literal = format!("{so_far}{text}");
+			if text.is_empty() || !literals.iter().any(|l| l.starts_with(&literal)) {
+				return Err(BiaserError::InvalidToken(input.clone()));
+			}
+			self.state = JsonParserState::InLiteral(literal);
+			return Ok(());
+		}
 		self.state.advance(input, self.child_item_schema())
 	}
 
+	/// Whether the input is one of the valid next tokens
+	fn accepts(&self, input: &JsonToken) -> bool {
+		self.next_valid_tokens().iter().any(|json_token| json_token.allows(input))
+	}
+
+	/// The sequence of tokens that turns the output so far into a value that is valid according to the schema. At each
+	/// step, the token that gets closest to the end of the value is chosen: closing brackets and quotes first, then
+	/// separators, then the shortest values and keys that the schema requires. Empty when the value can already end;
+	/// `None` when there is no way to end it.
+	pub fn closing_sequence(&self) -> Option<Vec<JsonToken>> {
+		let mut biaser = JsonBiaser {
+			schema: self.schema,
+			state: self.state.clone(),
+			vocabulary: None,
+			pattern: self.pattern.clone(),
+		};
+		let mut sequence = vec![];
+		while !biaser.can_end() {
+			let next = biaser
+				.next_valid_tokens()
+				.into_iter()
+				.filter_map(|json_token| match json_token {
+					JsonToken::AnyOf(string_values) | JsonToken::Literal(string_values) => {
+						string_values.into_iter().min_by_key(|s| s.len()).map(JsonToken::String)
+					}
+					// The shortest text after which the string may end: the rest of the pattern, or else padding up to the
+					// minimum length
+					JsonToken::AnyString { min_length, pattern, .. } => {
+						let text = match pattern {
+							Some(pattern) => String::from_utf8(pattern.completion()?).ok()?,
+							None => " ".repeat(min_length),
+						};
+						(!text.is_empty()).then_some(JsonToken::String(text))
+					}
+					json_token => Some(json_token),
+				})
+				.min_by_key(closing_rank);
+			let Some(next) = next else {
+				tracing::warn!("no way to close JSON value in state {:?}", biaser.state);
+				return None;
+			};
+			biaser.advance(&next).ok()?;
+			sequence.push(next);
+		}
+		Some(sequence)
+	}
+
+	/// The rest of each of the values of an enum or constant that starts with the text so far
+	fn literal_tokens(&self, so_far: &str) -> Vec<JsonToken> {
+		let remainders: Vec<String> = self
+			.schema
+			.literals()
+			.unwrap_or_default()
+			.iter()
+			.filter_map(|literal| literal.strip_prefix(so_far))
+			.filter(|remainder| !remainder.is_empty())
+			.map(String::from)
+			.collect();
+		if remainders.is_empty() {
+			vec![]
+		} else {
+			vec![JsonToken::Literal(remainders)]
+		}
+	}
+
+	/// The digits (and the decimal point or minus sign) that can follow the number so far, so that it can still become a
+	/// number the schema allows
+	fn number_tokens(&self, so_far: &str) -> Vec<JsonToken> {
+		let Some(bounds) = self.schema.number_bounds() else {
+			panic!("in number without number schema");
+		};
+		let mut tokens: Vec<JsonToken> = (0..=9)
+			.filter(|digit| bounds.allows(&format!("{so_far}{digit}")))
+			.map(JsonToken::Digit)
+			.collect();
+		if bounds.allows(&format!("{so_far}.")) {
+			tokens.push(JsonToken::Decimal);
+		}
+		if so_far.is_empty() && bounds.allows("-") {
+			tokens.push(JsonToken::Minus);
+		}
+		tokens
+	}
+
 	pub fn can_end(&self) -> bool {
 		match self.state {
 			JsonParserState::Start => false,
 			JsonParserState::InObject(ref object_state) => object_state.can_end(),
 			JsonParserState::InArray(ref _array_state) => false,
-			JsonParserState::InInteger(ref s) => !s.is_empty() && s.parse::<f32>().is_ok() && !s.ends_with('.'),
+			JsonParserState::InInteger(ref s) => self.schema.number_bounds().is_some_and(|bounds| bounds.can_end(s)),
 			JsonParserState::End(_) => true,
 			JsonParserState::InString(_) => false,
+			JsonParserState::InOneOf(ref branches) => branches.iter().any(|branch| branch.can_end()),
+			JsonParserState::InLiteral(ref so_far) => self.schema.literals().is_some_and(|literals| literals.contains(so_far)),
 		}
 	}
 
@@ -612,16 +1269,21 @@ impl<'schema> JsonBiaser<'schema> {
 		match &self.state {
 			JsonParserState::End(_) => vec![],
 			JsonParserState::InObject(object_state) => object_state.next_valid_tokens(),
+			JsonParserState::InOneOf(branches) => union_of_tokens(branches.iter()),
+			JsonParserState::InLiteral(so_far) => self.literal_tokens(so_far),
 			JsonParserState::InString(string_so_far) => {
 				let JsonSchema::String {
+					min_length,
 					max_length,
 					r#enum: string_values,
+					..
 				} = self.schema
 				else {
 					panic!("in string without string schema");
 				};
 
-				let max_next_length = max_length.as_ref().map(|max_length| max_length - string_so_far.len());
+				let length = string_so_far.chars().count();
+				let max_next_length = max_length.as_ref().map(|max_length| max_length.saturating_sub(length));
 				if max_next_length == Some(0) {
 					// Must end string now
 					return vec![JsonToken::DoubleQuote];
@@ -633,11 +1295,13 @@ impl<'schema> JsonBiaser<'schema> {
 					let possible_remainders: Vec<String> = string_values
 						.iter()
 						.filter_map(|ps| {
-							// Remove any strings that are too long to begin with
-							if let Some(max_length) = max_length {
-								if ps.len() > *max_length {
-									return None;
-								}
+							// Remove any strings that are too long or too short to begin with, or that do not match the pattern
+							let ps_length = ps.chars().count();
+							if max_length.is_some_and(|max_length| ps_length > max_length)
+								|| min_length.is_some_and(|min_length| ps_length < min_length)
+								|| self.pattern().is_some_and(|pattern| !pattern.matches(ps.as_bytes()))
+							{
+								return None;
 							}
 
 							if ps == string_so_far {
@@ -664,8 +1328,31 @@ impl<'schema> JsonBiaser<'schema> {
 					return next_tokens;
 				}
 
-				// Any string
-				vec![JsonToken::DoubleQuote, JsonToken::AnyString { max_length: max_next_length }]
+				// Any string that keeps matching the pattern, which may only end when it is long enough and matches
+				let pattern = match self.pattern() {
+					Some(pattern) if !pattern.allows(string_so_far.as_bytes()) => {
+						// The string so far can never match, so end it right away
+						return vec![JsonToken::DoubleQuote];
+					}
+					Some(pattern) => {
+						let mut pattern = pattern.clone();
+						pattern.advance_bytes(string_so_far.as_bytes());
+						Some(pattern)
+					}
+					None => None,
+				};
+				let min_next_length = min_length.unwrap_or(0).saturating_sub(length);
+
+				let mut next_tokens = vec![];
+				if min_next_length == 0 && pattern.as_ref().map_or(true, RegexBiaser::can_end) {
+					next_tokens.push(JsonToken::DoubleQuote);
+				}
+				next_tokens.push(JsonToken::AnyString {
+					min_length: min_next_length,
+					max_length: max_next_length,
+					pattern,
+				});
+				next_tokens
 			}
 			JsonParserState::InArray(array_state) => {
 				let JsonSchema::Array { min_items, max_items, .. } = self.schema else {
@@ -689,72 +1376,7 @@ impl<'schema> JsonBiaser<'schema> {
 
 				valid
 			}
-			JsonParserState::InInteger(s) => {
-				let JsonSchema::Number { max_decimals, min, max } = self.schema else {
-					panic!();
-				};
-				let max_decimals = max_decimals.unwrap_or(0);
-				let has_decimal = s.contains('.');
-
-				if max_decimals == 0 && has_decimal {
-					panic!("have decimal while not allowed");
-				}
-
-				// Check if we are below the set maximum number of decimals
-				if s.contains('.') && max_decimals > 0 {
-					let decimals = s.split_once('.').unwrap().1;
-					if decimals.len() >= max_decimals {
-						return vec![];
-					}
-				}
-
-				// First digit cannot be zero
-				let mut digits: Vec<JsonToken> = if s == "-" {
-					(1..=9).map(JsonToken::Digit).collect()
-				} else {
-					(0..=9).map(JsonToken::Digit).collect()
-				};
-
-				// Limit the length of a number literal to what fits in a 32 bit integer
-				if let Ok(v) = s.parse::<f64>() {
-					if v >= (u32::MAX as f64) {
-						return vec![];
-					}
-
-					if let Some(max) = max {
-						if v >= *max {
-							return vec![];
-						}
-
-						digits.retain_mut(|digit| {
-							// Try to append the digit and see if we still meet the minimum
-							match format!("{s}{}", digit).parse::<f64>() {
-								Err(_) => false,
-								Ok(v) => v <= *max,
-							}
-						});
-					}
-
-					if let Some(min) = min {
-						if v <= *min {
-							return vec![];
-						}
-
-						digits.retain_mut(|digit| {
-							// Try to append the digit and see if we still meet the minimum
-							match format!("{s}{}", digit).parse::<f64>() {
-								Err(_) => false,
-								Ok(v) => v >= *min,
-							}
-						});
-					}
-				}
-
-				if !has_decimal && max_decimals > 0 {
-					digits.push(JsonToken::Decimal);
-				}
-				digits
-			}
+			JsonParserState::InInteger(s) => self.number_tokens(s),
 			JsonParserState::Start => match self.schema {
 				JsonSchema::Boolean => {
 					vec![JsonToken::True, JsonToken::False]
@@ -768,25 +1390,24 @@ impl<'schema> JsonBiaser<'schema> {
 				JsonSchema::String { .. } => {
 					vec![JsonToken::DoubleQuote]
 				}
-				JsonSchema::Number { max, min, max_decimals: _ } => {
-					// First digit cannot be zero
-					let mut d: Vec<JsonToken> = (1..=9)
-						.filter(|d| {
-							let df = *d as f64;
-							df <= max.unwrap_or(df) && df >= min.unwrap_or(df)
-						})
-						.map(JsonToken::Digit)
-						.collect();
-
-					if min.unwrap_or(-1.0) < 0.0 || max.unwrap_or(-1.0) < 0.0 {
-						d.push(JsonToken::Minus);
-					}
-					d
-				}
+				JsonSchema::Number { r#enum: Some(_), .. } | JsonSchema::Enum { .. } | JsonSchema::Const { .. } => self.literal_tokens(""),
+				JsonSchema::Number { .. } | JsonSchema::Integer { .. } => self.number_tokens(""),
 				JsonSchema::Array { .. } => {
 					vec![JsonToken::BracketOpen]
 				}
+				JsonSchema::OneOf { one_of } => union_of_tokens(one_of.iter().map(JsonBiaser::new).collect::<Vec<_>>().iter()),
 			},
 		}
 	}
 }
+
+/// The tokens that are valid next for any of the parsers (each listed once)
+fn union_of_tokens<'a, 'schema: 'a>(biasers: impl Iterator<Item = &'a JsonBiaser<'schema>>) -> Vec<JsonToken> {
+	let mut tokens = vec![];
+	for json_token in biasers.flat_map(|biaser| biaser.next_valid_tokens()) {
+		if !tokens.contains(&json_token) {
+			tokens.push(json_token);
+		}
+	}
+	tokens
+}
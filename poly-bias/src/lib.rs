@@ -1,6 +1,9 @@
 use llm::{TokenId, Tokenizer};
 
+pub mod choices;
 pub mod json;
+pub mod regex;
+pub mod vocabulary;
 
 /// Logit value to indicate a token is allowed to be present in the result
 pub const TOKEN_ALLOWED: f32 = 10000.0;
@@ -16,6 +19,17 @@ pub trait Biaser {
 	/// Advance the biaser by feeding it a single next token (must be one of the tokens allowed as described by the
 	/// result of a call to `bias`)
 	fn advance(&mut self, vocabulary: &Tokenizer, token: TokenId);
+
+	/// Return the shortest sequence of tokens that makes the output so far valid (e.g. by closing open brackets and
+	/// quotes), to be emitted when generation has to end before the biaser allows it to. Empty when the output is already
+	/// valid; `None` when the output cannot be made valid.
+	fn closing_tokens(&self, vocabulary: &Tokenizer) -> Option<Vec<TokenId>>;
+
+	/// Return the index of the choice the output so far is, for biasers that constrain the output to one of a fixed set of
+	/// choices (see [`choices::ChoicesBiaser`])
+	fn choice(&self) -> Option<usize> {
+		None
+	}
 }
 
 /// A biaser that does not bias in any way
@@ -27,4 +41,8 @@ impl Biaser for NullBiaser {
 	}
 
 	fn advance(&mut self, _vocabulary: &Tokenizer, _token: TokenId) {}
+
+	fn closing_tokens(&self, _vocabulary: &Tokenizer) -> Option<Vec<TokenId>> {
+		Some(vec![])
+	}
 }
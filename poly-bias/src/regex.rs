@@ -0,0 +1,155 @@
+use std::{
+	collections::{HashSet, VecDeque},
+	sync::Arc,
+};
+
+use llm::{TokenId, Tokenizer};
+use regex_automata::{
+	dfa::{
+		dense::{self, BuildError, DFA},
+		Automaton, StartKind,
+	},
+	util::primitives::StateID,
+	Anchored, Input, MatchKind,
+};
+
+use crate::{Biaser, TOKEN_ALLOWED};
+
+/// A biaser that constrains the output to text that matches a regular expression as a whole. The expression is compiled
+/// into a DFA that works on bytes, so that tokens can be checked without regard for character boundaries: a token is
+/// allowed when feeding its bytes to the DFA does not lead to the dead state, and the end-of-text token is allowed once
+/// the output so far matches.
+#[derive(Clone, Debug)]
+pub struct RegexBiaser {
+	dfa: Arc<DFA<Vec<u32>>>,
+	state: StateID,
+}
+
+impl RegexBiaser {
+	pub fn new(pattern: &str) -> Result<RegexBiaser, BuildError> {
+		// The pattern is anchored at both ends, so that a match always covers the whole output. All matches are kept (not
+		// just the leftmost-first), as the output can only end where the pattern ends.
+		let dfa = dense::Builder::new()
+			.configure(DFA::config().match_kind(MatchKind::All).start_kind(StartKind::Anchored))
+			.build(&format!(r"(?:{pattern})\z"))?;
+		let state = dfa
+			.start_state_forward(&Input::new("").anchored(Anchored::Yes))
+			.expect("anchored start state exists");
+		Ok(RegexBiaser { dfa: Arc::new(dfa), state })
+	}
+
+	/// The state the DFA is in after feeding it `bytes` from `state`, or `None` when there is no way for the output to
+	/// match anymore
+	fn next_state(&self, state: StateID, bytes: &[u8]) -> Option<StateID> {
+		bytes.iter().try_fold(state, |state, byte| {
+			let next = self.dfa.next_state(state, *byte);
+			(!self.dfa.is_dead_state(next) && !self.dfa.is_quit_state(next)).then_some(next)
+		})
+	}
+
+	fn is_accepting(&self, state: StateID) -> bool {
+		self.dfa.is_match_state(self.dfa.next_eoi_state(state))
+	}
+
+	/// Whether the output so far can be followed by `bytes` (which need not consist of whole characters)
+	pub fn allows(&self, bytes: &[u8]) -> bool {
+		self.next_state(self.state, bytes).is_some()
+	}
+
+	/// Whether the output so far matches the pattern
+	pub fn can_end(&self) -> bool {
+		self.is_accepting(self.state)
+	}
+
+	/// Whether the output so far followed by `bytes` matches the pattern
+	pub fn matches(&self, bytes: &[u8]) -> bool {
+		self.next_state(self.state, bytes).is_some_and(|state| self.is_accepting(state))
+	}
+
+	/// Append `bytes` to the output (which must be allowed, see [`RegexBiaser::allows`])
+	pub fn advance_bytes(&mut self, bytes: &[u8]) {
+		self.state = self.next_state(self.state, bytes).expect("bytes are allowed by the pattern");
+	}
+
+	/// The shortest sequence of bytes that makes the output so far match the pattern (empty when it already does), or
+	/// `None` when there is no way for the output to match anymore
+	pub fn completion(&self) -> Option<Vec<u8>> {
+		// Breadth-first search through the states of the DFA, like in closing_tokens but byte by byte
+		let mut visited = HashSet::from([self.state]);
+		let mut queue = VecDeque::from([(self.state, vec![])]);
+		while let Some((state, bytes)) = queue.pop_front() {
+			if self.is_accepting(state) {
+				return Some(bytes);
+			}
+			for byte in 0..=u8::MAX {
+				if let Some(next) = self.next_state(state, &[byte]) {
+					if visited.insert(next) {
+						let mut bytes = bytes.clone();
+						bytes.push(byte);
+						queue.push_back((next, bytes));
+					}
+				}
+			}
+		}
+		None
+	}
+}
+
+/// Biasers are equal when they use the same compiled pattern and have seen output that brings it in the same state
+impl PartialEq for RegexBiaser {
+	fn eq(&self, other: &Self) -> bool {
+		Arc::ptr_eq(&self.dfa, &other.dfa) && self.state == other.state
+	}
+}
+
+impl Eq for RegexBiaser {}
+
+impl Biaser for RegexBiaser {
+	fn bias(&self, vocabulary: &Tokenizer, eot_token: TokenId) -> Vec<(TokenId, f32)> {
+		// Empty tokens are left out, as they would never bring the output closer to its end
+		let mut next_valid_tokens: Vec<(TokenId, f32)> = (0..vocabulary.len())
+			.filter(|token| *token != eot_token as usize)
+			.filter(|token| {
+				let bytes = vocabulary.token(*token);
+				!bytes.is_empty() && self.allows(&bytes)
+			})
+			.map(|token| (token as TokenId, TOKEN_ALLOWED))
+			.collect();
+		tracing::debug!("total tokens: {} valid: {}", vocabulary.len(), next_valid_tokens.len());
+
+		if self.can_end() {
+			next_valid_tokens.push((eot_token, TOKEN_ALLOWED));
+		}
+		next_valid_tokens
+	}
+
+	fn advance(&mut self, vocabulary: &Tokenizer, token: TokenId) {
+		self.advance_bytes(&vocabulary.token(token as usize));
+	}
+
+	fn closing_tokens(&self, vocabulary: &Tokenizer) -> Option<Vec<TokenId>> {
+		// Breadth-first search through the states of the DFA for the shortest sequence of tokens to a matching state
+		let tokens: Vec<(TokenId, Vec<u8>)> = (0..vocabulary.len())
+			.map(|token| (token as TokenId, vocabulary.token(token)))
+			.filter(|(_, bytes)| !bytes.is_empty())
+			.collect();
+		let mut visited = HashSet::from([self.state]);
+		let mut queue = VecDeque::from([(self.state, vec![])]);
+		while let Some((state, sequence)) = queue.pop_front() {
+			if self.is_accepting(state) {
+				return Some(sequence);
+			}
+			for (token, bytes) in &tokens {
+				if let Some(next) = self.next_state(state, bytes) {
+					if visited.insert(next) {
+						let mut sequence = sequence.clone();
+						sequence.push(*token);
+						queue.push_back((next, sequence));
+					}
+				}
+			}
+		}
+		tracing::warn!("no way to make output match the pattern");
+		None
+	}
+}
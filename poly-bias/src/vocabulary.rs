@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use llm::{TokenId, Tokenizer};
+
+use crate::json::JsonToken;
+
+/// What biasers need to know about the tokens in the vocabulary of a model. Analyzing the vocabulary takes a while for
+/// large vocabularies, so this is done once per model and shared (using an `Arc`) between all biasers for the model.
+#[derive(Debug)]
+pub struct VocabularyInfo {
+	/// The text of each token (`None` for tokens that are not valid UTF-8 by themselves)
+	texts: Vec<Option<String>>,
+
+	/// Tokens that can appear inside a JSON string as they are (i.e. non-empty and without double quotes or line
+	/// breaks and tabs), ordered by their length in characters
+	string_tokens: Vec<TokenId>,
+
+	/// The token for each structural JSON token (brackets, separators, literals and digits) that is a single token in the
+	/// vocabulary
+	json_tokens: HashMap<String, TokenId>,
+}
+
+/// JSON tokens of which the token is looked up when analyzing a vocabulary
+const JSON_TOKENS: [JsonToken; 12] = [
+	JsonToken::BracketClose,
+	JsonToken::BracketOpen,
+	JsonToken::Colon,
+	JsonToken::Comma,
+	JsonToken::CurlyClose,
+	JsonToken::CurlyOpen,
+	JsonToken::Decimal,
+	JsonToken::DoubleQuote,
+	JsonToken::False,
+	JsonToken::Minus,
+	JsonToken::Null,
+	JsonToken::True,
+];
+
+impl VocabularyInfo {
+	pub fn new(vocabulary: &Tokenizer) -> VocabularyInfo {
+		let texts: Vec<Option<String>> = (0..vocabulary.len())
+			.map(|token| String::from_utf8(vocabulary.token(token)).ok())
+			.collect();
+
+		let mut string_tokens: Vec<TokenId> = texts
+			.iter()
+			.enumerate()
+			.filter(|(_, text)| text.as_ref().is_some_and(|s| !s.is_empty() && !s.contains(['"', '\n', '\t', '\r'])))
+			.map(|(token, _)| token as TokenId)
+			.collect();
+		string_tokens.sort_by_key(|token| texts[*token as usize].as_ref().map_or(0, |s| s.chars().count()));
+
+		let json_tokens = JSON_TOKENS
+			.iter()
+			.cloned()
+			.chain((0..10).map(JsonToken::Digit))
+			.filter_map(|json_token| {
+				let token = json_token.token_id(vocabulary)?;
+				Some((json_token.to_string()?.into_owned(), token))
+			})
+			.collect();
+
+		VocabularyInfo {
+			texts,
+			string_tokens,
+			json_tokens,
+		}
+	}
+
+	/// Number of tokens in the vocabulary
+	pub fn len(&self) -> usize {
+		self.texts.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.texts.is_empty()
+	}
+
+	/// The text of a token, when it is valid UTF-8 by itself
+	pub fn text(&self, token: TokenId) -> Option<&str> {
+		self.texts.get(token as usize)?.as_deref()
+	}
+
+	/// Tokens that can appear inside a JSON string as they are and that are at most `max_length` characters (Unicode scalar
+	/// values) long (when given)
+	pub fn string_tokens(&self, max_length: Option<usize>) -> &[TokenId] {
+		match max_length {
+			Some(max_length) => {
+				let end = self
+					.string_tokens
+					.partition_point(|token| self.text(*token).map_or(0, |s| s.chars().count()) <= max_length);
+				&self.string_tokens[..end]
+			}
+			None => &self.string_tokens,
+		}
+	}
+
+	/// The token for a structural JSON token, when it is a single token in the vocabulary. Returns `None` for other JSON
+	/// tokens (these need to be tokenized).
+	pub fn json_token(&self, json_token: &JsonToken) -> Option<TokenId> {
+		self.json_tokens.get(json_token.to_string()?.as_ref()).copied()
+	}
+}
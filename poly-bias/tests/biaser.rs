@@ -4,17 +4,21 @@ use std::{
 	sync::{Mutex, Once},
 };
 #[cfg(test)]
-use std::{path::Path, sync::Arc};
+use std::{
+	path::{Path, PathBuf},
+	sync::Arc,
+};
 
 use llm::{
 	samplers::{llm_samplers::types::SamplerChain, ConfiguredSamplers},
 	InferenceError, InferenceFeedback, InferenceParameters, InferenceSessionConfig, Model, ModelArchitecture, ModelParameters, OutputRequest, Prompt,
-	TokenUtf8Buffer,
+	TokenId, TokenUtf8Buffer,
 };
 
 use poly_bias::{
 	json::{BiaserError, JsonBiaser, JsonSchema, JsonToken},
-	Biaser,
+	vocabulary::VocabularyInfo,
+	Biaser, TOKEN_ALLOWED,
 };
 use rand::SeedableRng;
 use serde_json::Value;
@@ -38,8 +42,10 @@ pub fn test_parser() {
 #[test]
 pub fn test_string_parser() {
 	let schema = JsonSchema::String {
+		min_length: None,
 		max_length: Some(10),
 		r#enum: None,
+		pattern: None,
 	};
 	let mut bias = JsonBiaser::new(&schema);
 	assert_eq!(bias.next_valid_tokens(), vec![JsonToken::DoubleQuote]);
@@ -54,8 +60,10 @@ pub fn test_string_enum_parser() {
 	setup();
 	let words = vec!["foo".to_string(), "bar".to_string(), "baz".to_string()];
 	let schema = JsonSchema::String {
+		min_length: None,
 		max_length: Some(10),
 		r#enum: Some(words.clone()),
+		pattern: None,
 	};
 	let mut bias = JsonBiaser::new(&schema);
 	assert_eq!(bias.next_valid_tokens(), vec![JsonToken::DoubleQuote]);
@@ -100,8 +108,10 @@ pub fn test_nested_object_parser() {
 						hn.insert(
 							"name".to_string(),
 							Box::new(JsonSchema::String {
+								min_length: None,
 								max_length: None,
 								r#enum: None,
+								pattern: None,
 							}),
 						);
 						hn
@@ -146,15 +156,19 @@ pub fn test_object_parser() {
 	fields.insert(
 		"first_name".to_string(),
 		Box::new(JsonSchema::String {
+			min_length: None,
 			max_length: Some(5),
 			r#enum: None,
+			pattern: None,
 		}),
 	);
 	fields.insert(
 		"last_name".to_string(),
 		Box::new(JsonSchema::String {
+			min_length: None,
 			max_length: Some(7),
 			r#enum: None,
+			pattern: None,
 		}),
 	);
 	let schema = JsonSchema::Object {
@@ -235,6 +249,548 @@ pub fn test_array_parser() {
 	assert!(bias.can_end());
 }
 
+#[test]
+pub fn test_closing_sequence() {
+	setup();
+	let mut fields = HashMap::new();
+	for key in ["first_name", "last_name"] {
+		fields.insert(
+			key.to_string(),
+			Box::new(JsonSchema::String {
+				min_length: None,
+				max_length: Some(7),
+				r#enum: None,
+				pattern: None,
+			}),
+		);
+	}
+	let schema = JsonSchema::Object {
+		required: vec!["first_name".to_string(), "last_name".to_string()],
+		properties: fields,
+	};
+
+	// '{"first_name":"to' is closed with '","last_name":""}'
+	let mut biaser = JsonBiaser::new(&schema);
+	for token in [
+		JsonToken::CurlyOpen,
+		JsonToken::DoubleQuote,
+		JsonToken::String("first_name".to_string()),
+		JsonToken::DoubleQuote,
+		JsonToken::Colon,
+		JsonToken::DoubleQuote,
+		JsonToken::String("to".to_string()),
+	] {
+		biaser.advance(&token).unwrap();
+	}
+	let closing = biaser.closing_sequence().unwrap();
+	let text: String = closing.iter().map(|t| t.to_string().unwrap()).collect();
+	assert_eq!(text, r#"","last_name":""}"#);
+
+	// Closing does not change the state of the biaser itself
+	assert!(!biaser.can_end());
+	for token in &closing {
+		biaser.advance(token).unwrap();
+	}
+	assert!(biaser.can_end());
+	assert_eq!(biaser.closing_sequence(), Some(vec![]));
+
+	// Arrays get the minimum number of items
+	let schema = JsonSchema::Array {
+		items: Box::new(JsonSchema::Boolean),
+		min_items: Some(2),
+		max_items: Some(5),
+	};
+	let mut biaser = JsonBiaser::new(&schema);
+	biaser.advance(&JsonToken::BracketOpen).unwrap();
+	biaser.advance(&JsonToken::True).unwrap();
+	assert_eq!(
+		biaser.closing_sequence(),
+		Some(vec![JsonToken::Comma, JsonToken::True, JsonToken::BracketClose])
+	);
+}
+
+#[test]
+pub fn test_one_of_parser() {
+	setup();
+	let call = |tool: &str, argument: &str| JsonSchema::Object {
+		required: vec!["tool".to_string(), argument.to_string()],
+		properties: HashMap::from([
+			(
+				"tool".to_string(),
+				Box::new(JsonSchema::String {
+					min_length: None,
+					max_length: None,
+					r#enum: Some(vec![tool.to_string()]),
+					pattern: None,
+				}),
+			),
+			(argument.to_string(), Box::new(JsonSchema::Boolean)),
+		]),
+	};
+	let schema = JsonSchema::OneOf {
+		one_of: vec![call("get", "all"), call("get_weather", "celsius"), JsonSchema::Null],
+	};
+
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(biaser.next_valid_tokens(), vec![JsonToken::CurlyOpen, JsonToken::Null]);
+	for token in [
+		JsonToken::CurlyOpen,
+		JsonToken::DoubleQuote,
+		JsonToken::String("tool".to_string()),
+		JsonToken::DoubleQuote,
+		JsonToken::Colon,
+		JsonToken::DoubleQuote,
+	] {
+		biaser.advance(&token).unwrap();
+	}
+	assert_eq!(
+		biaser.next_valid_tokens(),
+		vec![
+			JsonToken::AnyOf(vec!["get".to_string()]),
+			JsonToken::AnyOf(vec!["get_weather".to_string()])
+		]
+	);
+
+	// Both tools start with "get"; the closing quote only fits the first
+	biaser.advance(&JsonToken::String("get".to_string())).unwrap();
+	assert_eq!(
+		biaser.next_valid_tokens(),
+		vec![JsonToken::DoubleQuote, JsonToken::AnyOf(vec!["_weather".to_string()])]
+	);
+	biaser.advance(&JsonToken::String("_weather".to_string())).unwrap();
+	assert!(biaser.advance(&JsonToken::String("x".to_string())).is_err());
+
+	let mut biaser = JsonBiaser::new(&schema);
+	for token in [
+		JsonToken::CurlyOpen,
+		JsonToken::DoubleQuote,
+		JsonToken::String("tool".to_string()),
+		JsonToken::DoubleQuote,
+		JsonToken::Colon,
+		JsonToken::DoubleQuote,
+		JsonToken::String("get_weather".to_string()),
+		JsonToken::DoubleQuote,
+		JsonToken::Comma,
+	] {
+		biaser.advance(&token).unwrap();
+	}
+	let closing: String = biaser.closing_sequence().unwrap().iter().map(|t| t.to_string().unwrap()).collect();
+	assert_eq!(closing, r#""celsius":true}"#);
+}
+
+fn literal(values: &[&str]) -> JsonToken {
+	JsonToken::Literal(values.iter().map(|v| v.to_string()).collect())
+}
+
+#[test]
+pub fn test_enum_parser() {
+	setup();
+
+	// Values that share a prefix, mixed with numbers (of which one is a prefix of another)
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "enum", "enum": ["green", "grey", "red", 4, 42] }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(
+		biaser.next_valid_tokens(),
+		vec![literal(&["\"green\"", "\"grey\"", "\"red\"", "4", "42"])]
+	);
+	assert!(biaser.advance(&JsonToken::String(String::from("red"))).is_err());
+	biaser.advance(&JsonToken::String(String::from("\"gr"))).unwrap();
+	assert_eq!(biaser.next_valid_tokens(), vec![literal(&["een\"", "ey\""])]);
+	assert!(!biaser.can_end());
+	assert!(biaser.advance(&JsonToken::String(String::from("ay"))).is_err());
+	assert_eq!(biaser.closing_sequence(), Some(vec![JsonToken::String(String::from("ey\""))]));
+	biaser.advance(&JsonToken::String(String::from("ey"))).unwrap();
+	assert!(!biaser.can_end());
+	biaser.advance(&JsonToken::DoubleQuote).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![]);
+
+	// A number that is complete may still be continued to a longer one
+	let mut biaser = JsonBiaser::new(&schema);
+	biaser.advance(&JsonToken::Digit(4)).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![literal(&["2"])]);
+	biaser.advance(&JsonToken::Digit(2)).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![]);
+
+	assert!(schema.is_valid(&Value::from("grey")));
+	assert!(schema.is_valid(&Value::from(42)));
+	assert!(!schema.is_valid(&Value::from("blue")));
+
+	// Numbers can be limited to a set of values as well
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "number", "enum": [1.5, 15] }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	biaser.advance(&JsonToken::Digit(1)).unwrap();
+	assert_eq!(biaser.next_valid_tokens(), vec![literal(&[".5", "5"])]);
+	assert!(!biaser.can_end());
+	assert!(biaser.advance(&JsonToken::Digit(2)).is_err());
+	biaser.advance(&JsonToken::Decimal).unwrap();
+	biaser.advance(&JsonToken::Digit(5)).unwrap();
+	assert!(biaser.can_end());
+	assert!(schema.is_valid(&Value::from(1.5)));
+	assert!(!schema.is_valid(&Value::from(2)));
+}
+
+#[test]
+pub fn test_const_parser() {
+	setup();
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "const", "const": "yes" }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(biaser.next_valid_tokens(), vec![literal(&["\"yes\""])]);
+	assert_eq!(biaser.closing_sequence(), Some(vec![JsonToken::String(String::from("\"yes\""))]));
+	biaser.advance(&JsonToken::DoubleQuote).unwrap();
+	biaser.advance(&JsonToken::String(String::from("yes\""))).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(schema.to_json_schema(), serde_json::from_str::<Value>(r#"{ "const": "yes" }"#).unwrap());
+}
+
+#[test]
+pub fn test_enum_bias() {
+	setup();
+
+	// A mocked vocabulary with digits, and tokens spanning several digits ("20" and "2024")
+	let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/../data/regex-tokenizer.json"));
+	let tokenizer = llm::TokenizerSource::HuggingFaceTokenizerFile(path).retrieve(Path::new("")).unwrap();
+	let eot: TokenId = 1;
+	let id = |text: &str| (0..tokenizer.len()).find(|token| tokenizer.token(*token) == text.as_bytes()).unwrap() as TokenId;
+	let bias = |biaser: &JsonBiaser| {
+		let mut bias = biaser.bias(&tokenizer, eot);
+		bias.sort_by_key(|(token, _)| *token);
+		bias
+	};
+	let allowed = |tokens: &[TokenId]| tokens.iter().map(|token| (*token, TOKEN_ALLOWED)).collect::<Vec<_>>();
+
+	// Values sharing a prefix can be spelled with any of the tokens that start them
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "enum", "enum": [2024, 2020, 20] }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(bias(&biaser), allowed(&[id("2"), id("20"), id("2024")]));
+	Biaser::advance(&mut biaser, &tokenizer, id("20"));
+	assert_eq!(bias(&biaser), allowed(&[eot, id("2"), id("20")]));
+	Biaser::advance(&mut biaser, &tokenizer, id("2"));
+	assert_eq!(bias(&biaser), allowed(&[id("0"), id("4")]));
+
+	// A constant is spelled with one token at a time, so that it is emitted without sampling
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "const", "const": 2024 }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(bias(&biaser), allowed(&[id("2024")]));
+	Biaser::advance(&mut biaser, &tokenizer, id("2024"));
+	assert_eq!(bias(&biaser), allowed(&[eot]));
+}
+
+#[test]
+pub fn test_string_length_parser() {
+	setup();
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "string", "min_length": 2, "max_length": 4 }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	biaser.advance(&JsonToken::DoubleQuote).unwrap();
+
+	// The string cannot end before it has the minimum length, and is padded when it has to
+	assert_eq!(
+		biaser.next_valid_tokens(),
+		vec![JsonToken::AnyString {
+			min_length: 2,
+			max_length: Some(4),
+			pattern: None
+		}]
+	);
+	assert_eq!(
+		biaser.closing_sequence(),
+		Some(vec![JsonToken::String(String::from("  ")), JsonToken::DoubleQuote])
+	);
+
+	// Lengths are in characters, not bytes
+	biaser.advance(&JsonToken::String(String::from("éé"))).unwrap();
+	let any_string = JsonToken::AnyString {
+		min_length: 0,
+		max_length: Some(2),
+		pattern: None,
+	};
+	assert_eq!(biaser.next_valid_tokens(), vec![JsonToken::DoubleQuote, any_string.clone()]);
+	assert!(any_string.allows(&JsonToken::String(String::from("éé"))));
+
+	// A token that would go over the maximum length is not allowed, even though its first characters would fit
+	assert!(!any_string.allows(&JsonToken::String(String::from("abc"))));
+	biaser.advance(&JsonToken::String(String::from("ab"))).unwrap();
+	assert_eq!(biaser.next_valid_tokens(), vec![JsonToken::DoubleQuote]);
+
+	assert!(schema.is_valid(&Value::from("éééé")));
+	assert!(!schema.is_valid(&Value::from("é")));
+	assert!(!schema.is_valid(&Value::from("abcde")));
+}
+
+#[test]
+pub fn test_string_pattern_parser() {
+	setup();
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "string", "pattern": "[A-Z]{2}-\\d+" }"#).unwrap();
+	let allows = |biaser: &JsonBiaser, text: &str| {
+		biaser
+			.next_valid_tokens()
+			.iter()
+			.any(|json_token| json_token.allows(&JsonToken::String(String::from(text))))
+	};
+	let mut biaser = JsonBiaser::new(&schema);
+	biaser.advance(&JsonToken::DoubleQuote).unwrap();
+
+	// Only text that keeps the pattern satisfiable is allowed, and the string can only end when it matches
+	assert!(allows(&biaser, "NL-4"));
+	assert!(!allows(&biaser, "nl"));
+	assert!(!allows(&biaser, "NLD"));
+	assert!(!biaser.next_valid_tokens().contains(&JsonToken::DoubleQuote));
+
+	biaser.advance(&JsonToken::String(String::from("NL-"))).unwrap();
+	assert_eq!(
+		biaser.closing_sequence(),
+		Some(vec![JsonToken::String(String::from("0")), JsonToken::DoubleQuote])
+	);
+	biaser.advance(&JsonToken::Digit(42)).unwrap();
+	assert!(biaser.next_valid_tokens().contains(&JsonToken::DoubleQuote));
+	biaser.advance(&JsonToken::DoubleQuote).unwrap();
+	assert!(biaser.can_end());
+
+	// The pattern must match the string as a whole
+	assert!(schema.is_valid(&Value::from("NL-42")));
+	assert!(!schema.is_valid(&Value::from("The NL-42")));
+	assert_eq!(
+		schema.to_json_schema(),
+		serde_json::json!({ "type": "string", "pattern": "^(?:[A-Z]{2}-\\d+)$" })
+	);
+}
+
+#[test]
+pub fn test_string_bias() {
+	setup();
+
+	// A mocked vocabulary with a double quote, tokens of several characters and characters of several bytes
+	let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/../data/string-tokenizer.json"));
+	let tokenizer = llm::TokenizerSource::HuggingFaceTokenizerFile(path).retrieve(Path::new("")).unwrap();
+	let eot: TokenId = 1;
+	let id = |text: &str| (0..tokenizer.len()).find(|token| tokenizer.token(*token) == text.as_bytes()).unwrap() as TokenId;
+	let ids = |texts: &[&str]| {
+		let mut ids: Vec<TokenId> = texts.iter().map(|text| id(text)).collect();
+		ids.sort();
+		ids
+	};
+	let allowed = |biaser: &JsonBiaser| {
+		let mut tokens: Vec<TokenId> = biaser.bias(&tokenizer, eot).into_iter().map(|(token, _)| token).collect();
+		tokens.sort();
+		tokens
+	};
+
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "string", "min_length": 2, "max_length": 3 }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	Biaser::advance(&mut biaser, &tokenizer, id("\""));
+	assert_eq!(
+		allowed(&biaser),
+		ids(&["a", "ab", "abc", "é", "éé", "A", "B", "AB", "NL", "-", "4", "42", "424"])
+	);
+
+	// After two characters (of four bytes), only tokens of a single character still fit
+	Biaser::advance(&mut biaser, &tokenizer, id("éé"));
+	assert_eq!(allowed(&biaser), ids(&["\"", "a", "é", "A", "B", "-", "4"]));
+	Biaser::advance(&mut biaser, &tokenizer, id("é"));
+	assert_eq!(allowed(&biaser), ids(&["\""]));
+
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "string", "pattern": "[A-Z]{2}-\\d+" }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	Biaser::advance(&mut biaser, &tokenizer, id("\""));
+	assert_eq!(allowed(&biaser), ids(&["A", "B", "AB", "NL"]));
+	Biaser::advance(&mut biaser, &tokenizer, id("NL"));
+	assert_eq!(allowed(&biaser), ids(&["-"]));
+	Biaser::advance(&mut biaser, &tokenizer, id("-"));
+	assert_eq!(allowed(&biaser), ids(&["4", "42", "424"]));
+	Biaser::advance(&mut biaser, &tokenizer, id("42"));
+	assert_eq!(allowed(&biaser), ids(&["\"", "4", "42", "424"]));
+	Biaser::advance(&mut biaser, &tokenizer, id("\""));
+	assert_eq!(allowed(&biaser), vec![eot]);
+}
+
+fn digits(digits: &[usize]) -> Vec<JsonToken> {
+	digits.iter().copied().map(JsonToken::Digit).collect()
+}
+
+#[test]
+pub fn test_integer_parser() {
+	setup();
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "integer", "min": 0, "max": 100 }"#).unwrap();
+
+	// Zero is allowed, but cannot be followed by other digits; an integer never has a decimal point
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]));
+	biaser.advance(&JsonToken::Digit(0)).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![]);
+
+	// Digits that would take the number over the maximum are not allowed
+	let mut biaser = JsonBiaser::new(&schema);
+	biaser.advance(&JsonToken::Digit(1)).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]));
+	biaser.advance(&JsonToken::Digit(0)).unwrap();
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0]));
+	biaser.advance(&JsonToken::Digit(0)).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![]);
+
+	let mut biaser = JsonBiaser::new(&schema);
+	biaser.advance(&JsonToken::Digit(9)).unwrap();
+	biaser.advance(&JsonToken::Digit(9)).unwrap();
+	assert_eq!(biaser.next_valid_tokens(), vec![]);
+
+	assert!(schema.is_valid(&Value::from(100)));
+	assert!(!schema.is_valid(&Value::from(101)));
+	assert!(!schema.is_valid(&Value::from(-1)));
+	assert!(!schema.is_valid(&Value::from(2.5)));
+	assert_eq!(
+		schema.to_json_schema(),
+		serde_json::json!({ "type": "integer", "minimum": 0.0, "maximum": 100.0 })
+	);
+
+	// Without bounds, negative integers are allowed too
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "integer" }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert!(biaser.next_valid_tokens().contains(&JsonToken::Minus));
+	biaser.advance(&JsonToken::Digit(4)).unwrap();
+	assert!(!biaser.next_valid_tokens().contains(&JsonToken::Decimal));
+}
+
+#[test]
+pub fn test_negative_number_parser() {
+	setup();
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "integer", "min": -20, "max": -5 }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(biaser.next_valid_tokens(), vec![JsonToken::Minus]);
+	assert_eq!(
+		biaser.closing_sequence(),
+		Some(vec![JsonToken::Minus, JsonToken::Digit(1), JsonToken::Digit(0)])
+	);
+
+	// A digit is allowed when it can still lead to a number within bounds (e.g. -2 can become -20), and "-0" is not
+	biaser.advance(&JsonToken::Minus).unwrap();
+	assert!(!biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), digits(&[1, 2, 5, 6, 7, 8, 9]));
+	biaser.advance(&JsonToken::Digit(2)).unwrap();
+	assert!(!biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0]));
+	biaser.advance(&JsonToken::Digit(0)).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![]);
+
+	// Numbers between minus one and zero start with "-0."
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "number", "max_decimals": 1, "min": -0.5, "max": 0 }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(biaser.next_valid_tokens(), vec![JsonToken::Digit(0), JsonToken::Minus]);
+	biaser.advance(&JsonToken::Minus).unwrap();
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0]));
+	biaser.advance(&JsonToken::Digit(0)).unwrap();
+	assert!(!biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![JsonToken::Decimal]);
+	biaser.advance(&JsonToken::Decimal).unwrap();
+	assert_eq!(biaser.next_valid_tokens(), digits(&[1, 2, 3, 4, 5]));
+}
+
+#[test]
+pub fn test_number_bounds_parser() {
+	setup();
+
+	// Exclusive bounds
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "number", "max_decimals": 2, "exclusive_min": 0, "exclusive_max": 1 }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0]));
+	biaser.advance(&JsonToken::Digit(0)).unwrap();
+	assert!(!biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![JsonToken::Decimal]);
+	biaser.advance(&JsonToken::Decimal).unwrap();
+	assert!(!biaser.can_end());
+	biaser.advance(&JsonToken::Digit(0)).unwrap();
+	assert!(!biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), digits(&[1, 2, 3, 4, 5, 6, 7, 8, 9]));
+	biaser.advance(&JsonToken::Digit(5)).unwrap();
+	assert!(biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), vec![]);
+	assert!(schema.is_valid(&Value::from(0.05)));
+	assert!(!schema.is_valid(&Value::from(0)));
+	assert!(!schema.is_valid(&Value::from(1)));
+
+	// Multiples of a fraction
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "number", "max_decimals": 2, "min": 0, "max": 1, "multiple_of": 0.25 }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	biaser.advance(&JsonToken::Digit(0)).unwrap();
+	biaser.advance(&JsonToken::Decimal).unwrap();
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0, 2, 5, 7]));
+	biaser.advance(&JsonToken::Digit(2)).unwrap();
+	assert!(!biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), digits(&[5]));
+	assert_eq!(biaser.closing_sequence(), Some(digits(&[5])));
+	assert!(schema.is_valid(&Value::from(0.75)));
+	assert!(!schema.is_valid(&Value::from(0.3)));
+
+	// Multiples of a whole number
+	let schema: JsonSchema = serde_json::from_str(r#"{ "type": "integer", "min": 0, "max": 20, "multiple_of": 5 }"#).unwrap();
+	let mut biaser = JsonBiaser::new(&schema);
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0, 1, 2, 5]));
+	biaser.advance(&JsonToken::Digit(1)).unwrap();
+	assert!(!biaser.can_end());
+	assert_eq!(biaser.next_valid_tokens(), digits(&[0, 5]));
+	assert_eq!(
+		schema.to_json_schema(),
+		serde_json::json!({ "type": "integer", "minimum": 0.0, "maximum": 20.0, "multipleOf": 5.0 })
+	);
+}
+
+#[test]
+pub fn test_to_json_schema() {
+	let schema = JsonSchema::Object {
+		required: vec!["name".to_string()],
+		properties: HashMap::from([
+			(
+				"name".to_string(),
+				Box::new(JsonSchema::String {
+					min_length: None,
+					max_length: Some(10),
+					r#enum: None,
+					pattern: None,
+				}),
+			),
+			(
+				"scores".to_string(),
+				Box::new(JsonSchema::Array {
+					items: Box::new(JsonSchema::Number {
+						min: Some(0.0),
+						max: None,
+						max_decimals: Some(1),
+						exclusive_min: None,
+						exclusive_max: None,
+						multiple_of: None,
+						r#enum: None,
+					}),
+					min_items: None,
+					max_items: Some(3),
+				}),
+			),
+		]),
+	};
+	assert_eq!(
+		schema.to_json_schema(),
+		serde_json::json!({
+			"type": "object",
+			"required": ["name"],
+			"properties": {
+				"name": { "type": "string", "maxLength": 10 },
+				"scores": { "type": "array", "items": { "type": "number", "minimum": 0.0 }, "maxItems": 3 },
+			},
+			"additionalProperties": false,
+		})
+	);
+	assert_eq!(
+		JsonSchema::OneOf {
+			one_of: vec![JsonSchema::Null, JsonSchema::Boolean]
+		}
+		.to_json_schema(),
+		serde_json::json!({ "oneOf": [{ "type": "null" }, { "type": "boolean" }] })
+	);
+}
+
 static MODEL_PATH: &str = "../data/gpt2.bin";
 
 #[test]
@@ -261,15 +817,19 @@ pub fn test_json_biaser_objects() {
 	fields.insert(
 		"first_name".to_string(),
 		Box::new(JsonSchema::String {
+			min_length: None,
 			max_length: Some(5),
 			r#enum: None,
+			pattern: None,
 		}),
 	);
 	fields.insert(
 		"last_name".to_string(),
 		Box::new(JsonSchema::String {
+			min_length: None,
 			max_length: Some(7),
 			r#enum: None,
+			pattern: None,
 		}),
 	);
 
@@ -300,11 +860,13 @@ pub fn test_json_biaser() {
 
 	test_json_bias(
 		JsonSchema::String {
+			min_length: None,
 			max_length: Some(20),
 			r#enum: Some(vec![
 				"The quick brown fox".to_string(),
 				"Jumped over the".to_string(),
 				"The quick".to_string(),
+			pattern: None,
 			]),
 		},
 		model.as_ref(),
@@ -312,8 +874,10 @@ pub fn test_json_biaser() {
 
 	test_json_bias(
 		JsonSchema::String {
+			min_length: None,
 			max_length: Some(20),
 			r#enum: None,
+			pattern: None,
 		},
 		model.as_ref(),
 	);
@@ -323,6 +887,10 @@ pub fn test_json_biaser() {
 			max_decimals: Some(2),
 			min: Some(-0.32),
 			max: Some(5.87),
+			exclusive_min: None,
+			exclusive_max: None,
+			multiple_of: None,
+			r#enum: None,
 		},
 		model.as_ref(),
 	);
@@ -345,6 +913,10 @@ pub fn test_json_biaser() {
 					max_decimals: Some(2),
 					min: Some(-10.0),
 					max: Some(10.0),
+					exclusive_min: None,
+					exclusive_max: None,
+					multiple_of: None,
+					r#enum: None,
 				}),
 				min_items: Some(2),
 				max_items: Some(4),
@@ -356,13 +928,75 @@ pub fn test_json_biaser() {
 	);
 }
 
+#[test]
+pub fn test_shared_vocabulary() {
+	setup();
+	let model = llm::load_dynamic(
+		Some(ModelArchitecture::Gpt2),
+		Path::new(MODEL_PATH),
+		llm::TokenizerSource::Embedded,
+		ModelParameters::default(),
+		|_progress| {},
+	)
+	.unwrap();
+	let tokenizer = model.tokenizer();
+	let vocabulary = Arc::new(VocabularyInfo::new(tokenizer));
+	assert_eq!(vocabulary.len(), tokenizer.len());
+	assert_eq!(vocabulary.json_token(&JsonToken::CurlyOpen), JsonToken::CurlyOpen.token_id(tokenizer));
+	assert_eq!(vocabulary.json_token(&JsonToken::Digit(7)), JsonToken::Digit(7).token_id(tokenizer));
+	assert_eq!(vocabulary.json_token(&JsonToken::String(String::from("hello"))), None);
+
+	// String tokens never contain quotes and respect the maximum length
+	let short_tokens = vocabulary.string_tokens(Some(2));
+	assert!(!short_tokens.is_empty() && short_tokens.len() < vocabulary.string_tokens(None).len());
+	assert!(short_tokens
+		.iter()
+		.all(|token| vocabulary.text(*token).is_some_and(|s| !s.is_empty() && s.len() <= 2 && !s.contains('"'))));
+
+	// Biasers sharing the analysis bias the same way as biasers that analyze the vocabulary themselves
+	let schema = JsonSchema::Object {
+		required: vec![String::from("name")],
+		properties: HashMap::from([(
+			String::from("name"),
+			Box::new(JsonSchema::String {
+				min_length: None,
+				max_length: Some(5),
+				r#enum: None,
+				pattern: None,
+			}),
+		)]),
+	};
+	let mut own = JsonBiaser::new(&schema);
+	let mut shared = JsonBiaser::with_vocabulary(&schema, vocabulary.clone());
+	let eot = model.eot_token_id();
+	for json_token in [
+		JsonToken::CurlyOpen,
+		JsonToken::DoubleQuote,
+		JsonToken::String(String::from("na")),
+		JsonToken::String(String::from("me")),
+		JsonToken::DoubleQuote,
+		JsonToken::Colon,
+		JsonToken::DoubleQuote,
+	] {
+		let mut own_bias = own.bias(tokenizer, eot);
+		let mut shared_bias = shared.bias(tokenizer, eot);
+		own_bias.sort_by_key(|(token, _)| *token);
+		shared_bias.sort_by_key(|(token, _)| *token);
+		assert_eq!(own_bias, shared_bias);
+		own.advance(&json_token).unwrap();
+		shared.advance(&json_token).unwrap();
+	}
+	assert_eq!(Arc::strong_count(&vocabulary), 2);
+}
+
 fn test_json_bias(schema: JsonSchema, model: &dyn Model) {
 	setup();
+	let vocabulary = Arc::new(VocabularyInfo::new(model.tokenizer()));
 	for seed in [1340, 1338, 1339] {
 		println!("Run with seed {seed}");
 		let mut rng = rand::rngs::StdRng::seed_from_u64(seed); // Deterministic for tests
 
-		let mut bias = JsonBiaser::new(&schema);
+		let mut bias = JsonBiaser::with_vocabulary(&schema, vocabulary.clone());
 		let mut session = model.start_session(InferenceSessionConfig::default());
 		let vocab = model.tokenizer();
 
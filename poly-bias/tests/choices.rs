@@ -0,0 +1,105 @@
+use std::path::{Path, PathBuf};
+
+use llm::{TokenId, Tokenizer, TokenizerSource};
+use poly_bias::{choices::ChoicesBiaser, Biaser};
+
+/// End-of-text token of the mocked vocabulary ("</s>")
+const EOT: TokenId = 1;
+
+/// A mocked vocabulary in which most choices can be spelled in several ways (e.g. "positive" and "pos" + "itive")
+fn tokenizer() -> Tokenizer {
+	let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/../data/choices-tokenizer.json"));
+	TokenizerSource::HuggingFaceTokenizerFile(path).retrieve(Path::new("")).unwrap()
+}
+
+fn ids(vocabulary: &Tokenizer, texts: &[&str]) -> Vec<TokenId> {
+	let mut ids: Vec<TokenId> = texts
+		.iter()
+		.map(|text| {
+			(0..vocabulary.len())
+				.find(|token| vocabulary.token(*token) == text.as_bytes())
+				.unwrap_or_else(|| panic!("token for {text}")) as TokenId
+		})
+		.collect();
+	ids.sort();
+	ids
+}
+
+fn allowed(biaser: &ChoicesBiaser, vocabulary: &Tokenizer) -> Vec<TokenId> {
+	let mut tokens: Vec<TokenId> = biaser
+		.bias(vocabulary, EOT)
+		.into_iter()
+		.filter(|(_, bias)| *bias > 0.0)
+		.map(|(t, _)| t)
+		.collect();
+	tokens.sort();
+	tokens
+}
+
+fn advance(biaser: &mut ChoicesBiaser, vocabulary: &Tokenizer, texts: &[&str]) {
+	for text in texts {
+		biaser.advance(vocabulary, ids(vocabulary, &[text])[0]);
+	}
+}
+
+fn labels() -> Vec<String> {
+	["positive", "negative", "neutral"].map(String::from).to_vec()
+}
+
+#[test]
+pub fn test_choices_biaser() {
+	let vocabulary = tokenizer();
+	let mut biaser = ChoicesBiaser::new(labels());
+
+	// Any token that starts one of the choices is allowed, but the output cannot end yet
+	assert_eq!(
+		allowed(&biaser, &vocabulary),
+		ids(&vocabulary, &["pos", "positive", "neg", "negative", "neutral", "ne", "n"])
+	);
+	assert_eq!(biaser.choice(), None);
+
+	advance(&mut biaser, &vocabulary, &["ne"]);
+	assert_eq!(allowed(&biaser, &vocabulary), ids(&vocabulary, &["g", "u", "utral"]));
+	assert_eq!(biaser.closing_tokens(&vocabulary), Some(ids(&vocabulary, &["utral"])));
+
+	// Once a choice is complete, the output ends
+	advance(&mut biaser, &vocabulary, &["utral"]);
+	assert_eq!(allowed(&biaser, &vocabulary), vec![EOT]);
+	assert_eq!(biaser.choice(), Some(2));
+	assert_eq!(biaser.closing_tokens(&vocabulary), Some(vec![]));
+}
+
+#[test]
+pub fn test_choices_biaser_ambiguous() {
+	// A choice can be spelled with other tokens than the tokenizer would use
+	let vocabulary = tokenizer();
+	let mut biaser = ChoicesBiaser::new(labels());
+	advance(&mut biaser, &vocabulary, &["pos", "itive"]);
+	assert_eq!(biaser.choice(), Some(0));
+	assert_eq!(allowed(&biaser, &vocabulary), vec![EOT]);
+
+	let mut biaser = ChoicesBiaser::new(labels());
+	advance(&mut biaser, &vocabulary, &["neg", "ative"]);
+	assert_eq!(biaser.choice(), Some(1));
+}
+
+#[test]
+pub fn test_choices_biaser_prefix() {
+	let vocabulary = tokenizer();
+	let mut biaser = ChoicesBiaser::new(["no", "nope"].map(String::from).to_vec());
+	assert_eq!(allowed(&biaser, &vocabulary), ids(&vocabulary, &["n", "no", "nope"]));
+
+	// When forced to end, the output is closed with the shortest choice
+	advance(&mut biaser, &vocabulary, &["n"]);
+	assert_eq!(biaser.closing_tokens(&vocabulary), Some(ids(&vocabulary, &["o"])));
+
+	// A choice that is the start of a longer choice may end the output, but the longer choice can still be generated
+	advance(&mut biaser, &vocabulary, &["o"]);
+	assert_eq!(biaser.choice(), Some(0));
+	assert_eq!(allowed(&biaser, &vocabulary), vec![EOT, ids(&vocabulary, &["pe"])[0]]);
+	assert_eq!(biaser.closing_tokens(&vocabulary), Some(vec![]));
+
+	advance(&mut biaser, &vocabulary, &["pe"]);
+	assert_eq!(biaser.choice(), Some(1));
+	assert_eq!(allowed(&biaser, &vocabulary), vec![EOT]);
+}
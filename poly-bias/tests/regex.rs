@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+
+use llm::{TokenId, Tokenizer, TokenizerSource};
+use poly_bias::{regex::RegexBiaser, Biaser};
+
+/// End-of-text token of the mocked vocabulary ("</s>")
+const EOT: TokenId = 1;
+
+/// A mocked vocabulary with digits, words and tokens that span several characters of a pattern (e.g. "4-" and "-12-")
+fn tokenizer() -> Tokenizer {
+	let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/../data/regex-tokenizer.json"));
+	TokenizerSource::HuggingFaceTokenizerFile(path).retrieve(Path::new("")).unwrap()
+}
+
+fn token_id(vocabulary: &Tokenizer, text: &str) -> TokenId {
+	(0..vocabulary.len())
+		.find(|token| vocabulary.token(*token) == text.as_bytes())
+		.unwrap_or_else(|| panic!("token for {text}")) as TokenId
+}
+
+fn allowed(biaser: &RegexBiaser, vocabulary: &Tokenizer) -> Vec<TokenId> {
+	let mut tokens: Vec<TokenId> = biaser
+		.bias(vocabulary, EOT)
+		.into_iter()
+		.filter(|(_, bias)| *bias > 0.0)
+		.map(|(t, _)| t)
+		.collect();
+	tokens.sort();
+	tokens
+}
+
+/// Generate until the end-of-text token, each time choosing the longest token the biaser allows
+fn generate(biaser: &mut RegexBiaser, vocabulary: &Tokenizer) -> String {
+	let mut output = vec![];
+	loop {
+		let next = allowed(biaser, vocabulary)
+			.into_iter()
+			.max_by_key(|token| (vocabulary.token(*token as usize).len(), std::cmp::Reverse(*token)))
+			.expect("the biaser allows a token");
+		if next == EOT {
+			return String::from_utf8(output).unwrap();
+		}
+		biaser.advance(vocabulary, next);
+		output.extend(vocabulary.token(next as usize));
+	}
+}
+
+#[test]
+pub fn test_regex_biaser_date() {
+	let vocabulary = tokenizer();
+	let pattern = r"\d{4}-\d{2}-\d{2}";
+	let mut biaser = RegexBiaser::new(pattern).unwrap();
+
+	// Tokens that would take the output past a digit into a dash are not allowed at the start
+	let start = allowed(&biaser, &vocabulary);
+	assert!(start.contains(&token_id(&vocabulary, "2024")));
+	assert!(!start.contains(&token_id(&vocabulary, "4-")));
+	assert!(!start.contains(&token_id(&vocabulary, "-")));
+	assert!(!start.contains(&EOT));
+
+	// ...but they are after the third digit
+	biaser.advance(&vocabulary, token_id(&vocabulary, "20"));
+	biaser.advance(&vocabulary, token_id(&vocabulary, "2"));
+	let after_three = allowed(&biaser, &vocabulary);
+	assert!(after_three.contains(&token_id(&vocabulary, "4-")));
+	assert!(!after_three.contains(&token_id(&vocabulary, "2024")));
+	assert!(!after_three.contains(&token_id(&vocabulary, "-12-")));
+
+	let mut biaser = RegexBiaser::new(pattern).unwrap();
+	let output = generate(&mut biaser, &vocabulary);
+	assert_eq!(output, "2024-12-20");
+	assert!(biaser.can_end());
+
+	// Once complete, only the end-of-text token is allowed
+	assert_eq!(biaser.bias(&vocabulary, EOT), vec![(EOT, poly_bias::TOKEN_ALLOWED)]);
+	assert_eq!(biaser.closing_tokens(&vocabulary), Some(vec![]));
+
+	// Incomplete output is closed with the shortest sequence of tokens
+	let mut biaser = RegexBiaser::new(pattern).unwrap();
+	for text in ["2024", "-", "1"] {
+		biaser.advance(&vocabulary, token_id(&vocabulary, text));
+	}
+	let closing = biaser.closing_tokens(&vocabulary).unwrap();
+	assert_eq!(closing, vec![token_id(&vocabulary, "1-"), token_id(&vocabulary, "20")]);
+	for token in closing {
+		biaser.advance(&vocabulary, token);
+	}
+	assert!(biaser.can_end());
+}
+
+#[test]
+pub fn test_regex_biaser_alternatives() {
+	let vocabulary = tokenizer();
+	let mut biaser = RegexBiaser::new("(yes|no)").unwrap();
+	let ids = |texts: &[&str]| {
+		let mut ids: Vec<TokenId> = texts.iter().map(|text| token_id(&vocabulary, text)).collect();
+		ids.sort();
+		ids
+	};
+
+	// Only (prefixes of) the alternatives are allowed, not text that continues after them
+	assert_eq!(allowed(&biaser, &vocabulary), ids(&["y", "ye", "yes", "n", "no"]));
+	biaser.advance(&vocabulary, token_id(&vocabulary, "y"));
+	assert_eq!(allowed(&biaser, &vocabulary), ids(&["es"]));
+	biaser.advance(&vocabulary, token_id(&vocabulary, "es"));
+	assert_eq!(allowed(&biaser, &vocabulary), vec![EOT]);
+
+	let mut biaser = RegexBiaser::new("(yes|no)").unwrap();
+	assert_eq!(generate(&mut biaser, &vocabulary), "yes");
+}
+
+#[test]
+pub fn test_regex_biaser_bytes() {
+	// Tokens need not consist of whole characters: the bytes of 'é' (0xC3 0xA9) can be split over two tokens
+	let mut biaser = RegexBiaser::new("caf(é|e)").unwrap();
+	assert!(biaser.allows(b"caf\xC3"));
+	assert!(!biaser.allows(b"cafx"));
+	biaser.advance_bytes(b"caf\xC3");
+	assert!(!biaser.can_end());
+	assert!(biaser.allows(b"\xA9"));
+	assert!(!biaser.allows(b"\xA8"));
+	biaser.advance_bytes(b"\xA9");
+	assert!(biaser.can_end());
+	assert!(!biaser.allows(b"s"));
+}
@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use poly_client::{types::PromptRequest, Client, ClientError};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::config::ClientConfig;
+
+#[derive(Error, Debug)]
+pub enum CliError {
+	#[error("cannot read configuration file {path}: {message}")]
+	Config { path: PathBuf, message: String },
+
+	#[error("invalid parameter '{0}': must be written as key=value")]
+	InvalidParameter(String),
+
+	#[error("invalid parameters: {0}")]
+	InvalidParameterValue(String),
+
+	#[error("cannot read input: {0}")]
+	Input(#[from] std::io::Error),
+
+	#[error(transparent)]
+	Client(#[from] ClientError),
+}
+
+impl CliError {
+	/// Exit code for the process: 1 when the server reported an error, 2 for invalid usage or configuration and 3 when
+	/// the server could not be reached or the connection was lost
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			CliError::Client(ClientError::Api(_) | ClientError::UnexpectedResponse(_)) => 1,
+			CliError::Config { .. }
+			| CliError::InvalidParameter(_)
+			| CliError::InvalidParameterValue(_)
+			| CliError::Input(_)
+			| CliError::Client(ClientError::InvalidUrl(_) | ClientError::InvalidToken | ClientError::SegmentsNotSupported) => 2,
+			CliError::Client(ClientError::Http(_) | ClientError::WebSocket(_) | ClientError::Closed) => 3,
+		}
+	}
+}
+
+/// Create a client for the configured server
+pub fn connect(config: ClientConfig) -> Result<Client, CliError> {
+	let client = Client::new(config.url)?;
+	Ok(match config.token {
+		Some(token) => client.with_token(token),
+		None => client,
+	})
+}
+
+/// Parse parameters written as `key=value`. Values are interpreted as JSON when possible (e.g. `max_tokens=10`), and
+/// as a string otherwise (e.g. `stop=bye`).
+pub fn parse_params(params: &[String]) -> Result<Map<String, Value>, CliError> {
+	params
+		.iter()
+		.map(|param| {
+			let (key, value) = param
+				.split_once('=')
+				.filter(|(key, _)| !key.is_empty())
+				.ok_or_else(|| CliError::InvalidParameter(param.clone()))?;
+			let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+			Ok((key.to_string(), value))
+		})
+		.collect()
+}
+
+/// The request for a completion of a prompt with the given parameters (see [`parse_params`])
+pub fn prompt_request(prompt: &str, params: Map<String, Value>) -> Result<PromptRequest, CliError> {
+	let mut request = params;
+	request.insert(String::from("prompt"), Value::String(prompt.to_string()));
+	serde_json::from_value(Value::Object(request)).map_err(|e| CliError::InvalidParameterValue(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+	use serde_json::json;
+
+	use super::{parse_params, prompt_request, CliError};
+
+	#[test]
+	fn test_parse_params() {
+		let params = parse_params(&[
+			String::from("max_tokens=10"),
+			String::from("temperature=0.5"),
+			String::from("stop=bye"),
+			String::from("prefix=a=b"),
+		])
+		.unwrap();
+		assert_eq!(params["max_tokens"], json!(10));
+		assert_eq!(params["temperature"], json!(0.5));
+		assert_eq!(params["stop"], json!("bye"));
+		assert_eq!(params["prefix"], json!("a=b"));
+
+		assert!(matches!(parse_params(&[String::from("max_tokens")]), Err(CliError::InvalidParameter(_))));
+		assert!(matches!(parse_params(&[String::from("=10")]), Err(CliError::InvalidParameter(_))));
+	}
+
+	#[test]
+	fn test_prompt_request() {
+		let params = parse_params(&[String::from("max_tokens=10"), String::from("stop=[\"bye\"]")]).unwrap();
+		let request = prompt_request("Hello", params).unwrap();
+		assert_eq!(request.prompt, "Hello");
+		assert_eq!(request.max_tokens, Some(10));
+		assert_eq!(request.stop, vec!["bye"]);
+
+		let params = parse_params(&[String::from("max_tokens=many")]).unwrap();
+		assert!(matches!(prompt_request("Hello", params), Err(CliError::InvalidParameterValue(_))));
+	}
+}
@@ -0,0 +1,91 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::client::CliError;
+
+/// Server URL used when none is configured
+pub const DEFAULT_URL: &str = "http://localhost:3000";
+
+/// Contents of the configuration file (by default `~/.config/llmc.toml`)
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+	/// URL of the server, e.g. "http://localhost:3000"
+	pub url: Option<String>,
+
+	/// Static API key or JWT to authenticate with
+	pub token: Option<String>,
+}
+
+impl FileConfig {
+	pub fn default_path() -> Option<PathBuf> {
+		directories::BaseDirs::new().map(|dirs| dirs.home_dir().join(".config").join("llmc.toml"))
+	}
+
+	/// Read a configuration file. A file that does not exist is treated as an empty configuration.
+	pub fn read(path: &Path) -> Result<FileConfig, CliError> {
+		let source = match std::fs::read_to_string(path) {
+			Ok(source) => source,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(FileConfig::default()),
+			Err(e) => {
+				return Err(CliError::Config {
+					path: path.to_path_buf(),
+					message: e.to_string(),
+				})
+			}
+		};
+		toml::from_str(&source).map_err(|e| CliError::Config {
+			path: path.to_path_buf(),
+			message: e.to_string(),
+		})
+	}
+}
+
+/// Settings used to connect to the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientConfig {
+	pub url: String,
+	pub token: Option<String>,
+}
+
+impl ClientConfig {
+	/// Combine the settings given as flags or environment variables with those in the configuration file. Flags and
+	/// environment variables take precedence.
+	pub fn resolve(url: Option<String>, token: Option<String>, file: FileConfig) -> ClientConfig {
+		ClientConfig {
+			url: url.or(file.url).unwrap_or_else(|| DEFAULT_URL.to_string()),
+			token: token.or(file.token).filter(|t| !t.is_empty()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{ClientConfig, FileConfig, DEFAULT_URL};
+
+	#[test]
+	fn test_resolve() {
+		let file: FileConfig = toml::from_str(
+			r#"
+			url = "https://llm.example.com"
+			token = "from-file"
+			"#,
+		)
+		.unwrap();
+
+		let config = ClientConfig::resolve(None, Some(String::from("from-flag")), file.clone());
+		assert_eq!(config.url, "https://llm.example.com");
+		assert_eq!(config.token.as_deref(), Some("from-flag"));
+
+		let config = ClientConfig::resolve(Some(String::from("http://localhost:1234")), None, file);
+		assert_eq!(config.url, "http://localhost:1234");
+		assert_eq!(config.token.as_deref(), Some("from-file"));
+
+		let config = ClientConfig::resolve(None, Some(String::new()), FileConfig::default());
+		assert_eq!(config.url, DEFAULT_URL);
+		assert_eq!(config.token, None);
+
+		assert!(toml::from_str::<FileConfig>("api_key = \"x\"").is_err());
+	}
+}
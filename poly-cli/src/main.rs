@@ -0,0 +1,208 @@
+use std::{
+	io::{IsTerminal, Read, Write},
+	path::PathBuf,
+};
+
+use clap::{Parser, Subcommand};
+use futures_util::StreamExt;
+use poly_client::{
+	types::{PromptRequest, RecallRequest},
+	Client, ClientError, TokenEvent,
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use client::{connect, parse_params, prompt_request, CliError};
+use config::{ClientConfig, FileConfig};
+
+mod client;
+mod config;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Command-line client for llmd", long_about = None)]
+struct Args {
+	/// URL of the server [default: http://localhost:3000]
+	#[arg(long, short = 'u', env = "LLMC_URL", global = true)]
+	url: Option<String>,
+
+	/// Static API key or JWT to authenticate with
+	#[arg(long, short = 't', env = "LLMC_TOKEN", global = true, hide_env_values = true)]
+	token: Option<String>,
+
+	/// Configuration file to read the URL and token from when they are not given as flags or environment variables
+	/// [default: ~/.config/llmc.toml]
+	#[arg(long, short = 'c', env = "LLMC_CONFIG", global = true)]
+	config_path: Option<PathBuf>,
+
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Generate a completion for a prompt
+	Complete {
+		task: String,
+
+		/// The prompt (read from standard input when not given)
+		prompt: Vec<String>,
+
+		/// Print tokens as they are generated
+		#[arg(long, short = 's')]
+		stream: bool,
+
+		/// Parameter of the request, e.g. `--param max_tokens=10` (values are interpreted as JSON when possible)
+		#[arg(long = "param", short = 'p', value_name = "KEY=VALUE")]
+		params: Vec<String>,
+	},
+
+	/// Chat with a task interactively
+	Chat { task: String },
+
+	/// Calculate the embedding of a prompt using a model
+	Embed {
+		model: String,
+
+		/// The prompt (read from standard input when not given)
+		prompt: Vec<String>,
+	},
+
+	/// Store text in a memory
+	Remember {
+		memory: String,
+
+		/// The text (read from standard input when not given)
+		text: Vec<String>,
+
+		/// Return without waiting for the text to be stored
+		#[arg(long)]
+		no_wait: bool,
+	},
+
+	/// Find the chunks in a memory that are most similar to a prompt
+	Recall {
+		memory: String,
+
+		/// The prompt (read from standard input when not given)
+		prompt: Vec<String>,
+
+		/// Number of chunks to return
+		#[arg(long, short = 'n', default_value_t = 1)]
+		n: usize,
+	},
+
+	/// Show statistics of the server
+	Stats,
+}
+
+#[tokio::main]
+async fn main() {
+	let args = Args::parse();
+	if let Err(e) = run(args).await {
+		eprintln!("error: {e}");
+		std::process::exit(e.exit_code());
+	}
+}
+
+async fn run(args: Args) -> Result<(), CliError> {
+	let file_config = match args.config_path.or_else(FileConfig::default_path) {
+		Some(path) => FileConfig::read(&path)?,
+		None => FileConfig::default(),
+	};
+	let client = connect(ClientConfig::resolve(args.url, args.token, file_config))?;
+
+	match args.command {
+		Command::Complete {
+			task,
+			prompt,
+			stream,
+			params,
+		} => {
+			let params = parse_params(&params)?;
+			let request = prompt_request(&input(prompt)?, params)?;
+			if stream {
+				stream_completion(&client, &task, &request).await?;
+			} else {
+				print!("{}", client.complete(&task, &request).await?.text);
+			}
+			println!();
+		}
+
+		Command::Chat { task } => chat(&client, &task).await?,
+
+		Command::Embed { model, prompt } => {
+			let response = client.embed(&model, &PromptRequest::new(input(prompt)?)).await?;
+			println!("{}", serde_json::to_string(&response.embedding).unwrap());
+		}
+
+		Command::Remember { memory, text, no_wait } => client.remember(&memory, &input(text)?, !no_wait).await?,
+
+		Command::Recall { memory, prompt, n } => {
+			let request = RecallRequest {
+				prompt: Some(input(prompt)?),
+				embedding: None,
+				n: Some(n),
+				language: None,
+			};
+			for chunk in client.recall(&memory, &request).await?.chunks {
+				println!("{chunk}");
+			}
+		}
+
+		Command::Stats => println!("{}", serde_json::to_string_pretty(&client.stats().await?).unwrap()),
+	}
+	Ok(())
+}
+
+/// The words given as arguments, or the contents of standard input when there are none
+fn input(words: Vec<String>) -> Result<String, CliError> {
+	if !words.is_empty() {
+		return Ok(words.join(" "));
+	}
+
+	if std::io::stdin().is_terminal() {
+		eprintln!("reading from standard input (end with Ctrl-D)");
+	}
+	let mut text = String::new();
+	std::io::stdin().read_to_string(&mut text)?;
+	Ok(text.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn print_token(token: &str) {
+	print!("{token}");
+	_ = std::io::stdout().flush();
+}
+
+/// Generate a completion, printing tokens as they arrive
+async fn stream_completion(client: &Client, task: &str, request: &PromptRequest) -> Result<(), CliError> {
+	let mut events = std::pin::pin!(client.complete_streaming(task, request).await?);
+	while let Some(event) = events.next().await {
+		match event? {
+			TokenEvent::Token { text } => print_token(&text),
+			TokenEvent::Done(_) => return Ok(()),
+			TokenEvent::Open { .. } => {}
+		}
+	}
+
+	// The stream ends with a `done` event, unless the connection was lost
+	Err(ClientError::Closed.into())
+}
+
+async fn chat(client: &Client, task: &str) -> Result<(), CliError> {
+	let mut chat = client.chat(task).await?;
+	let mut editor = DefaultEditor::new().map_err(|e| CliError::Input(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+
+	loop {
+		let line = match editor.readline("> ") {
+			Ok(line) => line,
+			Err(ReadlineError::Interrupted | ReadlineError::Eof) => return Ok(()),
+			Err(e) => return Err(CliError::Input(std::io::Error::new(std::io::ErrorKind::Other, e))),
+		};
+		if line.trim().is_empty() {
+			continue;
+		}
+		_ = editor.add_history_entry(line.as_str());
+
+		chat.send(&line, print_token).await?;
+		println!();
+	}
+}
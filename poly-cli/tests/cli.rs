@@ -0,0 +1,54 @@
+use std::{process::Command, sync::Arc};
+
+use poly_backend::backend::Backend;
+use poly_server::{
+	config::Config,
+	routes,
+	server::{serve, Server},
+};
+
+/// Start a public server without models, returning its URL
+async fn start_server() -> String {
+	let mut config = Config::default();
+	config.public = true;
+	config.backend_config.cache_path = Some(std::env::temp_dir().join("poly-cli-test"));
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+	format!("http://{}", listening.addresses[0])
+}
+
+async fn llmc(url: &str, args: &[&str]) -> std::process::Output {
+	let mut command = Command::new(env!("CARGO_BIN_EXE_llmc"));
+	command
+		.args(args)
+		.env("LLMC_URL", url)
+		.env("LLMC_CONFIG", std::env::temp_dir().join("poly-cli-test-missing.toml"))
+		.env_remove("LLMC_TOKEN");
+	tokio::task::spawn_blocking(move || command.output().unwrap()).await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_llmc() {
+	let url = start_server().await;
+
+	let output = llmc(&url, &["stats"]).await;
+	assert!(output.status.success());
+	let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+	assert_eq!(stats["active"]["chats"], 0);
+
+	// Errors reported by the server result in exit code 1
+	let output = llmc(&url, &["embed", "missing", "Hello"]).await;
+	assert_eq!(output.status.code(), Some(1));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("model_not_found"));
+
+	let output = llmc(&url, &["complete", "missing", "--param", "max_tokens=10", "Hello"]).await;
+	assert_eq!(output.status.code(), Some(1));
+	assert!(String::from_utf8_lossy(&output.stderr).contains("task_not_found"));
+
+	// Invalid usage results in exit code 2, and an unreachable server in exit code 3
+	let output = llmc(&url, &["complete", "missing", "--param", "max_tokens", "Hello"]).await;
+	assert_eq!(output.status.code(), Some(2));
+	let output = llmc("http://127.0.0.1:1", &["stats"]).await;
+	assert_eq!(output.status.code(), Some(3));
+}
@@ -0,0 +1,103 @@
+use futures_util::{SinkExt, StreamExt};
+use poly_backend::types::{ChatClientMessage, ChatServerMessage, ToolCall, ToolResult};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::error::{ApiError, ClientError};
+
+/// The response of a task to a prompt or tool result
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatReply {
+	/// The generated text
+	Text(String),
+
+	/// The task called a tool
+	ToolCall(ToolCall),
+}
+
+/// A chat over the WebSocket of a task (see [`crate::Client::chat`]). Prompts are completed in the same session, so
+/// later prompts can refer to earlier ones.
+pub struct Chat {
+	socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+	session_token: Option<String>,
+}
+
+impl Chat {
+	pub(crate) fn new(socket: WebSocketStream<MaybeTlsStream<TcpStream>>) -> Chat {
+		Chat { socket, session_token: None }
+	}
+
+	/// The token with which the server allows the chat to be resumed after the connection is lost (known after the first
+	/// response, and only when the server keeps chats)
+	pub fn session_token(&self) -> Option<&str> {
+		self.session_token.as_deref()
+	}
+
+	/// Send a prompt and call `on_token` for each token of the response as it arrives. Returns the full response. Fails
+	/// when the task calls a tool instead (use [`Chat::send_with_tools`] for tasks that have tools).
+	pub async fn send(&mut self, prompt: &str, on_token: impl FnMut(&str)) -> Result<String, ClientError> {
+		match self.send_with_tools(prompt, on_token).await? {
+			ChatReply::Text(text) => Ok(text),
+			ChatReply::ToolCall(call) => Err(ClientError::UnexpectedResponse(format!("the task called tool '{}'", call.tool))),
+		}
+	}
+
+	/// Send a prompt like [`Chat::send`], but return a call of a tool by the task instead of failing. The result of the
+	/// call should be sent using [`Chat::send_tool_result`].
+	pub async fn send_with_tools(&mut self, prompt: &str, on_token: impl FnMut(&str)) -> Result<ChatReply, ClientError> {
+		self.exchange(&ChatClientMessage::prompt(prompt), on_token).await
+	}
+
+	/// Send the result of a tool call, to which the task responds like to a prompt
+	pub async fn send_tool_result(&mut self, result: ToolResult, on_token: impl FnMut(&str)) -> Result<ChatReply, ClientError> {
+		let message = ChatClientMessage::ToolResult {
+			tool: result.tool,
+			result: result.result,
+		};
+		self.exchange(&message, on_token).await
+	}
+
+	async fn exchange(&mut self, message: &ChatClientMessage, mut on_token: impl FnMut(&str)) -> Result<ChatReply, ClientError> {
+		self.socket.send(Message::Text(serde_json::to_string(message).unwrap())).await?;
+
+		let mut response = String::new();
+		let mut tool_call = None;
+		loop {
+			match self.socket.next().await {
+				Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+					Ok(ChatServerMessage::Session { token, .. }) => self.session_token = Some(token),
+					Ok(ChatServerMessage::Queued { .. }) => {}
+					Ok(ChatServerMessage::Token { text }) => {
+						on_token(&text);
+						response.push_str(&text);
+					}
+					Ok(ChatServerMessage::ToolCall { tool, arguments }) => tool_call = Some(ToolCall { tool, arguments }),
+					Ok(ChatServerMessage::End { .. }) => {
+						return Ok(match tool_call {
+							Some(call) => ChatReply::ToolCall(call),
+							None => ChatReply::Text(response),
+						})
+					}
+					Ok(ChatServerMessage::Error { message }) => {
+						return Err(ClientError::Api(ApiError {
+							status: None,
+							kind: None,
+							message,
+							retryable: false,
+						}))
+					}
+					Err(e) => return Err(ClientError::UnexpectedResponse(e.to_string())),
+				},
+				Some(Ok(Message::Close(_))) | None => return Err(ClientError::Closed),
+				Some(Ok(_)) => {}
+				Some(Err(e)) => return Err(e.into()),
+			}
+		}
+	}
+
+	/// Close the connection
+	pub async fn close(mut self) -> Result<(), ClientError> {
+		self.socket.close(None).await?;
+		Ok(())
+	}
+}
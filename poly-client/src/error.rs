@@ -0,0 +1,85 @@
+use std::fmt;
+
+use reqwest::{Response, StatusCode};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+	#[error("invalid server URL '{0}': must start with http:// or https://")]
+	InvalidUrl(String),
+
+	#[error("the token cannot be sent in a header")]
+	InvalidToken,
+
+	#[error("prompts consisting of segments cannot be streamed")]
+	SegmentsNotSupported,
+
+	#[error("request failed: {0}")]
+	Http(#[from] reqwest::Error),
+
+	#[error("connection failed: {0}")]
+	WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+
+	#[error("the server closed the connection")]
+	Closed,
+
+	#[error("unexpected response from server: {0}")]
+	UnexpectedResponse(String),
+
+	#[error("{0}")]
+	Api(ApiError),
+}
+
+/// An error reported by the server
+#[derive(Debug, Clone)]
+pub struct ApiError {
+	/// Status of the response (not set for errors reported on a chat WebSocket)
+	pub status: Option<StatusCode>,
+
+	/// Machine-readable kind of error (e.g. `context_full`), when the server reported one
+	pub kind: Option<String>,
+	pub message: String,
+
+	/// Whether the same request may succeed when it is sent again later
+	pub retryable: bool,
+}
+
+impl fmt::Display for ApiError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (self.status, &self.kind) {
+			(Some(status), Some(kind)) => write!(f, "server responded with status {status} ({kind}): {}", self.message),
+			(Some(status), None) => write!(f, "server responded with status {status}: {}", self.message),
+			(None, _) => write!(f, "server reported an error: {}", self.message),
+		}
+	}
+}
+
+impl ApiError {
+	pub(crate) async fn from_response(response: Response) -> ApiError {
+		#[derive(Deserialize)]
+		struct ErrorResponse {
+			error: String,
+			message: String,
+			#[serde(default)]
+			retryable: bool,
+		}
+
+		let status = response.status();
+		let body = response.text().await.unwrap_or_default();
+		match serde_json::from_str::<ErrorResponse>(&body) {
+			Ok(error) => ApiError {
+				status: Some(status),
+				kind: Some(error.error),
+				message: error.message,
+				retryable: error.retryable,
+			},
+			Err(_) => ApiError {
+				status: Some(status),
+				kind: None,
+				message: body,
+				retryable: status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE,
+			},
+		}
+	}
+}
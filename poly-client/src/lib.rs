@@ -0,0 +1,253 @@
+//! Client for the HTTP and WebSocket APIs of llmd. Requests and responses use the types of [`poly_backend::types`],
+//! which are also used by the server.
+
+use std::time::Duration;
+
+use async_stream::stream;
+use futures_util::{Stream, StreamExt};
+use poly_backend::types::{EmbeddingResponse, GenerateResponse, LiveDone, PromptRequest, RecallRequest, RecallResponse, StatsResponse};
+use reqwest::{header::RETRY_AFTER, Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue};
+
+pub use chat::{Chat, ChatReply};
+pub use error::{ApiError, ClientError};
+pub use poly_backend::types;
+
+use sse::{SseEvent, SseParser};
+
+mod chat;
+mod error;
+mod sse;
+
+/// Number of times a request is retried when the server responds that there are too many requests
+pub const DEFAULT_MAX_RETRIES: usize = 3;
+
+/// Time to wait before retrying when the server does not say how long to wait
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest time to wait before retrying. When the server asks to wait longer, the request fails instead.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// An event received while streaming a completion (see [`Client::complete_streaming`])
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenEvent {
+	/// The stream was opened, before any token was generated. Holds an identifier of the generation.
+	Open { generation_id: String },
+
+	/// A generated token
+	Token { text: String },
+
+	/// The completion is done. This is the last event of a stream that was not cut off.
+	Done(LiveDone),
+}
+
+impl TokenEvent {
+	/// The event for an event received from the server, if it is of interest. An `error` event becomes an error.
+	fn from_sse(event: SseEvent) -> Option<Result<TokenEvent, ClientError>> {
+		match (event.event.as_deref(), event.id.as_deref()) {
+			(Some("open"), _) => Some(Ok(TokenEvent::Open { generation_id: event.data })),
+			(None, Some("token")) => Some(Ok(TokenEvent::Token { text: event.data })),
+			(Some("done"), _) => Some(
+				serde_json::from_str(&event.data)
+					.map(TokenEvent::Done)
+					.map_err(|e| ClientError::UnexpectedResponse(e.to_string())),
+			),
+			(Some("error"), _) => Some(Err(ClientError::Api(ApiError {
+				status: None,
+				kind: None,
+				message: event.data,
+				retryable: false,
+			}))),
+			_ => None,
+		}
+	}
+}
+
+/// Client for the API of llmd
+#[derive(Clone)]
+pub struct Client {
+	url: String,
+	token: Option<String>,
+	max_retries: usize,
+	http: reqwest::Client,
+}
+
+impl Client {
+	/// Create a client for the server at `url` (e.g. "http://localhost:3000")
+	pub fn new(url: impl Into<String>) -> Result<Client, ClientError> {
+		let url = url.into();
+		if !url.starts_with("http://") && !url.starts_with("https://") {
+			return Err(ClientError::InvalidUrl(url));
+		}
+		Ok(Client {
+			url: url.trim_end_matches('/').to_string(),
+			token: None,
+			max_retries: DEFAULT_MAX_RETRIES,
+			http: reqwest::Client::new(),
+		})
+	}
+
+	/// Authenticate using a static API key or JWT, which is sent as bearer token
+	pub fn with_token(mut self, token: impl Into<String>) -> Client {
+		self.token = Some(token.into());
+		self
+	}
+
+	/// Set the number of times a request is retried when the server responds with status 429 (too many requests)
+	pub fn with_max_retries(mut self, max_retries: usize) -> Client {
+		self.max_retries = max_retries;
+		self
+	}
+
+	fn request(&self, method: Method, path: &str) -> RequestBuilder {
+		let request = self.http.request(method, format!("{}{path}", self.url));
+		match self.token {
+			Some(ref token) => request.bearer_auth(token),
+			None => request,
+		}
+	}
+
+	/// Send a request, retrying when there are too many requests and turning error responses into [`ClientError::Api`]
+	async fn send(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+		let mut attempt = 0;
+		loop {
+			// Bodies are never streamed, so requests can always be cloned
+			let response = request.try_clone().unwrap().send().await?;
+			if response.status() == StatusCode::TOO_MANY_REQUESTS && attempt < self.max_retries {
+				let delay = retry_after(&response).unwrap_or(DEFAULT_RETRY_DELAY);
+				if delay <= MAX_RETRY_DELAY {
+					attempt += 1;
+					tokio::time::sleep(delay).await;
+					continue;
+				}
+			}
+
+			if !response.status().is_success() {
+				return Err(ClientError::Api(ApiError::from_response(response).await));
+			}
+			return Ok(response);
+		}
+	}
+
+	async fn json<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, ClientError> {
+		let body = self.send(request).await?.text().await?;
+		serde_json::from_str(&body).map_err(|e| ClientError::UnexpectedResponse(e.to_string()))
+	}
+
+	/// Generate a completion for a prompt using a task
+	pub async fn complete(&self, task: &str, request: &PromptRequest) -> Result<GenerateResponse, ClientError> {
+		self.json(self.request(Method::POST, &format!("/v1/task/{task}/completion")).json(request))
+			.await
+	}
+
+	/// Generate a completion for a prompt using a task, returning the tokens as they are generated. The prompt is sent in
+	/// the query string, and therefore cannot consist of segments. The stream ends after [`TokenEvent::Done`] or an
+	/// error; when the connection is lost before the completion is done, it ends with [`ClientError::Closed`].
+	pub async fn complete_streaming(
+		&self,
+		task: &str,
+		request: &PromptRequest,
+	) -> Result<impl Stream<Item = Result<TokenEvent, ClientError>>, ClientError> {
+		if !request.segments.is_empty() {
+			return Err(ClientError::SegmentsNotSupported);
+		}
+
+		let response = self
+			.send(self.request(Method::GET, &format!("/v1/task/{task}/live")).query(request))
+			.await?;
+		let mut body = response.bytes_stream();
+		Ok(stream! {
+			let mut parser = SseParser::default();
+			while let Some(chunk) = body.next().await {
+				match chunk {
+					Ok(chunk) => {
+						for event in parser.feed(&chunk).into_iter().filter_map(TokenEvent::from_sse) {
+							let last = !matches!(event, Ok(TokenEvent::Open { .. } | TokenEvent::Token { .. }));
+							yield event;
+							if last {
+								return;
+							}
+						}
+					}
+					Err(e) => {
+						yield Err(e.into());
+						return;
+					}
+				}
+			}
+			yield Err(ClientError::Closed);
+		})
+	}
+
+	/// Calculate the embedding of a prompt using a model
+	pub async fn embed(&self, model: &str, request: &PromptRequest) -> Result<EmbeddingResponse, ClientError> {
+		self.json(self.request(Method::POST, &format!("/v1/model/{model}/embedding")).json(request))
+			.await
+	}
+
+	/// Store text in a memory. When `wait` is false, the server stores the text in the background.
+	pub async fn remember(&self, memory: &str, text: &str, wait: bool) -> Result<(), ClientError> {
+		let request = self
+			.request(Method::PUT, &format!("/v1/memory/{memory}"))
+			.query(&[("wait", wait)])
+			.header(reqwest::header::CONTENT_TYPE, "text/plain")
+			.body(text.to_string());
+		self.send(request).await?;
+		Ok(())
+	}
+
+	/// Find the chunks in a memory that are most similar to a prompt
+	pub async fn recall(&self, memory: &str, request: &RecallRequest) -> Result<RecallResponse, ClientError> {
+		self.json(self.request(Method::POST, &format!("/v1/memory/{memory}")).json(request)).await
+	}
+
+	pub async fn stats(&self) -> Result<StatsResponse, ClientError> {
+		self.json(self.request(Method::GET, "/v1/stats")).await
+	}
+
+	/// Connect to the chat WebSocket of a task
+	pub async fn chat(&self, task: &str) -> Result<Chat, ClientError> {
+		let url = format!("{}/v1/task/{task}/chat?format=json", self.url.replacen("http", "ws", 1));
+		let mut request = url.into_client_request()?;
+		if let Some(ref token) = self.token {
+			let value = HeaderValue::from_str(&format!("Bearer {token}")).map_err(|_| ClientError::InvalidToken)?;
+			request.headers_mut().insert("Authorization", value);
+		}
+		let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+		Ok(Chat::new(socket))
+	}
+}
+
+/// The time to wait before retrying a request, as given in the `Retry-After` header of the response (only the number of
+/// seconds is supported, not a date)
+fn retry_after(response: &Response) -> Option<Duration> {
+	let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+	Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+	use super::{sse::SseEvent, TokenEvent};
+
+	#[test]
+	fn test_token_event() {
+		let event = |event: Option<&str>, id: Option<&str>, data: &str| SseEvent {
+			event: event.map(String::from),
+			id: id.map(String::from),
+			data: data.to_string(),
+		};
+
+		assert_eq!(
+			TokenEvent::from_sse(event(Some("open"), None, "0123")),
+			Some(TokenEvent::Open {
+				generation_id: String::from("0123")
+			})
+		);
+		assert_eq!(
+			TokenEvent::from_sse(event(None, Some("token"), "Hello")),
+			Some(TokenEvent::Token { text: String::from("Hello") })
+		);
+		assert_eq!(TokenEvent::from_sse(event(Some("other"), None, "x")), None);
+	}
+}
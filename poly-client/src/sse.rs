@@ -0,0 +1,102 @@
+/// An event received from a server-sent event stream
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SseEvent {
+	/// Type of the event (`None` for plain messages)
+	pub event: Option<String>,
+	pub id: Option<String>,
+	pub data: String,
+}
+
+/// Incremental parser for `text/event-stream` bodies
+#[derive(Default)]
+pub struct SseParser {
+	buffer: Vec<u8>,
+	current: SseEvent,
+	has_data: bool,
+}
+
+impl SseParser {
+	/// Parse a chunk of the body. Returns the events that are completed by the chunk; a partial event is kept until the
+	/// rest of it arrives.
+	pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+		self.buffer.extend_from_slice(chunk);
+		let mut events = vec![];
+
+		while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+			let line: Vec<u8> = self.buffer.drain(..=end).collect();
+			let line = String::from_utf8_lossy(&line[..end]);
+			let line = line.strip_suffix('\r').unwrap_or(&line);
+
+			// An empty line ends the event; events without data (such as keep-alive events) are not dispatched
+			if line.is_empty() {
+				let event = std::mem::take(&mut self.current);
+				if std::mem::take(&mut self.has_data) {
+					events.push(event);
+				}
+				continue;
+			}
+
+			// Lines starting with a colon are comments (e.g. keep-alive comments)
+			if line.starts_with(':') {
+				continue;
+			}
+
+			let (field, value) = match line.split_once(':') {
+				Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+				None => (line, ""),
+			};
+			match field {
+				"event" => self.current.event = Some(value.to_string()),
+				"id" => self.current.id = Some(value.to_string()),
+				"data" => {
+					if self.has_data {
+						self.current.data.push('\n');
+					}
+					self.current.data.push_str(value);
+					self.has_data = true;
+				}
+				_ => {}
+			}
+		}
+		events
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{SseEvent, SseParser};
+
+	#[test]
+	fn test_sse_parser() {
+		let mut parser = SseParser::default();
+		let body = "event: open\ndata: 0123\n\n: keep-alive-text\n\nid: token\ndata: Hello\n\nevent: keep-alive\n\nid: token\ndata: ,\r\ndata:  world\r\n\r\n";
+
+		// Events are the same regardless of how the body is split into chunks
+		let mut events = vec![];
+		for chunk in body.as_bytes().chunks(5) {
+			events.extend(parser.feed(chunk));
+		}
+
+		let token = |data: &str| SseEvent {
+			event: None,
+			id: Some(String::from("token")),
+			data: data.to_string(),
+		};
+		assert_eq!(
+			events,
+			vec![
+				SseEvent {
+					event: Some(String::from("open")),
+					id: None,
+					data: String::from("0123"),
+				},
+				token("Hello"),
+				token(",\n world"),
+			]
+		);
+
+		// An incomplete event is not returned until it is complete
+		assert!(parser.feed(b"id: token\ndata: !\n").is_empty());
+		assert_eq!(parser.feed(b"\n"), vec![token("!")]);
+	}
+}
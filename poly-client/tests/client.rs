@@ -0,0 +1,309 @@
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+};
+
+use axum::{
+	http::{header::RETRY_AFTER, StatusCode},
+	response::IntoResponse,
+	routing::get,
+	Json, Router,
+};
+use futures_util::{SinkExt, StreamExt};
+use poly_backend::{
+	backend::Backend,
+	config::Secret,
+	types::{ActiveStats, ChatServerMessage, FinishReason, PromptRequest, PromptSegment, RecallRequest, StatsResponse},
+};
+use poly_client::{Client, ClientError};
+use poly_server::{
+	config::Config,
+	routes,
+	server::{serve, Server},
+};
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// Start a server without models that accepts the key "secret", returning its URL
+async fn start_server() -> String {
+	let mut config = Config::default();
+	config.allowed_keys = vec![Secret::new("secret")];
+	config.backend_config.cache_path = Some(std::env::temp_dir().join("poly-client-test"));
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+	format!("http://{}", listening.addresses[0])
+}
+
+/// The kind of error reported by the server
+fn kind<T>(result: Result<T, ClientError>) -> String {
+	match result {
+		Err(ClientError::Api(error)) => error.kind.unwrap_or_default(),
+		Err(e) => panic!("unexpected error {e}"),
+		Ok(_) => panic!("expected an error"),
+	}
+}
+
+#[tokio::test]
+async fn test_client() {
+	let url = start_server().await;
+
+	// Requests without a valid token are refused
+	let error = Client::new(&url).unwrap().stats().await.unwrap_err();
+	assert!(matches!(error, ClientError::Api(ref e) if e.status == Some(StatusCode::UNAUTHORIZED)));
+
+	let client = Client::new(&url).unwrap().with_token("secret");
+	let stats = client.stats().await.unwrap();
+	assert_eq!(stats.active.chats, 0);
+	assert!(stats.tasks.is_empty());
+
+	// Errors of the backend are reported with their kind
+	let prompt = PromptRequest::new("Hello");
+	assert_eq!(kind(client.complete("missing", &prompt).await), "task_not_found");
+	assert_eq!(kind(client.complete_streaming("missing", &prompt).await), "task_not_found");
+	assert_eq!(kind(client.embed("missing", &prompt).await), "model_not_found");
+	assert_eq!(kind(client.remember("missing", "Hello", true).await), "memory_not_found");
+	let recall = RecallRequest {
+		prompt: Some(String::from("Hello")),
+		embedding: None,
+		n: Some(2),
+		language: None,
+	};
+	assert_eq!(kind(client.recall("missing", &recall).await), "memory_not_found");
+
+	// Prompts consisting of segments cannot be sent in the query string of a live stream
+	let segmented = PromptRequest {
+		segments: vec![PromptSegment {
+			text: String::from("Hello"),
+			..Default::default()
+		}],
+		..Default::default()
+	};
+	assert!(matches!(
+		client.complete_streaming("missing", &segmented).await,
+		Err(ClientError::SegmentsNotSupported)
+	));
+
+	assert!(matches!(Client::new("localhost:3000"), Err(ClientError::InvalidUrl(_))));
+}
+
+/// Start a server that responds to the first `limited` requests for statistics with status 429
+async fn start_limited_server(limited: usize) -> SocketAddr {
+	let requests = Arc::new(AtomicUsize::new(0));
+	let router = Router::new().route(
+		"/v1/stats",
+		get(move || async move {
+			if requests.fetch_add(1, Ordering::SeqCst) < limited {
+				return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, "0")], "slow down").into_response();
+			}
+			Json(StatsResponse {
+				tasks: HashMap::new(),
+				models: HashMap::new(),
+				active: ActiveStats {
+					sessions: 0,
+					chats: 0,
+					parked_chats: 0,
+					live_streams: 0,
+					inference_busy: 0,
+					inference_queued: 0,
+				},
+				memories: HashMap::new(),
+				queue: HashMap::new(),
+				rate_limit: Default::default(),
+			})
+			.into_response()
+		}),
+	);
+	let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+	let address = server.local_addr();
+	tokio::spawn(server);
+	address
+}
+
+#[tokio::test]
+async fn test_client_retry() {
+	// Requests are retried when there are too many requests
+	let address = start_limited_server(2).await;
+	let client = Client::new(format!("http://{address}")).unwrap();
+	assert_eq!(client.stats().await.unwrap().active.sessions, 0);
+
+	// ...but only as often as configured
+	let address = start_limited_server(2).await;
+	let client = Client::new(format!("http://{address}")).unwrap().with_max_retries(1);
+	let error = client.stats().await.unwrap_err();
+	assert!(matches!(error, ClientError::Api(ref e) if e.status == Some(StatusCode::TOO_MANY_REQUESTS) && e.retryable));
+}
+
+#[tokio::test]
+async fn test_client_streaming() {
+	let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/gpt2.bin");
+	let mut config: Config = toml::from_str(&format!(
+		r#"
+		public = true
+
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "{model_path}"
+
+		[tasks.complete]
+		model = "gpt2"
+		max_tokens = 5
+		"#
+	))
+	.unwrap();
+	config.backend_config.cache_path = Some(std::env::temp_dir().join("poly-client-test"));
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+	let client = Client::new(format!("http://{}", listening.addresses[0])).unwrap();
+
+	// The stream is opened before any token is generated
+	let stream = client
+		.complete_streaming("complete", &PromptRequest::new("Once upon a time"))
+		.await
+		.unwrap();
+	let events: Vec<_> = stream.map(|e| e.unwrap()).collect().await;
+	assert!(matches!(events[0], poly_client::TokenEvent::Open { .. }));
+	assert!(events.len() > 2);
+
+	// The stream ends with the token counts of the completion
+	let Some(poly_client::TokenEvent::Done(done)) = events.last() else {
+		panic!("stream should end with a done event");
+	};
+	assert!(done.usage.sampled_tokens > 0 && done.usage.sampled_tokens <= 5);
+
+	// Chats continue in the same session
+	let mut chat = client.chat("complete").await.unwrap();
+	let mut tokens = 0;
+	let response = chat.send("Hello", |_| tokens += 1).await.unwrap();
+	assert!(tokens > 0 && !response.is_empty());
+	chat.send("Goodbye", |_| {}).await.unwrap();
+	chat.close().await.unwrap();
+}
+
+/// Receive the next message on a chat WebSocket in the JSON format, skipping messages about the session and the queue
+async fn next_chat_message(socket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> ChatServerMessage {
+	loop {
+		match socket.next().await.unwrap().unwrap() {
+			Message::Text(text) => match serde_json::from_str(&text).unwrap() {
+				ChatServerMessage::Session { .. } | ChatServerMessage::Queued { .. } => {}
+				message => return message,
+			},
+			Message::Close(_) => panic!("the server closed the chat"),
+			_ => {}
+		}
+	}
+}
+
+#[tokio::test]
+async fn test_chat_protocol() {
+	let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/gpt2.bin");
+	let mut config: Config = toml::from_str(&format!(
+		r#"
+		public = true
+
+		[models.gpt2]
+		architecture = "gpt2"
+		model_path = "{model_path}"
+
+		[tasks.story]
+		model = "gpt2"
+		max_tokens = 64
+		stop_sequences = []
+		"#
+	))
+	.unwrap();
+	config.backend_config.cache_path = Some(std::env::temp_dir().join("poly-client-chat-test"));
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+	let (mut socket, _) = connect_async(format!("ws://{}/v1/task/story/chat", listening.addresses[0]))
+		.await
+		.unwrap();
+	let send = |message: serde_json::Value| Message::Text(message.to_string());
+
+	// A response that is cancelled after its first token ends early, with the tokens generated until then
+	socket.send(send(json!({ "type": "prompt", "text": "Once upon a time" }))).await.unwrap();
+	assert!(matches!(next_chat_message(&mut socket).await, ChatServerMessage::Token { .. }));
+	socket.send(send(json!({ "type": "cancel" }))).await.unwrap();
+	let end = loop {
+		match next_chat_message(&mut socket).await {
+			ChatServerMessage::Token { .. } => {}
+			message => break message,
+		}
+	};
+	let ChatServerMessage::End { usage, finish_reason } = end else {
+		panic!("expected the response to end, got {end:?}");
+	};
+	assert_eq!(finish_reason, Some(FinishReason::Cancelled));
+	assert!(usage.sampled_tokens > 0 && usage.sampled_tokens < 64);
+
+	// Errors of the backend are reported, after which the chat goes on
+	socket
+		.send(send(json!({ "type": "prompt", "text": "Hello", "temperature": 100.0 })))
+		.await
+		.unwrap();
+	let ChatServerMessage::Error { message } = next_chat_message(&mut socket).await else {
+		panic!("expected an error");
+	};
+	assert!(message.contains("temperature"), "unexpected error: {message}");
+
+	socket
+		.send(send(json!({ "type": "prompt", "text": "Hello", "max_tokens": 2 })))
+		.await
+		.unwrap();
+	let end = loop {
+		match next_chat_message(&mut socket).await {
+			ChatServerMessage::Token { .. } => {}
+			message => break message,
+		}
+	};
+	assert!(matches!(end, ChatServerMessage::End { usage, finish_reason: Some(FinishReason::MaxTokens) } if usage.sampled_tokens == 2));
+	socket.close(None).await.unwrap();
+}
+
+/// The code of the close frame the server sends next on a WebSocket, skipping other messages
+async fn close_code(socket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> u16 {
+	loop {
+		match socket.next().await {
+			Some(Ok(Message::Close(frame))) => return frame.map_or(0, |frame| frame.code.into()),
+			Some(Ok(_)) => {}
+			other => panic!("expected a close frame, got {other:?}"),
+		}
+	}
+}
+
+#[tokio::test]
+async fn test_chat_malformed_frames() {
+	let url = start_server().await.replacen("http", "ws", 1);
+	let connect = |query: &str| connect_async(format!("{url}/v1/task/missing/chat?api_key=secret{query}"));
+
+	// A binary frame on a JSON chat is refused, regardless of what follows
+	let (mut socket, _) = connect("&format=json").await.unwrap();
+	socket.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+	socket.send(Message::Text(String::from("Hello"))).await.unwrap();
+	assert_eq!(close_code(&mut socket).await, 1003);
+
+	// A chat that starts with a binary frame uses MessagePack, in which this frame is not a valid message
+	let (mut socket, _) = connect("").await.unwrap();
+	socket.send(Message::Binary(vec![0xc1])).await.unwrap();
+	socket.send(Message::Text(String::from("Hello"))).await.unwrap();
+	assert_eq!(close_code(&mut socket).await, 1007);
+
+	// When the session cannot be started, the client is told why before the connection is closed
+	let (mut socket, _) = connect("&format=json").await.unwrap();
+	socket
+		.send(Message::Text(json!({ "type": "prompt", "text": "Hello" }).to_string()))
+		.await
+		.unwrap();
+	let ChatServerMessage::Error { message } = next_chat_message(&mut socket).await else {
+		panic!("expected an error");
+	};
+	assert!(message.contains("missing"), "unexpected error: {message}");
+	assert_eq!(close_code(&mut socket).await, 1011);
+}
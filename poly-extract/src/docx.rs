@@ -1,47 +1,110 @@
-use std::{
-	collections::VecDeque,
-	io::{Read, Seek},
-};
+use std::io::{Read, Seek};
 
 use minidom::Element;
-use zip::ZipArchive;
 
-/// Retrieve plain text from a Word DOCX file
-pub fn get_text_from_docx<R>(reader: R) -> Option<String>
+use crate::{
+	office::{attr, child, heading, list_item, open_archive, read_xml},
+	ExtractError,
+};
+
+/// Retrieve plain text from a Word DOCX file. Each paragraph becomes a line: headings are prefixed with '#' (one for each
+/// level), list items with '-' and the cells of each table row are separated by '|'. Footnotes follow the text, each
+/// on a line starting with the number it is referenced by.
+pub fn get_text_from_docx<R>(reader: R) -> Result<String, ExtractError>
 where
 	R: Read + Seek,
 {
-	let mut result: String = String::new();
-	let mut xml_string: String = String::new();
+	let mut archive = open_archive(reader)?;
+	let document =
+		read_xml(&mut archive, "word/document.xml")?.ok_or_else(|| ExtractError::Corrupt(String::from("the document has no word/document.xml")))?;
 
-	let mut zip_reader: ZipArchive<R>;
-	match ZipArchive::new(reader) {
-		Ok(zp) => zip_reader = zp,
-		Err(_err) => return None,
+	let mut lines = vec![];
+	if let Some(body) = child(&document, "body") {
+		block_lines(body, &mut lines);
 	}
-	let mut document_xml_file: zip::read::ZipFile<'_>;
-	match zip_reader.by_name("word/document.xml") {
-		Ok(zpf) => document_xml_file = zpf,
-		Err(_err) => return None,
-	}
-
-	let _outcome: std::result::Result<usize, std::io::Error> = document_xml_file.read_to_string(&mut xml_string);
-	let element: Element = xml_string.parse().unwrap();
-	let mut node_que: VecDeque<&Element> = VecDeque::new();
-	let mut _text_string: String = String::new();
-	node_que.push_back(&element);
 
-	while let Some(node) = node_que.pop_front() {
-		if node.name() == "t" {
-			result.push_str(&node.text());
-			result.push('\n');
+	if let Some(footnotes) = read_xml(&mut archive, "word/footnotes.xml")? {
+		// Footnotes with a type are separators, not actual notes
+		for footnote in footnotes.children().filter(|c| c.name() == "footnote" && attr(c, "type").is_none()) {
+			let mut note_lines = vec![];
+			block_lines(footnote, &mut note_lines);
+			lines.push(format!("[{}] {}", attr(footnote, "id").unwrap_or_default(), note_lines.join(" ").trim()));
 		}
-		for child in node.children() {
-			node_que.push_back(child);
+	}
+	Ok(lines.join("\n"))
+}
+
+/// Add the lines of the paragraphs and tables in an element (e.g. the body, a table cell or a footnote)
+fn block_lines(element: &Element, lines: &mut Vec<String>) {
+	for block in element.children() {
+		match block.name() {
+			"p" => {
+				let line = paragraph(block);
+				if !line.trim().is_empty() {
+					lines.push(line);
+				}
+			}
+			"tbl" => {
+				for row in block.children().filter(|c| c.name() == "tr") {
+					let cells: Vec<String> = row
+						.children()
+						.filter(|c| c.name() == "tc")
+						.map(|cell| {
+							let mut cell_lines = vec![];
+							block_lines(cell, &mut cell_lines);
+							cell_lines.join(" ")
+						})
+						.collect();
+					lines.push(cells.join(" | "));
+				}
+			}
+			// Content controls and custom XML wrap paragraphs
+			"sdt" | "sdtContent" | "customXml" => block_lines(block, lines),
+			_ => {}
 		}
 	}
-	if result.is_empty() {
-		result.push_str("   ");
+}
+
+fn paragraph(paragraph: &Element) -> String {
+	let mut text = String::new();
+	inline_text(paragraph, &mut text);
+
+	let Some(properties) = child(paragraph, "pPr") else {
+		return text;
+	};
+	let style = child(properties, "pStyle").and_then(|s| attr(s, "val"));
+	if let Some(level) = style.and_then(heading_level) {
+		return heading(level, &text);
+	}
+	if let Some(numbering) = child(properties, "numPr") {
+		let level = child(numbering, "ilvl")
+			.and_then(|l| attr(l, "val"))
+			.and_then(|l| l.parse().ok())
+			.unwrap_or(0);
+		return list_item(level, &text);
+	}
+	text
+}
+
+/// Heading level for the built-in paragraph styles ("Title", "Heading1" up to "Heading9")
+fn heading_level(style: &str) -> Option<usize> {
+	if style == "Title" {
+		return Some(1);
+	}
+	style.strip_prefix("Heading").and_then(|level| level.parse().ok())
+}
+
+/// Add the text in the runs of a paragraph (including those in hyperlinks and insertions, but not deletions)
+fn inline_text(element: &Element, text: &mut String) {
+	for inline in element.children() {
+		match inline.name() {
+			"t" => text.push_str(&inline.text()),
+			"tab" => text.push('\t'),
+			"br" | "cr" => text.push(' '),
+			"noBreakHyphen" => text.push('-'),
+			"footnoteReference" => text.push_str(&format!("[{}]", attr(inline, "id").unwrap_or_default())),
+			"pPr" | "rPr" | "del" | "instrText" => {}
+			_ => inline_text(inline, text),
+		}
 	}
-	Some(result)
 }
@@ -0,0 +1,419 @@
+//! Fetching documents by URL. Only public addresses are fetched: requests to private, loopback and link-local addresses
+//! are refused (also after redirects), and hosts can further be restricted using allow and deny lists.
+
+use std::{
+	io::Cursor,
+	net::{IpAddr, SocketAddr},
+	str::FromStr,
+	time::{Duration, SystemTime},
+};
+
+use ipnet::IpNet;
+use reqwest::{
+	header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+	redirect::Policy,
+	Url,
+};
+use thiserror::Error;
+
+use crate::{
+	table::{get_sections_from_csv, get_sections_from_xlsx, TableOptions},
+	ExtractError, CSV_CONTENT_TYPE, DOCX_CONTENT_TYPE, ODT_CONTENT_TYPE, XLSX_CONTENT_TYPE,
+};
+
+/// User agent sent when fetching documents
+pub const USER_AGENT: &str = concat!("poly-extract/", env!("CARGO_PKG_VERSION"), " (+https://github.com/pixelspark/poly)");
+
+/// Networks that are never fetched from: private, loopback, link-local, multicast and reserved ranges
+const BLOCKED_NETWORKS: [&str; 21] = [
+	"0.0.0.0/8",
+	"10.0.0.0/8",
+	"100.64.0.0/10",
+	"127.0.0.0/8",
+	"169.254.0.0/16",
+	"172.16.0.0/12",
+	"192.0.0.0/24",
+	"192.0.2.0/24",
+	"192.168.0.0/16",
+	"198.18.0.0/15",
+	"198.51.100.0/24",
+	"203.0.113.0/24",
+	"224.0.0.0/4",
+	"240.0.0.0/4",
+	"::/128",
+	"::1/128",
+	"64:ff9b::/96",
+	"2001:db8::/32",
+	"fc00::/7",
+	"fe80::/10",
+	"ff00::/8",
+];
+
+#[derive(Error, Debug)]
+pub enum FetchError {
+	#[error("invalid URL: {0}")]
+	InvalidUrl(String),
+
+	#[error("fetching documents by URL is not enabled")]
+	Disabled,
+
+	#[error("fetching from {0} is not allowed")]
+	Blocked(String),
+
+	#[error("the URL redirects too many times")]
+	TooManyRedirects,
+
+	#[error("the document is larger than {0} bytes")]
+	TooLarge(usize),
+
+	#[error("fetching the document took too long")]
+	Timeout,
+
+	#[error("the server responded with status {0}")]
+	Status(u16),
+
+	#[error("fetching the document failed: {0}")]
+	Request(String),
+
+	#[error("documents of type '{0}' are not supported")]
+	UnsupportedContentType(String),
+
+	#[error(transparent)]
+	Extract(#[from] ExtractError),
+}
+
+impl FetchError {
+	/// Machine-readable kind of the error
+	pub fn kind(&self) -> &'static str {
+		match self {
+			FetchError::InvalidUrl(_) => "invalid_url",
+			FetchError::Disabled => "fetch_disabled",
+			FetchError::Blocked(_) => "url_blocked",
+			FetchError::TooManyRedirects => "too_many_redirects",
+			FetchError::TooLarge(_) => "document_too_large",
+			FetchError::Timeout => "fetch_timeout",
+			FetchError::Status(_) | FetchError::Request(_) => "fetch_failed",
+			FetchError::UnsupportedContentType(_) => "unsupported_content_type",
+			FetchError::Extract(e) => e.kind(),
+		}
+	}
+
+	/// Whether the same request may succeed when it is retried later
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			FetchError::Timeout | FetchError::Request(_) => true,
+			FetchError::Status(status) => *status == 429 || *status >= 500,
+			_ => false,
+		}
+	}
+}
+
+impl From<reqwest::Error> for FetchError {
+	fn from(e: reqwest::Error) -> Self {
+		if e.is_timeout() {
+			FetchError::Timeout
+		} else {
+			FetchError::Request(e.to_string())
+		}
+	}
+}
+
+/// Rule matching the hosts of URLs that are fetched
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostRule {
+	/// A host name (e.g. "example.com")
+	Host(String),
+
+	/// Subdomains of a domain (written as "*.example.com")
+	Subdomains(String),
+
+	/// Addresses in a network (e.g. "203.0.113.7" or "2001:db8::/32"), matched against the resolved address
+	Network(IpNet),
+}
+
+impl FromStr for HostRule {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim().to_ascii_lowercase();
+		if let Ok(network) = s.parse::<IpNet>() {
+			return Ok(HostRule::Network(network));
+		}
+		if let Ok(address) = s.parse::<IpAddr>() {
+			return Ok(HostRule::Network(IpNet::from(address)));
+		}
+
+		let (rule, domain): (fn(String) -> HostRule, &str) = match s.strip_prefix("*.") {
+			Some(domain) => (HostRule::Subdomains, domain),
+			None => (HostRule::Host, &s),
+		};
+		if domain.is_empty() || !domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+			return Err(format!("invalid host '{s}'"));
+		}
+		Ok(rule(domain.to_string()))
+	}
+}
+
+impl HostRule {
+	pub fn matches(&self, host: &str, address: IpAddr) -> bool {
+		match self {
+			HostRule::Host(name) => host == name,
+			HostRule::Subdomains(domain) => host.strip_suffix(domain.as_str()).is_some_and(|prefix| prefix.ends_with('.')),
+			HostRule::Network(network) => network.contains(&address),
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct FetchOptions {
+	/// Time after which fetching a document (including redirects) is aborted
+	pub timeout: Duration,
+
+	/// Maximum size of a document in bytes
+	pub max_size: usize,
+
+	/// Maximum number of redirects that are followed
+	pub max_redirects: usize,
+
+	/// When not empty, only hosts matching one of these rules are fetched from
+	pub allowed_hosts: Vec<HostRule>,
+
+	/// Hosts matching any of these rules are never fetched from
+	pub denied_hosts: Vec<HostRule>,
+}
+
+impl Default for FetchOptions {
+	fn default() -> Self {
+		Self {
+			timeout: Duration::from_secs(30),
+			max_size: 10 * 1024 * 1024,
+			max_redirects: 5,
+			allowed_hosts: vec![],
+			denied_hosts: vec![],
+		}
+	}
+}
+
+/// Where a fetched document came from
+#[derive(Clone, Debug)]
+pub struct Source {
+	/// The URL the document was read from, after following redirects
+	pub url: String,
+
+	/// Title of the document, if it has one (for HTML pages, the contents of the `<title>` element)
+	pub title: Option<String>,
+
+	pub fetched_at: SystemTime,
+}
+
+#[derive(Clone, Debug)]
+pub struct FetchedDocument {
+	/// Text of the document. Tables are split in sections that should each be stored as separate chunks; other documents
+	/// consist of a single section.
+	pub sections: Vec<String>,
+	pub source: Source,
+}
+
+/// Whether an address is in one of the private or reserved ranges that are never fetched from. IPv4 addresses that are
+/// mapped to IPv6 are checked as IPv4 addresses.
+pub fn is_blocked_address(address: IpAddr) -> bool {
+	let address = match address {
+		IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+		IpAddr::V4(_) => address,
+	};
+	BLOCKED_NETWORKS
+		.iter()
+		.any(|network| network.parse::<IpNet>().unwrap().contains(&address))
+}
+
+impl FetchOptions {
+	/// Check whether a URL may be fetched, returning the address to connect to
+	async fn resolve(&self, url: &Url) -> Result<SocketAddr, FetchError> {
+		if url.scheme() != "http" && url.scheme() != "https" {
+			return Err(FetchError::InvalidUrl(format!("unsupported scheme '{}'", url.scheme())));
+		}
+		let host = url
+			.host_str()
+			.ok_or_else(|| FetchError::InvalidUrl(String::from("the URL has no host")))?;
+		let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+		let port = url.port_or_known_default().unwrap_or(80);
+
+		let addresses: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+			Ok(address) => vec![SocketAddr::new(address, port)],
+			Err(_) => tokio::net::lookup_host((host.as_str(), port))
+				.await
+				.map_err(|e| FetchError::Request(format!("cannot resolve {host}: {e}")))?
+				.collect(),
+		};
+
+		// Refuse the host when any of its addresses is not allowed, so that it does not matter which one is used
+		for address in &addresses {
+			let ip = address.ip();
+			let allowed = self.allowed_hosts.is_empty() || self.allowed_hosts.iter().any(|rule| rule.matches(&host, ip));
+			if !allowed || is_blocked_address(ip) || self.denied_hosts.iter().any(|rule| rule.matches(&host, ip)) {
+				return Err(FetchError::Blocked(host));
+			}
+		}
+		addresses
+			.first()
+			.copied()
+			.ok_or_else(|| FetchError::Request(format!("cannot resolve {host}")))
+	}
+
+	async fn fetch(&self, url: &str) -> Result<(Url, String, Vec<u8>), FetchError> {
+		let mut url = Url::parse(url).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+		let mut redirects = 0;
+
+		loop {
+			// Connect to the address that was checked, instead of letting the client resolve the host again
+			let address = self.resolve(&url).await?;
+			let mut client = reqwest::Client::builder().user_agent(USER_AGENT).redirect(Policy::none()).no_proxy();
+			if let Some(domain) = url.domain() {
+				client = client.resolve(domain, address);
+			}
+			let mut response = client.build()?.get(url.clone()).send().await?;
+
+			let status = response.status();
+			if status.is_redirection() {
+				if redirects >= self.max_redirects {
+					return Err(FetchError::TooManyRedirects);
+				}
+				let location = response
+					.headers()
+					.get(LOCATION)
+					.and_then(|l| l.to_str().ok())
+					.ok_or(FetchError::Status(status.as_u16()))?;
+				url = url.join(location).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+				redirects += 1;
+				continue;
+			}
+			if !status.is_success() {
+				return Err(FetchError::Status(status.as_u16()));
+			}
+
+			let content_type = response
+				.headers()
+				.get(CONTENT_TYPE)
+				.and_then(|c| c.to_str().ok())
+				.unwrap_or("application/octet-stream")
+				.to_string();
+			let content_length = response
+				.headers()
+				.get(CONTENT_LENGTH)
+				.and_then(|l| l.to_str().ok())
+				.and_then(|l| l.parse::<usize>().ok());
+			if content_length.is_some_and(|length| length > self.max_size) {
+				return Err(FetchError::TooLarge(self.max_size));
+			}
+
+			let mut body = Vec::new();
+			while let Some(chunk) = response.chunk().await? {
+				if body.len() + chunk.len() > self.max_size {
+					return Err(FetchError::TooLarge(self.max_size));
+				}
+				body.extend_from_slice(&chunk);
+			}
+			return Ok((url, content_type, body));
+		}
+	}
+}
+
+/// Fetch a document and extract its text. HTML pages, PDF, Word DOCX, OpenDocument text, CSV, Excel XLSX and plain text
+/// documents are supported; the type is determined by the content type the server responds with.
+pub async fn fetch_document(url: &str, options: &FetchOptions) -> Result<FetchedDocument, FetchError> {
+	let (url, content_type, body) = tokio::time::timeout(options.timeout, options.fetch(url))
+		.await
+		.map_err(|_| FetchError::Timeout)??;
+	let fetched_at = SystemTime::now();
+	tracing::debug!(%url, content_type, size = body.len(), "fetched document");
+
+	let mime_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+	let (sections, title) = tokio::task::spawn_blocking(move || -> Result<_, FetchError> {
+		let text = match mime_type.as_str() {
+			"text/html" | "application/xhtml+xml" => {
+				let page = crate::html::get_text_from_html(&String::from_utf8_lossy(&body));
+				return Ok((vec![page.text], page.title));
+			}
+			CSV_CONTENT_TYPE => return Ok((get_sections_from_csv(&body, &TableOptions::default())?, None)),
+			XLSX_CONTENT_TYPE => return Ok((get_sections_from_xlsx(Cursor::new(body), &TableOptions::default())?, None)),
+			"text/plain" | "text/markdown" => String::from_utf8_lossy(&body).into_owned(),
+			"application/pdf" => {
+				crate::pdf::get_text_from_pdf(&body).ok_or_else(|| ExtractError::Corrupt(String::from("cannot read text from PDF")))?
+			}
+			DOCX_CONTENT_TYPE => crate::docx::get_text_from_docx(Cursor::new(body))?,
+			ODT_CONTENT_TYPE => crate::odt::get_text_from_odt(Cursor::new(body))?,
+			_ => return Err(FetchError::UnsupportedContentType(mime_type)),
+		};
+		Ok((vec![text], None))
+	})
+	.await
+	.unwrap()?;
+
+	Ok(FetchedDocument {
+		sections,
+		source: Source {
+			url: url.to_string(),
+			title,
+			fetched_at,
+		},
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use std::net::IpAddr;
+
+	use super::{is_blocked_address, HostRule};
+
+	fn ip(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn test_blocked_address() {
+		for address in [
+			"127.0.0.1",
+			"10.1.2.3",
+			"172.16.0.1",
+			"192.168.1.1",
+			"169.254.169.254",
+			"::1",
+			"fe80::1",
+			"fd00::1",
+		] {
+			assert!(is_blocked_address(ip(address)), "{address} should be blocked");
+		}
+
+		// IPv4 addresses mapped to IPv6 are checked as IPv4 addresses
+		assert!(is_blocked_address(ip("::ffff:127.0.0.1")));
+		assert!(!is_blocked_address(ip("::ffff:93.184.216.34")));
+
+		for address in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+			assert!(!is_blocked_address(ip(address)), "{address} should not be blocked");
+		}
+	}
+
+	#[test]
+	fn test_host_rule() {
+		let address = ip("93.184.216.34");
+		let rule: HostRule = "Example.com".parse().unwrap();
+		assert!(rule.matches("example.com", address));
+		assert!(!rule.matches("www.example.com", address));
+
+		let rule: HostRule = "*.example.com".parse().unwrap();
+		assert!(rule.matches("www.example.com", address));
+		assert!(!rule.matches("example.com", address));
+		assert!(!rule.matches("badexample.com", address));
+
+		let rule: HostRule = "93.184.216.0/24".parse().unwrap();
+		assert!(rule.matches("example.com", address));
+		assert!(!rule.matches("example.com", ip("93.184.217.1")));
+		assert_eq!(
+			"93.184.216.34".parse::<HostRule>().unwrap(),
+			HostRule::Network("93.184.216.34/32".parse().unwrap())
+		);
+
+		assert!("".parse::<HostRule>().is_err());
+		assert!("http://example.com".parse::<HostRule>().is_err());
+	}
+}
@@ -0,0 +1,340 @@
+//! Extraction of text from HTML pages. The text is structured in the same way as that of office documents: one line per
+//! block, with headings prefixed by '#' markers, list items by '-' and table cells separated by '|'.
+
+/// Text and title of an HTML page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HtmlText {
+	/// Contents of the `<title>` element, if any
+	pub title: Option<String>,
+	pub text: String,
+}
+
+/// Elements whose contents are not part of the text of a page
+const SKIPPED_ELEMENTS: [&str; 8] = ["script", "style", "noscript", "template", "svg", "head", "iframe", "object"];
+
+/// Elements that start a new line
+const BLOCK_ELEMENTS: [&str; 28] = [
+	"address",
+	"article",
+	"aside",
+	"blockquote",
+	"body",
+	"dd",
+	"details",
+	"div",
+	"dl",
+	"dt",
+	"fieldset",
+	"figcaption",
+	"figure",
+	"footer",
+	"form",
+	"header",
+	"hr",
+	"html",
+	"main",
+	"nav",
+	"ol",
+	"p",
+	"pre",
+	"section",
+	"summary",
+	"table",
+	"tr",
+	"ul",
+];
+
+/// Builds the lines of text while walking through the page
+#[derive(Default)]
+struct Writer {
+	lines: Vec<String>,
+	line: String,
+	prefix: String,
+	pending_space: bool,
+	list_depth: usize,
+	cells_in_row: usize,
+}
+
+impl Writer {
+	fn text(&mut self, text: &str) {
+		if text.starts_with(char::is_whitespace) {
+			self.pending_space = true;
+		}
+		for (index, word) in text.split_whitespace().enumerate() {
+			if self.line.is_empty() {
+				self.line.push_str(&self.prefix);
+			} else if self.pending_space || index > 0 {
+				self.line.push(' ');
+			}
+			self.line.push_str(word);
+			self.pending_space = false;
+		}
+		if text.ends_with(char::is_whitespace) {
+			self.pending_space = true;
+		}
+	}
+
+	fn break_line(&mut self) {
+		let line = std::mem::take(&mut self.line);
+		if !line.trim().is_empty() {
+			self.lines.push(line);
+		}
+		self.prefix.clear();
+		self.pending_space = false;
+	}
+
+	fn cell(&mut self) {
+		if self.cells_in_row > 0 {
+			self.line.push_str(" | ");
+			self.pending_space = false;
+		}
+		self.cells_in_row += 1;
+	}
+}
+
+/// A tag found in the page
+struct Tag {
+	name: String,
+	closing: bool,
+	self_closing: bool,
+	/// The position in the page right after the tag
+	end: usize,
+}
+
+/// Parse the tag that starts at `start` (which points at the '<'). Returns None when there is no valid tag there, in which
+/// case the '<' is treated as text.
+fn parse_tag(html: &str, start: usize) -> Option<Tag> {
+	let bytes = html.as_bytes();
+	let mut pos = start + 1;
+	let closing = bytes.get(pos) == Some(&b'/');
+	if closing {
+		pos += 1;
+	}
+
+	let name_start = pos;
+	while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'-') {
+		pos += 1;
+	}
+	if pos == name_start || !bytes[name_start].is_ascii_alphabetic() {
+		return None;
+	}
+	let name = html[name_start..pos].to_ascii_lowercase();
+
+	// Skip the attributes, which may contain '>' in quoted values
+	let mut quote = None;
+	while pos < bytes.len() {
+		match (quote, bytes[pos]) {
+			(None, b'"' | b'\'') => quote = Some(bytes[pos]),
+			(Some(q), c) if c == q => quote = None,
+			(None, b'>') => {
+				return Some(Tag {
+					name,
+					closing,
+					self_closing: bytes[pos - 1] == b'/',
+					end: pos + 1,
+				})
+			}
+			_ => {}
+		}
+		pos += 1;
+	}
+	None
+}
+
+/// Find the end of the element with the given name whose contents start at `from`, returning the positions of the start
+/// of its end tag and of right after it
+fn find_end_tag(html: &str, from: usize, name: &str) -> (usize, usize) {
+	let lower = html[from..].to_ascii_lowercase();
+	let needle = format!("</{name}");
+	let mut offset = 0;
+	while let Some(found) = lower[offset..].find(&needle) {
+		let start = from + offset + found;
+		if let Some(tag) = parse_tag(html, start) {
+			if tag.name == name {
+				return (start, tag.end);
+			}
+		}
+		offset += found + needle.len();
+	}
+	(html.len(), html.len())
+}
+
+/// Decode character references (e.g. `&amp;` and `&#233;`). Unknown references are kept as they are.
+pub fn decode_entities(text: &str) -> String {
+	let mut output = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(start) = rest.find('&') {
+		output.push_str(&rest[..start]);
+		rest = &rest[start..];
+		let decoded = rest[1..].find(';').filter(|end| *end <= 10).and_then(|end| {
+			let entity = &rest[1..end + 1];
+			let c = match entity {
+				"amp" => Some('&'),
+				"lt" => Some('<'),
+				"gt" => Some('>'),
+				"quot" => Some('"'),
+				"apos" => Some('\''),
+				"nbsp" => Some('\u{a0}'),
+				"ndash" => Some('–'),
+				"mdash" => Some('—'),
+				"hellip" => Some('…'),
+				"copy" => Some('©'),
+				"euro" => Some('€'),
+				_ => match entity.strip_prefix('#') {
+					Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok().and_then(char::from_u32),
+					Some(decimal) => decimal.parse().ok().and_then(char::from_u32),
+					None => None,
+				},
+			};
+			c.map(|c| (c, end + 2))
+		});
+		match decoded {
+			Some((c, length)) => {
+				output.push(c);
+				rest = &rest[length..];
+			}
+			None => {
+				output.push('&');
+				rest = &rest[1..];
+			}
+		}
+	}
+	output.push_str(rest);
+	output
+}
+
+/// Retrieve the structured text and the title of an HTML page
+pub fn get_text_from_html(html: &str) -> HtmlText {
+	let mut writer = Writer::default();
+	let mut title = None;
+	let mut pos = 0;
+
+	while let Some(found) = html[pos..].find('<') {
+		let start = pos + found;
+		writer.text(&decode_entities(&html[pos..start]));
+
+		if html[start..].starts_with("<!--") {
+			pos = html[start..].find("-->").map(|end| start + end + 3).unwrap_or(html.len());
+			continue;
+		}
+		if html[start..].starts_with("<!") || html[start..].starts_with("<?") {
+			pos = html[start..].find('>').map(|end| start + end + 1).unwrap_or(html.len());
+			continue;
+		}
+
+		let Some(tag) = parse_tag(html, start) else {
+			writer.text("<");
+			pos = start + 1;
+			continue;
+		};
+		pos = tag.end;
+		let name = tag.name.as_str();
+
+		if !tag.closing && !tag.self_closing && (name == "title" || SKIPPED_ELEMENTS.contains(&name)) {
+			// The title may be placed in the head, which is skipped otherwise
+			let (contents_end, end) = find_end_tag(html, pos, name);
+			if name == "title" || name == "head" {
+				let contents = &html[pos..contents_end];
+				let found_title = if name == "title" {
+					Some(contents)
+				} else {
+					contents.to_ascii_lowercase().find("<title").and_then(|title_start| {
+						let tag = parse_tag(contents, title_start)?;
+						Some(&contents[tag.end..find_end_tag(contents, tag.end, "title").0])
+					})
+				};
+				if let Some(found_title) = found_title {
+					let found_title = decode_entities(found_title).split_whitespace().collect::<Vec<_>>().join(" ");
+					if title.is_none() && !found_title.is_empty() {
+						title = Some(found_title);
+					}
+				}
+			}
+			pos = end;
+			continue;
+		}
+
+		match (name, tag.closing) {
+			("br", _) => writer.break_line(),
+			("h1" | "h2" | "h3" | "h4" | "h5" | "h6", false) => {
+				writer.break_line();
+				let level = name[1..].parse().unwrap_or(1);
+				writer.prefix = format!("{} ", "#".repeat(level));
+			}
+			("h1" | "h2" | "h3" | "h4" | "h5" | "h6", true) => writer.break_line(),
+			("ul" | "ol", false) => {
+				writer.break_line();
+				writer.list_depth += 1;
+			}
+			("ul" | "ol", true) => {
+				writer.break_line();
+				writer.list_depth = writer.list_depth.saturating_sub(1);
+			}
+			("li", false) => {
+				writer.break_line();
+				writer.prefix = format!("{}- ", "  ".repeat(writer.list_depth.saturating_sub(1)));
+			}
+			("li", true) => writer.break_line(),
+			("tr", _) => {
+				writer.break_line();
+				writer.cells_in_row = 0;
+			}
+			("td" | "th", false) => writer.cell(),
+			(name, _) if BLOCK_ELEMENTS.contains(&name) => writer.break_line(),
+			_ => {}
+		}
+	}
+
+	writer.text(&decode_entities(&html[pos..]));
+	writer.break_line();
+
+	HtmlText {
+		title,
+		text: writer.lines.join("\n"),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{decode_entities, get_text_from_html};
+
+	#[test]
+	fn test_decode_entities() {
+		assert_eq!(decode_entities("a &amp; b &lt;c&gt;"), "a & b <c>");
+		assert_eq!(decode_entities("&#233;t&#xE9; &euro;"), "été €");
+		assert_eq!(decode_entities("AT&T &unknown; &"), "AT&T &unknown; &");
+	}
+
+	#[test]
+	fn test_html() {
+		let page = get_text_from_html(
+			r#"<!DOCTYPE html>
+			<html>
+				<head><title>Quarterly
+				report</title><style>p { color: red; }</style></head>
+				<body>
+					<!-- navigation -->
+					<h1>Quarterly report</h1>
+					<script>let x = "<p>not text</p>";</script>
+					<p>Sales grew in <b>Zürich</b>,
+					东京 &amp; São Paulo.<br>Second line</p>
+					<ul><li>First item<ul><li>Nested item</li></ul></li></ul>
+					<table><tr><th>Region</th><th>Revenue</th></tr><tr><td>Europe</td><td>&euro; 1.200</td></tr></table>
+					<p data-note="a > b">Done. 1 < 2</p>
+				</body>
+			</html>"#,
+		);
+		assert_eq!(page.title.as_deref(), Some("Quarterly report"));
+		assert_eq!(
+			page.text,
+			"# Quarterly report
+Sales grew in Zürich, 东京 & São Paulo.
+Second line
+- First item
+  - Nested item
+Region | Revenue
+Europe | € 1.200
+Done. 1 < 2"
+		);
+	}
+}
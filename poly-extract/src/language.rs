@@ -0,0 +1,50 @@
+//! Detection of the language of extracted text
+
+/// Reported when the language of a text cannot be determined reliably (e.g. because the text is too short)
+pub const UNKNOWN_LANGUAGE: &str = "unknown";
+
+/// Texts with fewer letters than this are too short to reliably detect their language
+const MIN_LETTERS: usize = 24;
+
+/// Only this number of characters at the start of a text is used for detection
+const MAX_CHARS: usize = 4096;
+
+/// Minimum confidence (0.0-1.0) of a detection to accept it
+const MIN_CONFIDENCE: f64 = 0.5;
+
+/// Detect the language of a text, returning its ISO 639-3 code (e.g. "eng" or "nld"), or [`UNKNOWN_LANGUAGE`] when the
+/// text is too short or the detection is not reliable.
+pub fn detect_language(text: &str) -> &'static str {
+	let end = text.char_indices().nth(MAX_CHARS).map(|(i, _)| i).unwrap_or(text.len());
+	let sample = &text[..end];
+	if sample.chars().filter(|c| c.is_alphabetic()).count() < MIN_LETTERS {
+		return UNKNOWN_LANGUAGE;
+	}
+
+	match whatlang::detect(sample) {
+		Some(info) if info.is_reliable() && info.confidence() >= MIN_CONFIDENCE => info.lang().code(),
+		_ => UNKNOWN_LANGUAGE,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{detect_language, UNKNOWN_LANGUAGE};
+
+	#[test]
+	fn test_detect_language() {
+		assert_eq!(
+			detect_language("The quarterly report shows that sales grew in most regions, although the growth was slower than expected."),
+			"eng"
+		);
+		assert_eq!(
+			detect_language("Het kwartaalverslag laat zien dat de verkopen in de meeste regio's zijn gegroeid, al was de groei lager dan verwacht."),
+			"nld"
+		);
+
+		// Short texts are not guessed
+		for text in ["", "Hallo", "Dank je wel", "OK, thanks!", "12345 67890 12345 67890 12345"] {
+			assert_eq!(detect_language(text), UNKNOWN_LANGUAGE, "{text}");
+		}
+	}
+}
@@ -1,5 +1,52 @@
+use thiserror::Error;
+
 pub mod docx;
+#[cfg(feature = "fetch")]
+pub mod fetch;
+pub mod html;
+pub mod language;
+pub mod odt;
+mod office;
 pub mod pdf;
+pub mod table;
 
 #[cfg(feature = "axum")]
 pub mod middleware;
+
+/// Content type of Word DOCX files
+pub const DOCX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+/// Content type of OpenDocument text files
+pub const ODT_CONTENT_TYPE: &str = "application/vnd.oasis.opendocument.text";
+
+/// Content type of CSV files
+pub const CSV_CONTENT_TYPE: &str = "text/csv";
+
+/// Content type of Excel XLSX files
+pub const XLSX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+#[derive(Error, Debug)]
+pub enum ExtractError {
+	/// The document is not valid for its type (e.g. not a ZIP archive, or missing the part that holds its text)
+	#[error("the document is corrupt: {0}")]
+	Corrupt(String),
+
+	/// The document is protected with a password
+	#[error("the document is encrypted")]
+	Encrypted,
+
+	/// The requested sheet does not exist in the spreadsheet
+	#[error("the document has no sheet named '{0}'")]
+	SheetNotFound(String),
+}
+
+impl ExtractError {
+	/// Machine-readable kind of the error
+	pub fn kind(&self) -> &'static str {
+		match self {
+			ExtractError::Corrupt(_) => "corrupt_document",
+			ExtractError::Encrypted => "encrypted_document",
+			ExtractError::SheetNotFound(_) => "sheet_not_found",
+		}
+	}
+}
@@ -1,13 +1,123 @@
 use axum::{
 	async_trait,
-	extract::FromRequest,
+	extract::{FromRequest, Query},
 	http::{header::CONTENT_TYPE, Request, StatusCode},
 	response::IntoResponse,
+	Json,
+};
+
+use crate::{
+	fetch::FetchError,
+	table::{get_sections_from_csv, get_sections_from_xlsx, TableOptions},
+	ExtractError, CSV_CONTENT_TYPE, DOCX_CONTENT_TYPE, ODT_CONTENT_TYPE, XLSX_CONTENT_TYPE,
 };
 
 /// Extractor that converts various body file types to plain text string
 pub struct Plaintext(pub String);
 
+/// Extractor for a document that is either sent in the body (see [`Plaintext`]) or referred to by URL, using a JSON body
+/// of the form `{"url": "https://..."}`. The document a URL refers to can be fetched using [`crate::fetch::fetch_document`].
+pub enum Document {
+	Text(String),
+
+	/// Sections of a table (CSV or XLSX), which should each be stored as separate chunks (see [`crate::table`])
+	Table(Vec<String>),
+
+	Url(String),
+}
+
+fn is_table(content_type: &str) -> bool {
+	content_type.starts_with(CSV_CONTENT_TYPE) || content_type == XLSX_CONTENT_TYPE
+}
+
+/// Read the tables in a CSV or XLSX body as sections of text, using the [`TableOptions`] given in the query string
+async fn table_sections(mut req: Request<axum::body::Body>, content_type: String) -> Result<Vec<String>, axum::response::Response> {
+	let Query(options) = Query::<TableOptions>::try_from_uri(req.uri()).map_err(IntoResponse::into_response)?;
+	let Ok(bytes) = hyper::body::to_bytes(req.body_mut()).await else {
+		return Err(StatusCode::UNPROCESSABLE_ENTITY.into_response());
+	};
+	tokio::task::spawn_blocking(move || {
+		if content_type == XLSX_CONTENT_TYPE {
+			get_sections_from_xlsx(std::io::Cursor::new(bytes), &options)
+		} else {
+			get_sections_from_csv(&bytes, &options)
+		}
+	})
+	.await
+	.unwrap()
+	.map_err(rejection)
+}
+
+fn rejection(error: ExtractError) -> axum::response::Response {
+	tracing::debug!("cannot extract text from document: {error}");
+	(
+		StatusCode::UNPROCESSABLE_ENTITY,
+		Json(serde_json::json!({
+			"error": error.kind(),
+			"message": error.to_string(),
+			"retryable": false,
+		})),
+	)
+		.into_response()
+}
+
+impl IntoResponse for FetchError {
+	fn into_response(self) -> axum::response::Response {
+		let status = match self {
+			FetchError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+			FetchError::Disabled | FetchError::Blocked(_) => StatusCode::FORBIDDEN,
+			FetchError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+			FetchError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+			FetchError::TooManyRedirects | FetchError::Status(_) | FetchError::Request(_) => StatusCode::BAD_GATEWAY,
+			FetchError::UnsupportedContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+			FetchError::Extract(e) => return rejection(e),
+		};
+		tracing::debug!("cannot fetch document: {self}");
+		(
+			status,
+			Json(serde_json::json!({
+				"error": self.kind(),
+				"message": self.to_string(),
+				"retryable": self.is_retryable(),
+			})),
+		)
+			.into_response()
+	}
+}
+
+#[async_trait]
+impl<S> FromRequest<S, axum::body::Body> for Document
+where
+	S: Send + Sync,
+{
+	type Rejection = axum::response::Response;
+
+	async fn from_request(mut req: Request<axum::body::Body>, state: &S) -> Result<Self, Self::Rejection> {
+		let is_json = req
+			.headers()
+			.get(CONTENT_TYPE)
+			.and_then(|value| value.to_str().ok())
+			.is_some_and(|content_type| content_type.starts_with("application/json"));
+		if !is_json {
+			let content_type = req.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+			if is_table(content_type) {
+				let content_type = content_type.to_string();
+				return table_sections(req, content_type).await.map(Document::Table);
+			}
+			return Plaintext::from_request(req, state).await.map(|Plaintext(text)| Document::Text(text));
+		}
+
+		let Ok(bytes) = hyper::body::to_bytes(req.body_mut()).await else {
+			return Err(StatusCode::UNPROCESSABLE_ENTITY.into_response());
+		};
+		let body: serde_json::Value = serde_json::from_slice(&bytes).map_err(|_| StatusCode::UNPROCESSABLE_ENTITY.into_response())?;
+		match body.get("url").and_then(|url| url.as_str()) {
+			Some(url) => Ok(Document::Url(url.to_string())),
+			None => Err(StatusCode::UNPROCESSABLE_ENTITY.into_response()),
+		}
+	}
+}
+
 #[async_trait]
 impl<S> FromRequest<S, axum::body::Body> for Plaintext
 where
@@ -30,21 +140,24 @@ where
 						.map_err(|_| StatusCode::UNPROCESSABLE_ENTITY.into_response())?
 						.to_string(),
 				));
-			} else if content_type == "application/vnd.openxmlformats-officedocument.wordprocessingml.document" {
+			} else if content_type == DOCX_CONTENT_TYPE || content_type == ODT_CONTENT_TYPE {
 				let Ok(bytes) = hyper::body::to_bytes(req.body_mut()).await else {
 					return Err(StatusCode::UNPROCESSABLE_ENTITY.into_response());
 				};
-				let text = tokio::task::spawn_blocking(|| {
-					let mut cur = std::io::Cursor::new(bytes);
-					crate::docx::get_text_from_docx(&mut cur)
+				let text = tokio::task::spawn_blocking(move || {
+					let cur = std::io::Cursor::new(bytes);
+					if content_type == ODT_CONTENT_TYPE {
+						crate::odt::get_text_from_odt(cur)
+					} else {
+						crate::docx::get_text_from_docx(cur)
+					}
 				})
 				.await
 				.unwrap();
 
-				match text {
-					Some(text) => return Ok(Self(text)),
-					None => return Err(StatusCode::UNPROCESSABLE_ENTITY.into_response()),
-				}
+				return text.map(Self).map_err(rejection);
+			} else if is_table(&content_type) {
+				return table_sections(req, content_type).await.map(|sections| Self(sections.join("\n\n")));
 			} else if content_type == "application/pdf" {
 				let Ok(bytes) = hyper::body::to_bytes(req.body_mut()).await else {
 					return Err(StatusCode::UNPROCESSABLE_ENTITY.into_response());
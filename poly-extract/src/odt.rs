@@ -0,0 +1,115 @@
+use std::io::{Read, Seek};
+
+use minidom::{Element, Node};
+
+use crate::{
+	office::{attr, child, heading, list_item, open_archive, read_xml},
+	ExtractError,
+};
+
+/// Retrieve plain text from an OpenDocument text (ODT) file, formatted like [`crate::docx::get_text_from_docx`] does:
+/// a line for each paragraph, with markers for headings, list items and table cells, and the footnotes at the end.
+pub fn get_text_from_odt<R>(reader: R) -> Result<String, ExtractError>
+where
+	R: Read + Seek,
+{
+	let mut archive = open_archive(reader)?;
+
+	// The manifest lists encryption data for each file that is encrypted
+	if let Some(manifest) = read_xml(&mut archive, "META-INF/manifest.xml")? {
+		if manifest.children().any(|entry| child(entry, "encryption-data").is_some()) {
+			return Err(ExtractError::Encrypted);
+		}
+	}
+
+	let content = read_xml(&mut archive, "content.xml")?.ok_or_else(|| ExtractError::Corrupt(String::from("the document has no content.xml")))?;
+	let mut lines = vec![];
+	let mut notes = vec![];
+	if let Some(text) = child(&content, "body").and_then(|body| child(body, "text")) {
+		block_lines(text, None, &mut lines, &mut notes);
+	}
+	lines.append(&mut notes);
+	Ok(lines.join("\n"))
+}
+
+/// Add the lines of the paragraphs, headings, lists and tables in an element. Paragraphs are list items when
+/// `list_level` is set. Notes are collected separately.
+fn block_lines(element: &Element, list_level: Option<usize>, lines: &mut Vec<String>, notes: &mut Vec<String>) {
+	for block in element.children() {
+		match block.name() {
+			"p" | "h" => {
+				let mut text = String::new();
+				inline_text(block, &mut text, notes);
+				if text.trim().is_empty() {
+					continue;
+				}
+				let line = match (block.name(), list_level) {
+					("h", _) => heading(attr(block, "outline-level").and_then(|l| l.parse().ok()).unwrap_or(1), &text),
+					(_, Some(level)) => list_item(level, &text),
+					_ => text,
+				};
+				lines.push(line);
+			}
+			"list" => {
+				let level = list_level.map(|l| l + 1).unwrap_or(0);
+				for item in block.children().filter(|c| c.name() == "list-item" || c.name() == "list-header") {
+					block_lines(item, Some(level), lines, notes);
+				}
+			}
+			"table" => table_lines(block, lines, notes),
+			"section" => block_lines(block, list_level, lines, notes),
+			_ => {}
+		}
+	}
+}
+
+/// Add a line for each row of a table (which may be in groups of header or other rows)
+fn table_lines(element: &Element, lines: &mut Vec<String>, notes: &mut Vec<String>) {
+	for rows in element.children() {
+		match rows.name() {
+			"table-row" => {
+				let cells: Vec<String> = rows
+					.children()
+					.filter(|c| c.name() == "table-cell")
+					.map(|cell| {
+						let mut cell_lines = vec![];
+						block_lines(cell, None, &mut cell_lines, notes);
+						cell_lines.join(" ")
+					})
+					.collect();
+				lines.push(cells.join(" | "));
+			}
+			"table-header-rows" | "table-rows" | "table-row-group" => table_lines(rows, lines, notes),
+			_ => {}
+		}
+	}
+}
+
+/// Add the text of a paragraph. Notes are referenced by their citation and added to `notes`.
+fn inline_text(element: &Element, text: &mut String, notes: &mut Vec<String>) {
+	for node in element.nodes() {
+		let inline = match node {
+			Node::Text(t) => {
+				text.push_str(t);
+				continue;
+			}
+			Node::Element(inline) => inline,
+		};
+		match inline.name() {
+			"s" => text.push_str(&" ".repeat(attr(inline, "c").and_then(|c| c.parse().ok()).unwrap_or(1))),
+			"tab" => text.push('\t'),
+			"line-break" => text.push(' '),
+			"note" => {
+				let citation = child(inline, "note-citation").map(|c| c.text()).unwrap_or_default();
+				let mut note_lines = vec![];
+				if let Some(body) = child(inline, "note-body") {
+					block_lines(body, None, &mut note_lines, notes);
+				}
+				text.push_str(&format!("[{citation}]"));
+				notes.push(format!("[{citation}] {}", note_lines.join(" ").trim()));
+			}
+			"annotation" | "tracked-changes" => {}
+			_ => inline_text(inline, text, notes),
+		}
+	}
+}
@@ -0,0 +1,75 @@
+//! Helpers for reading office documents, which are ZIP archives holding XML files
+
+use std::io::{Read, Seek, SeekFrom};
+
+use minidom::Element;
+use zip::{result::ZipError, ZipArchive};
+
+use crate::ExtractError;
+
+/// Signature of OLE compound files. Word and Excel store documents that are protected with a password in such a file
+/// instead of a ZIP archive.
+const OLE_SIGNATURE: [u8; 8] = [0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+
+/// Check that a document is not an OLE compound file (i.e. encrypted), leaving the reader at the start of the document
+pub(crate) fn check_not_encrypted<R>(reader: &mut R) -> Result<(), ExtractError>
+where
+	R: Read + Seek,
+{
+	let mut signature = [0u8; 8];
+	if reader.read_exact(&mut signature).is_ok() && signature == OLE_SIGNATURE {
+		return Err(ExtractError::Encrypted);
+	}
+	reader.seek(SeekFrom::Start(0)).map_err(|e| ExtractError::Corrupt(e.to_string()))?;
+	Ok(())
+}
+
+pub(crate) fn open_archive<R>(mut reader: R) -> Result<ZipArchive<R>, ExtractError>
+where
+	R: Read + Seek,
+{
+	check_not_encrypted(&mut reader)?;
+	ZipArchive::new(reader).map_err(|e| ExtractError::Corrupt(e.to_string()))
+}
+
+/// Read and parse an XML file from an archive, returning None when the archive does not contain it
+pub(crate) fn read_xml<R>(archive: &mut ZipArchive<R>, name: &str) -> Result<Option<Element>, ExtractError>
+where
+	R: Read + Seek,
+{
+	let mut file = match archive.by_name(name) {
+		Ok(file) => file,
+		Err(ZipError::FileNotFound) => return Ok(None),
+		Err(ZipError::UnsupportedArchive(message)) if message == ZipError::PASSWORD_REQUIRED => return Err(ExtractError::Encrypted),
+		Err(e) => return Err(ExtractError::Corrupt(e.to_string())),
+	};
+	let mut xml = String::new();
+	file.read_to_string(&mut xml)
+		.map_err(|e| ExtractError::Corrupt(format!("cannot read {name}: {e}")))?;
+	xml.parse()
+		.map(Some)
+		.map_err(|e| ExtractError::Corrupt(format!("invalid XML in {name}: {e}")))
+}
+
+/// Value of an attribute by its local name (i.e. regardless of its namespace)
+pub(crate) fn attr<'a>(element: &'a Element, name: &str) -> Option<&'a str> {
+	element
+		.attrs()
+		.find(|(key, _)| key.rsplit([':', '}']).next() == Some(name))
+		.map(|(_, value)| value)
+}
+
+/// The first child element with the given local name
+pub(crate) fn child<'a>(element: &'a Element, name: &str) -> Option<&'a Element> {
+	element.children().find(|c| c.name() == name)
+}
+
+/// Prefix a line of text with a heading marker ('#' for each level)
+pub(crate) fn heading(level: usize, text: &str) -> String {
+	format!("{} {text}", "#".repeat(level.max(1)))
+}
+
+/// Prefix a line of text with a list item marker, indented by two spaces for each level of nesting (starting at zero)
+pub(crate) fn list_item(level: usize, text: &str) -> String {
+	format!("{}- {text}", "  ".repeat(level))
+}
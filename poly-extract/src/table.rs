@@ -0,0 +1,264 @@
+//! Extraction of text from tables in CSV files and spreadsheets. Small tables are rendered as a markdown table; larger
+//! tables are rendered as one "column: value" record per row, in groups of rows. Each table or group of rows is returned
+//! as a separate section, so that it can be stored as a chunk of its own.
+
+use std::io::{Read, Seek};
+
+use calamine::{Data, Reader, Xlsx};
+
+use crate::{office::check_not_encrypted, ExtractError};
+
+/// Tables with at most this number of rows (excluding the header) are rendered as a markdown table
+const MARKDOWN_MAX_ROWS: usize = 10;
+
+/// Number of records in each section when a table is rendered as records
+const ROWS_PER_GROUP: usize = 10;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "axum", derive(serde::Deserialize))]
+#[cfg_attr(feature = "axum", serde(default))]
+pub struct TableOptions {
+	/// Whether the first row holds the names of the columns. When not set, this is detected from the contents.
+	pub header: Option<bool>,
+
+	/// Name of the sheet to read from a spreadsheet (all sheets are read when not set)
+	pub sheet: Option<String>,
+
+	/// Maximum number of rows to read from each table, excluding the header (all rows are read when not set)
+	pub max_rows: Option<usize>,
+}
+
+/// A table with its (optional) name and its cells as text
+struct Table {
+	name: Option<String>,
+	rows: Vec<Vec<String>>,
+}
+
+/// Whether a row looks like it holds the names of the columns: all cells are non-empty, not numeric and distinct
+fn is_header(row: &[String]) -> bool {
+	!row.is_empty()
+		&& row.iter().all(|cell| !cell.is_empty() && cell.parse::<f64>().is_err())
+		&& row.iter().enumerate().all(|(i, cell)| !row[..i].contains(cell))
+}
+
+/// Make a cell fit on a single line (and in a markdown table)
+fn clean(cell: &str) -> String {
+	cell.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl Table {
+	/// Render the table as sections of text
+	fn render(mut self, options: &TableOptions) -> Vec<String> {
+		self.rows.retain(|row| row.iter().any(|cell| !cell.is_empty()));
+		if self.rows.is_empty() {
+			return vec![];
+		}
+
+		let has_header = options.header.unwrap_or_else(|| self.rows.len() > 1 && is_header(&self.rows[0]));
+		let header = if has_header { Some(self.rows.remove(0)) } else { None };
+		if let Some(max_rows) = options.max_rows {
+			self.rows.truncate(max_rows);
+		}
+
+		let width = self.rows.iter().chain(header.iter()).map(|row| row.len()).max().unwrap_or(0);
+		let columns: Vec<String> = (0..width)
+			.map(|i| match header.as_ref().and_then(|h| h.get(i)).map(|c| clean(c)) {
+				Some(name) if !name.is_empty() => name,
+				_ => format!("Column {}", i + 1),
+			})
+			.collect();
+		let title = self.name.as_ref().map(|name| format!("# {name}\n"));
+
+		if self.rows.len() <= MARKDOWN_MAX_ROWS {
+			let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+			let mut lines = vec![
+				line(columns.iter().map(|c| c.replace('|', "\\|")).collect()),
+				line(vec![String::from("---"); width]),
+			];
+			for row in &self.rows {
+				lines.push(line(
+					(0..width)
+						.map(|i| row.get(i).map(|c| clean(c).replace('|', "\\|")).unwrap_or_default())
+						.collect(),
+				));
+			}
+			return vec![format!("{}{}", title.unwrap_or_default(), lines.join("\n"))];
+		}
+
+		self.rows
+			.chunks(ROWS_PER_GROUP)
+			.map(|group| {
+				let records: Vec<String> = group
+					.iter()
+					.map(|row| {
+						row.iter()
+							.zip(&columns)
+							.filter(|(cell, _)| !cell.is_empty())
+							.map(|(cell, column)| format!("{column}: {}", clean(cell)))
+							.collect::<Vec<_>>()
+							.join("; ")
+					})
+					.collect();
+				format!("{}{}", title.as_deref().unwrap_or_default(), records.join("\n"))
+			})
+			.collect()
+	}
+}
+
+/// Guess the delimiter of a CSV file from its first line: the most common of comma, semicolon and tab outside quotes
+fn detect_delimiter(text: &str) -> u8 {
+	let mut counts = [(b',', 0), (b';', 0), (b'\t', 0)];
+	let mut quoted = false;
+	for c in text.bytes() {
+		if c == b'"' {
+			quoted = !quoted;
+		} else if c == b'\n' && !quoted {
+			break;
+		}
+		if let Some((_, count)) = counts.iter_mut().find(|(d, _)| *d == c && !quoted) {
+			*count += 1;
+		}
+	}
+	counts
+		.iter()
+		.max_by_key(|(_, count)| *count)
+		.filter(|(_, count)| *count > 0)
+		.map(|(d, _)| *d)
+		.unwrap_or(b',')
+}
+
+/// Retrieve the table in a CSV file as sections of text
+pub fn get_sections_from_csv(bytes: &[u8], options: &TableOptions) -> Result<Vec<String>, ExtractError> {
+	let text = String::from_utf8_lossy(bytes);
+	let text = text.trim_start_matches('\u{feff}');
+	let mut reader = csv::ReaderBuilder::new()
+		.has_headers(false)
+		.flexible(true)
+		.delimiter(detect_delimiter(text))
+		.from_reader(text.as_bytes());
+
+	let rows = reader
+		.records()
+		.map(|record| record.map(|r| r.iter().map(|cell| cell.trim().to_string()).collect()))
+		.collect::<Result<Vec<Vec<String>>, _>>()
+		.map_err(|e| ExtractError::Corrupt(e.to_string()))?;
+	Ok(Table { name: None, rows }.render(options))
+}
+
+/// Retrieve the tables in the sheets of an Excel XLSX file as sections of text. The value of a merged cell is repeated in
+/// each of the cells it spans.
+pub fn get_sections_from_xlsx<R>(mut reader: R, options: &TableOptions) -> Result<Vec<String>, ExtractError>
+where
+	R: Read + Seek,
+{
+	check_not_encrypted(&mut reader)?;
+	let mut workbook = Xlsx::new(reader).map_err(|e| ExtractError::Corrupt(e.to_string()))?;
+	workbook.load_merged_regions().map_err(|e| ExtractError::Corrupt(e.to_string()))?;
+
+	let sheets = match options.sheet {
+		Some(ref sheet) if !workbook.sheet_names().contains(sheet) => return Err(ExtractError::SheetNotFound(sheet.clone())),
+		Some(ref sheet) => vec![sheet.clone()],
+		None => workbook.sheet_names(),
+	};
+
+	let mut sections = vec![];
+	for sheet in sheets {
+		let range = workbook.worksheet_range(&sheet).map_err(|e| ExtractError::Corrupt(e.to_string()))?;
+		let (top, left) = range.start().unwrap_or_default();
+		let mut rows: Vec<Vec<String>> = range
+			.rows()
+			.map(|row| {
+				row.iter()
+					.map(|cell| match cell {
+						Data::Error(_) => String::new(),
+						cell => cell.to_string().trim().to_string(),
+					})
+					.collect()
+			})
+			.collect();
+
+		// Merged regions are given in absolute positions, whereas the range starts at the first cell that is used
+		for (_, _, region) in workbook.merged_regions_by_sheet(&sheet) {
+			let (Some(start_row), Some(start_column)) = (region.start.0.checked_sub(top), region.start.1.checked_sub(left)) else {
+				continue;
+			};
+			let Some(value) = rows.get(start_row as usize).and_then(|r| r.get(start_column as usize)).cloned() else {
+				continue;
+			};
+			for row in rows
+				.iter_mut()
+				.take(region.end.0.saturating_sub(top) as usize + 1)
+				.skip(start_row as usize)
+			{
+				for cell in row
+					.iter_mut()
+					.take(region.end.1.saturating_sub(left) as usize + 1)
+					.skip(start_column as usize)
+				{
+					cell.clone_from(&value);
+				}
+			}
+		}
+
+		sections.extend(Table { name: Some(sheet), rows }.render(options));
+	}
+	Ok(sections)
+}
+
+#[cfg(test)]
+mod test {
+	use super::{detect_delimiter, is_header, Table, TableOptions};
+
+	fn table(rows: &[&[&str]]) -> Table {
+		Table {
+			name: None,
+			rows: rows.iter().map(|row| row.iter().map(|c| c.to_string()).collect()).collect(),
+		}
+	}
+
+	#[test]
+	fn test_detect_delimiter() {
+		assert_eq!(detect_delimiter("a,b,c\n1;2"), b',');
+		assert_eq!(detect_delimiter("a;b;\"c,d,e\"\n"), b';');
+		assert_eq!(detect_delimiter("a\tb"), b'\t');
+		assert_eq!(detect_delimiter("a"), b',');
+	}
+
+	#[test]
+	fn test_header_detection() {
+		let row = |cells: &[&str]| cells.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+		assert!(is_header(&row(&["Name", "Age"])));
+		assert!(!is_header(&row(&["Name", "42"])));
+		assert!(!is_header(&row(&["Name", ""])));
+		assert!(!is_header(&row(&["Name", "Name"])));
+
+		// Without a header, columns are numbered
+		let sections = table(&[&["1", "2"], &["3", "4"]]).render(&TableOptions::default());
+		assert_eq!(sections, vec!["| Column 1 | Column 2 |\n| --- | --- |\n| 1 | 2 |\n| 3 | 4 |"]);
+
+		// The header can be forced
+		let options = TableOptions {
+			header: Some(true),
+			..Default::default()
+		};
+		let sections = table(&[&["1", "2"], &["3", "4"]]).render(&options);
+		assert_eq!(sections, vec!["| 1 | 2 |\n| --- | --- |\n| 3 | 4 |"]);
+	}
+
+	#[test]
+	fn test_records() {
+		let mut rows: Vec<Vec<String>> = vec![vec![String::from("Name"), String::from("Office")]];
+		for i in 1..=12 {
+			let office = if i % 3 == 0 { "" } else { "Amsterdam" };
+			rows.push(vec![format!("Person {i}"), office.to_string()]);
+		}
+		let table = Table {
+			name: Some(String::from("Staff")),
+			rows,
+		};
+		let sections = table.render(&TableOptions::default());
+		assert_eq!(sections.len(), 2);
+		assert!(sections[0].starts_with("# Staff\nName: Person 1; Office: Amsterdam\nName: Person 2; Office: Amsterdam\nName: Person 3\n"));
+		assert_eq!(sections[1], "# Staff\nName: Person 11; Office: Amsterdam\nName: Person 12");
+	}
+}
@@ -0,0 +1,148 @@
+use std::io::Cursor;
+
+use poly_extract::{
+	docx::get_text_from_docx,
+	odt::get_text_from_odt,
+	table::{get_sections_from_csv, get_sections_from_xlsx, TableOptions},
+	ExtractError,
+};
+
+/// Text of the sample documents, which hold the same content
+static SAMPLE_TEXT: &str = "# Quarterly report
+## Overview
+Sales grew in Zürich, 東京 and São Paulo 🦀[1].
+- First item
+  - Nested item
+Region | Revenue
+Europe | € 1.200
+Done.
+[1] Figures are preliminary.";
+
+fn read(name: &str) -> Vec<u8> {
+	std::fs::read(format!("{}/../data/{name}", env!("CARGO_MANIFEST_DIR"))).unwrap()
+}
+
+#[test]
+fn test_docx() {
+	let text = get_text_from_docx(Cursor::new(read("sample.docx"))).unwrap();
+	assert_eq!(text, SAMPLE_TEXT);
+}
+
+#[test]
+fn test_odt() {
+	let text = get_text_from_odt(Cursor::new(read("sample.odt"))).unwrap();
+	assert_eq!(text, SAMPLE_TEXT);
+}
+
+#[test]
+fn test_corrupt() {
+	// Truncated archives and documents of the other type are corrupt
+	let docx = read("sample.docx");
+	let truncated = &docx[..docx.len() / 2];
+	assert!(matches!(get_text_from_docx(Cursor::new(truncated)), Err(ExtractError::Corrupt(_))));
+	assert!(matches!(get_text_from_odt(Cursor::new(truncated)), Err(ExtractError::Corrupt(_))));
+	assert!(matches!(
+		get_text_from_docx(Cursor::new(read("sample.odt"))),
+		Err(ExtractError::Corrupt(_))
+	));
+	assert!(matches!(get_text_from_odt(Cursor::new(docx)), Err(ExtractError::Corrupt(_))));
+	assert!(matches!(get_text_from_docx(Cursor::new(b"")), Err(ExtractError::Corrupt(_))));
+}
+
+#[test]
+fn test_encrypted() {
+	// Word stores documents protected with a password in an OLE compound file
+	let mut ole = vec![0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+	ole.resize(512, 0);
+	let error = get_text_from_docx(Cursor::new(ole)).unwrap_err();
+	assert!(matches!(error, ExtractError::Encrypted));
+	assert_eq!(error.kind(), "encrypted_document");
+
+	assert!(matches!(
+		get_text_from_odt(Cursor::new(read("encrypted.odt"))),
+		Err(ExtractError::Encrypted)
+	));
+}
+
+#[test]
+fn test_csv() {
+	// Quoted cells may contain commas, quotes and line breaks
+	let sections = get_sections_from_csv(&read("sample.csv"), &TableOptions::default()).unwrap();
+	assert_eq!(
+		sections,
+		vec![
+			"| name | description | price |
+| --- | --- | --- |
+| Widget, large | A \"big\" widget | 10 |
+| Gadget | Two lines | 5.5 |"
+		]
+	);
+
+	let options = TableOptions {
+		header: Some(false),
+		max_rows: Some(2),
+		..Default::default()
+	};
+	let sections = get_sections_from_csv(&read("sample.csv"), &options).unwrap();
+	assert_eq!(
+		sections,
+		vec![
+			"| Column 1 | Column 2 | Column 3 |
+| --- | --- | --- |
+| name | description | price |
+| Widget, large | A \"big\" widget | 10 |"
+		]
+	);
+}
+
+#[test]
+fn test_xlsx() {
+	// Each sheet is a table; the value of a merged cell is repeated and large tables are split in groups of records
+	let sections = get_sections_from_xlsx(Cursor::new(read("sample.xlsx")), &TableOptions::default()).unwrap();
+	assert_eq!(sections.len(), 3);
+	assert_eq!(
+		sections[0],
+		"# Revenue
+| Region | Quarter | Revenue |
+| --- | --- | --- |
+| Europe | Q1 | 1200 |
+| Europe | Q2 | 1350.5 |
+| Asia | Q1 | 800 |"
+	);
+	assert!(sections[1].starts_with("# Staff\nName: Person 1; Office: Amsterdam\nName: Person 2; Office: Zürich\n"));
+	assert_eq!(sections[1].lines().count(), 11);
+	assert_eq!(
+		sections[2],
+		"# Staff\nName: Person 11; Office: Amsterdam\nName: Person 12; Office: Zürich"
+	);
+
+	// A single sheet can be selected
+	let options = TableOptions {
+		sheet: Some(String::from("Staff")),
+		max_rows: Some(3),
+		..Default::default()
+	};
+	let sections = get_sections_from_xlsx(Cursor::new(read("sample.xlsx")), &options).unwrap();
+	assert_eq!(
+		sections,
+		vec![
+			"# Staff
+| Name | Office |
+| --- | --- |
+| Person 1 | Amsterdam |
+| Person 2 | Zürich |
+| Person 3 | Amsterdam |"
+		]
+	);
+
+	let options = TableOptions {
+		sheet: Some(String::from("Missing")),
+		..Default::default()
+	};
+	let error = get_sections_from_xlsx(Cursor::new(read("sample.xlsx")), &options).unwrap_err();
+	assert_eq!(error.kind(), "sheet_not_found");
+	assert!(matches!(
+		get_sections_from_xlsx(Cursor::new(read("sample.docx")), &TableOptions::default()),
+		Err(ExtractError::Corrupt(_))
+	));
+}
@@ -0,0 +1,37 @@
+#![cfg(feature = "fetch")]
+
+use poly_extract::fetch::{fetch_document, FetchError, FetchOptions};
+
+#[tokio::test]
+async fn test_fetch_refused() {
+	let options = FetchOptions::default();
+
+	// Private addresses are never fetched from, also when a name resolves to them
+	for url in [
+		"http://127.0.0.1:1/",
+		"http://localhost/",
+		"http://[::1]/",
+		"http://[::ffff:10.0.0.1]/",
+		"http://169.254.169.254/latest",
+	] {
+		let error = fetch_document(url, &options).await.unwrap_err();
+		assert!(matches!(error, FetchError::Blocked(_)), "{url}: unexpected error {error}");
+		assert_eq!(error.kind(), "url_blocked");
+	}
+
+	for url in ["ftp://example.com/file.txt", "not a url", "file:///etc/passwd"] {
+		assert!(matches!(fetch_document(url, &options).await, Err(FetchError::InvalidUrl(_))), "{url}");
+	}
+
+	// Hosts that are not allowed or that are denied are refused before connecting
+	let options = FetchOptions {
+		allowed_hosts: vec!["*.example.com".parse().unwrap()],
+		denied_hosts: vec!["93.184.216.0/24".parse().unwrap()],
+		..Default::default()
+	};
+	assert!(matches!(fetch_document("http://1.1.1.1/", &options).await, Err(FetchError::Blocked(_))));
+	assert!(matches!(
+		fetch_document("http://93.184.216.34/", &options).await,
+		Err(FetchError::Blocked(_))
+	));
+}
@@ -0,0 +1,5 @@
+fn main() {
+	// Compiling the protocol buffers requires `protoc` (see https://grpc.io/docs/protoc-installation/)
+	#[cfg(feature = "grpc")]
+	tonic_build::compile_protos("proto/poly.proto").expect("compile protocol buffers");
+}
@@ -0,0 +1,424 @@
+//! Admission of requests, of which at most `max_concurrent` are serviced at the same time (see
+//! [`crate::config::Config::max_concurrent`]). Requests that arrive while the limit is reached wait in line. Whenever a
+//! request is done, the waiting request with the highest priority is admitted, and of those with the same priority the
+//! one that arrived first. A request that waits long enough is promoted to the next higher priority every
+//! `priority_aging_secs`, so that requests with a low priority are not starved. Waiting requests can be told their
+//! position in line and an estimate of how long they will have to wait, based on how long recently serviced requests
+//! took.
+
+use std::{
+	collections::{BTreeMap, HashMap, VecDeque},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use poly_backend::{stats::QueueWaitStats, types::Priority};
+use serde::Serialize;
+use tokio::sync::oneshot;
+use utoipa::ToSchema;
+
+/// Number of recently serviced requests whose durations are used to estimate waiting times
+const RECENT_DURATIONS: usize = 32;
+
+pub struct Admission {
+	max_concurrent: usize,
+
+	/// Time after which a waiting request is promoted to the next higher priority
+	aging: Duration,
+	queue: Mutex<Queue>,
+}
+
+struct Queue {
+	/// Number of requests that can be admitted before the maximum is reached
+	available: usize,
+
+	/// Tickets are numbered in the order in which requests started waiting
+	next_ticket: u64,
+	waiting: BTreeMap<u64, Waiter>,
+
+	/// How long the most recently serviced requests took (most recent last)
+	recent: VecDeque<Duration>,
+
+	waits: HashMap<Priority, QueueWaitStats>,
+}
+
+struct Waiter {
+	priority: Priority,
+	since: Instant,
+
+	/// Signalled when the request is admitted, after which it holds one of the available places
+	admit: oneshot::Sender<()>,
+}
+
+/// The position of a waiting request in line
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+pub struct QueueStatus {
+	/// Number of requests (including this one) that will be admitted before this one is (one when it is next)
+	pub position: usize,
+
+	/// Estimated time until the request is admitted in milliseconds (not set when no request has been serviced yet)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub estimated_wait_ms: Option<u64>,
+}
+
+/// A place in line for a request that could not be admitted immediately (see [`Admission::enter`]). The place is given
+/// up when the ticket is dropped.
+pub struct QueueTicket {
+	admission: Arc<Admission>,
+	number: u64,
+	priority: Priority,
+	admitted: oneshot::Receiver<()>,
+
+	/// Position when the request started waiting
+	initial_position: usize,
+	waiting_since: Instant,
+}
+
+/// Allows a request to be serviced. The next waiting request is admitted when the permit is dropped.
+pub struct AdmissionPermit {
+	admission: Arc<Admission>,
+	admitted_at: Instant,
+
+	/// Set when the request had to wait before it was admitted
+	pub queued: Option<QueuedFor>,
+}
+
+/// How long a request waited before it was admitted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueuedFor {
+	/// The position of the request when it started waiting
+	pub position: usize,
+	pub duration: Duration,
+}
+
+impl Admission {
+	pub fn new(max_concurrent: usize, aging: Duration) -> Admission {
+		Admission {
+			max_concurrent,
+			aging,
+			queue: Mutex::new(Queue {
+				available: max_concurrent,
+				next_ticket: 0,
+				waiting: BTreeMap::new(),
+				recent: VecDeque::with_capacity(RECENT_DURATIONS),
+				waits: HashMap::new(),
+			}),
+		}
+	}
+
+	/// Admit a request immediately when fewer than the maximum number of requests are being serviced, or else return a
+	/// ticket with which the request waits for its turn
+	pub fn enter(self: &Arc<Self>, priority: Priority) -> Result<AdmissionPermit, QueueTicket> {
+		let mut queue = self.queue.lock().unwrap();
+		if queue.available > 0 {
+			queue.available -= 1;
+			queue.waits.entry(priority).or_default().add(None);
+			return Ok(self.permit(None));
+		}
+
+		let number = queue.next_ticket;
+		queue.next_ticket += 1;
+		let (admit, admitted) = oneshot::channel();
+		let now = Instant::now();
+		queue.waiting.insert(number, Waiter { priority, since: now, admit });
+		let initial_position = self.position(&queue, number, now);
+		Err(QueueTicket {
+			admission: self.clone(),
+			number,
+			priority,
+			admitted,
+			initial_position,
+			waiting_since: now,
+		})
+	}
+
+	/// Admit a request, waiting for its turn when necessary
+	pub async fn admit(self: &Arc<Self>, priority: Priority) -> AdmissionPermit {
+		match self.enter(priority) {
+			Ok(permit) => permit,
+			Err(ticket) => ticket.admit().await,
+		}
+	}
+
+	/// The number of requests currently waiting to be admitted
+	pub fn depth(&self) -> usize {
+		self.queue.lock().unwrap().waiting.len()
+	}
+
+	/// How long admitted requests waited, by the priority they were given
+	pub fn wait_stats(&self) -> HashMap<Priority, QueueWaitStats> {
+		self.queue.lock().unwrap().waits.clone()
+	}
+
+	/// The expected time until a request at the specified position is admitted, or `None` when no request was serviced
+	/// yet. Requests are assumed to take the average of the recent durations, with `max_concurrent` serviced at a time.
+	pub fn estimated_wait(&self, position: usize) -> Option<Duration> {
+		self.estimate(&self.queue.lock().unwrap(), position)
+	}
+
+	fn estimate(&self, queue: &Queue, position: usize) -> Option<Duration> {
+		if queue.recent.is_empty() {
+			return None;
+		}
+		let average = queue.recent.iter().sum::<Duration>() / queue.recent.len() as u32;
+		Some(average * position.div_ceil(self.max_concurrent) as u32)
+	}
+
+	/// The priority of a waiting request, taking into account how long it has been waiting
+	fn effective_priority(&self, waiter: &Waiter, now: Instant) -> Priority {
+		let levels = now.duration_since(waiter.since).as_millis() / self.aging.as_millis().max(1);
+		waiter.priority.promoted(levels as u64)
+	}
+
+	/// The tickets of the waiting requests in the order in which they would be admitted now
+	fn order(&self, queue: &Queue, now: Instant) -> Vec<u64> {
+		let mut order: Vec<(Priority, u64)> = queue
+			.waiting
+			.iter()
+			.map(|(number, waiter)| (self.effective_priority(waiter, now), *number))
+			.collect();
+		order.sort_by(|(pa, na), (pb, nb)| pb.cmp(pa).then(na.cmp(nb)));
+		order.into_iter().map(|(_, number)| number).collect()
+	}
+
+	fn position(&self, queue: &Queue, number: u64, now: Instant) -> usize {
+		self.order(queue, now).iter().position(|n| *n == number).map_or(0, |index| index + 1)
+	}
+
+	/// Give a place that became available to the next waiting request, or keep it available when no request is waiting
+	fn release(&self, queue: &mut Queue) {
+		while let Some(number) = self.order(queue, Instant::now()).first().copied() {
+			let waiter = queue.waiting.remove(&number).expect("waiter is in line");
+			if waiter.admit.send(()).is_ok() {
+				return;
+			}
+		}
+		queue.available += 1;
+	}
+
+	fn permit(self: &Arc<Self>, queued: Option<QueuedFor>) -> AdmissionPermit {
+		AdmissionPermit {
+			admission: self.clone(),
+			admitted_at: Instant::now(),
+			queued,
+		}
+	}
+}
+
+impl QueueTicket {
+	/// The current position of the request in line
+	pub fn position(&self) -> usize {
+		let queue = self.admission.queue.lock().unwrap();
+		self.admission.position(&queue, self.number, Instant::now())
+	}
+
+	pub fn status(&self) -> QueueStatus {
+		let queue = self.admission.queue.lock().unwrap();
+		let position = self.admission.position(&queue, self.number, Instant::now());
+		QueueStatus {
+			position,
+			estimated_wait_ms: self.admission.estimate(&queue, position).map(|d| d.as_millis() as u64),
+		}
+	}
+
+	/// Wait until the request is admitted
+	pub async fn admit(mut self) -> AdmissionPermit {
+		(&mut self.admitted).await.expect("waiters are only dropped when admitted");
+		self.admitted()
+	}
+
+	/// Wait for at most `period` for the request to be admitted. Returns `None` when it was not admitted yet, in which case
+	/// it keeps its place in line (e.g. so that its new position can be reported before waiting again).
+	pub async fn admit_within(&mut self, period: Duration) -> Option<AdmissionPermit> {
+		match tokio::time::timeout(period, &mut self.admitted).await {
+			Ok(admitted) => {
+				admitted.expect("waiters are only dropped when admitted");
+				Some(self.admitted())
+			}
+			Err(_) => None,
+		}
+	}
+
+	fn admitted(&mut self) -> AdmissionPermit {
+		let duration = self.waiting_since.elapsed();
+		let mut queue = self.admission.queue.lock().unwrap();
+		queue.waits.entry(self.priority).or_default().add(Some(duration));
+		self.admission.permit(Some(QueuedFor {
+			position: self.initial_position,
+			duration,
+		}))
+	}
+}
+
+impl Drop for QueueTicket {
+	fn drop(&mut self) {
+		let mut queue = self.admission.queue.lock().unwrap();
+		if queue.waiting.remove(&self.number).is_none() && self.admitted.try_recv().is_ok() {
+			// The request was admitted, but gave up before it noticed; its place goes to the next request
+			self.admission.release(&mut queue);
+		}
+	}
+}
+
+impl Drop for AdmissionPermit {
+	fn drop(&mut self) {
+		let mut queue = self.admission.queue.lock().unwrap();
+		if queue.recent.len() == RECENT_DURATIONS {
+			queue.recent.pop_front();
+		}
+		queue.recent.push_back(self.admitted_at.elapsed());
+		self.admission.release(&mut queue);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		sync::{Arc, Mutex},
+		time::Duration,
+	};
+
+	use poly_backend::types::Priority;
+
+	use super::Admission;
+
+	const AGING: Duration = Duration::from_secs(30);
+
+	#[tokio::test]
+	async fn test_queue_positions() {
+		let admission = Arc::new(Admission::new(1, AGING));
+		let first = admission.enter(Priority::Normal).ok().unwrap();
+		assert!(first.queued.is_none());
+
+		let Err(mut second) = admission.enter(Priority::Normal) else {
+			panic!("second request should wait");
+		};
+		let Err(third) = admission.enter(Priority::Normal) else {
+			panic!("third request should wait");
+		};
+		assert_eq!((second.position(), third.position()), (1, 2));
+		assert_eq!(admission.depth(), 2);
+
+		// Nothing was serviced yet, so there is no estimate
+		assert_eq!(second.status().estimated_wait_ms, None);
+		assert!(second.admit_within(Duration::from_millis(10)).await.is_none());
+
+		// Requests that leave the line move the others up
+		drop(first);
+		let second = second.admit().await;
+		assert_eq!(second.queued.map(|q| q.position), Some(1));
+		assert_eq!(third.position(), 1);
+		assert_eq!(admission.depth(), 1);
+		assert!(third.status().estimated_wait_ms.is_some());
+
+		drop(third);
+		assert_eq!(admission.depth(), 0);
+		drop(second);
+		assert!(admission.enter(Priority::Normal).is_ok());
+	}
+
+	#[test]
+	fn test_estimated_wait() {
+		let admission = Arc::new(Admission::new(2, AGING));
+		assert_eq!(admission.estimated_wait(1), None);
+
+		for duration in [100, 300] {
+			let mut permit = admission.enter(Priority::Normal).ok().unwrap();
+			permit.admitted_at -= Duration::from_millis(duration);
+		}
+		let estimated = |position| admission.estimated_wait(position).unwrap().as_millis();
+		assert!((200..210).contains(&estimated(1)));
+		assert!((200..210).contains(&estimated(2)));
+		assert!((400..420).contains(&estimated(3)));
+	}
+
+	/// Service a burst of requests one at a time, each taking a while (like a slow model), and return the order in which
+	/// they were serviced
+	async fn service_burst(admission: Arc<Admission>, burst: &[(&'static str, Priority)]) -> Vec<&'static str> {
+		let serviced = Arc::new(Mutex::new(vec![]));
+
+		// Keep the server busy while the burst arrives
+		let busy = admission.enter(Priority::Normal).ok().unwrap();
+		let mut handles = vec![];
+		for (name, priority) in burst.iter().copied() {
+			let Err(ticket) = admission.enter(priority) else {
+				panic!("request should wait");
+			};
+			let serviced = serviced.clone();
+			handles.push(tokio::spawn(async move {
+				let _permit = ticket.admit().await;
+				serviced.lock().unwrap().push(name);
+				tokio::time::sleep(Duration::from_millis(20)).await;
+			}));
+		}
+		drop(busy);
+
+		for handle in handles {
+			handle.await.unwrap();
+		}
+		Arc::try_unwrap(serviced).unwrap().into_inner().unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_priority_order() {
+		let admission = Arc::new(Admission::new(1, AGING));
+		let burst = [
+			("summary1", Priority::Low),
+			("completion1", Priority::Normal),
+			("summary2", Priority::Low),
+			("chat1", Priority::High),
+			("completion2", Priority::Normal),
+			("chat2", Priority::High),
+		];
+		let order = service_burst(admission.clone(), &burst).await;
+		assert_eq!(order, ["chat1", "chat2", "completion1", "completion2", "summary1", "summary2"]);
+
+		let stats = admission.wait_stats();
+		assert_eq!(stats[&Priority::High].admitted, 2);
+		assert_eq!(stats[&Priority::High].queued, 2);
+		assert_eq!(stats[&Priority::Normal].admitted, 3);
+		assert_eq!(stats[&Priority::Normal].queued, 2);
+		assert_eq!(stats[&Priority::Low].queued, 2);
+		assert!(stats[&Priority::Low].max_wait > stats[&Priority::High].max_wait);
+	}
+
+	#[tokio::test]
+	async fn test_priority_aging() {
+		// Requests are promoted every 50ms, so a low-priority request that has waited for over 100ms goes before a
+		// high-priority request that just arrived
+		let admission = Arc::new(Admission::new(1, Duration::from_millis(50)));
+		let busy = admission.enter(Priority::Normal).ok().unwrap();
+		let Err(low) = admission.enter(Priority::Low) else {
+			panic!("request should wait");
+		};
+		tokio::time::sleep(Duration::from_millis(110)).await;
+		let Err(high) = admission.enter(Priority::High) else {
+			panic!("request should wait");
+		};
+		assert_eq!((low.position(), high.position()), (1, 2));
+
+		drop(busy);
+		let low = low.admit().await;
+		assert_eq!(high.position(), 1);
+		drop(low);
+		high.admit().await;
+	}
+
+	#[tokio::test]
+	async fn test_abandoned_ticket() {
+		let admission = Arc::new(Admission::new(1, AGING));
+		let busy = admission.enter(Priority::Normal).ok().unwrap();
+		let Err(abandoned) = admission.enter(Priority::High) else {
+			panic!("request should wait");
+		};
+		let Err(waiting) = admission.enter(Priority::Normal) else {
+			panic!("request should wait");
+		};
+
+		// The place given to a request that is gone by the time it is admitted goes to the next request
+		drop(busy);
+		drop(abandoned);
+		drop(waiting.admit().await);
+		assert!(admission.enter(Priority::Low).is_ok());
+	}
+}
@@ -1,17 +1,91 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{http::StatusCode, response::IntoResponse, Json};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
 
-use poly_backend::stats::TaskStats;
-use poly_backend::types::BackendError as OriginalGenerateError;
+use poly_backend::{
+	memory::MemoryError,
+	types::{BackendError as OriginalGenerateError, MemoryStage, Priority, PromptRequest},
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct JwtClaims {
 	pub exp: Option<usize>,            // Expiry time
-	pub sub: Option<String>,           // User identifier (currently only used for logging)
+	pub sub: Option<String>,           // User identifier (used for logging and to tell whose chats can be resumed)
 	pub tasks: Option<Vec<String>>,    // Optional list of tasks this token is allowed to use
 	pub models: Option<Vec<String>>,   // Optional list of models this token is allowed to use
 	pub memories: Option<Vec<String>>, // Optional list of memories this token is allowed to use
+
+	/// Highest priority this token may request (see [`JwtClaims::priority`])
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_priority: Option<Priority>,
+
+	/// Whether this token may mark segments of prompts as trusted, which exempts them from the check for private tokens
+	/// (see [`JwtClaims::check_prompt`])
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub trusted_segments: bool,
+}
+
+impl JwtClaims {
+	pub fn allows_task(&self, task_name: &str) -> bool {
+		allows(&self.tasks, task_name)
+	}
+
+	pub fn allows_model(&self, model_name: &str) -> bool {
+		allows(&self.models, model_name)
+	}
+
+	pub fn allows_memory(&self, memory_name: &str) -> bool {
+		allows(&self.memories, memory_name)
+	}
+
+	/// Check that a prompt only has segments marked as trusted when the user is allowed to mark them so. Anyone else
+	/// could otherwise use such segments to smuggle private tokens into the prompt.
+	pub fn check_prompt(&self, prompt: &PromptRequest) -> Result<(), OriginalGenerateError> {
+		if !self.trusted_segments && prompt.segments.iter().any(|s| s.trusted) {
+			return Err(OriginalGenerateError::TrustedSegmentNotAllowed);
+		}
+		Ok(())
+	}
+
+	/// The priority of a request that asks for `requested` while the priority would otherwise be `default` (the priority of
+	/// the task). A lower priority can always be requested, a higher one only up to `max_priority`.
+	pub fn priority(&self, requested: Option<Priority>, default: Priority) -> Priority {
+		match requested {
+			Some(requested) if requested > default => requested.min(self.max_priority.unwrap_or(default)).max(default),
+			Some(requested) => requested,
+			None => default,
+		}
+	}
+}
+
+/// Query parameter with which a request asks for a priority different from that of the task (see [`JwtClaims::priority`])
+#[derive(Deserialize, Clone, Debug, Default, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct PriorityRequest {
+	pub priority: Option<Priority>,
+}
+
+/// Whether a name is in a list of allowed names. When there is no list, any name is allowed.
+fn allows(allowed: &Option<Vec<String>>, name: &str) -> bool {
+	allowed.as_ref().map_or(true, |names| names.iter().any(|n| n == name))
+}
+
+/// The way in which a request was authenticated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethod {
+	/// Using one of the static API keys
+	Key,
+
+	/// Using a signed JWT
+	Jwt,
+
+	/// Not authenticated (server is public)
+	Public,
+
+	/// Not authenticated on a server that is not public, which only allows using the public tasks (see
+	/// [`crate::middleware::claims_for_token`])
+	Anonymous,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -19,9 +93,9 @@ pub struct KeyQuery {
 	pub api_key: Option<String>,
 }
 
-#[derive(Serialize, Clone, Debug)]
-pub struct StatsResponse {
-	pub tasks: HashMap<String, TaskStats>,
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct ReloadErrorResponse {
+	pub problems: Vec<String>,
 }
 
 #[derive(Deserialize, Clone, Debug, Default)]
@@ -32,25 +106,205 @@ trait ToStatusCode {
 	fn status_code(&self) -> StatusCode;
 }
 
+/// Body of the response to a request that failed
+#[derive(Serialize, Clone, Debug, PartialEq, ToSchema)]
+pub struct ErrorResponse {
+	/// Machine-readable kind of error (e.g. `context_full`)
+	#[schema(value_type = String)]
+	pub error: &'static str,
+
+	/// Human-readable description of the error
+	pub message: String,
+
+	/// Whether the same request may succeed when it is sent again later
+	pub retryable: bool,
+
+	/// For `context_full` and `prompt_too_long`: number of tokens the request needed room for
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub needed: Option<usize>,
+
+	/// For `context_full`: number of tokens there was room for
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub available: Option<usize>,
+
+	/// For `prompt_too_long`: maximum number of prompt tokens the task allows
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub allowed: Option<usize>,
+
+	/// For `inference_failed`: number of tokens generated before inference failed
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub after_tokens: Option<usize>,
+
+	/// For `illegal_token`: index of the prompt segment that contains the token that is not allowed. The token itself is
+	/// not returned, as it may be one of the private tokens of the task.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub segment: Option<usize>,
+
+	/// For `illegal_token`: byte offset of the token that is not allowed in the text of the segment
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub offset: Option<usize>,
+
+	/// For `memory_failed`: the operation on the memory that failed
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub stage: Option<MemoryStage>,
+
+	/// For `model_not_available`: the model that could not be loaded
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub model: Option<String>,
+
+	/// For `model_not_available`: why the model could not be loaded
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub reason: Option<String>,
+
+	/// For `biased_output_invalid`: number of completions performed (including repairs)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub attempts: Option<usize>,
+
+	/// For `biased_output_invalid`: why the last output is invalid
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub validation_errors: Option<Vec<String>>,
+
+	/// For `invalid_variables`: variables of the prompt template that were not given
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub missing_variables: Option<Vec<String>>,
+
+	/// For `invalid_variables`: variables that were given but are not in the prompt template
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub unknown_variables: Option<Vec<String>>,
+
+	/// For `invalid_variables`: variables with a value longer than the task allows
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub too_long_variables: Option<Vec<String>>,
+}
+
 pub struct BackendError(OriginalGenerateError);
 
 impl BackendError {
-	fn status_code(&self) -> StatusCode {
+	pub(crate) fn status_code(&self) -> StatusCode {
 		match self.0 {
 			OriginalGenerateError::TaskNotFound(_) | OriginalGenerateError::ModelNotFound(_) | OriginalGenerateError::MemoryNotFound(_) => {
 				StatusCode::NOT_FOUND
 			}
-			OriginalGenerateError::InferenceError(_) | OriginalGenerateError::TokenizationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-			OriginalGenerateError::Memory(_) => StatusCode::INTERNAL_SERVER_ERROR,
-			OriginalGenerateError::IllegalToken | OriginalGenerateError::InvalidDocument => StatusCode::BAD_REQUEST,
-			OriginalGenerateError::InvalidChunkSeparator(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			OriginalGenerateError::ModelNotAvailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+			OriginalGenerateError::ContextFull { .. }
+			| OriginalGenerateError::PromptTooLong { .. }
+			| OriginalGenerateError::SummaryLimitExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+			OriginalGenerateError::InferenceFailed { .. }
+			| OriginalGenerateError::TokenizationError(_)
+			| OriginalGenerateError::BiasedOutputInvalid { .. }
+			| OriginalGenerateError::TokenBudgetExceeded { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+			OriginalGenerateError::MemoryFailed {
+				source: MemoryError::Full { .. },
+				..
+			} => StatusCode::INSUFFICIENT_STORAGE,
+			OriginalGenerateError::MemoryFailed { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+			OriginalGenerateError::InvalidQuery(_)
+			| OriginalGenerateError::InvalidVariables { .. }
+			| OriginalGenerateError::TrustedSegmentNotAllowed => StatusCode::UNPROCESSABLE_ENTITY,
+			OriginalGenerateError::IllegalToken { .. } | OriginalGenerateError::InvalidDocument | OriginalGenerateError::InvalidParameter(..) => {
+				StatusCode::BAD_REQUEST
+			}
+			OriginalGenerateError::BudgetExceeded { .. } => StatusCode::GATEWAY_TIMEOUT,
+			OriginalGenerateError::InvalidChunkSeparator(_)
+			| OriginalGenerateError::InvalidEotToken(_)
+			| OriginalGenerateError::InvalidPrivateToken(_)
+			| OriginalGenerateError::InvalidRerankToken(_)
+			| OriginalGenerateError::SessionState(_)
+			| OriginalGenerateError::InvalidBiaser(_)
+			| OriginalGenerateError::InvalidPromptTemplate(_) => StatusCode::INTERNAL_SERVER_ERROR,
 		}
 	}
+
+	pub(crate) fn body(&self) -> ErrorResponse {
+		let mut body = ErrorResponse {
+			error: "",
+			message: self.0.to_string(),
+			retryable: self.0.is_retryable(),
+			needed: None,
+			available: None,
+			allowed: None,
+			after_tokens: None,
+			segment: None,
+			offset: None,
+			stage: None,
+			model: None,
+			reason: None,
+			attempts: None,
+			validation_errors: None,
+			missing_variables: None,
+			unknown_variables: None,
+			too_long_variables: None,
+		};
+		body.error = match self.0 {
+			OriginalGenerateError::TaskNotFound(_) => "task_not_found",
+			OriginalGenerateError::ModelNotFound(_) => "model_not_found",
+			OriginalGenerateError::ModelNotAvailable { ref model, ref reason } => {
+				body.model = Some(model.clone());
+				body.reason = Some(reason.clone());
+				"model_not_available"
+			}
+			OriginalGenerateError::InferenceFailed { after_tokens, .. } => {
+				body.after_tokens = Some(after_tokens);
+				"inference_failed"
+			}
+			OriginalGenerateError::ContextFull { needed, available } => {
+				body.needed = Some(needed);
+				body.available = Some(available);
+				"context_full"
+			}
+			OriginalGenerateError::PromptTooLong { needed, allowed } => {
+				body.needed = Some(needed);
+				body.allowed = Some(allowed);
+				"prompt_too_long"
+			}
+			OriginalGenerateError::SessionState(_) => "session_state",
+			OriginalGenerateError::TokenizationError(_) => "tokenization_failed",
+			OriginalGenerateError::IllegalToken { segment, offset } => {
+				body.segment = Some(segment);
+				body.offset = Some(offset);
+				"illegal_token"
+			}
+			OriginalGenerateError::InvalidParameter(..) => "invalid_parameter",
+			OriginalGenerateError::TrustedSegmentNotAllowed => "trusted_segment_not_allowed",
+			OriginalGenerateError::InvalidBiaser(_) => "invalid_biaser",
+			OriginalGenerateError::InvalidVariables {
+				ref missing,
+				ref unknown,
+				ref too_long,
+			} => {
+				body.missing_variables = Some(missing.clone());
+				body.unknown_variables = Some(unknown.clone());
+				body.too_long_variables = Some(too_long.clone());
+				"invalid_variables"
+			}
+			OriginalGenerateError::InvalidPromptTemplate(_) => "invalid_prompt_template",
+			OriginalGenerateError::MemoryFailed { stage, .. } => {
+				body.stage = Some(stage);
+				"memory_failed"
+			}
+			OriginalGenerateError::MemoryNotFound(_) => "memory_not_found",
+			OriginalGenerateError::InvalidDocument => "invalid_document",
+			OriginalGenerateError::InvalidQuery(_) => "invalid_query",
+			OriginalGenerateError::InvalidChunkSeparator(_) => "invalid_chunk_separator",
+			OriginalGenerateError::InvalidEotToken(_) => "invalid_eot_token",
+			OriginalGenerateError::InvalidPrivateToken(_) => "invalid_private_token",
+			OriginalGenerateError::InvalidRerankToken(_) => "invalid_rerank_token",
+			OriginalGenerateError::BudgetExceeded { .. } => "budget_exceeded",
+			OriginalGenerateError::TokenBudgetExceeded { .. } => "token_budget_exceeded",
+			OriginalGenerateError::SummaryLimitExceeded(_) => "summary_limit_exceeded",
+			OriginalGenerateError::BiasedOutputInvalid { attempts, ref errors } => {
+				body.attempts = Some(attempts);
+				body.validation_errors = Some(errors.clone());
+				"biased_output_invalid"
+			}
+		};
+		body
+	}
 }
 
 impl IntoResponse for BackendError {
 	fn into_response(self) -> axum::response::Response {
-		(self.status_code(), format!("{}", self.0)).into_response()
+		(self.status_code(), Json(self.body())).into_response()
 	}
 }
 
@@ -59,3 +313,148 @@ impl From<OriginalGenerateError> for BackendError {
 		BackendError(t)
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use axum::http::StatusCode;
+	use poly_backend::{
+		memory::MemoryError,
+		types::{MemoryStage, PromptRequest, PromptSegment},
+	};
+	use serde_json::json;
+
+	use super::{BackendError, JwtClaims, OriginalGenerateError};
+
+	fn response(error: OriginalGenerateError) -> (StatusCode, serde_json::Value) {
+		let error = BackendError::from(error);
+		(error.status_code(), serde_json::to_value(error.body()).unwrap())
+	}
+
+	#[test]
+	fn test_error_response() {
+		let (status, body) = response(OriginalGenerateError::ContextFull { needed: 40, available: 12 });
+		assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+		assert_eq!(body["error"], "context_full");
+		assert_eq!(body["needed"], 40);
+		assert_eq!(body["available"], 12);
+		assert_eq!(body["retryable"], false);
+		assert!(body.get("segment").is_none());
+
+		let (status, body) = response(OriginalGenerateError::PromptTooLong { needed: 300, allowed: 256 });
+		assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+		assert_eq!(body["error"], "prompt_too_long");
+		assert_eq!(body["needed"], 300);
+		assert_eq!(body["allowed"], 256);
+		assert!(body.get("available").is_none());
+
+		let (status, body) = response(OriginalGenerateError::InferenceFailed {
+			after_tokens: 3,
+			source: "out of memory".into(),
+		});
+		assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+		assert_eq!(body["error"], "inference_failed");
+		assert_eq!(body["after_tokens"], 3);
+		assert_eq!(body["retryable"], true);
+
+		let (status, body) = response(OriginalGenerateError::IllegalToken { segment: 1, offset: 6 });
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+		assert_eq!(body["error"], "illegal_token");
+		assert_eq!(body["segment"], 1);
+		assert_eq!(body["offset"], 6);
+		assert!(body.get("token").is_none());
+
+		let (status, body) = response(OriginalGenerateError::ModelNotAvailable {
+			model: String::from("llama"),
+			reason: String::from("model file not found"),
+		});
+		assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+		assert_eq!(body["error"], "model_not_available");
+		assert_eq!(body["model"], "llama");
+		assert_eq!(body["reason"], "model file not found");
+		assert_eq!(body["retryable"], true);
+
+		let (status, body) = response(OriginalGenerateError::MemoryFailed {
+			stage: MemoryStage::Retrieve,
+			source: MemoryError::DimensionalityMismatch,
+		});
+		assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+		assert_eq!(
+			body,
+			json!({
+				"error": "memory_failed",
+				"message": "retrieving from memory failed: mismatch in dimensionality",
+				"retryable": true,
+				"stage": "retrieve",
+			})
+		);
+
+		let (status, body) = response(OriginalGenerateError::MemoryFailed {
+			stage: MemoryStage::Store,
+			source: MemoryError::Full { max_items: 100 },
+		});
+		assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+		assert_eq!(body["error"], "memory_failed");
+		assert_eq!(body["retryable"], false);
+
+		let (status, body) = response(OriginalGenerateError::BiasedOutputInvalid {
+			attempts: 3,
+			errors: vec![String::from("/age: -1 is less than the minimum of 0")],
+		});
+		assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+		assert_eq!(body["error"], "biased_output_invalid");
+		assert_eq!(body["attempts"], 3);
+		assert_eq!(body["validation_errors"], json!(["/age: -1 is less than the minimum of 0"]));
+
+		let (status, body) = response(OriginalGenerateError::InvalidVariables {
+			missing: vec![String::from("language")],
+			unknown: vec![],
+			too_long: vec![String::from("audience")],
+		});
+		assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+		assert_eq!(body["error"], "invalid_variables");
+		assert_eq!(body["missing_variables"], json!(["language"]));
+		assert_eq!(body["unknown_variables"], json!([]));
+		assert_eq!(body["too_long_variables"], json!(["audience"]));
+
+		let (status, body) = response(OriginalGenerateError::TrustedSegmentNotAllowed);
+		assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+		assert_eq!(body["error"], "trusted_segment_not_allowed");
+	}
+
+	#[test]
+	fn test_trusted_segments() {
+		let segment = |trusted| PromptSegment {
+			text: String::from("<|im_start|>system"),
+			trusted,
+			..Default::default()
+		};
+		let untrusted = PromptRequest {
+			segments: vec![segment(false)],
+			..Default::default()
+		};
+		let trusted = PromptRequest {
+			segments: vec![segment(false), segment(true)],
+			..Default::default()
+		};
+
+		// Only users holding the claim may mark segments as trusted
+		let claims = JwtClaims::default();
+		assert!(claims.check_prompt(&untrusted).is_ok());
+		assert!(matches!(
+			claims.check_prompt(&trusted),
+			Err(OriginalGenerateError::TrustedSegmentNotAllowed)
+		));
+
+		let claims = JwtClaims {
+			trusted_segments: true,
+			..Default::default()
+		};
+		assert!(claims.check_prompt(&trusted).is_ok());
+
+		// The claim is only present in tokens that explicitly hold it
+		let claims: JwtClaims = serde_json::from_value(json!({"sub": "user"})).unwrap();
+		assert!(!claims.trusted_segments);
+		let claims: JwtClaims = serde_json::from_value(json!({"sub": "user", "trusted_segments": true})).unwrap();
+		assert!(claims.trusted_segments);
+	}
+}
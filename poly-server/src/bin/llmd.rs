@@ -1,96 +1,322 @@
-use axum::extract::State;
-use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
-use axum::http::{HeaderValue, Method, StatusCode};
-
-use axum::response::IntoResponse;
-use axum::routing::get;
-use axum::{Json, Router};
 use clap::Parser;
-use poly_backend::backend::Backend;
-use poly_backend::types::{Status, StatusResponse};
-use poly_server::api::StatsResponse;
-use poly_server::config::{Args, Config};
-use poly_server::middleware::authenticate;
+use poly_backend::backend::{sha256_file, Backend};
+use poly_backend::config::{BackendConfig, BiaserConfig, ConfigProblem};
+use poly_backend::types::{FinishReason, PromptRequest, SessionRequest};
+use poly_server::config::{Args, Command, Config, RunArgs};
 use poly_server::routes;
-use poly_server::server::Server;
-
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::{fs::File, io::Read};
-use tower::limit::ConcurrencyLimitLayer;
-use tower_http::cors::{Any, CorsLayer};
-use tower_http::services::ServeDir;
-use tower_http::trace::TraceLayer;
+use poly_server::server::{serve, Server};
+use poly_server::{service, telemetry};
+
+use std::{io::Read, sync::Arc};
 use tracing::info;
-use tracing_subscriber::EnvFilter;
 
 pub use llm::InferenceFeedback;
 
 #[tokio::main]
 async fn main() {
-	tracing_subscriber::fmt()
-		.with_env_filter(EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info")).unwrap())
-		.init();
 	// Read config file
 	let args = Args::parse();
-	let mut config_file = File::open(args.config_path).expect("open config file");
-	let mut config_string = String::new();
-	config_file.read_to_string(&mut config_string).expect("read config file");
-	let config: Config = toml::from_str(&config_string).unwrap();
-	let bind_address: SocketAddr = config.bind_address.parse().unwrap();
-	info!("Starting llmd; bind address: {bind_address}",);
-
-	// Set up CORS
-	let mut cors_layer = CorsLayer::new();
-	if let Some(ref origins) = config.allowed_origins {
-		for origin in origins.iter() {
-			if origin == "*" {
-				cors_layer = cors_layer.allow_origin(Any);
-			} else {
-				cors_layer = cors_layer.allow_origin(origin.parse::<HeaderValue>().unwrap());
-			}
+	let config = match Config::from_file(&args.config_path) {
+		Ok(config) => config,
+		// Settings that cannot be parsed (such as invalid host patterns) are reported like the other problems `check` finds
+		Err(e) if matches!(args.command, Some(Command::Check { .. })) => {
+			std::process::exit(report_problems(vec![ConfigProblem::new("config_path", e.to_string())]))
 		}
-	} else {
-		// Allow any origin by default
-		cors_layer = cors_layer.allow_origin(Any);
+		Err(e) => {
+			eprintln!("{e}");
+			std::process::exit(1);
+		}
+	};
+	let quiet = matches!(args.command, Some(Command::Run(_)));
+	telemetry::init(config.telemetry.as_ref(), quiet);
+
+	match args.command {
+		Some(Command::Check { load_models, hash }) => std::process::exit(check(config, load_models, hash).await),
+		Some(Command::Run(run_args)) => std::process::exit(run(config, run_args).await),
+		Some(Command::Serve) | None => {}
 	}
-	cors_layer = cors_layer.allow_headers([CONTENT_TYPE, AUTHORIZATION]);
-	cors_layer = cors_layer.allow_methods([Method::GET, Method::POST, Method::OPTIONS, Method::PUT, Method::DELETE]);
 
-	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	info!("Starting llmd");
+	let _pid_file = match config.pid_file {
+		Some(ref path) => match service::PidFile::create(path) {
+			Ok(pid_file) => Some(pid_file),
+			Err(e) => {
+				tracing::error!("cannot write PID file {}: {e}", path.display());
+				std::process::exit(1);
+			}
+		},
+		None => None,
+	};
+
+	// The service manager is told how loading the models progresses
+	let (progress, mut fractions) = tokio::sync::mpsc::channel(32);
+	let mut model_names: Vec<String> = config.backend_config.models.keys().cloned().collect();
+	model_names.sort();
+	tokio::spawn(async move {
+		let mut last_status = None;
+		while let Some(fraction) = fractions.recv().await {
+			let status = service::loading_status(&model_names, fraction);
+			if last_status.as_ref() != Some(&status) {
+				service::status(&status);
+				last_status = Some(status);
+			}
+		}
+	});
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), Some(progress)).await);
+	let bind_addresses = config.bind_address.clone();
+	let grpc_bind_address = config.grpc_bind_address;
 	let state = Arc::new(Server::new(backend, config));
+	tokio::spawn(reload_on_hangup(state.clone()));
+
+	// Set up gRPC server
+	#[cfg(feature = "grpc")]
+	if let Some(address) = grpc_bind_address {
+		if let Err(e) = poly_server::grpc::serve(state.clone(), address).await {
+			tracing::error!("cannot listen on {address} for gRPC: {e}");
+			std::process::exit(1);
+		}
+	}
+
+	#[cfg(not(feature = "grpc"))]
+	if grpc_bind_address.is_some() {
+		tracing::warn!("grpc_bind_address is configured but llmd was built without the 'grpc' feature; gRPC will not be served");
+	}
 
 	// Set up API server
-	let app = Router::new()
-		.nest_service("/", ServeDir::new("client/dist/"))
-		.route("/status", get(status_handler))
-		.nest(
-			"/v1",
-			Router::new()
-				.nest("/model", routes::models::router())
-				.nest("/task", routes::tasks::router())
-				.nest("/memory", routes::memories::router())
-				.route("/stats", get(stats_handler))
-				.layer(axum::middleware::from_fn_with_state(state.clone(), authenticate)),
-		)
-		.fallback(handler_not_found)
-		.layer(cors_layer)
-		.layer(ConcurrencyLimitLayer::new(state.config.max_concurrent))
-		.layer(TraceLayer::new_for_http())
-		.with_state(state);
-
-	axum::Server::bind(&bind_address).serve(app.into_make_service()).await.unwrap();
+	let listening = match serve(routes::router(state.clone()), &bind_addresses).await {
+		Ok(listening) => listening,
+		Err(e) => {
+			tracing::error!("{e}");
+			std::process::exit(1);
+		}
+	};
+
+	// The server is ready when some models could not be loaded (like `/readyz` tells), as tasks using other models work
+	let unavailable_models = state.backend.unavailable_models().len();
+	if unavailable_models == 0 {
+		service::ready("serving");
+	} else {
+		service::ready(&format!("serving (degraded: {unavailable_models} model(s) unavailable)"));
+	}
+	if let Some(interval) = service::watchdog_interval() {
+		tokio::spawn(service::watchdog(state.backend.clone(), interval));
+	}
+
+	tokio::select! {
+		result = listening.wait() => {
+			if let Err(e) = result {
+				tracing::error!("{e}");
+			}
+		}
+		_ = shutdown_signal() => info!("shutting down"),
+	}
+	service::stopping();
+	telemetry::shutdown();
 }
 
-async fn stats_handler(State(state): State<Arc<Server>>) -> impl IntoResponse {
-	let task_stats = state.backend.stats.task_stats.lock().unwrap().clone();
-	Json(StatsResponse { tasks: task_stats })
+/// Wait until the process is asked to stop (SIGTERM or SIGINT)
+#[cfg(unix)]
+async fn shutdown_signal() {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	let mut terminate = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+	tokio::select! {
+		_ = terminate.recv() => {}
+		_ = tokio::signal::ctrl_c() => {}
+	}
 }
 
-async fn status_handler() -> impl IntoResponse {
-	Json(StatusResponse { status: Status::Ok })
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+	_ = tokio::signal::ctrl_c().await;
 }
 
-async fn handler_not_found() -> impl IntoResponse {
-	(StatusCode::NOT_FOUND, "not found")
+/// Validate the configuration and print any problems found. Returns the exit code for the process.
+async fn check(config: Config, load_models: bool, hash: bool) -> i32 {
+	let mut problems = config.check();
+
+	if hash {
+		problems.extend(print_hashes(config.backend_config.clone()).await);
+	}
+
+	if load_models && problems.is_empty() {
+		match tokio::spawn(Backend::from(config.backend_config.clone(), None)).await {
+			Ok(backend) => {
+				problems.extend(
+					backend
+						.unavailable_models()
+						.into_iter()
+						.map(|(model_name, reason)| ConfigProblem::new(format!("models.{model_name}"), reason)),
+				);
+				problems.extend(backend.check());
+			}
+			Err(e) => problems.push(ConfigProblem::new("models", format!("loading models failed: {e}"))),
+		}
+	}
+
+	report_problems(problems)
+}
+
+/// Print the problems found in the configuration. Returns the exit code for the process.
+fn report_problems(problems: Vec<ConfigProblem>) -> i32 {
+	if problems.is_empty() {
+		println!("configuration is valid");
+		0
+	} else {
+		eprintln!("{} problem(s) found in configuration:", problems.len());
+		for problem in problems {
+			eprintln!("- {problem}");
+		}
+		1
+	}
 }
+
+/// Calculate the checksums of the files of all models and print them in the format of the configuration file. Returns
+/// problems for files that cannot be read or that do not match their configured checksum.
+async fn print_hashes(mut backend_config: BackendConfig) -> Vec<ConfigProblem> {
+	backend_config.set_default_cache_path();
+	let mut model_names: Vec<&String> = backend_config.models.keys().collect();
+	model_names.sort();
+
+	let mut problems = vec![];
+	for model_name in model_names {
+		let key = format!("models.{model_name}");
+		let path = match backend_config.model_path(model_name) {
+			Ok(path) => path,
+			Err(e) => {
+				problems.push(ConfigProblem::new(&key, e));
+				continue;
+			}
+		};
+
+		let percentage = std::cell::Cell::new(0);
+		let progress = |fraction: f64| {
+			let current = (fraction * 100.0) as u32;
+			if current != percentage.replace(current) {
+				eprint!("\rhashing {}: {current}%", path.display());
+			}
+		};
+		let digest = sha256_file(&path, progress).await;
+		eprint!("\r\x1b[2K");
+		match digest {
+			Ok(digest) => {
+				println!("[models.{model_name}]\nsha256 = \"{digest}\"\n");
+				if let Some(ref expected) = backend_config.models[model_name].sha256 {
+					if !expected.eq_ignore_ascii_case(&digest) {
+						problems.push(ConfigProblem::new(
+							&key,
+							format!("checksum mismatch for {path:?}: expected {expected}, found {digest}"),
+						));
+					}
+				}
+			}
+			Err(e) => problems.push(ConfigProblem::new(&key, e)),
+		}
+	}
+	problems
+}
+
+/// Perform a single completion (see [`Command::Run`]). Returns the exit code for the process.
+async fn run(mut config: Config, args: RunArgs) -> i32 {
+	let prompt = match read_prompt(&args) {
+		Ok(prompt) => prompt,
+		Err(e) => {
+			eprintln!("cannot read prompt: {e}");
+			return 2;
+		}
+	};
+
+	// The seed and JSON schema cannot be set per request, so these are changed in the configuration of the task
+	let Some(task_config) = config.backend_config.tasks.get_mut(&args.task) else {
+		eprintln!("task '{}' not found", args.task);
+		return 2;
+	};
+	if args.seed.is_some() {
+		task_config.seed = args.seed;
+	}
+	if let Some(path) = args.json_schema {
+		match BiaserConfig::JsonSchemaFile(path).schema() {
+			Ok(schema) => task_config.biaser = schema.map(BiaserConfig::JsonSchema),
+			Err(e) => {
+				eprintln!("{e}");
+				return 2;
+			}
+		}
+	}
+
+	let request = PromptRequest {
+		prompt,
+		temperature: args.temperature,
+		top_p: args.top_p,
+		max_tokens: args.max_tokens,
+		max_chars: args.max_chars,
+		max_lines: args.max_lines,
+		..Default::default()
+	};
+
+	let backend = Arc::new(Backend::from(config.backend_config, None).await);
+	let stream = args.stream;
+	let result = tokio::task::spawn_blocking(move || {
+		backend.complete(&args.task, &SessionRequest::default(), &request, |token| {
+			if stream {
+				eprint!("{token}");
+			}
+			InferenceFeedback::Continue
+		})
+	})
+	.await
+	.unwrap();
+
+	match result {
+		Ok((text, completion)) => {
+			if stream {
+				eprintln!();
+			}
+			println!("{text}");
+			match completion.finish_reason {
+				FinishReason::Eot | FinishReason::StopSequence | FinishReason::BannedPhrase => 0,
+				FinishReason::MaxTokens => 3,
+				FinishReason::LengthLimit => 4,
+				FinishReason::ContextFull => 5,
+				FinishReason::Timeout => 6,
+				// Not expected, as generation is never halted
+				FinishReason::Cancelled => 1,
+			}
+		}
+		Err(e) => {
+			eprintln!("completion failed: {e}");
+			1
+		}
+	}
+}
+
+fn read_prompt(args: &RunArgs) -> std::io::Result<String> {
+	if let Some(ref prompt) = args.prompt {
+		return Ok(prompt.clone());
+	}
+	if let Some(ref path) = args.prompt_file {
+		return std::fs::read_to_string(path);
+	}
+	let mut prompt = String::new();
+	std::io::stdin().read_to_string(&mut prompt)?;
+	Ok(prompt)
+}
+
+/// Reload the configuration whenever the process receives SIGHUP
+#[cfg(unix)]
+async fn reload_on_hangup(state: Arc<Server>) {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	let mut hangups = signal(SignalKind::hangup()).expect("install SIGHUP handler");
+	while hangups.recv().await.is_some() {
+		info!("SIGHUP received, reloading configuration");
+		if let Err(problems) = state.reload().await {
+			for problem in problems {
+				tracing::error!("configuration not reloaded: {problem}");
+			}
+		}
+	}
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_state: Arc<Server>) {}
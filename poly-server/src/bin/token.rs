@@ -1,7 +1,8 @@
-use std::{fs::File, io::Read, path::PathBuf};
+use std::path::PathBuf;
 
 use clap::Parser;
 use jsonwebtoken::{get_current_timestamp, Header};
+use poly_backend::types::Priority;
 use poly_server::{api::JwtClaims, config::Config};
 use rand::Rng;
 
@@ -24,6 +25,14 @@ pub struct Args {
 	#[arg(long, short = 'n')]
 	pub memories: Option<Vec<String>>,
 
+	/// When supplied, the highest priority (low, normal or high) that this token can request
+	#[arg(long)]
+	pub max_priority: Option<Priority>,
+
+	/// Allow the token to mark prompt segments as trusted (so that they may contain private tokens)
+	#[arg(long)]
+	pub trusted_segments: bool,
+
 	/// User ID (`sub` claim) in token
 	#[arg(long, short = 's')]
 	pub sub: Option<String>,
@@ -33,10 +42,7 @@ pub fn main() {
 	tracing_subscriber::fmt::init();
 	// Read config file
 	let args = Args::parse();
-	let mut config_file = File::open(args.config_path).expect("open config file");
-	let mut config_string = String::new();
-	config_file.read_to_string(&mut config_string).expect("read config file");
-	let config: Config = toml::from_str(&config_string).unwrap();
+	let config = Config::from_file(&args.config_path).expect("read config file");
 
 	match config.jwt_private_key {
 		Some(jwk_key) => {
@@ -49,6 +55,8 @@ pub fn main() {
 					tasks: args.tasks,
 					models: args.models,
 					memories: args.memories,
+					max_priority: args.max_priority,
+					trusted_segments: args.trusted_segments,
 				},
 				&ek,
 			)
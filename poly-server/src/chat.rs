@@ -0,0 +1,277 @@
+use axum::extract::ws::{close_code, Message};
+pub use poly_backend::types::{ChatClientMessage, ChatServerMessage};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::{IntoParams, ToSchema};
+
+/// Encoding of the messages on a chat WebSocket, selected using the `format` (or `protocol`) query parameter. When no
+/// format is requested, a connection that starts with a binary frame uses MessagePack, a connection that starts with a
+/// JSON message uses JSON and any other connection uses text.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatFormat {
+	/// Prompts and tokens as plain text frames. An empty frame ends a response; errors close the connection.
+	#[default]
+	Text,
+
+	/// [`ChatClientMessage`] and [`ChatServerMessage`] as JSON in text frames
+	Json,
+
+	/// [`ChatClientMessage`] and [`ChatServerMessage`] as MessagePack in binary frames
+	Msgpack,
+}
+
+/// Query parameters of a chat WebSocket
+#[derive(Deserialize, Clone, Debug, Default, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct ChatRequest {
+	/// Format of the messages (`protocol=text` keeps clients of the plain text protocol working regardless of what they
+	/// send first)
+	#[serde(alias = "protocol")]
+	pub format: Option<ChatFormat>,
+
+	/// Token of an earlier chat to resume (from its `session` message). When the chat cannot be resumed, a new chat is
+	/// started and the `session` message holds a notice saying so.
+	pub resume: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum ChatProtocolError {
+	#[error("unexpected {0} frame")]
+	UnexpectedFrame(&'static str),
+
+	#[error("invalid JSON message: {0}")]
+	Json(#[from] serde_json::Error),
+
+	#[error("invalid MessagePack message: {0}")]
+	Msgpack(#[from] rmp_serde::decode::Error),
+}
+
+impl ChatProtocolError {
+	/// Code of the close frame with which the WebSocket is closed because of the error
+	pub fn close_code(&self) -> u16 {
+		match self {
+			ChatProtocolError::UnexpectedFrame(_) => close_code::UNSUPPORTED,
+			ChatProtocolError::Json(_) | ChatProtocolError::Msgpack(_) => close_code::INVALID,
+		}
+	}
+}
+
+impl ChatFormat {
+	/// The format to use for a connection for which no format was requested, given its first message
+	pub fn negotiate(first: &Message) -> ChatFormat {
+		match first {
+			Message::Binary(_) => ChatFormat::Msgpack,
+			Message::Text(text) if serde_json::from_str::<ChatClientMessage>(text).is_ok() => ChatFormat::Json,
+			_ => ChatFormat::Text,
+		}
+	}
+
+	/// Decode a text or binary frame received from a client
+	pub fn decode(&self, frame: Message) -> Result<ChatClientMessage, ChatProtocolError> {
+		match (self, frame) {
+			(ChatFormat::Text, Message::Text(text)) => Ok(ChatClientMessage::prompt(text)),
+			(ChatFormat::Text, _) => Err(ChatProtocolError::UnexpectedFrame("binary")),
+			(_, frame) => self.decode_structured(frame),
+		}
+	}
+
+	/// Encode a message for a client. Returns `None` for errors in the text format, which cannot express them (the
+	/// connection should be closed instead). Tool calls are sent as JSON in the text format.
+	pub fn encode(&self, message: &ChatServerMessage) -> Option<Message> {
+		match (self, message) {
+			(ChatFormat::Text, ChatServerMessage::Token { text }) => Some(Message::Text(text.clone())),
+			(ChatFormat::Text, ChatServerMessage::End { .. }) => Some(Message::Text(String::new())),
+			(ChatFormat::Text, ChatServerMessage::Error { .. }) => None,
+			(_, message) => Some(self.encode_structured(message)),
+		}
+	}
+
+	fn encode_structured<T: Serialize>(&self, message: &T) -> Message {
+		match self {
+			ChatFormat::Msgpack => Message::Binary(rmp_serde::to_vec_named(message).expect("chat messages can be encoded")),
+			ChatFormat::Text | ChatFormat::Json => Message::Text(serde_json::to_string(message).expect("chat messages can be encoded")),
+		}
+	}
+
+	fn decode_structured<T: DeserializeOwned>(&self, frame: Message) -> Result<T, ChatProtocolError> {
+		match (self, frame) {
+			(ChatFormat::Msgpack, Message::Binary(data)) => Ok(rmp_serde::from_slice(&data)?),
+			(ChatFormat::Msgpack, _) => Err(ChatProtocolError::UnexpectedFrame("text")),
+			(_, Message::Text(text)) => Ok(serde_json::from_str(&text)?),
+			(_, _) => Err(ChatProtocolError::UnexpectedFrame("binary")),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use axum::extract::ws::Message;
+
+	use poly_backend::{stats::TokenUsage, types::FinishReason};
+
+	use super::{ChatClientMessage, ChatFormat, ChatServerMessage};
+
+	/// Messages with their JSON encoding, shared by the JSON and MessagePack tests
+	fn server_fixtures() -> Vec<(ChatServerMessage, &'static str)> {
+		vec![
+			(
+				ChatServerMessage::Queued {
+					position: 2,
+					estimated_wait_ms: Some(1500),
+				},
+				r#"{"type":"queued","position":2,"estimated_wait_ms":1500}"#,
+			),
+			(
+				ChatServerMessage::Queued {
+					position: 1,
+					estimated_wait_ms: None,
+				},
+				r#"{"type":"queued","position":1}"#,
+			),
+			(
+				ChatServerMessage::Token {
+					text: String::from(" Hello"),
+				},
+				r#"{"type":"token","text":" Hello"}"#,
+			),
+			(
+				ChatServerMessage::ToolCall {
+					tool: String::from("get_weather"),
+					arguments: serde_json::json!({ "city": "Amsterdam" }),
+				},
+				r#"{"type":"tool_call","tool":"get_weather","arguments":{"city":"Amsterdam"}}"#,
+			),
+			(
+				ChatServerMessage::End {
+					usage: TokenUsage {
+						prompt_tokens: 4,
+						sampled_tokens: 2,
+						..Default::default()
+					},
+					finish_reason: Some(FinishReason::Cancelled),
+				},
+				r#"{"type":"end","usage":{"prompt_tokens":4,"sampled_tokens":2,"forced_tokens":0,"forced_duration":{"secs":0,"nanos":0}},"finish_reason":"cancelled"}"#,
+			),
+			(
+				ChatServerMessage::Error {
+					message: String::from("the context window of the session is full"),
+				},
+				r#"{"type":"error","message":"the context window of the session is full"}"#,
+			),
+			(
+				ChatServerMessage::Session {
+					token: String::from("0123abcd"),
+					resumed: false,
+					notice: Some(String::from("the chat could not be resumed")),
+					dropped_messages: None,
+				},
+				r#"{"type":"session","token":"0123abcd","resumed":false,"notice":"the chat could not be resumed"}"#,
+			),
+			(
+				ChatServerMessage::Session {
+					token: String::from("4567ef89"),
+					resumed: true,
+					notice: None,
+					dropped_messages: Some(3),
+				},
+				r#"{"type":"session","token":"4567ef89","resumed":true,"dropped_messages":3}"#,
+			),
+		]
+	}
+
+	fn client_fixtures() -> Vec<(ChatClientMessage, &'static str)> {
+		vec![
+			(
+				ChatClientMessage::prompt("Héllo, \"world\"\n"),
+				r#"{"type":"prompt","text":"Héllo, \"world\"\n"}"#,
+			),
+			(
+				ChatClientMessage::Prompt {
+					text: String::from("Hello"),
+					max_tokens: Some(16),
+					temperature: Some(0.5),
+					top_p: None,
+				},
+				r#"{"type":"prompt","text":"Hello","max_tokens":16,"temperature":0.5}"#,
+			),
+			(
+				ChatClientMessage::ToolResult {
+					tool: String::from("get_weather"),
+					result: String::from("12 degrees"),
+				},
+				r#"{"type":"tool_result","tool":"get_weather","result":"12 degrees"}"#,
+			),
+			(ChatClientMessage::Cancel, r#"{"type":"cancel"}"#),
+		]
+	}
+
+	#[test]
+	fn test_structured_formats() {
+		for (message, json) in server_fixtures() {
+			assert_eq!(ChatFormat::Json.encode(&message), Some(Message::Text(json.to_string())));
+
+			// MessagePack carries the same messages as JSON
+			let Some(frame @ Message::Binary(_)) = ChatFormat::Msgpack.encode(&message) else {
+				panic!("expected a binary frame");
+			};
+			let decoded: ChatServerMessage = ChatFormat::Msgpack.decode_structured(frame).unwrap();
+			let from_json: ChatServerMessage = ChatFormat::Json.decode_structured(Message::Text(json.to_string())).unwrap();
+			assert_eq!(decoded, from_json);
+			assert_eq!(decoded, message);
+		}
+
+		for (message, json) in client_fixtures() {
+			let from_json = ChatFormat::Json.decode(Message::Text(json.to_string())).unwrap();
+			let from_msgpack = ChatFormat::Msgpack
+				.decode(Message::Binary(rmp_serde::to_vec_named(&message).unwrap()))
+				.unwrap();
+			assert_eq!(from_json, message);
+			assert_eq!(from_msgpack, message);
+		}
+
+		// Messages of servers that do not report statistics can still be decoded
+		let end: ChatServerMessage = ChatFormat::Json
+			.decode_structured(Message::Text(String::from(r#"{"type":"end"}"#)))
+			.unwrap();
+		assert!(matches!(end, ChatServerMessage::End { finish_reason: None, .. }));
+
+		// Frames of the other kind are rejected
+		assert!(ChatFormat::Msgpack.decode(Message::Text(client_fixtures()[0].1.to_string())).is_err());
+		assert!(ChatFormat::Json.decode(Message::Binary(vec![0x80])).is_err());
+		assert!(ChatFormat::Msgpack.decode(Message::Binary(vec![0xc1])).is_err());
+	}
+
+	#[test]
+	fn test_text_format() {
+		let text = ChatFormat::Text;
+		assert_eq!(
+			text.decode(Message::Text(String::from("Hello"))).unwrap(),
+			ChatClientMessage::prompt("Hello")
+		);
+		assert!(text.decode(Message::Binary(vec![1, 2, 3])).is_err());
+
+		let (token, _) = server_fixtures().remove(2);
+		assert_eq!(text.encode(&token), Some(Message::Text(String::from(" Hello"))));
+		let end = ChatServerMessage::End {
+			usage: TokenUsage::default(),
+			finish_reason: Some(FinishReason::Cancelled),
+		};
+		assert_eq!(text.encode(&end), Some(Message::Text(String::new())));
+		assert_eq!(
+			text.encode(&ChatServerMessage::Error {
+				message: String::from("failed")
+			}),
+			None
+		);
+
+		assert_eq!(ChatFormat::negotiate(&Message::Binary(vec![])), ChatFormat::Msgpack);
+		assert_eq!(ChatFormat::negotiate(&Message::Text(String::new())), ChatFormat::Text);
+		assert_eq!(ChatFormat::negotiate(&Message::Text(String::from("Hello"))), ChatFormat::Text);
+		assert_eq!(
+			ChatFormat::negotiate(&Message::Text(String::from(r#"{"type":"cancel"}"#))),
+			ChatFormat::Json
+		);
+	}
+}
@@ -1,51 +1,411 @@
-use clap::Parser;
+use axum::http::HeaderValue;
+use clap::{ArgGroup, Parser, Subcommand};
 use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
 pub use llm::ModelArchitecture;
-use poly_backend::config::BackendConfig;
-use serde::Deserialize;
-use std::path::PathBuf;
+use poly_backend::config::{from_toml_file_with_secrets, BackendConfig, ConfigError, ConfigProblem, Secret, SECRET_KEYS};
+use poly_extract::fetch::{FetchOptions, HostRule};
+use serde::{Deserialize, Deserializer};
+use std::{
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	str::FromStr,
+	time::Duration,
+};
+use utoipa::ToSchema;
 
-#[derive(Deserialize, Clone, Debug)]
-#[serde(rename_all = "snake_case")]
+use crate::net::{HostPattern, ListenAddress, TrustedProxies};
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<ListenAddress>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum OneOrMany {
+		One(ListenAddress),
+		Many(Vec<ListenAddress>),
+	}
+
+	Ok(match OneOrMany::deserialize(deserializer)? {
+		OneOrMany::One(address) => vec![address],
+		OneOrMany::Many(addresses) => addresses,
+	})
+}
+
+/// Parses each entry of a list of strings, so that invalid entries are reported when the configuration is read
+fn parsed_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: FromStr<Err = String>,
+{
+	Vec::<String>::deserialize(deserializer)?
+		.iter()
+		.map(|entry| entry.parse().map_err(serde::de::Error::custom))
+		.collect()
+}
+
+fn optional_parsed_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: FromStr<Err = String>,
+{
+	parsed_list(deserializer).map(Some)
+}
+
+/// Accepts origin patterns, which must also be valid header values
+fn origin_patterns<'de, D>(deserializer: D) -> Result<Option<Vec<HostPattern>>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	Vec::<String>::deserialize(deserializer)?
+		.iter()
+		.map(|origin| {
+			origin
+				.parse::<HeaderValue>()
+				.ok()
+				.and_then(|_| origin.parse::<HostPattern>().ok())
+				.ok_or_else(|| serde::de::Error::custom(format!("invalid origin '{origin}'")))
+		})
+		.collect::<Result<_, _>>()
+		.map(Some)
+}
+
+fn trusted_proxies<'de, D>(deserializer: D) -> Result<TrustedProxies, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	TrustedProxies::parse(Vec::<String>::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Keys in the server configuration that hold secrets (in addition to those in [SECRET_KEYS]), which can be read from a
+/// file or environment variable (e.g. `jwt_private_key_file` or `allowed_keys_env`)
+const SERVER_SECRET_KEYS: &[&str] = &["jwt_private_key", "allowed_keys"];
+
+/// Accepts a list of keys, or a string containing keys separated by newlines or commas (as read from a file)
+fn secret_list<'de, D>(deserializer: D) -> Result<Vec<Secret>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum ListOrString {
+		List(Vec<Secret>),
+		String(String),
+	}
+
+	Ok(match ListOrString::deserialize(deserializer)? {
+		ListOrString::List(keys) => keys,
+		ListOrString::String(s) => s.split(['\n', ',']).map(str::trim).filter(|k| !k.is_empty()).map(Secret::new).collect(),
+	})
+}
+
+#[derive(Clone, Debug)]
 pub enum JwtPrivateKey {
-	Symmetric(String),
+	Symmetric(Secret),
+}
+
+impl<'de> Deserialize<'de> for JwtPrivateKey {
+	/// Accepts `{ symmetric = "..." }` as well as a plain string (a symmetric key), as read from a file
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		#[derive(Deserialize)]
+		#[serde(rename_all = "snake_case")]
+		enum Tagged {
+			Symmetric(Secret),
+		}
+
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum KeyOrTagged {
+			Key(Secret),
+			Tagged(Tagged),
+		}
+
+		Ok(match KeyOrTagged::deserialize(deserializer)? {
+			KeyOrTagged::Key(key) | KeyOrTagged::Tagged(Tagged::Symmetric(key)) => JwtPrivateKey::Symmetric(key),
+		})
+	}
 }
 
 #[derive(Deserialize, Clone, Debug)]
 #[serde(default)]
 pub struct Config {
-	/// Address and port to bind the server to ("0.0.0.0:1234")
-	pub bind_address: String,
+	/// Address(es) to listen on: either a single address or a list. TCP addresses are written as "0.0.0.0:1234" (use port
+	/// 0 to listen on any available port) and Unix domain sockets as "unix:/path/to/socket".
+	#[serde(deserialize_with = "one_or_many")]
+	pub bind_address: Vec<ListenAddress>,
+
+	/// TCP address to serve the gRPC API on (requires the `grpc` feature). When not set, the gRPC API is not served.
+	pub grpc_bind_address: Option<SocketAddr>,
 
 	#[serde(flatten)]
 	pub backend_config: BackendConfig,
 
-	/// CORS allowed origins
-	pub allowed_origins: Option<Vec<String>>,
+	/// CORS allowed origins. Origins may contain wildcards (e.g. "https://*.example.com"); "*" allows any origin.
+	#[serde(deserialize_with = "origin_patterns")]
+	pub allowed_origins: Option<Vec<HostPattern>>,
+
+	/// Host names that requests are accepted for (e.g. "api.example.com" or "*.example.com"). When not set, requests for
+	/// any host are accepted.
+	#[serde(deserialize_with = "optional_parsed_list")]
+	pub allowed_hosts: Option<Vec<HostPattern>>,
+
+	/// Proxies (IP addresses or CIDR ranges) that are trusted to report the client address using `Forwarded` or
+	/// `X-Forwarded-For` headers
+	#[serde(deserialize_with = "trusted_proxies")]
+	pub trusted_proxies: TrustedProxies,
 
 	/// The maximum number of concurrent requests serviced
 	pub max_concurrent: usize,
 
+	/// Requests that have been waiting to be serviced for this number of seconds are treated as if they had the next
+	/// higher priority (and so on), so that requests with a low priority are serviced eventually even when the server
+	/// stays busy
+	pub priority_aging_secs: u64,
+
+	/// A warning is logged when the number of open chat WebSockets or SSE streams exceeds this number
+	pub soft_connection_limit: Option<usize>,
+
+	/// Keep-alive messages sent on live (SSE) streams
+	pub live_keep_alive: KeepAliveConfig,
+
+	/// Keeping chats after their WebSocket closes, so that clients can resume them by reconnecting
+	pub chat_resume: ChatResumeConfig,
+
 	/// Whether access is allowed without keys
 	pub public: bool,
 
-	/// Allowed static API keys
-	pub allowed_keys: Vec<String>,
+	/// Limits the rate of requests of clients that did not authenticate (on a public server, or using public tasks), per
+	/// IP address (per /64 network for IPv6). Requests that authenticated are not limited. Not limited when not set.
+	pub ip_rate_limit: Option<RateLimitConfig>,
 
-	/// Key for JWT signed keys
+	/// Allowed static API keys. Can also be read from a file (`allowed_keys_file`, one key per line) or an environment
+	/// variable (`allowed_keys_env`, keys separated by commas).
+	#[serde(deserialize_with = "secret_list")]
+	pub allowed_keys: Vec<Secret>,
+
+	/// Key for JWT signed keys. Can also be read from a file (`jwt_private_key_file`) or an environment variable
+	/// (`jwt_private_key_env`), in which case it is a symmetric key.
 	pub jwt_private_key: Option<JwtPrivateKey>,
+
+	/// Export of tracing spans using OpenTelemetry (requires the `otel` feature)
+	pub telemetry: Option<TelemetryConfig>,
+
+	/// Fetching of documents by URL to store them in a memory (disabled when not set)
+	pub fetch: Option<FetchConfig>,
+
+	/// File to write the process ID to once the server starts (removed when it stops), for supervisors other than
+	/// systemd
+	pub pid_file: Option<PathBuf>,
+
+	/// The file this configuration was read from (set by [`Config::from_file`])
+	#[serde(skip)]
+	pub path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct KeepAliveConfig {
+	/// Interval between keep-alive messages in milliseconds
+	pub interval_ms: u64,
+
+	/// Smallest interval clients may request (using the `keep_alive_ms` query parameter)
+	pub min_interval_ms: u64,
+
+	/// Largest interval clients may request
+	pub max_interval_ms: u64,
+
+	/// What is sent to keep the stream alive (clients may request otherwise using the `keep_alive` query parameter)
+	pub message: KeepAliveMessage,
+
+	/// Text of keep-alive comments
+	pub comment: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum KeepAliveMessage {
+	/// A comment line (`: keep-alive-text`), which clients should ignore
+	Comment,
+
+	/// An event without data (`event: keep-alive`), for clients that do not accept comments
+	Event,
+}
+
+impl Default for KeepAliveConfig {
+	fn default() -> Self {
+		Self {
+			interval_ms: 1000,
+			min_interval_ms: 1000,
+			max_interval_ms: 60_000,
+			message: KeepAliveMessage::Comment,
+			comment: String::from("keep-alive-text"),
+		}
+	}
+}
+
+impl KeepAliveConfig {
+	/// Apply the keep-alive interval and message requested by a client. Fails when the interval is out of bounds.
+	pub fn with_overrides(&self, interval_ms: Option<u64>, message: Option<KeepAliveMessage>) -> Result<KeepAliveConfig, String> {
+		let mut config = self.clone();
+		if let Some(interval_ms) = interval_ms {
+			if !(self.min_interval_ms..=self.max_interval_ms).contains(&interval_ms) {
+				return Err(format!("must be between {} and {}", self.min_interval_ms, self.max_interval_ms));
+			}
+			config.interval_ms = interval_ms;
+		}
+		config.message = message.unwrap_or(self.message);
+		Ok(config)
+	}
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ChatResumeConfig {
+	/// Number of seconds a chat (and its session) is kept after its WebSocket closed (0 to never keep chats)
+	pub grace_secs: u64,
+
+	/// Maximum number of messages generated while the client is away that are kept to be sent when it resumes the chat.
+	/// When more are generated, the oldest are dropped.
+	pub max_replay_messages: usize,
+
+	/// Maximum number of chats kept for a single user. When a user has this many, their oldest chat is ended.
+	pub max_per_user: usize,
+
+	/// Maximum number of chats kept in total. When this many are kept, chats are not kept when their WebSocket closes.
+	pub max_total: usize,
+}
+
+impl Default for ChatResumeConfig {
+	fn default() -> Self {
+		Self {
+			grace_secs: 60,
+			max_replay_messages: 1024,
+			max_per_user: 2,
+			max_total: 32,
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+	/// Number of requests a client can make in quick succession
+	pub burst: u32,
+
+	/// Rate at which a client can make requests after the burst, in requests per second (may be a fraction)
+	pub requests_per_second: f64,
+
+	/// Number of requests a client can make while being limited after which it is banned (0 to never ban)
+	pub ban_after: u32,
+
+	/// Duration of a ban in seconds, during which all requests of the client are rejected
+	pub ban_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+	fn default() -> Self {
+		Self {
+			burst: 20,
+			requests_per_second: 1.0,
+			ban_after: 20,
+			ban_secs: 300,
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TelemetryConfig {
+	/// OTLP (gRPC) endpoint to export spans to, e.g. "http://localhost:4317"
+	pub endpoint: String,
+
+	/// Service name to report
+	#[serde(default = "default_service_name")]
+	pub service_name: String,
+
+	/// Ratio of traces to sample (0.0-1.0). Traces that are started by a caller that sent a `traceparent` header follow
+	/// the sampling decision of the caller.
+	#[serde(default = "default_sample_ratio")]
+	pub sample_ratio: f64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct FetchConfig {
+	/// Time after which fetching a document (including redirects) is aborted
+	#[serde(default = "default_fetch_timeout_ms")]
+	pub timeout_ms: u64,
+
+	/// Maximum size of a fetched document in bytes
+	#[serde(default = "default_fetch_max_size")]
+	pub max_size: usize,
+
+	/// Maximum number of redirects that are followed
+	#[serde(default = "default_fetch_max_redirects")]
+	pub max_redirects: usize,
+
+	/// When set, only these hosts are fetched from. Entries are host names ("example.com"), subdomains of a domain
+	/// ("*.example.com"), IP addresses or CIDR ranges. Private and link-local addresses are never fetched from.
+	#[serde(default, deserialize_with = "parsed_list")]
+	pub allowed_hosts: Vec<HostRule>,
+
+	/// Hosts that are never fetched from, written in the same way as `allowed_hosts`
+	#[serde(default, deserialize_with = "parsed_list")]
+	pub denied_hosts: Vec<HostRule>,
+}
+
+impl FetchConfig {
+	pub fn options(&self) -> FetchOptions {
+		FetchOptions {
+			timeout: Duration::from_millis(self.timeout_ms),
+			max_size: self.max_size,
+			max_redirects: self.max_redirects,
+			allowed_hosts: self.allowed_hosts.clone(),
+			denied_hosts: self.denied_hosts.clone(),
+		}
+	}
+}
+
+const fn default_fetch_timeout_ms() -> u64 {
+	30_000
+}
+
+const fn default_fetch_max_size() -> usize {
+	10 * 1024 * 1024
+}
+
+const fn default_fetch_max_redirects() -> usize {
+	5
+}
+
+fn default_service_name() -> String {
+	String::from("llmd")
+}
+
+const fn default_sample_ratio() -> f64 {
+	1.0
 }
 
 impl Default for Config {
 	fn default() -> Self {
 		Self {
-			bind_address: String::from("0.0.0.0:3000"),
+			bind_address: vec![ListenAddress::Tcp(SocketAddr::from(([0, 0, 0, 0], 3000)))],
+			grpc_bind_address: None,
 			backend_config: BackendConfig::default(),
 			allowed_origins: None,
+			allowed_hosts: None,
+			trusted_proxies: TrustedProxies::default(),
 			max_concurrent: 8,
+			priority_aging_secs: 30,
+			soft_connection_limit: None,
+			live_keep_alive: KeepAliveConfig::default(),
+			chat_resume: ChatResumeConfig::default(),
 			allowed_keys: vec![],
 			public: false,
+			ip_rate_limit: None,
 			jwt_private_key: None,
+			telemetry: None,
+			fetch: None,
+			pid_file: None,
+			path: None,
 		}
 	}
 }
@@ -56,6 +416,157 @@ pub struct Args {
 	/// Where to load the config file from
 	#[arg(long, short = 'm', default_value = "config.toml")]
 	pub config_path: PathBuf,
+
+	#[command(subcommand)]
+	pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+	/// Start the server (default)
+	Serve,
+
+	/// Validate the configuration file and exit
+	Check {
+		/// Also load all models to verify that they load and that configured tokens are valid
+		#[arg(long)]
+		load_models: bool,
+
+		/// Calculate the SHA-256 checksums of the model files and print them in the format of the configuration file.
+		/// Files that do not match their configured checksum are reported as problems.
+		#[arg(long)]
+		hash: bool,
+	},
+
+	/// Perform a single completion for a task without starting a server, write the generated text to standard output and
+	/// exit. The exit code tells why the completion ended: 0 for the end of the text or a stop sequence, 3 when the
+	/// maximum number of tokens was generated, 4 for the character or line limit and 5 when the context window is full
+	/// (1 when the completion failed and 2 for invalid usage).
+	Run(RunArgs),
+}
+
+#[derive(clap::Args, Debug)]
+#[command(group(ArgGroup::new("input").required(true).args(["prompt", "prompt_file", "stdin"])))]
+pub struct RunArgs {
+	/// The task to perform
+	#[arg(long)]
+	pub task: String,
+
+	/// The prompt
+	#[arg(long)]
+	pub prompt: Option<String>,
+
+	/// Read the prompt from a file
+	#[arg(long)]
+	pub prompt_file: Option<PathBuf>,
+
+	/// Read the prompt from standard input
+	#[arg(long)]
+	pub stdin: bool,
+
+	/// Write tokens to standard error as they are generated
+	#[arg(long)]
+	pub stream: bool,
+
+	/// Maximum number of tokens to generate instead of the number configured for the task
+	#[arg(long)]
+	pub max_tokens: Option<usize>,
+
+	/// Maximum number of characters to generate instead of the number configured for the task
+	#[arg(long)]
+	pub max_chars: Option<usize>,
+
+	/// Maximum number of lines to generate instead of the number configured for the task
+	#[arg(long)]
+	pub max_lines: Option<usize>,
+
+	/// Temperature to sample with instead of the one configured for the task
+	#[arg(long)]
+	pub temperature: Option<f32>,
+
+	/// Cumulative probability to sample from instead of the one configured for the task
+	#[arg(long)]
+	pub top_p: Option<f32>,
+
+	/// Seed for sampling, for reproducible output
+	#[arg(long)]
+	pub seed: Option<u64>,
+
+	/// File containing a JSON schema the output must follow (instead of the biaser configured for the task)
+	#[arg(long)]
+	pub json_schema: Option<PathBuf>,
+}
+
+impl Config {
+	/// Read the configuration from a TOML file. Included files are merged and environment variables referenced in the
+	/// files are interpolated (see [from_toml_file_with_secrets]). Secrets are read from the files or environment
+	/// variables they refer to.
+	pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+		let secret_keys = [SERVER_SECRET_KEYS, SECRET_KEYS].concat();
+		let (mut config, sources): (Config, _) = from_toml_file_with_secrets(path, &secret_keys)?;
+		config.backend_config.sources = sources;
+		config.path = Some(path.to_path_buf());
+		Ok(config)
+	}
+
+	/// Check the configuration for problems that can be found without loading any models. Returns all problems found.
+	pub fn check(&self) -> Vec<ConfigProblem> {
+		let mut problems = vec![];
+
+		if self.bind_address.is_empty() {
+			problems.push(ConfigProblem::new("bind_address", "at least one address must be specified"));
+		}
+
+		if self.allowed_keys.iter().any(|k| k.is_empty()) {
+			problems.push(ConfigProblem::new("allowed_keys", "keys cannot be empty"));
+		}
+
+		if let Some(JwtPrivateKey::Symmetric(key)) = &self.jwt_private_key {
+			if key.is_empty() {
+				problems.push(ConfigProblem::new("jwt_private_key", "key cannot be empty"));
+			}
+		}
+
+		if self.max_concurrent == 0 {
+			problems.push(ConfigProblem::new("max_concurrent", "must be larger than zero"));
+		}
+
+		if self.priority_aging_secs == 0 {
+			problems.push(ConfigProblem::new("priority_aging_secs", "must be larger than zero"));
+		}
+
+		if let Some(rate_limit) = &self.ip_rate_limit {
+			if rate_limit.burst == 0 || rate_limit.requests_per_second.is_nan() || rate_limit.requests_per_second <= 0.0 {
+				problems.push(ConfigProblem::new(
+					"ip_rate_limit",
+					"burst and requests_per_second must be larger than zero",
+				));
+			}
+		}
+
+		let keep_alive = &self.live_keep_alive;
+		if keep_alive.min_interval_ms == 0 || keep_alive.min_interval_ms > keep_alive.max_interval_ms {
+			problems.push(ConfigProblem::new(
+				"live_keep_alive",
+				"min_interval_ms must be larger than zero and at most max_interval_ms",
+			));
+		} else if !(keep_alive.min_interval_ms..=keep_alive.max_interval_ms).contains(&keep_alive.interval_ms) {
+			problems.push(ConfigProblem::new(
+				"live_keep_alive.interval_ms",
+				"must be between min_interval_ms and max_interval_ms",
+			));
+		}
+
+		if let Some(fetch) = &self.fetch {
+			if fetch.timeout_ms == 0 || fetch.max_size == 0 {
+				problems.push(ConfigProblem::new("fetch", "timeout_ms and max_size must be larger than zero"));
+			}
+		}
+
+		problems.extend(self.backend_config.check());
+		self.backend_config.sources.annotate(&mut problems);
+		problems
+	}
 }
 
 impl JwtPrivateKey {
@@ -67,13 +578,79 @@ impl JwtPrivateKey {
 
 	pub fn decoding_key(&self) -> DecodingKey {
 		match self {
-			JwtPrivateKey::Symmetric(s) => DecodingKey::from_secret(s.as_bytes()),
+			JwtPrivateKey::Symmetric(s) => DecodingKey::from_secret(s.expose().as_bytes()),
 		}
 	}
 
 	pub fn encoding_key(&self) -> EncodingKey {
 		match self {
-			JwtPrivateKey::Symmetric(s) => EncodingKey::from_secret(s.as_bytes()),
+			JwtPrivateKey::Symmetric(s) => EncodingKey::from_secret(s.expose().as_bytes()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{Config, KeepAliveConfig, KeepAliveMessage, RateLimitConfig};
+
+	#[test]
+	fn test_keep_alive() {
+		let config = KeepAliveConfig::default();
+		assert_eq!(config.with_overrides(None, None).unwrap(), config);
+
+		let overridden = config.with_overrides(Some(15_000), Some(KeepAliveMessage::Event)).unwrap();
+		assert_eq!(overridden.interval_ms, 15_000);
+		assert_eq!(overridden.message, KeepAliveMessage::Event);
+		assert!(config.with_overrides(Some(10), None).is_err());
+		assert!(config.with_overrides(Some(60_001), None).is_err());
+
+		let mut config = Config::default();
+		assert!(config.check().is_empty());
+		config.live_keep_alive.interval_ms = 500;
+		let problems = config.check();
+		assert_eq!(problems.len(), 1);
+		assert_eq!(problems[0].key, "live_keep_alive.interval_ms");
+	}
+
+	#[test]
+	fn test_ip_rate_limit() {
+		let config: Config = serde_json::from_value(serde_json::json!({ "ip_rate_limit": { "burst": 5, "requests_per_second": 0.5 } })).unwrap();
+		let rate_limit = config.ip_rate_limit.clone().unwrap();
+		assert_eq!((rate_limit.burst, rate_limit.requests_per_second), (5, 0.5));
+		assert_eq!(rate_limit.ban_after, RateLimitConfig::default().ban_after);
+		assert!(config.check().is_empty());
+
+		let config: Config = serde_json::from_value(serde_json::json!({ "ip_rate_limit": { "requests_per_second": 0 } })).unwrap();
+		let problems = config.check();
+		assert_eq!(problems.len(), 1);
+		assert_eq!(problems[0].key, "ip_rate_limit");
+	}
+
+	#[test]
+	fn test_parse_network_settings() {
+		let config: Config = serde_json::from_value(serde_json::json!({
+			"allowed_origins": ["https://*.example.com"],
+			"allowed_hosts": ["api.example.com"],
+			"trusted_proxies": ["10.0.0.0/8", "::1"],
+			"fetch": { "allowed_hosts": ["*.wikipedia.org"], "denied_hosts": ["203.0.113.0/24"] }
+		}))
+		.unwrap();
+		assert!(config.allowed_origins.unwrap()[0].matches("https://www.example.com"));
+		assert!(config.allowed_hosts.unwrap()[0].matches("api.example.com"));
+		assert!(config.trusted_proxies.is_trusted(&"10.1.2.3".parse().unwrap()));
+		let fetch = config.fetch.unwrap().options();
+		assert_eq!(fetch.allowed_hosts.len(), 1);
+		assert_eq!(fetch.denied_hosts.len(), 1);
+
+		// Invalid entries are rejected when the configuration is read
+		for (key, value) in [
+			("allowed_origins", serde_json::json!(["https://\nexample.com"])),
+			("allowed_hosts", serde_json::json!(["**.example.com"])),
+			("trusted_proxies", serde_json::json!(["10.0.0.0/33"])),
+			("fetch", serde_json::json!({ "denied_hosts": ["exa mple.com"] })),
+		] {
+			let result = serde_json::from_value::<Config>(serde_json::json!({ key: value }));
+			assert!(result.is_err(), "{key} should be rejected");
 		}
 	}
 }
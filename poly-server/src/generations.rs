@@ -0,0 +1,128 @@
+//! Completions that are being generated, by the identifier of the generation, so that clients can cancel them (see
+//! `DELETE /v1/task/{task}/completion/{generation_id}`). A generation is registered for as long as the [`Generation`]
+//! returned by [`Generations::start`] lives.
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+use poly_backend::session::Cancellation;
+
+/// Maximum length of a generation identifier chosen by a client
+const MAX_ID_LENGTH: usize = 64;
+
+struct Running {
+	task: String,
+	cancellation: Cancellation,
+}
+
+#[derive(Default)]
+pub struct Generations {
+	running: Mutex<HashMap<String, Running>>,
+}
+
+/// A registered generation, which is unregistered when dropped
+pub struct Generation {
+	id: String,
+	cancellation: Cancellation,
+	generations: Arc<Generations>,
+}
+
+impl Generation {
+	pub fn id(&self) -> &str {
+		&self.id
+	}
+
+	/// Cancelled when a client asks to cancel the generation
+	pub fn cancellation(&self) -> &Cancellation {
+		&self.cancellation
+	}
+}
+
+impl Drop for Generation {
+	fn drop(&mut self) {
+		self.generations.running.lock().unwrap().remove(&self.id);
+	}
+}
+
+/// A new random generation identifier
+pub fn random_id() -> String {
+	format!("{:032x}", rand::random::<u128>())
+}
+
+/// Whether a client may use `id` as identifier of a generation: between 1 and 64 ASCII letters, digits, dashes and
+/// underscores (so that it can be used in a path and a header as it is)
+pub fn is_valid_id(id: &str) -> bool {
+	!id.is_empty() && id.len() <= MAX_ID_LENGTH && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+impl Generations {
+	pub fn new() -> Arc<Generations> {
+		Arc::new(Generations::default())
+	}
+
+	/// Register a generation for a task. Returns `None` when a generation with the same identifier is already running.
+	pub fn start(self: &Arc<Self>, task: &str, id: String) -> Option<Generation> {
+		let mut running = self.running.lock().unwrap();
+		if running.contains_key(&id) {
+			return None;
+		}
+		let cancellation = Cancellation::new();
+		running.insert(
+			id.clone(),
+			Running {
+				task: task.to_string(),
+				cancellation: cancellation.clone(),
+			},
+		);
+		Some(Generation {
+			id,
+			cancellation,
+			generations: self.clone(),
+		})
+	}
+
+	/// Cancel a running generation for a task. Returns false when no such generation is running.
+	pub fn cancel(&self, task: &str, id: &str) -> bool {
+		match self.running.lock().unwrap().get(id) {
+			Some(running) if running.task == task => {
+				running.cancellation.cancel();
+				true
+			}
+			_ => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_cancel_generation() {
+		let generations = Generations::new();
+		let generation = generations.start("task", String::from("abc")).unwrap();
+		assert!(generations.start("task", String::from("abc")).is_none());
+
+		// Generations can only be cancelled through the task they belong to
+		assert!(!generations.cancel("other", "abc"));
+		assert!(!generation.cancellation().is_cancelled());
+		assert!(generations.cancel("task", "abc"));
+		assert!(generation.cancellation().is_cancelled());
+
+		// A finished generation is no longer registered
+		drop(generation);
+		assert!(!generations.cancel("task", "abc"));
+		assert!(generations.start("task", String::from("abc")).is_some());
+	}
+
+	#[test]
+	fn test_valid_id() {
+		assert!(is_valid_id(&random_id()));
+		assert!(is_valid_id("my-generation_1"));
+		assert!(!is_valid_id(""));
+		assert!(!is_valid_id("a/b"));
+		assert!(!is_valid_id(&"a".repeat(MAX_ID_LENGTH + 1)));
+	}
+}
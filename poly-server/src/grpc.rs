@@ -0,0 +1,330 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use llm::{InferenceFeedback, InferenceResponse};
+use poly_backend::{
+	memory::{ItemMetadata, MemoryError, MemoryQuery},
+	session::Completion,
+	types::{BackendError as OriginalBackendError, FinishReason, PromptRequest, PromptSegment, SessionRequest},
+};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{metadata::MetadataValue, Code, Request, Response, Status};
+use tracing::debug;
+
+use crate::{
+	api::{BackendError, JwtClaims},
+	middleware::claims_for_token,
+	server::{IngestItem, Server},
+};
+
+/// Types generated from `proto/poly.proto`
+pub mod proto {
+	tonic::include_proto!("poly.v1");
+}
+
+use proto::{
+	completion_response::Event,
+	poly_server::{Poly, PolyServer},
+	CompletionRequest, CompletionResponse, EmbeddingRequest, EmbeddingResponse, RecallRequest, RecallResponse, RememberRequest, RememberResponse,
+	TokenizeRequest, TokenizeResponse,
+};
+
+/// Serve the gRPC API on a TCP address. Returns as soon as the server is listening, with the address listened on (which
+/// holds the assigned port when port 0 was specified).
+pub async fn serve(state: Arc<Server>, address: SocketAddr) -> std::io::Result<SocketAddr> {
+	let listener = TcpListener::bind(address).await?;
+	let address = listener.local_addr()?;
+
+	let auth_state = state.clone();
+	let service = PolyServer::with_interceptor(PolyService { state }, move |request| authenticate(&auth_state, request));
+	tokio::spawn(async move {
+		let server = tonic::transport::Server::builder()
+			.add_service(service)
+			.serve_with_incoming(TcpListenerStream::new(listener));
+		if let Err(e) = server.await {
+			tracing::error!("gRPC server error: {e}");
+		}
+	});
+
+	tracing::info!("listening for gRPC on {address}");
+	Ok(address)
+}
+
+/// Authenticates a call using the bearer token in its `authorization` metadata, like [`crate::middleware::authenticate`]
+/// does for HTTP requests
+fn authenticate(state: &Server, mut request: Request<()>) -> Result<Request<()>, Status> {
+	let auth_token = match request.metadata().get("authorization") {
+		Some(value) => Some(
+			value
+				.to_str()
+				.ok()
+				.and_then(|v| v.strip_prefix("Bearer "))
+				.ok_or_else(|| Status::unauthenticated("invalid bearer token"))?
+				.to_string(),
+		),
+		None => None,
+	};
+
+	let (claims, method) = claims_for_token(state, auth_token).map_err(Status::unauthenticated)?;
+	request.extensions_mut().insert(claims);
+	request.extensions_mut().insert(method);
+	Ok(request)
+}
+
+/// Check whether the claims of the user allow the request
+fn authorize<T>(request: &Request<T>, allowed: impl FnOnce(&JwtClaims, &T) -> bool) -> Result<(), Status> {
+	let claims = request
+		.extensions()
+		.get::<JwtClaims>()
+		.ok_or_else(|| Status::unauthenticated("not authenticated"))?;
+	if !allowed(claims, request.get_ref()) {
+		return Err(Status::permission_denied("not allowed"));
+	}
+	Ok(())
+}
+
+/// Convert an error of the backend to a gRPC status. The kind of error (e.g. `context_full`, as reported by the HTTP
+/// API) is set in the `poly-error` metadata entry.
+fn status(error: OriginalBackendError) -> Status {
+	let code = match error {
+		OriginalBackendError::TaskNotFound(_) | OriginalBackendError::ModelNotFound(_) | OriginalBackendError::MemoryNotFound(_) => Code::NotFound,
+		OriginalBackendError::ModelNotAvailable { .. } => Code::Unavailable,
+		OriginalBackendError::BudgetExceeded { .. } => Code::DeadlineExceeded,
+		OriginalBackendError::ContextFull { .. }
+		| OriginalBackendError::SummaryLimitExceeded(_)
+		| OriginalBackendError::MemoryFailed {
+			source: MemoryError::Full { .. },
+			..
+		} => Code::ResourceExhausted,
+		OriginalBackendError::IllegalToken { .. }
+		| OriginalBackendError::InvalidDocument
+		| OriginalBackendError::InvalidParameter(..)
+		| OriginalBackendError::PromptTooLong { .. }
+		| OriginalBackendError::InvalidQuery(_)
+		| OriginalBackendError::InvalidVariables { .. } => Code::InvalidArgument,
+		OriginalBackendError::TrustedSegmentNotAllowed => Code::PermissionDenied,
+		OriginalBackendError::InferenceFailed { .. }
+		| OriginalBackendError::BiasedOutputInvalid { .. }
+		| OriginalBackendError::TokenBudgetExceeded { .. }
+		| OriginalBackendError::TokenizationError(_)
+		| OriginalBackendError::MemoryFailed { .. }
+		| OriginalBackendError::InvalidChunkSeparator(_)
+		| OriginalBackendError::InvalidEotToken(_)
+		| OriginalBackendError::InvalidPrivateToken(_)
+		| OriginalBackendError::InvalidRerankToken(_)
+		| OriginalBackendError::SessionState(_)
+		| OriginalBackendError::InvalidBiaser(_)
+		| OriginalBackendError::InvalidPromptTemplate(_) => Code::Internal,
+	};
+
+	let body = BackendError::from(error).body();
+	let mut status = Status::new(code, body.message);
+	status.metadata_mut().insert("poly-error", MetadataValue::from_static(body.error));
+	status
+}
+
+fn prompt_request(request: CompletionRequest) -> PromptRequest {
+	PromptRequest {
+		prompt: request.prompt,
+		segments: request
+			.segments
+			.into_iter()
+			.map(|s| PromptSegment {
+				text: s.text,
+				trusted: s.trusted,
+				redact_in_logs: s.redact_in_logs,
+				memorize: s.memorize,
+			})
+			.collect(),
+		temperature: request.temperature,
+		top_p: request.top_p,
+		max_tokens: request.max_tokens.map(|n| n as usize),
+		max_chars: request.max_chars.map(|n| n as usize),
+		max_lines: request.max_lines.map(|n| n as usize),
+		stop: Vec::new(),
+		messages: Vec::new(),
+		tool_result: None,
+		truncate: request.truncate,
+		variables: request.variables,
+	}
+}
+
+fn usage(completion: &Completion) -> proto::Usage {
+	let usage = completion.usage;
+	proto::Usage {
+		prompt_tokens: usage.prompt_tokens as u64,
+		sampled_tokens: usage.sampled_tokens as u64,
+		forced_tokens: usage.forced_tokens as u64,
+		forced_duration_us: usage.forced_duration.as_micros() as u64,
+		finish_reason: proto::FinishReason::from(completion.finish_reason) as i32,
+		force_closed: completion.force_closed,
+	}
+}
+
+impl From<FinishReason> for proto::FinishReason {
+	fn from(reason: FinishReason) -> Self {
+		match reason {
+			FinishReason::Eot => proto::FinishReason::Eot,
+			FinishReason::StopSequence => proto::FinishReason::StopSequence,
+			FinishReason::MaxTokens => proto::FinishReason::MaxTokens,
+			FinishReason::LengthLimit => proto::FinishReason::LengthLimit,
+			FinishReason::ContextFull => proto::FinishReason::ContextFull,
+			FinishReason::Cancelled => proto::FinishReason::Cancelled,
+			FinishReason::BannedPhrase => proto::FinishReason::BannedPhrase,
+			FinishReason::Timeout => proto::FinishReason::Timeout,
+		}
+	}
+}
+
+/// Implementation of the gRPC API, backed by the same [`poly_backend::backend::Backend`] as the HTTP API
+pub struct PolyService {
+	state: Arc<Server>,
+}
+
+#[tonic::async_trait]
+impl Poly for PolyService {
+	type CompletionStream = ReceiverStream<Result<CompletionResponse, Status>>;
+
+	async fn completion(&self, request: Request<CompletionRequest>) -> Result<Response<Self::CompletionStream>, Status> {
+		authorize(&request, |claims, r| claims.allows_task(&r.task))?;
+		let claims = request.extensions().get::<JwtClaims>().cloned().unwrap_or_default();
+		let request = request.into_inner();
+		let task_name = request.task.clone();
+		debug!("New gRPC completion for task '{task_name}'");
+		let prompt = prompt_request(request);
+		claims.check_prompt(&prompt).map_err(status)?;
+
+		let state = self.state.clone();
+		let mut session = state
+			.backend
+			.start(&task_name, &SessionRequest::default(), state.backend.clone())
+			.map_err(status)?;
+
+		let (tx, rx) = tokio::sync::mpsc::channel(32);
+		let span = tracing::Span::current();
+		state.backend.pool.spawn(move || {
+			let _entered = span.enter();
+			let result = session.complete(&prompt, |r| -> Result<_, OriginalBackendError> {
+				match r {
+					InferenceResponse::InferredToken(t) => {
+						// The stream is dropped when the client cancels the call (or disconnects)
+						if tx
+							.blocking_send(Ok(CompletionResponse {
+								event: Some(Event::Token(t)),
+							}))
+							.is_err()
+						{
+							debug!("client has cancelled gRPC completion, halting generation");
+							return Ok(InferenceFeedback::Halt);
+						}
+						Ok(InferenceFeedback::Continue)
+					}
+					_ => Ok(InferenceFeedback::Continue),
+				}
+			});
+
+			let last = match result {
+				Ok(completion) => Ok(CompletionResponse {
+					event: Some(Event::Usage(usage(&completion))),
+				}),
+				Err(e) => Err(status(e)),
+			};
+
+			// This fails when the client has cancelled the call, but we don't care (anymore)
+			_ = tx.blocking_send(last);
+		});
+
+		Ok(Response::new(ReceiverStream::new(rx)))
+	}
+
+	async fn embedding(&self, request: Request<EmbeddingRequest>) -> Result<Response<EmbeddingResponse>, Status> {
+		authorize(&request, |claims, r| claims.allows_model(&r.model))?;
+		let request = request.into_inner();
+		let backend = self.state.backend.clone();
+		let response = self
+			.state
+			.backend
+			.pool
+			.run(move || backend.embedding(&request.model, &PromptRequest::new(request.prompt)))
+			.await
+			.map_err(status)?;
+		Ok(Response::new(EmbeddingResponse {
+			embedding: response.embedding,
+		}))
+	}
+
+	async fn tokenize(&self, request: Request<TokenizeRequest>) -> Result<Response<TokenizeResponse>, Status> {
+		authorize(&request, |claims, r| claims.allows_model(&r.model))?;
+		let request = request.into_inner();
+		let response = self
+			.state
+			.backend
+			.tokenize(&request.model, &PromptRequest::new(request.prompt))
+			.map_err(status)?;
+		Ok(Response::new(TokenizeResponse {
+			tokens: response
+				.tokens
+				.into_iter()
+				.map(|t| proto::Token {
+					text: t.text,
+					token: t.token,
+				})
+				.collect(),
+		}))
+	}
+
+	async fn recall(&self, request: Request<RecallRequest>) -> Result<Response<RecallResponse>, Status> {
+		authorize(&request, |claims, r| claims.allows_memory(&r.memory))?;
+		let request = request.into_inner();
+		let chunks = self
+			.state
+			.backend
+			.recall(&request.memory, MemoryQuery::Prompt(&request.prompt), request.n.map_or(1, |n| n as usize))
+			.await
+			.map_err(status)?;
+		Ok(Response::new(RecallResponse { chunks }))
+	}
+
+	async fn remember(&self, request: Request<RememberRequest>) -> Result<Response<RememberResponse>, Status> {
+		authorize(&request, |claims, r| claims.allows_memory(&r.memory))?;
+		let request = request.into_inner();
+		if request.wait {
+			self.state.backend.memorize(&request.memory, &request.text).await.map_err(status)?;
+		} else {
+			// Defer to a background job
+			self.state
+				.ingest(IngestItem {
+					memory_name: request.memory,
+					sections: vec![request.text],
+					metadata: ItemMetadata::default(),
+				})
+				.await;
+		}
+		Ok(Response::new(RememberResponse {}))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use poly_backend::types::BackendError as OriginalBackendError;
+	use tonic::Code;
+
+	use super::status;
+
+	#[test]
+	fn test_status() {
+		let error = status(OriginalBackendError::ContextFull { needed: 40, available: 12 });
+		assert_eq!(error.code(), Code::ResourceExhausted);
+		assert_eq!(error.metadata().get("poly-error").unwrap(), "context_full");
+
+		let error = status(OriginalBackendError::TaskNotFound(String::from("chat")));
+		assert_eq!(error.code(), Code::NotFound);
+		assert_eq!(error.metadata().get("poly-error").unwrap(), "task_not_found");
+
+		let error = status(OriginalBackendError::InvalidParameter(
+			String::from("max_lines"),
+			String::from("must be positive"),
+		));
+		assert_eq!(error.code(), Code::InvalidArgument);
+	}
+}
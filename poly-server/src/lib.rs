@@ -1,5 +1,17 @@
+pub mod admission;
 pub mod api;
+pub mod chat;
 pub mod config;
+pub mod generations;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod middleware;
+pub mod net;
+pub mod openai;
+pub mod openapi;
+pub mod ratelimit;
+pub mod resume;
 pub mod routes;
 pub mod server;
+pub mod service;
+pub mod telemetry;
@@ -1,18 +1,136 @@
-use std::sync::Arc;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use axum::{
-	extract::{Query, State},
-	http::{header::AUTHORIZATION, Request, StatusCode},
+	extract::{ConnectInfo, MatchedPath, Path, Query, State},
+	http::{
+		header::{AUTHORIZATION, HOST, RETRY_AFTER},
+		HeaderValue, Request, StatusCode,
+	},
 	middleware::Next,
-	response::IntoResponse,
+	response::{IntoResponse, Response},
+	Extension,
 };
 use jsonwebtoken::Validation;
+use poly_backend::types::Priority;
 
 use crate::{
-	api::{JwtClaims, KeyQuery},
+	api::{AuthMethod, JwtClaims, KeyQuery, PriorityRequest},
+	net::{host_without_port, ClientIp},
 	server::Server,
 };
 
+/// Middleware that determines the IP address of the client (taking trusted proxies into account) and makes it available
+/// to handlers as [ClientIp] extension. The address is also recorded in the request span.
+pub async fn resolve_client_ip<T>(State(state): State<Arc<Server>>, mut req: Request<T>, next: Next<T>) -> impl IntoResponse {
+	if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>().cloned() {
+		let client_ip = state.trusted_proxies.resolve_client_ip(peer.ip(), req.headers());
+		tracing::Span::current().record("client_ip", tracing::field::display(client_ip));
+		req.extensions_mut().insert(ClientIp(client_ip));
+	}
+	next.run(req).await
+}
+
+/// Middleware that rejects requests for hosts other than the allowed hosts (when configured)
+pub async fn check_host<T>(
+	State(state): State<Arc<Server>>,
+	req: Request<T>,
+	next: Next<T>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+	if let Some(ref allowed_hosts) = state.allowed_hosts {
+		let host = req
+			.headers()
+			.get(HOST)
+			.and_then(|h| h.to_str().ok())
+			.or_else(|| req.uri().host())
+			.map(host_without_port);
+
+		if !host.is_some_and(|host| allowed_hosts.iter().any(|pattern| pattern.matches(host))) {
+			tracing::debug!(?host, "rejecting request for host that is not allowed");
+			return Err((StatusCode::MISDIRECTED_REQUEST, "host not allowed"));
+		}
+	}
+
+	Ok(next.run(req).await)
+}
+
+/// Middleware that limits the rate of requests of clients that did not authenticate (see [crate::ratelimit]), which are
+/// rejected with status 429 and a `Retry-After` header. Must run after [authenticate] (when it is used), so that
+/// requests that authenticated are not limited.
+pub async fn limit_rate<T>(State(state): State<Arc<Server>>, method: Option<Extension<AuthMethod>>, req: Request<T>, next: Next<T>) -> Response {
+	let Some(ref limiter) = state.ip_rate_limiter else {
+		return next.run(req).await;
+	};
+	if matches!(method, Some(Extension(AuthMethod::Key | AuthMethod::Jwt))) {
+		return next.run(req).await;
+	}
+	let Some(ClientIp(client_ip)) = req.extensions().get::<ClientIp>().copied() else {
+		// Clients connecting over a Unix domain socket have no address to limit by
+		return next.run(req).await;
+	};
+
+	match limiter.check(client_ip) {
+		Ok(()) => next.run(req).await,
+		Err(retry_after) => {
+			tracing::debug!(%client_ip, "rejecting request that exceeds the rate limit");
+			let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+			(
+				StatusCode::TOO_MANY_REQUESTS,
+				[(RETRY_AFTER, retry_after.to_string())],
+				"too many requests",
+			)
+				.into_response()
+		}
+	}
+}
+
+/// Routes whose handlers admit requests themselves (see [admit]), so that they can tell the client its position in line
+/// while it waits
+const SELF_ADMITTING_ROUTES: [&str; 2] = ["/v1/task/:task/chat", "/v1/task/:task/live"];
+
+/// Routes that are serviced without being admitted, as they do not perform inference and should not wait behind the
+/// requests they concern
+const UNADMITTED_ROUTES: [&str; 1] = ["/v1/task/:task/completion/:generation_id"];
+
+/// Middleware that limits the number of requests serviced at the same time (see [crate::admission]). Waiting requests
+/// are admitted by priority (see [request_priority]). A request that had to wait is answered with `X-Queue-Position`
+/// (its position when it started waiting) and `X-Queue-Wait-Ms` headers.
+pub async fn admit<T>(
+	State(state): State<Arc<Server>>,
+	Query(priority): Query<PriorityRequest>,
+	path: Option<Path<HashMap<String, String>>>,
+	Extension(claims): Extension<JwtClaims>,
+	req: Request<T>,
+	next: Next<T>,
+) -> Response {
+	if req
+		.extensions()
+		.get::<MatchedPath>()
+		.is_some_and(|path| SELF_ADMITTING_ROUTES.contains(&path.as_str()) || UNADMITTED_ROUTES.contains(&path.as_str()))
+	{
+		return next.run(req).await;
+	}
+
+	let task_name = path.as_ref().and_then(|Path(params)| params.get("task"));
+	let priority = request_priority(&state, &claims, task_name.map(String::as_str), priority.priority);
+	let permit = state.admission.admit(priority).await;
+	let mut response = next.run(req).await;
+	if let Some(queued) = permit.queued {
+		let headers = response.headers_mut();
+		headers.insert("x-queue-position", HeaderValue::from(queued.position));
+		headers.insert("x-queue-wait-ms", HeaderValue::from(queued.duration.as_millis() as u64));
+	}
+	response
+}
+
+/// The priority of a request for a task (or for something other than a task, when `task_name` is `None`), given the
+/// priority it asked for (if any). Requests get the priority of their task unless the claims allow otherwise.
+pub fn request_priority(state: &Server, claims: &JwtClaims, task_name: Option<&str>, requested: Option<Priority>) -> Priority {
+	let default = task_name
+		.and_then(|name| state.backend.task(name))
+		.map_or(Priority::default(), |task| task.priority);
+	claims.priority(requested, default)
+}
+
 /// Middleware that authenticates a user using static pre-shared API keys or a JWT
 pub async fn authenticate<T>(
 	State(state): State<Arc<Server>>,
@@ -41,16 +159,37 @@ pub async fn authenticate<T>(
 		None
 	};
 
-	let claims: JwtClaims = match auth_token {
+	let (claims, method) = claims_for_token(&state, auth_token).map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
+
+	req.extensions_mut().insert(claims);
+	req.extensions_mut().insert(method);
+
+	Ok(next.run(req).await)
+}
+
+/// Subject of the claims of users that did not authenticate on a server that is not public
+pub const ANONYMOUS_SUB: &str = "anonymous";
+
+/// Determine the claims of a user from the static API key or JWT presented (if any). Used for both the HTTP and gRPC
+/// APIs. Users that present neither on a server that is not public can only use the public tasks (if there are any).
+pub fn claims_for_token(state: &Server, auth_token: Option<String>) -> Result<(JwtClaims, AuthMethod), &'static str> {
+	let config = &state.config;
+	match auth_token {
 		Some(auth_token) => {
 			// Check if key is allowed
-			if state.config.allowed_keys.contains(&auth_token) {
-				// OK
-				JwtClaims {
-					sub: Some(auth_token),
-					..Default::default()
-				}
-			} else if let Some(jwt_key) = &state.config.jwt_private_key {
+			if let Some(index) = config.allowed_keys.iter().position(|k| k.matches(&auth_token)) {
+				// OK; identify the user by the index of the key so that the key itself does not end up in logs. Static keys
+				// are trusted to choose any priority and to mark prompt segments as trusted.
+				Ok((
+					JwtClaims {
+						sub: Some(format!("key{index}")),
+						max_priority: Some(Priority::High),
+						trusted_segments: true,
+						..Default::default()
+					},
+					AuthMethod::Key,
+				))
+			} else if let Some(jwt_key) = &config.jwt_private_key {
 				// Attempt to decode and validate JWT token
 				let mut validation = Validation::new(jwt_key.algorithm());
 				validation.validate_nbf = true;
@@ -58,28 +197,37 @@ pub async fn authenticate<T>(
 				match jsonwebtoken::decode::<JwtClaims>(&auth_token, &jwt_key.decoding_key(), &validation) {
 					Ok(valid_token) => {
 						tracing::debug!(sub = valid_token.claims.sub, "valid JWT token");
-						valid_token.claims
+						Ok((valid_token.claims, AuthMethod::Jwt))
 					}
 					Err(e) => {
 						tracing::debug!("error validating JWT token: {e}");
-						return Err((StatusCode::UNAUTHORIZED, "invalid JWT token"));
+						Err("invalid JWT token")
 					}
 				}
 			} else {
-				return Err((StatusCode::UNAUTHORIZED, "no acceptable auth token provided"));
+				Err("no acceptable auth token provided")
 			}
 		}
 		None => {
-			if !state.config.public {
-				return Err((StatusCode::UNAUTHORIZED, "no auth token provided and not a public server"));
+			if !config.public {
+				let public_tasks = state.backend.public_task_names();
+				if public_tasks.is_empty() {
+					return Err("no auth token provided and not a public server");
+				}
+				return Ok((
+					JwtClaims {
+						sub: Some(String::from(ANONYMOUS_SUB)),
+						tasks: Some(public_tasks),
+						models: Some(vec![]),
+						memories: Some(vec![]),
+						..Default::default()
+					},
+					AuthMethod::Anonymous,
+				));
 			}
 
 			// Unauthenticated but access granted
-			JwtClaims::default()
+			Ok((JwtClaims::default(), AuthMethod::Public))
 		}
-	};
-
-	req.extensions_mut().insert(claims);
-
-	Ok(next.run(req).await)
+	}
 }
@@ -0,0 +1,305 @@
+use std::{
+	fmt::Display,
+	net::{IpAddr, SocketAddr},
+	path::PathBuf,
+	str::FromStr,
+};
+
+use axum::http::{header::FORWARDED, HeaderMap};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Prefix for listen addresses that refer to a Unix domain socket
+const UNIX_PREFIX: &str = "unix:";
+
+/// An address the server listens on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+	/// TCP socket address, e.g. "0.0.0.0:3000" (port 0 means any available port)
+	Tcp(SocketAddr),
+
+	/// Path to a Unix domain socket, written as "unix:/path/to/socket"
+	Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.strip_prefix(UNIX_PREFIX) {
+			Some("") => Err(format!("invalid address '{s}': socket path is missing")),
+			Some(path) => Ok(ListenAddress::Unix(PathBuf::from(path))),
+			None => s.parse().map(ListenAddress::Tcp).map_err(|e| format!("invalid address '{s}': {e}")),
+		}
+	}
+}
+
+impl Display for ListenAddress {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ListenAddress::Tcp(addr) => write!(f, "{addr}"),
+			ListenAddress::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+		}
+	}
+}
+
+impl<'de> Deserialize<'de> for ListenAddress {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		s.parse().map_err(serde::de::Error::custom)
+	}
+}
+
+/// The IP address of the client that made a request. When the request was forwarded by one of the trusted proxies, this
+/// is the address reported by the proxy (see [`TrustedProxies::resolve_client_ip`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Set of proxies (IP addresses or CIDR ranges) that are trusted to report the address of the client they forward
+/// requests for.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies {
+	networks: Vec<IpNet>,
+}
+
+impl FromStr for TrustedProxies {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		TrustedProxies::parse(s.split(',').map(str::trim).filter(|s| !s.is_empty()))
+	}
+}
+
+impl TrustedProxies {
+	/// Parse a list of IP addresses (e.g. "10.0.0.1") and CIDR ranges (e.g. "10.0.0.0/8")
+	pub fn parse<S: AsRef<str>>(entries: impl IntoIterator<Item = S>) -> Result<TrustedProxies, String> {
+		let networks = entries
+			.into_iter()
+			.map(|entry| {
+				let entry = entry.as_ref();
+				entry
+					.parse::<IpNet>()
+					.or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+					.map_err(|_| format!("invalid IP address or CIDR range '{entry}'"))
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+		Ok(TrustedProxies { networks })
+	}
+
+	pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+		self.networks.iter().any(|n| n.contains(ip))
+	}
+
+	/// Determine the address of the client, given the address of the peer that connected to us and the request headers.
+	/// Forwarding headers are only taken into account when the peer is a trusted proxy. The chain of addresses reported
+	/// in the headers is then followed from the most recent hop backwards, for as long as hops are trusted proxies. The
+	/// first address that is not a trusted proxy is the client. When an entry in the chain cannot be parsed (e.g. it is
+	/// obfuscated), the last trusted hop is used.
+	pub fn resolve_client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+		if !self.is_trusted(&peer) {
+			return peer;
+		}
+
+		let mut client = peer;
+		for hop in forwarded_chain(headers).iter().rev() {
+			let Some(hop) = hop else {
+				break;
+			};
+			client = *hop;
+			if !self.is_trusted(hop) {
+				break;
+			}
+		}
+		client
+	}
+}
+
+/// Returns the addresses reported in the `Forwarded` headers (or, when there are none, the `X-Forwarded-For` headers),
+/// from the original client to the most recent proxy. Entries that cannot be parsed are `None`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+	let forwarded: Vec<Option<IpAddr>> = headers
+		.get_all(FORWARDED)
+		.iter()
+		.flat_map(|value| value.to_str().ok().unwrap_or_default().split(','))
+		.filter_map(|element| {
+			element.split(';').find_map(|pair| {
+				let (key, value) = pair.trim().split_once('=')?;
+				key.eq_ignore_ascii_case("for").then(|| parse_node(value))
+			})
+		})
+		.collect();
+
+	if !forwarded.is_empty() {
+		return forwarded;
+	}
+
+	headers
+		.get_all(X_FORWARDED_FOR)
+		.iter()
+		.flat_map(|value| value.to_str().ok().unwrap_or_default().split(','))
+		.map(str::trim)
+		.filter(|s| !s.is_empty())
+		.map(parse_node)
+		.collect()
+}
+
+/// Parse a node as found in forwarding headers, which may be quoted and may include a port (e.g. `"[2001:db8::1]:4711"`)
+fn parse_node(node: &str) -> Option<IpAddr> {
+	let node = node.trim().trim_matches('"');
+	if let Ok(ip) = node.parse::<IpAddr>() {
+		return Some(ip);
+	}
+	if let Ok(addr) = node.parse::<SocketAddr>() {
+		return Some(addr.ip());
+	}
+	node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+/// A pattern for host names or origins, in which `*` matches any non-empty sequence of characters other than `:` and
+/// `/` (e.g. "*.example.com" or "https://*.example.com"). Matching is case-insensitive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostPattern(String);
+
+impl FromStr for HostPattern {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.is_empty() || s.contains("**") {
+			return Err(format!("invalid pattern '{s}'"));
+		}
+		Ok(HostPattern(s.to_ascii_lowercase()))
+	}
+}
+
+impl HostPattern {
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	pub fn matches(&self, value: &str) -> bool {
+		wildcard_match(self.0.as_bytes(), value.to_ascii_lowercase().as_bytes())
+	}
+}
+
+fn wildcard_match(pattern: &[u8], value: &[u8]) -> bool {
+	match pattern.split_first() {
+		None => value.is_empty(),
+		Some((b'*', rest)) => {
+			// The wildcard must match at least one character
+			(1..=value.len())
+				.take_while(|n| !matches!(value[n - 1], b':' | b'/'))
+				.any(|n| wildcard_match(rest, &value[n..]))
+		}
+		Some((c, rest)) => value.split_first().is_some_and(|(v, value)| v == c && wildcard_match(rest, value)),
+	}
+}
+
+/// Returns the host name from a `Host` header value, without port
+pub fn host_without_port(host: &str) -> &str {
+	if let Some(rest) = host.strip_prefix('[') {
+		// IPv6 literal
+		return rest.split(']').next().unwrap_or(rest);
+	}
+	host.rsplit_once(':')
+		.filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+		.map(|(host, _)| host)
+		.unwrap_or(host)
+}
+
+#[cfg(test)]
+mod test {
+	use std::net::IpAddr;
+
+	use axum::http::{HeaderMap, HeaderValue};
+
+	use super::{host_without_port, HostPattern, ListenAddress, TrustedProxies};
+
+	fn headers(entries: &[(&'static str, &'static str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for (name, value) in entries {
+			headers.append(*name, HeaderValue::from_static(value));
+		}
+		headers
+	}
+
+	fn ip(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn test_parse_trusted_proxies() {
+		let proxies: TrustedProxies = "10.0.0.0/8, 192.168.1.1, ::1".parse().unwrap();
+		assert!(proxies.is_trusted(&ip("10.1.2.3")));
+		assert!(proxies.is_trusted(&ip("192.168.1.1")));
+		assert!(!proxies.is_trusted(&ip("192.168.1.2")));
+		assert!(proxies.is_trusted(&ip("::1")));
+		assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+		assert!("proxy.local".parse::<TrustedProxies>().is_err());
+	}
+
+	#[test]
+	fn test_resolve_client_ip() {
+		let proxies: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+
+		// Headers from untrusted peers are ignored
+		let spoofed = headers(&[("x-forwarded-for", "1.2.3.4"), ("forwarded", "for=5.6.7.8")]);
+		assert_eq!(proxies.resolve_client_ip(ip("8.8.8.8"), &spoofed), ip("8.8.8.8"));
+
+		// A trusted proxy reports the client
+		let forwarded = headers(&[("x-forwarded-for", "1.2.3.4")]);
+		assert_eq!(proxies.resolve_client_ip(ip("10.0.0.1"), &forwarded), ip("1.2.3.4"));
+
+		// Entries that the client prepended itself are not trusted
+		let chain = headers(&[("x-forwarded-for", "6.6.6.6, 1.2.3.4"), ("x-forwarded-for", "10.0.0.2")]);
+		assert_eq!(proxies.resolve_client_ip(ip("10.0.0.1"), &chain), ip("1.2.3.4"));
+
+		// Forwarded takes precedence over X-Forwarded-For and may contain ports and quoted IPv6 addresses
+		let forwarded = headers(&[
+			("x-forwarded-for", "6.6.6.6"),
+			("forwarded", "for=\"[2001:db8:cafe::17]:4711\";proto=https, for=10.0.0.2:1234"),
+		]);
+		assert_eq!(proxies.resolve_client_ip(ip("10.0.0.1"), &forwarded), ip("2001:db8:cafe::17"));
+
+		// Obfuscated entries stop the chain at the last trusted hop
+		let obfuscated = headers(&[("forwarded", "for=1.2.3.4, for=_hidden, for=10.0.0.2")]);
+		assert_eq!(proxies.resolve_client_ip(ip("10.0.0.1"), &obfuscated), ip("10.0.0.2"));
+
+		// Without headers, the peer is the client
+		assert_eq!(proxies.resolve_client_ip(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+	}
+
+	#[test]
+	fn test_listen_address() {
+		assert_eq!(
+			"127.0.0.1:0".parse::<ListenAddress>().unwrap(),
+			ListenAddress::Tcp("127.0.0.1:0".parse().unwrap())
+		);
+		assert_eq!(
+			"unix:/run/llmd.sock".parse::<ListenAddress>().unwrap(),
+			ListenAddress::Unix("/run/llmd.sock".into())
+		);
+		assert_eq!("[::1]:3000".parse::<ListenAddress>().unwrap().to_string(), "[::1]:3000");
+		assert!("localhost".parse::<ListenAddress>().is_err());
+		assert!("unix:".parse::<ListenAddress>().is_err());
+	}
+
+	#[test]
+	fn test_host_pattern() {
+		let pattern: HostPattern = "*.Example.com".parse().unwrap();
+		assert!(pattern.matches("api.example.com"));
+		assert!(pattern.matches("a.b.EXAMPLE.com"));
+		assert!(!pattern.matches("example.com"));
+		assert!(!pattern.matches("evil.com/.example.com"));
+
+		let origin: HostPattern = "https://*.example.com".parse().unwrap();
+		assert!(origin.matches("https://app.example.com"));
+		assert!(!origin.matches("http://app.example.com"));
+		assert!(!origin.matches("https://evil.com:.example.com"));
+
+		assert_eq!(host_without_port("example.com:3000"), "example.com");
+		assert_eq!(host_without_port("[::1]:3000"), "::1");
+		assert_eq!(host_without_port("example.com"), "example.com");
+	}
+}
@@ -0,0 +1,457 @@
+//! Request and response types of the OpenAI-compatible API (see [`crate::routes::openai`]), which lets tools that only
+//! speak the OpenAI REST API use the tasks of the server. The `model` of a completion request names a task; that of an
+//! embedding request names a model.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{response::IntoResponse, Json};
+use poly_backend::{
+	stats::TokenUsage,
+	types::{FinishReason, PromptEmbedding, PromptRequest},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::api::BackendError;
+
+/// Role of the author of a message in a chat completion request
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+	System,
+	User,
+	Assistant,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Message {
+	pub role: Role,
+	pub content: String,
+}
+
+/// One or more strings (e.g. stop sequences or inputs to embed)
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Strings {
+	One(String),
+	Many(Vec<String>),
+}
+
+impl Strings {
+	fn into_vec(self) -> Vec<String> {
+		match self {
+			Strings::One(string) => vec![string],
+			Strings::Many(strings) => strings,
+		}
+	}
+}
+
+/// Parameters shared by chat completion and completion requests. Other parameters of the OpenAI API are ignored.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Parameters {
+	pub max_tokens: Option<usize>,
+	pub temperature: Option<f32>,
+	pub top_p: Option<f32>,
+	pub stop: Option<Strings>,
+
+	/// Whether to respond with a stream of server-sent events holding chunks of the response
+	#[serde(default)]
+	pub stream: bool,
+}
+
+impl Parameters {
+	/// A request for a completion of a prompt using these parameters
+	fn prompt_request(&self, prompt: String) -> PromptRequest {
+		PromptRequest {
+			max_tokens: self.max_tokens,
+			temperature: self.temperature,
+			top_p: self.top_p,
+			stop: self.stop.clone().map(Strings::into_vec).unwrap_or_default(),
+			..PromptRequest::new(prompt)
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChatCompletionRequest {
+	/// Name of the task
+	pub model: String,
+	pub messages: Vec<Message>,
+
+	#[serde(flatten)]
+	pub parameters: Parameters,
+}
+
+/// A conversation to continue: earlier exchanges (each a prompt and the response to it) and the prompt to respond to
+#[derive(Debug, PartialEq)]
+pub struct Conversation {
+	pub history: Vec<(PromptRequest, String)>,
+	pub prompt: PromptRequest,
+}
+
+impl ChatCompletionRequest {
+	/// The conversation held by the messages. The task applies its prefix and postfix to each user turn, so that earlier
+	/// turns are fed like they would have been in a chat. System messages and consecutive user messages are joined with
+	/// the user message that follows them. The last message should be from the user.
+	pub fn conversation(&self) -> Result<Conversation, BackendError> {
+		let invalid = |message: &str| {
+			BackendError::from(poly_backend::types::BackendError::InvalidParameter(
+				String::from("messages"),
+				message.to_string(),
+			))
+		};
+		let mut history = vec![];
+		let mut pending: Vec<&str> = vec![];
+		for message in &self.messages {
+			match message.role {
+				Role::System | Role::User => pending.push(&message.content),
+				Role::Assistant if pending.is_empty() => return Err(invalid("an assistant message should follow a user message")),
+				Role::Assistant => {
+					history.push((PromptRequest::new(pending.join("\n\n")), message.content.clone()));
+					pending.clear();
+				}
+			}
+		}
+		if pending.is_empty() {
+			return Err(invalid("the last message should be from the user"));
+		}
+		Ok(Conversation {
+			history,
+			prompt: self.parameters.prompt_request(pending.join("\n\n")),
+		})
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CompletionRequest {
+	/// Name of the task
+	pub model: String,
+	pub prompt: String,
+
+	#[serde(flatten)]
+	pub parameters: Parameters,
+}
+
+impl CompletionRequest {
+	pub fn prompt_request(&self) -> PromptRequest {
+		self.parameters.prompt_request(self.prompt.clone())
+	}
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Usage {
+	pub prompt_tokens: usize,
+	pub completion_tokens: usize,
+	pub total_tokens: usize,
+}
+
+impl From<TokenUsage> for Usage {
+	fn from(usage: TokenUsage) -> Usage {
+		let completion_tokens = usage.sampled_tokens + usage.forced_tokens;
+		Usage {
+			prompt_tokens: usage.prompt_tokens,
+			completion_tokens,
+			total_tokens: usage.prompt_tokens + completion_tokens,
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct EmbeddingRequest {
+	/// Name of the model
+	pub model: String,
+	pub input: Strings,
+}
+
+impl EmbeddingRequest {
+	/// The prompts to embed, of which there should be at least one
+	pub fn prompts(&self) -> Result<Vec<PromptRequest>, BackendError> {
+		let inputs = self.input.clone().into_vec();
+		if inputs.is_empty() {
+			return Err(poly_backend::types::BackendError::InvalidParameter(
+				String::from("input"),
+				String::from("at least one input should be given"),
+			)
+			.into());
+		}
+		Ok(inputs.into_iter().map(PromptRequest::new).collect())
+	}
+}
+
+/// Token usage of embedding inputs (which only consists of prompt tokens)
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EmbeddingUsage {
+	pub prompt_tokens: usize,
+	pub total_tokens: usize,
+}
+
+impl EmbeddingUsage {
+	fn new(prompt_tokens: usize) -> EmbeddingUsage {
+		EmbeddingUsage {
+			prompt_tokens,
+			total_tokens: prompt_tokens,
+		}
+	}
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Embeddings {
+	pub object: &'static str,
+	pub data: Vec<Embedding>,
+	pub model: String,
+	pub usage: EmbeddingUsage,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Embedding {
+	pub object: &'static str,
+	pub embedding: Vec<f32>,
+	pub index: usize,
+
+	/// Token usage of this input (not part of the OpenAI API, which only reports the total)
+	pub usage: EmbeddingUsage,
+}
+
+impl Embeddings {
+	pub fn new(model: String, embeddings: Vec<PromptEmbedding>) -> Embeddings {
+		let prompt_tokens = embeddings.iter().map(|e| e.prompt_tokens).sum();
+		Embeddings {
+			object: "list",
+			data: embeddings
+				.into_iter()
+				.enumerate()
+				.map(|(index, e)| Embedding {
+					object: "embedding",
+					embedding: e.embedding,
+					index,
+					usage: EmbeddingUsage::new(e.prompt_tokens),
+				})
+				.collect(),
+			model,
+			usage: EmbeddingUsage::new(prompt_tokens),
+		}
+	}
+}
+
+/// The finish reason in the OpenAI API for a finish reason
+pub fn finish_reason(reason: FinishReason) -> &'static str {
+	match reason {
+		FinishReason::Eot | FinishReason::StopSequence | FinishReason::Cancelled => "stop",
+		FinishReason::MaxTokens | FinishReason::LengthLimit | FinishReason::ContextFull | FinishReason::Timeout => "length",
+		FinishReason::BannedPhrase => "content_filter",
+	}
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChatCompletion {
+	pub id: String,
+	pub object: &'static str,
+	pub created: u64,
+	pub model: String,
+	pub choices: Vec<ChatCompletionChoice>,
+	pub usage: Usage,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChatCompletionChoice {
+	pub index: usize,
+	pub message: Message,
+	pub finish_reason: &'static str,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChatCompletionChunk {
+	pub id: String,
+	pub object: &'static str,
+	pub created: u64,
+	pub model: String,
+	pub choices: Vec<ChatCompletionChunkChoice>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChatCompletionChunkChoice {
+	pub index: usize,
+	pub delta: Delta,
+	pub finish_reason: Option<&'static str>,
+}
+
+/// Part of a message in a chunk of a streamed chat completion
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Delta {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub role: Option<Role>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub content: Option<String>,
+}
+
+/// A completion, or a chunk of a streamed completion (without usage)
+#[derive(Serialize, Clone, Debug)]
+pub struct Completion {
+	pub id: String,
+	pub object: &'static str,
+	pub created: u64,
+	pub model: String,
+	pub choices: Vec<CompletionChoice>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub usage: Option<Usage>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CompletionChoice {
+	pub text: String,
+	pub index: usize,
+	pub finish_reason: Option<&'static str>,
+}
+
+/// Seconds since the Unix epoch, as used for the `created` field of responses
+pub fn created() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// An error, reported in the format of the OpenAI API
+pub struct OpenAiError(pub BackendError);
+
+/// Body of a response reporting an error (also sent as event when a streamed response fails)
+#[derive(Serialize)]
+pub(crate) struct ErrorBody {
+	error: ErrorObject,
+}
+
+#[derive(Serialize)]
+struct ErrorObject {
+	message: String,
+	#[serde(rename = "type")]
+	kind: &'static str,
+	code: Option<&'static str>,
+}
+
+impl<T: Into<BackendError>> From<T> for OpenAiError {
+	fn from(error: T) -> OpenAiError {
+		OpenAiError(error.into())
+	}
+}
+
+impl OpenAiError {
+	pub(crate) fn body(&self) -> ErrorBody {
+		let body = self.0.body();
+		let error = ErrorObject {
+			message: body.message,
+			kind: if self.0.status_code().is_client_error() {
+				"invalid_request_error"
+			} else {
+				"server_error"
+			},
+			code: Some(body.error),
+		};
+		ErrorBody { error }
+	}
+
+	/// Body of the error sent when generating a streamed response ended without a result (because it panicked)
+	pub(crate) fn unexpected() -> ErrorBody {
+		ErrorBody {
+			error: ErrorObject {
+				message: String::from("generation failed unexpectedly"),
+				kind: "server_error",
+				code: None,
+			},
+		}
+	}
+}
+
+impl IntoResponse for OpenAiError {
+	fn into_response(self) -> axum::response::Response {
+		(self.0.status_code(), Json(self.body())).into_response()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use poly_backend::types::{PromptEmbedding, PromptRequest};
+	use serde_json::json;
+
+	use super::{ChatCompletionRequest, CompletionRequest, Conversation, EmbeddingRequest, Embeddings};
+
+	fn chat(messages: serde_json::Value) -> ChatCompletionRequest {
+		serde_json::from_value(json!({ "model": "chat", "messages": messages, "max_tokens": 16, "stop": "\n" })).unwrap()
+	}
+
+	#[test]
+	fn test_conversation() {
+		let request = chat(json!([
+			{ "role": "system", "content": "Be brief." },
+			{ "role": "user", "content": "Hello" },
+			{ "role": "assistant", "content": "Hi!" },
+			{ "role": "user", "content": "How are you?" },
+		]));
+		let Conversation { history, prompt } = request.conversation().unwrap();
+		assert_eq!(history, vec![(PromptRequest::new("Be brief.\n\nHello"), String::from("Hi!"))]);
+
+		// Parameters only apply to the prompt that is responded to
+		assert_eq!(prompt.prompt, "How are you?");
+		assert_eq!(prompt.max_tokens, Some(16));
+		assert_eq!(prompt.stop, vec![String::from("\n")]);
+
+		// The conversation should end with a message of the user
+		assert!(
+			chat(json!([{ "role": "user", "content": "Hello" }, { "role": "assistant", "content": "Hi!" }]))
+				.conversation()
+				.is_err()
+		);
+		assert!(
+			chat(json!([{ "role": "assistant", "content": "Hi!" }, { "role": "user", "content": "Hello" }]))
+				.conversation()
+				.is_err()
+		);
+		assert!(chat(json!([])).conversation().is_err());
+	}
+
+	#[test]
+	fn test_completion_request() {
+		let request: CompletionRequest = serde_json::from_value(json!({
+			"model": "complete",
+			"prompt": "Once upon a time",
+			"temperature": 0.5,
+			"stop": ["\n", "."],
+			"stream": true,
+			"n": 1
+		}))
+		.unwrap();
+		assert!(request.parameters.stream);
+		let prompt = request.prompt_request();
+		assert_eq!(prompt.temperature, Some(0.5));
+		assert_eq!(prompt.stop, vec![String::from("\n"), String::from(".")]);
+	}
+
+	#[test]
+	fn test_embeddings() {
+		let request = |input| serde_json::from_value::<EmbeddingRequest>(json!({ "model": "embed", "input": input })).unwrap();
+		assert_eq!(request(json!("a")).prompts().unwrap(), vec![PromptRequest::new("a")]);
+		assert_eq!(request(json!(["a", "b"])).prompts().unwrap().len(), 2);
+		assert!(request(json!([])).prompts().is_err());
+
+		let embeddings = Embeddings::new(
+			String::from("embed"),
+			vec![
+				PromptEmbedding {
+					embedding: vec![1.0],
+					prompt_tokens: 2,
+				},
+				PromptEmbedding {
+					embedding: vec![0.5],
+					prompt_tokens: 3,
+				},
+			],
+		);
+		assert_eq!(
+			serde_json::to_value(embeddings).unwrap(),
+			json!({
+				"object": "list",
+				"data": [
+					{ "object": "embedding", "embedding": [1.0], "index": 0, "usage": { "prompt_tokens": 2, "total_tokens": 2 } },
+					{ "object": "embedding", "embedding": [0.5], "index": 1, "usage": { "prompt_tokens": 3, "total_tokens": 3 } },
+				],
+				"model": "embed",
+				"usage": { "prompt_tokens": 5, "total_tokens": 5 }
+			})
+		);
+	}
+}
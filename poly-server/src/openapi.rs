@@ -0,0 +1,228 @@
+use poly_backend::{
+	stats::{schema::Duration, GenerationTimings, MemoryStats, ModelStats, QueueWaitStats, RateLimitStats, TaskStats, TokenUsage},
+	types::{
+		ActiveStats, ChatMessage, ChatRole, DocumentSource, EmbeddingResponse, FinishReason, ForgetResponse, GenerateResponse, LiveDone,
+		MemoriesResponse, MemoryStage, ModelInfo, ModelState, ModelsResponse, OutputValidation, Priority, PromptEmbedding, PromptRequest,
+		PromptSegment, PromptViolation, QuerySource, RecallRequest, RecallResponse, ReloadReport, RememberResponse, RerankMethod, RerankRequest,
+		RerankResponse, RerankResult, SessionAndPromptRequest, SessionRequest, SimilarityRequest, SimilarityResponse, SimilarityTexts, StatsResponse,
+		Status, StatusResponse, SummaryLevel, SummaryProgress, SummaryResponse, TasksResponse, TokenResponse, TokenizationResponse, ToolCall,
+		ToolResult, ValidationResponse,
+	},
+};
+use utoipa::{
+	openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+	Modify, OpenApi,
+};
+
+use crate::{
+	admission::QueueStatus,
+	api::{ErrorResponse, ReloadErrorResponse},
+	chat::{ChatClientMessage, ChatFormat, ChatServerMessage},
+	config::KeepAliveMessage,
+	routes,
+};
+
+/// The OpenAPI specification of the HTTP API, served at `/openapi.json`
+#[derive(OpenApi)]
+#[openapi(
+	info(title = "Poly server"),
+	paths(
+		routes::status_handler,
+		routes::readyz_handler,
+		routes::stats_handler,
+		routes::metrics_handler,
+		routes::tasks::tasks_handler,
+		routes::tasks::task_handler,
+		routes::tasks::status_with_user_handler,
+		routes::tasks::get_task_completion_handler,
+		routes::tasks::post_task_completion_handler,
+		routes::tasks::cancel_task_completion_handler,
+		routes::tasks::post_task_validate_handler,
+		routes::tasks::task_similarity_handler,
+		routes::tasks::task_rerank_handler,
+		routes::tasks::task_summary_handler,
+		routes::tasks::sse_task_handler,
+		routes::tasks::ws_task_handler,
+		routes::models::models_handler,
+		routes::models::get_model_embedding_handler,
+		routes::models::post_model_embedding_handler,
+		routes::models::get_model_tokenize_handler,
+		routes::models::post_model_tokenize_handler,
+		routes::models::model_similarity_handler,
+		routes::memories::memories_handler,
+		routes::memories::put_memory_ingest_handler,
+		routes::memories::delete_memory_items_handler,
+		routes::memories::post_memory_recall_handler,
+		routes::memories::get_memory_recall_handler,
+		routes::admin::reload_handler,
+	),
+	components(schemas(
+		ActiveStats,
+		ChatClientMessage,
+		ChatFormat,
+		ChatMessage,
+		ChatRole,
+		ChatServerMessage,
+		DocumentSource,
+		Duration,
+		EmbeddingResponse,
+		ErrorResponse,
+		FinishReason,
+		ForgetResponse,
+		GenerateResponse,
+		GenerationTimings,
+		KeepAliveMessage,
+		LiveDone,
+		MemoriesResponse,
+		MemoryStage,
+		ModelStats,
+		MemoryStats,
+		ModelInfo,
+		ModelState,
+		ModelsResponse,
+		OutputValidation,
+		Priority,
+		PromptEmbedding,
+		PromptRequest,
+		PromptSegment,
+		PromptViolation,
+		RecallRequest,
+		RecallResponse,
+		QuerySource,
+		QueueStatus,
+		QueueWaitStats,
+		RateLimitStats,
+		ReloadErrorResponse,
+		ReloadReport,
+		RememberResponse,
+		RerankMethod,
+		RerankRequest,
+		RerankResponse,
+		RerankResult,
+		SessionAndPromptRequest,
+		SessionRequest,
+		SimilarityRequest,
+		SimilarityResponse,
+		SimilarityTexts,
+		StatsResponse,
+		Status,
+		StatusResponse,
+		SummaryLevel,
+		SummaryProgress,
+		SummaryResponse,
+		TaskStats,
+		TasksResponse,
+		TokenResponse,
+		TokenUsage,
+		TokenizationResponse,
+		ToolCall,
+		ToolResult,
+		ValidationResponse,
+	)),
+	modifiers(&SecuritySchemes),
+	security(("bearer" = []), ("api_key" = [])),
+	tags(
+		(name = "server", description = "Status and statistics of the server"),
+		(name = "tasks", description = "Completions using the configured tasks"),
+		(name = "models", description = "Embeddings and tokenization using the configured models"),
+		(name = "memories", description = "Storing and recalling documents"),
+		(name = "admin", description = "Administration (requires a static API key)"),
+	)
+)]
+pub struct ApiDoc;
+
+/// Describes the ways a client can authenticate: a static API key or JWT as bearer token, or the `api_key` query
+/// parameter
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+	fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+		let components = openapi.components.get_or_insert_with(Default::default);
+		components.add_security_scheme("bearer", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+		components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Query(ApiKeyValue::new("api_key"))));
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use serde_json::Value;
+	use utoipa::OpenApi;
+
+	use super::ApiDoc;
+
+	/// Collects the targets of all `$ref`s in a document
+	fn references<'a>(value: &'a Value, refs: &mut Vec<&'a str>) {
+		match value {
+			Value::Object(object) => {
+				if let Some(Value::String(target)) = object.get("$ref") {
+					refs.push(target);
+				}
+				object.values().for_each(|v| references(v, refs));
+			}
+			Value::Array(array) => array.iter().for_each(|v| references(v, refs)),
+			_ => {}
+		}
+	}
+
+	#[test]
+	fn test_openapi_document() {
+		let json = ApiDoc::openapi().to_json().unwrap();
+
+		// The document must be a valid OpenAPI 3.0 document
+		let document: openapiv3::OpenAPI = serde_json::from_str(&json).unwrap();
+		assert!(document.openapi.starts_with("3.0"));
+
+		// All referenced schemas must be defined
+		let value: Value = serde_json::from_str(&json).unwrap();
+		let mut refs = vec![];
+		references(&value, &mut refs);
+		assert!(!refs.is_empty());
+		for target in refs {
+			let name = target.strip_prefix("#/components/schemas/").unwrap();
+			assert!(
+				value["components"]["schemas"].get(name).is_some(),
+				"schema {name} is referenced but not defined"
+			);
+		}
+
+		// All routes are documented
+		let mut paths: Vec<&str> = document.paths.paths.keys().map(|p| p.as_str()).collect();
+		paths.sort();
+		assert_eq!(
+			paths,
+			vec![
+				"/readyz",
+				"/status",
+				"/v1/admin/reload",
+				"/v1/memory",
+				"/v1/memory/{memory}",
+				"/v1/metrics",
+				"/v1/model",
+				"/v1/model/{model}/embedding",
+				"/v1/model/{model}/tokenization",
+				"/v1/stats",
+				"/v1/task",
+				"/v1/task/{task}",
+				"/v1/task/{task}/chat",
+				"/v1/task/{task}/completion",
+				"/v1/task/{task}/completion/{generation_id}",
+				"/v1/task/{task}/live",
+				"/v1/task/{task}/status",
+				"/v1/task/{task}/validate",
+			]
+		);
+
+		// Request and response schemas follow the serde types
+		let schemas = &value["components"]["schemas"];
+		assert_eq!(
+			schemas["TokenUsage"]["properties"]["forced_duration"]["$ref"],
+			"#/components/schemas/Duration"
+		);
+		assert_eq!(schemas["ErrorResponse"]["properties"]["error"]["type"], "string");
+		assert!(schemas["PromptSegment"]["required"].as_array().unwrap().iter().all(|r| r == "text"));
+
+		// The status endpoint does not require authentication
+		assert_eq!(value["paths"]["/status"]["get"]["security"], serde_json::json!([{}]));
+		assert_eq!(value["paths"]["/readyz"]["get"]["security"], serde_json::json!([{}]));
+	}
+}
@@ -0,0 +1,194 @@
+//! Limits the rate of requests of clients that did not authenticate (see
+//! [`crate::config::Config::ip_rate_limit`]), so that a single client cannot hammer the public tasks and the status
+//! endpoints. Each client has a bucket of `burst` requests that refills at `requests_per_second`; a request that finds
+//! the bucket empty is rejected. A client that keeps making requests while being rejected is banned for a while.
+//!
+//! Clients are told apart by their IP address (as resolved taking trusted proxies into account). As a single IPv6 host
+//! usually has a whole /64 network to pick addresses from, IPv6 clients are limited per /64 network.
+
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Ipv6Addr},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use poly_backend::stats::RateLimitStats;
+
+use crate::config::RateLimitConfig;
+
+/// Number of clients tracked before the buckets of clients that have not made requests in a while are removed
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+pub struct IpRateLimiter {
+	config: RateLimitConfig,
+	clients: Mutex<Clients>,
+}
+
+#[derive(Default)]
+struct Clients {
+	buckets: HashMap<IpAddr, Bucket>,
+	stats: RateLimitStats,
+}
+
+struct Bucket {
+	/// Number of requests the client can still make (refilled when the bucket is next used)
+	tokens: f64,
+	updated: Instant,
+
+	/// Number of requests rejected since the last one that was allowed
+	rejected: u32,
+	banned_until: Option<Instant>,
+}
+
+impl Bucket {
+	fn refill(&mut self, config: &RateLimitConfig, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * config.requests_per_second).min(config.burst as f64);
+		self.updated = now;
+	}
+}
+
+/// The key by which the requests of a client are limited: its IPv4 address or the /64 network of its IPv6 address
+pub fn client_key(ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V4(_) => ip,
+		IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+			Some(v4) => IpAddr::V4(v4),
+			None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+		},
+	}
+}
+
+impl IpRateLimiter {
+	pub fn new(config: RateLimitConfig) -> IpRateLimiter {
+		IpRateLimiter {
+			config,
+			clients: Mutex::new(Clients::default()),
+		}
+	}
+
+	/// Count a request of a client. Returns how long the client should wait before trying again when the request is
+	/// rejected.
+	pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+		self.check_at(ip, Instant::now())
+	}
+
+	fn check_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+		let config = &self.config;
+		let mut clients = self.clients.lock().unwrap();
+		if clients.buckets.len() >= MAX_TRACKED_CLIENTS {
+			clients.buckets.retain(|_, bucket| {
+				bucket.refill(config, now);
+				bucket.banned_until.is_some_and(|until| until > now) || bucket.tokens < config.burst as f64
+			});
+		}
+
+		let key = client_key(ip);
+		let Clients { buckets, stats } = &mut *clients;
+		let bucket = buckets.entry(key).or_insert_with(|| Bucket {
+			tokens: config.burst as f64,
+			updated: now,
+			rejected: 0,
+			banned_until: None,
+		});
+
+		if let Some(until) = bucket.banned_until {
+			if until > now {
+				stats.rejected_banned += 1;
+				return Err(until - now);
+			}
+			bucket.banned_until = None;
+			bucket.rejected = 0;
+		}
+
+		bucket.refill(config, now);
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			bucket.rejected = 0;
+			return Ok(());
+		}
+
+		stats.limited += 1;
+		bucket.rejected += 1;
+		if config.ban_after > 0 && bucket.rejected >= config.ban_after {
+			let ban = Duration::from_secs(config.ban_secs);
+			tracing::warn!(client = %key, ban_secs = config.ban_secs, "banning client that keeps exceeding the rate limit");
+			bucket.banned_until = Some(now + ban);
+			stats.bans += 1;
+			return Err(ban);
+		}
+		Err(Duration::from_secs_f64((1.0 - bucket.tokens) / config.requests_per_second))
+	}
+
+	pub fn stats(&self) -> RateLimitStats {
+		let clients = self.clients.lock().unwrap();
+		let now = Instant::now();
+		RateLimitStats {
+			banned: clients
+				.buckets
+				.values()
+				.filter(|bucket| bucket.banned_until.is_some_and(|until| until > now))
+				.count(),
+			..clients.stats
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		net::IpAddr,
+		time::{Duration, Instant},
+	};
+
+	use super::{client_key, IpRateLimiter};
+	use crate::config::RateLimitConfig;
+
+	fn ip(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn test_client_key() {
+		assert_eq!(client_key(ip("192.0.2.1")), ip("192.0.2.1"));
+		assert_eq!(client_key(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+		assert_eq!(client_key(ip("2001:db8:1:2:3:4:5:6")), ip("2001:db8:1:2::"));
+		assert_eq!(client_key(ip("2001:db8:1:2:ffff::1")), client_key(ip("2001:db8:1:2::7")));
+		assert_ne!(client_key(ip("2001:db8:1:2::1")), client_key(ip("2001:db8:1:3::1")));
+	}
+
+	#[test]
+	fn test_limit_and_ban() {
+		let limiter = IpRateLimiter::new(RateLimitConfig {
+			burst: 2,
+			requests_per_second: 1.0,
+			ban_after: 3,
+			ban_secs: 60,
+		});
+		let start = Instant::now();
+		let client = ip("2001:db8::1");
+
+		// The burst is allowed, after which the bucket refills at the configured rate
+		assert!(limiter.check_at(client, start).is_ok());
+		assert!(limiter.check_at(ip("2001:db8::2"), start).is_ok());
+		assert_eq!(limiter.check_at(client, start), Err(Duration::from_secs(1)));
+		assert!(limiter.check_at(ip("192.0.2.1"), start).is_ok());
+		assert!(limiter.check_at(client, start + Duration::from_secs(1)).is_ok());
+
+		// Clients that keep trying while limited are banned
+		let later = start + Duration::from_secs(1);
+		assert!(limiter.check_at(client, later).is_err());
+		assert!(limiter.check_at(client, later).is_err());
+		assert_eq!(limiter.check_at(client, later), Err(Duration::from_secs(60)));
+		assert_eq!(limiter.check_at(client, later + Duration::from_secs(30)), Err(Duration::from_secs(30)));
+		let stats = limiter.stats();
+		assert_eq!((stats.limited, stats.bans, stats.banned, stats.rejected_banned), (4, 1, 1, 1));
+
+		// Once the ban is over, the bucket has refilled
+		let after_ban = later + Duration::from_secs(60);
+		assert!(limiter.check_at(client, after_ban).is_ok());
+		assert!(limiter.check_at(client, after_ban).is_ok());
+		assert!(limiter.check_at(client, after_ban).is_err());
+	}
+}
@@ -0,0 +1,380 @@
+//! Chats whose WebSocket closed, kept for a while so that the client can resume them by reconnecting (see
+//! [`crate::config::ChatResumeConfig`]). A kept chat holds on to its session (and the thread that completes its prompts)
+//! and therefore to a KV cache, so the number of chats kept is limited per user and in total. Messages generated while
+//! the client is away are collected, to be sent when it returns.
+
+use std::{
+	collections::{HashMap, VecDeque},
+	net::IpAddr,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use poly_backend::{
+	session::Cancellation,
+	types::{ChatServerMessage, PromptRequest},
+};
+use tokio::{
+	sync::{
+		mpsc::{Receiver, Sender},
+		oneshot,
+	},
+	task::JoinHandle,
+};
+
+use crate::{api::AuthMethod, chat::ChatFormat, config::ChatResumeConfig, ratelimit::client_key};
+
+/// The user by which the chats of a client are kept and limited: the subject of its claims when it authenticated, or
+/// else its address (all clients that did not authenticate share the same subject). Chats of a client that can be told
+/// apart by neither (e.g. one connected over a Unix socket without authenticating) are not kept.
+pub fn chat_user(method: Option<AuthMethod>, sub: Option<String>, client_ip: Option<IpAddr>) -> Option<String> {
+	match (method, sub) {
+		(Some(AuthMethod::Key | AuthMethod::Jwt), Some(sub)) => Some(sub),
+		_ => client_ip.map(|ip| client_key(ip).to_string()),
+	}
+}
+
+/// A prompt of a chat, along with the means to cancel its response
+pub struct ChatPrompt {
+	pub request: PromptRequest,
+	pub cancellation: Cancellation,
+}
+
+/// The channels between a chat WebSocket and the thread that completes its prompts
+pub struct ChatChannels {
+	pub prompts: Sender<ChatPrompt>,
+
+	/// Cancels the response to the last prompt sent (see [`ChatPrompt`])
+	pub cancellation: Cancellation,
+
+	pub responses: Receiver<ChatServerMessage>,
+}
+
+/// A chat that was resumed (see [`ParkedChats::resume`])
+pub struct ResumedChat {
+	pub channels: ChatChannels,
+
+	/// The format the chat was using
+	pub format: ChatFormat,
+
+	/// Messages generated while the client was away, which should be sent before anything else
+	pub replay: VecDeque<ChatServerMessage>,
+
+	/// Number of messages generated while the client was away that were dropped because too many were generated
+	pub dropped: usize,
+}
+
+struct ParkedChat {
+	user: String,
+	task: String,
+	format: ChatFormat,
+	parked_at: Instant,
+	prompts: Sender<ChatPrompt>,
+	cancellation: Cancellation,
+
+	/// Stops the collector, which then hands back the receiver of responses and the messages it collected
+	stop: oneshot::Sender<()>,
+	collector: JoinHandle<Collected>,
+}
+
+struct Collected {
+	responses: Receiver<ChatServerMessage>,
+	replay: VecDeque<ChatServerMessage>,
+	dropped: usize,
+}
+
+impl Collected {
+	fn push(&mut self, message: ChatServerMessage, max_replay_messages: usize) {
+		// The position in line is only of interest while waiting
+		if matches!(message, ChatServerMessage::Queued { .. }) {
+			return;
+		}
+		self.replay.push_back(message);
+		while self.replay.len() > max_replay_messages {
+			self.replay.pop_front();
+			self.dropped += 1;
+		}
+	}
+}
+
+pub struct ParkedChats {
+	config: ChatResumeConfig,
+	chats: Mutex<HashMap<String, ParkedChat>>,
+}
+
+impl ParkedChats {
+	pub fn new(config: ChatResumeConfig) -> Arc<ParkedChats> {
+		Arc::new(ParkedChats {
+			config,
+			chats: Mutex::new(HashMap::new()),
+		})
+	}
+
+	/// Whether chats are kept after their WebSocket closes
+	pub fn enabled(&self) -> bool {
+		self.config.grace_secs > 0 && self.config.max_per_user > 0 && self.config.max_total > 0
+	}
+
+	/// A new random token with which a chat can be resumed
+	pub fn new_token() -> String {
+		format!("{:032x}", rand::random::<u128>())
+	}
+
+	/// Number of chats currently kept
+	pub fn count(&self) -> usize {
+		self.chats.lock().unwrap().len()
+	}
+
+	/// Keep a chat of which the WebSocket closed, so that it can be resumed using `token` by the same user for the same
+	/// task. `unsent` holds messages that were not sent to the client yet. When the user already has the maximum number
+	/// of chats kept, their oldest chat is ended. Returns false (and ends the chat) when the maximum number of chats is
+	/// kept in total.
+	pub fn park(
+		self: &Arc<Self>,
+		token: String,
+		user: &str,
+		task: &str,
+		format: ChatFormat,
+		channels: ChatChannels,
+		unsent: VecDeque<ChatServerMessage>,
+	) -> bool {
+		if !self.enabled() {
+			return false;
+		}
+
+		let mut chats = self.chats.lock().unwrap();
+		while chats.values().filter(|chat| chat.user == user).count() >= self.config.max_per_user {
+			let oldest = chats
+				.iter()
+				.filter(|(_, chat)| chat.user == user)
+				.min_by_key(|(_, chat)| chat.parked_at)
+				.map(|(token, _)| token.clone())
+				.unwrap();
+			tracing::debug!(user, "ending oldest kept chat of user to keep another");
+			chats.remove(&oldest);
+		}
+		if chats.len() >= self.config.max_total {
+			tracing::warn!(
+				max_total = self.config.max_total,
+				"not keeping chat, as the maximum number of chats is kept"
+			);
+			return false;
+		}
+
+		let (stop, mut stopped) = oneshot::channel();
+		let grace = Duration::from_secs(self.config.grace_secs);
+		let max_replay_messages = self.config.max_replay_messages;
+		let parked_chats = self.clone();
+		let expiring_token = token.clone();
+		let mut collected = Collected {
+			responses: channels.responses,
+			replay: VecDeque::new(),
+			dropped: 0,
+		};
+		for message in unsent {
+			collected.push(message, max_replay_messages);
+		}
+
+		let collector = tokio::spawn(async move {
+			let expiry = tokio::time::sleep(grace);
+			tokio::pin!(expiry);
+			let mut ended = false;
+			loop {
+				tokio::select! {
+					_ = &mut stopped => break,
+					_ = &mut expiry => {
+						tracing::debug!("kept chat expired");
+						parked_chats.chats.lock().unwrap().remove(&expiring_token);
+						break;
+					},
+					message = collected.responses.recv(), if !ended => match message {
+						Some(message) => collected.push(message, max_replay_messages),
+						None => ended = true,
+					},
+				}
+			}
+			collected
+		});
+
+		chats.insert(
+			token,
+			ParkedChat {
+				user: user.to_string(),
+				task: task.to_string(),
+				format,
+				parked_at: Instant::now(),
+				prompts: channels.prompts,
+				cancellation: channels.cancellation,
+				stop,
+				collector,
+			},
+		);
+		true
+	}
+
+	/// Take back a kept chat. Returns `None` when there is no chat kept for the token, or when it is not a chat of the
+	/// user with the task.
+	pub async fn resume(&self, token: &str, user: &str, task: &str) -> Option<ResumedChat> {
+		let parked = {
+			let mut chats = self.chats.lock().unwrap();
+			if !chats.get(token).is_some_and(|chat| chat.user == user && chat.task == task) {
+				return None;
+			}
+			chats.remove(token)?
+		};
+		_ = parked.stop.send(());
+		let collected = parked.collector.await.ok()?;
+		Some(ResumedChat {
+			channels: ChatChannels {
+				prompts: parked.prompts,
+				cancellation: parked.cancellation,
+				responses: collected.responses,
+			},
+			format: parked.format,
+			replay: collected.replay,
+			dropped: collected.dropped,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{collections::VecDeque, time::Duration};
+
+	use poly_backend::{
+		session::Cancellation,
+		stats::TokenUsage,
+		types::{ChatServerMessage, PromptRequest},
+	};
+	use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+	use super::{chat_user, ChatChannels, ChatPrompt, ParkedChats};
+	use crate::{api::AuthMethod, chat::ChatFormat, config::ChatResumeConfig, middleware::ANONYMOUS_SUB};
+
+	fn token(text: &str) -> ChatServerMessage {
+		ChatServerMessage::Token { text: text.to_string() }
+	}
+
+	fn end() -> ChatServerMessage {
+		ChatServerMessage::End {
+			usage: TokenUsage::default(),
+			finish_reason: None,
+		}
+	}
+
+	fn chat() -> (ChatChannels, Receiver<ChatPrompt>, Sender<ChatServerMessage>) {
+		let (tx_prompt, rx_prompt) = channel(16);
+		let (tx_response, rx_response) = channel(32);
+		(
+			ChatChannels {
+				prompts: tx_prompt,
+				cancellation: Cancellation::new(),
+				responses: rx_response,
+			},
+			rx_prompt,
+			tx_response,
+		)
+	}
+
+	#[tokio::test]
+	async fn test_park_and_resume() {
+		let parked = ParkedChats::new(ChatResumeConfig {
+			max_replay_messages: 2,
+			..Default::default()
+		});
+		let (channels, mut rx_prompt, tx_response) = chat();
+		assert!(parked.park(
+			String::from("t1"),
+			"alice",
+			"assistant",
+			ChatFormat::Msgpack,
+			channels,
+			VecDeque::from([token("a")])
+		));
+		assert_eq!(parked.count(), 1);
+
+		// Messages generated while the client is away are collected (the oldest are dropped when there are too many)
+		for message in [
+			ChatServerMessage::Queued {
+				position: 1,
+				estimated_wait_ms: None,
+			},
+			token("b"),
+			end(),
+		] {
+			tx_response.send(message).await.unwrap();
+		}
+		tokio::time::sleep(Duration::from_millis(50)).await;
+
+		// Only the same user can resume the chat, and only for the same task
+		assert!(parked.resume("t1", "bob", "assistant").await.is_none());
+		assert!(parked.resume("t1", "alice", "other").await.is_none());
+		assert!(parked.resume("t2", "alice", "assistant").await.is_none());
+
+		let mut resumed = parked.resume("t1", "alice", "assistant").await.unwrap();
+		assert_eq!(resumed.format, ChatFormat::Msgpack);
+		assert_eq!(resumed.replay, VecDeque::from([token("b"), end()]));
+		assert_eq!(resumed.dropped, 1);
+		assert_eq!(parked.count(), 0);
+		assert!(parked.resume("t1", "alice", "assistant").await.is_none());
+
+		// The resumed chat is still connected to the thread that completes its prompts
+		let prompt = ChatPrompt {
+			request: PromptRequest::new("hi"),
+			cancellation: Cancellation::new(),
+		};
+		resumed.channels.prompts.send(prompt).await.unwrap();
+		assert!(rx_prompt.recv().await.is_some());
+		tx_response.send(token("c")).await.unwrap();
+		assert_eq!(resumed.channels.responses.recv().await, Some(token("c")));
+	}
+
+	#[test]
+	fn test_chat_user() {
+		let ip = Some("192.0.2.1".parse().unwrap());
+		let sub = || Some(String::from("alice"));
+		assert_eq!(chat_user(Some(AuthMethod::Jwt), sub(), ip).as_deref(), Some("alice"));
+		assert_eq!(chat_user(Some(AuthMethod::Key), sub(), None).as_deref(), Some("alice"));
+
+		// Clients that did not authenticate share a subject, so they are told apart by address (or not at all)
+		let anonymous = || Some(String::from(ANONYMOUS_SUB));
+		assert_eq!(chat_user(Some(AuthMethod::Anonymous), anonymous(), ip).as_deref(), Some("192.0.2.1"));
+		assert_eq!(chat_user(Some(AuthMethod::Public), None, ip).as_deref(), Some("192.0.2.1"));
+		assert_eq!(
+			chat_user(Some(AuthMethod::Anonymous), anonymous(), Some("2001:db8::1".parse().unwrap())).as_deref(),
+			Some("2001:db8::")
+		);
+		assert_eq!(chat_user(Some(AuthMethod::Anonymous), anonymous(), None), None);
+		assert_eq!(chat_user(Some(AuthMethod::Jwt), None, None), None);
+	}
+
+	#[tokio::test]
+	async fn test_limits_and_expiry() {
+		let parked = ParkedChats::new(ChatResumeConfig {
+			grace_secs: 1,
+			max_per_user: 1,
+			max_total: 2,
+			..Default::default()
+		});
+		let (first, mut first_prompts, _first_responses) = chat();
+		let (second, _second_prompts, _second_responses) = chat();
+		let (third, _third_prompts, _third_responses) = chat();
+		assert!(parked.park(String::from("t1"), "alice", "assistant", ChatFormat::Json, first, VecDeque::new()));
+
+		// A user that has the maximum number of chats kept loses their oldest one
+		assert!(parked.park(String::from("t2"), "alice", "assistant", ChatFormat::Json, second, VecDeque::new()));
+		assert!(first_prompts.recv().await.is_none());
+		assert!(parked.resume("t1", "alice", "assistant").await.is_none());
+
+		// No more chats are kept than the maximum in total
+		let (other, _other_prompts, _other_responses) = chat();
+		assert!(parked.park(String::from("t3"), "bob", "assistant", ChatFormat::Json, other, VecDeque::new()));
+		assert!(!parked.park(String::from("t4"), "carol", "assistant", ChatFormat::Json, third, VecDeque::new()));
+		assert_eq!(parked.count(), 2);
+
+		// Chats are ended when they are not resumed in time
+		tokio::time::sleep(Duration::from_millis(1500)).await;
+		assert_eq!(parked.count(), 0);
+		assert!(parked.resume("t2", "alice", "assistant").await.is_none());
+	}
+}
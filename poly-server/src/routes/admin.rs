@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use axum::{
+	extract::State,
+	http::{Request, StatusCode},
+	middleware::Next,
+	response::IntoResponse,
+	routing::post,
+	Extension, Json, Router,
+};
+use poly_backend::types::ReloadReport;
+
+use crate::{
+	api::{AuthMethod, ReloadErrorResponse},
+	server::Server,
+};
+
+pub fn router() -> Router<Arc<Server>, axum::body::Body> {
+	Router::new()
+		.route("/reload", post(reload_handler))
+		.layer(axum::middleware::from_fn(authorize))
+}
+
+/// Reloads the configuration file, applying changes to tasks without restarting the server. Models that could not be
+/// loaded before are loaded again.
+#[utoipa::path(
+	post,
+	path = "/v1/admin/reload",
+	tag = "admin",
+	responses(
+		(status = 200, description = "The configuration was reloaded", body = ReloadReport),
+		(status = 401, description = "Not authenticated using a static API key"),
+		(status = 422, description = "The new configuration is invalid and was not applied", body = ReloadErrorResponse),
+	)
+)]
+async fn reload_handler(State(state): State<Arc<Server>>) -> Result<Json<ReloadReport>, (StatusCode, Json<ReloadErrorResponse>)> {
+	state.reload().await.map(Json).map_err(|problems| {
+		(
+			StatusCode::UNPROCESSABLE_ENTITY,
+			Json(ReloadErrorResponse {
+				problems: problems.iter().map(|p| p.to_string()).collect(),
+			}),
+		)
+	})
+}
+
+/// Middleware that only allows access to users that authenticated using one of the static API keys.
+pub async fn authorize<T>(Extension(method): Extension<AuthMethod>, req: Request<T>, next: Next<T>) -> Result<impl IntoResponse, StatusCode> {
+	if method != AuthMethod::Key {
+		return Err(StatusCode::UNAUTHORIZED);
+	}
+
+	Ok(next.run(req).await)
+}
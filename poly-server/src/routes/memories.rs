@@ -1,16 +1,24 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::UNIX_EPOCH};
 
 use axum::{
 	extract::{Path, Query, State},
 	http::{Request, StatusCode},
 	middleware::Next,
-	response::IntoResponse,
+	response::{IntoResponse, Response},
 	routing::{delete, get, post, put},
 	Extension, Json, Router,
 };
-use poly_backend::types::MemoriesResponse;
-use poly_extract::middleware::Plaintext;
-use serde::{Deserialize, Serialize};
+use poly_backend::{
+	memory::{ItemMetadata, MemoryQuery},
+	types::{DocumentSource, ForgetResponse, MemoriesResponse, QuerySource, RecallRequest, RecallResponse, RememberResponse},
+};
+use poly_extract::{
+	fetch::{fetch_document, FetchError},
+	language::detect_language,
+	middleware::Document,
+};
+use serde::Deserialize;
+use utoipa::IntoParams;
 
 use crate::{
 	api::{BackendError, JwtClaims},
@@ -29,59 +37,152 @@ pub fn router() -> Router<Arc<Server>, axum::body::Body> {
 	)
 }
 
-async fn memories_handler(State(state): State<Arc<Server>>) -> impl IntoResponse {
+/// Lists the memories that are available
+#[utoipa::path(
+	get,
+	path = "/v1/memory",
+	tag = "memories",
+	responses((status = 200, description = "Names of the memories the user is allowed to use", body = MemoriesResponse))
+)]
+async fn memories_handler(State(state): State<Arc<Server>>, Extension(claims): Extension<JwtClaims>) -> impl IntoResponse {
 	Json(MemoriesResponse {
-		memories: state.config.backend_config.memories.keys().cloned().collect(),
+		memories: state
+			.config
+			.backend_config
+			.memories
+			.keys()
+			.filter(|name| claims.allows_memory(name))
+			.cloned()
+			.collect(),
 	})
 }
 
-#[derive(Deserialize)]
-pub struct RecallRequest {
-	pub prompt: String,
-	pub n: Option<usize>,
-}
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IngestRequest {
+	/// Whether to respond only after the text has been stored (when false, the text is stored in the background)
+	#[serde(default = "default_wait")]
+	pub wait: bool,
 
-#[derive(Serialize)]
-pub struct RecallResponse {
-	pub chunks: Vec<String>,
-}
+	/// For CSV and XLSX documents: whether the first row holds the names of the columns (detected when not set)
+	pub header: Option<bool>,
 
-#[derive(Serialize)]
-pub struct ForgetResponse {}
+	/// For XLSX documents: name of the sheet to store (all sheets are stored when not set)
+	pub sheet: Option<String>,
 
-#[derive(Serialize)]
-pub struct RememberResponse {}
+	/// For CSV and XLSX documents: maximum number of rows to store of each table
+	pub max_rows: Option<usize>,
 
-#[derive(Deserialize)]
-pub struct IngestRequest {
-	#[serde(default = "default_wait")]
-	pub wait: bool,
+	/// Whether to detect the language of the document, which is stored with its chunks (and can be used to filter chunks
+	/// when recalling)
+	#[serde(default)]
+	pub detect_language: bool,
 }
 
 const fn default_wait() -> bool {
 	true
 }
 
+/// Stores a document in a memory
+#[utoipa::path(
+	put,
+	path = "/v1/memory/{memory}",
+	tag = "memories",
+	params(("memory" = String, Path, description = "Name of the memory"), IngestRequest),
+	request_body(
+		content = String,
+		content_type = "text/plain",
+		description = "The document as plain text (PDF, Word DOCX, OpenDocument text, CSV and Excel XLSX documents are also accepted, with the corresponding \
+			content type; each table or group of rows of a table is stored as a separate chunk). \
+			Alternatively a JSON object of the form `{\"url\": \"https://...\"}` (with content type application/json) refers to a document to fetch, when \
+			fetching is enabled in the configuration."
+	),
+	responses(
+		(status = 200, description = "The document was stored (or will be stored in the background)", body = RememberResponse),
+		(status = 400, description = "The URL of the document is invalid"),
+		(status = 403, description = "Fetching documents is not enabled, or not allowed for the URL (e.g. because it refers to a private address)"),
+		(status = 413, description = "The document to fetch is too large"),
+		(status = 415, description = "The document is not of a supported type"),
+		(status = 422, description = "No text could be read from the document (e.g. because it is corrupt or encrypted)"),
+		(status = 401, description = "Not authenticated, or not allowed to use the memory"),
+		(status = 404, description = "The memory does not exist", body = crate::api::ErrorResponse),
+		(status = 500, description = "The operation on the memory failed", body = crate::api::ErrorResponse),
+		(status = 502, description = "The document could not be fetched"),
+		(status = 504, description = "Fetching the document took too long"),
+	)
+)]
 async fn put_memory_ingest_handler(
 	State(state): State<Arc<Server>>,
 	Path(memory_name): Path<String>,
 	Query(params): Query<IngestRequest>,
-	Plaintext(body): Plaintext,
-) -> Result<Json<RememberResponse>, BackendError> {
+	document: Document,
+) -> Result<Json<RememberResponse>, Response> {
+	let (sections, source) = document_sections(&state, document).await?;
+
+	let metadata = ItemMetadata {
+		language: params.detect_language.then(|| detect_language(&sections.join("\n")).to_string()),
+	};
+	let language = metadata.language.clone();
+
 	if params.wait {
-		state.backend.memorize(&memory_name, &body).await?;
+		let sections: Vec<&str> = sections.iter().map(String::as_str).collect();
+		state
+			.backend
+			.memorize_sections(&memory_name, &sections, &metadata)
+			.await
+			.map_err(|e| BackendError::from(e).into_response())?;
 	} else {
 		// Defer to a background job
 		state
 			.ingest(IngestItem {
 				memory_name,
-				plaintext: body,
+				sections,
+				metadata,
 			})
 			.await;
 	}
-	Ok(Json(RememberResponse {}))
+	Ok(Json(RememberResponse { source, language }))
 }
 
+/// Read the sections of a document sent in a request, fetching it first when it is referred to by URL (which fails when
+/// fetching is not enabled). Returns the source of a fetched document along with its sections.
+pub(crate) async fn document_sections(state: &Server, document: Document) -> Result<(Vec<String>, Option<DocumentSource>), Response> {
+	Ok(match document {
+		Document::Text(text) => (vec![text], None),
+		Document::Table(sections) => (sections, None),
+		Document::Url(url) => {
+			let Some(ref options) = state.fetch_options else {
+				return Err(FetchError::Disabled.into_response());
+			};
+			let document = fetch_document(&url, options).await.map_err(IntoResponse::into_response)?;
+			let source = DocumentSource {
+				url: document.source.url,
+				title: document.source.title,
+				fetched_at: document
+					.source
+					.fetched_at
+					.duration_since(UNIX_EPOCH)
+					.map(|d| d.as_secs())
+					.unwrap_or_default(),
+			};
+			(document.sections, Some(source))
+		}
+	})
+}
+
+/// Removes all items from a memory
+#[utoipa::path(
+	delete,
+	path = "/v1/memory/{memory}",
+	tag = "memories",
+	params(("memory" = String, Path, description = "Name of the memory")),
+	responses(
+		(status = 200, description = "The memory is empty", body = ForgetResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the memory"),
+		(status = 404, description = "The memory does not exist", body = crate::api::ErrorResponse),
+		(status = 500, description = "The operation on the memory failed", body = crate::api::ErrorResponse),
+	)
+)]
 async fn delete_memory_items_handler(
 	State(state): State<Arc<Server>>,
 	Path(memory_name): Path<String>,
@@ -90,6 +191,21 @@ async fn delete_memory_items_handler(
 	Ok(Json(ForgetResponse {}))
 }
 
+/// Finds the chunks in a memory that are most similar to a prompt
+#[utoipa::path(
+	post,
+	path = "/v1/memory/{memory}",
+	tag = "memories",
+	params(("memory" = String, Path, description = "Name of the memory")),
+	request_body = RecallRequest,
+	responses(
+		(status = 200, description = "The most similar chunks", body = RecallResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the memory"),
+		(status = 404, description = "The memory does not exist", body = crate::api::ErrorResponse),
+		(status = 422, description = "Neither or both of prompt and embedding were given, or the embedding does not fit the memory", body = crate::api::ErrorResponse),
+		(status = 500, description = "The operation on the memory failed", body = crate::api::ErrorResponse),
+	)
+)]
 async fn post_memory_recall_handler(
 	State(state): State<Arc<Server>>,
 	Path(memory_name): Path<String>,
@@ -98,6 +214,20 @@ async fn post_memory_recall_handler(
 	memory_recall_handler(state, &memory_name, request).await.map(Json)
 }
 
+/// Finds the chunks in a memory that are most similar to a prompt given in the query string
+#[utoipa::path(
+	get,
+	path = "/v1/memory/{memory}",
+	tag = "memories",
+	params(("memory" = String, Path, description = "Name of the memory"), RecallRequest),
+	responses(
+		(status = 200, description = "The most similar chunks", body = RecallResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the memory"),
+		(status = 404, description = "The memory does not exist", body = crate::api::ErrorResponse),
+		(status = 422, description = "No prompt was given", body = crate::api::ErrorResponse),
+		(status = 500, description = "The operation on the memory failed", body = crate::api::ErrorResponse),
+	)
+)]
 async fn get_memory_recall_handler(
 	State(state): State<Arc<Server>>,
 	Path(memory_name): Path<String>,
@@ -108,8 +238,17 @@ async fn get_memory_recall_handler(
 
 async fn memory_recall_handler(state: Arc<Server>, memory_name: &str, request: RecallRequest) -> Result<RecallResponse, BackendError> {
 	let backend = state.backend.clone();
+	let query = request.query()?;
+	let filter = ItemMetadata {
+		language: request.language.clone(),
+	};
+	let hits = backend.recall_hits(memory_name, query, request.n.unwrap_or(1), &filter).await?;
 	Ok(RecallResponse {
-		chunks: backend.recall(memory_name, &request.prompt, request.n.unwrap_or(1)).await?,
+		chunks: hits.into_iter().map(|hit| hit.text).collect(),
+		query: match query {
+			MemoryQuery::Prompt(_) => QuerySource::Embedded,
+			MemoryQuery::Embedding(_) => QuerySource::Supplied,
+		},
 	})
 }
 
@@ -120,10 +259,8 @@ pub async fn authorize<T>(
 	req: Request<T>,
 	next: Next<T>,
 ) -> Result<impl IntoResponse, StatusCode> {
-	if let Some(memories) = &claims.memories {
-		if !memories.contains(&memory_name) {
-			return Err(StatusCode::UNAUTHORIZED);
-		}
+	if !claims.allows_memory(&memory_name) {
+		return Err(StatusCode::UNAUTHORIZED);
 	}
 
 	Ok(next.run(req).await)
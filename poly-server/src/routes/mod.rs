@@ -1,3 +1,235 @@
+use std::sync::Arc;
+
+use axum::{
+	extract::State,
+	http::{
+		header::{AUTHORIZATION, CONTENT_TYPE},
+		HeaderValue, Method, Request, StatusCode,
+	},
+	middleware::Next,
+	response::IntoResponse,
+	routing::get,
+	Extension, Json, Router,
+};
+use poly_backend::types::{ActiveStats, StatsResponse, Status, StatusResponse};
+use tower_http::{
+	cors::{AllowOrigin, Any, CorsLayer},
+	services::ServeDir,
+	trace::TraceLayer,
+};
+use utoipa::OpenApi;
+
+use crate::{
+	api::AuthMethod,
+	middleware::{admit, authenticate, check_host, limit_rate, resolve_client_ip},
+	net::HostPattern,
+	openapi::ApiDoc,
+	server::Server,
+	telemetry,
+};
+
+pub mod admin;
 pub mod memories;
 pub mod models;
+pub mod openai;
 pub mod tasks;
+
+/// Construct the router for the full API (including middleware) for a server
+pub fn router(state: Arc<Server>) -> Router {
+	Router::new()
+		.nest_service("/", ServeDir::new("client/dist/"))
+		.merge(
+			Router::new()
+				.route("/status", get(status_handler))
+				.route("/readyz", get(readyz_handler))
+				.layer(axum::middleware::from_fn_with_state(state.clone(), limit_rate)),
+		)
+		.route("/openapi.json", get(openapi_handler))
+		.nest(
+			"/v1",
+			Router::new()
+				.nest("/model", models::router())
+				.nest("/task", tasks::router())
+				.nest("/memory", memories::router())
+				.nest("/admin", admin::router())
+				.merge(
+					Router::new()
+						.route("/stats", get(stats_handler))
+						.route("/metrics", get(metrics_handler))
+						.layer(axum::middleware::from_fn(authorize)),
+				)
+				.layer(axum::middleware::from_fn_with_state(state.clone(), admit))
+				.layer(axum::middleware::from_fn_with_state(state.clone(), limit_rate))
+				.layer(axum::middleware::from_fn_with_state(state.clone(), authenticate)),
+		)
+		.nest(
+			"/openai/v1",
+			openai::router()
+				.layer(axum::middleware::from_fn_with_state(state.clone(), limit_rate))
+				.layer(axum::middleware::from_fn_with_state(state.clone(), authenticate)),
+		)
+		.merge(swagger_ui())
+		.fallback(handler_not_found)
+		.layer(cors_layer(state.config.allowed_origins.as_deref()))
+		.layer(axum::middleware::from_fn_with_state(state.clone(), check_host))
+		.layer(axum::middleware::from_fn_with_state(state.clone(), resolve_client_ip))
+		.layer(TraceLayer::new_for_http().make_span_with(telemetry::make_request_span))
+		.with_state(state)
+}
+
+/// Middleware that denies anonymous users (who may only use the public tasks) access to the statistics of the server
+async fn authorize<T>(Extension(method): Extension<AuthMethod>, req: Request<T>, next: Next<T>) -> Result<impl IntoResponse, StatusCode> {
+	if method == AuthMethod::Anonymous {
+		return Err(StatusCode::UNAUTHORIZED);
+	}
+
+	Ok(next.run(req).await)
+}
+
+fn cors_layer(allowed_origins: Option<&[HostPattern]>) -> CorsLayer {
+	let cors_layer = match allowed_origins {
+		// Allow any origin by default
+		None => CorsLayer::new().allow_origin(Any),
+		Some(origins) if origins.iter().any(|o| o.as_str() == "*") => CorsLayer::new().allow_origin(Any),
+		Some(origins) => {
+			let patterns = origins.to_vec();
+			CorsLayer::new().allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+				origin.to_str().is_ok_and(|origin| patterns.iter().any(|p| p.matches(origin)))
+			}))
+		}
+	};
+
+	cors_layer
+		.allow_headers([CONTENT_TYPE, AUTHORIZATION])
+		.allow_methods([Method::GET, Method::POST, Method::OPTIONS, Method::PUT, Method::DELETE])
+}
+
+/// Returns statistics of the tasks and models, and the number of active sessions and connections
+#[utoipa::path(
+	get,
+	path = "/v1/stats",
+	tag = "server",
+	responses((status = 200, description = "Statistics of the server", body = StatsResponse))
+)]
+async fn stats_handler(State(state): State<Arc<Server>>) -> impl IntoResponse {
+	let task_stats = state.backend.stats.task_stats.lock().unwrap().clone();
+	Json(StatsResponse {
+		tasks: task_stats,
+		models: state.backend.stats.model_stats(),
+		active: active_stats(&state),
+		memories: state.backend.memory_stats().await,
+		queue: state.admission.wait_stats(),
+		rate_limit: state.ip_rate_limiter.as_ref().map(|limiter| limiter.stats()).unwrap_or_default(),
+	})
+}
+
+fn active_stats(state: &Server) -> ActiveStats {
+	ActiveStats {
+		sessions: state.backend.stats.sessions.get(),
+		chats: state.chats.get(),
+		parked_chats: state.parked_chats.count(),
+		live_streams: state.live_streams.get(),
+		inference_busy: state.backend.pool.busy(),
+		inference_queued: state.backend.pool.queued(),
+	}
+}
+
+/// Exposes gauges in the Prometheus text exposition format
+#[utoipa::path(
+	get,
+	path = "/v1/metrics",
+	tag = "server",
+	responses((status = 200, description = "Gauges in the Prometheus text exposition format", content_type = "text/plain", body = String))
+)]
+async fn metrics_handler(State(state): State<Arc<Server>>) -> impl IntoResponse {
+	let active = active_stats(&state);
+	let gauges = [
+		(
+			"poly_active_sessions",
+			"Number of backend sessions currently held by the server",
+			active.sessions,
+		),
+		("poly_active_chats", "Number of connected chat WebSockets", active.chats),
+		(
+			"poly_parked_chats",
+			"Number of chats whose WebSocket closed that are kept so that the client can resume them",
+			active.parked_chats,
+		),
+		("poly_active_live_streams", "Number of open SSE streams", active.live_streams),
+		(
+			"poly_inference_busy",
+			"Number of inference threads performing a completion or embedding",
+			active.inference_busy,
+		),
+		(
+			"poly_inference_queued",
+			"Number of completions and embeddings waiting for an inference thread",
+			active.inference_queued,
+		),
+	];
+
+	let mut body = String::new();
+	for (name, help, value) in gauges {
+		body += &format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n");
+	}
+	([(CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Checks whether the server is running (does not require authentication). Models that could not be loaded and the
+/// tasks that cannot be used because of this are listed, as well as the models that share the weights of another model
+/// and the number of requests waiting to be serviced.
+#[utoipa::path(
+	get,
+	path = "/status",
+	tag = "server",
+	security(()),
+	responses((status = 200, description = "The server is running", body = StatusResponse))
+)]
+async fn status_handler(State(state): State<Arc<Server>>) -> impl IntoResponse {
+	Json(server_status(&state))
+}
+
+/// Checks whether the server is ready to handle requests (does not require authentication). A server that could not
+/// load some of its models is ready (with status `degraded`), as tasks using other models can still be used.
+#[utoipa::path(
+	get,
+	path = "/readyz",
+	tag = "server",
+	security(()),
+	responses((status = 200, description = "The server is ready", body = StatusResponse))
+)]
+async fn readyz_handler(State(state): State<Arc<Server>>) -> impl IntoResponse {
+	Json(server_status(&state))
+}
+
+fn server_status(state: &Server) -> StatusResponse {
+	let unavailable_models = state.backend.unavailable_models();
+	StatusResponse {
+		status: if unavailable_models.is_empty() { Status::Ok } else { Status::Degraded },
+		unavailable_models,
+		unavailable_tasks: state.backend.unavailable_tasks(),
+		model_aliases: state.backend.model_aliases.clone(),
+		queued_requests: state.admission.depth(),
+	}
+}
+
+async fn openapi_handler() -> impl IntoResponse {
+	Json(ApiDoc::openapi())
+}
+
+/// Serves Swagger UI for the OpenAPI specification at `/swagger-ui`
+#[cfg(feature = "swagger-ui")]
+fn swagger_ui() -> Router<Arc<Server>> {
+	utoipa_swagger_ui::SwaggerUi::new("/swagger-ui")
+		.config(utoipa_swagger_ui::Config::from("/openapi.json"))
+		.into()
+}
+
+#[cfg(not(feature = "swagger-ui"))]
+fn swagger_ui() -> Router<Arc<Server>> {
+	Router::new()
+}
+
+async fn handler_not_found() -> impl IntoResponse {
+	(StatusCode::NOT_FOUND, "not found")
+}
@@ -8,7 +8,10 @@ use axum::{
 	routing::{get, post},
 	Extension, Json, Router,
 };
-use poly_backend::types::{EmbeddingResponse, ModelsResponse, PromptRequest, SessionAndPromptRequest, SessionRequest, TokenizationResponse};
+use poly_backend::types::{
+	EmbeddingResponse, ModelsResponse, PromptRequest, SessionAndPromptRequest, SessionRequest, SimilarityRequest, SimilarityResponse,
+	TokenizationResponse,
+};
 
 use crate::{
 	api::{BackendError, JwtClaims},
@@ -23,43 +26,131 @@ pub fn router() -> Router<Arc<Server>, axum::body::Body> {
 			.route("/embedding", get(get_model_embedding_handler))
 			.route("/tokenization", post(post_model_tokenize_handler))
 			.route("/tokenization", get(get_model_tokenize_handler))
+			.route("/similarity", post(model_similarity_handler))
 			.layer(axum::middleware::from_fn(authorize)),
 	)
 }
 
-async fn models_handler(State(state): State<Arc<Server>>) -> impl IntoResponse {
+/// Lists the configured models the user is allowed to use, with their architecture, quantization, context length,
+/// embedding length and file size, and whether they are loaded (or why they could not be)
+#[utoipa::path(
+	get,
+	path = "/v1/model",
+	tag = "models",
+	responses((status = 200, description = "The models the user is allowed to use", body = ModelsResponse))
+)]
+async fn models_handler(State(state): State<Arc<Server>>, Extension(claims): Extension<JwtClaims>) -> impl IntoResponse {
 	Json(ModelsResponse {
-		models: state.config.backend_config.models.keys().cloned().collect(),
+		models: state
+			.backend
+			.model_info()
+			.into_iter()
+			.filter(|model| claims.allows_model(&model.name))
+			.collect(),
 	})
 }
 
+/// Calculates the embedding of a prompt given in the query string
+#[utoipa::path(
+	get,
+	path = "/v1/model/{model}/embedding",
+	tag = "models",
+	params(("model" = String, Path, description = "Name of the model"), PromptRequest),
+	responses(
+		(status = 200, description = "The embedding of the prompt", body = EmbeddingResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the model"),
+		(status = 404, description = "The model does not exist", body = crate::api::ErrorResponse),
+		(status = 500, description = "Tokenizing the prompt failed", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model is not available", body = crate::api::ErrorResponse),
+	)
+)]
 async fn get_model_embedding_handler(
 	State(state): State<Arc<Server>>,
 	Path(endpoint_name): Path<String>,
 	Query(request): Query<SessionAndPromptRequest>,
 ) -> Result<Json<EmbeddingResponse>, BackendError> {
 	let SessionAndPromptRequest { session, prompt } = request;
-	embedding_handler(state, &endpoint_name, &session, &prompt)
+	embedding_handler(state, endpoint_name, session, prompt).await
 }
 
+/// Calculates the embedding of a prompt
+#[utoipa::path(
+	post,
+	path = "/v1/model/{model}/embedding",
+	tag = "models",
+	params(("model" = String, Path, description = "Name of the model")),
+	request_body = SessionAndPromptRequest,
+	responses(
+		(status = 200, description = "The embedding of the prompt", body = EmbeddingResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the model"),
+		(status = 404, description = "The model does not exist", body = crate::api::ErrorResponse),
+		(status = 500, description = "Tokenizing the prompt failed", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model is not available", body = crate::api::ErrorResponse),
+	)
+)]
 async fn post_model_embedding_handler(
 	State(state): State<Arc<Server>>,
 	Path(endpoint_name): Path<String>,
 	Json(request): Json<SessionAndPromptRequest>,
 ) -> Result<Json<EmbeddingResponse>, BackendError> {
 	let SessionAndPromptRequest { session, prompt } = request;
-	embedding_handler(state, &endpoint_name, &session, &prompt)
+	embedding_handler(state, endpoint_name, session, prompt).await
 }
 
-fn embedding_handler(
+async fn embedding_handler(
 	state: Arc<Server>,
-	endpoint_name: &str,
-	_request: &SessionRequest,
-	prompt: &PromptRequest,
+	endpoint_name: String,
+	_request: SessionRequest,
+	prompt: PromptRequest,
 ) -> Result<Json<EmbeddingResponse>, BackendError> {
-	Ok(Json(state.backend.embedding(endpoint_name, prompt)?))
+	let backend = state.backend.clone();
+	let embedding = state.backend.pool.run(move || backend.embedding(&endpoint_name, &prompt)).await?;
+	Ok(Json(embedding))
 }
 
+/// Calculates the similarity of a text with one or more other texts, using the cosine similarity of their embeddings
+#[utoipa::path(
+	post,
+	path = "/v1/model/{model}/similarity",
+	tag = "models",
+	params(("model" = String, Path, description = "Name of the model")),
+	request_body = SimilarityRequest,
+	responses(
+		(status = 200, description = "The similarity of `a` with each of the texts in `b`", body = SimilarityResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the model"),
+		(status = 404, description = "The model does not exist", body = crate::api::ErrorResponse),
+		(status = 422, description = "No text, an empty text or more texts than the model allows were given to compare", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model is not available", body = crate::api::ErrorResponse),
+	)
+)]
+async fn model_similarity_handler(
+	State(state): State<Arc<Server>>,
+	Path(endpoint_name): Path<String>,
+	Json(request): Json<SimilarityRequest>,
+) -> Result<Json<SimilarityResponse>, BackendError> {
+	let backend = state.backend.clone();
+	let scores = state
+		.backend
+		.pool
+		.run(move || backend.similarity(&endpoint_name, &request.a, &request.b.texts()))
+		.await?;
+	Ok(Json(SimilarityResponse { scores }))
+}
+
+/// Tokenizes a prompt given in the query string
+#[utoipa::path(
+	get,
+	path = "/v1/model/{model}/tokenization",
+	tag = "models",
+	params(("model" = String, Path, description = "Name of the model"), PromptRequest),
+	responses(
+		(status = 200, description = "The tokenization of the prompt", body = TokenizationResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the model"),
+		(status = 404, description = "The model does not exist", body = crate::api::ErrorResponse),
+		(status = 500, description = "Tokenizing the prompt failed", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model is not available", body = crate::api::ErrorResponse),
+	)
+)]
 async fn get_model_tokenize_handler(
 	State(state): State<Arc<Server>>,
 	Path(endpoint_name): Path<String>,
@@ -69,6 +160,21 @@ async fn get_model_tokenize_handler(
 	tokenize_handler(state, &endpoint_name, &session, &prompt)
 }
 
+/// Tokenizes a prompt
+#[utoipa::path(
+	post,
+	path = "/v1/model/{model}/tokenization",
+	tag = "models",
+	params(("model" = String, Path, description = "Name of the model")),
+	request_body = SessionAndPromptRequest,
+	responses(
+		(status = 200, description = "The tokenization of the prompt", body = TokenizationResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the model"),
+		(status = 404, description = "The model does not exist", body = crate::api::ErrorResponse),
+		(status = 500, description = "Tokenizing the prompt failed", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model is not available", body = crate::api::ErrorResponse),
+	)
+)]
 async fn post_model_tokenize_handler(
 	State(state): State<Arc<Server>>,
 	Path(endpoint_name): Path<String>,
@@ -94,10 +200,8 @@ pub async fn authorize<T>(
 	req: Request<T>,
 	next: Next<T>,
 ) -> Result<impl IntoResponse, StatusCode> {
-	if let Some(models) = &claims.models {
-		if !models.contains(&model_name) {
-			return Err(StatusCode::UNAUTHORIZED);
-		}
+	if !claims.allows_model(&model_name) {
+		return Err(StatusCode::UNAUTHORIZED);
 	}
 
 	Ok(next.run(req).await)
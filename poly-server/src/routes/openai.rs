@@ -0,0 +1,310 @@
+//! Routes of the OpenAI-compatible API (see [`crate::openai`]), mounted at `/openai/v1`. Requests are authenticated
+//! like requests to the regular API, and may only use the tasks the user is allowed to use.
+
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use async_stream::stream;
+use axum::{
+	extract::State,
+	http::StatusCode,
+	response::{
+		sse::{Event, KeepAlive},
+		IntoResponse, Response, Sse,
+	},
+	routing::{get, post},
+	Extension, Json, Router,
+};
+use llm::{InferenceFeedback, InferenceResponse};
+use poly_backend::{
+	backend::Backend,
+	session::{Cancellation, Completion},
+	stats::TokenUsage,
+	types::{FinishReason, SessionRequest},
+};
+use serde_json::json;
+
+use crate::{
+	api::{BackendError, JwtClaims},
+	generations::random_id,
+	middleware::request_priority,
+	openai::{
+		created, finish_reason, ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionChunkChoice, ChatCompletionRequest,
+		Completion as TextCompletion, CompletionChoice, CompletionRequest, Conversation, Delta, EmbeddingRequest, Embeddings, ErrorBody, Message,
+		OpenAiError, Role,
+	},
+	server::Server,
+};
+
+pub fn router() -> Router<Arc<Server>, axum::body::Body> {
+	Router::new()
+		.route("/models", get(models_handler))
+		.route("/chat/completions", post(chat_completions_handler))
+		.route("/completions", post(completions_handler))
+		.route("/embeddings", post(embeddings_handler))
+}
+
+/// Lists the tasks the user is allowed to use as models
+async fn models_handler(State(state): State<Arc<Server>>, Extension(claims): Extension<JwtClaims>) -> impl IntoResponse {
+	let models: Vec<_> = state
+		.backend
+		.task_names()
+		.into_iter()
+		.filter(|name| claims.allows_task(name))
+		.map(|name| json!({ "id": name, "object": "model", "created": 0, "owned_by": "poly" }))
+		.collect();
+	Json(json!({ "object": "list", "data": models }))
+}
+
+async fn chat_completions_handler(
+	State(state): State<Arc<Server>>,
+	Extension(claims): Extension<JwtClaims>,
+	Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, OpenAiError> {
+	let conversation = request.conversation()?;
+	complete(state, &claims, Kind::Chat, request.model, conversation, request.parameters.stream).await
+}
+
+async fn completions_handler(
+	State(state): State<Arc<Server>>,
+	Extension(claims): Extension<JwtClaims>,
+	Json(request): Json<CompletionRequest>,
+) -> Result<Response, OpenAiError> {
+	let conversation = Conversation {
+		history: vec![],
+		prompt: request.prompt_request(),
+	};
+	complete(state, &claims, Kind::Text, request.model, conversation, request.parameters.stream).await
+}
+
+/// Calculates the embedding of each input using the model named by `model` (unlike the completion routes, which take a
+/// task)
+async fn embeddings_handler(
+	State(state): State<Arc<Server>>,
+	Extension(claims): Extension<JwtClaims>,
+	Json(request): Json<EmbeddingRequest>,
+) -> Result<Response, OpenAiError> {
+	if !claims.allows_model(&request.model) {
+		return Ok(StatusCode::UNAUTHORIZED.into_response());
+	}
+	let prompts = request.prompts()?;
+	let priority = request_priority(&state, &claims, None, None);
+	let permit = state.admission.admit(priority).await;
+	let backend = state.backend.clone();
+	let model = request.model.clone();
+	let response = state
+		.backend
+		.pool
+		.run(move || {
+			let _permit = permit;
+			backend.embedding_batch(&model, &prompts)
+		})
+		.await?;
+	Ok(Json(Embeddings::new(request.model, response.embeddings.unwrap_or_default())).into_response())
+}
+
+/// Kind of completion a client asked for, which determines the format of the response
+#[derive(Clone, Copy)]
+enum Kind {
+	Chat,
+	Text,
+}
+
+/// What the job generating a streamed completion sends to the stream
+enum Generated {
+	Token(String),
+	Done(FinishReason),
+	Error(BackendError),
+}
+
+async fn complete(
+	state: Arc<Server>,
+	claims: &JwtClaims,
+	kind: Kind,
+	model: String,
+	conversation: Conversation,
+	stream: bool,
+) -> Result<Response, OpenAiError> {
+	if !claims.allows_task(&model) {
+		return Ok(StatusCode::UNAUTHORIZED.into_response());
+	}
+	state.backend.check_task_available(&model)?;
+
+	// These routes are not admitted by middleware, so that streamed responses can start while the request waits. The
+	// generation is registered so that it can be cancelled like any other completion for the task.
+	let priority = request_priority(&state, claims, Some(&model), None);
+	let generation = state
+		.generations
+		.start(&model, random_id())
+		.expect("random generation identifiers are unique");
+	let id = match kind {
+		Kind::Chat => format!("chatcmpl-{}", generation.id()),
+		Kind::Text => format!("cmpl-{}", generation.id()),
+	};
+	let span = tracing::Span::current();
+
+	if !stream {
+		let permit = state.admission.admit(priority).await;
+		let backend = state.backend.clone();
+		let task_name = model.clone();
+		let (text, completion) = state
+			.backend
+			.pool
+			.run(move || {
+				let _entered = span.enter();
+				let _permit = permit;
+				let mut text = String::new();
+				let completion = generate(&backend, &task_name, &conversation, generation.cancellation(), |t| {
+					text += &t;
+					true
+				})?;
+				Ok::<_, BackendError>((text, completion))
+			})
+			.await?;
+		return Ok(completion_response(kind, id, model, text, completion.finish_reason, completion.usage));
+	}
+
+	// The channel is bounded, so that generation waits for a client that does not keep up
+	let (tx, mut rx) = tokio::sync::mpsc::channel(32);
+	let keep_alive = KeepAlive::new().interval(Duration::from_millis(state.config.live_keep_alive.interval_ms));
+	let stream_guard = state.live_streams.enter();
+	let stream = stream! {
+		let _stream_guard = stream_guard;
+		if let Kind::Chat = kind {
+			let delta = Delta { role: Some(Role::Assistant), content: None };
+			yield Ok::<_, Infallible>(chunk_event(kind, &id, &model, delta, None));
+		}
+
+		let permit = state.admission.admit(priority).await;
+		let backend = state.backend.clone();
+		let task_name = model.clone();
+		state.backend.pool.spawn(move || {
+			let _entered = span.enter();
+			let _permit = permit;
+			let result = generate(&backend, &task_name, &conversation, generation.cancellation(), |t| {
+				// Sending fails when the client has disconnected (and the stream was dropped)
+				tx.blocking_send(Generated::Token(t)).is_ok()
+			});
+			_ = tx.blocking_send(match result {
+				Ok(completion) => Generated::Done(completion.finish_reason),
+				Err(e) => Generated::Error(e),
+			});
+		});
+
+		loop {
+			match rx.recv().await {
+				Some(Generated::Token(token)) => {
+					let delta = Delta { role: None, content: Some(token) };
+					yield Ok::<_, Infallible>(chunk_event(kind, &id, &model, delta, None));
+				}
+				Some(Generated::Done(reason)) => {
+					yield Ok(chunk_event(kind, &id, &model, Delta::default(), Some(finish_reason(reason))));
+					break;
+				}
+				Some(Generated::Error(e)) => {
+					yield Ok(error_event(OpenAiError(e).body()));
+					break;
+				}
+				// The job ended without saying how, which means it panicked
+				None => {
+					yield Ok(error_event(OpenAiError::unexpected()));
+					break;
+				}
+			}
+		}
+		yield Ok(Event::default().data("[DONE]"));
+	};
+	Ok(Sse::new(stream).keep_alive(keep_alive).into_response())
+}
+
+/// Complete the last prompt of a conversation after feeding the earlier exchanges. `on_token` is called for each
+/// generated token; generation halts when it returns false.
+fn generate(
+	backend: &Arc<Backend>,
+	task_name: &str,
+	conversation: &Conversation,
+	cancellation: &Cancellation,
+	mut on_token: impl FnMut(String) -> bool,
+) -> Result<Completion, BackendError> {
+	let mut session = backend.start(task_name, &SessionRequest::default(), backend.clone())?;
+	for (prompt, response) in &conversation.history {
+		session.replay(prompt, response)?;
+	}
+	let completion = session.complete_cancellable(&conversation.prompt, cancellation, |r| match r {
+		InferenceResponse::InferredToken(t) => Ok(if on_token(t) {
+			InferenceFeedback::Continue
+		} else {
+			InferenceFeedback::Halt
+		}),
+		_ => Ok(InferenceFeedback::Continue),
+	})?;
+	Ok(completion)
+}
+
+fn completion_response(kind: Kind, id: String, model: String, text: String, reason: FinishReason, usage: TokenUsage) -> Response {
+	match kind {
+		Kind::Chat => Json(ChatCompletion {
+			id,
+			object: "chat.completion",
+			created: created(),
+			model,
+			choices: vec![ChatCompletionChoice {
+				index: 0,
+				message: Message {
+					role: Role::Assistant,
+					content: text,
+				},
+				finish_reason: finish_reason(reason),
+			}],
+			usage: usage.into(),
+		})
+		.into_response(),
+		Kind::Text => Json(TextCompletion {
+			id,
+			object: "text_completion",
+			created: created(),
+			model,
+			choices: vec![CompletionChoice {
+				text,
+				index: 0,
+				finish_reason: Some(finish_reason(reason)),
+			}],
+			usage: Some(usage.into()),
+		})
+		.into_response(),
+	}
+}
+
+/// Event holding a chunk of a streamed completion
+fn chunk_event(kind: Kind, id: &str, model: &str, delta: Delta, finish_reason: Option<&'static str>) -> Event {
+	let event = match kind {
+		Kind::Chat => Event::default().json_data(ChatCompletionChunk {
+			id: id.to_string(),
+			object: "chat.completion.chunk",
+			created: created(),
+			model: model.to_string(),
+			choices: vec![ChatCompletionChunkChoice {
+				index: 0,
+				delta,
+				finish_reason,
+			}],
+		}),
+		Kind::Text => Event::default().json_data(TextCompletion {
+			id: id.to_string(),
+			object: "text_completion",
+			created: created(),
+			model: model.to_string(),
+			choices: vec![CompletionChoice {
+				text: delta.content.unwrap_or_default(),
+				index: 0,
+				finish_reason,
+			}],
+			usage: None,
+		}),
+	};
+	event.expect("completion chunks can be serialized")
+}
+
+/// Event reporting an error that occurred after a streamed response started
+fn error_event(body: ErrorBody) -> Event {
+	Event::default().json_data(body).expect("errors can be serialized")
+}
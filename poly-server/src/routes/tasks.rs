@@ -1,31 +1,48 @@
 use std::{
+	borrow::Cow,
+	collections::{HashMap, VecDeque},
 	convert::Infallible,
-	sync::{
-		atomic::{AtomicBool, Ordering},
-		Arc,
-	},
+	sync::Arc,
 	time::Duration,
 };
 
 use async_stream::stream;
 use axum::{
 	extract::{
-		ws::{Message, WebSocket},
+		ws::{close_code, CloseFrame, Message, WebSocket},
 		Path, Query, State, WebSocketUpgrade,
 	},
-	http::{Request, StatusCode},
+	http::{HeaderMap, HeaderValue, Request, StatusCode},
 	middleware::Next,
-	response::{sse::Event, IntoResponse, Sse},
-	routing::{get, post},
+	response::{
+		sse::{Event, KeepAlive},
+		IntoResponse, Response, Sse,
+	},
+	routing::{delete, get, post},
 	Extension, Json, Router,
 };
 use futures_util::Stream;
 use llm::InferenceResponse;
-use poly_backend::types::{GenerateResponse, PromptRequest, SessionAndPromptRequest, SessionRequest, Status, StatusResponse, TasksResponse};
+use poly_backend::session::{Cancellation, Completion};
+use poly_backend::types::{
+	GenerateResponse, LiveDone, Priority, PromptRequest, RerankRequest, RerankResponse, SessionAndPromptRequest, SessionRequest, SimilarityRequest,
+	SimilarityResponse, StatusResponse, SummaryResponse, TaskResponse, TasksResponse, ToolCall, ToolResult, ValidationResponse,
+};
+use poly_extract::middleware::Document;
+use serde::Deserialize;
 use tracing::{debug, trace};
+use utoipa::IntoParams;
 
 use crate::{
-	api::{BackendError, JwtClaims},
+	admission::QueueStatus,
+	api::{AuthMethod, BackendError, JwtClaims, PriorityRequest},
+	chat::{ChatClientMessage, ChatFormat, ChatRequest, ChatServerMessage},
+	config::{KeepAliveConfig, KeepAliveMessage},
+	generations::{self, Generation},
+	middleware::request_priority,
+	net::ClientIp,
+	resume::{chat_user, ChatChannels, ChatPrompt, ParkedChats},
+	routes::memories::document_sections,
 	server::Server,
 };
 
@@ -33,41 +50,365 @@ pub fn router() -> Router<Arc<Server>, axum::body::Body> {
 	Router::new().route("/", get(tasks_handler)).nest(
 		"/:task",
 		Router::new()
+			.route("/", get(task_handler))
 			.route("/chat", get(ws_task_handler))
 			.route("/status", get(status_with_user_handler))
 			.route("/live", get(sse_task_handler))
 			.route("/completion", post(post_task_completion_handler))
 			.route("/completion", get(get_task_completion_handler))
+			.route("/completion/:generation_id", delete(cancel_task_completion_handler))
+			.route("/validate", post(post_task_validate_handler))
+			.route("/similarity", post(task_similarity_handler))
+			.route("/rerank", post(task_rerank_handler))
+			.route("/summary", post(task_summary_handler))
 			.layer(axum::middleware::from_fn(authorize)),
 	)
 }
 
-async fn tasks_handler(State(state): State<Arc<Server>>) -> impl IntoResponse {
+/// Lists the tasks that are available
+#[utoipa::path(
+	get,
+	path = "/v1/task",
+	tag = "tasks",
+	responses((status = 200, description = "Names of the tasks the user is allowed to use (only the public tasks for users that did not authenticate)", body = TasksResponse))
+)]
+async fn tasks_handler(State(state): State<Arc<Server>>, Extension(claims): Extension<JwtClaims>) -> impl IntoResponse {
 	Json(TasksResponse {
-		tasks: state.config.backend_config.tasks.keys().cloned().collect(),
+		tasks: state.backend.task_names().into_iter().filter(|name| claims.allows_task(name)).collect(),
 	})
 }
 
-async fn status_with_user_handler(Extension(current_user): Extension<JwtClaims>) -> impl IntoResponse {
+/// Returns the effective settings of a task (after applying defaults and its profile), leaving out those that are internal
+/// to the server such as prompts, private tokens and tools
+#[utoipa::path(
+	get,
+	path = "/v1/task/{task}",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task")),
+	responses(
+		(status = 200, description = "Settings of the task that users may know about, in the format of the configuration file (including limits such as max_prompt_tokens), with the variables of its prompt template as `template_variables`", body = Object),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "The task does not exist", body = crate::api::ErrorResponse),
+	)
+)]
+async fn task_handler(State(state): State<Arc<Server>>, Path(task_name): Path<String>) -> Result<Json<TaskResponse>, BackendError> {
+	match state.backend.task(&task_name) {
+		Some(config) => Ok(Json(TaskResponse::new(&config))),
+		None => Err(poly_backend::types::BackendError::TaskNotFound(task_name).into()),
+	}
+}
+
+/// Checks whether the task may be used
+#[utoipa::path(
+	get,
+	path = "/v1/task/{task}/status",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task")),
+	responses(
+		(status = 200, description = "The task may be used", body = StatusResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "The task does not exist", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model of the task could not be loaded", body = crate::api::ErrorResponse),
+	)
+)]
+async fn status_with_user_handler(
+	State(state): State<Arc<Server>>,
+	Path(task_name): Path<String>,
+	Extension(current_user): Extension<JwtClaims>,
+) -> Result<Json<StatusResponse>, BackendError> {
 	tracing::info!("task request from user {:?}", current_user.sub);
-	Json(StatusResponse { status: Status::Ok })
+	state.backend.check_task_available(&task_name)?;
+	Ok(Json(StatusResponse::ok()))
 }
 
+/// Generates a completion for a prompt given in the query string
+#[utoipa::path(
+	get,
+	path = "/v1/task/{task}/completion",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task"), PromptRequest, PriorityRequest),
+	responses(
+		(status = 200, description = "The completion", body = GenerateResponse, headers(("x-generation-id" = String, description = "Identifier of the generation"))),
+		(status = 400, description = "The prompt contains an illegal token, a parameter is invalid or the generation identifier is invalid or in use", body = crate::api::ErrorResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "The task or its model does not exist", body = crate::api::ErrorResponse),
+		(status = 413, description = "The prompt does not fit in the context window or is longer than the task allows", body = crate::api::ErrorResponse),
+		(status = 422, description = "The prompt has segments marked as trusted, which the user is not allowed to do", body = crate::api::ErrorResponse),
+		(status = 500, description = "Generating the completion failed", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model of the task is not available", body = crate::api::ErrorResponse),
+	)
+)]
 async fn get_task_completion_handler(
 	State(state): State<Arc<Server>>,
 	Path(task_name): Path<String>,
 	Query(request): Query<SessionRequest>,
 	Query(prompt): Query<PromptRequest>,
-) -> Result<Json<GenerateResponse>, BackendError> {
-	task_completion_handler(state, task_name, request, prompt).await
+	Extension(claims): Extension<JwtClaims>,
+	headers: HeaderMap,
+) -> Result<Response, BackendError> {
+	claims.check_prompt(&prompt)?;
+	task_completion_handler(state, task_name, request, prompt, &headers).await
 }
 
+/// Generates a completion for a prompt
+#[utoipa::path(
+	post,
+	path = "/v1/task/{task}/completion",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task"), PriorityRequest),
+	request_body = SessionAndPromptRequest,
+	responses(
+		(status = 200, description = "The completion", body = GenerateResponse, headers(("x-generation-id" = String, description = "Identifier of the generation"))),
+		(status = 400, description = "The prompt contains an illegal token, a parameter is invalid or the generation identifier is invalid or in use", body = crate::api::ErrorResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "The task or its model does not exist", body = crate::api::ErrorResponse),
+		(status = 413, description = "The prompt does not fit in the context window or is longer than the task allows", body = crate::api::ErrorResponse),
+		(status = 422, description = "The prompt has segments marked as trusted, which the user is not allowed to do", body = crate::api::ErrorResponse),
+		(status = 500, description = "Generating the completion failed", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model of the task is not available", body = crate::api::ErrorResponse),
+	)
+)]
 async fn post_task_completion_handler(
 	State(state): State<Arc<Server>>,
 	Path(task_name): Path<String>,
+	Extension(claims): Extension<JwtClaims>,
+	headers: HeaderMap,
 	Json(request): Json<SessionAndPromptRequest>,
-) -> Result<Json<GenerateResponse>, BackendError> {
-	task_completion_handler(state, task_name, request.session, request.prompt).await
+) -> Result<Response, BackendError> {
+	claims.check_prompt(&request.prompt)?;
+	task_completion_handler(state, task_name, request.session, request.prompt, &headers).await
+}
+
+/// Cancels a completion that is being generated
+///
+/// The completion ends before it generates its next token (or feeds the next part of its prompt) and its response holds
+/// the text generated so far, with finish reason `cancelled`. Completions are identified by the `X-Generation-Id`
+/// response header (which clients may also set on the request to choose the identifier themselves) or the `open` event
+/// of a live stream.
+#[utoipa::path(
+	delete,
+	path = "/v1/task/{task}/completion/{generation_id}",
+	tag = "tasks",
+	params(
+		("task" = String, Path, description = "Name of the task"),
+		("generation_id" = String, Path, description = "Identifier of the generation")
+	),
+	responses(
+		(status = 204, description = "The completion will be cancelled"),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "No completion with this identifier is being generated for the task"),
+	)
+)]
+async fn cancel_task_completion_handler(State(state): State<Arc<Server>>, Path((task_name, generation_id)): Path<(String, String)>) -> StatusCode {
+	if state.generations.cancel(&task_name, &generation_id) {
+		debug!(generation_id, "cancelling generation");
+		StatusCode::NO_CONTENT
+	} else {
+		StatusCode::NOT_FOUND
+	}
+}
+
+/// Header holding the identifier of a generation (see [cancel_task_completion_handler])
+const GENERATION_ID_HEADER: &str = "x-generation-id";
+
+/// Register a generation for a task, using the identifier chosen by the client (if any)
+fn start_generation(state: &Server, task_name: &str, headers: &HeaderMap) -> Result<Generation, BackendError> {
+	let invalid = |message: &str| poly_backend::types::BackendError::InvalidParameter(String::from(GENERATION_ID_HEADER), message.to_string());
+	let id = match headers.get(GENERATION_ID_HEADER) {
+		Some(value) => match value.to_str() {
+			Ok(id) if generations::is_valid_id(id) => id.to_string(),
+			_ => return Err(invalid("should consist of 1 to 64 letters, digits, dashes and underscores").into()),
+		},
+		None => generations::random_id(),
+	};
+	Ok(state
+		.generations
+		.start(task_name, id)
+		.ok_or_else(|| invalid("a generation with this identifier is already running"))?)
+}
+
+/// Checks whether a completion for the prompt would be started, without performing it
+#[utoipa::path(
+	post,
+	path = "/v1/task/{task}/validate",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task")),
+	request_body = SessionAndPromptRequest,
+	responses(
+		(status = 200, description = "The result of the check (also when the prompt would be rejected)", body = ValidationResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "The task or its model does not exist", body = crate::api::ErrorResponse),
+		(status = 422, description = "The prompt has segments marked as trusted, which the user is not allowed to do", body = crate::api::ErrorResponse),
+	)
+)]
+async fn post_task_validate_handler(
+	State(state): State<Arc<Server>>,
+	Path(task_name): Path<String>,
+	Extension(claims): Extension<JwtClaims>,
+	Json(request): Json<SessionAndPromptRequest>,
+) -> Result<Json<ValidationResponse>, BackendError> {
+	claims.check_prompt(&request.prompt)?;
+	Ok(Json(state.backend.validate(&task_name, &request.session, &request.prompt)?))
+}
+
+/// Calculates the similarity of a text with one or more other texts, using the embeddings calculated by the model of the
+/// task
+#[utoipa::path(
+	post,
+	path = "/v1/task/{task}/similarity",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task")),
+	request_body = SimilarityRequest,
+	responses(
+		(status = 200, description = "The similarity of `a` with each of the texts in `b`", body = SimilarityResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "The task or its model does not exist", body = crate::api::ErrorResponse),
+		(status = 422, description = "No text, an empty text or more texts than the model allows were given to compare", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model of the task is not available", body = crate::api::ErrorResponse),
+	)
+)]
+async fn task_similarity_handler(
+	State(state): State<Arc<Server>>,
+	Path(task_name): Path<String>,
+	Json(request): Json<SimilarityRequest>,
+) -> Result<Json<SimilarityResponse>, BackendError> {
+	let task_config = state
+		.backend
+		.task(&task_name)
+		.ok_or_else(|| poly_backend::types::BackendError::TaskNotFound(task_name.clone()))?;
+	let backend = state.backend.clone();
+	let scores = state
+		.backend
+		.pool
+		.run(move || backend.similarity(&task_config.model, &request.a, &request.b.texts()))
+		.await?;
+	Ok(Json(SimilarityResponse { scores }))
+}
+
+/// Orders documents by their relevance to a query, using the rerank method configured for the task
+#[utoipa::path(
+	post,
+	path = "/v1/task/{task}/rerank",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task")),
+	request_body = RerankRequest,
+	responses(
+		(status = 200, description = "The documents, most relevant first", body = RerankResponse),
+		(status = 400, description = "Too many documents were given", body = crate::api::ErrorResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "The task or its model does not exist", body = crate::api::ErrorResponse),
+		(status = 422, description = "The query or one of the documents is empty", body = crate::api::ErrorResponse),
+		(status = 503, description = "The model of the task is not available", body = crate::api::ErrorResponse),
+		(status = 504, description = "Reranking took longer than the budget of the task", body = crate::api::ErrorResponse),
+	)
+)]
+async fn task_rerank_handler(
+	State(state): State<Arc<Server>>,
+	Path(task_name): Path<String>,
+	Json(request): Json<RerankRequest>,
+) -> Result<Json<RerankResponse>, BackendError> {
+	let span = tracing::Span::current();
+	let backend = state.backend.clone();
+	backend
+		.pool
+		.run(move || {
+			let _entered = span.enter();
+			Ok(Json(state.backend.rerank(&task_name, &request)?))
+		})
+		.await
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct SummaryRequest {
+	/// Whether to report progress while summarizing, as server-sent events
+	#[serde(default)]
+	pub stream: bool,
+}
+
+/// Summarizes a document that may be larger than the context window of the model of the task
+///
+/// The document is split into parts of at most `summarize.chunk_tokens` tokens (see the configuration of the task), each
+/// of which is completed using the task. The summaries of the parts are then joined and summarized again in the same way,
+/// until a single summary remains. The prefix and postfix of the task should therefore ask for a summary of the text in
+/// between. Summarizing fails when more tokens than `summarize.max_total_tokens` would be needed, or when more than
+/// `summarize.max_levels` rounds would be needed.
+///
+/// When `stream` is set, the response is a stream of server-sent events instead: a `progress` event (holding a
+/// `SummaryProgress`) before each completion, followed by either a `summary` event (holding a `SummaryResponse`) or an
+/// `error` event (holding an `ErrorResponse`).
+#[utoipa::path(
+	post,
+	path = "/v1/task/{task}/summary",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task"), SummaryRequest, PriorityRequest),
+	request_body(
+		content = String,
+		content_type = "text/plain",
+		description = "The document, in any of the formats accepted when storing a document in a memory (including a JSON object of the form \
+			`{\"url\": \"https://...\"}` referring to a document to fetch)"
+	),
+	responses(
+		(status = 200, description = "The summary of the document (or a stream of server-sent events)", body = SummaryResponse),
+		(status = 400, description = "The document is empty", body = crate::api::ErrorResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 404, description = "The task or its model does not exist", body = crate::api::ErrorResponse),
+		(status = 413, description = "Summarizing the document would take more tokens or rounds than allowed", body = crate::api::ErrorResponse),
+		(status = 415, description = "The document is not of a supported type"),
+		(status = 503, description = "The model of the task is not available", body = crate::api::ErrorResponse),
+	)
+)]
+async fn task_summary_handler(
+	State(state): State<Arc<Server>>,
+	Path(task_name): Path<String>,
+	Query(request): Query<SummaryRequest>,
+	document: Document,
+) -> Result<Response, Response> {
+	if state.backend.task(&task_name).is_none() {
+		return Err(BackendError::from(poly_backend::types::BackendError::TaskNotFound(task_name)).into_response());
+	}
+	let (sections, _) = document_sections(&state, document).await?;
+	let text = sections.join("\n\n");
+	let span = tracing::Span::current();
+
+	if !request.stream {
+		let backend = state.backend.clone();
+		return backend
+			.pool
+			.run(move || {
+				let _entered = span.enter();
+				state.backend.summarize(&task_name, &text, |_| {})
+			})
+			.await
+			.map(|summary| Json(summary).into_response())
+			.map_err(|e| BackendError::from(e).into_response());
+	}
+
+	let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+	let backend = state.backend.clone();
+	state.backend.pool.spawn(move || {
+		let _entered = span.enter();
+		let result = backend.summarize(&task_name, &text, |progress| {
+			// The client may have disconnected, in which case the summary is still completed (but not sent)
+			let _ = tx.send(Event::default().event("progress").json_data(progress));
+		});
+		let event = match result {
+			Ok(summary) => Event::default().event("summary").json_data(summary),
+			Err(e) => Event::default().event("error").json_data(BackendError::from(e).body()),
+		};
+		let _ = tx.send(event);
+	});
+
+	let stream_guard = state.live_streams.enter();
+	let stream = stream! {
+		let _stream_guard = stream_guard;
+		while let Some(event) = rx.recv().await {
+			match event {
+				Ok(event) => yield Ok::<_, Infallible>(event),
+				Err(e) => tracing::error!("could not encode summary event: {e}"),
+			}
+		}
+	};
+	Ok(Sse::new(stream).keep_alive(keep_alive(&state.config.live_keep_alive)).into_response())
 }
 
 async fn task_completion_handler(
@@ -75,205 +416,573 @@ async fn task_completion_handler(
 	task_name: String,
 	request: SessionRequest,
 	prompt: PromptRequest,
-) -> Result<Json<GenerateResponse>, BackendError> {
-	tokio::task::spawn_blocking(move || {
-		let mut text = String::new();
-		state
-			.backend
-			.start(&task_name, &request, state.backend.clone())?
-			.complete(&prompt, |r| -> Result<_, poly_backend::types::BackendError> {
-				match r {
-					llm::InferenceResponse::InferredToken(t) => {
-						trace!("Output: {t}");
-						text += &t;
-						Ok(llm::InferenceFeedback::Continue)
-					}
-					_ => Ok(llm::InferenceFeedback::Continue),
-				}
-			})?;
-		Ok(Json(GenerateResponse { text }))
-	})
-	.await
-	.unwrap()
+	headers: &HeaderMap,
+) -> Result<Response, BackendError> {
+	let generation = start_generation(&state, &task_name, headers)?;
+	let generation_id = HeaderValue::from_str(generation.id()).expect("generation identifiers are valid header values");
+	let span = tracing::Span::current();
+	let backend = state.backend.clone();
+	let response = backend
+		.pool
+		.run(move || {
+			let _entered = span.enter();
+			let (text, completion) = state
+				.backend
+				.complete_cancellable(&task_name, &request, &prompt, generation.cancellation(), |t| {
+					trace!("Output: {t}");
+					llm::InferenceFeedback::Continue
+				})?;
+			Ok::<_, BackendError>(Json(GenerateResponse {
+				text,
+				usage: completion.usage,
+				finish_reason: completion.finish_reason,
+				force_closed: completion.force_closed,
+				tool_call: completion.tool_call,
+				validation: completion.validation,
+				choice: completion.choice,
+				truncated_tokens: Some(completion.truncated_tokens).filter(|n| *n > 0),
+				duration_ms: completion.duration.as_millis() as u64,
+			}))
+		})
+		.await?;
+	Ok(([(GENERATION_ID_HEADER, generation_id)], response).into_response())
 }
 
+/// Chats with a task over a WebSocket
+///
+/// The connection is upgraded to a WebSocket, on which the client sends prompts and the server responds with the
+/// generated tokens. Prompts are completed one after the other in the same session, so that later prompts can refer to
+/// earlier ones. Messages are encoded in one of the following formats (see `ChatFormat`):
+///
+/// - `text`: prompts and tokens are sent as plain text frames, and an empty frame ends each response. Errors close the
+///   connection (with code 1011 and the error as reason).
+/// - `json`: text frames holding a `ChatClientMessage` (client) or `ChatServerMessage` (server) as JSON. A `prompt`
+///   message may override `max_tokens`, `temperature` and `top_p` for its response. Each response consists of `token`
+///   messages followed by either an `end` message (holding the token usage and finish reason) or an `error` message. A
+///   `cancel` message stops the response to the last prompt, which then ends with finish reason `cancelled`. When the
+///   server is servicing the maximum number of requests, `queued` messages holding the position of the prompt in line
+///   are sent periodically until generation starts (the `text` format does not send these).
+/// - `msgpack`: the same messages as in the `json` format, encoded using MessagePack in binary frames.
+///
+/// A frame that cannot be decoded closes the connection with code 1003 (a frame of the wrong kind) or 1007 (an invalid
+/// message). When the chat cannot go on (e.g. because its session could not be started, which is reported in an `error`
+/// message first), the connection is closed with code 1011.
+///
+/// When no format is requested, a connection on which the first frame is binary uses `msgpack`, a connection on which
+/// the first frame is a JSON message uses `json`, and any other connection uses `text`. Clients of the plain text
+/// protocol can request it using `protocol=text` (or `format=text`).
+///
+/// When the task has tools, a response may consist of a `tool_call` message followed by `end`. The client then sends a
+/// `tool_result` message holding the result of the tool, after which the task continues. The `text` format sends tool
+/// calls as JSON text frames and cannot send tool results.
+///
+/// Chats in the `json` and `msgpack` formats can be resumed after the connection is lost. The first message the server
+/// sends is then a `session` message holding a token. The chat (and its session) is kept for a while after the
+/// connection closes; connecting again with the token as `resume` parameter continues it, after which the messages
+/// generated while the client was away are sent. When the chat cannot be resumed, a new one is started and the
+/// `session` message holds a notice saying so.
+#[utoipa::path(
+	get,
+	path = "/v1/task/{task}/chat",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task"), ChatRequest, PriorityRequest),
+	responses(
+		(status = 101, description = "The connection was upgraded to a WebSocket"),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+	)
+)]
 async fn ws_task_handler(
 	ws: WebSocketUpgrade,
 	State(state): State<Arc<Server>>,
 	Path(task_name): Path<String>,
 	Query(request): Query<SessionRequest>,
+	Query(chat): Query<ChatRequest>,
+	Query(priority): Query<PriorityRequest>,
+	Extension(claims): Extension<JwtClaims>,
+	method: Option<Extension<AuthMethod>>,
+	client_ip: Option<Extension<ClientIp>>,
 ) -> impl IntoResponse {
 	debug!("New websocket connection for task '{}'", task_name.as_str());
-	ws.on_upgrade(move |socket| socket_task_handler(socket, state, task_name, request))
+	let priority = request_priority(&state, &claims, Some(&task_name), priority.priority);
+
+	// Chats are resumed and limited per user; clients that did not authenticate are told apart by their address
+	let user = chat_user(
+		method.map(|Extension(method)| method),
+		claims.sub,
+		client_ip.map(|Extension(ClientIp(ip))| ip),
+	);
+	ws.on_upgrade(move |socket| socket_task_handler(socket, state, task_name, request, chat, user, priority))
 }
 
-async fn socket_task_handler(mut ws: WebSocket, state: Arc<Server>, task_name: String, request: SessionRequest) {
-	// Spawn a blocking thread
-	let (tx_prompt, mut rx_prompt) = tokio::sync::mpsc::channel(16);
-	let (tx_response, mut rx_response) = tokio::sync::mpsc::channel::<Result<String, String>>(32);
-	let t = tokio::task::spawn_blocking(move || {
-		let mut session = state.backend.start(&task_name, &request, state.backend.clone()).unwrap();
-		while let Some(prompt) = rx_prompt.blocking_recv() {
-			let prompt_request = PromptRequest { prompt };
-			let res = session.complete(&prompt_request, |r| match r {
-				InferenceResponse::InferredToken(token) => {
-					if tx_response.blocking_send(Ok(token)).is_err() {
-						// Connection is likely closed
-						return Ok(llm::InferenceFeedback::Halt);
-					}
-					Ok(llm::InferenceFeedback::Continue)
+/// Why the connection of a chat ended
+enum ChatEnd {
+	/// The WebSocket closed or broke. The chat can be resumed; it holds a message that could not be sent.
+	Disconnected(Option<ChatServerMessage>),
+
+	/// The chat cannot go on (the client sent an invalid message, an error occurred that the format cannot express, or
+	/// the thread completing the prompts ended)
+	Ended,
+}
+
+/// Encode and send a message to the client of a chat
+async fn send_chat_message(ws: &mut WebSocket, format: ChatFormat, message: ChatServerMessage) -> Result<(), ChatEnd> {
+	if let ChatServerMessage::Error { message: ref error } = message {
+		tracing::error!("WebSocket: backend thread reported error: {error}");
+	}
+
+	// The text format has no way to express the position in line or the session, so the client just waits
+	if format == ChatFormat::Text && matches!(message, ChatServerMessage::Queued { .. } | ChatServerMessage::Session { .. }) {
+		return Ok(());
+	}
+
+	// The text format cannot express errors, so the connection is closed instead (with the error as reason)
+	let Some(frame) = format.encode(&message) else {
+		if let ChatServerMessage::Error { message: ref error } = message {
+			close_chat(ws, close_code::ERROR, error).await;
+		}
+		return Err(ChatEnd::Ended);
+	};
+	if let Err(e) = ws.send(frame).await {
+		tracing::error!("WebSocket: send reported error: {e}");
+		return Err(ChatEnd::Disconnected(Some(message)));
+	}
+	Ok(())
+}
+
+/// Close the WebSocket of a chat that cannot go on, telling the client why
+async fn close_chat(ws: &mut WebSocket, code: u16, reason: &str) {
+	// The reason of a close frame can be at most 123 bytes long
+	let mut end = reason.len().min(123);
+	while !reason.is_char_boundary(end) {
+		end -= 1;
+	}
+	let frame = CloseFrame {
+		code,
+		reason: Cow::Owned(reason[..end].to_string()),
+	};
+	if let Err(e) = ws.send(Message::Close(Some(frame))).await {
+		tracing::debug!("WebSocket: could not send close frame: {e}");
+	}
+}
+
+async fn socket_task_handler(
+	mut ws: WebSocket,
+	state: Arc<Server>,
+	task_name: String,
+	request: SessionRequest,
+	chat: ChatRequest,
+	user: Option<String>,
+	priority: Priority,
+) {
+	let _chat_guard = state.chats.enter();
+
+	// When chats can be resumed, the client is told the token to resume this one with before anything else (sent as soon
+	// as the format is known). Chats of clients that cannot be told apart are not kept.
+	let token = (state.parked_chats.enabled() && user.is_some()).then(ParkedChats::new_token);
+	let resumed = match (&chat.resume, &user) {
+		(Some(resume), Some(user)) => state.parked_chats.resume(resume, user, &task_name).await,
+		_ => None,
+	};
+	let mut pending = VecDeque::new();
+	if let Some(ref token) = token {
+		pending.push_back(ChatServerMessage::Session {
+			token: token.clone(),
+			resumed: resumed.is_some(),
+			notice: (chat.resume.is_some() && resumed.is_none())
+				.then(|| String::from("the chat could not be resumed, as it is unknown or has expired; a new chat was started")),
+			dropped_messages: resumed.as_ref().map(|resumed| resumed.dropped).filter(|n| *n > 0),
+		});
+	}
+
+	let mut format = chat.format;
+	let channels = match resumed {
+		Some(resumed) => {
+			tracing::info!("WebSocket: resuming chat");
+			format = format.or(Some(resumed.format));
+			pending.extend(resumed.replay);
+			resumed.channels
+		}
+		None => start_chat(state.clone(), task_name.clone(), request, priority),
+	};
+	let ChatChannels {
+		prompts,
+		mut cancellation,
+		mut responses,
+	} = channels;
+
+	// When the client did not request a format, it is chosen based on the first message
+	let end = 'chat: loop {
+		if let Some(format) = format {
+			while let Some(message) = pending.pop_front() {
+				if let Err(end) = send_chat_message(&mut ws, format, message).await {
+					break 'chat end;
 				}
-				InferenceResponse::EotToken => Ok(llm::InferenceFeedback::Halt),
-				InferenceResponse::PromptToken(_) | InferenceResponse::SnapshotToken(_) => Ok(llm::InferenceFeedback::Continue),
-			});
+			}
+		}
 
-			match res {
-				Ok(_) => {
-					// Send empty token to signal this cycle has ended
-					if tx_response.blocking_send(Ok("".to_string())).is_err() {
-						// Output channel was probably dropped
-						break;
+		tokio::select! {
+			msg = ws.recv() => {
+				let msg = match msg {
+					Some(Ok(msg)) => msg,
+					Some(Err(e)) => {
+						tracing::debug!("WebSocket: receive reported error: {e}");
+						break ChatEnd::Disconnected(None);
 					}
+					None => break ChatEnd::Disconnected(None),
+				};
+
+				match msg {
+					Message::Text(_) | Message::Binary(_) => {
+						let format = *format.get_or_insert_with(|| ChatFormat::negotiate(&msg));
+						let prompt_request = match format.decode(msg) {
+							Ok(ChatClientMessage::Prompt { text, max_tokens, temperature, top_p }) => {
+								tracing::trace!("WebSocket receive prompt text: {text}");
+								PromptRequest { max_tokens, temperature, top_p, ..PromptRequest::new(text) }
+							},
+							Ok(ChatClientMessage::ToolResult { tool, result }) => {
+								tracing::trace!("WebSocket receive result of tool {tool}");
+								let tool_result = Some(ToolResult { tool, result });
+								PromptRequest { tool_result, ..Default::default() }
+							},
+							Ok(ChatClientMessage::Cancel) => {
+								tracing::debug!("WebSocket: cancelling response");
+								cancellation.cancel();
+								continue;
+							},
+							Err(e) => {
+								// Invalid message
+								tracing::warn!("WebSocket: {e}");
+								close_chat(&mut ws, e.close_code(), &e.to_string()).await;
+								break ChatEnd::Ended;
+							}
+						};
+						cancellation = Cancellation::new();
+						let prompt = ChatPrompt { request: prompt_request, cancellation: cancellation.clone() };
+						if prompts.send(prompt).await.is_err() {
+							tracing::warn!("WebSocket: the thread completing the prompts has ended");
+							close_chat(&mut ws, close_code::ERROR, "the chat has ended").await;
+							break ChatEnd::Ended;
+						}
+					},
+					Message::Close(_close_frame) => {
+						_ = ws.close().await;
+						break ChatEnd::Disconnected(None);
+					},
+					Message::Ping(p) => {
+						_ = ws.send(Message::Pong(p)).await;
+					},
+					Message::Pong(_) => {},
 				}
-				Err(e) => {
-					if tx_response.blocking_send(Err(e.to_string())).is_err() {
-						// Output channel was probably dropped
-						break;
+			},
+			response = responses.recv() => {
+				// The thread completing the prompts ends when the session could not be started (after sending the error)
+				let Some(response) = response else {
+					close_chat(&mut ws, close_code::ERROR, "the chat has ended").await;
+					break ChatEnd::Ended;
+				};
+				match format {
+					Some(format) => {
+						if let Err(end) = send_chat_message(&mut ws, format, response).await {
+							break end;
+						}
 					}
+					None => pending.push_back(response),
 				}
 			}
 		}
-		tracing::info!("ending model thread");
-	});
+	};
 
-	tokio::spawn(async move {
-		loop {
-			tokio::select! {
-				msg = ws.recv() => {
-					let Some(msg) = msg else {
-						// WebSocket closed?
-						break;
+	// A chat in a structured format is kept for a while after the client went away, so that it can resume it
+	if let (ChatEnd::Disconnected(unsent), Some(token), Some(user), Some(format @ (ChatFormat::Json | ChatFormat::Msgpack))) =
+		(end, token, user, format)
+	{
+		let unsent = unsent.into_iter().chain(pending).collect();
+		let channels = ChatChannels {
+			prompts,
+			cancellation,
+			responses,
+		};
+		if state.parked_chats.park(token, &user, &task_name, format, channels, unsent) {
+			tracing::info!("WebSocket connection closed; keeping chat to be resumed");
+			return;
+		}
+	}
+	tracing::info!("WebSocket connection closed");
+}
+
+/// Start a thread that completes the prompts of a chat in a new session (started when the first prompt is admitted)
+fn start_chat(state: Arc<Server>, task_name: String, request: SessionRequest, priority: Priority) -> ChatChannels {
+	let (tx_prompt, mut rx_prompt) = tokio::sync::mpsc::channel(16);
+	let (tx_response, rx_response) = tokio::sync::mpsc::channel::<ChatServerMessage>(32);
+	let span = tracing::Span::current();
+	let runtime = tokio::runtime::Handle::current();
+	let queue_update_interval = Duration::from_millis(state.config.live_keep_alive.interval_ms);
+	tokio::task::spawn_blocking(move || {
+		let _entered = span.enter();
+
+		// The session is started when the first prompt is admitted
+		let mut session = None;
+		while let Some(ChatPrompt {
+			request: prompt_request,
+			cancellation,
+		}) = rx_prompt.blocking_recv()
+		{
+			// Each prompt is admitted separately; while it waits, its position in line is sent periodically
+			let _permit = match state.admission.enter(priority) {
+				Ok(permit) => permit,
+				Err(mut ticket) => loop {
+					let status = ticket.status();
+					let queued = ChatServerMessage::Queued {
+						position: status.position,
+						estimated_wait_ms: status.estimated_wait_ms,
 					};
+					if tx_response.blocking_send(queued).is_err() {
+						return;
+					}
+					if let Some(permit) = runtime.block_on(ticket.admit_within(queue_update_interval)) {
+						break permit;
+					}
+				},
+			};
 
-					match msg.unwrap() {
-						Message::Text(prompt) => {
-							tracing::trace!("WebSocket receive prompt text: {prompt}");
-							tx_prompt.send(prompt).await.unwrap();
-						},
-						Message::Close(_close_frame) => {
-							_ = ws.close().await;
-							break;
-						},
-						Message::Binary(_) => {
-							// Invalid binary message
-							_ = ws.close().await;
-							break;
-						},
-						Message::Ping(p) => {
-							_ = ws.send(Message::Pong(p)).await;
-						},
-						Message::Pong(_) => {},
+			let mut started = match session.take() {
+				Some(started) => started,
+				None => match state.backend.start(&task_name, &request, state.backend.clone()) {
+					Ok(started) => started,
+					Err(e) => {
+						_ = tx_response.blocking_send(ChatServerMessage::Error { message: e.to_string() });
+						break;
 					}
 				},
-				response = rx_response.recv() => {
-					match response.unwrap() {
-						Ok(txt) => {
-							if let Err(e) = ws.send(Message::Text(txt)).await {
-								tracing::error!("WebSocket: send reported error: {e}");
-									break;
-							}
-						},
-						Err(e) => {
-							tracing::error!("WebSocket: backend thread reported error: {e}");
-							break;
+			};
+
+			// The prompt is completed on the inference pool, after which the session is handed back for the next prompt
+			let tx_tokens = tx_response.clone();
+			let (started, res) = state.backend.pool.run_blocking(move || {
+				let res = started.complete_cancellable(&prompt_request, &cancellation, |r| match r {
+					InferenceResponse::InferredToken(text) => {
+						if tx_tokens.blocking_send(ChatServerMessage::Token { text }).is_err() {
+							// Connection is likely closed
+							return Ok(llm::InferenceFeedback::Halt);
 						}
+						Ok(llm::InferenceFeedback::Continue)
 					}
+					InferenceResponse::EotToken => Ok(llm::InferenceFeedback::Halt),
+					InferenceResponse::PromptToken(_) | InferenceResponse::SnapshotToken(_) => Ok(llm::InferenceFeedback::Continue),
+				});
+				(started, res)
+			});
+			session = Some(started);
 
+			let message = match res {
+				Ok(Completion {
+					tool_call,
+					usage,
+					finish_reason,
+					..
+				}) => {
+					if let Some(ToolCall { tool, arguments }) = tool_call {
+						if tx_response.blocking_send(ChatServerMessage::ToolCall { tool, arguments }).is_err() {
+							break;
+						}
+					}
+					ChatServerMessage::End {
+						usage,
+						finish_reason: Some(finish_reason),
+					}
 				}
+				Err(e) => ChatServerMessage::Error { message: e.to_string() },
+			};
+			if tx_response.blocking_send(message).is_err() {
+				// Output channel was probably dropped
+				break;
 			}
 		}
+		tracing::info!("ending model thread");
 	});
-	t.await.unwrap();
-	tracing::info!("WebSocket connection closed");
+
+	ChatChannels {
+		prompts: tx_prompt,
+		cancellation: Cancellation::new(),
+		responses: rx_response,
+	}
+}
+
+/// Query parameters of a live stream that override the keep-alive settings of the server
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+struct KeepAliveRequest {
+	/// Interval between keep-alive messages in milliseconds (within the bounds configured for the server)
+	keep_alive_ms: Option<u64>,
+	keep_alive: Option<KeepAliveMessage>,
 }
 
+fn keep_alive(config: &KeepAliveConfig) -> KeepAlive {
+	let keep_alive = KeepAlive::new().interval(Duration::from_millis(config.interval_ms));
+	match config.message {
+		KeepAliveMessage::Comment => keep_alive.text(config.comment.as_str()),
+		KeepAliveMessage::Event => keep_alive.event(Event::default().event("keep-alive")),
+	}
+}
+
+/// Generates a completion for a prompt and streams the tokens as they are generated
+///
+/// The response is a stream of server-sent events (`text/event-stream`). It starts with an `open` event holding the
+/// identifier of the generation (random, unless the client chose one using the `X-Generation-Id` header), which is sent
+/// before any token is generated and can be used to cancel the generation. Each generated token is then sent as a
+/// message with `id: token` that holds the text of the token, as soon as it is generated. When the completion is done,
+/// a `done` event holding a `LiveDone` (the token usage and finish reason) is sent and the stream ends; a stream that
+/// ends without it was cut off. While no token is being sent, the server sends keep-alive messages: comment lines by
+/// default, or `keep-alive` events without data (see the `keep_alive` and `keep_alive_ms` parameters).
+///
+/// When the server is servicing the maximum number of requests, the request waits in line before generation starts.
+/// Meanwhile, a `queued` event holding a `QueueStatus` (the position in line and the estimated waiting time) is sent
+/// after the `open` event and then at every keep-alive interval. When the session cannot be started or generation
+/// fails, an `error` event holding the reason is sent (instead of `done`) and the stream ends.
+#[utoipa::path(
+	get,
+	path = "/v1/task/{task}/live",
+	tag = "tasks",
+	params(("task" = String, Path, description = "Name of the task"), PromptRequest, KeepAliveRequest, PriorityRequest),
+	responses(
+		(status = 200, description = "Stream of server-sent events", content_type = "text/event-stream", body = String),
+		(status = 400, description = "A keep-alive parameter or the generation identifier is invalid, or the identifier is in use", body = crate::api::ErrorResponse),
+		(status = 401, description = "Not authenticated, or not allowed to use the task"),
+		(status = 422, description = "The prompt has segments marked as trusted, which the user is not allowed to do", body = crate::api::ErrorResponse),
+	)
+)]
 async fn sse_task_handler(
 	State(state): State<Arc<Server>>,
 	Path(task_name): Path<String>,
 	Query(request): Query<SessionRequest>,
 	Query(prompt): Query<PromptRequest>,
+	Query(keep_alive_request): Query<KeepAliveRequest>,
+	Query(priority): Query<PriorityRequest>,
+	Extension(claims): Extension<JwtClaims>,
+	headers: HeaderMap,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, BackendError> {
 	debug!("New live connection for task '{}'", task_name.as_str());
+	let keep_alive_config = state
+		.config
+		.live_keep_alive
+		.with_overrides(keep_alive_request.keep_alive_ms, keep_alive_request.keep_alive)
+		.map_err(|e| poly_backend::types::BackendError::InvalidParameter(String::from("keep_alive_ms"), e))?;
+	claims.check_prompt(&prompt)?;
+
+	// Problems with the task are reported before the stream starts; the session itself is only started once the request
+	// has been admitted
+	state.backend.check_task_available(&task_name)?;
 
+	// Identifies the generation, so that clients can tell that the stream was opened before any token is generated, and
+	// can cancel it
+	let generation = start_generation(&state, &task_name, &headers)?;
+	debug!(generation_id = generation.id(), "starting live generation");
+	let priority = request_priority(&state, &claims, Some(&task_name), priority.priority);
+
+	// The channel is bounded, so that generation waits for a client that does not keep up
 	let (tx, mut rx) = tokio::sync::mpsc::channel(32);
-	let active = Arc::new(AtomicBool::new(true));
-	let active_clone = active.clone();
+	let queue_update_interval = Duration::from_millis(keep_alive_config.interval_ms);
+	let span = tracing::Span::current();
 
-	let mut session = state.backend.start(&task_name, &request, state.backend.clone()).unwrap();
+	let stream_guard = state.live_streams.enter();
+	let stream = stream! {
+		let _stream_guard = stream_guard;
+		yield Ok(Event::default().event("open").data(generation.id()));
 
-	tokio::task::spawn_blocking(move || {
-		session.complete(&prompt, |r| -> Result<_, poly_backend::types::BackendError> {
-			match r {
-				llm::InferenceResponse::InferredToken(t) => {
-					let tx = tx.clone();
+		// While the request waits to be admitted, its position in line is sent periodically
+		let permit = match state.admission.enter(priority) {
+			Ok(permit) => permit,
+			Err(mut ticket) => loop {
+				yield Ok(queued_event(&ticket.status()));
+				if let Some(permit) = ticket.admit_within(queue_update_interval).await {
+					break permit;
+				}
+			},
+		};
 
-					// Do not continue when client has disconnected
-					if tx.is_closed() || !active_clone.load(Ordering::SeqCst) {
+		let mut session = match state.backend.start(&task_name, &request, state.backend.clone()) {
+			Ok(session) => session,
+			Err(e) => {
+				yield Ok(Event::default().event("error").data(e.to_string()));
+				return;
+			}
+		};
+
+		state.backend.pool.spawn(move || {
+			let _entered = span.enter();
+			let _permit = permit;
+			let result = session.complete_cancellable(&prompt, generation.cancellation(), |r| match r {
+				InferenceResponse::InferredToken(t) => {
+					// Sending fails when the client has disconnected (and the stream was dropped)
+					if tx.blocking_send(LiveMessage::Token(t)).is_err() {
 						debug!("client has disconnected live session, halting generation");
 						return Ok(llm::InferenceFeedback::Halt);
 					}
-					tokio::spawn(async move {
-						// This may fail when a client disconnects while we are generating a token, but we don't care (anymore).
-						tx.send(t).await
-					});
 					Ok(llm::InferenceFeedback::Continue)
 				}
 				_ => Ok(llm::InferenceFeedback::Continue),
-			}
-		})
-	});
-
-	struct Guard {
-		flag: Arc<AtomicBool>,
-	}
-	impl Drop for Guard {
-		fn drop(&mut self) {
-			tracing::info!("SSE disconnected");
-			self.flag.store(false, Ordering::SeqCst);
-		}
-	}
+			});
+			let message = match result {
+				Ok(completion) => LiveMessage::Done(LiveDone {
+					usage: completion.usage,
+					finish_reason: completion.finish_reason,
+					duration_ms: completion.duration.as_millis() as u64,
+				}),
+				Err(e) => LiveMessage::Error(e.to_string()),
+			};
+			_ = tx.blocking_send(message);
+		});
 
-	let stream = stream! {
-		let _guard = Guard{ flag: active };
 		loop {
 			match rx.recv().await {
-				Some(token) => {
-					let evt = Event::default().id("token").data(token);
-					yield Ok(evt);
-				},
-				None => return
+				Some(LiveMessage::Token(token)) => {
+					yield Ok(Event::default().id("token").data(token));
+				}
+				Some(LiveMessage::Done(done)) => {
+					yield Ok(Event::default().event("done").json_data(done).expect("done event can be serialized"));
+					return;
+				}
+				Some(LiveMessage::Error(message)) => {
+					yield Ok(Event::default().event("error").data(message));
+					return;
+				}
+				// The job ended without saying how, which means it panicked
+				None => {
+					yield Ok(Event::default().event("error").data("generation failed unexpectedly"));
+					return;
+				}
 			}
 		}
 	};
 
-	Ok(Sse::new(stream).keep_alive(
-		axum::response::sse::KeepAlive::new()
-			.interval(Duration::from_secs(1))
-			.text("keep-alive-text"),
-	))
+	Ok(Sse::new(stream).keep_alive(keep_alive(&keep_alive_config)))
+}
+
+/// What the job generating a completion for a live stream sends to the stream
+enum LiveMessage {
+	Token(String),
+	Done(LiveDone),
+	Error(String),
+}
+
+/// Event sent on a live stream while the request waits to be admitted
+fn queued_event(status: &QueueStatus) -> Event {
+	Event::default()
+		.event("queued")
+		.json_data(status)
+		.expect("queue status can be serialized")
 }
 
 /// Middleware that checks whether the user has access to a certain task.
 pub async fn authorize<T>(
-	Path(task_name): Path<String>,
+	Path(params): Path<HashMap<String, String>>,
 	Extension(claims): Extension<JwtClaims>,
 	req: Request<T>,
 	next: Next<T>,
 ) -> Result<impl IntoResponse, StatusCode> {
-	if let Some(tasks) = &claims.tasks {
-		if !tasks.contains(&task_name) {
-			return Err(StatusCode::UNAUTHORIZED);
-		}
+	if !params.get("task").is_some_and(|task_name| claims.allows_task(task_name)) {
+		return Err(StatusCode::UNAUTHORIZED);
 	}
 
 	Ok(next.run(req).await)
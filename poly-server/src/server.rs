@@ -1,19 +1,65 @@
-use crate::config::Config;
-use std::sync::Arc;
-use tokio::sync::mpsc::{channel, Sender};
+use crate::{
+	admission::Admission,
+	config::{Config, FetchConfig},
+	generations::Generations,
+	net::{HostPattern, ListenAddress, TrustedProxies},
+	ratelimit::IpRateLimiter,
+	resume::ParkedChats,
+};
+use axum::Router;
+use futures_util::future::try_join_all;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::{
+	sync::mpsc::{channel, Sender},
+	task::JoinHandle,
+};
 
-use poly_backend::backend::Backend;
+use poly_backend::{backend::Backend, config::ConfigProblem, memory::ItemMetadata, stats::Gauge, types::ReloadReport};
+use poly_extract::fetch::FetchOptions;
 
 pub struct Server {
 	pub backend: Arc<Backend>,
 	pub config: Config,
 	ingest_sender: Sender<IngestItem>,
+
+	/// Limits the number of requests serviced at the same time (see [`Config::max_concurrent`])
+	pub admission: Arc<Admission>,
+
+	/// Number of currently connected chat WebSockets
+	pub chats: Arc<Gauge>,
+
+	/// Chats whose WebSocket closed, kept so that clients can resume them (see [`Config::chat_resume`])
+	pub parked_chats: Arc<ParkedChats>,
+
+	/// Completions being generated, which clients may cancel
+	pub generations: Arc<Generations>,
+
+	/// Number of currently open SSE streams
+	pub live_streams: Arc<Gauge>,
+
+	/// Proxies that are trusted to report client addresses
+	pub trusted_proxies: TrustedProxies,
+
+	/// Hosts requests are accepted for (when `None`, any host is accepted)
+	pub allowed_hosts: Option<Vec<HostPattern>>,
+
+	/// Options for fetching documents by URL (when `None`, documents cannot be fetched)
+	pub fetch_options: Option<FetchOptions>,
+
+	/// Limits the rate of requests of clients that did not authenticate (when `None`, they are not limited)
+	pub ip_rate_limiter: Option<IpRateLimiter>,
 }
 
 #[derive(Debug)]
 pub struct IngestItem {
 	pub memory_name: String,
-	pub plaintext: String,
+
+	/// Sections of the document, which are chunked separately (see [`Backend::memorize_sections`])
+	pub sections: Vec<String>,
+
+	/// Metadata to store with each chunk
+	pub metadata: ItemMetadata,
 }
 
 impl Server {
@@ -25,7 +71,8 @@ impl Server {
 			tracing::info!("starting ingest worker");
 			while let Some(item) = rx.recv().await {
 				tracing::trace!(?item, "ingest");
-				match ingest_backend.memorize(&item.memory_name, &item.plaintext).await {
+				let sections: Vec<&str> = item.sections.iter().map(String::as_str).collect();
+				match ingest_backend.memorize_sections(&item.memory_name, &sections, &item.metadata).await {
 					Ok(_) => {}
 					Err(e) => tracing::error!("error memorizing: {e}"),
 				}
@@ -33,15 +80,175 @@ impl Server {
 			tracing::info!("ending ingest worker");
 		});
 
+		// Admitting more requests than there are inference threads would only have them wait for a thread, without being
+		// told their position in line
+		let max_concurrent = config.max_concurrent.min(backend.pool.size());
+		if max_concurrent < config.max_concurrent {
+			tracing::info!(
+				max_concurrent,
+				configured = config.max_concurrent,
+				"servicing at most as many requests at the same time as there are inference threads"
+			);
+		}
+		let admission = Arc::new(Admission::new(max_concurrent, Duration::from_secs(config.priority_aging_secs)));
+		let chats = Arc::new(Gauge::new("chats", config.soft_connection_limit));
+		let parked_chats = ParkedChats::new(config.chat_resume.clone());
+		let live_streams = Arc::new(Gauge::new("live_streams", config.soft_connection_limit));
+		let trusted_proxies = config.trusted_proxies.clone();
+		let allowed_hosts = config.allowed_hosts.clone();
+		let fetch_options = config.fetch.as_ref().map(FetchConfig::options);
+		let ip_rate_limiter = config.ip_rate_limit.clone().map(IpRateLimiter::new);
+
 		Server {
 			backend,
 			config,
 			ingest_sender: tx,
+			admission,
+			chats,
+			parked_chats,
+			generations: Generations::new(),
+			live_streams,
+			trusted_proxies,
+			allowed_hosts,
+			fetch_options,
+			ip_rate_limiter,
 		}
 	}
 
+	/// Read the configuration file again and apply changes to tasks (see [`Backend::reload`]), and try again to load the
+	/// models that could not be loaded before (see [`Backend::retry_unavailable_models`]). Changes to other settings only
+	/// take effect after a restart.
+	pub async fn reload(&self) -> Result<ReloadReport, Vec<ConfigProblem>> {
+		let Some(ref path) = self.config.path else {
+			return Err(vec![ConfigProblem::new("config_path", "configuration was not read from a file")]);
+		};
+		let config = Config::from_file(path).map_err(|e| vec![ConfigProblem::new("config_path", e.to_string())])?;
+		let mut report = self.backend.reload(&config.backend_config)?;
+		report.loaded_models = self.backend.retry_unavailable_models().await;
+		report.unavailable_models = self.backend.unavailable_models();
+		Ok(report)
+	}
+
 	/// Enqueue an item for ingest
 	pub async fn ingest(&self, item: IngestItem) {
 		self.ingest_sender.send(item).await.unwrap()
 	}
 }
+
+#[derive(Error, Debug)]
+pub enum ServeError {
+	#[error("cannot listen on {address}: {error}")]
+	Bind { address: ListenAddress, error: hyper::Error },
+
+	#[error("cannot listen on {address}: {error}")]
+	Io { address: ListenAddress, error: std::io::Error },
+
+	#[error("server error: {0}")]
+	Server(#[from] hyper::Error),
+}
+
+/// A server listening on one or more addresses (see [serve])
+pub struct Listening {
+	/// The addresses the server is listening on. For TCP addresses with port 0, this contains the port that was assigned.
+	pub addresses: Vec<ListenAddress>,
+	servers: Vec<JoinHandle<Result<(), hyper::Error>>>,
+}
+
+impl Listening {
+	/// Wait until the server stops, which only happens when an error occurs on one of the addresses
+	pub async fn wait(self) -> Result<(), ServeError> {
+		try_join_all(self.servers.into_iter().map(|server| async { server.await.expect("server task") })).await?;
+		Ok(())
+	}
+}
+
+/// Serve a router on all of the specified addresses. Returns as soon as the server is listening on all addresses.
+pub async fn serve(router: Router, addresses: &[ListenAddress]) -> Result<Listening, ServeError> {
+	let mut listening = Listening {
+		addresses: vec![],
+		servers: vec![],
+	};
+
+	for address in addresses {
+		match address {
+			ListenAddress::Tcp(addr) => {
+				let server = axum::Server::try_bind(addr)
+					.map_err(|error| ServeError::Bind {
+						address: address.clone(),
+						error,
+					})?
+					.serve(router.clone().into_make_service_with_connect_info::<SocketAddr>());
+				listening.addresses.push(ListenAddress::Tcp(server.local_addr()));
+				listening.servers.push(tokio::spawn(server));
+			}
+
+			#[cfg(unix)]
+			ListenAddress::Unix(path) => {
+				let listener = unix::bind(path).map_err(|error| ServeError::Io {
+					address: address.clone(),
+					error,
+				})?;
+				let server = axum::Server::builder(listener).serve(router.clone().into_make_service());
+				listening.addresses.push(address.clone());
+				listening.servers.push(tokio::spawn(server));
+			}
+
+			#[cfg(not(unix))]
+			ListenAddress::Unix(_) => {
+				return Err(ServeError::Io {
+					address: address.clone(),
+					error: std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix domain sockets are not supported"),
+				});
+			}
+		}
+		tracing::info!("listening on {}", listening.addresses.last().unwrap());
+	}
+
+	Ok(listening)
+}
+
+#[cfg(unix)]
+mod unix {
+	use std::{
+		io::{Error, ErrorKind},
+		os::unix::fs::FileTypeExt,
+		path::Path,
+		pin::Pin,
+		task::{ready, Context, Poll},
+	};
+
+	use tokio::net::{UnixListener, UnixStream};
+
+	pub struct UnixAccept(UnixListener);
+
+	/// Bind to a Unix domain socket. A socket file that is left behind by an earlier process (i.e. one that nothing is
+	/// listening on anymore) is removed first. Anything else at the path is left alone, and binding fails.
+	pub fn bind(path: &Path) -> std::io::Result<UnixAccept> {
+		match std::fs::symlink_metadata(path) {
+			Ok(metadata) if !metadata.file_type().is_socket() => {
+				return Err(Error::new(
+					ErrorKind::AlreadyExists,
+					format!("{} exists and is not a socket", path.display()),
+				));
+			}
+			Ok(_) => {
+				if std::os::unix::net::UnixStream::connect(path).is_err() {
+					std::fs::remove_file(path)?;
+				}
+			}
+			Err(e) if e.kind() == ErrorKind::NotFound => {}
+			Err(e) => return Err(e),
+		}
+		Ok(UnixAccept(UnixListener::bind(path)?))
+	}
+
+	impl hyper::server::accept::Accept for UnixAccept {
+		type Conn = UnixStream;
+		type Error = std::io::Error;
+
+		fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+			let (stream, _addr) = ready!(self.0.poll_accept(cx))?;
+			Poll::Ready(Some(Ok(stream)))
+		}
+	}
+}
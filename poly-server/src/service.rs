@@ -0,0 +1,197 @@
+//! Integration with service managers. Under systemd with `Type=notify` (see `sd_notify(3)`), llmd reports its status
+//! while loading models, that it is ready once it serves requests and that it is stopping when it shuts down. When the
+//! service has a watchdog (`WatchdogSec=`), keep-alives are sent for as long as the backend responds. Notifications are
+//! only sent on Linux, when systemd passes a notification socket (`NOTIFY_SOCKET`). For other supervisors, the process
+//! ID can be written to a file (see [`crate::config::Config::pid_file`]).
+
+use std::{
+	ffi::OsStr,
+	io,
+	path::{Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
+
+use poly_backend::{backend::Backend, types::PromptRequest};
+
+/// Send a notification to the service manager: one or more `KEY=VALUE` assignments separated by newlines. Does nothing
+/// when there is no notification socket. Failures are logged, as the service can go on without notifying.
+pub fn notify(state: &str) {
+	let Some(socket) = notify_socket() else {
+		return;
+	};
+	match send(&socket, state) {
+		Ok(()) => tracing::debug!(state, "notified service manager"),
+		Err(e) => tracing::warn!("cannot notify service manager: {e}"),
+	}
+}
+
+/// Tell the service manager that the service is ready to handle requests
+pub fn ready(status: &str) {
+	notify(&format!("READY=1\nSTATUS={status}"));
+}
+
+/// Tell the service manager what the service is doing
+pub fn status(status: &str) {
+	notify(&format!("STATUS={status}"));
+}
+
+/// Tell the service manager that the service is shutting down
+pub fn stopping() {
+	notify("STOPPING=1\nSTATUS=stopping");
+}
+
+#[cfg(target_os = "linux")]
+fn notify_socket() -> Option<std::ffi::OsString> {
+	std::env::var_os("NOTIFY_SOCKET").filter(|socket| !socket.is_empty())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify_socket() -> Option<std::ffi::OsString> {
+	None
+}
+
+/// Send a notification to a socket, given as path or as name in the abstract namespace (prefixed with '@')
+#[cfg(target_os = "linux")]
+fn send(socket: &OsStr, state: &str) -> io::Result<()> {
+	use std::os::{
+		linux::net::SocketAddrExt,
+		unix::{
+			ffi::OsStrExt,
+			net::{SocketAddr, UnixDatagram},
+		},
+	};
+
+	let address = match socket.as_bytes().strip_prefix(b"@") {
+		Some(name) => SocketAddr::from_abstract_name(name)?,
+		None => SocketAddr::from_pathname(socket)?,
+	};
+	UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+	Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_socket: &OsStr, _state: &str) -> io::Result<()> {
+	Ok(())
+}
+
+/// Describe the progress of loading models as reported by [`Backend::from`] (which loads models in order of their
+/// names), e.g. "loading model chat (2/3): 25%"
+pub fn loading_status(model_names: &[String], fraction: f64) -> String {
+	if model_names.is_empty() || fraction >= 1.0 {
+		return String::from("models loaded");
+	}
+	let scaled = fraction.max(0.0) * model_names.len() as f64;
+	let index = (scaled as usize).min(model_names.len() - 1);
+	let percentage = ((scaled - index as f64) * 100.0) as u32;
+	format!(
+		"loading model {} ({}/{}): {percentage}%",
+		model_names[index],
+		index + 1,
+		model_names.len()
+	)
+}
+
+/// Interval at which keep-alives should be sent to the watchdog (half its timeout), when it is enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+	notify_socket()?;
+	if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+		if pid.parse::<u32>().ok() != Some(std::process::id()) {
+			return None;
+		}
+	}
+	let timeout_us: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+	(timeout_us > 0).then(|| Duration::from_micros(timeout_us / 2))
+}
+
+/// Send keep-alives to the watchdog for as long as the backend responds to a trivial request (tokenizing a short text
+/// using one of the loaded models). While it does not respond within the interval, no keep-alives are sent, so that
+/// the service manager restarts the service when this lasts longer than the watchdog timeout.
+pub async fn watchdog(backend: Arc<Backend>, interval: Duration) {
+	let mut ticks = tokio::time::interval(interval);
+	ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+	loop {
+		ticks.tick().await;
+		let backend = backend.clone();
+		let check = tokio::task::spawn_blocking(move || responds(&backend));
+		match tokio::time::timeout(interval, check).await {
+			Ok(Ok(Ok(()))) => notify("WATCHDOG=1"),
+			Ok(Ok(Err(e))) => tracing::error!("backend does not respond, not notifying watchdog: {e}"),
+			Ok(Err(e)) => tracing::error!("backend check failed, not notifying watchdog: {e}"),
+			Err(_) => tracing::error!("backend did not respond in time, not notifying watchdog"),
+		}
+	}
+}
+
+fn responds(backend: &Backend) -> Result<(), String> {
+	let unavailable_models = backend.unavailable_models();
+	let model_name = backend
+		.config
+		.models
+		.keys()
+		.filter(|model_name| !unavailable_models.contains_key(*model_name))
+		.min();
+	match model_name {
+		Some(model_name) => backend
+			.tokenize(model_name, &PromptRequest::new("ping"))
+			.map(|_| ())
+			.map_err(|e| e.to_string()),
+		None => Ok(()),
+	}
+}
+
+/// A file holding the ID of the process, which is removed when dropped
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+	pub fn create(path: &Path) -> io::Result<PidFile> {
+		std::fs::write(path, format!("{}\n", std::process::id()))?;
+		Ok(PidFile(path.to_path_buf()))
+	}
+}
+
+impl Drop for PidFile {
+	fn drop(&mut self) {
+		if let Err(e) = std::fs::remove_file(&self.0) {
+			tracing::warn!("cannot remove PID file {}: {e}", self.0.display());
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{loading_status, PidFile};
+
+	#[test]
+	fn test_loading_status() {
+		let model_names = vec![String::from("chat"), String::from("embed")];
+		assert_eq!(loading_status(&model_names, 0.0), "loading model chat (1/2): 0%");
+		assert_eq!(loading_status(&model_names, 0.125), "loading model chat (1/2): 25%");
+		assert_eq!(loading_status(&model_names, 0.75), "loading model embed (2/2): 50%");
+		assert_eq!(loading_status(&model_names, 1.0), "models loaded");
+		assert_eq!(loading_status(&[], 0.5), "models loaded");
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_send() {
+		use std::os::unix::net::UnixDatagram;
+
+		let path = std::env::temp_dir().join(format!("poly-notify-{}.sock", std::process::id()));
+		let receiver = UnixDatagram::bind(&path).unwrap();
+		super::send(path.as_os_str(), "READY=1\nSTATUS=serving").unwrap();
+		let mut buffer = [0u8; 64];
+		let n = receiver.recv(&mut buffer).unwrap();
+		assert_eq!(&buffer[..n], b"READY=1\nSTATUS=serving");
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_pid_file() {
+		let path = std::env::temp_dir().join(format!("poly-pid-{}.pid", std::process::id()));
+		let pid_file = PidFile::create(&path).unwrap();
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+		drop(pid_file);
+		assert!(!path.exists());
+	}
+}
@@ -0,0 +1,117 @@
+use axum::http::Request;
+use tracing::Span;
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, prelude::*, EnvFilter};
+
+use crate::config::TelemetryConfig;
+
+/// Set up the global tracing subscriber. When telemetry export is configured (and the `otel` feature is enabled), spans
+/// are also exported using OTLP. When `quiet` is set, log messages are written to standard error instead of standard
+/// output, and only warnings and errors are logged unless `RUST_LOG` says otherwise (for commands that write their
+/// result to standard output).
+pub fn init(telemetry: Option<&TelemetryConfig>, quiet: bool) {
+	let (default_level, writer) = if quiet {
+		("warn", BoxMakeWriter::new(std::io::stderr))
+	} else {
+		("info", BoxMakeWriter::new(std::io::stdout))
+	};
+	let registry = tracing_subscriber::registry()
+		.with(EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(default_level)).unwrap())
+		.with(tracing_subscriber::fmt::layer().with_writer(writer));
+
+	#[cfg(feature = "otel")]
+	{
+		let otel_layer = telemetry.map(|t| tracing_opentelemetry::layer().with_tracer(otel::tracer(t)));
+		registry.with(otel_layer).init();
+		if let Some(t) = telemetry {
+			tracing::info!(
+				endpoint = t.endpoint,
+				sample_ratio = t.sample_ratio,
+				"exporting spans using OpenTelemetry"
+			);
+		}
+	}
+
+	#[cfg(not(feature = "otel"))]
+	{
+		registry.init();
+		if telemetry.is_some() {
+			tracing::warn!("telemetry is configured but llmd was built without the 'otel' feature; spans will not be exported");
+		}
+	}
+}
+
+/// Flush any spans that have not been exported yet
+pub fn shutdown() {
+	#[cfg(feature = "otel")]
+	opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Create the span for an incoming HTTP request. When spans are exported, the span continues the trace indicated by the
+/// `traceparent` header (if any). Note that the query string is not recorded as it may contain prompts or API keys.
+pub fn make_request_span(request: &Request<axum::body::Body>) -> Span {
+	let span = tracing::info_span!(
+		"request",
+		method = %request.method(),
+		path = request.uri().path(),
+		version = ?request.version(),
+		client_ip = tracing::field::Empty,
+	);
+
+	#[cfg(feature = "otel")]
+	otel::set_parent(&span, request.headers());
+
+	span
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+	use axum::http::HeaderMap;
+	use opentelemetry::{
+		global,
+		propagation::Extractor,
+		sdk::{
+			propagation::TraceContextPropagator,
+			trace::{self, Sampler, Tracer},
+			Resource,
+		},
+		KeyValue,
+	};
+	use opentelemetry_otlp::WithExportConfig;
+	use tracing::Span;
+	use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+	use crate::config::TelemetryConfig;
+
+	pub fn tracer(config: &TelemetryConfig) -> Tracer {
+		global::set_text_map_propagator(TraceContextPropagator::new());
+
+		opentelemetry_otlp::new_pipeline()
+			.tracing()
+			.with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(&config.endpoint))
+			.with_trace_config(
+				trace::config()
+					.with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+					.with_resource(Resource::new(vec![KeyValue::new("service.name", config.service_name.clone())])),
+			)
+			.install_batch(opentelemetry::runtime::Tokio)
+			.expect("install OpenTelemetry tracer")
+	}
+
+	struct HeaderExtractor<'a>(&'a HeaderMap);
+
+	impl<'a> Extractor for HeaderExtractor<'a> {
+		fn get(&self, key: &str) -> Option<&str> {
+			self.0.get(key).and_then(|v| v.to_str().ok())
+		}
+
+		fn keys(&self) -> Vec<&str> {
+			self.0.keys().map(|k| k.as_str()).collect()
+		}
+	}
+
+	/// Link the span to the trace context found in the headers (i.e. `traceparent`)
+	pub fn set_parent(span: &Span, headers: &HeaderMap) {
+		let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+		span.set_parent(parent);
+	}
+}
@@ -0,0 +1,35 @@
+use std::process::Command;
+
+/// Run `llmd check` with the given configuration, returning the exit code and standard error
+fn llmd_check(name: &str, config: &str) -> (Option<i32>, String) {
+	let config_path = std::env::temp_dir().join(format!("poly-server-check-{name}-{}.toml", std::process::id()));
+	std::fs::write(&config_path, config).unwrap();
+
+	let output = Command::new(env!("CARGO_BIN_EXE_llmd"))
+		.arg("--config-path")
+		.arg(&config_path)
+		.arg("check")
+		.output()
+		.unwrap();
+	_ = std::fs::remove_file(&config_path);
+	(output.status.code(), String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn test_check_network_settings() {
+	let (code, _) = llmd_check("valid", "trusted_proxies = [\"10.0.0.0/8\"]\nallowed_hosts = [\"localhost\"]\n");
+	assert_eq!(code, Some(0));
+
+	// Settings that cannot be parsed are reported as problems, naming the setting
+	for (name, config, key) in [
+		("proxies", "trusted_proxies = [\"10.0.0.0/33\"]\n", "trusted_proxies"),
+		("hosts", "allowed_hosts = [\"**\"]\n", "allowed_hosts"),
+		("origins", "allowed_origins = [\"\"]\n", "allowed_origins"),
+		("fetch", "[fetch]\nallowed_hosts = [\"exa mple.com\"]\n", "allowed_hosts"),
+	] {
+		let (code, stderr) = llmd_check(name, config);
+		assert_eq!(code, Some(1), "{name}");
+		assert!(stderr.contains("1 problem(s) found"), "{name}: {stderr}");
+		assert!(stderr.contains(key), "{name}: {stderr}");
+	}
+}
@@ -0,0 +1,76 @@
+#![cfg(feature = "grpc")]
+
+use std::sync::Arc;
+
+use poly_backend::{backend::Backend, config::Secret};
+use poly_server::{
+	api::JwtClaims,
+	config::{Config, JwtPrivateKey},
+	grpc::{
+		self,
+		proto::{poly_client::PolyClient, RecallRequest, TokenizeRequest},
+	},
+	server::Server,
+};
+use tonic::{transport::Channel, Code, Request};
+
+async fn client(config: Config) -> PolyClient<Channel> {
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let address = grpc::serve(state, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+	PolyClient::connect(format!("http://{address}")).await.unwrap()
+}
+
+fn with_token<T>(message: T, token: &str) -> Request<T> {
+	let mut request = Request::new(message);
+	request.metadata_mut().insert("authorization", format!("Bearer {token}").parse().unwrap());
+	request
+}
+
+fn tokenize(model: &str) -> TokenizeRequest {
+	TokenizeRequest {
+		model: model.to_string(),
+		prompt: String::from("Hello"),
+	}
+}
+
+#[tokio::test]
+async fn test_grpc_auth() {
+	let mut config = Config::default();
+	config.backend_config.cache_path = Some(std::env::temp_dir().join("poly-server-test"));
+	config.allowed_keys = vec![Secret::new("secret")];
+	let jwt_key = JwtPrivateKey::Symmetric(Secret::new("jwt-secret"));
+	config.jwt_private_key = Some(jwt_key.clone());
+	let mut client = client(config).await;
+
+	// Calls without a valid token are refused
+	let error = client.tokenize(tokenize("gpt2")).await.unwrap_err();
+	assert_eq!(error.code(), Code::Unauthenticated);
+	let error = client.tokenize(with_token(tokenize("gpt2"), "wrong")).await.unwrap_err();
+	assert_eq!(error.code(), Code::Unauthenticated);
+
+	// Static keys may use any model, and errors of the backend are reported with their kind
+	let error = client.tokenize(with_token(tokenize("gpt2"), "secret")).await.unwrap_err();
+	assert_eq!(error.code(), Code::NotFound);
+	assert_eq!(error.metadata().get("poly-error").unwrap(), "model_not_found");
+
+	let recall = RecallRequest {
+		memory: String::from("missing"),
+		prompt: String::from("Hello"),
+		n: None,
+	};
+	let error = client.recall(with_token(recall, "secret")).await.unwrap_err();
+	assert_eq!(error.code(), Code::NotFound);
+
+	// A JWT may restrict the models that can be used
+	let claims = JwtClaims {
+		exp: Some(u32::MAX as usize),
+		models: Some(vec![String::from("gpt2")]),
+		..Default::default()
+	};
+	let jwt = jsonwebtoken::encode(&jsonwebtoken::Header::new(jwt_key.algorithm()), &claims, &jwt_key.encoding_key()).unwrap();
+	let error = client.tokenize(with_token(tokenize("other"), &jwt)).await.unwrap_err();
+	assert_eq!(error.code(), Code::PermissionDenied);
+	let error = client.tokenize(with_token(tokenize("gpt2"), &jwt)).await.unwrap_err();
+	assert_eq!(error.code(), Code::NotFound);
+}
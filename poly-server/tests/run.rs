@@ -0,0 +1,50 @@
+use std::process::Command;
+
+/// Run `llmd run` with a configuration holding a single GPT-2 task, returning the exit code and standard output
+fn llmd_run(args: &[&str]) -> (Option<i32>, String) {
+	let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/gpt2.bin");
+	let config_path = std::env::temp_dir().join(format!("poly-server-run-{}.toml", std::process::id()));
+	std::fs::write(
+		&config_path,
+		format!(
+			r#"
+			[models.gpt2]
+			architecture = "gpt2"
+			model_path = "{model_path}"
+
+			[tasks.complete]
+			model = "gpt2"
+			max_tokens = 12
+			"#
+		),
+	)
+	.unwrap();
+
+	let output = Command::new(env!("CARGO_BIN_EXE_llmd"))
+		.arg("--config-path")
+		.arg(&config_path)
+		.arg("run")
+		.args(args)
+		.output()
+		.unwrap();
+	(output.status.code(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn test_run() {
+	// With a seed, the output is the same every time; the exit code reflects the finish reason (here: max_tokens)
+	let args = ["--task", "complete", "--prompt", "Once upon a time", "--max-tokens", "5", "--seed", "42"];
+	let (code, first) = llmd_run(&args);
+	assert_eq!(code, Some(3));
+	assert!(!first.trim().is_empty());
+	let (_, second) = llmd_run(&args);
+	assert_eq!(first, second);
+
+	// Invalid parameters make the completion fail; an unknown task or a missing prompt is a usage error
+	let (code, _) = llmd_run(&["--task", "complete", "--prompt", "Hello", "--temperature", "5"]);
+	assert_eq!(code, Some(1));
+	let (code, _) = llmd_run(&["--task", "missing", "--prompt", "Hello"]);
+	assert_eq!(code, Some(2));
+	let (code, _) = llmd_run(&["--task", "complete"]);
+	assert_eq!(code, Some(2));
+}
@@ -0,0 +1,343 @@
+use std::sync::Arc;
+
+use poly_backend::backend::Backend;
+use poly_server::{
+	config::Config,
+	net::ListenAddress,
+	routes,
+	server::{serve, Server},
+};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+
+#[tokio::test]
+async fn test_serve_ephemeral_port() {
+	let mut config = Config::default();
+	config.backend_config.cache_path = Some(std::env::temp_dir().join("poly-server-test"));
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+
+	let addresses = ["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()];
+	let listening = serve(routes::router(state), &addresses).await.unwrap();
+	assert_eq!(listening.addresses.len(), 2);
+
+	for address in &listening.addresses {
+		let ListenAddress::Tcp(addr) = address else {
+			panic!("unexpected address {address}");
+		};
+		assert_ne!(addr.port(), 0);
+
+		let mut stream = TcpStream::connect(addr).await.unwrap();
+		stream.write_all(b"GET /status HTTP/1.0\r\nHost: localhost\r\n\r\n").await.unwrap();
+		let mut response = String::new();
+		stream.read_to_string(&mut response).await.unwrap();
+		assert!(response.contains(" 200 OK"), "unexpected response: {response}");
+		assert!(response.ends_with(r#"{"status":"ok"}"#), "unexpected response: {response}");
+	}
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_serve_unix_socket() {
+	let mut config = Config::default();
+	config.backend_config.cache_path = Some(std::env::temp_dir().join("poly-server-test"));
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let router = routes::router(Arc::new(Server::new(backend, config)));
+	let dir = std::env::temp_dir().join(format!("poly-server-unix-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let path = dir.join("llmd.sock");
+	let address = ListenAddress::Unix(path.clone());
+
+	// A file that is not a socket is never removed to make room for one
+	std::fs::write(&path, "important").unwrap();
+	assert!(serve(router.clone(), &[address.clone()]).await.is_err());
+	assert_eq!(std::fs::read_to_string(&path).unwrap(), "important");
+	std::fs::remove_file(&path).unwrap();
+
+	// A socket left behind by an earlier process is replaced, but one that is still in use is not
+	drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+	let _listening = serve(router.clone(), &[address.clone()]).await.unwrap();
+	assert!(serve(router, &[address]).await.is_err());
+	assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+	_ = std::fs::remove_dir_all(&dir);
+}
+
+/// Send a request over HTTP/1.0, returning the status line and body of the response
+async fn request(address: &ListenAddress, method: &str, path: &str) -> (String, String) {
+	request_with_headers(address, method, path, "").await
+}
+
+/// Like [request], with additional header lines (each ending in "\r\n")
+async fn request_with_headers(address: &ListenAddress, method: &str, path: &str, headers: &str) -> (String, String) {
+	request_with_body(address, method, path, headers, "").await
+}
+
+/// Like [request_with_headers], with a body
+async fn request_with_body(address: &ListenAddress, method: &str, path: &str, headers: &str, body: &str) -> (String, String) {
+	let ListenAddress::Tcp(addr) = address else {
+		panic!("unexpected address {address}");
+	};
+	let mut stream = TcpStream::connect(addr).await.unwrap();
+	let request = format!(
+		"{method} {path} HTTP/1.0\r\nHost: localhost\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+		body.len()
+	);
+	stream.write_all(request.as_bytes()).await.unwrap();
+	let mut response = String::new();
+	stream.read_to_string(&mut response).await.unwrap();
+	let (head, body) = response.split_once("\r\n\r\n").unwrap();
+	(head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+#[tokio::test]
+async fn test_serve_degraded() {
+	let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/gpt2.bin");
+	let dir = std::env::temp_dir().join(format!("poly-server-degraded-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let missing_path = dir.join("missing.bin");
+	let config_path = dir.join("config.toml");
+	std::fs::write(
+		&config_path,
+		format!(
+			r#"
+			public = true
+
+			[models.gpt2]
+			architecture = "gpt2"
+			model_path = "{model_path}"
+
+			[models.missing]
+			architecture = "gpt2"
+			model_path = "{}"
+
+			[tasks.complete]
+			model = "gpt2"
+			max_tokens = 1
+
+			[tasks.broken]
+			model = "missing"
+			"#,
+			missing_path.display()
+		),
+	)
+	.unwrap();
+
+	// The server starts even though one of the models cannot be loaded
+	let config = Config::from_file(&config_path).unwrap();
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state.clone()), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+	let address = &listening.addresses[0];
+
+	for path in ["/status", "/readyz"] {
+		let (status, body) = request(address, "GET", path).await;
+		assert!(status.contains(" 200 "), "unexpected status: {status}");
+		let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+		assert_eq!(body["status"], "degraded");
+		assert!(body["unavailable_models"]["missing"].is_string());
+		assert!(body["unavailable_tasks"]["broken"].is_string());
+		assert!(body["unavailable_tasks"].get("complete").is_none());
+		assert_eq!(body["queued_requests"], 0);
+	}
+
+	// Tasks using the model that could not be loaded are unavailable; other tasks work
+	let (status, body) = request(address, "GET", "/v1/task/broken/status").await;
+	assert!(status.contains(" 503 "), "unexpected status: {status}");
+	let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+	assert_eq!(body["error"], "model_not_available");
+	assert_eq!(body["model"], "missing");
+	let (status, _) = request(address, "GET", "/v1/task/broken/completion?prompt=Hello").await;
+	assert!(status.contains(" 503 "), "unexpected status: {status}");
+	let (status, _) = request(address, "GET", "/v1/task/complete/status").await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+	let (status, _) = request(address, "GET", "/v1/task/complete/completion?prompt=Hello").await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+
+	// Once the model file is present, reloading makes the model available
+	std::fs::copy(model_path, &missing_path).unwrap();
+	let report = state.reload().await.unwrap();
+	assert_eq!(report.loaded_models, vec![String::from("missing")]);
+	assert!(report.unavailable_models.is_empty());
+	let (status, body) = request(address, "GET", "/status").await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+	assert_eq!(body, r#"{"status":"ok","queued_requests":0}"#);
+	let (status, _) = request(address, "GET", "/v1/task/broken/completion?prompt=Hello").await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+	_ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_serve_model_aliases() {
+	let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/gpt2.bin");
+	let dir = std::env::temp_dir().join(format!("poly-server-aliases-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let config_path = dir.join("config.toml");
+	std::fs::write(
+		&config_path,
+		format!(
+			r#"
+			[models.gpt2]
+			architecture = "gpt2"
+			model_path = "{model_path}"
+
+			[models.gpt2_embeddings]
+			architecture = "gpt2"
+			model_path = "{model_path}"
+			threads_per_session = 2
+			"#
+		),
+	)
+	.unwrap();
+
+	let config = Config::from_file(&config_path).unwrap();
+	_ = std::fs::remove_dir_all(&dir);
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+	// The second entry loads the same file in the same way, so it uses the weights loaded for the first
+	let (status, body) = request(&listening.addresses[0], "GET", "/status").await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+	let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+	assert_eq!(body["status"], "ok");
+	assert_eq!(body["model_aliases"], serde_json::json!({ "gpt2_embeddings": "gpt2" }));
+}
+
+#[tokio::test]
+async fn test_serve_public_tasks() {
+	let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/gpt2.bin");
+	let dir = std::env::temp_dir().join(format!("poly-server-public-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let config_path = dir.join("config.toml");
+	std::fs::write(
+		&config_path,
+		format!(
+			r#"
+			[models.gpt2]
+			architecture = "gpt2"
+			model_path = "{model_path}"
+
+			allowed_keys = ["secret"]
+
+			[tasks.demo]
+			model = "gpt2"
+			max_tokens = 1
+			public = true
+			private_tokens = ["<|endoftext|>"]
+
+			[tasks.internal]
+			model = "gpt2"
+			max_tokens = 1
+			"#
+		),
+	)
+	.unwrap();
+
+	let config = Config::from_file(&config_path).unwrap();
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+	let address = &listening.addresses[0];
+
+	// Without authenticating, only the public task can be used and listed
+	let (status, _) = request(address, "GET", "/v1/task/demo/completion?prompt=Hello").await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+	let (status, body) = request(address, "GET", "/v1/task").await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+	let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+	assert_eq!(body["tasks"], serde_json::json!(["demo"]));
+
+	for path in [
+		"/v1/task/internal/completion?prompt=Hello",
+		"/v1/model/gpt2/tokenization?prompt=Hello",
+		"/v1/stats",
+		"/v1/metrics",
+	] {
+		let (status, _) = request(address, "GET", path).await;
+		assert!(status.contains(" 401 "), "unexpected status for {path}: {status}");
+	}
+
+	// Only users that are allowed to can mark prompt segments as trusted, and thereby use private tokens
+	let json = "Content-Type: application/json\r\n";
+	let trusted = r#"{"segments": [{"text": "<|endoftext|>Hello", "trusted": true}]}"#;
+	let (status, body) = request_with_body(address, "POST", "/v1/task/demo/completion", json, trusted).await;
+	assert!(status.contains(" 422 "), "unexpected status: {status}");
+	assert!(body.contains("trusted_segment_not_allowed"), "unexpected body: {body}");
+	let authenticated = format!("{json}Authorization: Bearer secret\r\n");
+	let (status, _) = request_with_body(address, "POST", "/v1/task/demo/completion", &authenticated, trusted).await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+
+	let untrusted = r#"{"segments": [{"text": "<|endoftext|>Hello"}]}"#;
+	let (status, body) = request_with_body(address, "POST", "/v1/task/demo/completion", &authenticated, untrusted).await;
+	assert!(status.contains(" 400 "), "unexpected status: {status}");
+	assert!(body.contains("illegal_token"), "unexpected body: {body}");
+	assert!(!body.contains("<|endoftext|>"), "private token in body: {body}");
+	_ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_serve_ip_rate_limit() {
+	let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/gpt2.bin");
+	let dir = std::env::temp_dir().join(format!("poly-server-rate-limit-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let config_path = dir.join("config.toml");
+	std::fs::write(
+		&config_path,
+		format!(
+			r#"
+			allowed_keys = ["secret"]
+			ip_rate_limit = {{ burst = 2, requests_per_second = 0.001, ban_after = 2, ban_secs = 60 }}
+
+			[models.gpt2]
+			architecture = "gpt2"
+			model_path = "{model_path}"
+
+			allowed_keys = ["secret"]
+
+			[tasks.demo]
+			model = "gpt2"
+			max_tokens = 1
+			public = true
+			private_tokens = ["<|endoftext|>"]
+			"#
+		),
+	)
+	.unwrap();
+
+	let config = Config::from_file(&config_path).unwrap();
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+	let address = &listening.addresses[0];
+	let authenticated = "Authorization: Bearer secret\r\n";
+
+	// Anonymous requests use up the allowance of the address, but authenticated requests from the same address neither
+	// count towards it nor are limited by it
+	for _ in 0..2 {
+		let (status, _) = request(address, "GET", "/v1/task/demo/status").await;
+		assert!(status.contains(" 200 "), "unexpected status: {status}");
+		let (status, _) = request_with_headers(address, "GET", "/v1/task/demo/status", authenticated).await;
+		assert!(status.contains(" 200 "), "unexpected status: {status}");
+	}
+	let (status, _) = request(address, "GET", "/v1/task/demo/status").await;
+	assert!(status.contains(" 429 "), "unexpected status: {status}");
+	let (status, _) = request_with_headers(address, "GET", "/v1/task/demo/status", authenticated).await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+
+	// Repeatedly exceeding the limit gets the address banned, also from the status endpoint
+	let (status, _) = request(address, "GET", "/status").await;
+	assert!(status.contains(" 429 "), "unexpected status: {status}");
+	let (status, _) = request(address, "GET", "/status").await;
+	assert!(status.contains(" 429 "), "unexpected status: {status}");
+
+	let (status, body) = request_with_headers(address, "GET", "/v1/stats", authenticated).await;
+	assert!(status.contains(" 200 "), "unexpected status: {status}");
+	let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+	assert_eq!(body["rate_limit"]["limited"], 2);
+	assert_eq!(body["rate_limit"]["bans"], 1);
+	assert_eq!(body["rate_limit"]["banned"], 1);
+	assert_eq!(body["rate_limit"]["rejected_banned"], 1);
+	_ = std::fs::remove_dir_all(&dir);
+}
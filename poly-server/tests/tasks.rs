@@ -0,0 +1,459 @@
+use std::{sync::Arc, time::Instant};
+
+use poly_backend::backend::Backend;
+use poly_server::{
+	config::Config,
+	net::ListenAddress,
+	routes,
+	server::{serve, Server},
+};
+use serde_json::{json, Value};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+	net::TcpStream,
+};
+
+/// Start a public server with a GPT-2 model, returning its address. `config` holds the settings and tasks to add (tasks
+/// use model `gpt2`).
+async fn start_server(name: &str, config: &str) -> ListenAddress {
+	let model_path = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/gpt2.bin");
+	let dir = std::env::temp_dir().join(format!("poly-server-tasks-{name}-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let config_path = dir.join("config.toml");
+	std::fs::write(
+		&config_path,
+		format!(
+			r#"
+			public = true
+			{config}
+
+			[models.gpt2]
+			architecture = "gpt2"
+			model_path = "{model_path}"
+			"#
+		),
+	)
+	.unwrap();
+
+	let config = Config::from_file(&config_path).unwrap();
+	_ = std::fs::remove_dir_all(&dir);
+	let backend = Arc::new(Backend::from(config.backend_config.clone(), None).await);
+	let state = Arc::new(Server::new(backend, config));
+	let listening = serve(routes::router(state), &["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+	listening.addresses[0].clone()
+}
+
+async fn connect(address: &ListenAddress) -> TcpStream {
+	let ListenAddress::Tcp(addr) = address else {
+		panic!("unexpected address {address}");
+	};
+	TcpStream::connect(addr).await.unwrap()
+}
+
+/// Send a request with a body over HTTP/1.0, returning the status code, the head and the body of the response
+async fn request(address: &ListenAddress, method: &str, path: &str, content_type: &str, body: &str) -> (u16, String, String) {
+	let mut stream = connect(address).await;
+	let request = format!(
+		"{method} {path} HTTP/1.0\r\nHost: localhost\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n{body}",
+		body.len()
+	);
+	stream.write_all(request.as_bytes()).await.unwrap();
+	let mut response = String::new();
+	stream.read_to_string(&mut response).await.unwrap();
+	let (head, body) = response.split_once("\r\n\r\n").unwrap();
+	let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+	(status, head.to_string(), body.to_string())
+}
+
+/// Post a JSON body, returning the status code and the JSON response
+async fn post_json(address: &ListenAddress, path: &str, body: &Value) -> (u16, Value) {
+	let (status, _, body) = request(address, "POST", path, "application/json", &body.to_string()).await;
+	(status, serde_json::from_str(&body).unwrap())
+}
+
+/// A stream of server-sent events, read one event at a time
+struct EventStream {
+	reader: BufReader<TcpStream>,
+}
+
+impl EventStream {
+	/// Send a request over HTTP/1.0 and check that it is answered with a stream of events
+	async fn open(address: &ListenAddress, method: &str, path: &str, content_type: &str, body: &str) -> EventStream {
+		let mut stream = connect(address).await;
+		let request = format!(
+			"{method} {path} HTTP/1.0\r\nHost: localhost\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n{body}",
+			body.len()
+		);
+		stream.write_all(request.as_bytes()).await.unwrap();
+		let mut reader = BufReader::new(stream);
+		let mut head = String::new();
+		while !head.ends_with("\r\n\r\n") {
+			assert_ne!(reader.read_line(&mut head).await.unwrap(), 0, "response ended in the head: {head}");
+		}
+		assert!(
+			head.starts_with("HTTP/1.0 200 ") || head.starts_with("HTTP/1.1 200 "),
+			"unexpected response: {head}"
+		);
+		assert!(head.contains("text/event-stream"), "unexpected response: {head}");
+		EventStream { reader }
+	}
+
+	/// The next event (its type, `message` when not set, and its data), skipping keep-alive comments. Returns `None` when
+	/// the stream has ended.
+	async fn next(&mut self) -> Option<(String, String)> {
+		let (mut event, mut data) = (String::from("message"), vec![]);
+		loop {
+			let mut line = String::new();
+			if self.reader.read_line(&mut line).await.unwrap() == 0 {
+				return None;
+			}
+			let line = line.trim_end_matches(['\r', '\n']);
+			if line.is_empty() {
+				if data.is_empty() && event == "message" {
+					continue;
+				}
+				return Some((event, data.join("\n")));
+			}
+			if let Some(value) = line.strip_prefix("event:") {
+				event = value.trim_start().to_string();
+			} else if let Some(value) = line.strip_prefix("data:") {
+				data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+			}
+		}
+	}
+}
+
+#[tokio::test]
+async fn test_rerank() {
+	let address = start_server(
+		"rerank",
+		r#"
+		[tasks.rank]
+		model = "gpt2"
+		rerank = { max_documents = 3 }
+
+		[tasks.rank_cross]
+		model = "gpt2"
+		rerank = { method = "cross_scoring" }
+
+		[tasks.rank_budget]
+		model = "gpt2"
+		rerank = { budget_ms = 1 }
+		"#,
+	)
+	.await;
+	let documents = [
+		"The cat sat on the mat",
+		"Stock prices fell sharply today",
+		"Dogs and cats are popular pets",
+	];
+
+	// Documents are returned most relevant first, with the method that scored them
+	for (task, method) in [("rank", "embedding"), ("rank_cross", "cross_scoring")] {
+		let (status, body) = post_json(
+			&address,
+			&format!("/v1/task/{task}/rerank"),
+			&json!({ "query": "pets", "documents": documents }),
+		)
+		.await;
+		assert_eq!(status, 200, "{task}: {body}");
+		assert_eq!(body["method"], method);
+		let results = body["results"].as_array().unwrap();
+		assert_eq!(results.len(), 3);
+		let scores: Vec<f64> = results.iter().map(|r| r["score"].as_f64().unwrap()).collect();
+		assert!(scores.windows(2).all(|w| w[0] >= w[1]), "{task}: {scores:?}");
+		for result in results {
+			let index = result["index"].as_u64().unwrap() as usize;
+			assert_eq!(result["document"], documents[index]);
+		}
+	}
+
+	let (status, body) = post_json(
+		&address,
+		"/v1/task/rank/rerank",
+		&json!({ "query": "pets", "documents": documents, "top_n": 1 }),
+	)
+	.await;
+	assert_eq!(status, 200);
+	assert_eq!(body["results"].as_array().unwrap().len(), 1);
+
+	// More documents than the task allows
+	let mut too_many = documents.to_vec();
+	too_many.push("Birds can fly");
+	let (status, body) = post_json(&address, "/v1/task/rank/rerank", &json!({ "query": "pets", "documents": too_many })).await;
+	assert_eq!(status, 400);
+	assert_eq!(body["error"], "invalid_parameter");
+
+	// Empty query or document
+	for request in [
+		json!({ "query": " ", "documents": documents }),
+		json!({ "query": "pets", "documents": ["The cat sat on the mat", ""] }),
+	] {
+		let (status, body) = post_json(&address, "/v1/task/rank/rerank", &request).await;
+		assert_eq!(status, 422);
+		assert_eq!(body["error"], "invalid_query");
+	}
+
+	// Scoring the documents takes longer than the budget of one millisecond
+	let (status, body) = post_json(
+		&address,
+		"/v1/task/rank_budget/rerank",
+		&json!({ "query": "pets", "documents": documents }),
+	)
+	.await;
+	assert_eq!(status, 504);
+	assert_eq!(body["error"], "budget_exceeded");
+}
+
+#[tokio::test]
+async fn test_force_closed() {
+	let address = start_server(
+		"force-closed",
+		r#"
+		[tasks.status]
+		model = "gpt2"
+		prefix = "Status report: "
+
+		[tasks.status.biaser.json_schema]
+		type = "object"
+		required = ["status", "component"]
+
+		[tasks.status.biaser.json_schema.properties]
+		status = { type = "string", enum = ["acknowledged"] }
+		component = { type = "string", enum = ["storage subsystem"] }
+		"#,
+	)
+	.await;
+
+	// Output that is cut off by max_tokens before it is complete is closed, which the response reports
+	let (status, body) = post_json(
+		&address,
+		"/v1/task/status/completion",
+		&json!({ "prompt": "all systems nominal", "max_tokens": 3 }),
+	)
+	.await;
+	assert_eq!(status, 200, "{body}");
+	assert_eq!(body["finish_reason"], "max_tokens");
+	assert_eq!(body["force_closed"], true);
+	assert!(serde_json::from_str::<Value>(body["text"].as_str().unwrap()).is_ok(), "{body}");
+
+	let (status, body) = post_json(&address, "/v1/task/status/completion", &json!({ "prompt": "all systems nominal" })).await;
+	assert_eq!(status, 200, "{body}");
+	assert_eq!(body["force_closed"], false);
+}
+
+#[tokio::test]
+async fn test_summary() {
+	let address = start_server(
+		"summary",
+		r#"
+		[tasks.summary]
+		model = "gpt2"
+		postfix = "\nIn short:"
+		max_tokens = 4
+		seed = 42
+		stop_sequences = []
+		summarize = { chunk_tokens = 16 }
+
+		[tasks.summary_one_level]
+		model = "gpt2"
+		max_tokens = 4
+		stop_sequences = []
+		summarize = { chunk_tokens = 16, max_levels = 1 }
+
+		[tasks.summary_few_tokens]
+		model = "gpt2"
+		max_tokens = 4
+		stop_sequences = []
+		summarize = { chunk_tokens = 16, max_total_tokens = 20 }
+		"#,
+	)
+	.await;
+	let document = "The city council met on Tuesday to discuss the new park. Residents asked for more trees and benches. \
+		The mayor promised to publish a plan next month. Several shop owners worried about parking near the park.";
+
+	let (status, _, body) = request(&address, "POST", "/v1/task/summary/summary", "text/plain", document).await;
+	assert_eq!(status, 200, "{body}");
+	let body: Value = serde_json::from_str(&body).unwrap();
+	assert!(body["chunks"].as_u64().unwrap() > 1);
+	assert_eq!(body["levels"].as_array().unwrap().last().unwrap()["chunks"], 1);
+
+	// Summarizing would take more tokens or rounds than allowed
+	for task in ["summary_one_level", "summary_few_tokens"] {
+		let (status, _, body) = request(&address, "POST", &format!("/v1/task/{task}/summary"), "text/plain", document).await;
+		assert_eq!(status, 413, "{task}: {body}");
+		let body: Value = serde_json::from_str(&body).unwrap();
+		assert_eq!(body["error"], "summary_limit_exceeded");
+	}
+
+	// When streaming, progress is reported before each completion, followed by the summary
+	let mut events = EventStream::open(&address, "POST", "/v1/task/summary/summary?stream=true", "text/plain", document).await;
+	let mut progress = vec![];
+	let summary = loop {
+		match events.next().await.expect("stream ends with a summary") {
+			(event, data) if event == "progress" => progress.push(serde_json::from_str::<Value>(&data).unwrap()),
+			(event, data) if event == "summary" => break serde_json::from_str::<Value>(&data).unwrap(),
+			(event, data) => panic!("unexpected event {event}: {data}"),
+		}
+	};
+	assert_eq!(progress[0], json!({ "level": 0, "chunk": 0, "chunks": summary["chunks"] }));
+	let completions: u64 = summary["levels"].as_array().unwrap().iter().map(|l| l["chunks"].as_u64().unwrap()).sum();
+	assert_eq!(progress.len() as u64, completions);
+	assert_eq!(events.next().await, None);
+
+	// Errors are sent as an event after the progress made until then
+	let mut events = EventStream::open(&address, "POST", "/v1/task/summary_one_level/summary?stream=true", "text/plain", document).await;
+	let mut progress = 0;
+	let error = loop {
+		match events.next().await.expect("stream ends with an error") {
+			(event, _) if event == "progress" => progress += 1,
+			(event, data) if event == "error" => break serde_json::from_str::<Value>(&data).unwrap(),
+			(event, data) => panic!("unexpected event {event}: {data}"),
+		}
+	};
+	assert!(progress > 1);
+	assert_eq!(error["error"], "summary_limit_exceeded");
+	assert_eq!(events.next().await, None);
+}
+
+/// Read the token events of a live stream until its `done` event (skipping updates of its place in line), returning the
+/// number of tokens and the `done` event
+async fn read_until_done(events: &mut EventStream) -> (u64, Value) {
+	let mut tokens = 0;
+	loop {
+		match events.next().await.expect("stream ends with a done event") {
+			(event, _) if event == "message" => tokens += 1,
+			(event, _) if event == "queued" => {}
+			(event, data) if event == "done" => return (tokens, serde_json::from_str(&data).unwrap()),
+			(event, data) => panic!("unexpected event {event}: {data}"),
+		}
+	}
+}
+
+#[tokio::test]
+async fn test_live_stream() {
+	let address = start_server(
+		"live",
+		r#"
+		[tasks.story]
+		model = "gpt2"
+		max_tokens = 200
+		seed = 42
+		stop_sequences = []
+
+		[tasks.short]
+		model = "gpt2"
+		max_tokens = 4
+		seed = 42
+		stop_sequences = []
+		"#,
+	)
+	.await;
+
+	// The tokens of a completion are followed by a `done` event describing it, after which the stream ends
+	let mut events = EventStream::open(&address, "GET", "/v1/task/short/live?prompt=Once%20upon%20a%20time", "text/plain", "").await;
+	assert_eq!(events.next().await.unwrap().0, "open");
+	let (tokens, done) = read_until_done(&mut events).await;
+	assert_eq!(events.next().await, None);
+	assert_eq!(done["finish_reason"], "max_tokens");
+	assert_eq!(done["usage"]["sampled_tokens"], 4);
+	assert!(done["usage"]["prompt_tokens"].as_u64().unwrap() > 0);
+	assert!(done["duration_ms"].is_u64());
+	assert!((1..=4).contains(&tokens));
+
+	let mut events = EventStream::open(&address, "GET", "/v1/task/story/live?prompt=Once%20upon%20a%20time", "text/plain", "").await;
+	let (event, generation_id) = events.next().await.unwrap();
+	assert_eq!(event, "open");
+	assert!(!generation_id.is_empty());
+
+	// The first token arrives while the completion is still running, so it can be cancelled. Generation waits for a client
+	// that does not read the stream, so it cannot have generated all 200 tokens in the meantime.
+	let (event, first_token) = events.next().await.unwrap();
+	assert_eq!(event, "message");
+	assert!(!first_token.is_empty());
+	let (status, _, _) = request(
+		&address,
+		"DELETE",
+		&format!("/v1/task/story/completion/{generation_id}"),
+		"text/plain",
+		"",
+	)
+	.await;
+	assert_eq!(status, 204);
+
+	let (tokens, done) = read_until_done(&mut events).await;
+	assert_eq!(events.next().await, None);
+	assert_eq!(done["finish_reason"], "cancelled");
+	let sampled_tokens = done["usage"]["sampled_tokens"].as_u64().unwrap();
+	assert!(sampled_tokens < 200);
+	assert!(tokens < sampled_tokens);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_priority() {
+	// One request is serviced at a time; the slow task takes long enough for the others to queue up behind it
+	let address = start_server(
+		"priority",
+		r#"
+		max_concurrent = 1
+		priority_aging_secs = 600
+
+		[tasks.slow]
+		model = "gpt2"
+		max_tokens = 64
+		seed = 42
+		stop_sequences = []
+		priority = "low"
+
+		[tasks.urgent]
+		model = "gpt2"
+		max_tokens = 1
+		priority = "high"
+		"#,
+	)
+	.await;
+	let slow_path = "/v1/task/slow/live?prompt=Once%20upon%20a%20time";
+
+	// The first low-priority request is admitted and starts generating
+	let mut running = EventStream::open(&address, "GET", slow_path, "text/plain", "").await;
+	assert_eq!(running.next().await.unwrap().0, "open");
+	assert_eq!(running.next().await.unwrap().0, "message");
+
+	// Two more low-priority requests wait in line
+	let mut waiting = vec![];
+	for position in 1..=2 {
+		let mut events = EventStream::open(&address, "GET", slow_path, "text/plain", "").await;
+		assert_eq!(events.next().await.unwrap().0, "open");
+		let (event, data) = events.next().await.unwrap();
+		assert_eq!(event, "queued");
+		assert_eq!(serde_json::from_str::<Value>(&data).unwrap()["position"], position);
+		waiting.push(events);
+	}
+
+	// A high-priority request arriving after them is placed ahead of them
+	let mut urgent = EventStream::open(&address, "GET", "/v1/task/urgent/live?prompt=Hello", "text/plain", "").await;
+	assert_eq!(urgent.next().await.unwrap().0, "open");
+	let (event, data) = urgent.next().await.unwrap();
+	assert_eq!(event, "queued");
+	assert_eq!(serde_json::from_str::<Value>(&data).unwrap()["position"], 1);
+
+	// Once the running request is done, the high-priority request is serviced first, then the others in order of arrival
+	let finish = |mut events: EventStream| {
+		tokio::spawn(async move {
+			read_until_done(&mut events).await;
+			Instant::now()
+		})
+	};
+	let running = finish(running);
+	let urgent = finish(urgent);
+	let waiting: Vec<_> = waiting.into_iter().map(finish).collect();
+	let running_done = running.await.unwrap();
+	let urgent_done = urgent.await.unwrap();
+	let mut waiting_done = vec![];
+	for handle in waiting {
+		waiting_done.push(handle.await.unwrap());
+	}
+	assert!(running_done < urgent_done);
+	assert!(urgent_done < waiting_done[0]);
+	assert!(waiting_done[0] < waiting_done[1]);
+}
@@ -1,53 +1,266 @@
 use crate::components::chatmessage::{ChatMessage, ChatMessageMessage};
-use crate::worker::{LLMWorkerCommand, LLMWorkerEvent};
+use crate::config::Args;
+use crate::util::transcript_path;
+use crate::worker::{
+	ConnectionStatus, ConversationId, LLMWorkerCommand, LLMWorkerErrorKind, LLMWorkerEvent, LLMWorkerFinishReason, LLMWorkerParameters,
+};
 use iced::alignment::Horizontal;
 use iced::futures::channel::mpsc::Sender;
 use iced::widget::scrollable::RelativeOffset;
-use iced::widget::{button, column, pick_list, progress_bar, row, scrollable, text, text_input};
-use iced::{clipboard, executor, Alignment, Application, Color, Command, Element, Subscription, Theme};
+use iced::widget::{button, column, pick_list, progress_bar, row, scrollable, slider, text, text_input};
+use iced::{clipboard, executor, window, Alignment, Application, Color, Command, Element, Event, Subscription, Theme};
 use iced::{widget::container, Length};
 use once_cell::sync::Lazy;
+use poly_backend::types::{TEMPERATURE_RANGE, TOP_P_RANGE};
+use std::time::Duration;
 
 static CHAT_MESSAGES_SCROLLABLE_ID: Lazy<scrollable::Id> = Lazy::new(scrollable::Id::unique);
 static CHAT_INPUT_ID: Lazy<text_input::Id> = Lazy::new(text_input::Id::unique);
 
+/// How long to wait for the worker to shut down when the window is closed
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A conversation held by the worker, as shown in the application
+struct Conversation {
+	id: ConversationId,
+	messages: Vec<ChatMessage>,
+	selected_task: Option<String>,
+
+	/// Sampling parameters changed by the user (sent to the worker in full on every change)
+	overrides: LLMWorkerParameters,
+
+	/// Sampling parameters that apply to the next prompt, as reported by the worker
+	parameters: Option<LLMWorkerParameters>,
+
+	/// The system prompt as being edited (set to the active system prompt when the worker reports it)
+	system_prompt: String,
+}
+
+impl Conversation {
+	fn new(id: ConversationId) -> Conversation {
+		Conversation {
+			id,
+			messages: vec![],
+			selected_task: None,
+			overrides: LLMWorkerParameters::default(),
+			parameters: None,
+			system_prompt: String::new(),
+		}
+	}
+
+	/// Label of the tab for this conversation
+	fn title(&self) -> String {
+		match self.selected_task {
+			Some(ref task) => format!("{} ({task})", self.id + 1),
+			None => format!("{}", self.id + 1),
+		}
+	}
+}
+
 pub struct App {
+	/// Command line options, which tell the worker which configuration to load
+	args: Args,
 	message: String,
-	messages: Vec<ChatMessage>,
 	sender: Option<Sender<LLMWorkerCommand>>,
 	tasks: Vec<String>,
-	selected_task: Option<String>,
+
+	/// Conversations opened in the worker, in the order they were created
+	conversations: Vec<Conversation>,
+
+	/// The conversation that is shown
+	current: Option<ConversationId>,
 	running: bool,
 	loading_progress: f64,
+
+	/// Token counts and throughput of the current or last completion
+	status: Option<String>,
+
+	/// The last error reported by the worker (cleared when a new prompt is sent or the conversation is reset)
+	error: Option<(LLMWorkerErrorKind, String)>,
+
+	/// State of the connection to the remote server (`None` when models are loaded locally)
+	connection: Option<ConnectionStatus>,
+
+	/// Tokens the message being typed would take and the tokens that remain in the context window, as last reported
+	token_count: Option<(usize, usize)>,
+
+	/// Set when the window was closed and the worker is shutting down
+	closing: bool,
 }
 
 #[derive(Debug, Clone)]
 pub enum AppMessage {
 	ChangeTask(String),
+	CloseConversation,
+	CloseRequested,
 	CopyText(String),
-	Interrupt,
+	Exit,
+	LoadTranscript,
+	NewConversation,
+	Regenerate,
 	Reset,
+	ResetParameters,
+	SaveTranscript,
+	SelectConversation(ConversationId),
 	Send,
+	SetMaxTokens(String),
+	SetSystemPrompt,
+	SetTemperature(f32),
+	SetTopP(f32),
+	Stop,
 	Type(String),
+	TypeSystemPrompt(String),
 	WorkerEvent(LLMWorkerEvent),
 }
 
+impl App {
+	/// Send a command to the worker (if it is ready)
+	fn send(&mut self, command: LLMWorkerCommand) {
+		if let Some(ref mut sender) = self.sender {
+			if let Err(e) = sender.try_send(command) {
+				tracing::error!("could not send command to worker: {e}");
+				self.error = Some((LLMWorkerErrorKind::Channel, format!("could not send command to worker: {e}")));
+			}
+		}
+	}
+
+	/// Send a command for the current conversation (if there is one), built from its identifier
+	fn send_current(&mut self, command: impl FnOnce(ConversationId) -> LLMWorkerCommand) {
+		if let Some(id) = self.current {
+			self.send(command(id));
+		}
+	}
+
+	fn conversation(&mut self, id: ConversationId) -> Option<&mut Conversation> {
+		self.conversations.iter_mut().find(|c| c.id == id)
+	}
+
+	fn current(&self) -> Option<&Conversation> {
+		self.conversations.iter().find(|c| Some(c.id) == self.current)
+	}
+
+	fn current_mut(&mut self) -> Option<&mut Conversation> {
+		self.current.and_then(|id| self.conversation(id))
+	}
+
+	/// Change the sampling parameters of the current conversation
+	fn set_overrides(&mut self, change: impl FnOnce(&mut LLMWorkerParameters)) {
+		let Some(conversation) = self.current_mut() else {
+			return;
+		};
+		change(&mut conversation.overrides);
+		let LLMWorkerParameters {
+			temperature,
+			top_p,
+			max_tokens,
+		} = conversation.overrides;
+		self.send_current(|conversation| LLMWorkerCommand::SetParameters {
+			conversation,
+			temperature,
+			top_p,
+			max_tokens,
+		});
+	}
+
+	/// Buttons to switch between, open and close conversations
+	fn conversations_view(&self) -> Element<AppMessage> {
+		let mut tabs: Vec<Element<AppMessage>> = self
+			.conversations
+			.iter()
+			.map(|c| {
+				let tab = button(text(c.title()).size(12));
+				if Some(c.id) == self.current {
+					tab.style(iced::theme::Button::Primary).into()
+				} else {
+					tab.style(iced::theme::Button::Secondary)
+						.on_press(AppMessage::SelectConversation(c.id))
+						.into()
+				}
+			})
+			.collect();
+		tabs.push(button(text("+").size(12)).on_press(AppMessage::NewConversation).into());
+		if self.conversations.len() > 1 {
+			tabs.push(button(text("Close").size(12)).on_press(AppMessage::CloseConversation).into());
+		}
+		row(tabs).spacing(5).align_items(Alignment::Center).into()
+	}
+
+	/// Controls for the system prompt and sampling parameters (empty when these cannot be changed, i.e. for a remote
+	/// server)
+	fn parameters_view(&self) -> Element<AppMessage> {
+		let Some(conversation) = self.current() else {
+			return Element::new(text(""));
+		};
+		let Some(parameters) = conversation.parameters.filter(|_| self.connection.is_none()) else {
+			return Element::new(text(""));
+		};
+
+		let mut controls: Vec<Element<AppMessage>> = vec![];
+		if let Some(temperature) = parameters.temperature {
+			controls.push(text("Temperature").size(12).into());
+			controls.push(
+				slider(TEMPERATURE_RANGE, temperature, AppMessage::SetTemperature)
+					.step(0.05)
+					.width(100)
+					.into(),
+			);
+			controls.push(text(format!("{temperature:.2}")).size(12).into());
+		}
+		if let Some(top_p) = parameters.top_p {
+			controls.push(text("Top-p").size(12).into());
+			controls.push(slider(TOP_P_RANGE, top_p, AppMessage::SetTopP).step(0.05).width(100).into());
+			controls.push(text(format!("{top_p:.2}")).size(12).into());
+		}
+
+		// The placeholder shows the maximum configured for the task
+		let max_tokens = conversation.overrides.max_tokens.map(|n| n.to_string()).unwrap_or_default();
+		let placeholder = parameters.max_tokens.map(|n| n.to_string()).unwrap_or_else(|| String::from("unlimited"));
+		controls.push(text("Max tokens").size(12).into());
+		controls.push(
+			text_input(&placeholder, &max_tokens)
+				.on_input(AppMessage::SetMaxTokens)
+				.size(12)
+				.width(80)
+				.into(),
+		);
+		if conversation.overrides != LLMWorkerParameters::default() {
+			controls.push(button(text("Defaults").size(12)).on_press(AppMessage::ResetParameters).into());
+		}
+
+		column![
+			text_input("system prompt (submit to restart the conversation)", &conversation.system_prompt)
+				.on_input(AppMessage::TypeSystemPrompt)
+				.on_submit(AppMessage::SetSystemPrompt)
+				.size(12),
+			row(controls).spacing(5).align_items(Alignment::Center)
+		]
+		.spacing(5)
+		.into()
+	}
+}
+
 impl Application for App {
 	type Message = AppMessage;
 	type Executor = executor::Default;
 	type Theme = Theme;
-	type Flags = ();
+	type Flags = Args;
 
-	fn new(_flags: Self::Flags) -> (Self, Command<AppMessage>) {
+	fn new(args: Self::Flags) -> (Self, Command<AppMessage>) {
 		(
 			App {
+				args,
 				message: String::new(),
-				messages: vec![],
 				sender: None,
 				running: false,
 				loading_progress: 0.0,
 				tasks: vec![],
-				selected_task: None,
+				conversations: vec![],
+				current: None,
+				status: None,
+				error: None,
+				connection: None,
+				token_count: None,
+				closing: false,
 			},
 			Command::none(),
 		)
@@ -58,59 +271,248 @@ impl Application for App {
 	}
 
 	fn subscription(&self) -> Subscription<Self::Message> {
-		crate::worker::llm_worker().map(AppMessage::WorkerEvent)
+		let close_requested = iced::subscription::events_with(|event, _status| match event {
+			Event::Window(window::Event::CloseRequested) => Some(AppMessage::CloseRequested),
+			_ => None,
+		});
+		Subscription::batch([crate::worker::llm_worker(self.args.clone()).map(AppMessage::WorkerEvent), close_requested])
 	}
 
 	fn update(&mut self, message: Self::Message) -> Command<AppMessage> {
 		match message {
-			AppMessage::Type(t) => self.message = t,
-			AppMessage::ChangeTask(t) => {
-				if !self.selected_task.as_ref().is_some_and(|x| x == &t) {
-					self.selected_task = Some(t);
-					self.messages.clear();
-					if let Some(ref mut sender) = self.sender {
-						sender
-							.try_send(LLMWorkerCommand::Reset {
-								task_name: self.selected_task.clone().unwrap(),
-							})
-							.unwrap();
-					}
+			AppMessage::Type(t) => {
+				// Counting tokens is not supported for remote servers
+				self.message = t;
+				if self.message.trim().is_empty() || self.running || self.connection.is_some() {
+					self.token_count = None;
+				} else {
+					let text = self.message.clone();
+					self.send_current(|conversation| LLMWorkerCommand::CountTokens { conversation, text });
+				}
+			}
+			AppMessage::TypeSystemPrompt(t) => {
+				if let Some(conversation) = self.current_mut() {
+					conversation.system_prompt = t;
+				}
+
+				// Keep the focus on the system prompt field
+				return Command::none();
+			}
+			AppMessage::SetSystemPrompt => {
+				// The worker restarts the conversation and reports the new system prompt
+				if let Some(prompt) = self.current().map(|c| c.system_prompt.clone()) {
+					self.send_current(|conversation| LLMWorkerCommand::SetSystemPrompt { conversation, prompt });
 				}
 			}
+			AppMessage::ChangeTask(task) => {
+				// The worker confirms the change with a TaskSelected event
+				if !self.current().is_some_and(|c| c.selected_task.as_ref() == Some(&task)) {
+					self.send_current(|conversation| LLMWorkerCommand::SetTask { conversation, task });
+				}
+			}
+			AppMessage::NewConversation => {
+				// The worker reports the new conversation with a ConversationCreated event
+				self.send(LLMWorkerCommand::NewConversation);
+			}
+			AppMessage::SelectConversation(id) => {
+				self.current = Some(id);
+				self.error = None;
+				self.token_count = None;
+			}
+			AppMessage::CloseConversation => self.send_current(LLMWorkerCommand::CloseConversation),
+			AppMessage::CloseRequested => {
+				// Let the worker save its state first, but do not wait for it forever
+				if self.sender.is_none() || self.closing {
+					return window::close();
+				}
+				self.closing = true;
+				self.status = Some(String::from("Shutting down..."));
+				self.send(LLMWorkerCommand::Shutdown);
+				return Command::perform(tokio::time::sleep(SHUTDOWN_TIMEOUT), |_| AppMessage::Exit);
+			}
+			AppMessage::Exit => {
+				tracing::warn!("the worker did not shut down in time");
+				return window::close();
+			}
 			AppMessage::CopyText(t) => return clipboard::write(t),
-			AppMessage::Interrupt => {
-				if let Some(ref mut sender) = self.sender {
-					sender.try_send(LLMWorkerCommand::Interrupt).unwrap();
+			AppMessage::Stop => self.send(LLMWorkerCommand::Stop),
+			AppMessage::SetTemperature(temperature) => self.set_overrides(|p| p.temperature = Some(temperature)),
+			AppMessage::SetTopP(top_p) => self.set_overrides(|p| p.top_p = Some(top_p)),
+			AppMessage::SetMaxTokens(max_tokens) => {
+				// An empty field restores the maximum configured for the task
+				let max_tokens = match max_tokens.trim() {
+					"" => None,
+					n => match n.parse() {
+						Ok(n) => Some(n),
+						Err(_) => return Command::none(),
+					},
+				};
+				self.set_overrides(|p| p.max_tokens = max_tokens);
+
+				// Keep the focus on the field
+				return Command::none();
+			}
+			AppMessage::ResetParameters => self.set_overrides(|p| *p = LLMWorkerParameters::default()),
+			AppMessage::SaveTranscript => {
+				if let Some(path) = transcript_path() {
+					self.send_current(|conversation| LLMWorkerCommand::SaveTranscript { conversation, path });
+				}
+			}
+			AppMessage::LoadTranscript => {
+				if let Some(path) = transcript_path() {
+					self.send_current(|conversation| LLMWorkerCommand::LoadTranscript { conversation, path });
 				}
 			}
 
 			AppMessage::WorkerEvent(wevt) => {
 				match wevt {
+					LLMWorkerEvent::Tasks(tasks) => {
+						self.tasks = tasks;
+					}
 					LLMWorkerEvent::Loading(progress) => {
 						self.loading_progress = progress;
 					}
-					LLMWorkerEvent::Ready {
-						sender,
-						tasks,
-						selected_task,
-					} => {
+					LLMWorkerEvent::Connection(status) => {
+						self.connection = Some(status);
+					}
+					LLMWorkerEvent::Ready { sender } => {
 						self.sender = Some(sender);
-						self.tasks = tasks;
-						self.selected_task = Some(selected_task);
+					}
+					LLMWorkerEvent::ConversationCreated(id) => {
+						self.conversations.push(Conversation::new(id));
+						self.current = Some(id);
+					}
+					LLMWorkerEvent::ConversationClosed(id) => {
+						self.conversations.retain(|c| c.id != id);
+						if self.current == Some(id) {
+							self.current = self.conversations.last().map(|c| c.id);
+						}
+					}
+					LLMWorkerEvent::TaskSelected { conversation, task } => {
+						if let Some(conversation) = self.conversation(conversation) {
+							conversation.selected_task = Some(task);
+							conversation.messages.clear();
+						}
+						self.error = None;
+					}
+					LLMWorkerEvent::SystemPrompt { conversation, prompt, .. } => {
+						if let Some(conversation) = self.conversation(conversation) {
+							conversation.system_prompt = prompt;
+						}
+					}
+					LLMWorkerEvent::Parameters { conversation, parameters } => {
+						if let Some(conversation) = self.conversation(conversation) {
+							conversation.parameters = Some(parameters);
+						}
+					}
+					LLMWorkerEvent::TokenCount {
+						conversation,
+						prompt,
+						overhead,
+						used,
+						context,
+						..
+					} => {
+						if Some(conversation) == self.current && !self.message.trim().is_empty() {
+							self.token_count = Some((prompt + overhead, context.saturating_sub(used)));
+						}
+						return Command::none();
+					}
+					LLMWorkerEvent::Rewound(id) => {
+						if let Some(conversation) = self.conversation(id) {
+							if conversation.messages.last().is_some_and(|m| !m.from_user) {
+								conversation.messages.pop();
+							}
+						}
+					}
+					LLMWorkerEvent::TranscriptSaved(path) => {
+						self.status = Some(format!("Conversation saved to {}", path.display()));
+					}
+					LLMWorkerEvent::TranscriptLoaded { conversation, task, turns } => {
+						let Some(conversation) = self.conversation(conversation) else {
+							return Command::none();
+						};
+						conversation.selected_task = Some(task);
+						conversation.messages = turns
+							.into_iter()
+							.flat_map(|turn| {
+								[
+									ChatMessage {
+										text: turn.prompt,
+										from_user: true,
+									},
+									ChatMessage {
+										text: turn.response,
+										from_user: false,
+									},
+								]
+							})
+							.collect();
+						return scrollable::snap_to(CHAT_MESSAGES_SCROLLABLE_ID.clone(), RelativeOffset::END);
+					}
+					LLMWorkerEvent::ContextReduced { dropped_turns, summary, .. } => {
+						let what = if summary.is_some() { "summarized" } else { "forgotten" };
+						self.status = Some(format!("The conversation was too long; the first {dropped_turns} exchanges were {what}"));
+					}
+					LLMWorkerEvent::MemoriesRecalled { .. } | LLMWorkerEvent::Memories { .. } | LLMWorkerEvent::MemoryForgotten { .. } => {
+						// Memories are not browsed from the application (yet)
+					}
+					LLMWorkerEvent::ShutdownComplete => return window::close(),
+					LLMWorkerEvent::Warning(message) => {
+						tracing::warn!("worker warning: {message}");
+						self.status = Some(message);
+					}
+					LLMWorkerEvent::Error { kind, message, recoverable } => {
+						tracing::error!(?kind, recoverable, "worker error: {message}");
+						self.error = Some((kind, message));
+					}
+					LLMWorkerEvent::Progress {
+						generated_tokens,
+						tokens_per_sec,
+					} => {
+						self.status = Some(format!("{generated_tokens} tokens, {tokens_per_sec:.1} tokens/s"));
+					}
+					LLMWorkerEvent::Stats {
+						prompt_tokens,
+						generated_tokens,
+						tokens_per_sec,
+						duration,
+					} => {
+						self.status = Some(format!(
+							"{prompt_tokens} prompt tokens, {generated_tokens} generated in {:.1}s ({tokens_per_sec:.1} tokens/s)",
+							duration.as_secs_f64()
+						));
+					}
+					LLMWorkerEvent::Finished { reason, .. } => {
+						let note = match reason {
+							LLMWorkerFinishReason::Cancelled => Some("stopped"),
+							LLMWorkerFinishReason::MaxTokens | LLMWorkerFinishReason::LengthLimit => Some("maximum length reached"),
+							LLMWorkerFinishReason::Timeout => Some("time limit reached"),
+							_ => None,
+						};
+						if let (Some(note), Some(status)) = (note, self.status.as_mut()) {
+							status.push_str(&format!(" - {note}"));
+						}
+						if reason == LLMWorkerFinishReason::ContextFull && self.error.is_none() {
+							self.error = Some((LLMWorkerErrorKind::ContextFull, String::from("The conversation is full")));
+						}
 					}
 					LLMWorkerEvent::Running(r) => {
 						self.running = r;
 						return iced::widget::text_input::focus(CHAT_INPUT_ID.clone());
 					}
-					LLMWorkerEvent::ResponseToken(rt) => {
-						if let Some(last) = self.messages.last_mut() {
+					LLMWorkerEvent::ResponseToken { conversation, token: rt } => {
+						let Some(conversation) = self.conversation(conversation) else {
+							return Command::none();
+						};
+						if let Some(last) = conversation.messages.last_mut() {
 							if !last.from_user {
 								last.text.push_str(&rt);
 							} else {
-								self.messages.push(ChatMessage { text: rt, from_user: false });
+								conversation.messages.push(ChatMessage { text: rt, from_user: false });
 							}
 						} else {
-							self.messages.push(ChatMessage { text: rt, from_user: false });
+							conversation.messages.push(ChatMessage { text: rt, from_user: false });
 						}
 
 						return scrollable::snap_to(CHAT_MESSAGES_SCROLLABLE_ID.clone(), RelativeOffset::END);
@@ -118,24 +520,23 @@ impl Application for App {
 				};
 			}
 			AppMessage::Send => {
-				if let Some(ref mut sender) = self.sender {
+				if self.sender.is_some() {
 					let message = std::mem::take(&mut self.message);
-					self.messages.push(ChatMessage {
-						text: message.clone(),
-						from_user: true,
-					});
-					sender.try_send(LLMWorkerCommand::Prompt(message)).unwrap();
+					self.token_count = None;
+					if let Some(conversation) = self.current_mut() {
+						conversation.messages.push(ChatMessage {
+							text: message.clone(),
+							from_user: true,
+						});
+					}
+					self.error = None;
+					self.send_current(|conversation| LLMWorkerCommand::Prompt { conversation, text: message });
 				}
 			}
-			AppMessage::Reset => {
-				self.messages.clear();
-				if let Some(ref mut sender) = self.sender {
-					sender
-						.try_send(LLMWorkerCommand::Reset {
-							task_name: self.selected_task.clone().unwrap(),
-						})
-						.unwrap();
-				}
+			AppMessage::Reset => self.send_current(LLMWorkerCommand::Reset),
+			AppMessage::Regenerate => {
+				self.error = None;
+				self.send_current(LLMWorkerCommand::Regenerate);
 			}
 		};
 
@@ -143,10 +544,33 @@ impl Application for App {
 	}
 
 	fn view(&self) -> Element<AppMessage> {
+		let error_text = self.error.as_ref().map(|(kind, message)| {
+			let text_content = match kind {
+				LLMWorkerErrorKind::ContextFull => format!("{message}. Restart the conversation to continue."),
+				_ => message.clone(),
+			};
+			text(text_content).style(iced::theme::Text::Color(Color::from_rgb8(200, 0, 0)))
+		});
+
 		if self.sender.is_none() {
+			if let Some(error_text) = error_text {
+				// The worker failed to start
+				return container(error_text)
+					.height(Length::Fill)
+					.width(Length::Fill)
+					.align_y(iced::alignment::Vertical::Center)
+					.align_x(Horizontal::Center)
+					.padding(30)
+					.into();
+			}
+
+			let loading_text = match self.connection {
+				Some(_) => "Connecting to server...",
+				None => "Loading models...",
+			};
 			return container(
 				column![
-					text("Loading models...").size(25).horizontal_alignment(Horizontal::Center),
+					text(loading_text).size(25).horizontal_alignment(Horizontal::Center),
 					progress_bar(0.0..=1.0, self.loading_progress as f32)
 				]
 				.spacing(10),
@@ -159,6 +583,11 @@ impl Application for App {
 			.into();
 		}
 
+		let (messages, selected_task) = match self.current() {
+			Some(conversation) => (&conversation.messages[..], conversation.selected_task.clone()),
+			None => (&[][..], None),
+		};
+
 		let input: Element<AppMessage> = if self.running {
 			Element::new(text("Working..."))
 		} else {
@@ -172,31 +601,45 @@ impl Application for App {
 
 		container(
 			column![
+				// Conversations
+				self.conversations_view(),
 				// Toolbar
 				row![
-					if self.messages.is_empty() || self.running {
+					if messages.is_empty() || self.running {
 						Element::new(text(""))
 					} else {
 						button("Restart").on_press(AppMessage::Reset).into()
 					},
 					if self.running {
-						button("Stop").on_press(AppMessage::Interrupt).into()
+						button("Stop").on_press(AppMessage::Stop).into()
+					} else if messages.last().is_some_and(|m| !m.from_user) {
+						button("Regenerate").on_press(AppMessage::Regenerate).into()
+					} else {
+						Element::new(text(""))
+					},
+					if messages.is_empty() || self.running {
+						Element::new(text(""))
 					} else {
+						button("Save").on_press(AppMessage::SaveTranscript).into()
+					},
+					if self.running {
 						Element::new(text(""))
+					} else {
+						button("Open").on_press(AppMessage::LoadTranscript).into()
 					},
 					if self.tasks.is_empty() {
-						Element::new(text(self.selected_task.clone().unwrap_or("".to_string())))
+						Element::new(text(selected_task.unwrap_or("".to_string())))
 					} else {
-						pick_list(&self.tasks, self.selected_task.clone(), AppMessage::ChangeTask)
-							.width(Length::Fill)
-							.into()
+						pick_list(&self.tasks, selected_task, AppMessage::ChangeTask).width(Length::Fill).into()
 					}
 				]
 				.spacing(5)
 				.align_items(Alignment::Center)
 				.width(Length::Fill),
+				// System prompt and sampling parameters
+				self.parameters_view(),
 				// Messages
-				scrollable(if self.messages.is_empty() {
+				scrollable(if messages.is_empty() {
 					Element::new(
 						text("Ready to chat.")
 							.horizontal_alignment(Horizontal::Center)
@@ -206,7 +649,7 @@ impl Application for App {
 				} else {
 					Element::new(
 						column(
-							self.messages
+							messages
 								.iter()
 								.map(|m| -> Element<AppMessage> {
 									m.view().map(|cmm| match cmm {
@@ -221,6 +664,31 @@ impl Application for App {
 				.width(Length::Fill)
 				.height(Length::Fill)
 				.id(CHAT_MESSAGES_SCROLLABLE_ID.clone()),
+				// Errors
+				match error_text {
+					Some(error_text) => Element::new(error_text),
+					None => Element::new(text("")),
+				},
+				// Status bar
+				text(match self.connection {
+					Some(ConnectionStatus::Connecting) => "Connecting to server...",
+					Some(ConnectionStatus::Disconnected) => "Disconnected from server",
+					_ => self.status.as_deref().unwrap_or_default(),
+				})
+				.size(12)
+				.style(iced::theme::Text::Color(Color::from_rgb8(77, 77, 77))),
+				// Size of the message being typed
+				match self.token_count {
+					Some((needed, remaining)) =>
+						Element::new(text(format!("This will use {needed} of {remaining} remaining tokens")).size(12).style(
+							iced::theme::Text::Color(if needed > remaining {
+								Color::from_rgb8(200, 0, 0)
+							} else {
+								Color::from_rgb8(77, 77, 77)
+							})
+						)),
+					None => Element::new(text("")),
+				},
 				// Text input
 				input
 			]
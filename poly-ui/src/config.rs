@@ -0,0 +1,271 @@
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use directories::ProjectDirs;
+use poly_backend::config::{from_toml_file, BackendConfig, ConfigError, Secret};
+use serde::Deserialize;
+
+use crate::{
+	remote::RemoteClient,
+	util::{resource_path, resources_path},
+};
+
+/// Command line options of the application. Each can also be set using an environment variable.
+#[derive(Parser, Debug, Clone, Default)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+	/// Configuration file to use (by default the user's configuration file when it exists, or else the built-in one)
+	#[arg(long = "config", env = "POLY_UI_CONFIG")]
+	pub config_path: Option<PathBuf>,
+
+	/// Folder that relative model paths are resolved against (the data folder by default)
+	#[arg(long, env = "POLY_UI_MODELS_DIR")]
+	pub models_dir: Option<PathBuf>,
+
+	/// Check the configuration and that the model files it refers to exist, print the problems found and exit
+	#[arg(long)]
+	pub check: bool,
+}
+
+impl Args {
+	/// The configuration file to read: the one specified, else the user's configuration file when it exists, else the
+	/// built-in one
+	pub fn config_file_path(&self) -> PathBuf {
+		if let Some(ref path) = self.config_path {
+			tracing::info!("Using configuration file {}", path.display());
+			return path.clone();
+		}
+
+		// Check if the user has a local override config
+		if let Some(proj_dirs) = ProjectDirs::from("nl", "Dialogic", "Poly") {
+			let user_config_path = proj_dirs.config_dir().join("config.toml");
+			tracing::info!("Looking for configuration file at {}", user_config_path.display());
+			if user_config_path.exists() {
+				tracing::info!("Using user configuration file");
+				return user_config_path;
+			}
+		}
+		tracing::info!("Using built-in configuration file");
+		resource_path("config.toml")
+	}
+
+	/// The folder relative model paths are resolved against
+	pub fn models_dir(&self) -> PathBuf {
+		self.models_dir.clone().unwrap_or_else(resources_path)
+	}
+}
+
+/// Configuration of the application: the backend configuration, optionally with a remote server to use instead
+#[derive(Deserialize, Debug)]
+pub struct UiConfig {
+	#[serde(flatten)]
+	pub backend: BackendConfig,
+
+	/// When set, sessions are held with a remote llmd server and no models are loaded locally
+	pub remote: Option<RemoteConfig>,
+
+	/// What to do when a conversation no longer fits in the context window
+	#[serde(default)]
+	pub context: ContextConfig,
+
+	/// Maximum number of conversations that can be open at the same time. Each conversation holds a session, which
+	/// takes memory for the context window of its model.
+	#[serde(default = "default_max_conversations")]
+	pub max_conversations: usize,
+
+	/// Folder to save the open conversations to when the application quits (each as a transcript named after the
+	/// conversation). Conversations are not saved when not set.
+	pub autosave_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct RemoteConfig {
+	/// Base URL of the server (e.g. "http://localhost:3000")
+	pub url: String,
+
+	/// API key to authenticate with (one of the `allowed_keys` of the server, or a JWT token)
+	pub api_key: Option<Secret>,
+
+	/// Number of times to try to (re)connect to the server before giving up
+	#[serde(default = "default_reconnect_attempts")]
+	pub reconnect_attempts: usize,
+
+	/// Time to wait between two attempts to connect, in seconds
+	#[serde(default = "default_reconnect_interval_secs")]
+	pub reconnect_interval_secs: u64,
+}
+
+/// How to make room in the context window of a session when a conversation grows too long
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+	/// Do nothing; the conversation has to be reset to continue
+	None,
+
+	/// Start a new session with only the most recent turns
+	#[default]
+	Truncate,
+
+	/// Start a new session with a summary of the older turns (written by the model) and the most recent turns
+	Summarize,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ContextConfig {
+	pub strategy: ContextStrategy,
+
+	/// Make room when fewer tokens than this remain in the context window after a response
+	pub min_remaining_tokens: usize,
+
+	/// Number of most recent turns to keep (fewer are kept when they do not fit)
+	pub keep_turns: usize,
+
+	/// Prompt asking the model to summarize a conversation, which is appended to it
+	pub summary_prompt: String,
+
+	/// Prompt that the summary is presented as the response to in the new session
+	pub recap_prompt: String,
+}
+
+impl Default for ContextConfig {
+	fn default() -> Self {
+		ContextConfig {
+			strategy: ContextStrategy::default(),
+			min_remaining_tokens: 64,
+			keep_turns: 2,
+			summary_prompt: String::from("Summarize the following conversation in a few sentences."),
+			recap_prompt: String::from("What have we discussed so far?"),
+		}
+	}
+}
+
+impl UiConfig {
+	/// Read the configuration from a file, resolving relative model paths against `models_dir`
+	pub fn from_file(path: &Path, models_dir: &Path) -> Result<UiConfig, ConfigError> {
+		let (mut config, sources): (UiConfig, _) = from_toml_file(path)?;
+		config.backend.sources = sources;
+		config.resolve_model_paths(models_dir);
+		Ok(config)
+	}
+
+	/// Resolve relative model paths against `models_dir`. Paths may also be prefixed with '@' to mark them as relative.
+	fn resolve_model_paths(&mut self, models_dir: &Path) {
+		for model_config in self.backend.models.values_mut() {
+			if let Some(ref model_path) = model_config.model_path {
+				let relative = match model_path.to_str().and_then(|p| p.strip_prefix('@')) {
+					Some(relative) => Path::new(relative),
+					None if model_path.is_absolute() => continue,
+					None => model_path.as_path(),
+				};
+				model_config.model_path = Some(models_dir.join(relative));
+			}
+		}
+	}
+
+	/// Problems with the configuration, including model files that do not exist (also when they would be downloaded, as
+	/// a bundle should include its models). A configuration that uses a remote server has no models to check.
+	pub fn problems(&self) -> Vec<String> {
+		if let Some(ref remote) = self.remote {
+			return match RemoteClient::new(remote.clone()) {
+				Ok(_) => vec![],
+				Err(e) => vec![format!("remote: {e}")],
+			};
+		}
+
+		let mut problems: Vec<String> = self.backend.check().iter().map(|p| p.to_string()).collect();
+		let mut model_names: Vec<&String> = self.backend.models.keys().collect();
+		model_names.sort();
+		for model_name in model_names {
+			let model_config = &self.backend.models[model_name];
+			if let (Some(path), Some(url)) = (&model_config.model_path, &model_config.url) {
+				if !path.exists() {
+					problems.push(format!(
+						"models.{model_name}: model file {} does not exist (it would be downloaded from {url})",
+						path.display()
+					));
+				}
+			}
+		}
+		problems
+	}
+}
+
+pub const fn default_max_conversations() -> usize {
+	4
+}
+
+const fn default_reconnect_attempts() -> usize {
+	5
+}
+
+const fn default_reconnect_interval_secs() -> u64 {
+	2
+}
+
+#[cfg(test)]
+mod test {
+	use std::path::{Path, PathBuf};
+
+	use poly_backend::config::from_toml_str;
+
+	use super::{ContextStrategy, UiConfig};
+
+	#[test]
+	fn test_ui_config() {
+		let config: UiConfig = from_toml_str(
+			r#"
+			[models.gpt2]
+			model_path = "@gpt2.bin"
+			architecture = "gpt2"
+
+			[tasks.chat]
+			model = "gpt2"
+			"#,
+		)
+		.unwrap();
+		assert!(config.remote.is_none());
+		assert!(config.backend.tasks.contains_key("chat"));
+		assert_eq!(config.max_conversations, 4);
+
+		let config: UiConfig = from_toml_str(
+			r#"
+			[remote]
+			url = "https://llm.example.com"
+			api_key = "secret"
+			"#,
+		)
+		.unwrap();
+		let remote = config.remote.unwrap();
+		assert_eq!(remote.url, "https://llm.example.com");
+		assert_eq!(remote.api_key.unwrap().expose(), "secret");
+		assert_eq!(remote.reconnect_attempts, 5);
+
+		let config: UiConfig = from_toml_str("[context]\nstrategy = \"summarize\"\nkeep_turns = 4\n").unwrap();
+		assert_eq!(config.context.strategy, ContextStrategy::Summarize);
+		assert_eq!(config.context.keep_turns, 4);
+		assert_eq!(config.context.min_remaining_tokens, 64);
+	}
+
+	#[test]
+	fn test_resolve_model_paths() {
+		let absolute = std::env::temp_dir().join("gpt2.bin");
+		let mut config: UiConfig = from_toml_str(&format!(
+			"[models.a]\nmodel_path = \"@a.bin\"\narchitecture = \"gpt2\"\n\
+			[models.b]\nmodel_path = \"models/b.bin\"\narchitecture = \"gpt2\"\n\
+			[models.c]\nmodel_path = {:?}\narchitecture = \"gpt2\"\n",
+			absolute.to_str().unwrap()
+		))
+		.unwrap();
+		let models_dir = Path::new("shared").join("models");
+		config.resolve_model_paths(&models_dir);
+		let path = |name: &str| config.backend.models[name].model_path.clone().unwrap();
+		assert_eq!(path("a"), models_dir.join("a.bin"));
+		assert_eq!(path("b"), models_dir.join(PathBuf::from("models/b.bin")));
+		assert_eq!(path("c"), absolute);
+
+		// Model files that do not exist are reported
+		let problems = config.problems();
+		assert!(problems.iter().any(|p| p.starts_with("models.a")), "{problems:?}");
+	}
+}
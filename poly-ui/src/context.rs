@@ -0,0 +1,298 @@
+use poly_backend::{
+	backend::InferenceFeedback,
+	types::{BackendError, PromptRequest},
+};
+
+use crate::{
+	config::{ContextConfig, ContextStrategy},
+	session::{ChatSession, SessionError},
+	transcript::Turn,
+};
+
+/// A conversation that was rebuilt in a new session to make room in the context window
+pub struct Reduction {
+	pub session: Box<dyn ChatSession>,
+
+	/// The conversation as held by the new session (starting with the recap, when the older turns were summarized)
+	pub turns: Vec<Turn>,
+
+	/// Number of turns of the original conversation that are no longer held by the session
+	pub dropped_turns: usize,
+	pub summary: Option<String>,
+
+	/// Set when the older turns could not be summarized (and were dropped instead)
+	pub warning: Option<String>,
+}
+
+/// Rebuild a conversation in a new session (obtained from `start`), keeping only the most recent turns and (depending on
+/// the strategy) a summary of the older ones. At least one turn is dropped. When the kept turns do not fit, fewer are
+/// kept; those are not included in the summary.
+pub fn reduce(
+	start: &mut dyn FnMut() -> Result<Box<dyn ChatSession>, SessionError>,
+	turns: &[Turn],
+	config: &ContextConfig,
+) -> Result<Reduction, SessionError> {
+	let keep = config.keep_turns.min(turns.len().saturating_sub(1));
+	let (older, recent) = turns.split_at(turns.len() - keep);
+
+	let mut warning = None;
+	let summary = if config.strategy == ContextStrategy::Summarize && !older.is_empty() {
+		match summarize(start()?.as_mut(), older, config) {
+			Ok(summary) => Some(summary),
+			Err(e) => {
+				tracing::warn!("could not summarize conversation: {e}");
+				warning = Some(format!(
+					"the conversation could not be summarized ({e}); older turns were dropped instead"
+				));
+				None
+			}
+		}
+	} else {
+		None
+	};
+	let recap = summary.as_ref().map(|summary| Turn {
+		prompt: config.recap_prompt.clone(),
+		response: summary.clone(),
+	});
+
+	for skip in 0..=recent.len() {
+		let kept: Vec<Turn> = recap.iter().chain(&recent[skip..]).cloned().collect();
+		let mut session = start()?;
+		match replay(session.as_mut(), &kept, config) {
+			Ok(()) => {
+				return Ok(Reduction {
+					session,
+					turns: kept,
+					dropped_turns: older.len() + skip,
+					summary,
+					warning,
+				})
+			}
+			Err(e) if e.is_context_full() => tracing::debug!("{} turns do not fit in the context window", kept.len()),
+			Err(e) => return Err(e),
+		}
+	}
+
+	// Not even the summary fits
+	Ok(Reduction {
+		session: start()?,
+		turns: vec![],
+		dropped_turns: turns.len(),
+		summary: None,
+		warning,
+	})
+}
+
+/// Feed turns to a session. Fails with a context-full error when less room than configured remains afterwards.
+fn replay(session: &mut dyn ChatSession, turns: &[Turn], config: &ContextConfig) -> Result<(), SessionError> {
+	for turn in turns {
+		session.replay(&turn.prompt, &turn.response)?;
+	}
+	match session.context_remaining() {
+		Some(remaining) if remaining < config.min_remaining_tokens => Err(BackendError::ContextFull {
+			needed: config.min_remaining_tokens,
+			available: remaining,
+		}
+		.into()),
+		_ => Ok(()),
+	}
+}
+
+/// Ask the model (in a separate session) to summarize the turns
+fn summarize(session: &mut dyn ChatSession, turns: &[Turn], config: &ContextConfig) -> Result<String, SessionError> {
+	let mut prompt = config.summary_prompt.clone();
+	for turn in turns {
+		prompt.push_str(&format!("\n\nUser: {}\nAssistant: {}", turn.prompt.trim(), turn.response.trim()));
+	}
+
+	let mut summary = String::new();
+	session.complete(&PromptRequest::new(prompt), &mut |token| {
+		summary.push_str(&token);
+		InferenceFeedback::Continue
+	})?;
+	Ok(summary.trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::{Read, Write};
+
+	use poly_backend::{
+		backend::InferenceFeedback,
+		session::{Completion, SessionCheckpoint, TokenCount},
+		types::{BackendError, FinishReason, PromptRequest},
+	};
+
+	use super::{reduce, replay};
+	use crate::{
+		config::{ContextConfig, ContextStrategy},
+		session::{ChatSession, SessionError},
+		transcript::Turn,
+	};
+
+	/// A model with a tiny context window, that counts words as tokens and always gives the same response
+	struct TinySession {
+		context_size: usize,
+		used: usize,
+		response: &'static str,
+	}
+
+	impl TinySession {
+		fn feed(&mut self, text: &str) -> Result<(), SessionError> {
+			let needed = text.split_whitespace().count();
+			let available = self.context_size.saturating_sub(self.used);
+			self.used += needed;
+			if needed > available {
+				return Err(BackendError::ContextFull { needed, available }.into());
+			}
+			Ok(())
+		}
+	}
+
+	impl ChatSession for TinySession {
+		fn task_name(&self) -> &str {
+			"tiny"
+		}
+
+		fn model_name(&self) -> &str {
+			"tiny"
+		}
+
+		fn complete(&mut self, request: &PromptRequest, callback: &mut dyn FnMut(String) -> InferenceFeedback) -> Result<Completion, SessionError> {
+			self.feed(&request.prompt)?;
+			for word in self.response.split_whitespace() {
+				self.feed(word)?;
+				callback(format!("{word} "));
+			}
+			Ok(Completion::new(FinishReason::Eot))
+		}
+
+		fn replay(&mut self, prompt: &str, response: &str) -> Result<(), SessionError> {
+			self.feed(prompt)?;
+			self.feed(response)
+		}
+
+		fn context_remaining(&self) -> Option<usize> {
+			Some(self.context_size.saturating_sub(self.used))
+		}
+
+		fn count_tokens(&self, prompt: &str) -> Result<TokenCount, SessionError> {
+			Ok(TokenCount {
+				prompt: prompt.split_whitespace().count(),
+				overhead: 0,
+				used: self.used,
+				pinned: 0,
+				context: self.context_size,
+			})
+		}
+
+		fn checkpoint(&self) -> Option<SessionCheckpoint> {
+			None
+		}
+
+		fn rewind_to(&mut self, _checkpoint: &SessionCheckpoint) -> Result<(), SessionError> {
+			Err(SessionError::Unsupported("rewinding"))
+		}
+
+		fn save_state(&mut self, _writer: &mut dyn Write) -> Result<(), SessionError> {
+			Err(SessionError::Unsupported("saving"))
+		}
+
+		fn restore_state(&mut self, _reader: &mut dyn Read) -> Result<(), SessionError> {
+			Err(SessionError::Unsupported("restoring"))
+		}
+	}
+
+	fn turns(n: usize) -> Vec<Turn> {
+		(0..n)
+			.map(|i| Turn {
+				prompt: format!("question {i}"),
+				response: format!("answer {i}"),
+			})
+			.collect()
+	}
+
+	fn start(context_size: usize) -> impl FnMut() -> Result<Box<dyn ChatSession>, SessionError> {
+		move || {
+			let session: Box<dyn ChatSession> = Box::new(TinySession {
+				context_size,
+				used: 0,
+				response: "we talked about things",
+			});
+			Ok(session)
+		}
+	}
+
+	fn config(strategy: ContextStrategy) -> ContextConfig {
+		ContextConfig {
+			strategy,
+			min_remaining_tokens: 2,
+			keep_turns: 2,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_truncate() {
+		let reduction = reduce(&mut start(16), &turns(5), &config(ContextStrategy::Truncate)).unwrap();
+		assert_eq!(reduction.turns, turns(5)[3..]);
+		assert_eq!(reduction.dropped_turns, 3);
+		assert!(reduction.summary.is_none());
+		assert_eq!(reduction.session.context_remaining(), Some(8));
+
+		// At least one turn is dropped
+		let reduction = reduce(&mut start(16), &turns(2), &config(ContextStrategy::Truncate)).unwrap();
+		assert_eq!(reduction.turns, turns(2)[1..]);
+
+		// Fewer turns are kept when they do not fit
+		let reduction = reduce(&mut start(6), &turns(5), &config(ContextStrategy::Truncate)).unwrap();
+		assert_eq!(reduction.turns, turns(5)[4..]);
+		assert_eq!(reduction.dropped_turns, 4);
+
+		let reduction = reduce(&mut start(3), &turns(5), &config(ContextStrategy::Truncate)).unwrap();
+		assert!(reduction.turns.is_empty());
+		assert_eq!(reduction.dropped_turns, 5);
+	}
+
+	#[test]
+	fn test_replay() {
+		let config = config(ContextStrategy::Truncate);
+
+		// The turns do not fit
+		let mut session = start(15)().unwrap();
+		let error = replay(session.as_mut(), &turns(4), &config).unwrap_err();
+		assert!(matches!(
+			error,
+			SessionError::Backend(BackendError::ContextFull { needed: 2, available: 1 })
+		));
+
+		// The turns fit, but leave less room than configured
+		let mut session = start(17)().unwrap();
+		let error = replay(session.as_mut(), &turns(4), &config).unwrap_err();
+		assert!(matches!(
+			error,
+			SessionError::Backend(BackendError::ContextFull { needed: 2, available: 1 })
+		));
+
+		let mut session = start(18)().unwrap();
+		assert!(replay(session.as_mut(), &turns(4), &config).is_ok());
+	}
+
+	#[test]
+	fn test_summarize() {
+		let config = config(ContextStrategy::Summarize);
+		let reduction = reduce(&mut start(32), &turns(5), &config).unwrap();
+		assert_eq!(reduction.summary.as_deref(), Some("we talked about things"));
+		assert_eq!(reduction.turns.len(), 3);
+		assert_eq!(reduction.turns[0].prompt, config.recap_prompt);
+		assert_eq!(reduction.turns[1..], turns(5)[3..]);
+		assert_eq!(reduction.dropped_turns, 3);
+		assert!(reduction.warning.is_none());
+
+		// When the conversation is too long to summarize, the older turns are dropped
+		let reduction = reduce(&mut start(16), &turns(5), &config).unwrap();
+		assert!(reduction.summary.is_none());
+		assert!(reduction.warning.is_some());
+		assert_eq!(reduction.turns, turns(5)[3..]);
+	}
+}
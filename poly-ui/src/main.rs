@@ -1,19 +1,53 @@
+use clap::Parser;
 use iced::{Application, Settings};
 
 mod app;
 mod components;
+mod config;
+mod context;
+mod remote;
+mod session;
+mod transcript;
 mod util;
 mod worker;
 use app::App;
+use config::{Args, UiConfig};
 
 pub fn main() -> iced::Result {
 	tracing_subscriber::fmt::init();
+	let args = Args::parse();
+	if args.check {
+		std::process::exit(check(&args));
+	}
+
 	App::run(Settings {
+		flags: args,
 		window: iced::window::Settings {
 			size: (400, 700),
 			min_size: Some((200, 200)),
+			// The worker is shut down first (see `AppMessage::CloseRequested`)
+			exit_on_close_request: false,
 			..Default::default()
 		},
 		..Default::default()
 	})
 }
+
+/// Check the configuration the app would use and the model files it refers to without opening a window (so that bundles
+/// can be validated). Prints the problems found and returns the exit code.
+fn check(args: &Args) -> i32 {
+	let config_file_path = args.config_file_path();
+	println!("Checking {}", config_file_path.display());
+	let problems = match UiConfig::from_file(&config_file_path, &args.models_dir()) {
+		Ok(config) => config.problems(),
+		Err(e) => vec![format!("{}: {e}", config_file_path.display())],
+	};
+	if problems.is_empty() {
+		println!("No problems found");
+		return 0;
+	}
+	for problem in &problems {
+		eprintln!("{problem}");
+	}
+	1
+}
@@ -0,0 +1,275 @@
+use std::{
+	io::{Read, Write},
+	time::{Duration, Instant},
+};
+
+use iced::futures::{SinkExt, StreamExt};
+use poly_backend::{
+	backend::InferenceFeedback,
+	session::{Completion, InferenceStats, SessionCheckpoint, TokenCount},
+	stats::TokenUsage,
+	types::{FinishReason, PromptRequest},
+};
+use reqwest::{header::AUTHORIZATION, StatusCode};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+	tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+	MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+	config::RemoteConfig,
+	session::{ChatSession, SessionError},
+};
+
+#[derive(Error, Debug)]
+pub enum RemoteError {
+	#[error("invalid server URL '{0}': must start with http:// or https://")]
+	InvalidUrl(String),
+
+	#[error("the API key cannot be sent in a header")]
+	InvalidApiKey,
+
+	#[error("request failed: {0}")]
+	Http(#[from] reqwest::Error),
+
+	#[error("server responded with status {0}")]
+	Status(StatusCode),
+
+	#[error("connection failed: {0}")]
+	WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+
+	#[error("the server closed the connection")]
+	Closed,
+}
+
+impl RemoteError {
+	/// Whether the server could not be reached or the connection was lost (as opposed to the server refusing a request)
+	pub fn is_transient(&self) -> bool {
+		matches!(self, RemoteError::Http(_) | RemoteError::WebSocket(_) | RemoteError::Closed)
+	}
+}
+
+#[derive(Deserialize)]
+struct TasksResponse {
+	tasks: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TaskResponse {
+	model: String,
+}
+
+/// Client for the API of a remote llmd server
+pub struct RemoteClient {
+	config: RemoteConfig,
+	http: reqwest::Client,
+}
+
+impl RemoteClient {
+	pub fn new(config: RemoteConfig) -> Result<RemoteClient, RemoteError> {
+		if !config.url.starts_with("http://") && !config.url.starts_with("https://") {
+			return Err(RemoteError::InvalidUrl(config.url));
+		}
+		Ok(RemoteClient {
+			config,
+			http: reqwest::Client::new(),
+		})
+	}
+
+	pub fn url(&self) -> &str {
+		self.config.url.trim_end_matches('/')
+	}
+
+	pub fn reconnect_attempts(&self) -> usize {
+		self.config.reconnect_attempts
+	}
+
+	pub fn reconnect_interval(&self) -> Duration {
+		Duration::from_secs(self.config.reconnect_interval_secs)
+	}
+
+	fn authorization(&self) -> Option<String> {
+		self.config.api_key.as_ref().map(|key| format!("Bearer {}", key.expose()))
+	}
+
+	async fn get<T: for<'de> Deserialize<'de>>(&self, path: &str) -> Result<T, RemoteError> {
+		let mut request = self.http.get(format!("{}{path}", self.url()));
+		if let Some(authorization) = self.authorization() {
+			request = request.header(AUTHORIZATION, authorization);
+		}
+		let response = request.send().await?;
+		if !response.status().is_success() {
+			return Err(RemoteError::Status(response.status()));
+		}
+		Ok(response.json().await?)
+	}
+
+	/// Returns the names of the tasks available on the server
+	pub async fn tasks(&self) -> Result<Vec<String>, RemoteError> {
+		let mut tasks = self.get::<TasksResponse>("/v1/task").await?.tasks;
+		tasks.sort();
+		Ok(tasks)
+	}
+
+	/// Returns the name of the model a task runs on
+	async fn task_model(&self, task_name: &str) -> Result<String, RemoteError> {
+		Ok(self.get::<TaskResponse>(&format!("/v1/task/{task_name}")).await?.model)
+	}
+
+	/// Open the chat WebSocket of a task
+	async fn chat(&self, task_name: &str) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, RemoteError> {
+		// The URL was checked to start with http:// or https:// in `new`
+		let url = format!("ws{}/v1/task/{task_name}/chat", self.url().strip_prefix("http").unwrap());
+		let mut request = url.into_client_request()?;
+		if let Some(authorization) = self.authorization() {
+			let value = HeaderValue::from_str(&authorization).map_err(|_| RemoteError::InvalidApiKey)?;
+			request.headers_mut().insert(AUTHORIZATION, value);
+		}
+		let (socket, _response) = tokio_tungstenite::connect_async(request).await?;
+		Ok(socket)
+	}
+}
+
+/// A session held through the chat WebSocket of a remote server. The server keeps the conversation for as long as the
+/// connection is open.
+pub struct RemoteSession {
+	task_name: String,
+	model_name: String,
+	socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl RemoteSession {
+	pub async fn connect(client: &RemoteClient, task_name: &str) -> Result<RemoteSession, RemoteError> {
+		let model_name = client.task_model(task_name).await?;
+		let socket = client.chat(task_name).await?;
+		Ok(RemoteSession {
+			task_name: task_name.to_string(),
+			model_name,
+			socket,
+		})
+	}
+
+	/// Receive the next part of a response
+	async fn receive(&mut self) -> Result<String, RemoteError> {
+		loop {
+			match self.socket.next().await {
+				None | Some(Ok(Message::Close(_))) => return Err(RemoteError::Closed),
+				Some(Err(e)) => return Err(e.into()),
+				Some(Ok(Message::Text(token))) => return Ok(token),
+				Some(Ok(_)) => {}
+			}
+		}
+	}
+}
+
+impl ChatSession for RemoteSession {
+	fn task_name(&self) -> &str {
+		&self.task_name
+	}
+
+	fn model_name(&self) -> &str {
+		&self.model_name
+	}
+
+	fn complete(&mut self, request: &PromptRequest, callback: &mut dyn FnMut(String) -> InferenceFeedback) -> Result<Completion, SessionError> {
+		// The chat endpoint only accepts the prompt text
+		if request.has_overrides() {
+			return Err(SessionError::Unsupported("changing parameters"));
+		}
+
+		// The callback may block, so it is not called from within the runtime
+		let runtime = tokio::runtime::Handle::current();
+		runtime
+			.block_on(self.socket.send(Message::Text(request.text().into_owned())))
+			.map_err(RemoteError::from)?;
+
+		// The server sends the response token by token, followed by an empty message. When the callback asks to halt,
+		// the rest of the response is still received (but not passed on), as the server cannot be asked to stop. The
+		// server does not say why the response ended, so it is assumed the model ended it.
+		let started = Instant::now();
+		let mut first_token: Option<Instant> = None;
+		let mut tokens = 0;
+		let mut halted = false;
+		loop {
+			let token = runtime.block_on(self.receive())?;
+			if token.is_empty() {
+				break;
+			}
+			first_token.get_or_insert_with(Instant::now);
+			tokens += 1;
+			if !halted {
+				halted = matches!(callback(token), InferenceFeedback::Halt);
+			}
+		}
+
+		// The number of prompt tokens is not known; the time until the first token is counted as feeding the prompt
+		let first_token = first_token.unwrap_or_else(Instant::now);
+		let stats = InferenceStats {
+			feed_prompt_duration: first_token.duration_since(started),
+			prompt_tokens: 0,
+			predict_duration: first_token.elapsed(),
+			predict_tokens: tokens,
+		};
+		let usage = TokenUsage {
+			sampled_tokens: tokens,
+			..TokenUsage::default()
+		};
+		let finish_reason = if halted { FinishReason::Cancelled } else { FinishReason::Eot };
+		Ok(Completion {
+			stats,
+			usage,
+			duration: started.elapsed(),
+			..Completion::new(finish_reason)
+		})
+	}
+
+	fn replay(&mut self, _prompt: &str, _response: &str) -> Result<(), SessionError> {
+		Err(SessionError::Unsupported("restoring a conversation"))
+	}
+
+	fn context_remaining(&self) -> Option<usize> {
+		None
+	}
+
+	fn count_tokens(&self, _prompt: &str) -> Result<TokenCount, SessionError> {
+		Err(SessionError::Unsupported("counting tokens"))
+	}
+
+	fn checkpoint(&self) -> Option<SessionCheckpoint> {
+		None
+	}
+
+	fn rewind_to(&mut self, _checkpoint: &SessionCheckpoint) -> Result<(), SessionError> {
+		Err(SessionError::Unsupported("rewinding the conversation"))
+	}
+
+	fn save_state(&mut self, _writer: &mut dyn Write) -> Result<(), SessionError> {
+		Err(SessionError::Unsupported("saving the session state"))
+	}
+
+	fn restore_state(&mut self, _reader: &mut dyn Read) -> Result<(), SessionError> {
+		Err(SessionError::Unsupported("restoring the session state"))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{RemoteClient, RemoteError};
+	use crate::config::RemoteConfig;
+
+	#[test]
+	fn test_remote_url() {
+		let config = |url: &str| RemoteConfig {
+			url: url.to_string(),
+			..Default::default()
+		};
+		assert_eq!(
+			RemoteClient::new(config("http://localhost:3000/")).unwrap().url(),
+			"http://localhost:3000"
+		);
+		assert!(matches!(RemoteClient::new(config("localhost:3000")), Err(RemoteError::InvalidUrl(_))));
+	}
+}
@@ -0,0 +1,151 @@
+use std::{
+	io::{Read, Write},
+	sync::Arc,
+};
+
+use poly_backend::{
+	backend::{Backend, InferenceFeedback, InferenceResponse},
+	config::TaskConfig,
+	session::{BackendSession, Completion, SessionCheckpoint, TokenCount},
+	types::{BackendError, PromptRequest, SessionRequest},
+};
+use thiserror::Error;
+
+use crate::remote::{RemoteClient, RemoteError, RemoteSession};
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+	#[error("{0}")]
+	Backend(#[from] BackendError),
+
+	#[error("{0}")]
+	Remote(#[from] RemoteError),
+
+	#[error("{0} is not supported for remote sessions")]
+	Unsupported(&'static str),
+}
+
+impl SessionError {
+	/// Whether the error is caused by a connection problem, which may be resolved by connecting again
+	pub fn is_transient(&self) -> bool {
+		matches!(self, SessionError::Remote(e) if e.is_transient())
+	}
+
+	pub fn is_context_full(&self) -> bool {
+		matches!(self, SessionError::Backend(BackendError::ContextFull { .. }))
+	}
+}
+
+/// A conversation held by the worker: either with a model loaded in this process or through a remote server. The
+/// methods block and should be called from a blocking thread.
+pub trait ChatSession: Send {
+	fn task_name(&self) -> &str;
+
+	fn model_name(&self) -> &str;
+
+	/// Generate a response to the prompt in the request, calling `callback` for each generated token until it returns
+	/// `Halt`
+	fn complete(&mut self, request: &PromptRequest, callback: &mut dyn FnMut(String) -> InferenceFeedback) -> Result<Completion, SessionError>;
+
+	/// Feed an earlier prompt and response to the session without generating anything
+	fn replay(&mut self, prompt: &str, response: &str) -> Result<(), SessionError>;
+
+	/// Number of tokens that still fit in the context window. Returns `None` when this is not known.
+	fn context_remaining(&self) -> Option<usize>;
+
+	/// Count the tokens a prompt would take in the context window, without feeding it. This is cheap enough to call
+	/// while the prompt is being typed.
+	fn count_tokens(&self, prompt: &str) -> Result<TokenCount, SessionError>;
+
+	/// Mark the current position in the conversation. Returns `None` when the session cannot be rewound.
+	fn checkpoint(&self) -> Option<SessionCheckpoint>;
+
+	/// Undo everything that happened in the session after the checkpoint was taken
+	fn rewind_to(&mut self, checkpoint: &SessionCheckpoint) -> Result<(), SessionError>;
+
+	fn save_state(&mut self, writer: &mut dyn Write) -> Result<(), SessionError>;
+
+	fn restore_state(&mut self, reader: &mut dyn Read) -> Result<(), SessionError>;
+}
+
+impl ChatSession for BackendSession {
+	fn task_name(&self) -> &str {
+		BackendSession::task_name(self)
+	}
+
+	fn model_name(&self) -> &str {
+		BackendSession::model_name(self)
+	}
+
+	fn complete(&mut self, request: &PromptRequest, callback: &mut dyn FnMut(String) -> InferenceFeedback) -> Result<Completion, SessionError> {
+		let completion = BackendSession::complete(self, request, |response| match response {
+			InferenceResponse::InferredToken(token) => Ok(callback(token)),
+			InferenceResponse::EotToken => Ok(InferenceFeedback::Halt),
+			InferenceResponse::SnapshotToken(_) | InferenceResponse::PromptToken(_) => Ok(InferenceFeedback::Continue),
+		})?;
+		Ok(completion)
+	}
+
+	fn replay(&mut self, prompt: &str, response: &str) -> Result<(), SessionError> {
+		let request = PromptRequest::new(prompt);
+		BackendSession::replay(self, &request, response)?;
+		Ok(())
+	}
+
+	fn context_remaining(&self) -> Option<usize> {
+		Some(BackendSession::context_remaining(self))
+	}
+
+	fn count_tokens(&self, prompt: &str) -> Result<TokenCount, SessionError> {
+		Ok(BackendSession::count_tokens(self, prompt)?)
+	}
+
+	fn checkpoint(&self) -> Option<SessionCheckpoint> {
+		Some(BackendSession::checkpoint(self))
+	}
+
+	fn rewind_to(&mut self, checkpoint: &SessionCheckpoint) -> Result<(), SessionError> {
+		Ok(BackendSession::rewind_to(self, checkpoint)?)
+	}
+
+	fn save_state(&mut self, writer: &mut dyn Write) -> Result<(), SessionError> {
+		Ok(BackendSession::save_state(self, writer)?)
+	}
+
+	fn restore_state(&mut self, reader: &mut dyn Read) -> Result<(), SessionError> {
+		Ok(BackendSession::restore_state(self, reader)?)
+	}
+}
+
+/// Where the worker gets its sessions from
+#[derive(Clone)]
+pub enum WorkerBackend {
+	/// Models are loaded in this process
+	Local(Arc<Backend>),
+
+	/// Sessions are held with a remote server
+	Remote(Arc<RemoteClient>),
+}
+
+impl WorkerBackend {
+	/// Start a new session for a task
+	pub async fn start(&self, task_name: &str, request: &SessionRequest) -> Result<Box<dyn ChatSession>, SessionError> {
+		match self {
+			WorkerBackend::Local(backend) => Ok(Box::new(backend.start(task_name, request, backend.clone())?)),
+			WorkerBackend::Remote(_) if request.prefix.is_some() => Err(SessionError::Unsupported("changing the system prompt")),
+			WorkerBackend::Remote(client) => Ok(Box::new(RemoteSession::connect(client, task_name).await?)),
+		}
+	}
+
+	/// Returns the configuration of a task (`None` when it is not known, which is always the case for a remote server)
+	pub fn task_config(&self, task_name: &str) -> Option<TaskConfig> {
+		match self {
+			WorkerBackend::Local(backend) => backend.task(task_name),
+			WorkerBackend::Remote(_) => None,
+		}
+	}
+
+	pub fn is_remote(&self) -> bool {
+		matches!(self, WorkerBackend::Remote(_))
+	}
+}
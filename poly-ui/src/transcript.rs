@@ -0,0 +1,224 @@
+use std::{
+	fs::File,
+	io::{BufReader, BufWriter, Write},
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::session::{ChatSession, SessionError};
+
+/// Version of the transcript format written by this version of the application. Transcripts with a higher version
+/// cannot be read.
+pub const TRANSCRIPT_VERSION: u32 = 1;
+
+/// Extension of the file that holds the session state, which is saved next to the transcript
+const STATE_EXTENSION: &str = "state";
+
+#[derive(Error, Debug)]
+pub enum TranscriptError {
+	#[error("could not access {}: {error}", path.display())]
+	Io { path: PathBuf, error: std::io::Error },
+
+	#[error("invalid transcript {}: {error}", path.display())]
+	Format { path: PathBuf, error: serde_json::Error },
+
+	#[error("transcript {} has version {version}; only versions up to {} are supported", path.display(), TRANSCRIPT_VERSION)]
+	UnsupportedVersion { path: PathBuf, version: u32 },
+
+	#[error("could not restore conversation: {0}")]
+	Session(#[from] SessionError),
+}
+
+/// A prompt and the response to it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Turn {
+	pub prompt: String,
+	pub response: String,
+}
+
+/// A conversation as saved to disk
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transcript {
+	pub version: u32,
+	pub task: String,
+
+	/// The model the conversation was held with. The session state can only be restored for the same model.
+	pub model: String,
+	pub turns: Vec<Turn>,
+
+	/// File holding the state of the session after the last turn, relative to the transcript
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub state: Option<PathBuf>,
+}
+
+impl Transcript {
+	pub fn new(task: &str, model: &str, turns: Vec<Turn>) -> Transcript {
+		Transcript {
+			version: TRANSCRIPT_VERSION,
+			task: task.to_string(),
+			model: model.to_string(),
+			turns,
+			state: None,
+		}
+	}
+
+	pub fn read(path: &Path) -> Result<Transcript, TranscriptError> {
+		let file = File::open(path).map_err(|error| TranscriptError::Io {
+			path: path.to_path_buf(),
+			error,
+		})?;
+		let transcript: Transcript = serde_json::from_reader(BufReader::new(file)).map_err(|error| TranscriptError::Format {
+			path: path.to_path_buf(),
+			error,
+		})?;
+		if transcript.version > TRANSCRIPT_VERSION {
+			return Err(TranscriptError::UnsupportedVersion {
+				path: path.to_path_buf(),
+				version: transcript.version,
+			});
+		}
+		Ok(transcript)
+	}
+
+	pub fn write(&self, path: &Path) -> Result<(), TranscriptError> {
+		let io_error = |error| TranscriptError::Io {
+			path: path.to_path_buf(),
+			error,
+		};
+		let mut writer = BufWriter::new(File::create(path).map_err(io_error)?);
+		serde_json::to_writer_pretty(&mut writer, self).map_err(|error| TranscriptError::Format {
+			path: path.to_path_buf(),
+			error,
+		})?;
+		writer.flush().map_err(io_error)
+	}
+
+	/// Returns the path of the session state file, given the path the transcript was read from
+	pub fn state_path(&self, path: &Path) -> Option<PathBuf> {
+		let state = self.state.as_ref()?;
+		Some(path.parent().unwrap_or(Path::new("")).join(state))
+	}
+}
+
+/// Save the conversation held in a session to `path`. The state of the session is saved next to it, so that it can be
+/// restored without feeding the conversation again. Returns a warning when the state could not be saved (the
+/// conversation can then still be restored by replaying it).
+pub fn save(session: &mut dyn ChatSession, turns: Vec<Turn>, path: &Path) -> Result<Option<String>, TranscriptError> {
+	let mut transcript = Transcript::new(session.task_name(), session.model_name(), turns);
+
+	let state_path = path.with_extension(STATE_EXTENSION);
+	let warning = match save_state(session, &state_path) {
+		Ok(()) => {
+			transcript.state = state_path.file_name().map(PathBuf::from);
+			None
+		}
+		Err(e) => Some(format!(
+			"the session state could not be saved ({e}); the conversation will be replayed when loaded"
+		)),
+	};
+
+	transcript.write(path)?;
+	Ok(warning)
+}
+
+fn save_state(session: &mut dyn ChatSession, path: &Path) -> Result<(), TranscriptError> {
+	let io_error = |error| TranscriptError::Io {
+		path: path.to_path_buf(),
+		error,
+	};
+	let mut writer = BufWriter::new(File::create(path).map_err(io_error)?);
+	session.save_state(&mut writer)?;
+	writer.flush().map_err(io_error)
+}
+
+/// Restore a conversation (read from `path`) into a fresh session for its task. The saved session state is used when
+/// possible; otherwise the conversation is fed to the session again. Returns a warning when the state could not be used.
+pub fn restore(session: &mut dyn ChatSession, transcript: &Transcript, path: &Path) -> Result<Option<String>, TranscriptError> {
+	let warning = if transcript.model != session.model_name() {
+		Some(format!(
+			"the conversation was held with model {} and is replayed with model {}",
+			transcript.model,
+			session.model_name()
+		))
+	} else if let Some(state_path) = transcript.state_path(path) {
+		let restored = File::open(&state_path)
+			.map_err(|error| TranscriptError::Io { path: state_path, error })
+			.and_then(|file| Ok(session.restore_state(&mut BufReader::new(file))?));
+		match restored {
+			Ok(()) => return Ok(None),
+			Err(e) => Some(format!("the session state could not be restored ({e}); the conversation is replayed")),
+		}
+	} else {
+		None
+	};
+
+	for turn in &transcript.turns {
+		session.replay(&turn.prompt, &turn.response)?;
+	}
+	Ok(warning)
+}
+
+#[cfg(test)]
+mod test {
+	use std::path::Path;
+
+	use super::{Transcript, TranscriptError, Turn, TRANSCRIPT_VERSION};
+
+	fn transcript() -> Transcript {
+		Transcript::new(
+			"chat",
+			"gpt2",
+			vec![Turn {
+				prompt: String::from("Hello"),
+				response: String::from("Hi! How can I help?"),
+			}],
+		)
+	}
+
+	#[test]
+	fn test_transcript_round_trip() {
+		let dir = std::env::temp_dir().join(format!("poly-ui-transcript-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("chat.json");
+
+		let mut written = transcript();
+		written.state = Some("chat.state".into());
+		written.write(&path).unwrap();
+		let read = Transcript::read(&path).unwrap();
+		assert_eq!(read, written);
+		assert_eq!(read.state_path(&path), Some(dir.join("chat.state")));
+
+		// Transcripts written by a newer version cannot be read
+		let mut newer = transcript();
+		newer.version = TRANSCRIPT_VERSION + 1;
+		newer.write(&path).unwrap();
+		assert!(matches!(Transcript::read(&path), Err(TranscriptError::UnsupportedVersion { .. })));
+
+		std::fs::write(&path, "not a transcript").unwrap();
+		assert!(matches!(Transcript::read(&path), Err(TranscriptError::Format { .. })));
+		std::fs::remove_dir_all(&dir).unwrap();
+
+		assert!(matches!(Transcript::read(&path), Err(TranscriptError::Io { .. })));
+	}
+
+	#[test]
+	fn test_transcript_format() {
+		// Version 1 of the format
+		let json = r#"{
+			"version": 1,
+			"task": "chat",
+			"model": "gpt2",
+			"turns": [{ "prompt": "Hello", "response": "Hi! How can I help?" }]
+		}"#;
+		let transcript_v1: Transcript = serde_json::from_str(json).unwrap();
+		assert_eq!(transcript_v1, transcript());
+		assert_eq!(transcript_v1.state_path(Path::new("chat.json")), None);
+
+		let value = serde_json::to_value(transcript()).unwrap();
+		assert_eq!(value["version"], 1);
+		assert_eq!(value["turns"][0]["response"], "Hi! How can I help?");
+		assert!(value.get("state").is_none());
+	}
+}
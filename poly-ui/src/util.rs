@@ -2,7 +2,8 @@ use std::path::{Path, PathBuf};
 
 static RESOURCES_DIR: &str = "data";
 
-fn resources_path() -> PathBuf {
+/// Return the path of the folder holding the resources (RESOURCES_DIR relative to the crate root)
+pub fn resources_path() -> PathBuf {
 	if std::env::var_os("CARGO").is_some() {
 		PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap())
 			.parent()
@@ -31,3 +32,14 @@ pub fn resource_path(resource: &str) -> PathBuf {
 	tracing::debug!("PATH={p:?}");
 	p
 }
+
+/// Return the path of the file the conversation is saved to (and loaded from), creating its folder if necessary
+pub fn transcript_path() -> Option<PathBuf> {
+	let proj_dirs = directories::ProjectDirs::from("nl", "Dialogic", "Poly")?;
+	let data_dir = proj_dirs.data_dir();
+	if let Err(e) = std::fs::create_dir_all(data_dir) {
+		tracing::error!("could not create data folder {}: {e}", data_dir.display());
+		return None;
+	}
+	Some(data_dir.join("transcript.json"))
+}
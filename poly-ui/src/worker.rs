@@ -1,191 +1,2055 @@
 use std::{
-	fs::File,
-	io::Read,
+	collections::BTreeMap,
+	future::Future,
+	path::{Path, PathBuf},
 	sync::{
 		atomic::{AtomicBool, Ordering},
 		Arc,
 	},
+	time::{Duration, Instant},
 };
 
-use directories::ProjectDirs;
 use iced::{
 	futures::{channel::mpsc, SinkExt, StreamExt},
 	subscription, Subscription,
 };
 use poly_backend::{
-	backend::{Backend, InferenceFeedback, InferenceResponse},
-	config::BackendConfig,
-	types::{PromptRequest, SessionRequest},
+	backend::{Backend, InferenceFeedback},
+	config::{SamplerConfig, TaskConfig},
+	memory::{ItemMetadata, MemoryHit, MemoryItem, MemoryQuery},
+	session::{Completion, InferenceStats, SessionCheckpoint},
+	types::{BackendError, FinishReason, PromptRequest, SessionRequest, TEMPERATURE_RANGE, TOP_P_RANGE},
+};
+use tokio::{
+	select,
+	task::{spawn_blocking, JoinError, JoinHandle},
+};
+
+use crate::{
+	config::{default_max_conversations, Args, ContextConfig, ContextStrategy, RemoteConfig, UiConfig},
+	context,
+	remote::RemoteClient,
+	session::{ChatSession, SessionError, WorkerBackend},
+	transcript::{self, Transcript, Turn},
 };
-use tokio::{select, task::spawn_blocking};
 
-use crate::util::resource_path;
+/// Identifies a conversation held by the worker
+pub type ConversationId = u64;
 
 #[derive(Debug, Clone)]
 pub enum LLMWorkerEvent {
+	/// The tasks that are available (sent before the models are loaded)
+	Tasks(Vec<String>),
 	Loading(f64),
+
+	/// The state of the connection to the remote server changed (only sent when using a remote server)
+	Connection(ConnectionStatus),
 	Ready {
 		sender: mpsc::Sender<LLMWorkerCommand>,
-		tasks: Vec<String>,
-		selected_task: String,
 	},
+
+	/// A conversation was opened (after `NewConversation`, and for the first conversation when the worker is ready).
+	/// Followed by `TaskSelected` when a session could be started for it.
+	ConversationCreated(ConversationId),
+
+	/// A conversation was closed (after `CloseConversation`)
+	ConversationClosed(ConversationId),
+
+	/// A new session was started for the task in a conversation (after `SetTask` or `Reset`)
+	TaskSelected {
+		conversation: ConversationId,
+		task: String,
+	},
+
+	/// The system prompt (the text each prompt is prefixed with) of a conversation (sent after `SetSystemPrompt` and
+	/// when a task is selected). `overridden` is false when the prefix configured for the task is used.
+	SystemPrompt {
+		conversation: ConversationId,
+		prompt: String,
+		overridden: bool,
+	},
+
+	/// The sampling parameters that apply to the next prompt in a conversation (sent after `SetParameters` and when a
+	/// task is selected)
+	Parameters {
+		conversation: ConversationId,
+		parameters: LLMWorkerParameters,
+	},
+
+	/// Number of tokens a prompt would take in the context window of a conversation (after `CountTokens`). The prompt
+	/// fits when `prompt + overhead <= context - used`.
+	TokenCount {
+		conversation: ConversationId,
+
+		/// Tokens of the prompt itself
+		prompt: usize,
+
+		/// Tokens the task adds to the prompt (prefix and postfix)
+		overhead: usize,
+
+		/// Tokens already in the context window
+		used: usize,
+
+		/// Tokens in the context window that are never dropped to make room (the prelude)
+		pinned: usize,
+
+		/// Size of the context window
+		context: usize,
+	},
+
+	/// The last response in a conversation was discarded (after `Regenerate`); a new response to the same prompt follows
+	Rewound(ConversationId),
+
+	/// Whether a response is being generated (in any conversation)
 	Running(bool),
-	ResponseToken(String),
+	ResponseToken {
+		conversation: ConversationId,
+		token: String,
+	},
+
+	/// Sent periodically while generating
+	Progress {
+		generated_tokens: usize,
+		tokens_per_sec: f64,
+	},
+
+	/// Sent after each completion
+	Stats {
+		prompt_tokens: usize,
+		generated_tokens: usize,
+		tokens_per_sec: f64,
+
+		/// Time spent on feeding the prompt and generating the response
+		duration: Duration,
+	},
+
+	/// Generating the response to a prompt has ended (sent after `Stats` or `Error`, before `Running(false)`)
+	Finished {
+		conversation: ConversationId,
+		reason: LLMWorkerFinishReason,
+	},
+	/// A transcript was saved (after `SaveTranscript`)
+	TranscriptSaved(PathBuf),
+
+	/// A conversation was restored (after `LoadTranscript`). Sent after `TaskSelected` for the task of the conversation.
+	TranscriptLoaded {
+		conversation: ConversationId,
+		task: String,
+		turns: Vec<Turn>,
+	},
+
+	/// The session was rebuilt with only the most recent part of the conversation (and possibly a summary of the rest),
+	/// because the context window was (almost) full
+	ContextReduced {
+		conversation: ConversationId,
+		dropped_turns: usize,
+		summary: Option<String>,
+	},
+
+	/// Chunks from the memory of the task that are relevant to a query (after `RecallMemories`), most relevant first
+	MemoriesRecalled {
+		conversation: ConversationId,
+		query: String,
+		hits: Vec<MemoryHit>,
+	},
+
+	/// A page of the chunks in the memory of the task (after `ListMemories`)
+	Memories {
+		conversation: ConversationId,
+		offset: usize,
+		items: Vec<MemoryItem>,
+	},
+
+	/// A chunk was removed from the memory of the task (after `ForgetMemory`)
+	MemoryForgotten {
+		conversation: ConversationId,
+		id: String,
+	},
+
+	/// The worker has shut down (after `Shutdown`) and handles no more commands
+	ShutdownComplete,
+
+	/// Something did not work out as intended, but the command was still carried out
+	Warning(String),
+	Error {
+		kind: LLMWorkerErrorKind,
+		message: String,
+
+		/// Whether the worker can still be used after the error
+		recoverable: bool,
+	},
+}
+
+/// Why the response to a prompt ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LLMWorkerFinishReason {
+	/// The model ended the response
+	Eot,
+
+	/// One of the stop sequences of the task was generated
+	StopSequence,
+
+	/// The maximum number of tokens configured for the task was generated
+	MaxTokens,
+
+	/// The maximum number of characters or lines configured for the task was generated
+	LengthLimit,
+
+	/// The context window is full; the conversation needs to be reset to continue
+	ContextFull,
+
+	/// The maximum duration configured for the task was reached
+	Timeout,
+
+	/// Generation was stopped using `Stop`
+	Cancelled,
+
+	/// Generation failed (an error event has been sent)
+	Error,
+}
+
+impl From<FinishReason> for LLMWorkerFinishReason {
+	fn from(reason: FinishReason) -> LLMWorkerFinishReason {
+		match reason {
+			FinishReason::Eot => LLMWorkerFinishReason::Eot,
+			FinishReason::StopSequence | FinishReason::BannedPhrase => LLMWorkerFinishReason::StopSequence,
+			FinishReason::MaxTokens => LLMWorkerFinishReason::MaxTokens,
+			FinishReason::LengthLimit => LLMWorkerFinishReason::LengthLimit,
+			FinishReason::ContextFull => LLMWorkerFinishReason::ContextFull,
+			FinishReason::Timeout => LLMWorkerFinishReason::Timeout,
+			FinishReason::Cancelled => LLMWorkerFinishReason::Cancelled,
+		}
+	}
 }
 
+/// Sampling parameters for prompts. When sent to the worker, `None` means the value configured for the task is used.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LLMWorkerParameters {
+	/// `None` in events when the task configures its own sampler chain (or the value is not known, for a remote server)
+	pub temperature: Option<f32>,
+	pub top_p: Option<f32>,
+
+	/// `None` in events when the number of generated tokens is not limited (or not known)
+	pub max_tokens: Option<usize>,
+}
+
+impl LLMWorkerParameters {
+	/// Bring values within their valid range. Returns a warning for each value that was changed.
+	fn clamp(&mut self) -> Vec<String> {
+		let mut warnings = vec![];
+		for (name, value, range) in [
+			("temperature", &mut self.temperature, TEMPERATURE_RANGE),
+			("top_p", &mut self.top_p, TOP_P_RANGE),
+		] {
+			match *value {
+				Some(v) if v.is_nan() => {
+					warnings.push(format!("{name} is not a number; the value configured for the task is used"));
+					*value = None;
+				}
+				Some(v) if !range.contains(&v) => {
+					let clamped = v.clamp(*range.start(), *range.end());
+					warnings.push(format!("{name} {v} is out of range; {clamped} is used instead"));
+					*value = Some(clamped);
+				}
+				_ => {}
+			}
+		}
+		if self.max_tokens == Some(0) {
+			warnings.push(String::from("max_tokens must be at least 1; 1 is used instead"));
+			self.max_tokens = Some(1);
+		}
+		warnings
+	}
+
+	/// The parameters that apply to a task: the overridden values, or else the values configured for the task
+	fn effective(&self, task: Option<&TaskConfig>) -> LLMWorkerParameters {
+		let sampler = task.and_then(|task| match task.sampler {
+			SamplerConfig::Standard(ref sampler) => Some(sampler),
+			SamplerConfig::Advanced(_) => None,
+		});
+		LLMWorkerParameters {
+			temperature: sampler.map(|sampler| self.temperature.unwrap_or(sampler.temperature)),
+			top_p: sampler.map(|sampler| self.top_p.unwrap_or(sampler.top_p)),
+			max_tokens: self.max_tokens.or(task.and_then(|task| task.max_tokens)),
+		}
+	}
+
+	/// Build a request for a prompt to a task, with the overridden values that the task supports (temperature and top-p
+	/// cannot be changed for tasks that configure their own sampler chain)
+	fn request(&self, prompt: String, task: Option<&TaskConfig>) -> PromptRequest {
+		let standard = task.is_some_and(|task| matches!(task.sampler, SamplerConfig::Standard(_)));
+		PromptRequest {
+			prompt,
+			temperature: self.temperature.filter(|_| standard),
+			top_p: self.top_p.filter(|_| standard),
+			max_tokens: self.max_tokens,
+			..Default::default()
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+	Connecting,
+	Connected,
+
+	/// The server could not be reached; the connection is attempted again when a new session is needed
+	Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LLMWorkerErrorKind {
+	/// The configuration could not be read or is invalid
+	Config,
+
+	/// The models could not be loaded
+	Load,
+
+	/// A session for the task could not be started
+	Task,
+
+	/// The command cannot be handled in the current state (e.g. while generating)
+	Rejected,
+
+	/// Generating a response failed
+	Generation,
+
+	/// A transcript could not be saved or loaded
+	Transcript,
+
+	/// The context window is full; the conversation needs to be reset to continue
+	ContextFull,
+
+	/// The memory of the task could not be accessed, or the task does not have a memory
+	Memory,
+
+	/// The connection to the remote server was lost
+	Connection,
+
+	/// Communication with the application or the generating thread failed
+	Channel,
+}
+
+impl LLMWorkerErrorKind {
+	fn is_recoverable(&self) -> bool {
+		!matches!(self, LLMWorkerErrorKind::Config | LLMWorkerErrorKind::Load)
+	}
+}
+
+impl LLMWorkerEvent {
+	fn stats(stats: &InferenceStats) -> LLMWorkerEvent {
+		LLMWorkerEvent::Stats {
+			prompt_tokens: stats.prompt_tokens,
+			generated_tokens: stats.predict_tokens,
+			tokens_per_sec: tokens_per_sec(stats.predict_tokens, stats.predict_duration),
+			duration: stats.feed_prompt_duration + stats.predict_duration,
+		}
+	}
+
+	fn error(kind: LLMWorkerErrorKind, message: impl Into<String>) -> LLMWorkerEvent {
+		LLMWorkerEvent::Error {
+			kind,
+			message: message.into(),
+			recoverable: kind.is_recoverable(),
+		}
+	}
+}
+
+/// Commands for the worker. Most apply to a single conversation; commands for conversations that are not open are
+/// rejected with an error event.
+#[derive(Debug)]
 pub enum LLMWorkerCommand {
-	Prompt(String),
-	Interrupt,
-	Reset { task_name: String },
+	/// Open a new conversation (with the first task). Rejected when the maximum number of conversations is open.
+	NewConversation,
+
+	/// Close a conversation, freeing its session. A response being generated in the conversation is stopped.
+	CloseConversation(ConversationId),
+
+	Prompt {
+		conversation: ConversationId,
+		text: String,
+	},
+
+	/// Stop the completion that is currently running (ignored when nothing is running)
+	Stop,
+
+	/// Stop the running completion, save the open conversations (when an autosave path is configured) and persist the
+	/// memories, then report `ShutdownComplete`. The worker handles no more commands afterwards.
+	Shutdown,
+
+	/// Start the conversation over with its current task
+	Reset(ConversationId),
+
+	/// Discard the last response in a conversation and generate a new one for the same prompt
+	Regenerate(ConversationId),
+
+	/// Start the conversation over with the specified task. Rejected while a completion is running.
+	SetTask {
+		conversation: ConversationId,
+		task: String,
+	},
+
+	/// Prefix prompts with the specified text instead of the prefix configured for the task (an empty string restores the
+	/// configured prefix). The conversation is restarted with the new system prompt. Not supported for remote servers.
+	SetSystemPrompt {
+		conversation: ConversationId,
+		prompt: String,
+	},
+
+	/// Change the sampling parameters for subsequent prompts (`None` restores the value configured for the task). Values
+	/// out of range are clamped. Not supported for remote servers.
+	SetParameters {
+		conversation: ConversationId,
+		temperature: Option<f32>,
+		top_p: Option<f32>,
+		max_tokens: Option<usize>,
+	},
+
+	/// Count the tokens a prompt would take in the context window, without sending it. Cheap enough to send while the
+	/// prompt is being typed. Not supported for remote servers.
+	CountTokens {
+		conversation: ConversationId,
+		text: String,
+	},
+
+	/// Retrieve the chunks in the memory of the task that are most relevant to a query
+	RecallMemories {
+		conversation: ConversationId,
+		query: String,
+	},
+
+	/// Remove a chunk (by its identifier) from the memory of the task
+	ForgetMemory {
+		conversation: ConversationId,
+		id: String,
+	},
+
+	/// List the chunks in the memory of the task, skipping the first `offset`
+	ListMemories {
+		conversation: ConversationId,
+		offset: usize,
+		limit: usize,
+	},
+
+	/// Save the conversation (and the state of its session) to a file
+	SaveTranscript {
+		conversation: ConversationId,
+		path: PathBuf,
+	},
+
+	/// Restore a conversation saved using `SaveTranscript`, starting a new session for its task
+	LoadTranscript {
+		conversation: ConversationId,
+		path: PathBuf,
+	},
+}
+
+impl LLMWorkerCommand {
+	/// Whether the command can be handled while a completion is running. Other commands are rejected with an error event.
+	fn allowed_while_generating(&self) -> bool {
+		matches!(
+			self,
+			LLMWorkerCommand::Stop
+				| LLMWorkerCommand::Shutdown
+				| LLMWorkerCommand::SetParameters { .. }
+				| LLMWorkerCommand::CountTokens { .. }
+				| LLMWorkerCommand::NewConversation
+				| LLMWorkerCommand::CloseConversation(_)
+		)
+	}
+
+	/// The conversation the command applies to
+	fn conversation(&self) -> Option<ConversationId> {
+		match *self {
+			LLMWorkerCommand::NewConversation | LLMWorkerCommand::Stop | LLMWorkerCommand::Shutdown => None,
+			LLMWorkerCommand::CloseConversation(id) | LLMWorkerCommand::Reset(id) | LLMWorkerCommand::Regenerate(id) => Some(id),
+			LLMWorkerCommand::Prompt { conversation, .. }
+			| LLMWorkerCommand::SetTask { conversation, .. }
+			| LLMWorkerCommand::SetSystemPrompt { conversation, .. }
+			| LLMWorkerCommand::SetParameters { conversation, .. }
+			| LLMWorkerCommand::CountTokens { conversation, .. }
+			| LLMWorkerCommand::RecallMemories { conversation, .. }
+			| LLMWorkerCommand::ForgetMemory { conversation, .. }
+			| LLMWorkerCommand::ListMemories { conversation, .. }
+			| LLMWorkerCommand::SaveTranscript { conversation, .. }
+			| LLMWorkerCommand::LoadTranscript { conversation, .. } => Some(conversation),
+		}
+	}
 }
 
 enum LLMWorkerState {
 	Starting,
 	Ready(mpsc::Receiver<LLMWorkerCommand>),
+
+	/// The worker has shut down after being asked to
+	Stopped,
+
+	/// The worker could not start or the application has gone away; nothing left to do
+	Failed,
 }
 
-pub fn llm_worker() -> Subscription<LLMWorkerEvent> {
-	struct LLMWorker;
+/// Minimum time between two progress events
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
 
-	subscription::channel(std::any::TypeId::of::<LLMWorker>(), 100, move |mut output| async move {
-		let mut state = LLMWorkerState::Starting;
+fn tokens_per_sec(tokens: usize, duration: Duration) -> f64 {
+	if duration.is_zero() {
+		0.0
+	} else {
+		tokens as f64 / duration.as_secs_f64()
+	}
+}
 
-		let mut config_file_path = resource_path("config.toml");
+/// Counts the tokens received from a running generation to report live throughput
+#[derive(Default)]
+struct Progress {
+	generated_tokens: usize,
+	first_token: Option<Instant>,
+	last_reported: Option<Instant>,
+}
 
-		// Check if the user has a local override config
-		if let Some(proj_dirs) = ProjectDirs::from("nl", "Dialogic", "Poly") {
-			let config_dir = proj_dirs.config_dir();
-			let user_config_path = config_dir.join("config.toml");
-			tracing::info!("Looking for configuration file at {}", user_config_path.to_str().unwrap());
-			if user_config_path.exists() {
-				config_file_path = user_config_path;
-				tracing::info!("Using user configuration file");
-			} else {
-				tracing::info!("Using built-in configuration file");
-			}
+impl Progress {
+	/// Count a generated token. Returns a progress event when it is time to report one.
+	fn add_token(&mut self, now: Instant) -> Option<LLMWorkerEvent> {
+		self.generated_tokens += 1;
+		let first_token = *self.first_token.get_or_insert(now);
+		if now.duration_since(self.last_reported.unwrap_or(first_token)) < PROGRESS_INTERVAL {
+			return None;
 		}
+		self.last_reported = Some(now);
+
+		// Throughput is measured from the first token, so that the time spent on feeding the prompt is not included
+		Some(LLMWorkerEvent::Progress {
+			generated_tokens: self.generated_tokens,
+			tokens_per_sec: tokens_per_sec(self.generated_tokens - 1, now.duration_since(first_token)),
+		})
+	}
+}
 
-		// Load the config file
-		let mut config_file = File::open(config_file_path).expect("open config file");
-		let mut config_string = String::new();
-		config_file.read_to_string(&mut config_string).expect("read config file");
+/// A completion running on a blocking thread. The session is handed back when the completion finishes.
+struct Generation {
+	conversation: ConversationId,
+	cancelled: Arc<AtomicBool>,
+	tokens: tokio::sync::mpsc::Receiver<String>,
+	handle: JoinHandle<(Box<dyn ChatSession>, Result<Completion, SessionError>)>,
+	progress: Progress,
 
-		let mut config: BackendConfig = toml::from_str(&config_string).unwrap();
+	/// Whether the prompt is fed again after making room in the context window
+	is_retry: bool,
+}
+
+impl Generation {
+	fn start(conversation: ConversationId, mut session: Box<dyn ChatSession>, request: PromptRequest) -> Generation {
+		let (ttx, trx) = tokio::sync::mpsc::channel(16);
+		let cancelled = Arc::new(AtomicBool::new(false));
+		let cancelled_clone = cancelled.clone();
 
-		// Update model paths
-		for (_k, model_config) in config.models.iter_mut() {
-			if let Some(ref model_path) = model_config.model_path {
-				let model_path_str = model_path.to_str().unwrap();
-				// Paths that are prefixed with '@' are relative to the data folder
-				if model_path_str.starts_with('@') {
-					model_config.model_path = Some(resource_path(model_path_str.strip_prefix('@').unwrap()));
+		let handle = spawn_blocking(move || {
+			let result = session.complete(&request, &mut |token| {
+				if cancelled_clone.load(Ordering::SeqCst) {
+					return InferenceFeedback::Halt;
 				}
+
+				// The receiving end is gone when the worker has stopped
+				if ttx.blocking_send(token).is_err() {
+					return InferenceFeedback::Halt;
+				}
+				InferenceFeedback::Continue
+			});
+			(session, result)
+		});
+
+		Generation {
+			conversation,
+			cancelled,
+			tokens: trx,
+			handle,
+			progress: Progress::default(),
+			is_retry: false,
+		}
+	}
+
+	/// Request the completion to stop. It will stop after the token currently being generated.
+	fn stop(&self) {
+		self.cancelled.store(true, Ordering::SeqCst);
+	}
+
+	fn is_stopped(&self) -> bool {
+		self.cancelled.load(Ordering::SeqCst)
+	}
+}
+
+/// What the worker needs to act on next
+enum WorkerInput {
+	Command(LLMWorkerCommand),
+
+	/// A token from the running generation, or `None` when the generation has finished
+	Token(Option<String>),
+}
+
+/// Send an event to the application. Fails when the application has stopped listening.
+async fn emit(output: &mut mpsc::Sender<LLMWorkerEvent>, event: LLMWorkerEvent) -> bool {
+	match output.send(event).await {
+		Ok(()) => true,
+		Err(e) => {
+			tracing::error!("could not send event to application: {e}");
+			false
+		}
+	}
+}
+
+/// Describe why a spawned task failed, using the panic message if there is one
+fn join_error_message(error: JoinError) -> String {
+	if !error.is_panic() {
+		return error.to_string();
+	}
+	let panic = error.into_panic();
+	panic
+		.downcast_ref::<String>()
+		.cloned()
+		.or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+		.unwrap_or_else(|| String::from("unknown error"))
+}
+
+/// Number of chunks retrieved from memory for `RecallMemories`
+const RECALLED_MEMORIES: usize = 10;
+
+/// Minimum time between two attempts to connect to the remote server
+const MIN_RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Call `f` until it succeeds, fails with an error that retrying will not fix, or the number of attempts configured for
+/// the remote server is reached
+async fn retry<T, F: Future<Output = Result<T, SessionError>>>(client: &RemoteClient, mut f: impl FnMut() -> F) -> Result<T, SessionError> {
+	let mut attempt = 1;
+	loop {
+		match f().await {
+			Err(e) if e.is_transient() && attempt < client.reconnect_attempts() => {
+				tracing::warn!("could not reach {} (attempt {attempt}): {e}", client.url());
+				attempt += 1;
+				tokio::time::sleep(client.reconnect_interval().max(MIN_RECONNECT_INTERVAL)).await;
 			}
+			result => return result,
+		}
+	}
+}
+
+/// A conversation held by the worker, in a session of its own
+#[derive(Default)]
+struct Conversation {
+	task_name: Option<String>,
+
+	/// The conversation held in the session
+	turns: Vec<Turn>,
+
+	/// Sampling parameters that override those configured for the task
+	parameters: LLMWorkerParameters,
+
+	/// Text to prefix prompts with instead of the prefix configured for the task
+	system_prompt: Option<String>,
+
+	/// The last prompt, with the position of the session before it was fed (`None` when the session cannot be rewound)
+	last_prompt: Option<(String, Option<SessionCheckpoint>)>,
+
+	/// The session for the task (`None` while a completion is running, or when no task could be started)
+	session: Option<Box<dyn ChatSession>>,
+}
+
+/// State of the worker once the backend has been loaded
+struct Worker {
+	backend: WorkerBackend,
+	output: mpsc::Sender<LLMWorkerEvent>,
+	tasks: Vec<String>,
+
+	/// How to make room in the context window when a conversation grows too long
+	context: ContextConfig,
+
+	/// Maximum number of conversations that can be open at the same time (each holds a session, and thus memory)
+	max_conversations: usize,
+	conversations: BTreeMap<ConversationId, Conversation>,
+	next_conversation: ConversationId,
+
+	/// The completion that is running. Only one runs at a time, as conversations share the models.
+	generation: Option<Generation>,
+
+	/// Folder the open conversations are saved to on shutdown
+	autosave_path: Option<PathBuf>,
+
+	/// Set after `Shutdown` was handled
+	shut_down: bool,
+}
+
+impl Worker {
+	fn new(backend: WorkerBackend, tasks: Vec<String>, output: mpsc::Sender<LLMWorkerEvent>) -> Worker {
+		Worker {
+			backend,
+			output,
+			tasks,
+			context: ContextConfig::default(),
+			max_conversations: default_max_conversations(),
+			conversations: BTreeMap::new(),
+			next_conversation: 0,
+			generation: None,
+			autosave_path: None,
+			shut_down: false,
+		}
+	}
+
+	/// Read the configuration (resolving relative model paths against `models_dir`) and load the backend (or connect to
+	/// the remote server, when configured). Reports the available tasks and loading progress. Returns the error event to
+	/// report when the configuration is invalid or the models cannot be loaded.
+	async fn load(config_file_path: &Path, models_dir: &Path, mut output: mpsc::Sender<LLMWorkerEvent>) -> Result<Worker, LLMWorkerEvent> {
+		let config = UiConfig::from_file(config_file_path, models_dir)
+			.map_err(|e| LLMWorkerEvent::error(LLMWorkerErrorKind::Config, format!("{}: {e}", config_file_path.display())))?;
+		if let Some(remote) = config.remote {
+			let mut worker = Worker::connect(remote, output).await?;
+			worker.context = config.context;
+			worker.max_conversations = config.max_conversations;
+			worker.autosave_path = config.autosave_path;
+			return Ok(worker);
+		}
+
+		let context_config = config.context;
+		let max_conversations = config.max_conversations;
+		let autosave_path = config.autosave_path;
+		let config = config.backend;
+		let problems = config.check();
+		if !problems.is_empty() {
+			let message = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>().join("\n");
+			return Err(LLMWorkerEvent::error(LLMWorkerErrorKind::Config, message));
 		}
 
 		let mut task_names: Vec<String> = config.tasks.keys().cloned().collect();
 		task_names.sort();
-		let mut selected_task_name = config.tasks.keys().next().unwrap().clone();
+		emit(&mut output, LLMWorkerEvent::Tasks(task_names.clone())).await;
 
 		// Load backend
-		let backend = Arc::new({
-			let (ptx, mut prx) = tokio::sync::mpsc::channel(32);
-			let backend_future = Backend::from(config, Some(ptx));
+		let (ptx, mut prx) = tokio::sync::mpsc::channel(32);
+		let backend_future = Backend::from(config, Some(ptx));
 
-			let mut output2 = output.clone();
-			tokio::spawn(async move {
-				while let Some(progress) = prx.recv().await {
-					output2.send(LLMWorkerEvent::Loading(progress)).await.unwrap();
+		let mut output2 = output.clone();
+		tokio::spawn(async move {
+			while let Some(progress) = prx.recv().await {
+				if !emit(&mut output2, LLMWorkerEvent::Loading(progress)).await {
+					break;
 				}
-			});
+			}
+		});
+
+		let backend = tokio::spawn(backend_future).await.map_err(|e| {
+			let message = format!("could not load models: {}", join_error_message(e));
+			LLMWorkerEvent::error(LLMWorkerErrorKind::Load, message)
+		})?;
+		let mut worker = Worker::new(WorkerBackend::Local(Arc::new(backend)), task_names, output);
+		worker.context = context_config;
+		worker.max_conversations = max_conversations;
+		worker.autosave_path = autosave_path;
+		Ok(worker)
+	}
+
+	/// Connect to a remote server and retrieve the available tasks
+	async fn connect(config: RemoteConfig, mut output: mpsc::Sender<LLMWorkerEvent>) -> Result<Worker, LLMWorkerEvent> {
+		let client = RemoteClient::new(config).map_err(|e| LLMWorkerEvent::error(LLMWorkerErrorKind::Config, e.to_string()))?;
+		emit(&mut output, LLMWorkerEvent::Connection(ConnectionStatus::Connecting)).await;
+		match retry(&client, || async { Ok(client.tasks().await?) }).await {
+			Ok(task_names) => {
+				emit(&mut output, LLMWorkerEvent::Connection(ConnectionStatus::Connected)).await;
+				emit(&mut output, LLMWorkerEvent::Tasks(task_names.clone())).await;
+				Ok(Worker::new(WorkerBackend::Remote(Arc::new(client)), task_names, output))
+			}
+			Err(e) => {
+				emit(&mut output, LLMWorkerEvent::Connection(ConnectionStatus::Disconnected)).await;
+				let message = format!("could not connect to {}: {e}", client.url());
+				Err(LLMWorkerEvent::error(LLMWorkerErrorKind::Load, message))
+			}
+		}
+	}
+
+	async fn emit(&mut self, event: LLMWorkerEvent) -> bool {
+		emit(&mut self.output, event).await
+	}
+
+	/// Returns a conversation that is known to be open (commands for other conversations are rejected before they are
+	/// handled, and conversations are only closed by a command)
+	fn conversation(&mut self, id: ConversationId) -> &mut Conversation {
+		self.conversations.get_mut(&id).expect("conversation is open")
+	}
+
+	/// Read the next command sent from `Application` or, while generating, the next generated token
+	async fn next_input(&mut self, receiver: &mut mpsc::Receiver<LLMWorkerCommand>) -> WorkerInput {
+		match self.generation.as_mut() {
+			Some(running) => select! {
+				token = running.tokens.recv() => WorkerInput::Token(token),
+				command = receiver.select_next_some() => WorkerInput::Command(command),
+			},
+			None => WorkerInput::Command(receiver.select_next_some().await),
+		}
+	}
+
+	async fn handle(&mut self, input: WorkerInput) {
+		match input {
+			WorkerInput::Token(Some(token)) => {
+				let running = self.generation.as_mut().unwrap();
+
+				// Tokens that were still underway when the generation was stopped are dropped
+				if running.is_stopped() {
+					return;
+				}
+				let progress = running.progress.add_token(Instant::now());
+				let conversation = running.conversation;
+				if let Some(turn) = self.conversation(conversation).turns.last_mut() {
+					turn.response.push_str(&token);
+				}
+
+				if !self.emit(LLMWorkerEvent::ResponseToken { conversation, token }).await {
+					// Nobody is listening anymore
+					self.generation.as_ref().unwrap().stop();
+				} else if let Some(progress) = progress {
+					self.emit(progress).await;
+				}
+			}
+
+			WorkerInput::Token(None) => self.finish_generation().await,
+
+			WorkerInput::Command(command) => self.handle_command(command).await,
+		}
+	}
+
+	async fn finish_generation(&mut self) {
+		let finished = self.generation.take().unwrap();
+		let id = finished.conversation;
+		let (session, result) = match finished.handle.await {
+			Ok(finished) => finished,
+			Err(e) => {
+				self.session_lost(id, LLMWorkerErrorKind::Generation, e).await;
+				self.emit(LLMWorkerEvent::Finished {
+					conversation: id,
+					reason: LLMWorkerFinishReason::Error,
+				})
+				.await;
+				self.emit(LLMWorkerEvent::Running(false)).await;
+				return;
+			}
+		};
+
+		let Some(conversation) = self.conversations.get_mut(&id) else {
+			// The conversation was closed while generating; its session is dropped here
+			self.emit(LLMWorkerEvent::Finished {
+				conversation: id,
+				reason: LLMWorkerFinishReason::Cancelled,
+			})
+			.await;
+			self.emit(LLMWorkerEvent::Running(false)).await;
+			return;
+		};
+		conversation.session = Some(session);
+
+		// Room is made in the context window at most once per prompt
+		let mut manage_context = self.context.strategy != ContextStrategy::None && !finished.is_retry;
+
+		let reason = match result {
+			Ok(completion) => {
+				self.emit(LLMWorkerEvent::stats(&completion.stats)).await;
+				completion.finish_reason.into()
+			}
+
+			// The prompt did not fit; make room and feed it again (once)
+			Err(e) if e.is_context_full() && manage_context => {
+				tracing::info!("prompt does not fit in the context window: {e}");
+				manage_context = false;
+				if let Some(turn) = self.conversation(id).turns.pop() {
+					if self.reduce_context(id).await {
+						self.prompt(id, turn.prompt).await;
+						if let Some(ref mut retry) = self.generation {
+							retry.is_retry = true;
+						}
+						return;
+					}
+				}
+				self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::ContextFull, e.to_string())).await;
+				LLMWorkerFinishReason::ContextFull
+			}
+
+			Err(e) => {
+				tracing::error!("completion failed: {e}");
+				let kind = match e {
+					SessionError::Backend(BackendError::ContextFull { .. }) => LLMWorkerErrorKind::ContextFull,
+					SessionError::Backend(BackendError::MemoryFailed { .. }) => LLMWorkerErrorKind::Memory,
+					SessionError::Remote(_) if e.is_transient() => LLMWorkerErrorKind::Connection,
+					_ => LLMWorkerErrorKind::Generation,
+				};
+				self.emit(LLMWorkerEvent::error(kind, e.to_string())).await;
+
+				// The conversation held by the server is gone with the connection; start a new one
+				if kind == LLMWorkerErrorKind::Connection {
+					self.conversation(id).session = None;
+					if let Some(task_name) = self.conversation(id).task_name.clone() {
+						self.set_task(id, task_name).await;
+					}
+				}
+
+				match kind {
+					LLMWorkerErrorKind::ContextFull => LLMWorkerFinishReason::ContextFull,
+					_ => LLMWorkerFinishReason::Error,
+				}
+			}
+		};
+
+		// Make room for the next prompt when the context window is (almost) full
+		let remaining = self.conversation(id).session.as_ref().and_then(|session| session.context_remaining());
+		let almost_full = remaining.is_some_and(|remaining| remaining < self.context.min_remaining_tokens);
+		if manage_context && (almost_full || reason == LLMWorkerFinishReason::ContextFull) {
+			self.reduce_context(id).await;
+		}
+
+		self.emit(LLMWorkerEvent::Finished { conversation: id, reason }).await;
+		self.emit(LLMWorkerEvent::Running(false)).await;
+	}
+
+	/// Rebuild the session of a conversation with only part of the conversation (see [`ContextConfig`]) to make room in
+	/// the context window. The current session is kept when this fails. Returns whether the session was rebuilt.
+	async fn reduce_context(&mut self, id: ConversationId) -> bool {
+		let Some(task_name) = self.conversation(id).task_name.clone() else {
+			return false;
+		};
+		let backend = self.backend.clone();
+		let request = self.session_request(id);
+		let turns = self.conversation(id).turns.clone();
+		let config = self.context.clone();
+		let reduced = spawn_blocking(move || {
+			let runtime = tokio::runtime::Handle::current();
+			context::reduce(&mut || runtime.block_on(backend.start(&task_name, &request)), &turns, &config)
+		})
+		.await;
+
+		let error = match reduced {
+			Ok(Ok(reduction)) => {
+				tracing::info!(
+					dropped_turns = reduction.dropped_turns,
+					"rebuilt session to make room in the context window"
+				);
+				let conversation = self.conversation(id);
+				conversation.session = Some(reduction.session);
+				conversation.turns = reduction.turns;
+				conversation.last_prompt = None;
+				if let Some(warning) = reduction.warning {
+					self.emit(LLMWorkerEvent::Warning(warning)).await;
+				}
+				self.emit(LLMWorkerEvent::ContextReduced {
+					conversation: id,
+					dropped_turns: reduction.dropped_turns,
+					summary: reduction.summary,
+				})
+				.await;
+				return true;
+			}
+			Ok(Err(e)) => e.to_string(),
+			Err(e) => join_error_message(e),
+		};
+		let message = format!("could not make room in the context window: {error}");
+		tracing::error!("{message}");
+		self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::ContextFull, message)).await;
+		false
+	}
+
+	async fn handle_command(&mut self, command: LLMWorkerCommand) {
+		if self.generation.is_some() && !command.allowed_while_generating() {
+			tracing::warn!("rejecting command while generating: {command:?}");
+			let message = format!("cannot handle {command:?} while a response is being generated");
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, message)).await;
+			return;
+		}
+		if let Some(id) = command.conversation().filter(|id| !self.conversations.contains_key(id)) {
+			let message = format!("there is no open conversation with id {id}");
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, message)).await;
+			return;
+		}
+
+		match command {
+			LLMWorkerCommand::NewConversation => {
+				self.new_conversation().await;
+			}
+
+			LLMWorkerCommand::CloseConversation(id) => self.close_conversation(id).await,
+
+			LLMWorkerCommand::Stop => {
+				if let Some(ref running) = self.generation {
+					tracing::info!("Stopping generation");
+					running.stop();
+				}
+			}
+
+			LLMWorkerCommand::Shutdown => self.shutdown().await,
+
+			LLMWorkerCommand::Reset(id) => {
+				if let Some(task_name) = self.conversation(id).task_name.clone() {
+					self.set_task(id, task_name).await;
+				}
+			}
+
+			LLMWorkerCommand::SetTask { conversation, task } => {
+				self.set_task(conversation, task).await;
+			}
+
+			LLMWorkerCommand::SetSystemPrompt { conversation, prompt } => self.set_system_prompt(conversation, prompt).await,
+
+			LLMWorkerCommand::SetParameters {
+				conversation,
+				temperature,
+				top_p,
+				max_tokens,
+			} => {
+				let parameters = LLMWorkerParameters {
+					temperature,
+					top_p,
+					max_tokens,
+				};
+				self.set_parameters(conversation, parameters).await
+			}
+
+			LLMWorkerCommand::CountTokens { conversation, text } => self.count_tokens(conversation, &text).await,
+
+			LLMWorkerCommand::RecallMemories { conversation, query } => self.recall_memories(conversation, query).await,
+
+			LLMWorkerCommand::ForgetMemory { conversation, id } => self.forget_memory(conversation, id).await,
+
+			LLMWorkerCommand::ListMemories { conversation, offset, limit } => self.list_memories(conversation, offset, limit).await,
+
+			LLMWorkerCommand::SaveTranscript { conversation, path } => self.save_transcript(conversation, path).await,
+
+			LLMWorkerCommand::LoadTranscript { conversation, path } => self.load_transcript(conversation, path).await,
+
+			LLMWorkerCommand::Regenerate(id) => self.regenerate(id).await,
+
+			LLMWorkerCommand::Prompt { conversation, text } => self.prompt(conversation, text).await,
+		}
+	}
+
+	/// Stop the running completion (if any) and wait for it to end
+	async fn stop_generation(&mut self) {
+		while let Some(ref mut running) = self.generation {
+			running.stop();
+			if running.tokens.recv().await.is_none() {
+				self.finish_generation().await;
+			}
+		}
+	}
+
+	/// Stop generating, save the open conversations (when configured) and persist the memories. Sessions are freed.
+	async fn shutdown(&mut self) {
+		tracing::info!("shutting down");
+		self.stop_generation().await;
+
+		if let Some(folder) = self.autosave_path.clone() {
+			self.autosave(&folder).await;
+		}
+
+		if let WorkerBackend::Local(backend) = self.backend.clone() {
+			if let Err(e) = backend.flush_memories().await {
+				self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Memory, e.to_string())).await;
+			}
+		}
+
+		self.conversations.clear();
+		self.shut_down = true;
+		self.emit(LLMWorkerEvent::ShutdownComplete).await;
+	}
+
+	/// Save each conversation that has turns to a transcript in a folder
+	async fn autosave(&mut self, folder: &Path) {
+		if let Err(e) = std::fs::create_dir_all(folder) {
+			let message = format!("could not create folder {}: {e}", folder.display());
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Transcript, message)).await;
+			return;
+		}
+
+		let ids: Vec<ConversationId> = self
+			.conversations
+			.iter()
+			.filter(|(_, conversation)| !conversation.turns.is_empty())
+			.map(|(id, _)| *id)
+			.collect();
+		for id in ids {
+			self.save_transcript(id, folder.join(format!("conversation-{id}.json"))).await;
+		}
+	}
+
+	/// Open a conversation with the first task. Returns its identifier, or `None` when the maximum number of
+	/// conversations is open.
+	async fn new_conversation(&mut self) -> Option<ConversationId> {
+		if self.conversations.len() >= self.max_conversations {
+			let message = format!(
+				"at most {} conversations can be open at the same time; close one first",
+				self.max_conversations
+			);
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, message)).await;
+			return None;
+		}
+
+		let id = self.next_conversation;
+		self.next_conversation += 1;
+		self.conversations.insert(id, Conversation::default());
+		self.emit(LLMWorkerEvent::ConversationCreated(id)).await;
+
+		match self.tasks.first().cloned() {
+			Some(task_name) => {
+				self.set_task(id, task_name).await;
+			}
+			None => {
+				self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Task, "no tasks configured")).await;
+			}
+		}
+		Some(id)
+	}
+
+	/// Close a conversation, freeing its session. A response that is being generated in the conversation is stopped
+	/// (its session is freed when the generation has ended).
+	async fn close_conversation(&mut self, id: ConversationId) {
+		self.conversations.remove(&id);
+		if let Some(ref running) = self.generation {
+			if running.conversation == id {
+				running.stop();
+			}
+		}
+		self.emit(LLMWorkerEvent::ConversationClosed(id)).await;
+	}
+
+	async fn prompt(&mut self, id: ConversationId, prompt: String) {
+		let task_config = self.task_config(id);
+		let Some(session) = self.conversation(id).session.take() else {
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, "no task selected")).await;
+			return;
+		};
 
-			tokio::spawn(backend_future).await.unwrap()
+		self.emit(LLMWorkerEvent::Running(true)).await;
+		let conversation = self.conversation(id);
+		conversation.turns.push(Turn {
+			prompt: prompt.clone(),
+			response: String::new(),
 		});
-		let mut session = backend.start(&selected_task_name, &SessionRequest {}, backend.clone()).unwrap();
+		conversation.last_prompt = Some((prompt.clone(), session.checkpoint()));
+		let request = conversation.parameters.request(prompt, task_config.as_ref());
+		self.generation = Some(Generation::start(id, session, request));
+	}
+
+	fn task_config(&self, id: ConversationId) -> Option<TaskConfig> {
+		self.backend.task_config(self.conversations.get(&id)?.task_name.as_ref()?)
+	}
+
+	/// Report the sampling parameters that apply to the next prompt in a conversation
+	async fn emit_parameters(&mut self, id: ConversationId) {
+		let parameters = self.conversation(id).parameters.effective(self.task_config(id).as_ref());
+		self.emit(LLMWorkerEvent::Parameters {
+			conversation: id,
+			parameters,
+		})
+		.await;
+	}
+
+	/// Report the system prompt of a conversation
+	async fn emit_system_prompt(&mut self, id: ConversationId) {
+		let (prompt, overridden) = match self.conversation(id).system_prompt.clone() {
+			Some(prompt) => (prompt, true),
+			None => (self.task_config(id).and_then(|task| task.prefix).unwrap_or_default(), false),
+		};
+		self.emit(LLMWorkerEvent::SystemPrompt {
+			conversation: id,
+			prompt,
+			overridden,
+		})
+		.await;
+	}
+
+	async fn set_system_prompt(&mut self, id: ConversationId, prompt: String) {
+		if self.backend.is_remote() && !prompt.is_empty() {
+			let message = "changing the system prompt is not supported for remote servers";
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, message)).await;
+			return;
+		}
+
+		let conversation = self.conversation(id);
+		let previous = std::mem::replace(&mut conversation.system_prompt, Some(prompt).filter(|prompt| !prompt.is_empty()));
+		match conversation.task_name.clone() {
+			// The system prompt is reported when the new session has started
+			Some(task_name) => {
+				if !self.set_task(id, task_name).await {
+					// The current session (with the previous system prompt) is kept
+					self.conversation(id).system_prompt = previous;
+				}
+			}
+			None => self.emit_system_prompt(id).await,
+		}
+	}
+
+	async fn set_parameters(&mut self, id: ConversationId, mut parameters: LLMWorkerParameters) {
+		if self.backend.is_remote() && parameters != LLMWorkerParameters::default() {
+			let message = "changing parameters is not supported for remote servers";
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, message)).await;
+			return;
+		}
+
+		for warning in parameters.clamp() {
+			tracing::warn!("{warning}");
+			self.emit(LLMWorkerEvent::Warning(warning)).await;
+		}
+		let custom_sampler = self
+			.task_config(id)
+			.is_some_and(|task| matches!(task.sampler, SamplerConfig::Advanced(_)));
+		if custom_sampler && (parameters.temperature.is_some() || parameters.top_p.is_some()) {
+			let warning = "the task uses its own sampler chain; temperature and top_p are not applied";
+			self.emit(LLMWorkerEvent::Warning(warning.to_string())).await;
+		}
+
+		self.conversation(id).parameters = parameters;
+		self.emit_parameters(id).await;
+	}
+
+	/// Rewind the session of a conversation to before the last prompt and feed it again
+	async fn regenerate(&mut self, id: ConversationId) {
+		let conversation = self.conversation(id);
+		let Some((prompt, checkpoint)) = conversation.last_prompt.take() else {
+			let message = "there is no response to regenerate";
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, message)).await;
+			return;
+		};
+		let Some(session) = conversation.session.as_mut() else {
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, "no task selected")).await;
+			return;
+		};
+
+		let rewound = match checkpoint {
+			Some(ref checkpoint) => session.rewind_to(checkpoint),
+			None => Err(SessionError::Unsupported("regenerating a response")),
+		};
+		if let Err(e) = rewound {
+			conversation.last_prompt = Some((prompt, checkpoint));
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, e.to_string())).await;
+			return;
+		}
+
+		conversation.turns.pop();
+		self.emit(LLMWorkerEvent::Rewound(id)).await;
+		self.prompt(id, prompt).await;
+	}
+
+	/// Start a new session for the specified task in a conversation. When the session cannot be started, the current
+	/// session is kept. Returns whether a new session was started.
+	async fn set_task(&mut self, id: ConversationId, task_name: String) -> bool {
+		match self.start_session(id, &task_name).await {
+			Ok(session) => {
+				let conversation = self.conversation(id);
+				conversation.session = Some(session);
+				conversation.task_name = Some(task_name.clone());
+				conversation.turns.clear();
+				conversation.last_prompt = None;
+				self.emit(LLMWorkerEvent::TaskSelected {
+					conversation: id,
+					task: task_name,
+				})
+				.await;
+				self.emit_system_prompt(id).await;
+				self.emit_parameters(id).await;
+				true
+			}
+			Err(e) => {
+				tracing::error!("could not start session for task {task_name}: {e}");
+				let message = format!("could not start task '{task_name}': {e}");
+				self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Task, message)).await;
+				false
+			}
+		}
+	}
+
+	/// How sessions for a conversation are to be started (with the system prompt set for the conversation)
+	fn session_request(&self, id: ConversationId) -> SessionRequest {
+		SessionRequest {
+			prefix: self.conversations.get(&id).and_then(|conversation| conversation.system_prompt.clone()),
+		}
+	}
+
+	/// Start a session for a task in a conversation. For a remote server, reports the state of the connection and retries
+	/// when the server cannot be reached.
+	async fn start_session(&mut self, id: ConversationId, task_name: &str) -> Result<Box<dyn ChatSession>, SessionError> {
+		let request = self.session_request(id);
+		let WorkerBackend::Remote(client) = &self.backend else {
+			return self.backend.start(task_name, &request).await;
+		};
+		let client = client.clone();
+
+		self.emit(LLMWorkerEvent::Connection(ConnectionStatus::Connecting)).await;
+		let backend = &self.backend;
+		let result = retry(&client, || backend.start(task_name, &request)).await;
+		let status = match result {
+			Err(ref e) if e.is_transient() => ConnectionStatus::Disconnected,
+			_ => ConnectionStatus::Connected,
+		};
+		self.emit(LLMWorkerEvent::Connection(status)).await;
+		result
+	}
+
+	/// Run a blocking operation on the session of a conversation on a blocking thread. Returns `None` (after reporting an
+	/// error) when there is no session or the operation failed unexpectedly.
+	async fn with_session<T: Send + 'static>(
+		&mut self,
+		id: ConversationId,
+		kind: LLMWorkerErrorKind,
+		f: impl FnOnce(&mut dyn ChatSession) -> T + Send + 'static,
+	) -> Option<T> {
+		let Some(mut session) = self.conversation(id).session.take() else {
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, "no task selected")).await;
+			return None;
+		};
+
+		match spawn_blocking(move || {
+			let result = f(session.as_mut());
+			(session, result)
+		})
+		.await
+		{
+			Ok((session, result)) => {
+				self.conversation(id).session = Some(session);
+				Some(result)
+			}
+			Err(e) => {
+				self.session_lost(id, kind, e).await;
+				None
+			}
+		}
+	}
+
+	/// Report that the thread using the session of a conversation failed (taking the session with it) and start a new
+	/// session for the conversation (if it is still open)
+	async fn session_lost(&mut self, id: ConversationId, kind: LLMWorkerErrorKind, error: JoinError) {
+		let message = format!("the session failed: {}", join_error_message(error));
+		tracing::error!("{message}");
+		self.emit(LLMWorkerEvent::error(kind, message)).await;
+		if let Some(task_name) = self.conversations.get(&id).and_then(|conversation| conversation.task_name.clone()) {
+			self.set_task(id, task_name).await;
+		}
+	}
+
+	/// Run an operation on the memory of the task of a conversation on a blocking thread (as it may need to calculate
+	/// embeddings). Returns `None` (after reporting an error) when the task has no memory or the operation failed.
+	async fn with_memory<T: Send + 'static, F: Future<Output = Result<T, BackendError>>>(
+		&mut self,
+		id: ConversationId,
+		f: impl FnOnce(Arc<Backend>, String) -> F + Send + 'static,
+	) -> Option<T> {
+		let WorkerBackend::Local(backend) = &self.backend else {
+			let message = "browsing memories is not supported for remote servers";
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, message)).await;
+			return None;
+		};
+		let backend = backend.clone();
+		let Some(task_name) = self.conversation(id).task_name.clone() else {
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, "no task selected")).await;
+			return None;
+		};
+		let Some(memory_name) = backend.task(&task_name).and_then(|task| task.memorization).map(|m| m.memory) else {
+			let message = format!("task '{task_name}' does not have a memory");
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Memory, message)).await;
+			return None;
+		};
+
+		let result = spawn_blocking(move || tokio::runtime::Handle::current().block_on(f(backend, memory_name))).await;
+		let error = match result {
+			Ok(Ok(value)) => return Some(value),
+			Ok(Err(e)) => e.to_string(),
+			Err(e) => join_error_message(e),
+		};
+		tracing::error!("memory operation failed: {error}");
+		self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Memory, error)).await;
+		None
+	}
+
+	/// Count the tokens of a prompt. Tokenizing does not touch the inference state, so this is done right away instead of
+	/// on a blocking thread.
+	async fn count_tokens(&mut self, id: ConversationId, text: &str) {
+		let Some(ref session) = self.conversation(id).session else {
+			let message = "no session to count tokens with (no task selected, or a response is being generated)";
+			self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, message)).await;
+			return;
+		};
+		match session.count_tokens(text) {
+			Ok(count) => {
+				self.emit(LLMWorkerEvent::TokenCount {
+					conversation: id,
+					prompt: count.prompt,
+					overhead: count.overhead,
+					used: count.used,
+					pinned: count.pinned,
+					context: count.context,
+				})
+				.await
+			}
+			Err(e) => self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Rejected, e.to_string())).await,
+		}
+	}
+
+	async fn recall_memories(&mut self, id: ConversationId, query: String) {
+		let recall_query = query.clone();
+		let hits = self
+			.with_memory(id, move |backend, memory_name| async move {
+				backend
+					.recall_hits(
+						&memory_name,
+						MemoryQuery::Prompt(&recall_query),
+						RECALLED_MEMORIES,
+						&ItemMetadata::default(),
+					)
+					.await
+			})
+			.await;
+		if let Some(hits) = hits {
+			self.emit(LLMWorkerEvent::MemoriesRecalled {
+				conversation: id,
+				query,
+				hits,
+			})
+			.await;
+		}
+	}
+
+	async fn list_memories(&mut self, id: ConversationId, offset: usize, limit: usize) {
+		let items = self
+			.with_memory(id, move |backend, memory_name| async move {
+				backend.list_memory(&memory_name, offset, limit).await
+			})
+			.await;
+		if let Some(items) = items {
+			self.emit(LLMWorkerEvent::Memories {
+				conversation: id,
+				offset,
+				items,
+			})
+			.await;
+		}
+	}
+
+	async fn forget_memory(&mut self, id: ConversationId, item_id: String) {
+		let forget_id = item_id.clone();
+		let forgotten = self
+			.with_memory(id, move |backend, memory_name| async move {
+				backend.forget_item(&memory_name, &forget_id).await
+			})
+			.await;
+		if forgotten.is_some() {
+			self.emit(LLMWorkerEvent::MemoryForgotten {
+				conversation: id,
+				id: item_id,
+			})
+			.await;
+		}
+	}
+
+	async fn save_transcript(&mut self, id: ConversationId, path: PathBuf) {
+		let turns = self.conversation(id).turns.clone();
+		let save_path = path.clone();
+		let saved = self
+			.with_session(id, LLMWorkerErrorKind::Transcript, move |session| {
+				transcript::save(session, turns, &save_path)
+			})
+			.await;
+
+		match saved {
+			Some(Ok(warning)) => {
+				if let Some(warning) = warning {
+					self.emit(LLMWorkerEvent::Warning(warning)).await;
+				}
+				self.emit(LLMWorkerEvent::TranscriptSaved(path)).await;
+			}
+			Some(Err(e)) => {
+				self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Transcript, e.to_string())).await;
+			}
+			None => {}
+		}
+	}
+
+	async fn load_transcript(&mut self, id: ConversationId, path: PathBuf) {
+		let transcript = match Transcript::read(&path) {
+			Ok(transcript) => transcript,
+			Err(e) => {
+				self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Transcript, e.to_string())).await;
+				return;
+			}
+		};
+
+		// Restore the conversation into a fresh session
+		let task = transcript.task.clone();
+		if !self.set_task(id, task.clone()).await {
+			return;
+		}
+
+		self.emit(LLMWorkerEvent::Running(true)).await;
+		let turns = transcript.turns.clone();
+		let restored = self
+			.with_session(id, LLMWorkerErrorKind::Transcript, move |session| {
+				transcript::restore(session, &transcript, &path)
+			})
+			.await;
+
+		match restored {
+			Some(Ok(warning)) => {
+				if let Some(warning) = warning {
+					self.emit(LLMWorkerEvent::Warning(warning)).await;
+				}
+				self.conversation(id).turns = turns.clone();
+				self.emit(LLMWorkerEvent::TranscriptLoaded {
+					conversation: id,
+					task,
+					turns,
+				})
+				.await;
+			}
+			Some(Err(e)) => {
+				// The session may contain part of the conversation; start over
+				self.emit(LLMWorkerEvent::error(LLMWorkerErrorKind::Transcript, e.to_string())).await;
+				self.set_task(id, task).await;
+			}
+			None => {}
+		}
+		self.emit(LLMWorkerEvent::Running(false)).await;
+	}
+}
+
+pub fn llm_worker(args: Args) -> Subscription<LLMWorkerEvent> {
+	struct LLMWorker;
+
+	subscription::channel(std::any::TypeId::of::<LLMWorker>(), 100, move |mut output| async move {
+		let mut state = LLMWorkerState::Starting;
+
+		let config_file_path = args.config_file_path();
+		let mut worker = match Worker::load(&config_file_path, &args.models_dir(), output.clone()).await {
+			Ok(worker) => Some(worker),
+			Err(error) => {
+				tracing::error!("worker could not start: {error:?}");
+				emit(&mut output, error).await;
+				state = LLMWorkerState::Failed;
+				None
+			}
+		};
 
 		loop {
-			match &mut state {
-				LLMWorkerState::Starting => {
+			match (&mut state, worker.as_mut()) {
+				(LLMWorkerState::Starting, Some(worker)) => {
 					// Create channel
 					let (sender, receiver) = mpsc::channel(100);
 
 					// Send the sender back to the application
-					output
-						.send(LLMWorkerEvent::Ready {
-							sender,
-							tasks: task_names.clone(),
-							selected_task: selected_task_name.clone(),
-						})
-						.await
-						.unwrap();
+					if !worker.emit(LLMWorkerEvent::Ready { sender }).await {
+						state = LLMWorkerState::Failed;
+						continue;
+					}
+
+					// Open the first conversation (with the first task)
+					worker.new_conversation().await;
 
 					// We are ready to receive messages
 					state = LLMWorkerState::Ready(receiver);
 				}
-				LLMWorkerState::Ready(receiver) => {
-					// Read next input sent from `Application`
-					let input = receiver.select_next_some().await;
-
-					match input {
-						LLMWorkerCommand::Reset { task_name } => {
-							// Create a new session
-							selected_task_name = task_name;
-							session = backend.start(&selected_task_name, &SessionRequest {}, backend.clone()).unwrap();
-						}
-
-						LLMWorkerCommand::Interrupt => {}
-
-						LLMWorkerCommand::Prompt(prompt) => {
-							let (ptx, mut prx) = tokio::sync::mpsc::channel(16);
-
-							// Do some async work...
-							let cancelled = Arc::new(AtomicBool::new(false));
-							let cancelled_clone = cancelled.clone();
-
-							output.send(LLMWorkerEvent::Running(true)).await.unwrap();
-							let session_fut = spawn_blocking(move || {
-								// Swallow errors. Typically 'context full'
-								// TODO handle this in a better way
-								let _ = session.complete(&PromptRequest { prompt }, |feo| {
-									match feo {
-										InferenceResponse::SnapshotToken(_) => {}
-										InferenceResponse::PromptToken(_) => {}
-										InferenceResponse::InferredToken(ft) => {
-											ptx.blocking_send(ft).unwrap();
-										}
-										InferenceResponse::EotToken => return Ok(InferenceFeedback::Halt),
-									}
-									if cancelled_clone.load(Ordering::SeqCst) {
-										return Ok(InferenceFeedback::Halt);
-									}
-									Ok(InferenceFeedback::Continue)
-								});
-								session
-							});
-
-							loop {
-								select! {
-									token = prx.recv() => {
-										match token {
-											Some(token) => output.send(LLMWorkerEvent::ResponseToken(token)).await.unwrap(),
-											None => break
-										};
-									},
-									LLMWorkerCommand::Interrupt = receiver.select_next_some() => {
-										tracing::info!("Interrupted");
-										cancelled.store(true, Ordering::SeqCst);
-										break;
-									},
-									else => break
-								}
-							}
-
-							session = session_fut.await.unwrap();
-							output.send(LLMWorkerEvent::Running(false)).await.unwrap();
-						}
+				(LLMWorkerState::Ready(receiver), Some(worker)) => {
+					if receiver.is_terminated() {
+						// The application has dropped its sender
+						state = LLMWorkerState::Failed;
+						continue;
 					}
+					let input = worker.next_input(receiver).await;
+					worker.handle(input).await;
+					if worker.shut_down {
+						state = LLMWorkerState::Stopped;
+					}
+				}
+				_ => {
+					// Idle; the subscription must not end
+					std::future::pending::<()>().await;
 				}
 			}
 		}
 	})
 }
+
+#[cfg(test)]
+mod test {
+	use std::{
+		io::{Read, Write},
+		path::Path,
+		sync::{atomic::AtomicBool, Arc},
+		time::{Duration, Instant},
+	};
+
+	use iced::futures::{channel::mpsc, StreamExt};
+	use poly_backend::{
+		backend::{Backend, InferenceFeedback},
+		config::BackendConfig,
+		session::{Completion, InferenceStats, SessionCheckpoint, TokenCount},
+		types::{FinishReason, PromptRequest},
+	};
+
+	use super::{
+		ConnectionStatus, Conversation, ConversationId, Generation, LLMWorkerCommand, LLMWorkerErrorKind, LLMWorkerEvent, LLMWorkerFinishReason,
+		LLMWorkerParameters, Progress, Worker, PROGRESS_INTERVAL,
+	};
+	use crate::{
+		config::RemoteConfig,
+		session::{ChatSession, SessionError, WorkerBackend},
+	};
+
+	/// Conversation that is open in the worker returned by `test_worker`
+	const CONVERSATION: ConversationId = 0;
+
+	/// A worker without models or tasks, with one conversation open
+	async fn test_worker() -> (Worker, mpsc::Receiver<LLMWorkerEvent>) {
+		let config = BackendConfig {
+			cache_path: Some(std::env::temp_dir().join("poly-ui-test")),
+			..Default::default()
+		};
+		let backend = Arc::new(Backend::from(config, None).await);
+		let (output, events) = mpsc::channel(16);
+		let mut worker = Worker::new(WorkerBackend::Local(backend), vec![], output);
+		worker.conversations.insert(CONVERSATION, Conversation::default());
+		worker.next_conversation = CONVERSATION + 1;
+		(worker, events)
+	}
+
+	/// A generation that never ends
+	fn pending_generation(conversation: ConversationId) -> Generation {
+		let (_tokens_tx, tokens) = tokio::sync::mpsc::channel(1);
+		Generation {
+			conversation,
+			cancelled: Arc::new(AtomicBool::new(false)),
+			tokens,
+			handle: tokio::spawn(std::future::pending::<(Box<dyn ChatSession>, Result<Completion, SessionError>)>()),
+			progress: Progress::default(),
+			is_retry: false,
+		}
+	}
+
+	/// A session that cannot do anything, to hand back from fake generations
+	struct StubSession;
+
+	impl ChatSession for StubSession {
+		fn task_name(&self) -> &str {
+			"stub"
+		}
+
+		fn model_name(&self) -> &str {
+			"stub"
+		}
+
+		fn complete(&mut self, _request: &PromptRequest, _callback: &mut dyn FnMut(String) -> InferenceFeedback) -> Result<Completion, SessionError> {
+			Err(SessionError::Unsupported("completion"))
+		}
+
+		fn replay(&mut self, _prompt: &str, _response: &str) -> Result<(), SessionError> {
+			Err(SessionError::Unsupported("replaying"))
+		}
+
+		fn context_remaining(&self) -> Option<usize> {
+			None
+		}
+
+		fn count_tokens(&self, prompt: &str) -> Result<TokenCount, SessionError> {
+			Ok(TokenCount {
+				prompt: prompt.split_whitespace().count(),
+				overhead: 2,
+				used: 10,
+				pinned: 4,
+				context: 16,
+			})
+		}
+
+		fn checkpoint(&self) -> Option<SessionCheckpoint> {
+			None
+		}
+
+		fn rewind_to(&mut self, _checkpoint: &SessionCheckpoint) -> Result<(), SessionError> {
+			Err(SessionError::Unsupported("rewinding"))
+		}
+
+		fn save_state(&mut self, _writer: &mut dyn Write) -> Result<(), SessionError> {
+			Err(SessionError::Unsupported("saving"))
+		}
+
+		fn restore_state(&mut self, _reader: &mut dyn Read) -> Result<(), SessionError> {
+			Err(SessionError::Unsupported("restoring"))
+		}
+	}
+
+	fn error_kind(event: Option<LLMWorkerEvent>) -> Option<(LLMWorkerErrorKind, bool)> {
+		match event {
+			Some(LLMWorkerEvent::Error { kind, recoverable, .. }) => Some((kind, recoverable)),
+			_ => None,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_load_broken_config() {
+		let (output, mut events) = mpsc::channel(16);
+		let missing = Path::new("/nonexistent/poly/config.toml");
+		let error = Worker::load(missing, Path::new("."), output.clone()).await.err();
+		assert_eq!(error_kind(error), Some((LLMWorkerErrorKind::Config, false)));
+
+		// A configuration that refers to a model that does not exist
+		let path = std::env::temp_dir().join(format!("poly-ui-broken-config-{}.toml", std::process::id()));
+		std::fs::write(&path, "[tasks.chat]\nmodel = \"missing\"\n").unwrap();
+		let error = Worker::load(&path, Path::new("."), output.clone()).await.err();
+		assert_eq!(error_kind(error), Some((LLMWorkerErrorKind::Config, false)));
+		std::fs::remove_file(&path).unwrap();
+
+		// Nothing is reported before the configuration has been validated
+		assert!(events.try_next().is_err());
+	}
+
+	#[tokio::test]
+	async fn test_connect_unreachable() {
+		let (output, mut events) = mpsc::channel(16);
+		let config = RemoteConfig {
+			url: String::from("http://127.0.0.1:1"),
+			reconnect_attempts: 2,
+			..Default::default()
+		};
+		let error = Worker::connect(config, output).await.err();
+		assert_eq!(error_kind(error), Some((LLMWorkerErrorKind::Load, false)));
+		assert!(matches!(
+			events.next().await,
+			Some(LLMWorkerEvent::Connection(ConnectionStatus::Connecting))
+		));
+		assert!(matches!(
+			events.next().await,
+			Some(LLMWorkerEvent::Connection(ConnectionStatus::Disconnected))
+		));
+
+		// Invalid URLs are a configuration problem
+		let (output, _events) = mpsc::channel(16);
+		let config = RemoteConfig {
+			url: String::from("localhost:3000"),
+			..Default::default()
+		};
+		let error = Worker::connect(config, output).await.err();
+		assert_eq!(error_kind(error), Some((LLMWorkerErrorKind::Config, false)));
+	}
+
+	#[tokio::test]
+	async fn test_set_unknown_task() {
+		let (mut worker, mut events) = test_worker().await;
+		worker
+			.handle_command(LLMWorkerCommand::SetTask {
+				conversation: CONVERSATION,
+				task: String::from("missing"),
+			})
+			.await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Task, true)));
+		assert!(worker.conversation(CONVERSATION).task_name.is_none());
+
+		// Without a session, prompts are answered with an error
+		worker
+			.handle_command(LLMWorkerCommand::Prompt {
+				conversation: CONVERSATION,
+				text: String::from("hello"),
+			})
+			.await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Rejected, true)));
+	}
+
+	#[tokio::test]
+	async fn test_regenerate_without_prompt() {
+		let (mut worker, mut events) = test_worker().await;
+		worker.handle_command(LLMWorkerCommand::Regenerate(CONVERSATION)).await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Rejected, true)));
+		assert!(worker.generation.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_conversations() {
+		let (mut worker, mut events) = test_worker().await;
+		worker.max_conversations = 2;
+
+		// Without tasks, conversations are opened without a session
+		worker.handle_command(LLMWorkerCommand::NewConversation).await;
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::ConversationCreated(1))));
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Task, true)));
+
+		// No more conversations can be opened than configured
+		worker.handle_command(LLMWorkerCommand::NewConversation).await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Rejected, true)));
+		assert_eq!(worker.conversations.len(), 2);
+
+		// Closing a conversation stops the response being generated in it
+		worker.generation = Some(pending_generation(1));
+		worker.handle_command(LLMWorkerCommand::CloseConversation(1)).await;
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::ConversationClosed(1))));
+		assert!(worker.generation.as_ref().unwrap().is_stopped());
+		worker.generation = None;
+
+		// Commands for conversations that are not open are rejected
+		worker.handle_command(LLMWorkerCommand::Reset(1)).await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Rejected, true)));
+
+		// Identifiers are not reused
+		worker.handle_command(LLMWorkerCommand::NewConversation).await;
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::ConversationCreated(2))));
+	}
+
+	#[tokio::test]
+	async fn test_count_tokens() {
+		let (mut worker, mut events) = test_worker().await;
+		let count_tokens = || LLMWorkerCommand::CountTokens {
+			conversation: CONVERSATION,
+			text: String::from("how are you"),
+		};
+		worker.handle_command(count_tokens()).await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Rejected, true)));
+
+		worker.conversation(CONVERSATION).session = Some(Box::new(StubSession));
+		worker.handle_command(count_tokens()).await;
+		assert!(matches!(
+			events.next().await,
+			Some(LLMWorkerEvent::TokenCount {
+				conversation: CONVERSATION,
+				prompt: 3,
+				overhead: 2,
+				used: 10,
+				pinned: 4,
+				context: 16
+			})
+		));
+	}
+
+	#[tokio::test]
+	async fn test_shutdown() {
+		let (mut worker, mut events) = test_worker().await;
+		let (_, tokens) = tokio::sync::mpsc::channel(1);
+		worker.generation = Some(Generation {
+			conversation: CONVERSATION,
+			cancelled: Arc::new(AtomicBool::new(false)),
+			tokens,
+			handle: tokio::spawn(async {
+				let session: Box<dyn ChatSession> = Box::new(StubSession);
+				(session, Ok(Completion::new(FinishReason::Cancelled)))
+			}),
+			progress: Progress::default(),
+			is_retry: false,
+		});
+
+		// The running generation is stopped and ended before shutting down
+		worker.handle_command(LLMWorkerCommand::Shutdown).await;
+		assert!(worker.generation.is_none());
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::Stats { .. })));
+		assert!(matches!(
+			events.next().await,
+			Some(LLMWorkerEvent::Finished {
+				reason: LLMWorkerFinishReason::Cancelled,
+				..
+			})
+		));
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::Running(false))));
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::ShutdownComplete)));
+		assert!(worker.shut_down);
+		assert!(worker.conversations.is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_set_system_prompt() {
+		let (mut worker, mut events) = test_worker().await;
+		let set_system_prompt = |prompt: &str| LLMWorkerCommand::SetSystemPrompt {
+			conversation: CONVERSATION,
+			prompt: prompt.to_string(),
+		};
+		worker.handle_command(set_system_prompt("You are a pirate.")).await;
+		assert!(matches!(
+			events.next().await,
+			Some(LLMWorkerEvent::SystemPrompt { prompt, overridden: true, .. }) if prompt == "You are a pirate."
+		));
+		assert_eq!(worker.session_request(CONVERSATION).prefix.as_deref(), Some("You are a pirate."));
+
+		// An empty system prompt restores the configured prefix
+		worker.handle_command(set_system_prompt("")).await;
+		assert!(matches!(
+			events.next().await,
+			Some(LLMWorkerEvent::SystemPrompt { prompt, overridden: false, .. }) if prompt.is_empty()
+		));
+		assert!(worker.session_request(CONVERSATION).prefix.is_none());
+
+		// When the session cannot be restarted, the previous system prompt is kept
+		worker.conversation(CONVERSATION).task_name = Some(String::from("missing"));
+		worker.handle_command(set_system_prompt("You are a pirate.")).await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Task, true)));
+		assert!(worker.conversation(CONVERSATION).system_prompt.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_memory_without_task() {
+		let (mut worker, mut events) = test_worker().await;
+		worker
+			.handle_command(LLMWorkerCommand::RecallMemories {
+				conversation: CONVERSATION,
+				query: String::from("hello"),
+			})
+			.await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Rejected, true)));
+
+		// A task without memorization has no memory to browse
+		worker.conversation(CONVERSATION).task_name = Some(String::from("chat"));
+		worker
+			.handle_command(LLMWorkerCommand::ListMemories {
+				conversation: CONVERSATION,
+				offset: 0,
+				limit: 10,
+			})
+			.await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Memory, true)));
+		worker
+			.handle_command(LLMWorkerCommand::ForgetMemory {
+				conversation: CONVERSATION,
+				id: String::from("id"),
+			})
+			.await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Memory, true)));
+	}
+
+	#[tokio::test]
+	async fn test_set_task_while_generating() {
+		let (mut worker, mut events) = test_worker().await;
+		worker.generation = Some(pending_generation(CONVERSATION));
+
+		// Switching tasks is rejected while generating
+		worker
+			.handle_command(LLMWorkerCommand::SetTask {
+				conversation: CONVERSATION,
+				task: String::from("other"),
+			})
+			.await;
+		assert_eq!(error_kind(events.next().await), Some((LLMWorkerErrorKind::Rejected, true)));
+
+		// Stopping is not
+		worker.handle_command(LLMWorkerCommand::Stop).await;
+		assert!(worker.generation.as_ref().unwrap().is_stopped());
+	}
+
+	#[test]
+	fn test_stats() {
+		let stats = InferenceStats {
+			feed_prompt_duration: Duration::from_secs(1),
+			prompt_tokens: 10,
+			predict_duration: Duration::from_secs(2),
+			predict_tokens: 20,
+		};
+		match LLMWorkerEvent::stats(&stats) {
+			LLMWorkerEvent::Stats {
+				prompt_tokens,
+				generated_tokens,
+				tokens_per_sec,
+				duration,
+			} => {
+				assert_eq!((prompt_tokens, generated_tokens), (10, 20));
+				assert_eq!(tokens_per_sec, 10.0);
+				assert_eq!(duration, Duration::from_secs(3));
+			}
+			e => panic!("unexpected event {e:?}"),
+		}
+
+		// Nothing generated
+		let stats = InferenceStats {
+			predict_duration: Duration::ZERO,
+			predict_tokens: 0,
+			..stats
+		};
+		assert!(matches!(LLMWorkerEvent::stats(&stats), LLMWorkerEvent::Stats { tokens_per_sec, .. } if tokens_per_sec == 0.0));
+	}
+
+	#[tokio::test]
+	async fn test_finished() {
+		let (mut worker, mut events) = test_worker().await;
+		let (_, tokens) = tokio::sync::mpsc::channel(1);
+		worker.generation = Some(Generation {
+			conversation: CONVERSATION,
+			cancelled: Arc::new(AtomicBool::new(false)),
+			tokens,
+			handle: tokio::spawn(async {
+				let session: Box<dyn ChatSession> = Box::new(StubSession);
+				(session, Ok(Completion::new(FinishReason::ContextFull)))
+			}),
+			progress: Progress::default(),
+			is_retry: false,
+		});
+
+		// The generation has ended when its token channel is closed
+		let (_commands_tx, mut commands) = mpsc::channel(1);
+		let input = worker.next_input(&mut commands).await;
+		worker.handle(input).await;
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::Stats { .. })));
+		let reason = LLMWorkerFinishReason::ContextFull;
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::Finished { reason: r, .. }) if r == reason));
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::Running(false))));
+		assert!(worker.conversation(CONVERSATION).session.is_some());
+	}
+
+	#[test]
+	fn test_clamp_parameters() {
+		let mut parameters = LLMWorkerParameters {
+			temperature: Some(5.0),
+			top_p: Some(f32::NAN),
+			max_tokens: Some(0),
+		};
+		assert_eq!(parameters.clamp().len(), 3);
+		assert_eq!(
+			parameters,
+			LLMWorkerParameters {
+				temperature: Some(2.0),
+				top_p: None,
+				max_tokens: Some(1),
+			}
+		);
+		assert!(parameters.clamp().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_set_parameters() {
+		let (mut worker, mut events) = test_worker().await;
+		worker
+			.handle_command(LLMWorkerCommand::SetParameters {
+				conversation: CONVERSATION,
+				temperature: Some(-1.0),
+				top_p: None,
+				max_tokens: Some(50),
+			})
+			.await;
+		assert!(matches!(events.next().await, Some(LLMWorkerEvent::Warning(_))));
+
+		// Without a task, only the overridden number of tokens is known
+		match events.next().await {
+			Some(LLMWorkerEvent::Parameters { parameters, .. }) => assert_eq!(
+				parameters,
+				LLMWorkerParameters {
+					temperature: None,
+					top_p: None,
+					max_tokens: Some(50),
+				}
+			),
+			e => panic!("unexpected event {e:?}"),
+		}
+		let parameters = &worker.conversation(CONVERSATION).parameters;
+		assert_eq!(parameters.temperature, Some(0.0));
+		let request = parameters.request(String::from("hello"), None);
+		assert_eq!((request.temperature, request.max_tokens), (None, Some(50)));
+	}
+
+	#[test]
+	fn test_progress() {
+		let mut progress = Progress::default();
+		let start = Instant::now();
+		assert!(progress.add_token(start).is_none());
+		assert!(progress.add_token(start + PROGRESS_INTERVAL / 2).is_none());
+
+		// Two tokens were generated after the first one in one interval
+		match progress.add_token(start + PROGRESS_INTERVAL) {
+			Some(LLMWorkerEvent::Progress {
+				generated_tokens,
+				tokens_per_sec,
+			}) => {
+				assert_eq!(generated_tokens, 3);
+				assert_eq!(tokens_per_sec, 2.0 / PROGRESS_INTERVAL.as_secs_f64());
+			}
+			e => panic!("unexpected event {e:?}"),
+		}
+		assert!(progress.add_token(start + PROGRESS_INTERVAL + Duration::from_millis(1)).is_none());
+	}
+}